@@ -0,0 +1,73 @@
+//! Compares sending on a node with a single executor, which skips the executor
+//! map, against a node with several, which looks the protocol up on every send.
+//!
+//! Run with `cargo bench --bench executor_lookup`.
+
+use std::{
+    future::Future,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use anytape::{
+    Address, Identity, MessageBuilder, MessageStatus, NodeInstance, Protocol, ProtocolExecutor,
+    SendOutcome,
+};
+
+const SENDS: u32 = 200_000;
+
+/// Accepts everything without doing any work.
+struct Discard;
+
+impl ProtocolExecutor for Discard {
+    type Error = std::io::Error;
+
+    fn send(
+        &self,
+        _remote: &Identity,
+        message: anytape::Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        black_box(message);
+        std::future::ready(Ok(SendOutcome::sent()))
+    }
+    fn get_status(
+        &self,
+        _remote: &Identity,
+        _message: anytape::Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        std::future::ready(Ok(MessageStatus::Sended))
+    }
+}
+
+async fn per_send(node: &NodeInstance, to: &Address) -> Duration {
+    let message = MessageBuilder::new(to.clone()).payload("x").build();
+    let started = Instant::now();
+    for _ in 0..SENDS {
+        node.send(message.clone(), to.clone()).await.unwrap();
+    }
+    started.elapsed() / SENDS
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let protocol = Protocol::new_static(b"bench");
+    let to = Address::new(protocol.clone(), Identity::new("peer"));
+
+    let single = NodeInstance::new().with_executor(protocol.clone(), Discard);
+    let mut several = NodeInstance::new().with_executor(protocol, Discard);
+    for i in 0..8 {
+        several = several.with_executor(Protocol::new(format!("other-{i}")), Discard);
+    }
+
+    runtime.block_on(async {
+        // warm up both before measuring
+        per_send(&single, &to).await;
+        per_send(&several, &to).await;
+        let fast = per_send(&single, &to).await;
+        let map = per_send(&several, &to).await;
+        println!("single executor: {fast:?} per send");
+        println!("executor map:    {map:?} per send");
+    });
+}
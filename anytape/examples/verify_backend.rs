@@ -0,0 +1,15 @@
+//! Checks [`MemoryBackend`] against the `DataBackend` contract. Point
+//! `verify_backend` at your own backend the same way.
+//!
+//! Run with `cargo run --example verify_backend --features test-util`.
+
+use anytape::{conformance::verify_backend, MemoryBackend};
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let report = runtime.block_on(verify_backend(&MemoryBackend::new()));
+    println!("{report}");
+    assert!(report.is_ok());
+}
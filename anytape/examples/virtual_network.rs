@@ -0,0 +1,62 @@
+//! Routes a message across three virtual nodes, `a -> b -> c`, and checks when
+//! and where it arrived.
+//!
+//! Run with `cargo run --example virtual_network --features test-util`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anytape::{
+    virtual_net::{LinkConfig, VirtualNetwork},
+    Clock, DataBackend, MemoryBackend, Message, MessageBuilder, MessageStatus,
+};
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let c = VirtualNetwork::address("c");
+        let routes = MemoryBackend::new();
+        routes
+            .set_next(&c, Some(&VirtualNetwork::address("b")))
+            .await
+            .unwrap();
+
+        let mut net = VirtualNetwork::new();
+        let link = LinkConfig {
+            latency: Duration::from_millis(20),
+            loss: 0.0,
+        };
+        net.connect("a", "b", link);
+        net.connect("b", "c", link);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let a = net.add_node("a", |node| node.with_backend(routes));
+        net.add_node("b", |node| node);
+        net.add_node("c", |node| {
+            let received = received.clone();
+            node.with_handler(move |message: Message| {
+                received.lock().unwrap().push(message.payload);
+                async {}
+            })
+        });
+
+        let mut message = MessageBuilder::new(c.clone()).payload("hello").build();
+        a.mark(VirtualNetwork::address("a"), &mut message);
+        a.forward(message).await.unwrap();
+
+        let hops = net.run_until_idle(10).await;
+        let route: Vec<_> = hops
+            .iter()
+            .map(|hop| String::from_utf8_lossy(hop.to.as_bytes()).into_owned())
+            .collect();
+        assert_eq!(route, ["b", "c"]);
+        assert!(matches!(hops[1].result, Ok(MessageStatus::Received)));
+        assert_eq!(*received.lock().unwrap(), [b"hello".to_vec()]);
+        assert_eq!(net.clock().monotonic(), Duration::from_millis(40));
+        println!("delivered over {route:?}");
+    });
+}
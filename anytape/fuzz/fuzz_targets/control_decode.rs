@@ -0,0 +1,18 @@
+//! Decoding arbitrary bytes as a control message never panics and allocates
+//! in proportion to the input, and what it decodes encodes to bytes that
+//! decode to the same control message.
+
+#![no_main]
+
+use anytape::control::ControlMessage;
+use anytape_fuzz::{assert_bounded, peak_allocation};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (decoded, peak) = peak_allocation(|| ControlMessage::decode(data));
+    assert_bounded(data.len(), peak);
+    let Ok(Some(control)) = decoded else {
+        return;
+    };
+    assert_eq!(ControlMessage::decode(&control.encode()), Ok(Some(control)));
+});
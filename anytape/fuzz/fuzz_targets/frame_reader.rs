@@ -0,0 +1,45 @@
+//! Feeding a frame reader arbitrary chunks never panics, and the frames it
+//! returns are within the limit, split off without copying, and framed again
+//! give back the bytes fed.
+
+#![no_main]
+
+use anytape::frame::{FrameReader, MessageLimits};
+use anytape_fuzz::{assert_bounded, peak_allocation};
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let (Ok(max), Ok(chunks)) = (u.arbitrary::<u16>(), u.arbitrary::<Vec<&[u8]>>()) else {
+        return;
+    };
+    let limits = MessageLimits {
+        max_total_encoded_size: usize::from(max),
+    };
+    let mut reader = FrameReader::new(limits);
+    let mut fed = Vec::new();
+    let mut framed = Vec::new();
+    'feed: for chunk in chunks {
+        reader.extend(chunk);
+        fed.extend_from_slice(chunk);
+        loop {
+            let (next, peak) = peak_allocation(|| reader.next_frame());
+            assert_bounded(0, peak);
+            match next {
+                Ok(Some(frame)) => {
+                    assert!(!frame.is_empty() && frame.len() <= limits.max_total_encoded_size);
+                    framed.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                    framed.extend_from_slice(&frame);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    assert!(e.is_protocol_violation());
+                    break 'feed;
+                }
+            }
+        }
+        assert!(reader.buffered() <= fed.len() - framed.len());
+    }
+    assert!(fed.starts_with(&framed));
+});
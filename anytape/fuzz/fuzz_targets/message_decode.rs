@@ -0,0 +1,19 @@
+//! Decoding arbitrary bytes as a message never panics and allocates in
+//! proportion to the input, and what it decodes encodes to bytes that decode
+//! to the same message.
+
+#![no_main]
+
+use anytape::Message;
+use anytape_fuzz::{assert_bounded, peak_allocation};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (decoded, peak) = peak_allocation(|| Message::decode(data));
+    assert_bounded(data.len(), peak);
+    let Ok(message) = decoded else {
+        return;
+    };
+    let encoded = message.encode();
+    assert!(Message::decode(&encoded) == Ok(message));
+});
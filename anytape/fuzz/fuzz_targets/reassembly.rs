@@ -0,0 +1,91 @@
+//! Handing a node the fragments of a message in any order, repeated, missing
+//! or mixed with stray fragments never panics, never delivers more payload
+//! than it was given, and delivers the message once every fragment arrived.
+
+#![no_main]
+
+use std::sync::{Arc, Mutex};
+
+use anytape::{Address, Identity, Message, NodeInstance, Protocol, FRAGMENT_HEADER};
+use arbitrary::{Arbitrary, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary)]
+struct Input {
+    message: Message,
+    max_size: u16,
+    deliveries: Vec<Delivery>,
+}
+
+#[derive(Arbitrary)]
+enum Delivery {
+    /// The fragment at this index, modulo the fragment count.
+    Fragment(u8),
+    /// Another message with this fragment header.
+    Stray {
+        message: Box<Message>,
+        header: Vec<u8>,
+    },
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = Unstructured::new(data).arbitrary::<Input>() else {
+        return;
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let local = Address::new(Protocol::new_static(b"fuzz"), Identity::new("local"));
+    let delivered = Arc::new(Mutex::new(Vec::<Message>::new()));
+    let node = NodeInstance::new()
+        .with_address(local.clone())
+        .with_handler({
+            let delivered = delivered.clone();
+            move |message: Message| {
+                delivered.lock().unwrap().push(message);
+                async {}
+            }
+        });
+
+    let message = Message {
+        destination: local.clone(),
+        ..input.message
+    };
+    let fragments = message.split(usize::from(input.max_size));
+    let mut arrived = vec![false; fragments.len()];
+    let mut fed = 0;
+    let mut collides = false;
+    for delivery in input.deliveries {
+        let mut incoming = match delivery {
+            Delivery::Fragment(index) => {
+                let index = usize::from(index) % fragments.len();
+                arrived[index] = true;
+                fragments[index].clone()
+            }
+            Delivery::Stray {
+                message: mut stray,
+                header,
+            } => {
+                collides |= stray.unique_id == message.unique_id;
+                stray.headers.insert(FRAGMENT_HEADER.to_owned(), header);
+                *stray
+            }
+        };
+        incoming.destination = local.clone();
+        fed += incoming.payload.len();
+        runtime.block_on(node.receive(incoming, local.clone()));
+    }
+
+    let delivered = delivered.lock().unwrap();
+    assert!(delivered.iter().all(|whole| whole.payload.len() <= fed));
+    // reserved headers send the message elsewhere than the handler
+    let plain = !message
+        .headers
+        .keys()
+        .any(|name| name.starts_with("anytape-"));
+    if plain && !collides && arrived.iter().all(|arrived| *arrived) {
+        assert!(delivered
+            .iter()
+            .any(|whole| whole.unique_id == message.unique_id && whole.payload == message.payload));
+    }
+});
@@ -0,0 +1,42 @@
+//! Arbitrary messages and control messages encode to bytes that decode to
+//! them again, and messages encoded for older versions lose only the fields
+//! those versions cannot carry.
+
+#![no_main]
+
+use anytape::{control::ControlMessage, Message, PathNode};
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    if let Ok(message) = u.arbitrary::<Message>() {
+        assert!(Message::decode(&message.encode()).as_ref() == Ok(&message));
+        let v2 = Message {
+            path: message
+                .path
+                .iter()
+                .map(|node| PathNode {
+                    sig: None,
+                    remaining_budget_ms: None,
+                    ..node.clone()
+                })
+                .collect(),
+            seq: None,
+            budget_ms: None,
+            route_plan: None,
+            reply_to: None,
+            extensions: Vec::new(),
+            ..message.clone()
+        };
+        assert!(Message::decode(&message.encode_for_version(2)).as_ref() == Ok(&v2));
+        let v1 = Message {
+            metadata: Vec::new(),
+            ..v2
+        };
+        assert!(Message::decode(&message.encode_for_version(1)).as_ref() == Ok(&v1));
+    }
+    if let Ok(control) = u.arbitrary::<ControlMessage>() {
+        assert_eq!(ControlMessage::decode(&control.encode()), Ok(Some(control)));
+    }
+});
@@ -0,0 +1,70 @@
+//! What the fuzz targets share: an allocator that tracks the most memory in
+//! use, to check that decoding allocates in proportion to its input only.
+//!
+//! Run a target with e.g. `cargo fuzz run message_decode` from the crate root.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What decoding may allocate per input byte. The densest inputs are lists of
+/// empty entries: a metadata tag takes two bytes and 48 of memory, tripled
+/// while its vector grows.
+pub const ALLOCATION_PER_BYTE: usize = 128;
+/// What decoding may allocate on top, e.g. for the first node of a map.
+pub const ALLOCATION_BASE: usize = 4096;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct Tracking;
+
+fn grow(size: usize) {
+    let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            // both blocks may be in use while the old one is copied
+            grow(new_size);
+            LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Tracking = Tracking;
+
+/// Run `f`, returning what it returns and the most memory it had allocated at
+/// once, what it returns included.
+pub fn peak_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let base = LIVE.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let output = f();
+    (output, PEAK.load(Ordering::Relaxed) - base)
+}
+
+/// Panic if decoding `input_len` bytes had up to `peak` bytes allocated at once.
+pub fn assert_bounded(input_len: usize, peak: usize) {
+    let limit = ALLOCATION_PER_BYTE * input_len + ALLOCATION_BASE;
+    assert!(
+        peak <= limit,
+        "decoding {input_len} bytes allocated {peak}, more than {limit}"
+    );
+}
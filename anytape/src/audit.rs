@@ -0,0 +1,397 @@
+//! Append-only record of security-relevant events.
+//!
+//! Enforcement points hand [`AuditEvent`]s to a bounded queue that a background
+//! thread drains into the node's [`AuditSink`], so a slow sink never holds up
+//! message handling. When the queue is full, events are dropped and counted; see
+//! [`NodeInstance::audit_events_dropped`].
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+};
+
+use crate::{Address, Identity, NodeInstance};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuditKind {
+    /// A message failed authentication, e.g. a group control message the
+    /// [authorizer](NodeInstance::with_group_authorizer) refused.
+    SignatureFailure,
+    /// Traffic was refused for exceeding its [quota](crate::QuotaManager).
+    QuotaRejected,
+    /// A route candidate was added or removed.
+    RouteChanged,
+    /// A group gained or lost a member.
+    GroupChanged,
+    /// A quota was set or cleared.
+    PolicyChanged,
+}
+
+impl AuditKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::SignatureFailure => "signature_failure",
+            AuditKind::QuotaRejected => "quota_rejected",
+            AuditKind::RouteChanged => "route_changed",
+            AuditKind::GroupChanged => "group_changed",
+            AuditKind::PolicyChanged => "policy_changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub kind: AuditKind,
+    /// The peer the event is about, if it is known only by identity.
+    pub identity: Option<Identity>,
+    pub addresses: Vec<Address>,
+    /// The message that triggered the event, if any.
+    pub unique_id: Option<u64>,
+    /// Unix time in milliseconds, from the node's [clock](crate::Clock).
+    pub at_millis: u64,
+    pub detail: String,
+}
+
+impl AuditEvent {
+    pub(crate) fn new(kind: AuditKind, at_millis: u64, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            identity: None,
+            addresses: Vec::new(),
+            unique_id: None,
+            at_millis,
+            detail: detail.into(),
+        }
+    }
+    pub(crate) fn identity(self, identity: &Identity) -> Self {
+        Self {
+            identity: Some(identity.clone()),
+            ..self
+        }
+    }
+    pub(crate) fn address(mut self, address: &Address) -> Self {
+        self.addresses.push(address.clone());
+        self
+    }
+    pub(crate) fn unique_id(self, unique_id: u64) -> Self {
+        Self {
+            unique_id: Some(unique_id),
+            ..self
+        }
+    }
+}
+
+/// Where audit events end up. Called from a dedicated thread, so it may block.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: AuditEvent);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(AuditEvent) + Send + Sync,
+{
+    fn record(&self, event: AuditEvent) {
+        self(event)
+    }
+}
+
+/// Keeps the latest `capacity` events in memory. Clones share the same events.
+#[derive(Debug, Clone)]
+pub struct MemoryAuditSink {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<AuditEvent>>>,
+}
+
+impl MemoryAuditSink {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            events: Arc::default(),
+        }
+    }
+    /// The recorded events, oldest first.
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl AuditSink for MemoryAuditSink {
+    fn record(&self, event: AuditEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+}
+
+/// The node side of the audit queue. Shared with subsystems that audit on their
+/// own, like the [`QuotaManager`](crate::QuotaManager).
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    queue: RwLock<Option<mpsc::SyncSender<AuditEvent>>>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    pub(crate) fn record(&self, event: AuditEvent) {
+        if let Some(queue) = &*self.queue.read().unwrap() {
+            if queue.try_send(event).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "serde")]
+pub use json::JsonLinesAuditSink;
+
+#[cfg(feature = "serde")]
+mod json {
+    use std::{
+        fs::{self, File, OpenOptions},
+        io::{self, Write},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::{AuditEvent, AuditSink};
+
+    /// Appends events as JSON lines to a file, moving it to `<path>.1` once it
+    /// would grow beyond `max_bytes`.
+    pub struct JsonLinesAuditSink {
+        path: PathBuf,
+        max_bytes: u64,
+        file: Mutex<(File, u64)>,
+        write_errors: AtomicU64,
+    }
+
+    impl JsonLinesAuditSink {
+        pub fn create(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+            let path = path.into();
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let len = file.metadata()?.len();
+            Ok(Self {
+                path,
+                max_bytes,
+                file: Mutex::new((file, len)),
+                write_errors: AtomicU64::new(0),
+            })
+        }
+        /// Events lost because the file could not be written or rotated.
+        pub fn write_errors(&self) -> u64 {
+            self.write_errors.load(Ordering::Relaxed)
+        }
+        fn append(&self, line: &[u8]) -> io::Result<()> {
+            let mut file = self.file.lock().unwrap();
+            if file.1 > 0 && file.1 + line.len() as u64 > self.max_bytes {
+                let mut rotated = self.path.clone().into_os_string();
+                rotated.push(".1");
+                fs::rename(&self.path, rotated)?;
+                *file = (
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)?,
+                    0,
+                );
+            }
+            file.0.write_all(line)?;
+            file.1 += line.len() as u64;
+            Ok(())
+        }
+    }
+
+    impl AuditSink for JsonLinesAuditSink {
+        fn record(&self, event: AuditEvent) {
+            let value = serde_json::json!({
+                "kind": event.kind.as_str(),
+                "identity": event.identity.map(|identity| identity.to_string()),
+                "addresses": event.addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "unique_id": event.unique_id,
+                "at_millis": event.at_millis,
+                "detail": event.detail,
+            });
+            let mut line = value.to_string().into_bytes();
+            line.push(b'\n');
+            if self.append(&line).is_err() {
+                self.write_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Record security-relevant events to `sink`, queueing up to `capacity` events
+    /// ahead of it. A background thread feeds the sink until the node is dropped.
+    pub fn with_audit_sink(self, sink: impl AuditSink + 'static, capacity: usize) -> Self {
+        let (queue, events) = mpsc::sync_channel::<AuditEvent>(capacity);
+        std::thread::Builder::new()
+            .name("anytape-audit".to_owned())
+            .spawn(move || {
+                for event in events {
+                    sink.record(event);
+                }
+            })
+            .expect("failed to spawn the audit thread");
+        *self.audit.queue.write().unwrap() = Some(queue);
+        self
+    }
+    /// Audit events lost because the queue was full.
+    pub fn audit_events_dropped(&self) -> u64 {
+        self.audit.dropped()
+    }
+    pub(crate) fn audit(&self, event: AuditEvent) {
+        self.audit.record(event)
+    }
+    pub(crate) fn audit_event(&self, kind: AuditKind, detail: impl Into<String>) -> AuditEvent {
+        AuditEvent::new(kind, self.clock.now_millis(), detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{
+        GroupControl, ManualClock, MessageStatus, Protocol, QuotaLimits, QuotaManager,
+        RejectReason, SendError,
+    };
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    /// Wait for the audit thread to have handed `count` events to `sink`.
+    fn wait_for(sink: &MemoryAuditSink, count: usize) -> Vec<AuditEvent> {
+        for _ in 0..1000 {
+            let events = sink.events();
+            if events.len() >= count {
+                return events;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!(
+            "only {} of {count} audit events arrived",
+            sink.events().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn every_enforcement_point_records_one_event() {
+        let sink = MemoryAuditSink::new(16);
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, Recorder::new())
+            .with_clock(ManualClock::new(NOW))
+            .with_quota_manager(QuotaManager::new(
+                Duration::from_secs(10),
+                Duration::from_secs(1),
+            ))
+            .with_audit_sink(sink.clone(), 16);
+        let (group, b) = (
+            Address::new(Protocol::GROUP, Identity::new("g")),
+            Identity::new("b"),
+        );
+
+        node.quotas().unwrap().set_quota(
+            b.clone(),
+            QuotaLimits {
+                max_messages: Some(0),
+                max_bytes: None,
+            },
+        );
+        let refused = message(addr("b"), b"x");
+        let refused_id = refused.unique_id;
+        let sent = node.send(refused, addr("b")).await;
+        assert!(matches!(sent, Err(SendError::QuotaExceeded)));
+        node.add_route_candidate(addr("dest"), addr("relay"));
+        node.group_add(group.clone(), addr("member"));
+        let mut control = GroupControl::Add {
+            group: group.clone(),
+            member: addr("intruder"),
+        }
+        .into_message(addr("me"));
+        node.mark(addr("stranger"), &mut control);
+        let control_id = control.unique_id;
+        assert_eq!(
+            node.dispatch_inbound(control, addr("me")).await.unwrap(),
+            MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature
+            }
+        );
+
+        let events = wait_for(&sink, 5);
+        let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                AuditKind::PolicyChanged,
+                AuditKind::QuotaRejected,
+                AuditKind::RouteChanged,
+                AuditKind::GroupChanged,
+                AuditKind::SignatureFailure,
+            ]
+        );
+        assert!(events.iter().all(|event| event.at_millis == NOW));
+        let [policy, quota, route, group_changed, signature] = &events[..] else {
+            unreachable!();
+        };
+        assert_eq!(policy.identity.as_ref(), Some(&b));
+        assert_eq!(
+            (&quota.identity, &quota.addresses, quota.unique_id),
+            (&Some(b), &vec![addr("b")], Some(refused_id))
+        );
+        assert_eq!(route.addresses, [addr("dest"), addr("relay")]);
+        assert_eq!(group_changed.addresses, [group, addr("member")]);
+        assert_eq!(
+            (&signature.addresses, signature.unique_id),
+            (&vec![addr("stranger")], Some(control_id))
+        );
+        // nothing else happened that is worth auditing
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(sink.events().len(), 5);
+        assert_eq!(node.audit_events_dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn events_beyond_a_full_queue_are_dropped_and_counted() {
+        let sink = MemoryAuditSink::new(16);
+        let (entered, first_taken) = mpsc::sync_channel(1);
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let node = NodeInstance::new().with_audit_sink(
+            {
+                let sink = sink.clone();
+                move |event| {
+                    let _ = entered.try_send(());
+                    let _ = released.lock().unwrap().recv();
+                    sink.record(event)
+                }
+            },
+            1,
+        );
+        let group = Address::new(Protocol::GROUP, Identity::new("g"));
+        node.group_add(group.clone(), addr("m0"));
+        // the sink is busy with the first event, the queue holds the second
+        first_taken.recv().unwrap();
+        for member in ["m1", "m2", "m3"] {
+            node.group_add(group.clone(), addr(member));
+        }
+        drop(release);
+        let events = wait_for(&sink, 2);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].addresses, [group, addr("m1")]);
+        assert_eq!(node.audit_events_dropped(), 2);
+        assert_eq!(node.metrics_snapshot().audit_events_dropped, 2);
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::sync::broadcast;
+
+use crate::{
+    Address, BackendEvents, BoxFuture, BoxResult, BoxStream, DataBackend, Identity, IdentityAlias,
+    NodeEvent, NodeInstance, RetryPolicy,
+};
+
+/// Retries failed [`DataBackend`] calls on `inner` according to a [`RetryPolicy`].
+///
+/// Only errors are retried; `Ok(None)` is a definitive "no route" and is
+/// returned straight away.
+pub struct RetryingBackend<B> {
+    inner: Arc<B>,
+    policy: RetryPolicy,
+}
+
+impl<B: DataBackend> RetryingBackend<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            policy,
+        }
+    }
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B: DataBackend + 'static> DataBackend for RetryingBackend<B> {
+    fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let addr = addr.clone();
+        Box::pin(async move { policy.retry(|| inner.get_next(&addr)).await })
+    }
+
+    fn set_next(
+        &self,
+        addr: &Address,
+        next: Option<&Address>,
+    ) -> BoxFuture<BoxResult<Option<Address>>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let addr = addr.clone();
+        let next = next.cloned();
+        Box::pin(async move { policy.retry(|| inner.set_next(&addr, next.as_ref())).await })
+    }
+
+    fn set_next_batch(&self, routes: Vec<(Address, Option<Address>)>) -> BoxFuture<BoxResult<()>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        Box::pin(async move { policy.retry(|| inner.set_next_batch(routes.clone())).await })
+    }
+
+    fn watch(&self) -> Option<BoxStream<RouteChange>> {
+        self.inner.watch()
+    }
+
+    fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+        self.inner.scan()
+    }
+
+    fn touch(&self, addr: &Address, at_ms: u64) -> BoxFuture<BoxResult<()>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let addr = addr.clone();
+        Box::pin(async move { policy.retry(|| inner.touch(&addr, at_ms)).await })
+    }
+
+    fn get_alias(&self, old: &Identity) -> BoxFuture<BoxResult<Option<IdentityAlias>>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let old = old.clone();
+        Box::pin(async move { policy.retry(|| inner.get_alias(&old)).await })
+    }
+
+    fn set_alias(&self, old: &Identity, alias: Option<&IdentityAlias>) -> BoxFuture<BoxResult<()>> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let old = old.clone();
+        let alias = alias.cloned();
+        Box::pin(async move { policy.retry(|| inner.set_alias(&old, alias.as_ref())).await })
+    }
+
+    fn attach_events(&self, events: BackendEvents) {
+        self.inner.attach_events(events)
+    }
+}
+
+/// A route a [`DataBackend`] stored, replaced or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteChange {
+    pub addr: Address,
+    pub old: Option<Address>,
+    pub new: Option<Address>,
+}
+
+/// A route as [scanned](DataBackend::scan) from a [`DataBackend`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredRoute {
+    pub addr: Address,
+    pub next: Address,
+    /// When the route was last [used](DataBackend::touch), in Unix milliseconds.
+    pub last_used_ms: Option<u64>,
+}
+
+/// Changes a watcher may fall behind by before it misses some.
+const WATCH_CAPACITY: usize = 256;
+
+/// A [`DataBackend`] keeping routes in memory and announcing every change to
+/// its [watchers](DataBackend::watch).
+pub struct MemoryBackend {
+    /// Next hops and when they were last used.
+    routes: RwLock<HashMap<Address, (Address, Option<u64>)>>,
+    aliases: RwLock<HashMap<Identity, IdentityAlias>>,
+    changes: broadcast::Sender<RouteChange>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            routes: Default::default(),
+            aliases: Default::default(),
+            changes: broadcast::channel(WATCH_CAPACITY).0,
+        }
+    }
+}
+
+impl DataBackend for MemoryBackend {
+    fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+        let next = self
+            .routes
+            .read()
+            .unwrap()
+            .get(addr)
+            .map(|(next, _)| next.clone());
+        Box::pin(std::future::ready(Ok(next)))
+    }
+
+    fn set_next(
+        &self,
+        addr: &Address,
+        next: Option<&Address>,
+    ) -> BoxFuture<BoxResult<Option<Address>>> {
+        let old = {
+            let mut routes = self.routes.write().unwrap();
+            match next {
+                Some(next) => {
+                    let last_used = routes.get(addr).and_then(|(_, last_used)| *last_used);
+                    routes.insert(addr.clone(), (next.clone(), last_used))
+                }
+                None => routes.remove(addr),
+            }
+            .map(|(old, _)| old)
+        };
+        if old.as_ref() != next {
+            // no watchers is fine
+            let _ = self.changes.send(RouteChange {
+                addr: addr.clone(),
+                old: old.clone(),
+                new: next.cloned(),
+            });
+        }
+        Box::pin(std::future::ready(Ok(old)))
+    }
+
+    fn watch(&self) -> Option<BoxStream<RouteChange>> {
+        Some(Box::pin(ChangeStream::new(self.changes.subscribe())))
+    }
+
+    fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+        let routes: Vec<_> = self
+            .routes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, (next, last_used_ms))| StoredRoute {
+                addr: addr.clone(),
+                next: next.clone(),
+                last_used_ms: *last_used_ms,
+            })
+            .collect();
+        Some(Box::pin(IterStream(routes.into_iter())))
+    }
+
+    fn touch(&self, addr: &Address, at_ms: u64) -> BoxFuture<BoxResult<()>> {
+        if let Some((_, last_used)) = self.routes.write().unwrap().get_mut(addr) {
+            *last_used = Some(at_ms);
+        }
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn get_alias(&self, old: &Identity) -> BoxFuture<BoxResult<Option<IdentityAlias>>> {
+        let alias = self.aliases.read().unwrap().get(old).cloned();
+        Box::pin(std::future::ready(Ok(alias)))
+    }
+
+    fn set_alias(&self, old: &Identity, alias: Option<&IdentityAlias>) -> BoxFuture<BoxResult<()>> {
+        let mut aliases = self.aliases.write().unwrap();
+        match alias {
+            Some(alias) => aliases.insert(old.clone(), alias.clone()),
+            None => aliases.remove(old),
+        };
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// An iterator as a stream that is always ready.
+struct IterStream<I>(I);
+
+impl<I: Iterator + Unpin> Stream for IterStream<I> {
+    type Item = I::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<I::Item>> {
+        Poll::Ready(self.0.next())
+    }
+}
+
+type Recv = BoxFuture<(
+    Result<RouteChange, broadcast::error::RecvError>,
+    broadcast::Receiver<RouteChange>,
+)>;
+
+/// Broadcast receiver as a stream. Changes missed by lagging behind are skipped.
+struct ChangeStream {
+    recv: Recv,
+}
+
+impl ChangeStream {
+    fn new(receiver: broadcast::Receiver<RouteChange>) -> Self {
+        Self {
+            recv: Self::recv(receiver),
+        }
+    }
+    fn recv(mut receiver: broadcast::Receiver<RouteChange>) -> Recv {
+        Box::pin(async move { (receiver.recv().await, receiver) })
+    }
+}
+
+impl Stream for ChangeStream {
+    type Item = RouteChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<RouteChange>> {
+        loop {
+            let (result, receiver) = ready!(self.recv.as_mut().poll(cx));
+            self.recv = Self::recv(receiver);
+            match result {
+                Ok(change) => return Poll::Ready(Some(change)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Start dropping cached routes the backend reports as changed, and hand the
+    /// backend the node's events. This waits for the first lookup inside a tokio
+    /// runtime so that configuring a node, or looking up routes without a
+    /// runtime, needs none.
+    pub(crate) fn start_backend_watch(&self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let Some(changes) = self.backend_watch.lock().unwrap().take() else {
+            return;
+        };
+        if let Some(backend) = &self.backend {
+            backend.attach_events(BackendEvents::new(&self.events, self.clock.clone()));
+        }
+        let Some(mut changes) = changes else {
+            return;
+        };
+        let cache = Arc::downgrade(&self.next_cache);
+        let interner = self.interner.clone();
+        let canonicalizers = self.canonicalizers.clone();
+        let events = self.events.downgrade();
+        let clock = self.clock.clone();
+        runtime.spawn(async move {
+            while let Some(change) = poll_fn(|cx| changes.as_mut().poll_next(cx)).await {
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                let addr = change.addr.canonical(&canonicalizers);
+                let key = match &interner {
+                    Some(interner) => interner.get(&addr),
+                    None => Some((&addr).into()),
+                };
+                let Some(key) = key else {
+                    continue;
+                };
+                if cache.write().unwrap().remove(&key).is_some() {
+                    if let Some(events) = events.upgrade() {
+                        events.publish(&*clock, || NodeEvent::RouteEvicted { destination: addr });
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::testing::{addr, eventually, next, Shared, TestError};
+
+    /// Fails the first `failures` lookups, then answers from `inner`.
+    struct Flaky {
+        inner: MemoryBackend,
+        failures: usize,
+        calls: AtomicUsize,
+    }
+
+    impl DataBackend for Flaky {
+        fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Box::pin(async { Err(Box::new(TestError("flaky")) as _) });
+            }
+            self.inner.get_next(addr)
+        }
+        fn set_next(
+            &self,
+            addr: &Address,
+            next: Option<&Address>,
+        ) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.inner.set_next(addr, next)
+        }
+    }
+
+    async fn flaky(failures: usize, policy: RetryPolicy) -> RetryingBackend<Flaky> {
+        let inner = MemoryBackend::new();
+        inner
+            .set_next(&addr("dest"), Some(&addr("b")))
+            .await
+            .unwrap();
+        let flaky = Flaky {
+            inner,
+            failures,
+            calls: AtomicUsize::new(0),
+        };
+        let backoff = Duration::from_millis(10);
+        RetryingBackend::new(flaky, policy.with_backoff(backoff, backoff))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retrying_backends_succeed_on_a_later_attempt() {
+        let backend = flaky(2, RetryPolicy::new(3)).await;
+        assert_eq!(
+            backend.get_next(&addr("dest")).await.unwrap(),
+            Some(addr("b"))
+        );
+        assert_eq!(backend.inner().calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retrying_backends_give_up_after_the_last_attempt() {
+        let backend = flaky(3, RetryPolicy::new(3)).await;
+        assert!(backend.get_next(&addr("dest")).await.is_err());
+        assert_eq!(backend.inner().calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_routes_are_not_retried() {
+        let backend = flaky(0, RetryPolicy::new(3)).await;
+        assert_eq!(backend.get_next(&addr("elsewhere")).await.unwrap(), None);
+        assert_eq!(backend.inner().calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn set_next_announces_the_change_and_evicts_the_cached_route() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut changes = backend.watch().unwrap();
+        let (destination, b, c) = (addr("dest"), addr("b"), addr("c"));
+        backend.set_next(&destination, Some(&b)).await.unwrap();
+        assert_eq!(
+            next(&mut changes).await,
+            Some(RouteChange {
+                addr: destination.clone(),
+                old: None,
+                new: Some(b.clone()),
+            })
+        );
+
+        let node = NodeInstance::new().with_backend(Shared(backend.clone()));
+        assert_eq!(node.resolve_next(&destination).await.unwrap(), b);
+        assert_eq!(node.iter_routes(), vec![(destination.clone(), b.clone())]);
+
+        backend.set_next(&destination, Some(&c)).await.unwrap();
+        assert_eq!(next(&mut changes).await.unwrap().old.as_ref(), Some(&b));
+        eventually(|| node.iter_routes().is_empty()).await;
+        assert_eq!(node.resolve_next(&destination).await.unwrap(), c);
+    }
+}
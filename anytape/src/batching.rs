@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    panic, receipt::Recorded, Address, ExecutorCapabilities, ExecutorPanic, Identity, Message,
+    MessageStatus, NodeInstance, Protocol, ProtocolExecutor, SendContext, SendError, SendFailure,
+    SendOutcome, SendReceipt, FRAGMENT_HEADER,
+};
+
+const DEFAULT_MAX_BATCH_SIZE: usize = 16;
+const DEFAULT_MAX_LINGER: Duration = Duration::from_millis(5);
+
+#[derive(Debug)]
+pub enum BatchError<E> {
+    /// The inner executor rejected this message.
+    Inner(E),
+    /// The inner executor failed the whole batch this message was part of.
+    BatchFailed(Arc<E>),
+    /// The batch was flushed but the inner executor returned no result for this message.
+    MissingResult,
+    /// The batch was dropped before it was flushed, e.g. because the runtime shut down.
+    Dropped,
+    /// The inner executor panicked while sending the batch.
+    Panicked(ExecutorPanic),
+}
+
+impl<E: fmt::Display> fmt::Display for BatchError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::Inner(e) => write!(f, "{e}"),
+            BatchError::BatchFailed(e) => write!(f, "batch failed: {e}"),
+            BatchError::MissingResult => write!(f, "executor returned no result for message"),
+            BatchError::Dropped => write!(f, "batch dropped before flush"),
+            BatchError::Panicked(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for BatchError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BatchError::Inner(e) => Some(e),
+            BatchError::BatchFailed(e) => Some(e.as_ref()),
+            BatchError::Panicked(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Gets the result of its message, and what the executor recorded for it while
+/// the batch was sent by another task.
+type Waiter<E> = oneshot::Sender<(Result<SendOutcome, BatchError<E>>, Recorded)>;
+
+struct Batch<E> {
+    id: u64,
+    messages: Vec<Message>,
+    waiters: Vec<Waiter<E>>,
+}
+
+/// Buffers sends per remote and hands them to the inner executor's
+/// [`send_batch`](ProtocolExecutor::send_batch) once `max_batch_size` messages
+/// have accumulated or the first of them has waited `max_linger`.
+///
+/// Each send resolves with its own result from the batch, and what the inner
+/// executor [recorded](SendContext) for its message counts towards it, such as
+/// the attempts of a [`RetryingExecutor`](crate::RetryingExecutor). Must be used
+/// inside a tokio runtime, which drives the flushes.
+pub struct BatchingExecutor<E: ProtocolExecutor> {
+    inner: Arc<E>,
+    pending: Arc<Mutex<HashMap<Identity, Batch<E::Error>>>>,
+    next_batch_id: Arc<AtomicU64>,
+    max_batch_size: usize,
+    max_linger: Duration,
+}
+
+impl<E: ProtocolExecutor> BatchingExecutor<E> {
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            pending: Default::default(),
+            next_batch_id: Default::default(),
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_linger: DEFAULT_MAX_LINGER,
+        }
+    }
+    pub fn with_max_batch_size(self, max_batch_size: usize) -> Self {
+        Self {
+            max_batch_size: max_batch_size.max(1),
+            ..self
+        }
+    }
+    pub fn with_max_linger(self, max_linger: Duration) -> Self {
+        Self { max_linger, ..self }
+    }
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+}
+
+async fn flush<E>(inner: Arc<E>, remote: Identity, batch: Batch<E::Error>)
+where
+    E: ProtocolExecutor,
+    E::Error: Sync,
+{
+    let Batch {
+        messages, waiters, ..
+    } = batch;
+    let count = waiters.len();
+    let waiters = waiters.into_iter().enumerate();
+    // a panicking executor fails this batch only, not later ones
+    let (result, recorded) =
+        SendContext::scope(panic::catch_unwind(|| inner.send_batch(&remote, messages))).await;
+    let result = match result {
+        Ok(result) => result,
+        Err(panic) => {
+            for (index, waiter) in waiters {
+                let recorded = recorded.for_message(index, count);
+                let _ = waiter.send((Err(BatchError::Panicked(panic.clone())), recorded));
+            }
+            return;
+        }
+    };
+    match result {
+        Ok(results) => {
+            let mut results = results.into_iter();
+            for (index, waiter) in waiters {
+                let result = match results.next() {
+                    Some(result) => result.map_err(BatchError::Inner),
+                    None => Err(BatchError::MissingResult),
+                };
+                let _ = waiter.send((result, recorded.for_message(index, count)));
+            }
+        }
+        Err(e) => {
+            let e = Arc::new(e);
+            for (index, waiter) in waiters {
+                let recorded = recorded.for_message(index, count);
+                let _ = waiter.send((Err(BatchError::BatchFailed(e.clone())), recorded));
+            }
+        }
+    }
+}
+
+impl<E> ProtocolExecutor for BatchingExecutor<E>
+where
+    E: ProtocolExecutor + Send + Sync + 'static,
+    E::Error: Sync,
+{
+    type Error = BatchError<E::Error>;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let inner = self.inner.clone();
+        let pending = self.pending.clone();
+        let next_batch_id = self.next_batch_id.clone();
+        let max_batch_size = self.max_batch_size;
+        let max_linger = self.max_linger;
+        let remote = remote.clone();
+        async move {
+            let (tx, rx) = oneshot::channel();
+            let (full, linger) = {
+                let mut pending = pending.lock().unwrap();
+                let batch = pending.entry(remote.clone()).or_insert_with(|| Batch {
+                    id: next_batch_id.fetch_add(1, Ordering::Relaxed),
+                    messages: Vec::new(),
+                    waiters: Vec::new(),
+                });
+                batch.messages.push(message);
+                batch.waiters.push(tx);
+                let linger = (batch.messages.len() == 1).then_some(batch.id);
+                let full = if batch.messages.len() >= max_batch_size {
+                    pending.remove(&remote)
+                } else {
+                    None
+                };
+                (full, linger)
+            };
+            if let Some(batch) = full {
+                tokio::spawn(flush(inner, remote, batch));
+            } else if let Some(batch_id) = linger {
+                tokio::spawn(async move {
+                    tokio::time::sleep(max_linger).await;
+                    let batch = {
+                        let mut pending = pending.lock().unwrap();
+                        // the batch may already have been flushed for being full,
+                        // in which case a newer one is collecting under this remote
+                        match pending.get(&remote) {
+                            Some(batch) if batch.id == batch_id => pending.remove(&remote),
+                            _ => None,
+                        }
+                    };
+                    if let Some(batch) = batch {
+                        flush(inner, remote, batch).await;
+                    }
+                });
+            }
+            let (result, recorded) = rx
+                .await
+                .unwrap_or_else(|_| (Err(BatchError::Dropped), Recorded::default()));
+            // the batch ran in a task of its own, outside this send
+            SendContext::replay(&recorded);
+            result
+        }
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let fut = self.inner.get_status(remote, message);
+        async move { fut.await.map_err(BatchError::Inner) }
+    }
+
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> impl Future<Output = Result<Vec<Result<SendOutcome, Self::Error>>, Self::Error>> + Send + 'static
+    {
+        let fut = self.inner.send_batch(remote, messages);
+        async move {
+            match fut.await {
+                Ok(results) => Ok(results
+                    .into_iter()
+                    .map(|result| result.map_err(BatchError::Inner))
+                    .collect()),
+                Err(e) => Err(BatchError::Inner(e)),
+            }
+        }
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.close()
+    }
+}
+
+/// The error of a batch the executor failed as a whole, handed to each of its
+/// messages. Keeps the kind of an underlying I/O error.
+fn batch_failure(error: &(dyn std::error::Error + 'static)) -> io::Error {
+    let mut kind = io::ErrorKind::Other;
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(io) = error.downcast_ref::<io::Error>() {
+            kind = io.kind();
+            break;
+        }
+        current = error.source();
+    }
+    io::Error::new(kind, format!("batch failed: {error}"))
+}
+
+impl NodeInstance {
+    /// Send `messages` to the next hop `to` through one
+    /// [`send_batch`](ProtocolExecutor::send_batch) call of its executor, returning
+    /// one result per message in order.
+    ///
+    /// Each message is checked like in [`NodeInstance::send`]; those that fail the
+    /// checks are left out of the batch, and those that have to be split are sent
+    /// on their own. The [send timeout](NodeInstance::with_send_timeout) applies
+    /// to the batch as a whole.
+    pub async fn send_batch(
+        &self,
+        messages: Vec<Message>,
+        to: impl Into<Address>,
+    ) -> Vec<Result<(), SendError>> {
+        let to = to.into();
+        let reports: Vec<_> = messages
+            .iter()
+            .map(|message| (message.unique_id, self.path_for_report(message)))
+            .collect();
+        let results = if to.protocol == Protocol::GROUP {
+            let mut results = Vec::with_capacity(messages.len());
+            for message in messages {
+                results.push(self.multicast(message, &to).await.map(|()| None));
+            }
+            results
+        } else {
+            self.send_batch_once(messages, &to)
+                .await
+                .into_iter()
+                .map(|result| result.map(Some))
+                .collect()
+        };
+        results
+            .into_iter()
+            .zip(reports)
+            .map(|(result, (unique_id, path))| match result {
+                Ok(None) => Ok(()),
+                Ok(Some(receipt)) => {
+                    let result = Ok(receipt);
+                    self.report_send(&to, unique_id, path, &result);
+                    Ok(())
+                }
+                Err(error) => {
+                    let result = Err(error);
+                    self.report_send(&to, unique_id, path, &result);
+                    result.map(|_| ())
+                }
+            })
+            .collect()
+    }
+    async fn send_batch_once(
+        &self,
+        messages: Vec<Message>,
+        to: &Address,
+    ) -> Vec<Result<SendReceipt, SendError>> {
+        let prepared = match self.ready().await {
+            Ok(permit) => self
+                .executor_for_send(to, None)
+                .await
+                .map(|executor| (permit, executor)),
+            Err(error) => Err(error),
+        };
+        let Ok((_permit, executor)) = prepared else {
+            // let every message fail on its own, with its own error
+            let mut results = Vec::with_capacity(messages.len());
+            for message in messages {
+                results.push(self.send_once(message, to).await);
+            }
+            return results;
+        };
+        let max_size = executor.capabilities().max_message_size;
+        let mut results: Vec<_> = messages.iter().map(|_| None).collect();
+        let mut batch = Vec::new();
+        let mut slots = Vec::new();
+        for (slot, message) in messages.into_iter().enumerate() {
+            let message = match self.admit_send(message, to).await {
+                Ok(message) => message,
+                Err(error) => {
+                    results[slot] = Some(Err(error));
+                    continue;
+                }
+            };
+            // fragments are never split again
+            if max_size != usize::MAX
+                && !message.headers.contains_key(FRAGMENT_HEADER)
+                && message.encode().len() > max_size
+            {
+                results[slot] = Some(self.transmit(&executor, None, message, to).await);
+                continue;
+            }
+            slots.push((slot, message.unique_id));
+            batch.push(message);
+        }
+        if !batch.is_empty() {
+            let start = tokio::time::Instant::now();
+            let send = SendContext::scope(executor.send_batch(&to.identity, batch));
+            let sent = match self.send_timeout_for(&to.protocol) {
+                Some(timeout) => tokio::time::timeout(timeout, send).await.ok(),
+                None => Some(send.await),
+            };
+            let elapsed = start.elapsed();
+            let registration = self.registered_as(&to.protocol, None);
+            let count = slots.len();
+            let receipt = |unique_id, attempts: u32, outcome| SendReceipt {
+                protocol: to.protocol.clone(),
+                registration: registration.clone(),
+                unique_id,
+                elapsed,
+                attempts: attempts.max(1),
+                outcome,
+            };
+            match sent {
+                None => {
+                    for (slot, _) in slots {
+                        results[slot] = Some(Err(SendError::DeadlineExceeded));
+                    }
+                }
+                Some((Ok(sent), recorded)) => {
+                    let mut sent = sent.into_iter();
+                    for (index, (slot, unique_id)) in slots.into_iter().enumerate() {
+                        let attempts = recorded.for_message(index, count).attempts;
+                        results[slot] = Some(match sent.next() {
+                            Some(Ok(outcome)) => Ok(receipt(unique_id, attempts, outcome)),
+                            Some(Err(error)) => {
+                                Err(self.executor_failure(to, None, attempts.max(1), error))
+                            }
+                            None => Err(self.executor_failure(
+                                to,
+                                None,
+                                attempts.max(1),
+                                Box::new(BatchError::<io::Error>::MissingResult),
+                            )),
+                        });
+                    }
+                }
+                Some((Err(error), recorded)) => {
+                    let attempts = recorded.attempts;
+                    if let Some(panic) = ExecutorPanic::find(&*error) {
+                        self.record_executor_panic(registration, None);
+                        let message = panic.message.clone();
+                        for (slot, _) in slots {
+                            results[slot] = Some(Err(SendError::ExecutorPanicked {
+                                message: message.clone(),
+                            }));
+                        }
+                    } else {
+                        for (slot, _) in slots {
+                            let failure = Box::new(batch_failure(&*error));
+                            results[slot] = Some(Err(SendError::ExecutorError(SendFailure::new(
+                                to,
+                                attempts.max(1),
+                                failure,
+                            ))));
+                        }
+                    }
+                }
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every message has a result"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{RetryPolicy, RetryingExecutor, SendOutcome};
+
+    fn retrying(inner: Recorder) -> RetryingExecutor<Recorder> {
+        let backoff = Duration::from_millis(1);
+        RetryingExecutor::new(inner, RetryPolicy::new(3).with_backoff(backoff, backoff))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_within_the_linger_arrive_as_one_batch() {
+        let recorder = Recorder::new();
+        let batching = BatchingExecutor::new(recorder.clone())
+            .with_max_batch_size(8)
+            .with_max_linger(Duration::from_millis(50));
+        let node = NodeInstance::new().with_executor(TEST, batching);
+        let send = |payload: &[u8]| node.send(message(addr("b"), payload), addr("b"));
+        let results = tokio::join!(send(b"x"), send(b"y"), send(b"z"));
+        assert!(results.0.is_ok() && results.1.is_ok() && results.2.is_ok());
+        assert_eq!(recorder.batches(), [3]);
+        let payloads: Vec<_> = recorder.sent().iter().map(|m| m.payload.clone()).collect();
+        assert_eq!(payloads, [b"x", b"y", b"z"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_batches_go_out_without_waiting_for_the_linger() {
+        let recorder = Recorder::new();
+        let batching = BatchingExecutor::new(recorder.clone())
+            .with_max_batch_size(2)
+            .with_max_linger(Duration::from_secs(3600));
+        let node = NodeInstance::new().with_executor(TEST, batching);
+        let start = tokio::time::Instant::now();
+        let (first, second) = tokio::join!(
+            node.send(message(addr("b"), b"x"), addr("b")),
+            node.send(message(addr("b"), b"y"), addr("b")),
+        );
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(recorder.batches(), [2]);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_linger_flushes_a_lone_message() {
+        let recorder = Recorder::new();
+        let linger = Duration::from_millis(50);
+        let batching = BatchingExecutor::new(recorder.clone()).with_max_linger(linger);
+        let node = NodeInstance::new().with_executor(TEST, batching);
+        let start = tokio::time::Instant::now();
+        node.send(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(recorder.batches(), [1]);
+        assert_eq!(start.elapsed(), linger);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acknowledgements_come_through_a_batch() {
+        let recorder = Recorder::new().with_outcome(SendOutcome::received(Some(7)));
+        let node = NodeInstance::new().with_executor(TEST, BatchingExecutor::new(recorder));
+        let receipt = node
+            .send_detailed(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.outcome, SendOutcome::received(Some(7)));
+    }
+
+    /// The receipts the node reported, in the order it reported them.
+    fn receipts(node: NodeInstance) -> (NodeInstance, Arc<Mutex<Vec<SendReceipt>>>) {
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+        let node = node.with_on_send_result({
+            let receipts = receipts.clone();
+            move |_, _, result| {
+                if let Ok(receipt) = result {
+                    receipts.lock().unwrap().push(receipt.clone());
+                }
+            }
+        });
+        (node, receipts)
+    }
+
+    #[tokio::test]
+    async fn node_batches_report_the_outcome_of_each_message() {
+        let recorder = Recorder::new().with_outcome(SendOutcome::received(Some(7)));
+        let (node, receipts) = receipts(NodeInstance::new().with_executor(TEST, recorder));
+        let batch = vec![message(addr("b"), b"x"), message(addr("b"), b"y")];
+        let results = node.send_batch(batch, addr("b")).await;
+        assert!(results.iter().all(Result::is_ok));
+        let outcomes: Vec<_> = receipts.lock().unwrap().iter().map(|r| r.outcome).collect();
+        assert_eq!(outcomes, [SendOutcome::received(Some(7)); 2]);
+    }
+
+    #[tokio::test]
+    async fn node_batches_reach_the_executor_once_and_in_order() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+        let payloads: [&[u8]; 4] = [b"1", b"2", b"3", b"4"];
+        let batch = payloads.iter().map(|p| message(addr("b"), p)).collect();
+        let results = node.send_batch(batch, addr("b")).await;
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(recorder.batches(), [4]);
+        let sent: Vec<_> = recorder.sent().into_iter().map(|m| m.payload).collect();
+        assert_eq!(sent, payloads);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_behind_a_batch_count_towards_the_send() {
+        let inner = retrying(Recorder::new().failing_first(2));
+        let node = NodeInstance::new().with_executor(TEST, BatchingExecutor::new(inner));
+        let receipt = node
+            .send_detailed(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.attempts, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_count_towards_their_own_message_of_a_batch() {
+        let inner = retrying(Recorder::new().failing_first(2));
+        let batching = BatchingExecutor::new(inner).with_max_batch_size(2);
+        let node = NodeInstance::new().with_executor(TEST, batching);
+        let (first, second) = tokio::join!(
+            node.send_detailed(message(addr("b"), b"x"), addr("b")),
+            node.send_detailed(message(addr("b"), b"y"), addr("b")),
+        );
+        assert_eq!((first.unwrap().attempts, second.unwrap().attempts), (3, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn node_batches_count_retries_per_message() {
+        let inner = retrying(Recorder::new().failing_first(2));
+        let (node, receipts) = receipts(NodeInstance::new().with_executor(TEST, inner));
+        let batch = vec![message(addr("b"), b"x"), message(addr("b"), b"y")];
+        let results = node.send_batch(batch, addr("b")).await;
+        assert!(results.iter().all(Result::is_ok));
+        let attempts: Vec<_> = receipts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.attempts)
+            .collect();
+        assert_eq!(attempts, [3, 1]);
+    }
+}
@@ -0,0 +1,347 @@
+//! A synchronous front for applications without an async runtime.
+//!
+//! [`Node`] owns a [`NodeInstance`] together with a tokio runtime that drives
+//! it, and offers the common operations as blocking calls. Every call checks
+//! that it is not made from inside an async context, where blocking on the
+//! runtime could deadlock, and fails with [`BlockingError::InAsyncContext`]
+//! instead.
+//!
+//! Dropping a [`Node`] [shuts the node down](NodeInstance::shutdown) and gives
+//! its runtime a bounded time to finish what is running.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{mpsc, Arc, Mutex, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    runtime::{self, Runtime},
+    sync::oneshot,
+};
+
+use crate::{
+    control::{ControlKind, ControlMessage},
+    random_u64, Address, Message, NodeHandle, NodeInstance, SendError,
+};
+
+/// How long dropping a [`Node`] waits for running tasks by default.
+pub const DEFAULT_SHUTDOWN_WAIT: Duration = Duration::from_secs(5);
+/// How often a blocked [`Incoming`] checks whether the node was shut down.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum BlockingError {
+    /// A blocking call was made from inside an async context.
+    InAsyncContext,
+    /// The runtime could not be built.
+    Runtime(io::Error),
+    Send(SendError),
+    /// No answer arrived in time.
+    Timeout,
+}
+
+impl fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockingError::InAsyncContext => {
+                write!(f, "blocking call made from within an async context")
+            }
+            BlockingError::Runtime(e) => write!(f, "failed to build runtime: {e}"),
+            BlockingError::Send(e) => write!(f, "send failed: {e:?}"),
+            BlockingError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for BlockingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlockingError::Runtime(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SendError> for BlockingError {
+    fn from(e: SendError) -> Self {
+        BlockingError::Send(e)
+    }
+}
+
+fn ensure_blocking_allowed() -> Result<(), BlockingError> {
+    match runtime::Handle::try_current() {
+        Ok(_) => Err(BlockingError::InAsyncContext),
+        Err(_) => Ok(()),
+    }
+}
+
+/// `control` wrapped for the node at `to`, with `node` as its origin so the
+/// answer finds its way back.
+fn control_message(node: &NodeInstance, control: ControlMessage, to: Address) -> Message {
+    let source = node.source_address(&to);
+    let mut message = control.into_message(to);
+    if let Some(source) = source {
+        node.mark(source, &mut message);
+    }
+    message
+}
+
+/// Pings waiting for their pong, keyed by nonce.
+#[derive(Default)]
+struct PingTable {
+    waiters: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+}
+
+/// A node driven by its own runtime; see the [module docs](self).
+pub struct Node {
+    node: NodeHandle,
+    runtime: Option<Runtime>,
+    incoming: Mutex<mpsc::Receiver<Message>>,
+    pings: Arc<PingTable>,
+    shutdown_wait: Duration,
+}
+
+impl Node {
+    /// Run `node` on a multi-thread runtime.
+    pub fn new(node: NodeInstance) -> Result<Self, BlockingError> {
+        ensure_blocking_allowed()?;
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(BlockingError::Runtime)?;
+        Ok(Self::with_runtime(node, runtime))
+    }
+    /// Run `node` on a current-thread runtime, which only makes progress while
+    /// a blocking call is waiting.
+    pub fn current_thread(node: NodeInstance) -> Result<Self, BlockingError> {
+        ensure_blocking_allowed()?;
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(BlockingError::Runtime)?;
+        Ok(Self::with_runtime(node, runtime))
+    }
+    /// Received messages go to [`Node::incoming_iter`] unless `node` already has
+    /// a receive handler, and pings are answered unless it already has a
+    /// handler for [`ControlKind::Ping`].
+    fn with_runtime(mut node: NodeInstance, runtime: Runtime) -> Self {
+        let (tx, rx) = mpsc::channel();
+        if node.handler.is_none() {
+            let tx = Mutex::new(tx);
+            node = node.with_handler(move |message: Message| {
+                // nobody reading incoming messages is fine
+                let _ = tx.lock().unwrap().send(message);
+                async {}
+            });
+        }
+        let pings = Arc::new(PingTable::default());
+        let this: Arc<OnceLock<Weak<NodeInstance>>> = Arc::default();
+        if !node.control.handlers.contains_key(&ControlKind::Ping) {
+            let pings = pings.clone();
+            let this = this.clone();
+            node = node.with_control_handler(
+                ControlKind::Ping,
+                move |control: ControlMessage, envelope: Message| {
+                    let pings = pings.clone();
+                    let node = this.get().and_then(Weak::upgrade);
+                    async move {
+                        let ControlMessage::Ping { nonce, pong } = control else {
+                            return;
+                        };
+                        if pong {
+                            if let Some(waiter) = pings.waiters.lock().unwrap().remove(&nonce) {
+                                let _ = waiter.send(());
+                            }
+                            return;
+                        }
+                        let (Some(node), Some(origin)) =
+                            (node, envelope.path.first().and_then(|n| n.address.clone()))
+                        else {
+                            return;
+                        };
+                        let answer = ControlMessage::Ping { nonce, pong: true };
+                        // an unreachable pinger times out on its own
+                        let _ = node.forward(control_message(&node, answer, origin)).await;
+                    }
+                },
+            );
+        }
+        let node = node.handle();
+        let _ = this.set(Arc::downgrade(node.arc()));
+        Self {
+            node,
+            runtime: Some(runtime),
+            incoming: Mutex::new(rx),
+            pings,
+            shutdown_wait: DEFAULT_SHUTDOWN_WAIT,
+        }
+    }
+    /// Wait up to `wait` for running tasks when dropped.
+    pub fn with_shutdown_wait(mut self, wait: Duration) -> Self {
+        self.shutdown_wait = wait;
+        self
+    }
+    pub fn node(&self) -> &NodeInstance {
+        self.node.node()
+    }
+    pub fn handle(&self) -> &NodeHandle {
+        &self.node
+    }
+    fn block_on<T>(
+        &self,
+        future: impl std::future::Future<Output = T>,
+    ) -> Result<T, BlockingError> {
+        ensure_blocking_allowed()?;
+        let runtime = self.runtime.as_ref().expect("runtime lives until drop");
+        Ok(runtime.block_on(future))
+    }
+    /// Send `payload` to `to`; see [`Sender::send_to`](crate::Sender::send_to).
+    pub fn send(
+        &self,
+        to: impl Into<Address>,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), BlockingError> {
+        let sender = self.node.sender();
+        Ok(self.block_on(sender.send_to(to, payload))??)
+    }
+    /// Send `payload` to `to` and wait up to `timeout` for the reply; see
+    /// [`Sender::send_and_wait_reply`](crate::Sender::send_and_wait_reply).
+    pub fn send_and_wait_reply(
+        &self,
+        to: impl Into<Address>,
+        payload: impl Into<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<Message, BlockingError> {
+        let sender = self.node.sender_to(to);
+        Ok(self.block_on(sender.send_and_wait_reply(payload, timeout))??)
+    }
+    /// Answer `message` with `payload`, for the sender's
+    /// [`Node::send_and_wait_reply`].
+    pub fn reply(
+        &self,
+        message: &Message,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), BlockingError> {
+        let node = self.node.node();
+        let mut reply = message.reply(payload).ok_or(SendError::NoRoute)?;
+        if let Some(source) = node.source_address(&reply.destination) {
+            node.mark(source, &mut reply);
+        }
+        Ok(self.block_on(node.forward(reply))??)
+    }
+    /// Round trip time of a [`ControlMessage::Ping`] to `to`. The peer must
+    /// answer pings, as every [`Node`] does.
+    pub fn ping(
+        &self,
+        to: impl Into<Address>,
+        timeout: Duration,
+    ) -> Result<Duration, BlockingError> {
+        let nonce = random_u64();
+        let (tx, rx) = oneshot::channel();
+        self.pings.waiters.lock().unwrap().insert(nonce, tx);
+        let node = self.node.node();
+        let ping = control_message(node, ControlMessage::Ping { nonce, pong: false }, to.into());
+        let started = Instant::now();
+        let result = self.block_on(async {
+            node.forward(ping).await?;
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(())) => Ok(started.elapsed()),
+                Ok(Err(_)) => Err(BlockingError::Send(SendError::Shutdown)),
+                Err(_) => Err(BlockingError::Timeout),
+            }
+        });
+        self.pings.waiters.lock().unwrap().remove(&nonce);
+        result?
+    }
+    /// The messages this node receives, blocking until the next arrives. Ends
+    /// once the node is shut down.
+    pub fn incoming_iter(&self) -> Result<Incoming<'_>, BlockingError> {
+        ensure_blocking_allowed()?;
+        Ok(Incoming { node: self })
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.node.node().shutdown();
+        if let Some(runtime) = self.runtime.take() {
+            if runtime::Handle::try_current().is_ok() {
+                // waiting here would block the surrounding runtime
+                runtime.shutdown_background();
+            } else {
+                runtime.shutdown_timeout(self.shutdown_wait);
+            }
+        }
+    }
+}
+
+/// Received messages of a [`Node`]; see [`Node::incoming_iter`].
+pub struct Incoming<'a> {
+    node: &'a Node,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        let incoming = self.node.incoming.lock().unwrap();
+        loop {
+            match incoming.recv_timeout(SHUTDOWN_POLL) {
+                Ok(message) => return Some(message),
+                Err(mpsc::RecvTimeoutError::Timeout) if !self.node.node().is_shut_down() => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, Loopback, TEST};
+
+    /// Two nodes reaching each other over one loopback.
+    fn pair() -> (Node, Node) {
+        let loopback = Loopback::new();
+        let node = |name| {
+            let node = NodeInstance::new()
+                .with_address(addr(name))
+                .with_executor(TEST, loopback.clone());
+            let node = Node::new(node).unwrap();
+            loopback.attach(name, node.handle().arc().clone());
+            node
+        };
+        (node("me"), node("peer"))
+    }
+
+    #[test]
+    fn blocking_sends_reach_a_loopback_peer() {
+        let (me, peer) = pair();
+        me.send(addr("peer"), "hello").unwrap();
+        let received = peer.incoming_iter().unwrap().next().unwrap();
+        assert_eq!(received.payload, b"hello");
+        assert!(me.ping(addr("peer"), Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn blocking_calls_from_async_code_fail() {
+        let (me, _peer) = pair();
+        let runtime = runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            assert!(matches!(
+                me.send(addr("peer"), "hi"),
+                Err(BlockingError::InAsyncContext)
+            ));
+            assert!(matches!(
+                me.incoming_iter(),
+                Err(BlockingError::InAsyncContext)
+            ));
+            assert!(matches!(
+                Node::new(NodeInstance::new()),
+                Err(BlockingError::InAsyncContext)
+            ));
+        });
+    }
+}
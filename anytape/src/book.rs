@@ -0,0 +1,216 @@
+//! Human-friendly names for peers.
+//!
+//! An [`AddressBook`] maps names such as `edge-paris-2` to [`Node`]s. Names are
+//! matched case-insensitively and must be unique. A node with a book
+//! [sends by name](NodeInstance::send_to_named) and signs its path entries with
+//! the name of the address a message arrived at, unless it has a name of its own.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{Address, MessageBuilder, Node, NodeInstance, SendError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressBookError {
+    /// The name, ignoring case, is already taken.
+    DuplicateName(String),
+}
+
+impl fmt::Display for AddressBookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBookError::DuplicateName(name) => {
+                write!(f, "the address book already has an entry named {name:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressBookError {}
+
+struct Entry {
+    name: String,
+    node: Node,
+}
+
+#[derive(Default)]
+pub struct AddressBook {
+    entries: HashMap<String, Entry>,
+    names: HashMap<Address, String>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert(&mut self, name: impl Into<String>, node: Node) -> Result<(), AddressBookError> {
+        let name = name.into();
+        let key = name.to_lowercase();
+        if self.entries.contains_key(&key) {
+            return Err(AddressBookError::DuplicateName(name));
+        }
+        for address in &node.address_set {
+            self.names
+                .entry(address.clone())
+                .or_insert_with(|| name.clone());
+        }
+        self.entries.insert(key, Entry { name, node });
+        Ok(())
+    }
+    pub fn with_entry(
+        mut self,
+        name: impl Into<String>,
+        node: Node,
+    ) -> Result<Self, AddressBookError> {
+        self.insert(name, node)?;
+        Ok(self)
+    }
+    pub fn get(&self, name: &str) -> Option<&Node> {
+        self.entries
+            .get(&name.to_lowercase())
+            .map(|entry| &entry.node)
+    }
+    /// The name of the first entry listing `address`, as it was inserted.
+    pub fn name_of(&self, address: &Address) -> Option<&str> {
+        self.names.get(address).map(String::as_str)
+    }
+    /// Entries as `(name, node)` pairs, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.entries
+            .values()
+            .map(|entry| (entry.name.as_str(), &entry.node))
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A map from names to nodes, e.g. a TOML table or JSON object.
+#[cfg(feature = "serde")]
+impl serde::Serialize for AddressBook {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries: std::collections::BTreeMap<&str, &Node> = self.iter().collect();
+        serde::Serialize::serialize(&entries, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AddressBook {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries: Vec<(String, Node)> =
+            <std::collections::BTreeMap<String, Node>>::deserialize(deserializer)?
+                .into_iter()
+                .collect();
+        let mut book = AddressBook::new();
+        for (name, node) in entries {
+            book.insert(name, node).map_err(serde::de::Error::custom)?;
+        }
+        Ok(book)
+    }
+}
+
+impl NodeInstance {
+    pub fn with_address_book(mut self, book: AddressBook) -> Self {
+        self.address_book = Some(book);
+        self
+    }
+    pub fn address_book(&self) -> Option<&AddressBook> {
+        self.address_book.as_ref()
+    }
+    pub fn resolve_name(&self, name: &str) -> Option<&Node> {
+        self.address_book.as_ref()?.get(name)
+    }
+    pub fn name_of(&self, address: &Address) -> Option<&str> {
+        let book = self.address_book.as_ref()?;
+        book.name_of(address)
+            .or_else(|| book.name_of(&self.aliased(address)))
+    }
+    /// [Forward](NodeInstance::forward) `payload` to the peer called `name`,
+    /// trying its addresses in order until one send succeeds. Fails with the
+    /// last address's error, or [`SendError::UnknownName`] if the book has no
+    /// such peer.
+    pub async fn send_to_named(
+        &self,
+        name: &str,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), SendError> {
+        let node = self
+            .resolve_name(name)
+            .ok_or_else(|| SendError::UnknownName(name.to_owned()))?;
+        let payload = payload.into();
+        let mut result = Err(SendError::UnknownName(name.to_owned()));
+        for address in &node.address_set {
+            let message = MessageBuilder::new(address.clone())
+                .payload(payload.clone())
+                .build();
+            result = self.forward(message).await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    #[test]
+    fn names_are_unique_ignoring_case() {
+        let mut book = AddressBook::new()
+            .with_entry("Edge-Paris", Node::new(vec![addr("relay-1")]))
+            .unwrap();
+        assert_eq!(
+            book.insert("edge-paris", Node::new(vec![addr("relay-2")])),
+            Err(AddressBookError::DuplicateName("edge-paris".to_owned()))
+        );
+        assert_eq!(book.len(), 1);
+        assert_eq!(
+            book.get("EDGE-PARIS").unwrap().address_set,
+            [addr("relay-1")]
+        );
+        assert_eq!(book.name_of(&addr("relay-2")), None);
+    }
+
+    #[cfg(feature = "serde")]
+    const FIXTURE: &str = r#"{
+        "Edge-Paris": {
+            "address_set": ["test:72656c61792d31", "test:72656c61792d32"],
+            "capabilities": ["relay"]
+        },
+        "core": { "address_set": ["test:636f7265"] }
+    }"#;
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn books_loaded_from_a_fixture_name_peers_both_ways() {
+        use crate::testing::{message, Recorder, TEST};
+
+        let book = || serde_json::from_str::<AddressBook>(FIXTURE).unwrap();
+        assert_eq!(book().len(), 2);
+        assert_eq!(book().get("core").unwrap().address_set, [addr("core")]);
+
+        // the first address of the entry fails, the second takes the message
+        let recorder = Recorder::new().failing_first(1);
+        let sender = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_address_book(book());
+        sender.send_to_named("edge-paris", "hi").await.unwrap();
+        assert_eq!(recorder.remotes(), [addr("relay-2").identity]);
+        assert!(matches!(
+            sender.send_to_named("nowhere", "hi").await,
+            Err(SendError::UnknownName(_))
+        ));
+
+        let relay = NodeInstance::new().with_address_book(book());
+        let mut message = message(addr("dest"), b"hi");
+        relay.mark(addr("relay-1"), &mut message);
+        assert_eq!(message.path[0].name.as_deref(), Some("Edge-Paris"));
+        assert_eq!(relay.name_of(&addr("relay-2")), Some("Edge-Paris"));
+        assert!(relay.resolve_name("CORE").unwrap().capabilities.is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! End-to-end latency budgets.
+//!
+//! A message sent with [`NodeInstance::forward_with_budget`] carries the
+//! milliseconds it may still spend in transit in [`Message::budget_ms`]. Every
+//! node subtracts the time it held the message, from its arrival in
+//! [`NodeInstance::dispatch_inbound`] (or the call to `forward_with_budget` at the
+//! origin) until it is handed to an executor, measured on the node's
+//! [clock](crate::Clock) and rounded up to whole milliseconds. Time on the
+//! wire is not charged, since no two nodes share a clock.
+//!
+//! Every node [marking](NodeInstance::mark) the message records what was left
+//! of the budget when it took the message on in
+//! [`PathNode::remaining_budget_ms`](crate::PathNode::remaining_budget_ms), so
+//! the recipient sees which hops spent it, and the
+//! [path summary](crate::Message::path_summary) the node dropping an exhausted
+//! message [reports](NodeInstance::with_on_send_err) shows where it ran out.
+//! Messages without a budget record what is left until their propagated
+//! [deadline](crate::Deadline::propagated) instead.
+
+use std::{future::Future, time::Duration};
+
+use crate::{Deadline, Message, NodeInstance, SendError};
+
+tokio::task_local! {
+    static HELD_SINCE: Duration;
+}
+
+impl NodeInstance {
+    /// Forward `message` with an end-to-end latency budget of `budget`. Once a
+    /// hop finds the budget used up it drops the message with
+    /// [`SendError::BudgetExhausted`], reported upstream as
+    /// [`MessageStatus::Expired`](crate::MessageStatus::Expired).
+    pub async fn forward_with_budget(
+        &self,
+        mut message: Message,
+        budget: Duration,
+    ) -> Result<(), SendError> {
+        message.budget_ms = Some(u64::try_from(budget.as_millis()).unwrap_or(u64::MAX));
+        self.holding(self.forward(message)).await
+    }
+    /// Run `f` with the budget clock of the messages it forwards started now.
+    pub(crate) async fn holding<F: Future>(&self, f: F) -> F::Output {
+        HELD_SINCE.scope(self.clock.monotonic(), f).await
+    }
+    /// How long this node has held the message it is handling, in milliseconds
+    /// rounded up, so fast hops still use up the budget.
+    fn held_ms(&self) -> u64 {
+        let held = HELD_SINCE
+            .try_with(|since| self.clock.monotonic().saturating_sub(*since))
+            .unwrap_or_default();
+        u64::try_from(held.as_micros().div_ceil(1000)).unwrap_or(u64::MAX)
+    }
+    /// What is left of `message`'s budget after the time this node held it so
+    /// far, or else until its propagated deadline, in milliseconds.
+    pub(crate) fn remaining_budget(&self, message: &Message) -> Option<u64> {
+        match message.budget_ms {
+            Some(budget_ms) => Some(budget_ms.saturating_sub(self.held_ms())),
+            None => {
+                Some(Deadline::header_expires_at(message)?.saturating_sub(self.clock.now_millis()))
+            }
+        }
+    }
+    /// Subtract the time this node held `message` from its budget.
+    pub(crate) fn charge_budget(&self, message: &mut Message) -> Result<(), SendError> {
+        let Some(budget_ms) = message.budget_ms else {
+            return Ok(());
+        };
+        let remaining = budget_ms
+            .checked_sub(self.held_ms())
+            .ok_or(SendError::BudgetExhausted)?;
+        message.budget_ms = Some(remaining);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::transform::{ForwardTransform, TransformFuture, TransformScope};
+    use crate::virtual_net::VirtualNetwork;
+    use crate::{Address, ManualClock, MessageBuilder, PathNode};
+
+    /// Holds every message it forwards for `hold` on the shared clock.
+    struct Slow {
+        clock: ManualClock,
+        hold: Duration,
+    }
+
+    impl ForwardTransform for Slow {
+        fn transform<'a>(&'a self, _: &'a mut Message, _: &'a Address) -> TransformFuture<'a> {
+            self.clock.advance(self.hold);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn two_slow_hops_use_up_the_budget() {
+        let at = VirtualNetwork::address;
+        let mut net = VirtualNetwork::new();
+        let slow = |clock: &ManualClock| Slow {
+            clock: clock.clone(),
+            hold: Duration::from_millis(60),
+        };
+        let everywhere = TransformScope::Protocol(VirtualNetwork::PROTOCOL);
+        let clock = net.clock().clone();
+        let received = Arc::new(Mutex::new(Vec::<Vec<PathNode>>::new()));
+        let sender = net.add_node("sender", |node| node);
+        net.add_node("b", |node| {
+            node.with_forward_transform(everywhere.clone(), slow(&clock))
+        });
+        net.add_node("c", |node| {
+            node.with_forward_transform(everywhere.clone(), slow(&clock))
+        });
+        net.add_node("d", |node| {
+            let received = received.clone();
+            node.with_handler(move |message: Message| {
+                received.lock().unwrap().push(message.path);
+                async {}
+            })
+        });
+        let send = |budget_ms: u64| {
+            let mut message = MessageBuilder::new(at("d"))
+                .route_plan(vec![at("b"), at("c"), at("d")])
+                .build();
+            sender.mark(at("sender"), &mut message);
+            sender.forward_with_budget(message, Duration::from_millis(budget_ms))
+        };
+
+        send(150).await.unwrap();
+        net.run_until_idle(10).await;
+        let paths = std::mem::take(&mut *received.lock().unwrap());
+        let remaining: Vec<_> = paths[0]
+            .iter()
+            .map(|node| node.remaining_budget_ms)
+            .collect();
+        // the sender marked the message before it had a budget
+        assert_eq!(remaining, [None, Some(150), Some(90)]);
+
+        send(100).await.unwrap();
+        let hops = net.run_until_idle(10).await;
+        let visited: Vec<_> = hops.iter().map(|hop| hop.to.as_bytes().to_vec()).collect();
+        assert_eq!(visited, [b"b", b"c"]);
+        assert!(matches!(hops[1].result, Err(SendError::BudgetExhausted)));
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sub_millisecond_hops_still_use_up_the_budget() {
+        let at = VirtualNetwork::address;
+        let mut net = VirtualNetwork::new();
+        let everywhere = TransformScope::Protocol(VirtualNetwork::PROTOCOL);
+        let clock = net.clock().clone();
+        let sender = net.add_node("sender", |node| node);
+        let relays: Vec<_> = (0..8).map(|relay| format!("r{relay}")).collect();
+        for relay in &relays {
+            let slow = Slow {
+                clock: clock.clone(),
+                hold: Duration::from_micros(900),
+            };
+            net.add_node(relay, |node| {
+                node.with_forward_transform(everywhere.clone(), slow)
+            });
+        }
+        net.add_node("d", |node| node.with_handler(|_| async {}));
+
+        let mut plan: Vec<_> = relays.iter().map(|relay| at(relay)).collect();
+        plan.push(at("d"));
+        let message = MessageBuilder::new(at("d")).route_plan(plan).build();
+        sender
+            .forward_with_budget(message, Duration::from_millis(5))
+            .await
+            .unwrap();
+        let hops = net.run_until_idle(20).await;
+        // every hop is charged a whole millisecond, so the sixth has none left
+        assert_eq!(hops.len(), 6);
+        assert_eq!(hops[5].to, at("r5").identity);
+        assert!(matches!(hops[5].result, Err(SendError::BudgetExhausted)));
+    }
+}
@@ -0,0 +1,255 @@
+//! The route cache and what it drops once full.
+//!
+//! Without a [capacity](NodeInstance::with_route_cache_capacity) the cache
+//! keeps every route it learns until the backend changes it or a downstream
+//! node withdraws it. With one, caching a route into a full cache first evicts
+//! the entry the [`CacheEviction`] policy values least. Every entry counts its
+//! hits and remembers when it was last used, so hot routes outlive one-off ones.
+//!
+//! Cache hits are also reported to the backend, at most once per
+//! [`ROUTE_TOUCH_INTERVAL`] and route, as the recency hint
+//! [warming](NodeInstance::warm_cache) a restarted node's cache goes by.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{InternedAddress, NodeInstance};
+
+/// How often a cached route's use is at most [reported](crate::DataBackend::touch)
+/// to the backend.
+pub const ROUTE_TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Which route a full [route cache](NodeInstance::with_route_cache_capacity)
+/// evicts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CacheEviction {
+    /// The least recently used.
+    Lru,
+    /// The least often used, the least recently used among equals.
+    Lfu,
+    /// The lowest hits per use elapsed since the last hit, so a route needs
+    /// both to be used often and to have been used lately to stay.
+    #[default]
+    Hybrid,
+}
+
+struct Entry {
+    next: InternedAddress,
+    hits: AtomicU64,
+    /// The cache's use counter at the last hit.
+    last_used: AtomicU64,
+    /// When the use was last reported to the backend, Unix milliseconds.
+    touched_ms: AtomicU64,
+}
+
+impl Entry {
+    fn new(next: InternedAddress, now: u64, touched_ms: u64) -> Self {
+        Self {
+            next,
+            hits: AtomicU64::new(1),
+            last_used: AtomicU64::new(now),
+            touched_ms: AtomicU64::new(touched_ms),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RouteCache {
+    entries: HashMap<InternedAddress, Entry>,
+    capacity: Option<usize>,
+    eviction: CacheEviction,
+    /// Counts lookups and inserts, as a clock for recency.
+    uses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl RouteCache {
+    fn tick(&self) -> u64 {
+        self.uses.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    /// The next hop for `destination`, counted as a hit.
+    pub(crate) fn get(&self, destination: &InternedAddress) -> Option<InternedAddress> {
+        let entry = self.entries.get(destination)?;
+        entry.hits.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(entry.next.clone())
+    }
+    /// The next hop for `destination`, without counting a hit.
+    pub(crate) fn peek(&self, destination: &InternedAddress) -> Option<&InternedAddress> {
+        self.entries.get(destination).map(|entry| &entry.next)
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub(crate) fn contains_key(&self, destination: &InternedAddress) -> bool {
+        self.entries.contains_key(destination)
+    }
+    pub(crate) fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.entries.len() >= capacity)
+    }
+    /// Whether a hit on `destination` at `now_ms` is to be reported to the
+    /// backend. Only one caller is told so per interval.
+    pub(crate) fn touch_due(&self, destination: &InternedAddress, now_ms: u64) -> bool {
+        let Some(entry) = self.entries.get(destination) else {
+            return false;
+        };
+        let touched = entry.touched_ms.load(Ordering::Relaxed);
+        now_ms.saturating_sub(touched) >= ROUTE_TOUCH_INTERVAL.as_millis() as u64
+            && entry
+                .touched_ms
+                .compare_exchange(touched, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+    /// Cache `next` for `destination`, returning the destination evicted to
+    /// make room, if any. Replacing a route keeps its hit count.
+    pub(crate) fn insert(
+        &mut self,
+        destination: InternedAddress,
+        next: InternedAddress,
+    ) -> Option<InternedAddress> {
+        let now = self.tick();
+        if let Some(entry) = self.entries.get_mut(&destination) {
+            entry.next = next;
+            entry.last_used.store(now, Ordering::Relaxed);
+            return None;
+        }
+        let evicted = if self.is_full() {
+            self.evict(now)
+        } else {
+            None
+        };
+        self.entries.insert(destination, Entry::new(next, now, 0));
+        evicted
+    }
+    /// Cache a route [warmed](NodeInstance::warm_cache) from the backend, unless
+    /// one is already cached for `destination` or the cache is full. Warmed
+    /// routes never evict others.
+    pub(crate) fn warm(
+        &mut self,
+        destination: InternedAddress,
+        next: InternedAddress,
+        touched_ms: u64,
+    ) -> bool {
+        if self.is_full() || self.entries.contains_key(&destination) {
+            return false;
+        }
+        let now = self.tick();
+        self.entries
+            .insert(destination, Entry::new(next, now, touched_ms));
+        true
+    }
+    pub(crate) fn remove(&mut self, destination: &InternedAddress) -> Option<InternedAddress> {
+        self.entries.remove(destination).map(|entry| entry.next)
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&InternedAddress, &InternedAddress)> {
+        self.entries
+            .iter()
+            .map(|(destination, entry)| (destination, &entry.next))
+    }
+    pub(crate) fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+    fn evict(&mut self, now: u64) -> Option<InternedAddress> {
+        let eviction = self.eviction;
+        let victim = self
+            .entries
+            .iter()
+            .map(|(destination, entry)| {
+                let hits = entry.hits.load(Ordering::Relaxed);
+                let last_used = entry.last_used.load(Ordering::Relaxed);
+                let value = match eviction {
+                    CacheEviction::Lru => last_used as f64,
+                    CacheEviction::Lfu => hits as f64,
+                    CacheEviction::Hybrid => hits as f64 / (now - last_used + 1) as f64,
+                };
+                (value, last_used, destination)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))?
+            .2
+            .clone();
+        self.entries.remove(&victim);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Some(victim)
+    }
+}
+
+impl NodeInstance {
+    /// Keep at most `capacity` routes in the route cache; see the
+    /// [module docs](self).
+    pub fn with_route_cache_capacity(self, capacity: usize) -> Self {
+        self.next_cache.write().unwrap().capacity = Some(capacity);
+        self
+    }
+    /// Choose which route a full route cache evicts.
+    pub fn with_cache_eviction(self, eviction: CacheEviction) -> Self {
+        self.next_cache.write().unwrap().eviction = eviction;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    fn cache(capacity: usize, eviction: CacheEviction) -> RouteCache {
+        RouteCache {
+            capacity: Some(capacity),
+            eviction,
+            ..RouteCache::default()
+        }
+    }
+
+    fn route(name: &str) -> InternedAddress {
+        InternedAddress::from(&addr(name))
+    }
+
+    #[test]
+    fn hot_routes_outlive_one_off_routes() {
+        for eviction in [CacheEviction::Lfu, CacheEviction::Hybrid] {
+            let mut cache = cache(2, eviction);
+            assert_eq!(cache.insert(route("hot"), route("b")), None);
+            for _ in 0..10 {
+                cache.get(&route("hot")).unwrap();
+            }
+            assert_eq!(cache.insert(route("once"), route("b")), None);
+            assert_eq!(
+                cache.insert(route("again"), route("b")),
+                Some(route("once")),
+                "{eviction:?}"
+            );
+            assert_eq!(
+                cache.insert(route("later"), route("b")),
+                Some(route("again")),
+                "{eviction:?}"
+            );
+            assert!(cache.contains_key(&route("hot")));
+            assert_eq!(cache.evictions(), 2);
+        }
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_however_hot() {
+        let mut cache = cache(2, CacheEviction::Lru);
+        cache.insert(route("hot"), route("b"));
+        for _ in 0..10 {
+            cache.get(&route("hot")).unwrap();
+        }
+        cache.insert(route("once"), route("b"));
+        assert_eq!(cache.insert(route("again"), route("b")), Some(route("hot")));
+    }
+
+    #[test]
+    fn warmed_routes_never_evict() {
+        let mut cache = cache(1, CacheEviction::Hybrid);
+        assert!(cache.warm(route("d1"), route("b"), 0));
+        assert!(!cache.warm(route("d2"), route("b"), 0));
+        assert!(cache.contains_key(&route("d1")));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+//! Normalizing identities that have several byte forms.
+//!
+//! Some protocols accept one identity written in more than one way, such as
+//! hostnames in any letter case. Such addresses compare unequal and would each
+//! get their own route cache entry. A [`Canonicalizer`] registered for the
+//! protocol with [`NodeInstance::with_canonicalizer`] maps every form to one,
+//! which the node uses for route lookups and its route cache. Protocols without
+//! a canonicalizer are left as they are.
+
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use crate::{Address, Identity, NodeInstance, Protocol};
+
+/// Maps the identities of one protocol to their canonical form.
+pub trait Canonicalizer: Send + Sync {
+    fn canonicalize(&self, identity: &Identity) -> Identity;
+}
+
+impl<F> Canonicalizer for F
+where
+    F: Fn(&Identity) -> Identity + Send + Sync,
+{
+    fn canonicalize(&self, identity: &Identity) -> Identity {
+        self(identity)
+    }
+}
+
+/// Lowercases ASCII letters, for protocols with case-insensitive identities such
+/// as hostnames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsciiCaseFold;
+
+impl Canonicalizer for AsciiCaseFold {
+    fn canonicalize(&self, identity: &Identity) -> Identity {
+        Identity::new(identity.as_bytes().to_ascii_lowercase())
+    }
+}
+
+/// The canonicalizer of each protocol that has one.
+#[derive(Clone, Default)]
+pub struct Canonicalizers {
+    by_protocol: HashMap<Protocol, Arc<dyn Canonicalizer>>,
+}
+
+impl Canonicalizers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with(mut self, protocol: Protocol, canonicalizer: impl Canonicalizer + 'static) -> Self {
+        self.by_protocol.insert(protocol, Arc::new(canonicalizer));
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.by_protocol.is_empty()
+    }
+}
+
+impl Address {
+    /// This address with its identity in the canonical form of its protocol.
+    pub fn canonical(&self, registry: &Canonicalizers) -> Address {
+        match registry.by_protocol.get(&self.protocol) {
+            Some(canonicalizer) => Address {
+                protocol: self.protocol.clone(),
+                identity: canonicalizer.canonicalize(&self.identity),
+            },
+            None => self.clone(),
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Treat identities of `protocol` as equal when `canonicalizer` maps them to
+    /// the same one; see the [module docs](self).
+    pub fn with_canonicalizer(
+        mut self,
+        protocol: Protocol,
+        canonicalizer: impl Canonicalizer + 'static,
+    ) -> Self {
+        self.canonicalizers = self.canonicalizers.with(protocol, canonicalizer);
+        self
+    }
+    pub fn canonicalizers(&self) -> &Canonicalizers {
+        &self.canonicalizers
+    }
+    pub(crate) fn canonical<'a>(&self, address: &'a Address) -> Cow<'a, Address> {
+        if self
+            .canonicalizers
+            .by_protocol
+            .contains_key(&address.protocol)
+        {
+            Cow::Owned(address.canonical(&self.canonicalizers))
+        } else {
+            Cow::Borrowed(address)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{addr, Shared, TEST},
+        DataBackend, MemoryBackend,
+    };
+
+    #[tokio::test]
+    async fn case_forms_share_one_cache_entry() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend
+            .set_next(&addr("dest.example"), Some(&addr("relay")))
+            .await
+            .unwrap();
+        let node = NodeInstance::new()
+            .with_backend(Shared(backend))
+            .with_canonicalizer(TEST, AsciiCaseFold);
+        for form in ["Dest.Example", "DEST.EXAMPLE", "dest.example"] {
+            assert_eq!(node.resolve_next(&addr(form)).await.unwrap(), addr("relay"));
+        }
+        assert_eq!(node.iter_routes(), [(addr("dest.example"), addr("relay"))]);
+        assert_eq!(node.metrics_snapshot().route_cache_misses, 1);
+
+        // other protocols keep their case
+        let other = Address::new(Protocol::new_static(b"other"), Identity::new("Dest"));
+        assert_eq!(other.canonical(node.canonicalizers()), other);
+    }
+}
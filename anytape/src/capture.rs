@@ -0,0 +1,583 @@
+//! Recording traffic to a file and replaying it into a node.
+//!
+//! A capture file starts with [`CAPTURE_MAGIC`] and a version byte, followed by
+//! one frame per entry: a big-endian `u32` length and the entry, which holds the
+//! [`Direction`], the time in Unix milliseconds, the local address in the
+//! [wire](crate::wire) encoding and the message in the compact wire format.
+//!
+//! A [`CaptureWriter`] appends entries, sampling a share of them and stopping
+//! at a size cap. It records the messages a node receives when installed with
+//! [`NodeInstance::with_inbound_capture`], and those an executor sends when
+//! that executor is wrapped in a [`CaptureExecutor`]. A [`CaptureReader`]
+//! iterates the entries of a file and [`NodeInstance::replay`] feeds the
+//! inbound ones back through [`NodeInstance::dispatch_inbound`]. A damaged file
+//! reads up to the damage and then yields one [`CaptureError`].
+
+use std::{
+    fmt,
+    fs::File,
+    future::Future,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    random_u64,
+    wire::{DecodeError, Reader, Writer},
+    Address, Clock, ExecutorCapabilities, Identity, Message, MessageStatus, NodeInstance, Protocol,
+    ProtocolExecutor, SendOutcome, SystemClock,
+};
+
+/// The first bytes of every capture file.
+pub const CAPTURE_MAGIC: [u8; 4] = *b"ATCP";
+pub const CAPTURE_VERSION: u8 = 1;
+/// Largest entry a [`CaptureReader`] accepts.
+const MAX_ENTRY_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn tag(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Direction::Inbound),
+            1 => Some(Direction::Outbound),
+            _ => None,
+        }
+    }
+}
+
+/// One captured message.
+#[derive(Clone)]
+pub struct CaptureEntry {
+    pub direction: Direction,
+    /// Unix time in milliseconds.
+    pub at_ms: u64,
+    /// Where an inbound message was accepted, or the address of the node that
+    /// sent an outbound one.
+    pub local: Address,
+    pub message: Message,
+}
+
+impl CaptureEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_u8(self.direction.tag());
+        w.put_u64(self.at_ms);
+        w.put_address(&self.local);
+        w.put_bytes(&self.message.encode());
+        w.finish()
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let tag = r.get_u8()?;
+        let direction = Direction::from_tag(tag).ok_or(DecodeError::InvalidFlags(tag))?;
+        let at_ms = r.get_u64()?;
+        let local = r.get_address()?;
+        let message = Message::decode(r.get_bytes()?)?;
+        r.finish()?;
+        Ok(Self {
+            direction,
+            at_ms,
+            local,
+            message,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    /// The file does not start with [`CAPTURE_MAGIC`].
+    NotACapture,
+    UnsupportedVersion(u8),
+    /// The file ended inside entry number `index`, counting from zero.
+    Truncated {
+        index: u64,
+    },
+    /// Entry number `index` declares a length beyond what a reader accepts.
+    Oversize {
+        index: u64,
+        declared: u32,
+    },
+    /// Entry number `index` could not be decoded.
+    Corrupt {
+        index: u64,
+        error: DecodeError,
+    },
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Io(e) => write!(f, "capture io error: {e}"),
+            CaptureError::NotACapture => write!(f, "not a capture file"),
+            CaptureError::UnsupportedVersion(version) => {
+                write!(f, "unsupported capture version {version}")
+            }
+            CaptureError::Truncated { index } => write!(f, "capture truncated in entry {index}"),
+            CaptureError::Oversize { index, declared } => {
+                write!(f, "capture entry {index} declares {declared} bytes")
+            }
+            CaptureError::Corrupt { index, error } => {
+                write!(f, "capture entry {index} is corrupt: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaptureError::Io(e) => Some(e),
+            CaptureError::Corrupt { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> Self {
+        CaptureError::Io(e)
+    }
+}
+
+struct WriterState {
+    sink: Box<dyn Write + Send>,
+    written: u64,
+    entries: u64,
+    skipped: u64,
+}
+
+/// Appends entries to a capture file; see the [module docs](self).
+pub struct CaptureWriter {
+    state: Mutex<WriterState>,
+    sample_rate: f64,
+    max_bytes: Option<u64>,
+}
+
+impl CaptureWriter {
+    /// Start a capture on `sink`, writing the file header right away.
+    pub fn new(sink: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut sink: Box<dyn Write + Send> = Box::new(sink);
+        sink.write_all(&CAPTURE_MAGIC)?;
+        sink.write_all(&[CAPTURE_VERSION])?;
+        Ok(Self {
+            state: Mutex::new(WriterState {
+                sink,
+                written: (CAPTURE_MAGIC.len() + 1) as u64,
+                entries: 0,
+                skipped: 0,
+            }),
+            sample_rate: 1.0,
+            max_bytes: None,
+        })
+    }
+    /// Create, or truncate, the file at `path` and start a capture on it.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+    /// Record only a random share `rate`, between 0 and 1, of the messages.
+    pub fn with_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate;
+        self
+    }
+    /// Stop recording once the file would grow beyond `max_bytes`.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+    /// Append an entry, unless sampling or the size cap skips it. Returns whether
+    /// it was written.
+    pub fn record(&self, entry: &CaptureEntry) -> io::Result<bool> {
+        if self.sample_rate < 1.0 && random_u64() as f64 / u64::MAX as f64 >= self.sample_rate {
+            self.state.lock().unwrap().skipped += 1;
+            return Ok(false);
+        }
+        let bytes = entry.encode();
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry too large"))?;
+        let mut state = self.state.lock().unwrap();
+        let written = state.written + 4 + u64::from(len);
+        if self.max_bytes.is_some_and(|max| written > max) {
+            state.skipped += 1;
+            return Ok(false);
+        }
+        state.sink.write_all(&len.to_be_bytes())?;
+        state.sink.write_all(&bytes)?;
+        state.written = written;
+        state.entries += 1;
+        Ok(true)
+    }
+    pub fn flush(&self) -> io::Result<()> {
+        self.state.lock().unwrap().sink.flush()
+    }
+    /// Entries written so far.
+    pub fn entries(&self) -> u64 {
+        self.state.lock().unwrap().entries
+    }
+    /// Entries left out by sampling or the size cap.
+    pub fn skipped(&self) -> u64 {
+        self.state.lock().unwrap().skipped
+    }
+    /// Size of the capture so far, header included.
+    pub fn bytes_written(&self) -> u64 {
+        self.state.lock().unwrap().written
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Iterates the entries of a capture file; see the [module docs](self).
+///
+/// After the first error the iterator ends.
+pub struct CaptureReader<R> {
+    source: R,
+    index: u64,
+    done: bool,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read the file header from `source`.
+    pub fn new(mut source: R) -> Result<Self, CaptureError> {
+        let mut header = [0; CAPTURE_MAGIC.len() + 1];
+        source.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => CaptureError::NotACapture,
+            _ => CaptureError::Io(e),
+        })?;
+        if header[..CAPTURE_MAGIC.len()] != CAPTURE_MAGIC {
+            return Err(CaptureError::NotACapture);
+        }
+        let version = header[CAPTURE_MAGIC.len()];
+        if version != CAPTURE_VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            source,
+            index: 0,
+            done: false,
+        })
+    }
+    fn read_entry(&mut self) -> Result<Option<CaptureEntry>, CaptureError> {
+        let index = self.index;
+        let mut prefix = [0; 4];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match self.source.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(CaptureError::Truncated { index }),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let declared = u32::from_be_bytes(prefix);
+        if declared > MAX_ENTRY_LEN {
+            return Err(CaptureError::Oversize { index, declared });
+        }
+        let mut body = vec![0; declared as usize];
+        self.source
+            .read_exact(&mut body)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => CaptureError::Truncated { index },
+                _ => CaptureError::Io(e),
+            })?;
+        let entry =
+            CaptureEntry::decode(&body).map_err(|error| CaptureError::Corrupt { index, error })?;
+        self.index += 1;
+        Ok(Some(entry))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureEntry, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+/// Records the messages `inner` sends; see the [module docs](self).
+pub struct CaptureExecutor<E> {
+    inner: E,
+    capture: Arc<CaptureWriter>,
+    local: Address,
+    clock: Arc<dyn Clock>,
+}
+
+impl<E: ProtocolExecutor> CaptureExecutor<E> {
+    /// Record what `inner` sends into `capture`, as sent by the node at `local`.
+    pub fn new(inner: E, capture: Arc<CaptureWriter>, local: Address) -> Self {
+        Self {
+            inner,
+            capture,
+            local,
+            clock: Arc::new(SystemClock),
+        }
+    }
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+    fn record(&self, message: &Message) {
+        // a failing capture must not fail the send
+        let _ = self.capture.record(&CaptureEntry {
+            direction: Direction::Outbound,
+            at_ms: self.clock.now_millis(),
+            local: self.local.clone(),
+            message: message.clone(),
+        });
+    }
+}
+
+impl<E> ProtocolExecutor for CaptureExecutor<E>
+where
+    E: ProtocolExecutor + Send + Sync + 'static,
+{
+    type Error = E::Error;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        self.record(&message);
+        self.inner.send(remote, message)
+    }
+
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        self.record(&message);
+        self.inner.send_via(protocol, remote, message)
+    }
+
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> impl Future<Output = Result<Vec<Result<SendOutcome, Self::Error>>, Self::Error>> + Send + 'static
+    {
+        for message in &messages {
+            self.record(message);
+        }
+        self.inner.send_batch(remote, messages)
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        self.inner.get_status(remote, message)
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.close()
+    }
+}
+
+/// What [`NodeInstance::replay`] did.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Inbound entries dispatched, whatever their outcome.
+    pub replayed: u64,
+    /// Outbound entries, which are not replayed.
+    pub skipped: u64,
+    /// Why reading stopped early, if it did.
+    pub error: Option<CaptureError>,
+}
+
+impl NodeInstance {
+    /// Record every message this node receives into `capture`, before anything
+    /// screens it.
+    pub fn with_inbound_capture(mut self, capture: Arc<CaptureWriter>) -> Self {
+        self.inbound_capture = Some(capture);
+        self
+    }
+    pub(crate) fn capture_inbound(&self, message: &Message, accept_at: &Address) {
+        if let Some(capture) = &self.inbound_capture {
+            // a failing capture must not fail the delivery
+            let _ = capture.record(&CaptureEntry {
+                direction: Direction::Inbound,
+                at_ms: self.clock.now_millis(),
+                local: accept_at.clone(),
+                message: message.clone(),
+            });
+        }
+    }
+    /// Dispatch the inbound entries of `entries` as if they arrived again, at the
+    /// address they were accepted at. The waits between entries follow their
+    /// time stamps divided by `speed`; a `speed` of 0 replays without waiting.
+    ///
+    /// Reading stops at the first error, which the report carries.
+    pub async fn replay(
+        &self,
+        entries: impl IntoIterator<Item = Result<CaptureEntry, CaptureError>>,
+        speed: f64,
+    ) -> ReplayReport {
+        let mut report = ReplayReport::default();
+        let mut previous = None;
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    report.error = Some(error);
+                    break;
+                }
+            };
+            if entry.direction != Direction::Inbound {
+                report.skipped += 1;
+                continue;
+            }
+            if let Some(previous) = previous.filter(|_| speed > 0.0 && speed.is_finite()) {
+                let gap = Duration::from_millis(entry.at_ms.saturating_sub(previous));
+                tokio::time::sleep(gap.div_f64(speed)).await;
+            }
+            previous = Some(entry.at_ms);
+            // the outcome is the node's own business, as on first arrival
+            let _ = self.dispatch_inbound(entry.message, entry.local).await;
+            report.replayed += 1;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Loopback, TEST};
+
+    /// A capture sink the test can read back.
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Observed = Arc<Mutex<Vec<(u64, Vec<u8>)>>>;
+
+    /// A node at `addr("dest")` noting what its handler sees.
+    fn receiver() -> (NodeInstance, Observed) {
+        let observed = Observed::default();
+        let node = NodeInstance::new()
+            .with_address(addr("dest"))
+            .with_handler({
+                let observed = observed.clone();
+                move |message: Message| {
+                    observed
+                        .lock()
+                        .unwrap()
+                        .push((message.unique_id, message.payload));
+                    async {}
+                }
+            });
+        (node, observed)
+    }
+
+    /// Capture three messages sent over a loopback, returning the capture file
+    /// and what the receiver's handler saw.
+    async fn captured_exchange() -> (Vec<u8>, Vec<(u64, Vec<u8>)>) {
+        let sink = SharedSink::default();
+        let capture = Arc::new(CaptureWriter::new(sink.clone()).unwrap());
+        let (node, observed) = receiver();
+        let loopback = Loopback::new();
+        loopback.attach("dest", Arc::new(node.with_inbound_capture(capture.clone())));
+        let sender = NodeInstance::new().with_executor(TEST, loopback);
+        for payload in [&b"one"[..], b"two", b"three"] {
+            sender
+                .send(message(addr("dest"), payload), addr("dest"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(capture.entries(), 3);
+        capture.flush().unwrap();
+        let file = sink.0.lock().unwrap().clone();
+        let observed = observed.lock().unwrap().clone();
+        (file, observed)
+    }
+
+    #[tokio::test]
+    async fn replays_show_the_handler_what_it_saw_first() {
+        let (file, first) = captured_exchange().await;
+        assert_eq!(first.len(), 3);
+        let (fresh, replayed) = receiver();
+        let report = fresh
+            .replay(CaptureReader::new(&file[..]).unwrap(), 0.0)
+            .await;
+        assert_eq!((report.replayed, report.skipped), (3, 0));
+        assert!(report.error.is_none());
+        assert_eq!(*replayed.lock().unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn truncated_captures_replay_up_to_the_damage() {
+        let (file, first) = captured_exchange().await;
+        let truncated = &file[..file.len() - 3];
+        let entries: Vec<_> = CaptureReader::new(truncated).unwrap().collect();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            entries[2],
+            Err(CaptureError::Truncated { index: 2 })
+        ));
+
+        let (fresh, replayed) = receiver();
+        let report = fresh
+            .replay(CaptureReader::new(truncated).unwrap(), 0.0)
+            .await;
+        assert_eq!(report.replayed, 2);
+        assert!(matches!(
+            report.error,
+            Some(CaptureError::Truncated { index: 2 })
+        ));
+        assert_eq!(*replayed.lock().unwrap(), first[..2]);
+
+        assert!(matches!(
+            CaptureReader::new(&file[..3]),
+            Err(CaptureError::NotACapture)
+        ));
+    }
+}
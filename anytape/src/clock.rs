@@ -0,0 +1,190 @@
+//! Where a node gets the time from.
+//!
+//! Wall-clock time stamps path nodes and propagated deadlines; monotonic time
+//! drives rate limits and quota windows. A [`ManualClock`] shared by every node
+//! of a test network makes all of them deterministic.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub trait Clock: Send + Sync {
+    /// Wall-clock time in milliseconds since the unix epoch. May jump in either
+    /// direction when the system clock is set.
+    fn now_millis(&self) -> u64;
+    /// Time since an arbitrary fixed origin; never goes backwards.
+    fn monotonic(&self) -> Duration;
+}
+
+/// The system clock for wall time and the tokio clock for monotonic time, so
+/// `tokio::time::pause` still works on it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+    fn monotonic(&self) -> Duration {
+        static ORIGIN: OnceLock<tokio::time::Instant> = OnceLock::new();
+        ORIGIN.get_or_init(tokio::time::Instant::now).elapsed()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    inner: Arc<ManualTime>,
+}
+
+#[derive(Debug, Default)]
+struct ManualTime {
+    wall_millis: AtomicU64,
+    monotonic_nanos: AtomicU64,
+}
+
+impl ManualClock {
+    /// A clock whose wall time starts at `wall_millis` and monotonic time at zero.
+    pub fn new(wall_millis: u64) -> Self {
+        let clock = Self::default();
+        clock.inner.wall_millis.store(wall_millis, Ordering::SeqCst);
+        clock
+    }
+    /// Move both wall and monotonic time forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.inner
+            .wall_millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+        self.inner
+            .monotonic_nanos
+            .fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+    /// Step the wall clock to `wall_millis`, possibly backwards, leaving monotonic
+    /// time alone.
+    pub fn set_wall_millis(&self, wall_millis: u64) {
+        self.inner.wall_millis.store(wall_millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.inner.wall_millis.load(Ordering::SeqCst)
+    }
+    fn monotonic(&self) -> Duration {
+        Duration::from_nanos(self.inner.monotonic_nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ROUTE_TOUCH_INTERVAL;
+    use crate::testing::{addr, block_on, eventually, message, next, Recorder, Shared, TEST};
+    use crate::{
+        ContentDedupConfig, DataBackend, MemoryBackend, MessageStatus, NodeInstance, RateLimitMode,
+        RateLimiter, RejectReason, SendError,
+    };
+
+    #[tokio::test]
+    async fn advancing_the_clock_expires_dedup_entries() {
+        let clock = ManualClock::new(1_700_000_000_000);
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_handler(|_| async {})
+            .with_clock(clock.clone())
+            .with_content_dedup(ContentDedupConfig {
+                window: Duration::from_secs(10),
+                ..ContentDedupConfig::default()
+            });
+        let receive = |unique_id| {
+            let mut message = message(addr("me"), b"same");
+            message.unique_id = unique_id;
+            node.dispatch_inbound(message, addr("me"))
+        };
+        assert_eq!(receive(1).await.unwrap(), MessageStatus::Received);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(
+            receive(2).await.unwrap(),
+            MessageStatus::Rejected {
+                reason: RejectReason::DuplicateContent
+            }
+        );
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(receive(3).await.unwrap(), MessageStatus::Received);
+    }
+
+    #[tokio::test]
+    async fn advancing_the_clock_refills_the_rate_limit() {
+        let clock = ManualClock::new(0);
+        let node = NodeInstance::new()
+            .with_executor(TEST, Recorder::new())
+            .with_rate_limiter(RateLimiter::new(RateLimitMode::Reject).with_limit(
+                TEST,
+                1,
+                Duration::from_secs(1),
+            ))
+            .with_clock(clock.clone());
+        let send = || node.send(message(addr("b"), b"x"), addr("b"));
+        assert!(send().await.is_ok());
+        assert!(matches!(send().await, Err(SendError::RateLimited)));
+        clock.advance(Duration::from_secs(1));
+        assert!(send().await.is_ok());
+    }
+
+    #[test]
+    fn a_backwards_wall_clock_step_keeps_hop_times_non_negative() {
+        let clock = ManualClock::new(1_700_000_000_000);
+        let first = NodeInstance::new().with_clock(clock.clone());
+        let second = NodeInstance::new().with_clock(clock.clone());
+        let mut message = message(addr("dest"), b"x");
+        first.mark(addr("a"), &mut message);
+        clock.set_wall_millis(1_600_000_000_000);
+        second.mark(addr("b"), &mut message);
+        let [a, b] = &message.path[..] else {
+            panic!("two hops expected");
+        };
+        assert!(b.ts >= a.ts);
+        // monotonic time is unaffected by the step
+        assert_eq!(clock.monotonic(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn advancing_the_clock_makes_cached_routes_report_their_use_again() {
+        let start = 1_700_000_000_000;
+        let clock = ManualClock::new(start);
+        let backend = Arc::new(MemoryBackend::new());
+        backend
+            .set_next(&addr("dest"), Some(&addr("relay")))
+            .await
+            .unwrap();
+        let node = NodeInstance::new()
+            .with_backend(Shared(backend.clone()))
+            .with_clock(clock.clone());
+        let last_used = || {
+            let mut routes = backend.scan().unwrap();
+            block_on(next(&mut routes)).unwrap().last_used_ms
+        };
+        // the first lookup fills the cache, the first hit reports the use
+        for _ in 0..2 {
+            node.resolve_next(&addr("dest")).await.unwrap();
+        }
+        eventually(|| last_used() == Some(start)).await;
+
+        clock.advance(ROUTE_TOUCH_INTERVAL / 2);
+        node.resolve_next(&addr("dest")).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(last_used(), Some(start));
+
+        clock.advance(ROUTE_TOUCH_INTERVAL / 2);
+        node.resolve_next(&addr("dest")).await.unwrap();
+        let touched = start + ROUTE_TOUCH_INTERVAL.as_millis() as u64;
+        eventually(|| last_used() == Some(touched)).await;
+    }
+}
@@ -0,0 +1,500 @@
+//! Buffering route writes, so that a backend only sees the latest of each.
+//!
+//! Route gossip and cost updates can set the same routes many times a second.
+//! A [`CoalescingBackend`] keeps only the latest route written for each
+//! destination and writes what it buffered to the backend behind it in one
+//! [batch](DataBackend::set_next_batch) every
+//! [`flush_interval`](CoalescingConfig::flush_interval), or as soon as
+//! [`max_pending`](CoalescingConfig::max_pending) destinations are buffered.
+//! Lookups see buffered routes before they are written.
+//!
+//! A failed flush keeps its routes and is tried again, after the
+//! [backoff](CoalescingConfig::backoff) of each failure in a row, until it
+//! succeeds; the attempts limit of the policy does not apply. Once the backend
+//! serves a node, failures and the recovery after them are published as
+//! [`NodeEvent::BackendFlushFailed`] and [`NodeEvent::BackendFlushRecovered`].
+//!
+//! [`CoalescingBackend::shutdown`] writes what is left and stops buffering.
+//! Dropping the backend also writes what is left: in place outside an async
+//! runtime, from a task inside one, where waiting would block the runtime.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{runtime, sync::Notify, task::JoinHandle};
+
+use crate::{
+    Address, BackendEvents, BoxFuture, BoxResult, BoxStream, DataBackend, Identity, IdentityAlias,
+    NodeEvent, RetryPolicy, RouteChange, StoredRoute,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalescingConfig {
+    /// How often buffered routes are written.
+    pub flush_interval: Duration,
+    /// Buffered destinations that have them written before the interval is up.
+    pub max_pending: usize,
+    /// How long to wait after failed flushes.
+    pub backoff: RetryPolicy,
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_millis(100),
+            max_pending: 1024,
+            backoff: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buffer {
+    /// The latest route written for each destination, `None` for a removal.
+    pending: HashMap<Address, Option<Address>>,
+    /// Routes of the flush under way, or of the last one if it failed.
+    writing: HashMap<Address, Option<Address>>,
+    /// Failed flushes in a row.
+    failures: u32,
+}
+
+impl Buffer {
+    fn get(&self, addr: &Address) -> Option<Option<Address>> {
+        self.pending
+            .get(addr)
+            .or_else(|| self.writing.get(addr))
+            .cloned()
+    }
+}
+
+enum Flusher {
+    Idle,
+    Running(JoinHandle<()>),
+    Stopped,
+}
+
+struct Shared<B> {
+    inner: B,
+    config: CoalescingConfig,
+    buffer: Mutex<Buffer>,
+    /// Held while writing to `inner`, so that flushes never overlap.
+    flushing: tokio::sync::Mutex<()>,
+    full: Notify,
+    flusher: Mutex<Flusher>,
+    events: Mutex<Option<BackendEvents>>,
+}
+
+impl<B: DataBackend + 'static> Shared<B> {
+    fn publish(&self, event: impl FnOnce() -> NodeEvent) {
+        let events = self.events.lock().unwrap().clone();
+        if let Some(events) = events {
+            events.publish(event);
+        }
+    }
+    /// Buffer `routes` now. The future returned starts the flusher, which needs
+    /// a runtime, or once buffering stopped flushes right away.
+    fn buffer(
+        self: &Arc<Self>,
+        routes: impl IntoIterator<Item = (Address, Option<Address>)>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let full = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.pending.extend(routes);
+            buffer.pending.len() >= self.config.max_pending
+        };
+        let shared = self.clone();
+        async move {
+            // checked after buffering, so that a shutdown in between still
+            // flushes the routes
+            let stopped = {
+                let mut flusher = shared.flusher.lock().unwrap();
+                match *flusher {
+                    Flusher::Idle => {
+                        *flusher = Flusher::Running(tokio::spawn(shared.clone().run()));
+                        false
+                    }
+                    Flusher::Running(_) => false,
+                    Flusher::Stopped => true,
+                }
+            };
+            if stopped {
+                // a failure stays buffered for the drop to retry
+                let _ = shared.flush().await;
+            } else if full {
+                shared.full.notify_one();
+            }
+        }
+    }
+    /// Write everything buffered, returning how many routes were written.
+    async fn flush(&self) -> BoxResult<usize> {
+        let _flushing = self.flushing.lock().await;
+        let batch: Vec<_> = {
+            let mut buffer = self.buffer.lock().unwrap();
+            let pending = mem::take(&mut buffer.pending);
+            // newer routes replace those a failed flush left
+            buffer.writing.extend(pending);
+            buffer
+                .writing
+                .iter()
+                .map(|(addr, next)| (addr.clone(), next.clone()))
+                .collect()
+        };
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let result = self.inner.set_next_batch(batch).await;
+        let mut buffer = self.buffer.lock().unwrap();
+        match result {
+            Ok(()) => {
+                let flushed = mem::take(&mut buffer.writing).len();
+                let failures = mem::take(&mut buffer.failures);
+                drop(buffer);
+                if failures > 0 {
+                    self.publish(|| NodeEvent::BackendFlushRecovered { flushed, failures });
+                }
+                Ok(flushed)
+            }
+            Err(e) => {
+                buffer.failures += 1;
+                let attempt = buffer.failures;
+                let pending = buffer.writing.len() + buffer.pending.len();
+                drop(buffer);
+                self.publish(|| NodeEvent::BackendFlushFailed {
+                    pending,
+                    attempt,
+                    error: e.to_string(),
+                });
+                Err(e)
+            }
+        }
+    }
+    async fn run(self: Arc<Self>) {
+        loop {
+            let failures = self.buffer.lock().unwrap().failures;
+            if failures == 0 {
+                // a full buffer cuts the wait short
+                let _ =
+                    tokio::time::timeout(self.config.flush_interval, self.full.notified()).await;
+            } else {
+                tokio::time::sleep(self.config.backoff.backoff(failures - 1)).await;
+            }
+            let _ = self.flush().await;
+        }
+    }
+}
+
+/// Buffers the routes written to `inner` and writes only the latest of each,
+/// in batches; see the [module docs](self).
+///
+/// [`set_next`](DataBackend::set_next) returns the route it replaced as
+/// buffered, and reads it from `inner` for destinations with nothing buffered.
+/// Aliases, touches and scans go to `inner` directly; scans do not see buffered
+/// routes.
+pub struct CoalescingBackend<B: DataBackend + 'static> {
+    shared: Arc<Shared<B>>,
+}
+
+impl<B: DataBackend + 'static> CoalescingBackend<B> {
+    pub fn new(inner: B, config: CoalescingConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                config,
+                buffer: Default::default(),
+                flushing: Default::default(),
+                full: Notify::new(),
+                flusher: Mutex::new(Flusher::Idle),
+                events: Mutex::new(None),
+            }),
+        }
+    }
+    pub fn inner(&self) -> &B {
+        &self.shared.inner
+    }
+    /// Destinations whose latest route is not written yet.
+    pub fn pending(&self) -> usize {
+        let buffer = self.shared.buffer.lock().unwrap();
+        buffer.pending.len()
+            + buffer
+                .writing
+                .keys()
+                .filter(|addr| !buffer.pending.contains_key(*addr))
+                .count()
+    }
+    /// Write everything buffered now, returning how many routes were written.
+    /// On failure the routes stay buffered.
+    pub async fn flush(&self) -> BoxResult<usize> {
+        self.shared.flush().await
+    }
+    /// Write everything buffered and stop buffering: routes set from now on are
+    /// written straight away.
+    pub async fn shutdown(&self) -> BoxResult<()> {
+        self.stop();
+        self.shared.flush().await.map(drop)
+    }
+    fn stop(&self) {
+        let flusher = mem::replace(&mut *self.shared.flusher.lock().unwrap(), Flusher::Stopped);
+        if let Flusher::Running(task) = flusher {
+            task.abort();
+        }
+    }
+}
+
+impl<B: DataBackend + 'static> Drop for CoalescingBackend<B> {
+    fn drop(&mut self) {
+        self.stop();
+        if self.pending() == 0 {
+            return;
+        }
+        let shared = self.shared.clone();
+        let flush = async move {
+            let _ = shared.flush().await;
+        };
+        match runtime::Handle::try_current() {
+            // waiting here would block the surrounding runtime
+            Ok(handle) => drop(handle.spawn(flush)),
+            Err(_) => {
+                if let Ok(runtime) = runtime::Builder::new_current_thread().enable_all().build() {
+                    runtime.block_on(flush);
+                }
+            }
+        }
+    }
+}
+
+impl<B: DataBackend + 'static> DataBackend for CoalescingBackend<B> {
+    fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+        let buffered = self.shared.buffer.lock().unwrap().get(addr);
+        match buffered {
+            Some(next) => Box::pin(std::future::ready(Ok(next))),
+            None => self.shared.inner.get_next(addr),
+        }
+    }
+
+    fn set_next(
+        &self,
+        addr: &Address,
+        next: Option<&Address>,
+    ) -> BoxFuture<BoxResult<Option<Address>>> {
+        let old = self.shared.buffer.lock().unwrap().get(addr);
+        // not yet buffered, so the old route is still the stored one
+        let stored = old.is_none().then(|| self.shared.inner.get_next(addr));
+        let buffer = self.shared.buffer([(addr.clone(), next.cloned())]);
+        Box::pin(async move {
+            let old = match stored {
+                Some(stored) => stored.await,
+                None => Ok(old.flatten()),
+            };
+            buffer.await;
+            old
+        })
+    }
+
+    fn set_next_batch(&self, routes: Vec<(Address, Option<Address>)>) -> BoxFuture<BoxResult<()>> {
+        let buffer = self.shared.buffer(routes);
+        Box::pin(async move {
+            buffer.await;
+            Ok(())
+        })
+    }
+
+    fn watch(&self) -> Option<BoxStream<RouteChange>> {
+        self.shared.inner.watch()
+    }
+
+    fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+        self.shared.inner.scan()
+    }
+
+    fn touch(&self, addr: &Address, at_ms: u64) -> BoxFuture<BoxResult<()>> {
+        self.shared.inner.touch(addr, at_ms)
+    }
+
+    fn get_alias(&self, old: &Identity) -> BoxFuture<BoxResult<Option<IdentityAlias>>> {
+        self.shared.inner.get_alias(old)
+    }
+
+    fn set_alias(&self, old: &Identity, alias: Option<&IdentityAlias>) -> BoxFuture<BoxResult<()>> {
+        self.shared.inner.set_alias(old, alias)
+    }
+
+    fn attach_events(&self, events: BackendEvents) {
+        self.shared.inner.attach_events(events.clone());
+        *self.shared.events.lock().unwrap() = Some(events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        testing::{addr, block_on, Shared as Wrapped, TestError},
+        MemoryBackend, NodeInstance,
+    };
+
+    /// Counts the batches written to the backend it wraps, failing the first
+    /// `fail` of them.
+    #[derive(Clone, Default)]
+    struct Counting {
+        routes: Arc<MemoryBackend>,
+        batches: Arc<AtomicUsize>,
+        fail: Arc<AtomicUsize>,
+    }
+
+    impl Counting {
+        fn batches(&self) -> usize {
+            self.batches.load(Ordering::Relaxed)
+        }
+    }
+
+    impl DataBackend for Counting {
+        fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.routes.get_next(addr)
+        }
+        fn set_next(
+            &self,
+            addr: &Address,
+            next: Option<&Address>,
+        ) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.routes.set_next(addr, next)
+        }
+        fn set_next_batch(
+            &self,
+            routes: Vec<(Address, Option<Address>)>,
+        ) -> BoxFuture<BoxResult<()>> {
+            self.batches.fetch_add(1, Ordering::Relaxed);
+            let failing = self
+                .fail
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Box::pin(std::future::ready(Err(Box::new(TestError("down")) as _)));
+            }
+            self.routes.set_next_batch(routes)
+        }
+    }
+
+    fn destination(i: usize) -> Address {
+        addr(&format!("d{i}"))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_updates_reach_the_backend_in_few_batches() {
+        let inner = Counting::default();
+        let backend = CoalescingBackend::new(inner.clone(), CoalescingConfig::default());
+        for round in 0..100 {
+            for i in 0..100 {
+                let next = addr(&format!("via{round}"));
+                backend
+                    .set_next(&destination(i), Some(&next))
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(backend.pending(), 100);
+        assert_eq!(inner.batches(), 0);
+        // reads see the latest write before it is flushed
+        let read = backend.get_next(&destination(7)).await.unwrap();
+        assert_eq!(read, Some(addr("via99")));
+        assert_eq!(inner.routes.get_next(&destination(7)).await.unwrap(), None);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(inner.batches(), 1);
+        assert_eq!(backend.pending(), 0);
+        for i in 0..100 {
+            let stored = inner.routes.get_next(&destination(i)).await.unwrap();
+            assert_eq!(stored, Some(addr("via99")));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_flushes_keep_their_routes_until_one_succeeds() {
+        let inner = Counting::default();
+        inner.fail.store(2, Ordering::Relaxed);
+        let backend = Arc::new(CoalescingBackend::new(
+            inner.clone(),
+            CoalescingConfig::default(),
+        ));
+        let node = NodeInstance::new().with_backend(Wrapped(backend.clone()));
+        let mut events = node.events();
+        // the first lookup hands the backend the node's events
+        let _ = node.resolve_next(&destination(0)).await;
+        backend
+            .set_next(&destination(1), Some(&addr("b")))
+            .await
+            .unwrap();
+
+        for attempt in 1..=2 {
+            match events.next_event().await.unwrap().event {
+                NodeEvent::BackendFlushFailed {
+                    pending,
+                    attempt: failed,
+                    ..
+                } => assert_eq!((pending, failed), (1, attempt)),
+                event => panic!("unexpected {event:?}"),
+            }
+            assert_eq!(backend.pending(), 1);
+            let read = backend.get_next(&destination(1)).await.unwrap();
+            assert_eq!(read, Some(addr("b")));
+        }
+        assert_eq!(
+            events.next_event().await.unwrap().event,
+            NodeEvent::BackendFlushRecovered {
+                flushed: 1,
+                failures: 2
+            }
+        );
+        assert_eq!(inner.batches(), 3);
+        assert_eq!(
+            inner.routes.get_next(&destination(1)).await.unwrap(),
+            Some(addr("b"))
+        );
+    }
+
+    #[test]
+    fn dropping_the_backend_writes_what_is_left() {
+        let inner = Counting::default();
+        let backend = CoalescingBackend::new(inner.clone(), CoalescingConfig::default());
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let routes = vec![(destination(1), Some(addr("b")))];
+            backend.set_next_batch(routes).await.unwrap();
+        });
+        drop(runtime);
+        assert_eq!(inner.batches(), 0);
+        drop(backend);
+        assert_eq!(inner.batches(), 1);
+        let stored = block_on(inner.routes.get_next(&destination(1)));
+        assert_eq!(stored.unwrap(), Some(addr("b")));
+    }
+
+    #[tokio::test]
+    async fn routes_set_after_shutdown_are_written_straight_away() {
+        let inner = Counting::default();
+        let backend = CoalescingBackend::new(inner.clone(), CoalescingConfig::default());
+        backend
+            .set_next(&destination(1), Some(&addr("b")))
+            .await
+            .unwrap();
+        backend.shutdown().await.unwrap();
+        assert_eq!(inner.batches(), 1);
+        backend
+            .set_next(&destination(2), Some(&addr("b")))
+            .await
+            .unwrap();
+        assert_eq!(inner.batches(), 2);
+        assert_eq!(backend.pending(), 0);
+    }
+}
@@ -0,0 +1,197 @@
+//! Every plain-data tunable of a node in one struct.
+//!
+//! A [`NodeConfig`] can be written out and loaded back with the `serde` feature;
+//! missing fields take their default. What cannot be serialized, executors, the
+//! backend and the various handlers and hooks, is passed to
+//! [`NodeInstance::with_config`] or set with the builder methods afterwards.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use crate::{
+    lazy, stream, Address, CacheEviction, ContentDedupConfig, CostConfig, DataBackend,
+    DynProtocolExecutor, LoadShedConfig, NodeInstance, PathCanonicalization, PeerScoreConfig,
+    Protocol, RouteSelection, SchemaConfig, ThrottleConfig, DEFAULT_CLOCK_SKEW_TOLERANCE,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct NodeConfig {
+    pub name: Option<String>,
+    pub anon: bool,
+    pub addresses: Vec<Address>,
+    /// See [`NodeInstance::with_clock_skew_tolerance`].
+    pub clock_skew_tolerance: Duration,
+    pub max_path_len: Option<usize>,
+    pub path_canonicalization: Option<PathCanonicalization>,
+    pub send_timeout: Option<Duration>,
+    /// Send timeouts overriding `send_timeout`, keyed by protocol name.
+    pub send_timeout_per_protocol: BTreeMap<String, Duration>,
+    pub stream_idle_timeout: Duration,
+    pub lazy_executor_cooldown: Duration,
+    /// See [`NodeInstance::with_reorder_buffer`].
+    pub reorder_timeout: Option<Duration>,
+    pub route_selection: RouteSelection,
+    pub route_cost: CostConfig,
+    pub route_plan_fallback: bool,
+    pub max_in_flight: Option<usize>,
+    pub executor_panic_limit: Option<u64>,
+    pub peer_scoring: Option<PeerScoreConfig>,
+    pub content_dedup: Option<ContentDedupConfig>,
+    /// See [`NodeInstance::with_load_shedding`]; running nodes take new
+    /// thresholds through [`NodeInstance::set_load_shedding`].
+    pub load_shedding: Option<LoadShedConfig>,
+    pub route_cache_capacity: Option<usize>,
+    pub cache_eviction: CacheEviction,
+    pub throttle_feedback: Option<ThrottleConfig>,
+    /// See [`SchemaRegistry`]; running nodes take new schemas through
+    /// [`NodeInstance::reload_schemas`].
+    pub schemas: SchemaConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            anon: false,
+            addresses: Vec::new(),
+            clock_skew_tolerance: DEFAULT_CLOCK_SKEW_TOLERANCE,
+            max_path_len: None,
+            path_canonicalization: None,
+            send_timeout: None,
+            send_timeout_per_protocol: BTreeMap::new(),
+            stream_idle_timeout: stream::DEFAULT_STALL_TIMEOUT,
+            lazy_executor_cooldown: lazy::DEFAULT_LAZY_COOLDOWN,
+            reorder_timeout: None,
+            route_selection: RouteSelection::default(),
+            route_cost: CostConfig::default(),
+            route_plan_fallback: false,
+            max_in_flight: None,
+            executor_panic_limit: None,
+            peer_scoring: None,
+            content_dedup: None,
+            load_shedding: None,
+            route_cache_capacity: None,
+            cache_eviction: CacheEviction::default(),
+            throttle_feedback: None,
+            schemas: SchemaConfig::default(),
+        }
+    }
+}
+
+impl NodeInstance {
+    /// A node tuned by `config`, sending over `executors` and looking up routes
+    /// in `backend`.
+    pub fn with_config(
+        config: NodeConfig,
+        executors: impl IntoIterator<Item = (Protocol, Arc<dyn DynProtocolExecutor>)>,
+        backend: Option<Arc<dyn DataBackend>>,
+    ) -> Self {
+        let mut node = Self::new()
+            .with_anon(config.anon)
+            .with_clock_skew_tolerance(config.clock_skew_tolerance)
+            .with_stream_idle_timeout(config.stream_idle_timeout)
+            .with_lazy_executor_cooldown(config.lazy_executor_cooldown)
+            .with_route_selection(config.route_selection)
+            .with_route_cost_config(config.route_cost)
+            .with_route_plan_fallback(config.route_plan_fallback)
+            .with_cache_eviction(config.cache_eviction);
+        if let Some(name) = config.name {
+            node = node.with_name(name);
+        }
+        for address in config.addresses {
+            node = node.with_address(address);
+        }
+        if let Some(max) = config.max_path_len {
+            node = node.with_max_path_len(max);
+        }
+        if let Some(policy) = config.path_canonicalization {
+            node = node.with_path_canonicalization(policy);
+        }
+        if let Some(timeout) = config.send_timeout {
+            node = node.with_send_timeout(timeout);
+        }
+        for (protocol, timeout) in config.send_timeout_per_protocol {
+            node = node.with_send_timeout_for(Protocol::new(protocol), timeout);
+        }
+        if let Some(timeout) = config.reorder_timeout {
+            node = node.with_reorder_buffer(timeout);
+        }
+        if let Some(max) = config.max_in_flight {
+            node = node.with_max_in_flight(max);
+        }
+        if let Some(limit) = config.executor_panic_limit {
+            node = node.with_executor_panic_limit(limit);
+        }
+        if let Some(scoring) = config.peer_scoring {
+            node = node.with_peer_scoring(scoring);
+        }
+        if let Some(dedup) = config.content_dedup {
+            node = node.with_content_dedup(dedup);
+        }
+        if let Some(shedding) = config.load_shedding {
+            node = node.with_load_shedding(shedding);
+        }
+        if let Some(throttle) = config.throttle_feedback {
+            node = node.with_throttle_feedback(throttle);
+        }
+        if let Some(capacity) = config.route_cache_capacity {
+            node = node.with_route_cache_capacity(capacity);
+        }
+        node.reload_schemas(&config.schemas);
+        for (protocol, executor) in executors {
+            node.register_executor(protocol, executor);
+        }
+        if let Some(backend) = backend {
+            node.backend_watch = std::sync::Mutex::new(Some(backend.watch()));
+            node.backend = Some(backend);
+        }
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TEST;
+
+    #[test]
+    fn the_default_config_is_a_default_node() {
+        let node = NodeInstance::with_config(NodeConfig::default(), [], None);
+        assert_eq!(node.local_addresses().count(), 0);
+        assert_eq!(node.send_timeout_for(&TEST), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn nodes_are_built_from_a_deserialized_config() {
+        use crate::testing::{addr, message, Recorder};
+
+        let config: NodeConfig = serde_json::from_str(
+            r#"{
+                "name": "edge",
+                "addresses": ["test:65646765"],
+                "send_timeout": { "secs": 5, "nanos": 0 },
+                "send_timeout_per_protocol": { "test": { "secs": 1, "nanos": 0 } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.clock_skew_tolerance, DEFAULT_CLOCK_SKEW_TOLERANCE);
+
+        let recorder = Recorder::new();
+        let executor: Arc<dyn DynProtocolExecutor> = Arc::new(recorder.clone());
+        let node = NodeInstance::with_config(config, [(TEST, executor)], None);
+        assert!(node.is_local(&addr("edge")));
+        assert_eq!(node.send_timeout_for(&TEST), Some(Duration::from_secs(1)));
+        assert_eq!(
+            node.send_timeout_for(&Protocol::new_static(b"other")),
+            Some(Duration::from_secs(5))
+        );
+
+        let mut sent = message(addr("dest"), b"hi");
+        node.mark(addr("edge"), &mut sent);
+        assert_eq!(sent.path[0].name.as_deref(), Some("edge"));
+        node.send(sent, addr("dest")).await.unwrap();
+        assert_eq!(recorder.remotes(), [addr("dest").identity]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Checking a [`DataBackend`] implementation against the trait's contract.
+//!
+//! [`verify_backend`] writes, overwrites and removes a few routes and checks
+//! what the backend answers: reads see the last write, [`DataBackend::set_next`]
+//! returns the route it replaced, `None` removes a route and
+//! [`DataBackend::set_next_batch`] applies its routes in order. The routes use a
+//! protocol of their own and identities fresh for every run, so a backend in
+//! use by nodes can be checked without touching their routes; they are removed
+//! again at the end.
+
+use std::fmt;
+
+use crate::{random_u64, Address, DataBackend, Identity, Protocol};
+
+/// The protocol of the routes [`verify_backend`] writes.
+pub const VERIFY_PROTOCOL: Protocol = Protocol::new_static(b"anytape-verify");
+
+/// A part of the contract a backend broke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// What was being checked, such as `overwrite returns previous`.
+    pub check: &'static str,
+    /// What was expected and what the backend did instead.
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.check, self.detail)
+    }
+}
+
+/// What [`verify_backend`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendReport {
+    /// How many checks ran.
+    pub checks: usize,
+    pub violations: Vec<Violation>,
+}
+
+impl BackendReport {
+    /// Whether the backend passed every check.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+    fn expect(
+        &mut self,
+        check: &'static str,
+        expected: Option<&Address>,
+        actual: Option<&Address>,
+    ) {
+        self.checks += 1;
+        if expected != actual {
+            self.violations.push(Violation {
+                check,
+                detail: format!("expected {}, got {}", show(expected), show(actual)),
+            });
+        }
+    }
+    fn failed(&mut self, check: &'static str, error: impl fmt::Display) {
+        self.checks += 1;
+        self.violations.push(Violation {
+            check,
+            detail: format!("backend error: {error}"),
+        });
+    }
+}
+
+impl fmt::Display for BackendReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} checks failed",
+            self.violations.len(),
+            self.checks
+        )?;
+        for violation in &self.violations {
+            write!(f, "\n  {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+fn show(address: Option<&Address>) -> String {
+    address.map_or_else(|| "none".to_owned(), ToString::to_string)
+}
+
+/// Check `backend` against the [`DataBackend`] contract; see the
+/// [module docs](self).
+pub async fn verify_backend(backend: &dyn DataBackend) -> BackendReport {
+    let run = random_u64();
+    let address =
+        |name: &str| Address::new(VERIFY_PROTOCOL, Identity::new(format!("{run:x}/{name}")));
+    let (route, first, second) = (address("route"), address("first"), address("second"));
+    let mut report = BackendReport::default();
+
+    macro_rules! read {
+        ($check:literal, $addr:expr, $expected:expr) => {
+            match backend.get_next($addr).await {
+                Ok(next) => report.expect($check, $expected, next.as_ref()),
+                Err(e) => report.failed($check, e),
+            }
+        };
+    }
+    macro_rules! write {
+        ($check:literal, $addr:expr, $next:expr, $previous:expr) => {
+            match backend.set_next($addr, $next).await {
+                Ok(previous) => report.expect($check, $previous, previous.as_ref()),
+                Err(e) => report.failed($check, e),
+            }
+        };
+    }
+
+    read!("unknown route reads as none", &route, None);
+    write!("first write returns none", &route, Some(&first), None);
+    read!("read sees first write", &route, Some(&first));
+    write!(
+        "overwrite returns previous",
+        &route,
+        Some(&second),
+        Some(&first)
+    );
+    read!("read sees overwrite", &route, Some(&second));
+    write!("removal returns previous", &route, None, Some(&second));
+    read!("removed route reads as none", &route, None);
+    write!("removing a missing route returns none", &route, None, None);
+
+    let (batched, other) = (address("batched"), address("other"));
+    let batch = vec![
+        (batched.clone(), Some(first.clone())),
+        (other.clone(), Some(first.clone())),
+        (batched.clone(), Some(second.clone())),
+        (other.clone(), None),
+    ];
+    match backend.set_next_batch(batch).await {
+        Ok(()) => {
+            read!("batch applies routes in order", &batched, Some(&second));
+            read!("batch removes routes", &other, None);
+        }
+        Err(e) => report.failed("batch write", e),
+    }
+    if let Err(e) = backend
+        .set_next_batch(vec![(batched, None), (route, None)])
+        .await
+    {
+        report.failed("cleanup", e);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{block_on, next},
+        BoxFuture, BoxResult, MemoryBackend,
+    };
+
+    #[test]
+    fn memory_backends_keep_to_the_contract() {
+        let backend = MemoryBackend::new();
+        let report = block_on(verify_backend(&backend));
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.checks, 10);
+    }
+
+    /// Stores routes but never says which one a write replaced.
+    struct Forgetful(MemoryBackend);
+
+    impl DataBackend for Forgetful {
+        fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.0.get_next(addr)
+        }
+        fn set_next(
+            &self,
+            addr: &Address,
+            next: Option<&Address>,
+        ) -> BoxFuture<BoxResult<Option<Address>>> {
+            let write = self.0.set_next(addr, next);
+            Box::pin(async move { write.await.map(|_| None) })
+        }
+    }
+
+    #[test]
+    fn violations_name_the_broken_checks() {
+        let report = block_on(verify_backend(&Forgetful(MemoryBackend::new())));
+        let broken: Vec<_> = report.violations.iter().map(|v| v.check).collect();
+        assert_eq!(
+            broken,
+            ["overwrite returns previous", "removal returns previous"]
+        );
+        assert!(report.violations[0].detail.ends_with("got none"));
+        assert!(report.to_string().starts_with("2 of 10 checks failed"));
+    }
+
+    #[test]
+    fn checked_routes_are_removed_again() {
+        let backend = MemoryBackend::new();
+        block_on(verify_backend(&backend));
+        let routes = block_on(next(&mut backend.scan().unwrap()));
+        assert!(routes.is_none());
+    }
+}
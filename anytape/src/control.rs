@@ -0,0 +1,772 @@
+//! Control traffic between nodes.
+//!
+//! A control message carries [`CONTROL_HEADER`] and a payload holding one
+//! [`ControlMessage`]: a variant byte, a version byte for that variant and the
+//! variant's fields in the [wire](crate::wire) encoding. Control messages for this
+//! node are handed to the [`ControlHandler`] registered for their [`ControlKind`]
+//! and never reach the [`ReceiveHandler`](crate::ReceiveHandler). Variants or
+//! versions this build does not know are counted and dropped, so newer peers can
+//! introduce them without breaking older ones. A receipt's reject reason this
+//! build does not know decodes as [`RejectReason::Unknown`].
+//!
+//! A [`ControlMessage::Hello`] may tell the receiver the newest
+//! [wire format version](crate::wire::CompactFormat::VERSION) its sender decodes.
+//! Nodes remember it for each of the sender's addresses; see
+//! [`NodeInstance::wire_format_for`].
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    random_u64,
+    wire::{CompactFormat, DecodeError, PinnedCompactFormat, Reader, Writer},
+    Address, BoxFuture, Identity, Message, MessageStatus, NodeInstance, RejectReason,
+    WithdrawReason,
+};
+
+/// Marks a message whose payload is a [`ControlMessage`].
+pub const CONTROL_HEADER: &str = "anytape-control";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// The final status of the message with `unique_id`. Receipts with a
+    /// reject reason added after the first version are encoded as the second,
+    /// which older peers drop and count instead of failing to decode.
+    Receipt {
+        unique_id: u64,
+        status: MessageStatus,
+    },
+    /// Introduces the sending node.
+    Hello {
+        name: Option<String>,
+        addresses: Vec<Address>,
+        /// The newest wire format version the sender decodes. Hellos without one
+        /// keep the encoding older peers read.
+        wire_version: Option<u8>,
+    },
+    Subscribe {
+        topic: String,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    /// The sender can reach `destination` at `cost`.
+    RouteAdvert {
+        destination: Address,
+        cost: u32,
+    },
+    /// Asks the receiver to hold back traffic to `scope` for `retry_after`,
+    /// pausing it or, with a `rate`, spacing it to that many messages a second;
+    /// see [`NodeInstance::with_throttle_feedback`].
+    Throttle {
+        scope: ThrottleScope,
+        retry_after: Duration,
+        rate: Option<u32>,
+    },
+    /// A liveness probe; the answer echoes `nonce` with `pong` set.
+    Ping {
+        nonce: u64,
+        pong: bool,
+    },
+    /// The sender, reached at `via`, can no longer deliver to `destination`; see
+    /// [`NodeInstance::with_route_withdraw`].
+    RouteWithdraw {
+        destination: Address,
+        via: Address,
+        reason: WithdrawReason,
+        /// How many more hops upstream the withdraw may travel.
+        ttl: u8,
+    },
+    /// The sender moves from `old` to `new`, and messages for `old` should
+    /// reach it until `valid_until`, in Unix milliseconds; see
+    /// [`NodeInstance::with_identity_rotation`]. Signed with the key of `old`.
+    IdentityRotation {
+        old: Identity,
+        new: Identity,
+        valid_until: u64,
+    },
+}
+
+/// What a [`ControlMessage::Throttle`] holds back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThrottleScope {
+    /// Traffic to the node sending the throttle.
+    Sender,
+    /// Traffic to this address. Unless the throttle is
+    /// [authorized](NodeInstance::with_throttle_authorizer), only honored if it
+    /// is the sender's.
+    Destination(Address),
+    /// All traffic of the receiver. Unless the throttle is authorized, only
+    /// traffic to the sender is held back.
+    All,
+}
+
+/// The variant of a [`ControlMessage`], used to pick its handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControlKind {
+    Receipt,
+    Hello,
+    Subscribe,
+    Unsubscribe,
+    RouteAdvert,
+    Throttle,
+    Ping,
+    RouteWithdraw,
+    IdentityRotation,
+}
+
+impl ControlKind {
+    const ALL: [ControlKind; 9] = [
+        ControlKind::Receipt,
+        ControlKind::Hello,
+        ControlKind::Subscribe,
+        ControlKind::Unsubscribe,
+        ControlKind::RouteAdvert,
+        ControlKind::Throttle,
+        ControlKind::Ping,
+        ControlKind::RouteWithdraw,
+        ControlKind::IdentityRotation,
+    ];
+
+    fn tag(self) -> u8 {
+        match self {
+            ControlKind::Receipt => 0,
+            ControlKind::Hello => 1,
+            ControlKind::Subscribe => 2,
+            ControlKind::Unsubscribe => 3,
+            ControlKind::RouteAdvert => 4,
+            ControlKind::Throttle => 5,
+            ControlKind::Ping => 6,
+            ControlKind::RouteWithdraw => 7,
+            ControlKind::IdentityRotation => 8,
+        }
+    }
+    /// The newest encoding of this variant; older peers drop anything newer.
+    fn version(self) -> u8 {
+        match self {
+            ControlKind::Receipt | ControlKind::Throttle | ControlKind::Hello => 2,
+            _ => 1,
+        }
+    }
+    fn decodes(self, version: u8) -> bool {
+        match self {
+            ControlKind::Receipt | ControlKind::Hello => matches!(version, 1 | 2),
+            kind => kind.version() == version,
+        }
+    }
+    fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.tag() == tag)
+    }
+}
+
+/// The oldest version of [`ControlKind::Receipt`] whose peers know `status`.
+fn receipt_version(status: MessageStatus) -> u8 {
+    match status {
+        MessageStatus::Rejected {
+            reason:
+                RejectReason::Quarantined
+                | RejectReason::DuplicateContent
+                | RejectReason::SchemaViolation
+                | RejectReason::Unknown(_),
+        } => 2,
+        _ => 1,
+    }
+}
+
+pub(crate) fn put_status(w: &mut Writer, status: MessageStatus) {
+    let (code, reason) = match status {
+        MessageStatus::Sended => (0, None),
+        MessageStatus::Received => (1, None),
+        MessageStatus::Unreachable => (2, None),
+        MessageStatus::SendError => (3, None),
+        MessageStatus::Rejected { reason } => (4, Some(reason)),
+        MessageStatus::Expired => (5, None),
+    };
+    w.put_u8(code);
+    if let Some(reason) = reason {
+        w.put_u8(match reason {
+            RejectReason::Duplicate => 0,
+            RejectReason::TtlExceeded => 1,
+            RejectReason::InvalidSignature => 2,
+            RejectReason::PathTooLong => 3,
+            RejectReason::NoHandler => 4,
+            RejectReason::QuotaExceeded => 5,
+            RejectReason::Quarantined => 6,
+            RejectReason::DuplicateContent => 7,
+            RejectReason::SchemaViolation => 8,
+            RejectReason::Unknown(code) => code,
+        });
+    }
+}
+
+pub(crate) fn get_status(r: &mut Reader<'_>) -> Result<MessageStatus, DecodeError> {
+    Ok(match r.get_u8()? {
+        0 => MessageStatus::Sended,
+        1 => MessageStatus::Received,
+        2 => MessageStatus::Unreachable,
+        3 => MessageStatus::SendError,
+        4 => MessageStatus::Rejected {
+            reason: match r.get_u8()? {
+                0 => RejectReason::Duplicate,
+                1 => RejectReason::TtlExceeded,
+                2 => RejectReason::InvalidSignature,
+                3 => RejectReason::PathTooLong,
+                4 => RejectReason::NoHandler,
+                5 => RejectReason::QuotaExceeded,
+                6 => RejectReason::Quarantined,
+                7 => RejectReason::DuplicateContent,
+                8 => RejectReason::SchemaViolation,
+                // from a newer peer; the receipt is worth keeping all the same
+                code => RejectReason::Unknown(code),
+            },
+        },
+        5 => MessageStatus::Expired,
+        code => return Err(DecodeError::InvalidFlags(code)),
+    })
+}
+
+fn get_string(r: &mut Reader<'_>) -> Result<String, DecodeError> {
+    std::str::from_utf8(r.get_bytes()?)
+        .map(str::to_owned)
+        .map_err(|_| DecodeError::InvalidUtf8)
+}
+
+impl ControlMessage {
+    /// A [`Hello`](ControlMessage::Hello) announcing the wire format version of
+    /// this build.
+    pub fn hello(name: Option<String>, addresses: Vec<Address>) -> Self {
+        ControlMessage::Hello {
+            name,
+            addresses,
+            wire_version: Some(CompactFormat::VERSION),
+        }
+    }
+
+    pub fn kind(&self) -> ControlKind {
+        match self {
+            ControlMessage::Receipt { .. } => ControlKind::Receipt,
+            ControlMessage::Hello { .. } => ControlKind::Hello,
+            ControlMessage::Subscribe { .. } => ControlKind::Subscribe,
+            ControlMessage::Unsubscribe { .. } => ControlKind::Unsubscribe,
+            ControlMessage::RouteAdvert { .. } => ControlKind::RouteAdvert,
+            ControlMessage::Throttle { .. } => ControlKind::Throttle,
+            ControlMessage::Ping { .. } => ControlKind::Ping,
+            ControlMessage::RouteWithdraw { .. } => ControlKind::RouteWithdraw,
+            ControlMessage::IdentityRotation { .. } => ControlKind::IdentityRotation,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        let kind = self.kind();
+        w.put_u8(kind.tag());
+        w.put_u8(match self {
+            ControlMessage::Hello {
+                wire_version: None, ..
+            } => 1,
+            ControlMessage::Receipt { status, .. } => receipt_version(*status),
+            _ => kind.version(),
+        });
+        match self {
+            ControlMessage::Receipt { unique_id, status } => {
+                w.put_u64(*unique_id);
+                put_status(&mut w, *status);
+            }
+            ControlMessage::Hello {
+                name,
+                addresses,
+                wire_version,
+            } => {
+                match name {
+                    Some(name) => {
+                        w.put_u8(1);
+                        w.put_bytes(name.as_bytes());
+                    }
+                    None => w.put_u8(0),
+                }
+                w.put_varint(addresses.len() as u64);
+                for address in addresses {
+                    w.put_address(address);
+                }
+                if let Some(wire_version) = wire_version {
+                    w.put_u8(*wire_version);
+                }
+            }
+            ControlMessage::Subscribe { topic } | ControlMessage::Unsubscribe { topic } => {
+                w.put_bytes(topic.as_bytes())
+            }
+            ControlMessage::RouteAdvert { destination, cost } => {
+                w.put_address(destination);
+                w.put_varint(u64::from(*cost));
+            }
+            ControlMessage::Throttle {
+                scope,
+                retry_after,
+                rate,
+            } => {
+                match scope {
+                    ThrottleScope::Sender => w.put_u8(0),
+                    ThrottleScope::Destination(destination) => {
+                        w.put_u8(1);
+                        w.put_address(destination);
+                    }
+                    ThrottleScope::All => w.put_u8(2),
+                }
+                w.put_varint(retry_after.as_millis().min(u128::from(u64::MAX)) as u64);
+                // zero for no rate, which pauses anyway
+                w.put_varint(u64::from(rate.unwrap_or(0)));
+            }
+            ControlMessage::Ping { nonce, pong } => {
+                w.put_u64(*nonce);
+                w.put_u8(u8::from(*pong));
+            }
+            ControlMessage::RouteWithdraw {
+                destination,
+                via,
+                reason,
+                ttl,
+            } => {
+                w.put_address(destination);
+                w.put_address(via);
+                w.put_u8(reason.tag());
+                w.put_u8(*ttl);
+            }
+            ControlMessage::IdentityRotation {
+                old,
+                new,
+                valid_until,
+            } => {
+                w.put_bytes(old.as_bytes());
+                w.put_bytes(new.as_bytes());
+                w.put_u64(*valid_until);
+            }
+        }
+        w.finish()
+    }
+
+    /// Decode a control payload. Unknown variants and versions give `Ok(None)`.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let tag = r.get_u8()?;
+        let version = r.get_u8()?;
+        let Some(kind) = ControlKind::from_tag(tag).filter(|kind| kind.decodes(version)) else {
+            return Ok(None);
+        };
+        let message = match kind {
+            ControlKind::Receipt => ControlMessage::Receipt {
+                unique_id: r.get_u64()?,
+                status: get_status(&mut r)?,
+            },
+            ControlKind::Hello => {
+                let name = match r.get_u8()? {
+                    0 => None,
+                    1 => Some(get_string(&mut r)?),
+                    flags => return Err(DecodeError::InvalidFlags(flags)),
+                };
+                let count = r.get_varint()?;
+                let mut addresses = Vec::new();
+                for _ in 0..count {
+                    addresses.push(r.get_address()?);
+                }
+                let wire_version = match version {
+                    1 => None,
+                    _ => Some(r.get_u8()?),
+                };
+                ControlMessage::Hello {
+                    name,
+                    addresses,
+                    wire_version,
+                }
+            }
+            ControlKind::Subscribe => ControlMessage::Subscribe {
+                topic: get_string(&mut r)?,
+            },
+            ControlKind::Unsubscribe => ControlMessage::Unsubscribe {
+                topic: get_string(&mut r)?,
+            },
+            ControlKind::RouteAdvert => ControlMessage::RouteAdvert {
+                destination: r.get_address()?,
+                cost: u32::try_from(r.get_varint()?).map_err(|_| DecodeError::VarintOverflow)?,
+            },
+            ControlKind::Throttle => ControlMessage::Throttle {
+                scope: match r.get_u8()? {
+                    0 => ThrottleScope::Sender,
+                    1 => ThrottleScope::Destination(r.get_address()?),
+                    2 => ThrottleScope::All,
+                    flags => return Err(DecodeError::InvalidFlags(flags)),
+                },
+                retry_after: Duration::from_millis(r.get_varint()?),
+                rate: match r.get_varint()? {
+                    0 => None,
+                    rate => Some(u32::try_from(rate).map_err(|_| DecodeError::VarintOverflow)?),
+                },
+            },
+            ControlKind::Ping => ControlMessage::Ping {
+                nonce: r.get_u64()?,
+                pong: match r.get_u8()? {
+                    0 => false,
+                    1 => true,
+                    flags => return Err(DecodeError::InvalidFlags(flags)),
+                },
+            },
+            ControlKind::RouteWithdraw => ControlMessage::RouteWithdraw {
+                destination: r.get_address()?,
+                via: r.get_address()?,
+                reason: {
+                    let tag = r.get_u8()?;
+                    WithdrawReason::from_tag(tag).ok_or(DecodeError::InvalidFlags(tag))?
+                },
+                ttl: r.get_u8()?,
+            },
+            ControlKind::IdentityRotation => ControlMessage::IdentityRotation {
+                old: Identity::new(r.get_bytes()?),
+                new: Identity::new(r.get_bytes()?),
+                valid_until: r.get_u64()?,
+            },
+        };
+        r.finish()?;
+        Ok(Some(message))
+    }
+
+    /// Wrap this control message in a message for the node at `to`.
+    pub fn into_message(self, to: Address) -> Message {
+        Message {
+            destination: to,
+            path: Vec::new(),
+            payload: self.encode(),
+            signature: Vec::new(),
+            unique_id: random_u64(),
+            ttl: None,
+            headers: [(CONTROL_HEADER.to_owned(), Vec::new())].into(),
+            metadata: Vec::new(),
+            extensions: Vec::new(),
+            seq: None,
+            budget_ms: None,
+            route_plan: None,
+            reply_to: None,
+        }
+    }
+}
+
+/// Receives the control messages of one [`ControlKind`], along with the message
+/// that carried them.
+pub trait ControlHandler: Send + Sync {
+    fn handle(&self, control: ControlMessage, envelope: Message) -> BoxFuture<()>;
+}
+
+impl<F, Fut> ControlHandler for F
+where
+    F: Fn(ControlMessage, Message) -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn handle(&self, control: ControlMessage, envelope: Message) -> BoxFuture<()> {
+        Box::pin(self(control, envelope))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ControlState {
+    pub(crate) handlers: HashMap<ControlKind, Arc<dyn ControlHandler>>,
+    unknown: AtomicU64,
+    /// The wire format versions peers announced, by address.
+    wire_versions: RwLock<HashMap<Address, u8>>,
+}
+
+impl NodeInstance {
+    /// Hand control messages of `kind` addressed to this node to `handler`.
+    pub fn with_control_handler(
+        mut self,
+        kind: ControlKind,
+        handler: impl ControlHandler + 'static,
+    ) -> Self {
+        self.control.handlers.insert(kind, Arc::new(handler));
+        self
+    }
+    /// How many control messages of unknown variants or versions were dropped.
+    pub fn unknown_control_messages(&self) -> u64 {
+        self.control.unknown.load(Ordering::Relaxed)
+    }
+    /// The newest wire format version the peer at `peer` announced in a
+    /// [`Hello`](ControlMessage::Hello), if it did.
+    pub fn peer_wire_version(&self, peer: &Address) -> Option<u8> {
+        self.control
+            .wire_versions
+            .read()
+            .unwrap()
+            .get(peer)
+            .copied()
+    }
+    /// The format to encode messages for `peer` in: the version it
+    /// [announced](NodeInstance::peer_wire_version), if older than this build's,
+    /// else the current one.
+    pub fn wire_format_for(&self, peer: &Address) -> PinnedCompactFormat {
+        let version = self.peer_wire_version(peer);
+        CompactFormat::pinned(version.map_or(CompactFormat::VERSION, |version| {
+            version.min(CompactFormat::VERSION)
+        }))
+    }
+    pub(crate) async fn handle_control(&self, mut message: Message) -> MessageStatus {
+        let payload = std::mem::take(&mut message.payload);
+        let control = match ControlMessage::decode(&payload) {
+            Ok(Some(control)) => control,
+            Ok(None) => {
+                self.control.unknown.fetch_add(1, Ordering::Relaxed);
+                return MessageStatus::Received;
+            }
+            Err(_) => return MessageStatus::SendError,
+        };
+        message.payload = payload;
+        if let ControlMessage::RouteWithdraw {
+            destination,
+            via,
+            reason,
+            ttl,
+        } = &control
+        {
+            if self.route_withdraw_enabled() {
+                return self
+                    .apply_withdraw(destination, via, *reason, *ttl, &message)
+                    .await;
+            }
+        }
+        if let ControlMessage::IdentityRotation {
+            old,
+            new,
+            valid_until,
+        } = &control
+        {
+            if self.rotation_enabled() {
+                return self.apply_rotation(old, new, *valid_until, &message).await;
+            }
+        }
+        if let ControlMessage::Hello {
+            addresses,
+            wire_version: Some(version),
+            ..
+        } = &control
+        {
+            let mut versions = self.control.wire_versions.write().unwrap();
+            for address in addresses {
+                versions.insert(address.clone(), *version);
+            }
+            drop(versions);
+            // remembered whether or not someone listens
+            if !self.control.handlers.contains_key(&ControlKind::Hello) {
+                return MessageStatus::Received;
+            }
+        }
+        if let ControlMessage::Throttle {
+            scope,
+            retry_after,
+            rate,
+        } = &control
+        {
+            self.apply_throttle(scope, *retry_after, *rate, &message);
+            // throttles are honored whether or not someone listens
+            if !self.control.handlers.contains_key(&ControlKind::Throttle) {
+                return MessageStatus::Received;
+            }
+        }
+        match self.control.handlers.get(&control.kind()) {
+            Some(handler) => {
+                handler.handle(control, message).await;
+                MessageStatus::Received
+            }
+            None => MessageStatus::Rejected {
+                reason: RejectReason::NoHandler,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::addr;
+
+    fn every_variant() -> Vec<ControlMessage> {
+        vec![
+            ControlMessage::Receipt {
+                unique_id: 7,
+                status: MessageStatus::Rejected {
+                    reason: RejectReason::Duplicate,
+                },
+            },
+            ControlMessage::hello(Some("node".to_owned()), vec![addr("a"), addr("b")]),
+            ControlMessage::Hello {
+                name: None,
+                addresses: Vec::new(),
+                wire_version: None,
+            },
+            ControlMessage::Subscribe {
+                topic: "news".to_owned(),
+            },
+            ControlMessage::Unsubscribe {
+                topic: "news".to_owned(),
+            },
+            ControlMessage::RouteAdvert {
+                destination: addr("far"),
+                cost: 12,
+            },
+            ControlMessage::Throttle {
+                scope: ThrottleScope::Destination(addr("busy")),
+                retry_after: Duration::from_millis(1500),
+                rate: Some(10),
+            },
+            ControlMessage::Throttle {
+                scope: ThrottleScope::All,
+                retry_after: Duration::from_secs(1),
+                rate: None,
+            },
+            ControlMessage::Ping {
+                nonce: u64::MAX,
+                pong: true,
+            },
+            ControlMessage::RouteWithdraw {
+                destination: addr("far"),
+                via: addr("relay"),
+                reason: WithdrawReason::Manual,
+                ttl: 3,
+            },
+            ControlMessage::IdentityRotation {
+                old: Identity::new("old"),
+                new: Identity::new("new"),
+                valid_until: 1_700_000_000_000,
+            },
+        ]
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let variants = every_variant();
+        let kinds: std::collections::HashSet<_> = variants.iter().map(|c| c.kind()).collect();
+        assert_eq!(kinds.len(), ControlKind::ALL.len());
+        for control in variants {
+            let bytes = control.encode();
+            assert_eq!(bytes[0], control.kind().tag());
+            assert_eq!(ControlMessage::decode(&bytes).unwrap(), Some(control));
+        }
+    }
+
+    #[test]
+    fn receipts_older_peers_cannot_read_use_the_second_version() {
+        let receipt = |reason| ControlMessage::Receipt {
+            unique_id: 7,
+            status: MessageStatus::Rejected { reason },
+        };
+        for (reason, version) in [
+            (RejectReason::Duplicate, 1),
+            (RejectReason::QuotaExceeded, 1),
+            (RejectReason::Quarantined, 2),
+            (RejectReason::DuplicateContent, 2),
+            (RejectReason::SchemaViolation, 2),
+            (RejectReason::Unknown(0xff), 2),
+        ] {
+            let bytes = receipt(reason).encode();
+            assert_eq!(bytes[1], version, "{reason:?}");
+            assert_eq!(
+                ControlMessage::decode(&bytes).unwrap(),
+                Some(receipt(reason))
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_variants_and_versions_decode_as_none() {
+        assert_eq!(ControlMessage::decode(&[0xee, 1, 1, 2, 3]).unwrap(), None);
+        let mut ping = ControlMessage::Ping {
+            nonce: 1,
+            pong: false,
+        }
+        .encode();
+        ping[1] = 9;
+        assert_eq!(ControlMessage::decode(&ping).unwrap(), None);
+        // reject reasons added by newer peers still decode
+        let mut receipt = ControlMessage::Receipt {
+            unique_id: 7,
+            status: MessageStatus::Rejected {
+                reason: RejectReason::Duplicate,
+            },
+        }
+        .encode();
+        *receipt.last_mut().unwrap() = 0xff;
+        let status = MessageStatus::Rejected {
+            reason: RejectReason::Unknown(0xff),
+        };
+        let decoded = ControlMessage::Receipt {
+            unique_id: 7,
+            status,
+        };
+        assert_eq!(
+            ControlMessage::decode(&receipt).unwrap(),
+            Some(decoded.clone())
+        );
+        assert_eq!(decoded.encode()[2..], receipt[2..]);
+        assert!(ControlMessage::decode(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn control_messages_reach_the_handler_for_their_kind() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler = |name: &'static str| {
+            let seen = seen.clone();
+            move |control: ControlMessage, _: Message| {
+                seen.lock().unwrap().push((name, control));
+                async {}
+            }
+        };
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_handler(|_| async { panic!("control traffic reached the receive handler") })
+            .with_control_handler(ControlKind::Ping, handler("ping"))
+            .with_control_handler(ControlKind::Subscribe, handler("subscribe"));
+        let receive =
+            |control: ControlMessage| node.receive(control.into_message(addr("me")), addr("me"));
+
+        let ping = ControlMessage::Ping {
+            nonce: 5,
+            pong: false,
+        };
+        let subscribe = ControlMessage::Subscribe {
+            topic: "news".to_owned(),
+        };
+        assert_eq!(receive(ping.clone()).await, MessageStatus::Received);
+        assert_eq!(receive(subscribe.clone()).await, MessageStatus::Received);
+        assert_eq!(
+            receive(ControlMessage::RouteAdvert {
+                destination: addr("far"),
+                cost: 1,
+            })
+            .await,
+            MessageStatus::Rejected {
+                reason: RejectReason::NoHandler
+            }
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [("ping", ping), ("subscribe", subscribe)]
+        );
+
+        let mut unknown = ControlMessage::Ping {
+            nonce: 6,
+            pong: false,
+        }
+        .into_message(addr("me"));
+        unknown.payload[0] = 0xee;
+        assert_eq!(
+            node.receive(unknown, addr("me")).await,
+            MessageStatus::Received
+        );
+        assert_eq!(node.unknown_control_messages(), 1);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,299 @@
+//! Latency weighted route selection.
+//!
+//! A destination can have several candidate next hops. Every forward over a
+//! candidate updates its cost, an exponentially weighted moving average of the
+//! observed send latency, and failures add a penalty. Under
+//! [`RouteSelection::LowestCost`], [`NodeInstance::resolve_next`] picks the
+//! cheapest candidate, except for an occasional random pick that keeps the costs
+//! of the others fresh.
+
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use crate::{random_u64, Address, AuditKind, NodeEvent, NodeInstance};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RouteSelection {
+    /// Use the route cache and backend only; candidates are ignored.
+    #[default]
+    First,
+    /// Pick the cheapest candidate, or with probability `exploration` a random one.
+    LowestCost { exploration: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CostConfig {
+    /// Weight of a new sample in the moving average, between 0 and 1.
+    pub alpha: f64,
+    /// Added to the cost of a candidate every time a send over it fails.
+    pub failure_penalty: Duration,
+    /// Cost of a candidate that has not been used yet.
+    pub initial_cost: Duration,
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        Self {
+            alpha: 0.3,
+            failure_penalty: Duration::from_secs(1),
+            initial_cost: Duration::ZERO,
+        }
+    }
+}
+
+/// A possible next hop towards a destination and what using it has cost so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteCandidate {
+    pub next: Address,
+    pub cost: Duration,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct CostTable {
+    pub(crate) selection: RouteSelection,
+    pub(crate) config: CostConfig,
+    candidates: RwLock<HashMap<Address, Vec<RouteCandidate>>>,
+}
+
+impl CostTable {
+    pub(crate) fn select(
+        &self,
+        destination: &Address,
+        usable: impl Fn(&Address) -> bool,
+    ) -> Option<Address> {
+        let RouteSelection::LowestCost { exploration } = self.selection else {
+            return None;
+        };
+        let candidates = self.candidates.read().unwrap();
+        let candidates: Vec<&RouteCandidate> = candidates
+            .get(destination)?
+            .iter()
+            .filter(|candidate| usable(&candidate.next))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let roll = random_u64() as f64 / u64::MAX as f64;
+        let chosen = if roll < exploration {
+            candidates[random_u64() as usize % candidates.len()]
+        } else {
+            *candidates.iter().min_by_key(|candidate| candidate.cost)?
+        };
+        Some(chosen.next.clone())
+    }
+    /// Feed the outcome of a send over `next`: its latency, or `None` on failure.
+    pub(crate) fn observe(&self, destination: &Address, next: &Address, latency: Option<Duration>) {
+        let mut candidates = self.candidates.write().unwrap();
+        let Some(candidate) = candidates
+            .get_mut(destination)
+            .and_then(|c| c.iter_mut().find(|candidate| &candidate.next == next))
+        else {
+            return;
+        };
+        match latency {
+            Some(latency) => {
+                candidate.cost = if candidate.successes == 0 && candidate.failures == 0 {
+                    latency
+                } else {
+                    let alpha = self.config.alpha.clamp(0.0, 1.0);
+                    candidate.cost.mul_f64(1.0 - alpha) + latency.mul_f64(alpha)
+                };
+                candidate.successes += 1;
+            }
+            None => {
+                candidate.cost += self.config.failure_penalty;
+                candidate.failures += 1;
+            }
+        }
+    }
+}
+
+impl NodeInstance {
+    pub fn with_route_selection(mut self, selection: RouteSelection) -> Self {
+        self.costs.selection = selection;
+        self
+    }
+    pub fn with_route_cost_config(mut self, config: CostConfig) -> Self {
+        self.costs.config = config;
+        self
+    }
+    /// Make `next` a candidate next hop towards `destination`.
+    pub fn add_route_candidate(&self, destination: Address, next: Address) {
+        let event = self
+            .audit_event(AuditKind::RouteChanged, "candidate added")
+            .address(&destination)
+            .address(&next);
+        let mut candidates = self.costs.candidates.write().unwrap();
+        let candidates = candidates.entry(destination.clone()).or_default();
+        if !candidates.iter().any(|candidate| candidate.next == next) {
+            candidates.push(RouteCandidate {
+                next: next.clone(),
+                cost: self.costs.config.initial_cost,
+                successes: 0,
+                failures: 0,
+            });
+            self.audit(event);
+            self.publish(|| NodeEvent::RouteCandidateAdded { destination, next });
+        }
+    }
+    pub fn remove_route_candidate(&self, destination: &Address, next: &Address) {
+        if let Some(candidates) = self.costs.candidates.write().unwrap().get_mut(destination) {
+            let before = candidates.len();
+            candidates.retain(|candidate| &candidate.next != next);
+            if candidates.len() < before {
+                self.audit(
+                    self.audit_event(AuditKind::RouteChanged, "candidate removed")
+                        .address(destination)
+                        .address(next),
+                );
+                self.publish(|| NodeEvent::RouteCandidateRemoved {
+                    destination: destination.clone(),
+                    next: next.clone(),
+                });
+            }
+        }
+    }
+    /// The candidates towards `destination` with their current costs.
+    pub fn route_costs(&self, destination: &Address) -> Vec<RouteCandidate> {
+        self.costs
+            .candidates
+            .read()
+            .unwrap()
+            .get(destination)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// Feed a round trip time measured out of band, e.g. by a ping, into the cost
+    /// of `next` towards `destination`.
+    pub fn record_route_rtt(&self, destination: &Address, next: &Address, rtt: Duration) {
+        self.costs.observe(destination, next, Some(rtt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::testing::{addr, message, TestError, TEST};
+    use crate::{Identity, Message, MessageStatus, ProtocolExecutor, SendOutcome};
+
+    /// Relays that take their own time to send, or fail while they are down.
+    #[derive(Clone, Default)]
+    struct Relays {
+        delays: Arc<Mutex<HashMap<Identity, Option<Duration>>>>,
+        used: Arc<Mutex<Vec<Identity>>>,
+    }
+
+    impl Relays {
+        fn set(&self, relay: &str, delay: Option<Duration>) {
+            let mut delays = self.delays.lock().unwrap();
+            delays.insert(Identity::new(relay), delay);
+        }
+        fn used(&self) -> Vec<Identity> {
+            std::mem::take(&mut *self.used.lock().unwrap())
+        }
+    }
+
+    impl ProtocolExecutor for Relays {
+        type Error = TestError;
+        fn send(
+            &self,
+            remote: &Identity,
+            _: Message,
+        ) -> impl Future<Output = Result<SendOutcome, TestError>> + Send + 'static {
+            self.used.lock().unwrap().push(remote.clone());
+            let delay = self.delays.lock().unwrap().get(remote).copied().flatten();
+            async move {
+                let delay = delay.ok_or(TestError("down"))?;
+                tokio::time::sleep(delay).await;
+                Ok(SendOutcome::sent())
+            }
+        }
+        fn get_status(
+            &self,
+            _: &Identity,
+            _: Message,
+        ) -> impl Future<Output = Result<MessageStatus, TestError>> + Send + 'static {
+            std::future::ready(Ok(MessageStatus::Sended))
+        }
+    }
+
+    const FAST: Duration = Duration::from_millis(10);
+    const SLOW: Duration = Duration::from_millis(50);
+
+    fn node(relays: &Relays) -> NodeInstance {
+        relays.set("fast", Some(FAST));
+        relays.set("slow", Some(SLOW));
+        let node = NodeInstance::new()
+            .with_executor(TEST, relays.clone())
+            .with_route_selection(RouteSelection::LowestCost { exploration: 0.0 });
+        node.add_route_candidate(addr("dest"), addr("fast"));
+        node.add_route_candidate(addr("dest"), addr("slow"));
+        node
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn traffic_settles_on_the_faster_relay() {
+        let relays = Relays::default();
+        let node = node(&relays);
+        for _ in 0..5 {
+            node.forward(message(addr("dest"), b"x")).await.unwrap();
+        }
+        // both are tried once while they cost nothing, then the fast one wins
+        let used = relays.used();
+        assert_eq!(used[2..], vec![Identity::new("fast"); 3]);
+        let costs = node.route_costs(&addr("dest"));
+        assert_eq!(
+            costs
+                .iter()
+                .map(|c| (c.cost, c.successes))
+                .collect::<Vec<_>>(),
+            [(FAST, 4), (SLOW, 1)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn traffic_moves_off_a_failing_relay_and_back_once_it_recovers() {
+        let relays = Relays::default();
+        let node = node(&relays);
+        for _ in 0..3 {
+            node.forward(message(addr("dest"), b"x")).await.unwrap();
+        }
+        relays.used();
+
+        relays.set("fast", None);
+        assert!(node.forward(message(addr("dest"), b"x")).await.is_err());
+        for _ in 0..3 {
+            node.forward(message(addr("dest"), b"x")).await.unwrap();
+        }
+        assert_eq!(
+            relays.used(),
+            [
+                Identity::new("fast"),
+                Identity::new("slow"),
+                Identity::new("slow"),
+                Identity::new("slow")
+            ]
+        );
+        let fast = &node.route_costs(&addr("dest"))[0];
+        assert_eq!(
+            (fast.failures, fast.cost),
+            (1, FAST + Duration::from_secs(1))
+        );
+
+        // pings bring the cost of the recovered relay back down
+        relays.set("fast", Some(FAST));
+        for _ in 0..10 {
+            node.record_route_rtt(&addr("dest"), &addr("fast"), FAST);
+        }
+        node.forward(message(addr("dest"), b"x")).await.unwrap();
+        assert_eq!(relays.used(), [Identity::new("fast")]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::Message;
+
+/// Header carrying a propagated deadline as little-endian unix milliseconds.
+pub const DEADLINE_HEADER: &str = "anytape-deadline";
+
+/// The point in time after which the caller no longer cares about a send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: Instant,
+    propagated: bool,
+}
+
+impl Deadline {
+    pub fn at(at: Instant) -> Self {
+        Self {
+            at,
+            propagated: false,
+        }
+    }
+    pub fn after(timeout: Duration) -> Self {
+        Self::at(Instant::now() + timeout)
+    }
+    /// Also stamp the deadline into the message so relays drop it once it passes.
+    pub fn propagated(self) -> Self {
+        Self {
+            propagated: true,
+            ..self
+        }
+    }
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+    pub fn is_propagated(&self) -> bool {
+        self.propagated
+    }
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+    /// Timeout for a single attempt made under this deadline: never longer than
+    /// what is left, nor than the attempt's own `configured` timeout.
+    pub fn attempt_timeout(&self, configured: Duration) -> Duration {
+        self.remaining().min(configured)
+    }
+    pub(crate) fn stamp(&self, message: &mut Message, now_millis: u64) {
+        let expires_at = now_millis + self.remaining().as_millis() as u64;
+        message.headers.insert(
+            DEADLINE_HEADER.to_owned(),
+            expires_at.to_le_bytes().to_vec(),
+        );
+    }
+    /// Whether `message` carries a propagated deadline that passed more than
+    /// `tolerance` before `now_millis`. Malformed headers are ignored.
+    pub(crate) fn header_expired(message: &Message, now_millis: u64, tolerance: Duration) -> bool {
+        let Some(expires_at) = Self::header_expires_at(message) else {
+            return false;
+        };
+        now_millis > expires_at.saturating_add(tolerance.as_millis() as u64)
+    }
+    /// When the deadline propagated in `message` passes, in unix milliseconds.
+    pub(crate) fn header_expires_at(message: &Message) -> Option<u64> {
+        message
+            .headers
+            .get(DEADLINE_HEADER)
+            .and_then(|value| <[u8; 8]>::try_from(value.as_slice()).ok())
+            .map(u64::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{ManualClock, NodeInstance, RetryPolicy, RetryingExecutor, SendError};
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_stop_exactly_at_the_deadline() {
+        let backoff = Duration::from_millis(100);
+        let retrying = RetryingExecutor::new(
+            Recorder::new().failing("down"),
+            RetryPolicy::new(100).with_backoff(backoff, backoff),
+        );
+        let node = NodeInstance::new().with_executor(TEST, retrying);
+        let start = Instant::now();
+        let deadline = Deadline::after(Duration::from_millis(250));
+        let result = node
+            .send_with_deadline(message(addr("b"), b"x"), addr("b"), deadline)
+            .await;
+        assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn propagated_deadlines_are_stamped_into_the_message() {
+        let recorder = Recorder::new();
+        let clock = ManualClock::new(1_000_000);
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_clock(clock);
+        let deadline = Deadline::after(Duration::from_millis(250)).propagated();
+        node.send_with_deadline(message(addr("b"), b"x"), addr("b"), deadline)
+            .await
+            .unwrap();
+        let sent = &recorder.sent()[0];
+        assert_eq!(Deadline::header_expires_at(sent), Some(1_000_250));
+    }
+
+    #[tokio::test]
+    async fn relays_drop_messages_past_their_deadline() {
+        let recorder = Recorder::new();
+        let clock = ManualClock::new(1_000_000);
+        let relay = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_clock(clock.clone())
+            .with_clock_skew_tolerance(Duration::from_millis(100));
+        let mut late = message(addr("d"), b"x");
+        late.headers.insert(
+            DEADLINE_HEADER.to_owned(),
+            1_000_050u64.to_le_bytes().to_vec(),
+        );
+
+        // within the tolerance for skew between the clocks of the two nodes
+        clock.advance(Duration::from_millis(100));
+        relay
+            .dispatch_inbound(late.clone(), addr("relay"))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_millis(100));
+        let result = relay.dispatch_inbound(late, addr("relay")).await;
+        assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+        assert_eq!(recorder.sent().len(), 1);
+    }
+}
@@ -0,0 +1,279 @@
+//! Dropping resent payloads that arrive under fresh ids.
+//!
+//! With [content deduplication](NodeInstance::with_content_dedup) on, a node
+//! takes the SHA-256 digest of the destination and payload of every inbound
+//! message and remembers it for [`ContentDedupConfig::window`]. A message whose
+//! digest was seen within the window is a duplicate, whatever its `unique_id`,
+//! and is handled according to the [`ContentDedupPolicy`].
+//!
+//! Each node hashes the payload itself and records the digest in a
+//! [`CONTENT_DIGEST_HEADER`] for the application and later hops to look at. A
+//! digest arriving in that header is never trusted, since any upstream peer
+//! could set it to that of a message it wants suppressed. Fragments and control
+//! messages are never deduplicated.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    control::CONTROL_HEADER, wire::Writer, Address, Message, MessageStatus, NodeInstance,
+    RejectReason, FRAGMENT_HEADER,
+};
+
+/// Carries the 32 byte content digest the last deduplicating node computed for
+/// a message, for information only.
+pub const CONTENT_DIGEST_HEADER: &str = "anytape-content-digest";
+/// Set, empty, on duplicates delivered under [`ContentDedupPolicy::Annotate`].
+pub const DUPLICATE_CONTENT_HEADER: &str = "anytape-duplicate-content";
+
+type ContentDigest = [u8; 32];
+
+/// What a node does with a message whose content it has seen before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ContentDedupPolicy {
+    /// Reject it with [`RejectReason::DuplicateContent`].
+    #[default]
+    Drop,
+    /// Mark it with a [`DUPLICATE_CONTENT_HEADER`] and handle it as usual.
+    Annotate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContentDedupConfig {
+    pub policy: ContentDedupPolicy,
+    /// How long a digest counts as seen.
+    pub window: Duration,
+    /// Digests remembered at most; the oldest are forgotten first.
+    pub capacity: usize,
+    /// Payloads longer than this are not hashed, and never count as duplicates.
+    pub max_payload_len: usize,
+}
+
+impl Default for ContentDedupConfig {
+    fn default() -> Self {
+        Self {
+            policy: ContentDedupPolicy::Drop,
+            window: Duration::from_secs(60),
+            capacity: 4096,
+            max_payload_len: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SeenSet {
+    seen: HashMap<ContentDigest, Duration>,
+    order: VecDeque<(ContentDigest, Duration)>,
+}
+
+impl SeenSet {
+    /// Record `digest` as seen `now`, telling whether it already was.
+    fn check(&mut self, digest: ContentDigest, now: Duration, config: &ContentDedupConfig) -> bool {
+        while let Some(&(oldest, at)) = self.order.front() {
+            if now.saturating_sub(at) < config.window && self.order.len() < config.capacity {
+                break;
+            }
+            self.order.pop_front();
+            // only forget the digest if it was not seen again since
+            if self.seen.get(&oldest) == Some(&at) {
+                self.seen.remove(&oldest);
+            }
+        }
+        let duplicate = self.seen.contains_key(&digest);
+        if config.capacity > 0 {
+            self.seen.insert(digest, now);
+            self.order.push_back((digest, now));
+        }
+        duplicate
+    }
+}
+
+pub(crate) struct ContentDedup {
+    config: ContentDedupConfig,
+    seen: Mutex<SeenSet>,
+    duplicates: AtomicU64,
+}
+
+impl Message {
+    /// SHA-256 digest of the destination and payload, as used for
+    /// [content deduplication](NodeInstance::with_content_dedup). Always computed
+    /// afresh; a [`CONTENT_DIGEST_HEADER`] on the message is ignored.
+    pub fn content_digest(&self) -> [u8; 32] {
+        digest_of(&self.destination, &self.payload)
+    }
+}
+
+fn digest_of(destination: &Address, payload: &[u8]) -> ContentDigest {
+    let mut w = Writer::new();
+    w.put_address(destination);
+    let mut hasher = Sha256::new();
+    hasher.update(w.finish());
+    hasher.update(payload);
+    hasher.finalize().into()
+}
+
+impl NodeInstance {
+    /// Treat inbound messages with the same destination and payload as one seen
+    /// recently as duplicates; see the [module docs](self).
+    pub fn with_content_dedup(mut self, config: ContentDedupConfig) -> Self {
+        self.content_dedup = Some(ContentDedup {
+            config,
+            seen: Mutex::default(),
+            duplicates: AtomicU64::new(0),
+        });
+        self
+    }
+    /// Inbound messages found to repeat recently seen content, dropped or not.
+    pub fn content_duplicates(&self) -> u64 {
+        self.content_dedup
+            .as_ref()
+            .map_or(0, |dedup| dedup.duplicates.load(Ordering::Relaxed))
+    }
+    /// Check an inbound message against the recently seen content, returning the
+    /// rejection if it is a duplicate to drop.
+    pub(crate) fn screen_content(&self, message: &mut Message) -> Option<MessageStatus> {
+        let dedup = self.content_dedup.as_ref()?;
+        if message.payload.len() > dedup.config.max_payload_len
+            || message.headers.contains_key(FRAGMENT_HEADER)
+            || message.headers.contains_key(CONTROL_HEADER)
+        {
+            return None;
+        }
+        // messages for a rotated identity are duplicates of those for its successor
+        let digest = digest_of(&self.aliased(&message.destination), &message.payload);
+        message
+            .headers
+            .insert(CONTENT_DIGEST_HEADER.to_owned(), digest.to_vec());
+        let duplicate =
+            dedup
+                .seen
+                .lock()
+                .unwrap()
+                .check(digest, self.clock.monotonic(), &dedup.config);
+        if !duplicate {
+            return None;
+        }
+        dedup.duplicates.fetch_add(1, Ordering::Relaxed);
+        match dedup.config.policy {
+            ContentDedupPolicy::Drop => Some(MessageStatus::Rejected {
+                reason: RejectReason::DuplicateContent,
+            }),
+            ContentDedupPolicy::Annotate => {
+                message
+                    .headers
+                    .insert(DUPLICATE_CONTENT_HEADER.to_owned(), Vec::new());
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message};
+
+    const DUPLICATE: MessageStatus = MessageStatus::Rejected {
+        reason: RejectReason::DuplicateContent,
+    };
+
+    fn node(config: ContentDedupConfig) -> NodeInstance {
+        NodeInstance::new()
+            .with_address(addr("me"))
+            .with_address(addr("other"))
+            .with_handler(|_| async {})
+            .with_content_dedup(config)
+    }
+
+    async fn receive(node: &NodeInstance, to: &str, payload: &[u8]) -> MessageStatus {
+        node.dispatch_inbound(message(addr(to), payload), addr(to))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn identical_payloads_under_fresh_ids_are_duplicates() {
+        let node = node(ContentDedupConfig::default());
+        assert_eq!(receive(&node, "me", b"same").await, MessageStatus::Received);
+        assert_eq!(receive(&node, "me", b"same").await, DUPLICATE);
+        assert_eq!(receive(&node, "me", b"else").await, MessageStatus::Received);
+        assert_eq!(node.content_duplicates(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_same_payload_for_another_destination_is_not_a_duplicate() {
+        let node = node(ContentDedupConfig::default());
+        assert_eq!(receive(&node, "me", b"same").await, MessageStatus::Received);
+        assert_eq!(
+            receive(&node, "other", b"same").await,
+            MessageStatus::Received
+        );
+    }
+
+    #[tokio::test]
+    async fn payloads_over_the_size_threshold_are_not_deduplicated() {
+        let node = node(ContentDedupConfig {
+            max_payload_len: 4,
+            ..ContentDedupConfig::default()
+        });
+        for _ in 0..2 {
+            assert_eq!(
+                receive(&node, "me", b"too long").await,
+                MessageStatus::Received
+            );
+        }
+        assert_eq!(node.content_duplicates(), 0);
+    }
+
+    #[tokio::test]
+    async fn annotated_duplicates_are_delivered_marked() {
+        let (sink, mut inbox) = tokio::sync::mpsc::unbounded_channel();
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_handler(move |message: Message| {
+                let _ = sink.send(message);
+                async {}
+            })
+            .with_content_dedup(ContentDedupConfig {
+                policy: ContentDedupPolicy::Annotate,
+                ..ContentDedupConfig::default()
+            });
+        for _ in 0..2 {
+            assert_eq!(receive(&node, "me", b"same").await, MessageStatus::Received);
+        }
+        let first = inbox.recv().await.unwrap();
+        let second = inbox.recv().await.unwrap();
+        assert!(!first.headers.contains_key(DUPLICATE_CONTENT_HEADER));
+        assert!(second.headers.contains_key(DUPLICATE_CONTENT_HEADER));
+    }
+
+    #[tokio::test]
+    async fn a_forged_digest_header_cannot_suppress_other_content() {
+        let node = node(ContentDedupConfig::default());
+        let victim = message(addr("me"), b"legitimate");
+        let mut forged = message(addr("me"), b"attacker");
+        forged.headers.insert(
+            CONTENT_DIGEST_HEADER.to_owned(),
+            victim.content_digest().to_vec(),
+        );
+        assert_eq!(forged.content_digest(), digest_of(&addr("me"), b"attacker"));
+        assert_eq!(
+            node.dispatch_inbound(forged, addr("me")).await.unwrap(),
+            MessageStatus::Received
+        );
+        assert_eq!(
+            node.dispatch_inbound(victim, addr("me")).await.unwrap(),
+            MessageStatus::Received
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! End-to-end delivery reports for source-routed messages.
+//!
+//! [`NodeInstance::send_traced`] sends a message along its
+//! [route plan](Message::route_plan) and then asks every planned hop, in turn,
+//! for the message's [status](NodeInstance::status). The answers, with the time
+//! each one came in, make up a [`DeliveryReport`]. The first hop that was
+//! unreachable, rejected the message or failed ends the report, since the
+//! message cannot have got past it. Hops this node cannot ask, for want of an
+//! executor for their protocol or one that can
+//! [report status](crate::ExecutorCapabilities::supports_status), end it as
+//! well; the first hop is then reported as [`MessageStatus::Sended`], since it
+//! was handed the message.
+
+use crate::{plan::is_unreachable, Address, Message, MessageStatus, NodeInstance, SendError};
+
+/// What became of a message at each hop; see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+    /// The hops in route order: their address, their status and how many
+    /// milliseconds after the send started it was known. A message without a
+    /// route plan has a single hop without an address, as relays choose the
+    /// rest of its route.
+    pub hops: Vec<(Option<Address>, MessageStatus, u64)>,
+    /// How long sending and tracing took altogether.
+    pub total_ms: u64,
+}
+
+impl DeliveryReport {
+    /// The hop that ended the report by failing, if one did.
+    pub fn failed_hop(&self) -> Option<&(Option<Address>, MessageStatus, u64)> {
+        self.hops
+            .last()
+            .filter(|(_, status, _)| !is_progress(status))
+    }
+}
+
+/// Whether a hop with `status` may have passed the message on.
+fn is_progress(status: &MessageStatus) -> bool {
+    matches!(status, MessageStatus::Sended | MessageStatus::Received)
+}
+
+/// Whether `error` means the hop could not be asked for its status.
+fn cannot_ask(error: &SendError) -> bool {
+    matches!(
+        error,
+        SendError::StatusUnsupported(_) | SendError::ProtocolNotSupport { .. }
+    )
+}
+
+/// The status to report for a hop the message could not be sent to.
+fn failure_status(error: &SendError) -> MessageStatus {
+    match error {
+        SendError::PlannedHopUnreachable { .. } => MessageStatus::Unreachable,
+        SendError::DeadlineExceeded | SendError::BudgetExhausted => MessageStatus::Expired,
+        error if is_unreachable(error) => MessageStatus::Unreachable,
+        _ => MessageStatus::SendError,
+    }
+}
+
+impl NodeInstance {
+    /// [Forward](NodeInstance::forward) `message` and report what became of it
+    /// at every hop of its route plan; see the [module docs](self).
+    ///
+    /// Fails without sending only if the route plan is
+    /// [invalid](SendError::InvalidRoutePlan); failures on the way are part of
+    /// the report. A hop that does not answer the status query within the
+    /// protocol's [send timeout](NodeInstance::with_send_timeout) counts as
+    /// unreachable.
+    pub async fn send_traced(&self, message: Message) -> Result<DeliveryReport, SendError> {
+        // the hops from the next one on; earlier ones, this node among them, are behind
+        let plan: Vec<_> = match self.planned_next(&message)? {
+            Some(next) => message
+                .route_plan
+                .iter()
+                .flatten()
+                .skip_while(|hop| **hop != next)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let start = self.clock.monotonic();
+        let elapsed_ms = || self.clock.monotonic().saturating_sub(start).as_millis() as u64;
+        let query = message.clone();
+        let mut hops = Vec::new();
+
+        if let Err(error) = self.forward(message).await {
+            let hop = match &error {
+                SendError::PlannedHopUnreachable { hop, .. } => Some(hop.clone()),
+                _ => plan.first().cloned(),
+            };
+            hops.push((hop, failure_status(&error), elapsed_ms()));
+            return Ok(DeliveryReport {
+                hops,
+                total_ms: elapsed_ms(),
+            });
+        }
+        if plan.is_empty() {
+            hops.push((None, MessageStatus::Sended, elapsed_ms()));
+        }
+        for (index, hop) in plan.into_iter().enumerate() {
+            let status = match self.hop_status(query.clone(), &hop).await {
+                Ok(status) => status,
+                Err(error) if cannot_ask(&error) && index == 0 => MessageStatus::Sended,
+                Err(error) if cannot_ask(&error) => break,
+                Err(error) => failure_status(&error),
+            };
+            let progress = is_progress(&status);
+            hops.push((Some(hop), status, elapsed_ms()));
+            if !progress {
+                break;
+            }
+        }
+        Ok(DeliveryReport {
+            hops,
+            total_ms: elapsed_ms(),
+        })
+    }
+    async fn hop_status(
+        &self,
+        message: Message,
+        hop: &Address,
+    ) -> Result<MessageStatus, SendError> {
+        let status = self.status(message, hop);
+        match self.send_timeout_for(&hop.protocol) {
+            Some(timeout) => tokio::time::timeout(timeout, status)
+                .await
+                .unwrap_or(Ok(MessageStatus::Unreachable)),
+            None => status.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{testing::*, MessageBuilder};
+
+    #[tokio::test]
+    async fn reports_end_at_the_first_unreachable_hop() {
+        // "a" relays the message on, but nothing answers for "b"
+        let relay = Recorder::new();
+        let a = Arc::new(
+            NodeInstance::new()
+                .with_address(addr("a"))
+                .with_executor(TEST, relay.clone()),
+        );
+        let loopback = Loopback::new();
+        loopback.attach("a", a);
+        let node = NodeInstance::new().with_executor(TEST, loopback);
+
+        let message = MessageBuilder::new(addr("b"))
+            .payload("x")
+            .route_plan(vec![addr("a"), addr("b")])
+            .build();
+        let report = node.send_traced(message).await.unwrap();
+
+        let hops: Vec<_> = report
+            .hops
+            .iter()
+            .map(|(hop, status, _)| (hop.clone(), *status))
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                (Some(addr("a")), MessageStatus::Received),
+                (Some(addr("b")), MessageStatus::Unreachable),
+            ]
+        );
+        let (failed, ..) = report.failed_hop().unwrap();
+        assert_eq!(failed.as_ref(), Some(&addr("b")));
+        assert_eq!(relay.remotes().len(), 1);
+        assert!(report.hops.iter().all(|(_, _, ms)| *ms <= report.total_ms));
+    }
+
+    #[tokio::test]
+    async fn failed_first_hops_are_reported_without_asking_further() {
+        let node = NodeInstance::new().with_executor(TEST, Recorder::new().failing("down"));
+        let message = MessageBuilder::new(addr("b"))
+            .payload("x")
+            .route_plan(vec![addr("a"), addr("b")])
+            .build();
+        let report = node.send_traced(message).await.unwrap();
+        assert_eq!(report.hops.len(), 1);
+        let (hop, status, _) = report.failed_hop().unwrap();
+        assert_eq!(hop.as_ref(), Some(&addr("a")));
+        assert_eq!(*status, MessageStatus::Unreachable);
+    }
+
+    #[tokio::test]
+    async fn unplanned_messages_report_a_single_sent_hop() {
+        let node = NodeInstance::new().with_executor(TEST, Recorder::new());
+        let report = node.send_traced(message(addr("b"), b"x")).await.unwrap();
+        assert_eq!(report.hops.len(), 1);
+        assert_eq!(report.hops[0].0, None);
+        assert_eq!(report.hops[0].1, MessageStatus::Sended);
+        assert!(report.failed_hop().is_none());
+    }
+}
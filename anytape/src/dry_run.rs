@@ -0,0 +1,196 @@
+//! Relaying without sending: what a node would do with a message.
+//!
+//! [`NodeInstance::relay_plan`] runs the checks [`NodeInstance::relay`] runs,
+//! marks a copy of the message and picks its next hop the same way, but stops
+//! before the executor is called. It reports the outcome as a [`RelayPlan`], so
+//! routing configuration can be tried out on live nodes.
+//!
+//! Finding the next hop looks routes up as relaying does, and may cache one
+//! the backend knows. Nothing is sent, and no executor is connected. Rate
+//! limits, quotas and budgets are not consumed either, so a plan does not tell
+//! whether they would hold the message back.
+
+use crate::{Address, Message, NodeInstance, Protocol, RejectReason, SendError};
+
+/// What [relaying](NodeInstance::relay) a message would do; see the
+/// [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayPlan {
+    /// The hop the message would be sent to. `None` if it would be dropped;
+    /// the group itself for a group destination, which is sent to every member.
+    pub next_hop: Option<Address>,
+    /// The length of the path once this node marked it.
+    pub new_path_len: usize,
+    /// The ttl the message would leave with.
+    pub ttl_after: Option<u32>,
+    /// Why the message would be dropped, if it would.
+    pub would_drop: Option<RejectReason>,
+}
+
+impl NodeInstance {
+    /// What [relaying](NodeInstance::relay) `message`, accepted at `accept_at`,
+    /// would do, without sending it. Fails with the error relaying would fail
+    /// with before the send, such as [`SendError::Loop`] or
+    /// [`SendError::NoRoute`].
+    pub async fn relay_plan(
+        &self,
+        message: &Message,
+        accept_at: Address,
+    ) -> Result<RelayPlan, SendError> {
+        if self.is_loop(message) {
+            return Err(SendError::Loop);
+        }
+        let mut plan = RelayPlan {
+            next_hop: None,
+            new_path_len: message.path.len() + 1,
+            ttl_after: message.ttl.map(|ttl| ttl.saturating_sub(1)),
+            would_drop: None,
+        };
+        if message.ttl == Some(0) {
+            plan.would_drop = Some(RejectReason::TtlExceeded);
+            return Ok(plan);
+        }
+        if self.max_path_len.is_some_and(|max| plan.new_path_len > max) {
+            plan.would_drop = Some(RejectReason::PathTooLong);
+            return Ok(plan);
+        }
+        let mut message = message.clone();
+        message.ttl = plan.ttl_after;
+        self.mark(accept_at, &mut message);
+        if message.destination.protocol == Protocol::GROUP {
+            plan.next_hop = Some(message.destination);
+            return Ok(plan);
+        }
+        let planned = match self.planned_next(&message)? {
+            Some(hop) => match self.can_send(&mut message.clone(), &hop) {
+                Ok(()) => Some(hop),
+                Err(_) if self.plan_fallback => None,
+                Err(error) => {
+                    return Err(SendError::PlannedHopUnreachable {
+                        hop,
+                        error: Box::new(error),
+                    })
+                }
+            },
+            None => None,
+        };
+        let next = match planned {
+            Some(hop) => hop,
+            None => {
+                let next = match self.tagged_next(&message) {
+                    Some(next) => next,
+                    None => self.resolve_next(&message.destination).await?,
+                };
+                self.can_send(&mut message, &next)?;
+                next
+            }
+        };
+        plan.next_hop = Some(next);
+        Ok(plan)
+    }
+    /// Whether an executor is there to send `message` to `to` with, without
+    /// connecting one.
+    fn can_send(&self, message: &mut Message, to: &Address) -> Result<(), SendError> {
+        let name = self.choose_executor(message, to);
+        if self.is_shut_down() {
+            return Err(SendError::Shutdown);
+        }
+        if self.panics.is_unavailable(
+            self.registered_as(&to.protocol, name.as_deref()),
+            name.as_deref(),
+        ) {
+            return Err(SendError::ProtocolUnavailable(to.protocol.clone()));
+        }
+        if let Some(name) = name {
+            return self.named_executor(to, &name).map(drop);
+        }
+        if self.has_executor(&to.protocol) {
+            return Ok(());
+        }
+        Err(SendError::ProtocolNotSupport {
+            supported: self.supported_protocols(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{addr, message, Recorder, TEST},
+        DataBackend, Identity, MemoryBackend, PathNode,
+    };
+
+    async fn relay_node(recorder: &Recorder) -> NodeInstance {
+        let backend = MemoryBackend::new();
+        backend
+            .set_next(&addr("far"), Some(&addr("next")))
+            .await
+            .unwrap();
+        NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, recorder.clone())
+            .with_backend(backend)
+    }
+
+    #[tokio::test]
+    async fn plans_match_what_relaying_does() {
+        let recorder = Recorder::new();
+        let node = relay_node(&recorder).await;
+        let mut relayed = message(addr("far"), b"x");
+        relayed.ttl = Some(4);
+        relayed
+            .path
+            .push(PathNode::new().with_address(addr("upstream")));
+
+        let plan = node.relay_plan(&relayed, addr("me")).await.unwrap();
+        assert!(recorder.sent().is_empty());
+        node.relay(relayed, addr("me")).await.unwrap();
+
+        let sent = &recorder.sent()[0];
+        assert_eq!(plan.would_drop, None);
+        assert_eq!(plan.next_hop, Some(addr("next")));
+        assert_eq!(recorder.remotes(), [addr("next").identity]);
+        assert_eq!(plan.new_path_len, sent.path.len());
+        assert_eq!(plan.ttl_after, sent.ttl);
+        assert_eq!(plan.ttl_after, Some(3));
+    }
+
+    #[tokio::test]
+    async fn plans_report_the_drops_and_errors_of_relaying() {
+        let recorder = Recorder::new();
+        let node = relay_node(&recorder).await;
+        let mut expired = message(addr("far"), b"x");
+        expired.ttl = Some(0);
+        let plan = node.relay_plan(&expired, addr("me")).await.unwrap();
+        assert_eq!(plan.would_drop, Some(RejectReason::TtlExceeded));
+        assert_eq!(plan.next_hop, None);
+        assert!(matches!(
+            node.relay(expired, addr("me")).await,
+            Err(SendError::TtlExceeded)
+        ));
+
+        let elsewhere = Address::new(Protocol::new("other"), Identity::new("far"));
+        let unknown = message(elsewhere, b"x");
+        assert!(matches!(
+            node.relay_plan(&unknown, addr("me")).await,
+            Err(SendError::NoRoute)
+        ));
+        assert!(matches!(
+            node.relay(unknown, addr("me")).await,
+            Err(SendError::NoRoute)
+        ));
+
+        let mut looped = message(addr("far"), b"x");
+        looped.path.push(PathNode::new().with_address(addr("me")));
+        assert!(matches!(
+            node.relay_plan(&looped, addr("me")).await,
+            Err(SendError::Loop)
+        ));
+        assert!(matches!(
+            node.relay(looped, addr("me")).await,
+            Err(SendError::Loop)
+        ));
+        assert!(recorder.sent().is_empty());
+    }
+}
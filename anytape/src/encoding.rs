@@ -0,0 +1,437 @@
+//! Text forms of [`Protocol`], [`Identity`] and [`Address`].
+//!
+//! An address renders as `<protocol>:<identity>`. The protocol is percent-encoded
+//! UTF-8; the identity is lowercase hex unless prefixed by an encoding tag such
+//! as `b58:` (base32 and base58 need the `encodings` feature). Each half also
+//! parses on its own, with the same [`ParseAddressError`].
+
+use std::{fmt, str::FromStr};
+
+use crate::{Address, Identity, Protocol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdentityEncoding {
+    Hex,
+    #[cfg(feature = "encodings")]
+    Base32,
+    #[cfg(feature = "encodings")]
+    Base58,
+}
+
+impl IdentityEncoding {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            IdentityEncoding::Hex => "hex",
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base32 => "b32",
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base58 => "b58",
+        }
+    }
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "hex" => Some(IdentityEncoding::Hex),
+            #[cfg(feature = "encodings")]
+            "b32" => Some(IdentityEncoding::Base32),
+            #[cfg(feature = "encodings")]
+            "b58" => Some(IdentityEncoding::Base58),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseAddressError {
+    /// No `:` between protocol and identity.
+    MissingSeparator,
+    InvalidPercentEncoding,
+    InvalidHex,
+    #[cfg(feature = "encodings")]
+    InvalidBase32,
+    #[cfg(feature = "encodings")]
+    InvalidBase58,
+}
+
+impl fmt::Display for ParseAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseAddressError::MissingSeparator => {
+                write!(f, "expected `<protocol>:<identity>`")
+            }
+            ParseAddressError::InvalidPercentEncoding => write!(f, "invalid percent-encoding"),
+            ParseAddressError::InvalidHex => write!(f, "invalid hex"),
+            #[cfg(feature = "encodings")]
+            ParseAddressError::InvalidBase32 => write!(f, "invalid base32"),
+            #[cfg(feature = "encodings")]
+            ParseAddressError::InvalidBase58 => write!(f, "invalid base58"),
+        }
+    }
+}
+
+impl std::error::Error for ParseAddressError {}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        out.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        out.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+    }
+    out
+}
+
+pub(crate) fn decode_hex(text: &str) -> Result<Vec<u8>, ParseAddressError> {
+    let digits = text.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return Err(ParseAddressError::InvalidHex);
+    }
+    digits
+        .chunks_exact(2)
+        .map(|pair| match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(hi), Some(lo)) => Ok(hi << 4 | lo),
+            _ => Err(ParseAddressError::InvalidHex),
+        })
+        .collect()
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn percent_decode(text: &str) -> Result<Vec<u8>, ParseAddressError> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let (Some(hi), Some(lo)) = (
+                bytes.get(i + 1).copied().and_then(hex_value),
+                bytes.get(i + 2).copied().and_then(hex_value),
+            ) else {
+                return Err(ParseAddressError::InvalidPercentEncoding);
+            };
+            out.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+impl Identity {
+    pub fn to_hex(&self) -> String {
+        encode_hex(self.as_bytes())
+    }
+    pub fn from_hex(text: &str) -> Result<Self, ParseAddressError> {
+        decode_hex(text).map(Identity::new)
+    }
+    /// Lowercase, unpadded RFC 4648 base32, which is safe to use in DNS labels.
+    #[cfg(feature = "encodings")]
+    pub fn to_base32(&self) -> String {
+        data_encoding::BASE32_NOPAD
+            .encode(self.as_bytes())
+            .to_ascii_lowercase()
+    }
+    #[cfg(feature = "encodings")]
+    pub fn from_base32(text: &str) -> Result<Self, ParseAddressError> {
+        data_encoding::BASE32_NOPAD
+            .decode(text.to_ascii_uppercase().as_bytes())
+            .map(Identity::new)
+            .map_err(|_| ParseAddressError::InvalidBase32)
+    }
+    #[cfg(feature = "encodings")]
+    pub fn to_base58(&self) -> String {
+        bs58::encode(self.as_bytes()).into_string()
+    }
+    #[cfg(feature = "encodings")]
+    pub fn from_base58(text: &str) -> Result<Self, ParseAddressError> {
+        bs58::decode(text)
+            .into_vec()
+            .map(Identity::new)
+            .map_err(|_| ParseAddressError::InvalidBase58)
+    }
+    pub fn encode(&self, encoding: IdentityEncoding) -> String {
+        match encoding {
+            IdentityEncoding::Hex => self.to_hex(),
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base32 => self.to_base32(),
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base58 => self.to_base58(),
+        }
+    }
+    pub fn decode(text: &str, encoding: IdentityEncoding) -> Result<Self, ParseAddressError> {
+        match encoding {
+            IdentityEncoding::Hex => Self::from_hex(text),
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base32 => Self::from_base32(text),
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base58 => Self::from_base58(text),
+        }
+    }
+    /// Parse the identity half of an address: an optional `<encoding>:` prefix,
+    /// hex without one.
+    pub(crate) fn parse_prefixed(text: &str) -> Result<Self, ParseAddressError> {
+        match text
+            .split_once(':')
+            .and_then(|(prefix, rest)| Some((IdentityEncoding::from_prefix(prefix)?, rest)))
+        {
+            Some((encoding, rest)) => Self::decode(rest, encoding),
+            None => Self::from_hex(text),
+        }
+    }
+}
+
+/// Percent-encoded UTF-8.
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in self.as_bytes() {
+            if is_unreserved(byte) {
+                write!(f, "{}", byte as char)?;
+            } else {
+                write!(f, "%{byte:02X}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase hex.
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.protocol, self.identity)
+    }
+}
+
+/// Displays an address with its identity in a chosen encoding; see [`Address::display_with`].
+pub struct DisplayAddress<'a> {
+    address: &'a Address,
+    encoding: IdentityEncoding,
+}
+
+impl fmt::Display for DisplayAddress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.address.protocol,
+            self.encoding.prefix(),
+            self.address.identity.encode(self.encoding)
+        )
+    }
+}
+
+impl Address {
+    /// Render with an explicit `<encoding>:` identity prefix, which [`Address::from_str`]
+    /// parses back unambiguously.
+    pub fn display_with(&self, encoding: IdentityEncoding) -> DisplayAddress<'_> {
+        DisplayAddress {
+            address: self,
+            encoding,
+        }
+    }
+}
+
+/// Percent-encoded bytes, as displayed.
+impl FromStr for Protocol {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        percent_decode(s).map(Protocol::new)
+    }
+}
+
+/// Hex, or another encoding behind its `<encoding>:` prefix.
+impl FromStr for Identity {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Identity::parse_prefixed(s)
+    }
+}
+
+impl FromStr for Address {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, identity) = s
+            .split_once(':')
+            .ok_or(ParseAddressError::MissingSeparator)?;
+        Ok(Address {
+            protocol: protocol.parse()?,
+            identity: identity.parse()?,
+        })
+    }
+}
+
+/// Serialized as its text form.
+/// Identities are raw bytes, without a protocol to pick a text encoding.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Identity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(self.as_bytes(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Identity {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <Vec<u8> as serde::Deserialize>::deserialize(deserializer).map(Identity::new)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identities() -> Vec<Identity> {
+        let mut all: Vec<u8> = (0..=255).collect();
+        all.reverse();
+        vec![
+            Identity::new(Vec::new()),
+            Identity::new([0u8]),
+            Identity::new([0u8, 0, 7]),
+            Identity::new([0xff, 0xfe, 0x80]),
+            Identity::new("plain text"),
+            Identity::new(all),
+        ]
+    }
+
+    fn encodings() -> Vec<IdentityEncoding> {
+        vec![
+            IdentityEncoding::Hex,
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base32,
+            #[cfg(feature = "encodings")]
+            IdentityEncoding::Base58,
+        ]
+    }
+
+    #[test]
+    fn every_encoding_round_trips_arbitrary_bytes() {
+        for identity in identities() {
+            for encoding in encodings() {
+                let text = identity.encode(encoding);
+                assert_eq!(
+                    Identity::decode(&text, encoding).unwrap(),
+                    identity,
+                    "{encoding:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn protocols_round_trip_through_their_text_form() {
+        for protocol in [
+            Protocol::new("tcp"),
+            Protocol::new("odd proto/1"),
+            Protocol::new("a:b%c"),
+            Protocol::new([0xff, 0x00, b'x']),
+            Protocol::new(Vec::new()),
+        ] {
+            let text = protocol.to_string();
+            assert!(!text.contains(':'), "{text}");
+            assert_eq!(text.parse::<Protocol>().unwrap(), protocol);
+        }
+        assert_eq!(
+            "bad%zz".parse::<Protocol>(),
+            Err(ParseAddressError::InvalidPercentEncoding)
+        );
+    }
+
+    #[test]
+    fn identities_round_trip_through_their_text_form() {
+        for identity in identities() {
+            assert_eq!(identity.to_string().parse::<Identity>().unwrap(), identity);
+            for encoding in encodings() {
+                let text = format!("{}:{}", encoding.prefix(), identity.encode(encoding));
+                assert_eq!(text.parse::<Identity>().unwrap(), identity, "{text}");
+            }
+        }
+        assert_eq!(
+            "not hex".parse::<Identity>(),
+            Err(ParseAddressError::InvalidHex)
+        );
+        assert_eq!(
+            "no-separator".parse::<Address>(),
+            Err(ParseAddressError::MissingSeparator)
+        );
+    }
+
+    #[test]
+    fn addresses_round_trip_in_every_encoding() {
+        let protocol = Protocol::new("odd proto/1");
+        for identity in identities() {
+            let address = Address::new(protocol.clone(), identity);
+            assert_eq!(address.to_string().parse::<Address>().unwrap(), address);
+            for encoding in encodings() {
+                let text = address.display_with(encoding).to_string();
+                assert!(text.contains(&format!(":{}:", encoding.prefix())), "{text}");
+                assert_eq!(text.parse::<Address>().unwrap(), address);
+            }
+        }
+    }
+
+    #[test]
+    fn hex_accepts_either_case_and_rejects_the_rest() {
+        assert_eq!(
+            Identity::from_hex("aBcD").unwrap(),
+            Identity::new([0xab, 0xcd])
+        );
+        assert_eq!(
+            Identity::from_hex("abc"),
+            Err(ParseAddressError::InvalidHex)
+        );
+        assert_eq!(Identity::from_hex("zz"), Err(ParseAddressError::InvalidHex));
+    }
+
+    #[cfg(feature = "encodings")]
+    #[test]
+    fn base32_is_lowercase_and_base58_rejects_its_missing_digits() {
+        let identity = Identity::new("dns label");
+        let text = identity.to_base32();
+        assert_eq!(text, text.to_ascii_lowercase());
+        assert!(!text.contains('='));
+        assert_eq!(
+            Identity::from_base32(&text.to_ascii_uppercase()).unwrap(),
+            identity
+        );
+        assert_eq!(
+            Identity::from_base58("0OIl"),
+            Err(ParseAddressError::InvalidBase58)
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Replies to anonymous senders.
+//!
+//! An anonymous sender leaves no address in the path, and a
+//! [`reply_to`](Message::reply_to) would give it away. Instead it
+//! [attaches a return envelope](NodeInstance::attach_return_envelope): its own
+//! address, sealed to a rendezvous relay of its choosing, in
+//! [`RETURN_ENVELOPE_HEADER`]. The recipient
+//! [answers](NodeInstance::reply_anonymous) by sending the reply to the
+//! rendezvous relay with the still sealed block in [`RETURN_BLOCK_HEADER`]; only
+//! the relay can open it, and it forwards the reply to the sender.
+//!
+//! Each block can be used once until it expires. The rendezvous relay remembers
+//! the blocks it opened and rejects a second reply with one of them as
+//! [`RejectReason::Duplicate`], and an expired block as
+//! [`MessageStatus::Expired`].
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use crate::{
+    onion::OnionError,
+    random_u64,
+    sender::REPLY_HEADER,
+    wire::{DecodeError, Reader, Writer},
+    Address, Message, MessageBuilder, MessageStatus, NodeInstance, OnionSealer, RejectReason,
+    SendError,
+};
+
+/// Carries a [return envelope](NodeInstance::attach_return_envelope): the
+/// rendezvous relay's address and the block sealed to it.
+pub const RETURN_ENVELOPE_HEADER: &str = "anytape-return-envelope";
+
+/// Carries the sealed block of a [reply](NodeInstance::reply_anonymous) to an
+/// anonymous sender, for the rendezvous relay to open.
+pub const RETURN_BLOCK_HEADER: &str = "anytape-return-block";
+
+/// What the rendezvous relay finds in an opened block.
+struct ReturnBlock {
+    id: u64,
+    /// Unix milliseconds.
+    expires_at: u64,
+    reply_at: Address,
+}
+
+impl ReturnBlock {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_u64(self.id);
+        w.put_u64(self.expires_at);
+        w.put_address(&self.reply_at);
+        w.finish()
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let block = Self {
+            id: r.get_u64()?,
+            expires_at: r.get_u64()?,
+            reply_at: r.get_address()?,
+        };
+        r.finish()?;
+        Ok(block)
+    }
+}
+
+/// Blocks a rendezvous relay has opened, with their expiry.
+#[derive(Default)]
+pub(crate) struct ConsumedBlocks {
+    expiries: Mutex<HashMap<u64, u64>>,
+}
+
+impl ConsumedBlocks {
+    /// Mark `block` as used; `false` if it was before.
+    fn consume(&self, block: &ReturnBlock, now: u64) -> bool {
+        let mut expiries = self.expiries.lock().unwrap();
+        // expired blocks are refused anyway
+        expiries.retain(|_, expires_at| *expires_at >= now);
+        expiries.insert(block.id, block.expires_at).is_none()
+    }
+}
+
+impl NodeInstance {
+    /// Let the recipient of `message` [answer](NodeInstance::reply_anonymous) at
+    /// `reply_at` through `rendezvous`, without learning `reply_at`. The block is
+    /// sealed to `rendezvous` with `sealer` and can be used once within
+    /// `valid_for`.
+    pub fn attach_return_envelope(
+        &self,
+        message: &mut Message,
+        reply_at: &Address,
+        rendezvous: Address,
+        sealer: &dyn OnionSealer,
+        valid_for: Duration,
+    ) -> Result<(), SendError> {
+        let block = ReturnBlock {
+            id: random_u64(),
+            expires_at: self
+                .clock
+                .now_millis()
+                .saturating_add(valid_for.as_millis() as u64),
+            reply_at: reply_at.clone(),
+        };
+        let sealed = sealer
+            .seal(&rendezvous, &block.encode())
+            .map_err(SendError::Onion)?;
+        let mut envelope = Writer::new();
+        envelope.put_address(&rendezvous);
+        envelope.put_bytes(&sealed);
+        message
+            .headers
+            .insert(RETURN_ENVELOPE_HEADER.to_owned(), envelope.finish());
+        Ok(())
+    }
+    /// Answer `original` with `payload` through the rendezvous relay named in its
+    /// [return envelope](NodeInstance::attach_return_envelope). Fails with
+    /// [`SendError::NoRoute`] if it has none.
+    pub async fn reply_anonymous(
+        &self,
+        original: &Message,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), SendError> {
+        let envelope = original
+            .headers
+            .get(RETURN_ENVELOPE_HEADER)
+            .ok_or(SendError::NoRoute)?;
+        let mut r = Reader::new(envelope);
+        let (rendezvous, sealed) = r
+            .get_address()
+            .and_then(|rendezvous| Ok((rendezvous, r.get_bytes()?.to_vec())))
+            .and_then(|opened| r.finish().map(|()| opened))
+            .map_err(|e| SendError::Onion(Box::new(OnionError::MalformedSeal(e))))?;
+        let mut reply = MessageBuilder::new(rendezvous)
+            .payload(payload)
+            .header(REPLY_HEADER, original.unique_id.to_le_bytes().to_vec())
+            .header(RETURN_BLOCK_HEADER, sealed)
+            .build();
+        if let Some(source) = self.source_address(&reply.destination) {
+            self.mark(source, &mut reply);
+        }
+        self.forward(reply).await
+    }
+    /// Open the return block of a reply addressed to this node and relay the
+    /// reply to the anonymous sender.
+    pub(crate) async fn open_return_block(
+        &self,
+        mut message: Message,
+        accept_at: Address,
+    ) -> Result<MessageStatus, SendError> {
+        let opener = self
+            .onion_opener
+            .as_ref()
+            .ok_or_else(|| SendError::Onion(Box::new(OnionError::NotARelay)))?;
+        let sealed = message
+            .headers
+            .remove(RETURN_BLOCK_HEADER)
+            .unwrap_or_default();
+        let opened = opener.open(&sealed).map_err(SendError::Onion)?;
+        let block = ReturnBlock::decode(&opened)
+            .map_err(|e| SendError::Onion(Box::new(OnionError::MalformedSeal(e))))?;
+        let now = self.clock.now_millis();
+        if block.expires_at < now {
+            return Ok(MessageStatus::Expired);
+        }
+        if !self.return_blocks.consume(&block, now) {
+            return Ok(MessageStatus::Rejected {
+                reason: RejectReason::Duplicate,
+            });
+        }
+        message.destination = block.reply_at;
+        self.relay(message, accept_at)
+            .await
+            .map(|()| MessageStatus::Sended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{virtual_net::VirtualNetwork, BoxResult, OnionOpener};
+
+    const REQUESTER: &str = "hidden-requester";
+
+    fn xor(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let key = key.iter().map(|k| k.wrapping_mul(31) | 0x80);
+        bytes.iter().zip(key.cycle()).map(|(b, k)| b ^ k).collect()
+    }
+
+    /// Seals to a relay by xoring with its name.
+    struct XorSealer;
+
+    impl OnionSealer for XorSealer {
+        fn seal(&self, relay: &Address, layer: &[u8]) -> BoxResult<Vec<u8>> {
+            Ok(xor(relay.identity.as_bytes(), layer))
+        }
+    }
+
+    struct XorOpener(&'static str);
+
+    impl OnionOpener for XorOpener {
+        fn open(&self, sealed: &[u8]) -> BoxResult<Vec<u8>> {
+            Ok(xor(self.0.as_bytes(), sealed))
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn inbox() -> (
+        Arc<Mutex<Vec<Message>>>,
+        impl Fn(Message) -> std::future::Ready<()>,
+    ) {
+        let inbox = Arc::new(Mutex::new(Vec::new()));
+        let keep = {
+            let inbox = inbox.clone();
+            move |message: Message| {
+                inbox.lock().unwrap().push(message);
+                std::future::ready(())
+            }
+        };
+        (inbox, keep)
+    }
+
+    #[tokio::test]
+    async fn anonymous_requests_are_answered_through_the_rendezvous_relay() {
+        let at = VirtualNetwork::address;
+        let (requests, keep_requests) = inbox();
+        let (replies, keep_replies) = inbox();
+        let mut net = VirtualNetwork::new();
+        let requester = net.add_node(REQUESTER, |node| {
+            node.with_anon(true).with_handler(keep_replies)
+        });
+        net.add_node("rendezvous", |node| {
+            node.with_onion_opener(XorOpener("rendezvous"))
+        });
+        let responder = net.add_node("responder", |node| node.with_handler(keep_requests));
+
+        let mut request = MessageBuilder::new(at("responder"))
+            .payload("question")
+            .build();
+        requester.mark(at(REQUESTER), &mut request);
+        requester
+            .attach_return_envelope(
+                &mut request,
+                &at(REQUESTER),
+                at("rendezvous"),
+                &XorSealer,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        let unique_id = request.unique_id;
+        requester.forward(request).await.unwrap();
+        net.run_until_idle(10).await;
+
+        let request = requests.lock().unwrap().remove(0);
+        assert!(!contains(&request.encode(), REQUESTER.as_bytes()));
+        responder.reply_anonymous(&request, "answer").await.unwrap();
+        let hops = net.run_until_idle(10).await;
+        let visited: Vec<_> = hops.iter().map(|hop| hop.to.clone()).collect();
+        assert_eq!(visited, [at("rendezvous").identity, at(REQUESTER).identity]);
+        let reply = replies.lock().unwrap().remove(0);
+        assert_eq!(reply.payload, b"answer");
+        assert_eq!(
+            reply.headers.get(REPLY_HEADER),
+            Some(&unique_id.to_le_bytes().to_vec())
+        );
+        assert!(!reply.headers.contains_key(RETURN_BLOCK_HEADER));
+
+        // the same block a second time
+        responder.reply_anonymous(&request, "again").await.unwrap();
+        let hops = net.run_until_idle(10).await;
+        assert_eq!(hops.len(), 1);
+        assert!(matches!(
+            hops[0].result,
+            Ok(MessageStatus::Rejected {
+                reason: RejectReason::Duplicate
+            })
+        ));
+        assert!(replies.lock().unwrap().is_empty());
+    }
+}
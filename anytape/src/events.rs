@@ -0,0 +1,381 @@
+//! A feed of the node's internal state changes, for dashboards and tests.
+//!
+//! [`NodeInstance::events`] subscribes to every [`NodeEvent`] published from then
+//! on, [`NodeInstance::events_filtered`] to those of some [kinds](EventMask).
+//! Events go through a bounded broadcast channel: publishing never waits, and a
+//! subscriber that falls more than the [capacity](NodeInstance::with_event_capacity)
+//! behind loses the oldest events it has not read, counted in
+//! [`EventStream::lagged`]. Nothing is built or sent while nobody subscribes.
+
+use std::{
+    ops::BitOr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::sync::broadcast;
+
+use crate::{Address, BoxFuture, Clock, Identity, NodeInstance, Protocol, SchemaError};
+
+/// Events a subscriber may fall behind by before losing some.
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    /// A route was stored in the route cache.
+    RouteCached {
+        destination: Address,
+        next: Address,
+    },
+    /// A route was dropped from the route cache, because the backend changed it,
+    /// a downstream node withdrew it or the [full cache](NodeInstance::with_route_cache_capacity)
+    /// made room.
+    RouteEvicted {
+        destination: Address,
+    },
+    /// Progress of [warming](NodeInstance::warm_cache) the route cache: routes
+    /// read from the backend and cached so far.
+    RouteCacheWarming {
+        scanned: u64,
+        loaded: u64,
+        finished: bool,
+    },
+    RouteCandidateAdded {
+        destination: Address,
+        next: Address,
+    },
+    RouteCandidateRemoved {
+        destination: Address,
+        next: Address,
+    },
+    ExecutorRegistered {
+        protocol: Protocol,
+        replaced: bool,
+    },
+    ExecutorDeregistered {
+        protocol: Protocol,
+    },
+    /// The executor for `protocol` was [replaced](NodeInstance::replace_executor)
+    /// with `in_flight` sends under way on it, which it is left to finish.
+    ExecutorReplacing {
+        protocol: Protocol,
+        in_flight: usize,
+    },
+    /// The executor replaced for `protocol` was closed, with `remaining` sends
+    /// still under way if the drain deadline passed.
+    ExecutorReplaced {
+        protocol: Protocol,
+        remaining: usize,
+    },
+    /// The executor for `protocol` reached the
+    /// [panic limit](NodeInstance::with_executor_panic_limit) and was taken out
+    /// of service.
+    ExecutorQuarantined {
+        protocol: Protocol,
+        panics: u64,
+    },
+    /// A peer's [score](NodeInstance::with_peer_scoring) reached the threshold.
+    PeerQuarantined {
+        identity: Identity,
+        score: f64,
+    },
+    /// A [`CoalescingBackend`](crate::CoalescingBackend) failed to write its
+    /// `pending` routes to the backend behind it, and will try again.
+    BackendFlushFailed {
+        pending: usize,
+        /// Failed flushes in a row, counting this one.
+        attempt: u32,
+        error: String,
+    },
+    /// A [`CoalescingBackend`](crate::CoalescingBackend) wrote its routes after
+    /// `failures` failed flushes.
+    BackendFlushRecovered {
+        flushed: usize,
+        failures: u32,
+    },
+    /// A payload did not match the [schema](crate::SchemaRegistry) of its
+    /// content type, on its way out or, if `inbound`, in. `dropped` says whether
+    /// the message was rejected rather than sent or delivered anyway.
+    SchemaViolation {
+        unique_id: u64,
+        error: SchemaError,
+        inbound: bool,
+        dropped: bool,
+    },
+}
+
+impl NodeEvent {
+    fn mask(&self) -> EventMask {
+        match self {
+            NodeEvent::RouteCached { .. }
+            | NodeEvent::RouteEvicted { .. }
+            | NodeEvent::RouteCacheWarming { .. } => EventMask::ROUTE_CACHE,
+            NodeEvent::RouteCandidateAdded { .. } | NodeEvent::RouteCandidateRemoved { .. } => {
+                EventMask::ROUTE_CANDIDATES
+            }
+            NodeEvent::ExecutorRegistered { .. }
+            | NodeEvent::ExecutorDeregistered { .. }
+            | NodeEvent::ExecutorReplacing { .. }
+            | NodeEvent::ExecutorReplaced { .. }
+            | NodeEvent::ExecutorQuarantined { .. } => EventMask::EXECUTORS,
+            NodeEvent::PeerQuarantined { .. } => EventMask::PEERS,
+            NodeEvent::BackendFlushFailed { .. } | NodeEvent::BackendFlushRecovered { .. } => {
+                EventMask::BACKEND
+            }
+            NodeEvent::SchemaViolation { .. } => EventMask::SCHEMAS,
+        }
+    }
+}
+
+/// A published event and when it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StampedEvent {
+    /// Unix time in milliseconds, from the [node's clock](NodeInstance::with_clock).
+    pub at_ms: u64,
+    pub event: NodeEvent,
+}
+
+/// A set of [`NodeEvent`] kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventMask(u32);
+
+impl EventMask {
+    pub const ROUTE_CACHE: Self = Self(1);
+    pub const ROUTE_CANDIDATES: Self = Self(1 << 1);
+    pub const EXECUTORS: Self = Self(1 << 2);
+    pub const PEERS: Self = Self(1 << 3);
+    pub const BACKEND: Self = Self(1 << 4);
+    pub const SCHEMAS: Self = Self(1 << 5);
+    pub const ALL: Self = Self(u32::MAX);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn matches(self, event: &NodeEvent) -> bool {
+        self.contains(event.mask())
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<StampedEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+impl EventBus {
+    fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+    pub(crate) fn downgrade(&self) -> WeakEventBus {
+        WeakEventBus(self.sender.downgrade())
+    }
+    /// Publish the event `event` builds, unless nobody listens.
+    pub(crate) fn publish(&self, clock: &dyn Clock, event: impl FnOnce() -> NodeEvent) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        // subscribers may have gone in the meantime, which is fine
+        let _ = self.sender.send(StampedEvent {
+            at_ms: clock.now_millis(),
+            event: event(),
+        });
+    }
+}
+
+/// An [`EventBus`] that does not keep subscriptions open, for background tasks
+/// that may outlive the node.
+#[derive(Clone)]
+pub(crate) struct WeakEventBus(broadcast::WeakSender<StampedEvent>);
+
+impl WeakEventBus {
+    pub(crate) fn upgrade(&self) -> Option<EventBus> {
+        Some(EventBus {
+            sender: self.0.upgrade()?,
+        })
+    }
+}
+
+/// Publishes to the events of the node a [`DataBackend`](crate::DataBackend) was
+/// [attached](crate::DataBackend::attach_events) to, for as long as the node
+/// lives.
+#[derive(Clone)]
+pub struct BackendEvents {
+    bus: WeakEventBus,
+    clock: Arc<dyn Clock>,
+}
+
+impl BackendEvents {
+    pub(crate) fn new(bus: &EventBus, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            bus: bus.downgrade(),
+            clock,
+        }
+    }
+    /// Publish the event `event` builds, unless nobody listens.
+    pub fn publish(&self, event: impl FnOnce() -> NodeEvent) {
+        if let Some(bus) = self.bus.upgrade() {
+            bus.publish(&*self.clock, event);
+        }
+    }
+}
+
+type Recv = BoxFuture<(
+    Result<StampedEvent, broadcast::error::RecvError>,
+    broadcast::Receiver<StampedEvent>,
+)>;
+
+/// The events of a node, in the order they were published. Ends when the node
+/// is dropped.
+pub struct EventStream {
+    recv: Recv,
+    mask: EventMask,
+    lagged: u64,
+}
+
+impl EventStream {
+    fn new(receiver: broadcast::Receiver<StampedEvent>, mask: EventMask) -> Self {
+        Self {
+            recv: Self::recv(receiver),
+            mask,
+            lagged: 0,
+        }
+    }
+    fn recv(mut receiver: broadcast::Receiver<StampedEvent>) -> Recv {
+        Box::pin(async move { (receiver.recv().await, receiver) })
+    }
+    /// Events lost by falling behind, including ones the mask would have skipped.
+    pub fn lagged(&self) -> u64 {
+        self.lagged
+    }
+    pub async fn next_event(&mut self) -> Option<StampedEvent> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for EventStream {
+    type Item = StampedEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StampedEvent>> {
+        loop {
+            let (result, receiver) = ready!(self.recv.as_mut().poll(cx));
+            self.recv = Self::recv(receiver);
+            match result {
+                Ok(event) if self.mask.matches(&event.event) => return Poll::Ready(Some(event)),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => self.lagged += missed,
+                Err(broadcast::error::RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Let subscribers fall `capacity` events behind before they lose some.
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.events = EventBus::new(capacity);
+        self
+    }
+    /// Every event published from now on; see the [module docs](self).
+    pub fn events(&self) -> EventStream {
+        self.events_filtered(EventMask::ALL)
+    }
+    /// The events of the kinds in `mask` published from now on.
+    pub fn events_filtered(&self, mask: EventMask) -> EventStream {
+        EventStream::new(self.events.sender.subscribe(), mask)
+    }
+    pub(crate) fn publish(&self, event: impl FnOnce() -> NodeEvent) {
+        self.events.publish(&*self.clock, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{addr, Recorder, Shared, TEST},
+        DataBackend, ManualClock, MemoryBackend,
+    };
+
+    #[tokio::test]
+    async fn subscribers_see_route_and_executor_events_in_order() {
+        let backend = Arc::new(MemoryBackend::new());
+        for destination in ["d1", "d2"] {
+            backend
+                .set_next(&addr(destination), Some(&addr("relay")))
+                .await
+                .unwrap();
+        }
+        let mut node = NodeInstance::new()
+            .with_clock(ManualClock::new(1_000))
+            .with_executor(TEST, Recorder::new())
+            .with_backend(Shared(backend))
+            .with_route_cache_capacity(1);
+        let mut all = node.events();
+        let mut routes = node.events_filtered(EventMask::ROUTE_CACHE);
+
+        node.resolve_next(&addr("d1")).await.unwrap();
+        node.deregister_executor(&TEST).unwrap();
+        // the full cache makes room for the second route
+        node.resolve_next(&addr("d2")).await.unwrap();
+        let cached = |destination| NodeEvent::RouteCached {
+            destination: addr(destination),
+            next: addr("relay"),
+        };
+        let expected = [
+            cached("d1"),
+            NodeEvent::ExecutorDeregistered { protocol: TEST },
+            NodeEvent::RouteEvicted {
+                destination: addr("d1"),
+            },
+            cached("d2"),
+        ];
+        for event in &expected {
+            let stamped = all.next_event().await.unwrap();
+            assert_eq!(stamped.at_ms, 1_000);
+            assert_eq!(&stamped.event, event);
+        }
+        for event in [&expected[0], &expected[2], &expected[3]] {
+            assert_eq!(&routes.next_event().await.unwrap().event, event);
+        }
+        drop(node);
+        assert_eq!(all.next_event().await, None);
+        assert_eq!(all.lagged(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_lose_events_without_holding_up_the_node() {
+        let mut node = NodeInstance::new().with_event_capacity(2);
+        let mut slow = node.events();
+        for n in 0..5 {
+            node.register_executor(Protocol::new(format!("p{n}")), Arc::new(Recorder::new()));
+        }
+        let mut protocols = Vec::new();
+        for _ in 0..2 {
+            match slow.next_event().await.unwrap().event {
+                NodeEvent::ExecutorRegistered { protocol, replaced } => {
+                    assert!(!replaced);
+                    protocols.push(protocol);
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        assert_eq!(protocols, [Protocol::new("p3"), Protocol::new("p4")]);
+        assert_eq!(slow.lagged(), 3);
+    }
+}
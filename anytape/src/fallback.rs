@@ -0,0 +1,255 @@
+use std::{fmt, future::Future, sync::Arc};
+
+use crate::{
+    retry::{credit, may_retry},
+    ExecutorCapabilities, Identity, Message, MessageStatus, Protocol, ProtocolExecutor,
+    RetryBudget, RetryPolicy, SendContext, SendOutcome,
+};
+
+/// Both executors of a [`FallbackExecutor`] failed.
+#[derive(Debug)]
+pub struct FallbackError<P, S> {
+    /// The last error of the primary.
+    pub primary: P,
+    pub secondary: S,
+}
+
+impl<P: fmt::Display, S: fmt::Display> fmt::Display for FallbackError<P, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "primary failed: {}; secondary failed: {}",
+            self.primary, self.secondary
+        )
+    }
+}
+
+impl<P, S> std::error::Error for FallbackError<P, S>
+where
+    P: std::error::Error + 'static,
+    S: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.secondary)
+    }
+}
+
+/// Retries failed sends on `primary` according to a [`RetryPolicy`], then tries
+/// `secondary` once before giving up.
+///
+/// Every attempt on either executor is reported through
+/// [`SendContext::record_attempt`], and the node reports a send that fails on
+/// both as a [`SendError::ExecutorError`](crate::SendError::ExecutorError).
+/// Status queries go to `primary` and, if it fails, to `secondary`. A message
+/// is only sent if both executors can carry it, so the capabilities are those
+/// they [have in common](ExecutorCapabilities::intersect).
+pub struct FallbackExecutor<P, S> {
+    primary: Arc<P>,
+    secondary: Arc<S>,
+    policy: RetryPolicy,
+    budget: Option<Arc<RetryBudget>>,
+}
+
+impl<P: ProtocolExecutor, S: ProtocolExecutor> FallbackExecutor<P, S> {
+    pub fn new(primary: P, secondary: S, policy: RetryPolicy) -> Self {
+        Self {
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+            policy,
+            budget: None,
+        }
+    }
+    /// Only retry `primary` as far as `budget` allows, crediting it with the
+    /// sends that succeed; see [`RetryBudget`]. Falling back to `secondary`
+    /// takes nothing from the budget.
+    pub fn with_budget(self, budget: Arc<RetryBudget>) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+impl<P, S> ProtocolExecutor for FallbackExecutor<P, S>
+where
+    P: ProtocolExecutor + Send + Sync + 'static,
+    S: ProtocolExecutor + Send + Sync + 'static,
+{
+    type Error = FallbackError<P::Error, S::Error>;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let primary = self.primary.clone();
+        let secondary = self.secondary.clone();
+        let policy = self.policy.clone();
+        let budget = self.budget.clone();
+        let remote = remote.clone();
+        async move {
+            let result = policy
+                .retry_if(
+                    || {
+                        SendContext::record_attempt();
+                        primary.send(&remote, message.clone())
+                    },
+                    || may_retry(budget.as_deref(), &remote),
+                )
+                .await;
+            credit(budget.as_deref(), &remote, &result);
+            let primary = match result {
+                Ok(outcome) => return Ok(outcome),
+                Err(primary) => primary,
+            };
+            SendContext::record_attempt();
+            secondary
+                .send(&remote, message)
+                .await
+                .map_err(|secondary| FallbackError { primary, secondary })
+        }
+    }
+
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let primary = self.primary.clone();
+        let secondary = self.secondary.clone();
+        let policy = self.policy.clone();
+        let budget = self.budget.clone();
+        let protocol = protocol.clone();
+        let remote = remote.clone();
+        async move {
+            let result = policy
+                .retry_if(
+                    || {
+                        SendContext::record_attempt();
+                        primary.send_via(&protocol, &remote, message.clone())
+                    },
+                    || may_retry(budget.as_deref(), &remote),
+                )
+                .await;
+            credit(budget.as_deref(), &remote, &result);
+            let primary = match result {
+                Ok(outcome) => return Ok(outcome),
+                Err(primary) => primary,
+            };
+            SendContext::record_attempt();
+            secondary
+                .send_via(&protocol, &remote, message)
+                .await
+                .map_err(|secondary| FallbackError { primary, secondary })
+        }
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let status = self.primary.get_status(remote, message.clone());
+        let secondary = self.secondary.clone();
+        let remote = remote.clone();
+        async move {
+            let primary = match status.await {
+                Ok(status) => return Ok(status),
+                Err(e) => e,
+            };
+            secondary
+                .get_status(&remote, message)
+                .await
+                .map_err(|secondary| FallbackError { primary, secondary })
+        }
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.primary
+            .capabilities()
+            .intersect(self.secondary.capabilities())
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        let primary = self.primary.close();
+        let secondary = self.secondary.close();
+        async move {
+            primary.await;
+            secondary.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        testing::{addr, message, Recorder, TEST},
+        NodeInstance, SendError,
+    };
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO)
+    }
+
+    #[tokio::test]
+    async fn failing_primaries_fall_back_to_the_secondary() {
+        let (primary, secondary) = (Recorder::new().failing("down"), Recorder::new());
+        let node = NodeInstance::new().with_executor(
+            TEST,
+            FallbackExecutor::new(primary, secondary.clone(), policy()),
+        );
+        let receipt = node
+            .send_detailed(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.attempts, 4);
+        assert_eq!(secondary.remotes(), [addr("b").identity]);
+    }
+
+    #[tokio::test]
+    async fn the_secondary_is_left_alone_while_the_primary_succeeds() {
+        let (primary, secondary) = (Recorder::new().failing_first(2), Recorder::new());
+        let node = NodeInstance::new().with_executor(
+            TEST,
+            FallbackExecutor::new(primary.clone(), secondary.clone(), policy()),
+        );
+        let receipt = node
+            .send_detailed(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.attempts, 3);
+        assert_eq!(primary.sent().len(), 1);
+        assert!(secondary.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_fail_once_both_executors_did() {
+        let node = NodeInstance::new().with_executor(
+            TEST,
+            FallbackExecutor::new(
+                Recorder::new().failing("primary down"),
+                Recorder::new().failing("secondary down"),
+                policy(),
+            ),
+        );
+        let error = node.send(message(addr("b"), b"x"), addr("b")).await;
+        let Err(SendError::ExecutorError(failure)) = error else {
+            panic!("expected an executor error");
+        };
+        assert_eq!(failure.attempt, 4);
+        assert_eq!(
+            failure.source.to_string(),
+            "primary failed: primary down; secondary failed: secondary down"
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! Splitting messages too large for an executor and putting them back together.
+//!
+//! [`Message::split`] cuts the payload into fragments that share the original's
+//! `unique_id` and carry a [`FRAGMENT_HEADER`] with their index and the fragment
+//! count. [`NodeInstance::send`] splits on its own whenever a message is larger
+//! than the executor's [`max_message_size`](crate::ExecutorCapabilities::max_message_size),
+//! and the next node collects the fragments until all have arrived before
+//! handling the reassembled message, splitting it again if it has to pass it on
+//! over an executor as small. A node buffers at most [`MAX_REASSEMBLED_LEN`]
+//! payload bytes for any one message.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    wire::{Reader, Writer},
+    Message, NodeInstance, SendError,
+};
+
+/// Carries the fragment index and the number of fragments.
+pub const FRAGMENT_HEADER: &str = "anytape-fragment";
+
+/// Incomplete messages a node buffers before evicting the oldest.
+const MAX_PENDING: usize = 1024;
+/// How long a node waits for the missing fragments of a message.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Payload bytes a node buffers at most for one fragmented message.
+pub const MAX_REASSEMBLED_LEN: usize = 16 * 1024 * 1024;
+
+fn encode_header(index: u32, total: u32) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.put_varint(index.into());
+    w.put_varint(total.into());
+    w.finish()
+}
+
+fn decode_header(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut r = Reader::new(bytes);
+    let index = u32::try_from(r.get_varint().ok()?).ok()?;
+    let total = u32::try_from(r.get_varint().ok()?).ok()?;
+    r.finish().ok()?;
+    (index < total).then_some((index, total))
+}
+
+impl Message {
+    fn fragment_header(&self) -> Option<(u32, u32)> {
+        decode_header(self.headers.get(FRAGMENT_HEADER)?)
+    }
+    /// Whether this message is one piece of a [split](Message::split) message,
+    /// i.e. carries a well formed [`FRAGMENT_HEADER`].
+    pub fn is_fragment(&self) -> bool {
+        self.fragment_header().is_some()
+    }
+    /// Position of this fragment among its siblings, counting from 0.
+    pub fn fragment_index(&self) -> Option<u32> {
+        self.fragment_header().map(|(index, _)| index)
+    }
+    /// Number of fragments the original message was split into.
+    pub fn fragment_total(&self) -> Option<u32> {
+        self.fragment_header().map(|(_, total)| total)
+    }
+    /// The `unique_id` all fragments of one message share.
+    pub fn fragment_group_id(&self) -> Option<u64> {
+        self.is_fragment().then_some(self.unique_id)
+    }
+    /// Cut this message into fragments whose [encoding](Message::encode) fits in
+    /// `max_size` bytes. A message that already fits, or has no payload to cut,
+    /// comes back as is.
+    ///
+    /// Every fragment keeps the destination, headers and `unique_id` of the
+    /// original. When the headers alone exceed `max_size`, fragments still carry at
+    /// least one payload byte each and end up larger than asked for.
+    pub fn split(&self, max_size: usize) -> Vec<Message> {
+        if self.payload.is_empty() || self.encode().len() <= max_size {
+            return vec![self.clone()];
+        }
+        let mut empty = Message {
+            payload: Vec::new(),
+            ..self.clone()
+        };
+        // the fragment count can never exceed the payload length, so a header
+        // built from it is at least as long as the real ones
+        let widest = u32::try_from(self.payload.len()).unwrap_or(u32::MAX);
+        empty
+            .headers
+            .insert(FRAGMENT_HEADER.to_owned(), encode_header(widest, widest));
+        // room for the payload's length prefix
+        let overhead = empty.encode().len() + 10;
+        let piece = max_size.saturating_sub(overhead).max(1);
+        let pieces: Vec<&[u8]> = self.payload.chunks(piece).collect();
+        let total = pieces.len() as u32;
+        pieces
+            .into_iter()
+            .enumerate()
+            .map(|(index, payload)| {
+                let mut fragment = Message {
+                    payload: payload.to_vec(),
+                    ..self.clone()
+                };
+                fragment.headers.insert(
+                    FRAGMENT_HEADER.to_owned(),
+                    encode_header(index as u32, total),
+                );
+                fragment
+            })
+            .collect()
+    }
+}
+
+struct Partial {
+    total: u32,
+    pieces: BTreeMap<u32, Vec<u8>>,
+    len: usize,
+    started: tokio::time::Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    pending: Mutex<HashMap<u64, Partial>>,
+}
+
+impl NodeInstance {
+    /// Buffer a fragment received by this node, returning the whole message once
+    /// its last fragment arrives.
+    pub(crate) fn reassemble(&self, mut fragment: Message) -> Result<Option<Message>, SendError> {
+        let (index, total) = fragment
+            .fragment_header()
+            .ok_or(SendError::MalformedFragment)?;
+        fragment.headers.remove(FRAGMENT_HEADER);
+        if total == 1 {
+            return Ok(Some(fragment));
+        }
+        // every fragment carries at least one payload byte
+        if total as usize > MAX_REASSEMBLED_LEN {
+            return Err(SendError::MalformedFragment);
+        }
+        let mut pending = self.fragments.pending.lock().unwrap();
+        let now = tokio::time::Instant::now();
+        pending.retain(|_, partial| now.duration_since(partial.started) < REASSEMBLY_TIMEOUT);
+        if pending.len() >= MAX_PENDING && !pending.contains_key(&fragment.unique_id) {
+            let oldest = pending
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(unique_id, _)| *unique_id);
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+        let partial = pending
+            .entry(fragment.unique_id)
+            .or_insert_with(|| Partial {
+                total,
+                pieces: BTreeMap::new(),
+                len: 0,
+                started: now,
+            });
+        if partial.total != total {
+            pending.remove(&fragment.unique_id);
+            return Err(SendError::MalformedFragment);
+        }
+        if !partial.pieces.contains_key(&index) {
+            partial.len += fragment.payload.len();
+            if partial.len > MAX_REASSEMBLED_LEN {
+                pending.remove(&fragment.unique_id);
+                return Err(SendError::MalformedFragment);
+            }
+            partial
+                .pieces
+                .insert(index, std::mem::take(&mut fragment.payload));
+        }
+        if (partial.pieces.len() as u32) < total {
+            return Ok(None);
+        }
+        let partial = pending
+            .remove(&fragment.unique_id)
+            .expect("complete message is pending");
+        fragment.payload = partial.pieces.into_values().flatten().collect();
+        Ok(Some(fragment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::{addr, message, Loopback, Recorder, TEST};
+    use crate::{BoxResult, ExecutorCapabilities, OnionOpener, OnionSealer};
+
+    /// Seals nothing, so every node can open every layer.
+    struct Clear;
+
+    impl OnionSealer for Clear {
+        fn seal(&self, _: &crate::Address, layer: &[u8]) -> BoxResult<Vec<u8>> {
+            Ok(layer.to_vec())
+        }
+    }
+
+    impl OnionOpener for Clear {
+        fn open(&self, sealed: &[u8]) -> BoxResult<Vec<u8>> {
+            Ok(sealed.to_vec())
+        }
+    }
+
+    fn inbox(node: NodeInstance) -> (NodeInstance, tokio::sync::mpsc::UnboundedReceiver<Message>) {
+        let (sink, inbox) = tokio::sync::mpsc::unbounded_channel();
+        let node = node.with_handler(move |message: Message| {
+            let _ = sink.send(message);
+            async {}
+        });
+        (node, inbox)
+    }
+
+    #[test]
+    fn split_pieces_know_their_place_in_the_group() {
+        let original = message(addr("b"), &[3; 1000]);
+        let pieces = original.split(300);
+        let total = pieces.len() as u32;
+        assert!(total > 1);
+        for (index, piece) in pieces.iter().enumerate() {
+            assert!(piece.is_fragment());
+            assert_eq!(piece.fragment_index(), Some(index as u32));
+            assert_eq!(piece.fragment_total(), Some(total));
+            assert_eq!(piece.fragment_group_id(), Some(original.unique_id));
+        }
+        assert!(!original.is_fragment());
+        assert_eq!(
+            (original.fragment_index(), original.fragment_total()),
+            (None, None)
+        );
+        assert_eq!(original.fragment_group_id(), None);
+        // a message that fits is not split
+        let small = original.split(usize::MAX);
+        assert!(small.len() == 1 && !small[0].is_fragment());
+    }
+
+    #[tokio::test]
+    async fn over_mtu_messages_are_chunked_for_small_executors() {
+        let recorder = Recorder::new().with_capabilities(ExecutorCapabilities {
+            max_message_size: 300,
+            ..ExecutorCapabilities::default()
+        });
+        let sender = NodeInstance::new().with_executor(TEST, recorder.clone());
+        sender
+            .send(message(addr("b"), &[7; 1000]), addr("b"))
+            .await
+            .unwrap();
+        let fragments = recorder.sent();
+        assert!(fragments.len() > 1);
+        assert!(fragments
+            .iter()
+            .all(|f| f.is_fragment() && f.encode().len() <= 300));
+
+        let (receiver, mut inbox) = inbox(NodeInstance::new().with_address(addr("b")));
+        for fragment in fragments {
+            receiver
+                .dispatch_inbound(fragment, addr("b"))
+                .await
+                .unwrap();
+        }
+        let whole = inbox.recv().await.unwrap();
+        assert_eq!(whole.payload, vec![7; 1000]);
+        assert!(!whole.is_fragment());
+    }
+
+    #[tokio::test]
+    async fn onions_cross_small_executors() {
+        let net = Loopback::new().with_max_message_size(400);
+        let sender = NodeInstance::new().with_executor(TEST, net.clone());
+        let relay = NodeInstance::new()
+            .with_address(addr("relay"))
+            .with_executor(TEST, net.clone())
+            .with_onion_opener(Clear);
+        let (recipient, mut inbox) = inbox(
+            NodeInstance::new()
+                .with_address(addr("c"))
+                .with_onion_opener(Clear),
+        );
+        net.attach("relay", Arc::new(relay));
+        net.attach("c", Arc::new(recipient));
+        sender
+            .send_onion(vec![9; 2000], &[addr("relay"), addr("c")], &Clear)
+            .await
+            .unwrap();
+        assert_eq!(inbox.recv().await.unwrap().payload, vec![9; 2000]);
+    }
+
+    #[test]
+    fn fragment_counts_beyond_the_byte_cap_are_refused() {
+        let node = NodeInstance::new();
+        let mut fragment = message(addr("me"), b"x");
+        fragment
+            .headers
+            .insert(FRAGMENT_HEADER.to_owned(), encode_header(0, u32::MAX));
+        assert!(matches!(
+            node.reassemble(fragment),
+            Err(SendError::MalformedFragment)
+        ));
+        assert!(node.fragments.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn buffered_fragments_stop_at_the_byte_cap() {
+        let node = NodeInstance::new();
+        let fragment = |index| {
+            let mut fragment = message(addr("me"), &vec![1; MAX_REASSEMBLED_LEN / 2 + 1]);
+            fragment.unique_id = 1;
+            fragment
+                .headers
+                .insert(FRAGMENT_HEADER.to_owned(), encode_header(index, 3));
+            fragment
+        };
+        assert!(matches!(node.reassemble(fragment(0)), Ok(None)));
+        assert!(matches!(
+            node.reassemble(fragment(1)),
+            Err(SendError::MalformedFragment)
+        ));
+        // the partial message is dropped, not kept at the cap
+        assert!(node.fragments.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+//! Length-prefixed framing for stream transports.
+//!
+//! Each frame is a big-endian `u32` length followed by that many bytes. A
+//! [`FrameReader`] decodes frames incrementally: the declared length is checked
+//! against the [`MessageLimits`] before anything is allocated, frame bodies are
+//! read in bounded chunks, and several frames arriving in one read are split off
+//! the same buffer without copying.
+
+use std::{fmt, io};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const LENGTH_PREFIX_LEN: usize = 4;
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Largest encoded message, without the length prefix, a peer may send.
+    pub max_total_encoded_size: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_total_encoded_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The peer declared a frame beyond the limit.
+    Oversize {
+        declared: u64,
+        max: usize,
+    },
+    /// The peer declared an empty frame.
+    EmptyFrame,
+    /// The connection ended in the middle of a frame.
+    Truncated,
+    Io(io::Error),
+}
+
+impl FrameError {
+    /// Whether the peer broke the framing protocol, as opposed to the connection
+    /// failing.
+    pub fn is_protocol_violation(&self) -> bool {
+        !matches!(self, FrameError::Io(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Oversize { declared, max } => {
+                write!(
+                    f,
+                    "declared frame of {declared} bytes exceeds limit of {max}"
+                )
+            }
+            FrameError::EmptyFrame => write!(f, "empty frame"),
+            FrameError::Truncated => write!(f, "connection closed mid-frame"),
+            FrameError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Incremental decoder for length-prefixed frames.
+pub struct FrameReader {
+    limits: MessageLimits,
+    buf: BytesMut,
+    /// Length of the frame being read, once its prefix was consumed.
+    expected: Option<usize>,
+}
+
+impl FrameReader {
+    pub fn new(limits: MessageLimits) -> Self {
+        Self {
+            limits,
+            buf: BytesMut::new(),
+            expected: None,
+        }
+    }
+    /// Append bytes received from the transport.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data)
+    }
+    /// Bytes received but not yet returned as frames.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+    /// The next complete frame in the buffer, if any.
+    pub fn next_frame(&mut self) -> Result<Option<Bytes>, FrameError> {
+        let len = match self.expected {
+            Some(len) => len,
+            None => {
+                if self.buf.len() < LENGTH_PREFIX_LEN {
+                    return Ok(None);
+                }
+                let declared = self.buf.get_u32();
+                let len = self.check(u64::from(declared))?;
+                self.expected = Some(len);
+                len
+            }
+        };
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+        self.expected = None;
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+    /// Read the next frame from `reader`. Returns `None` when the connection ends
+    /// cleanly between frames.
+    pub async fn read_frame<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<Bytes>, FrameError> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return Ok(Some(frame));
+            }
+            let missing = match self.expected {
+                Some(len) => len - self.buf.len(),
+                None => LENGTH_PREFIX_LEN - self.buf.len(),
+            };
+            self.buf.reserve(missing.min(READ_CHUNK));
+            let read = reader
+                .read_buf(&mut self.buf)
+                .await
+                .map_err(FrameError::Io)?;
+            if read == 0 {
+                return if self.buf.is_empty() && self.expected.is_none() {
+                    Ok(None)
+                } else {
+                    Err(FrameError::Truncated)
+                };
+            }
+        }
+    }
+    fn check(&self, declared: u64) -> Result<usize, FrameError> {
+        let max = self.limits.max_total_encoded_size;
+        match usize::try_from(declared) {
+            Ok(0) => Err(FrameError::EmptyFrame),
+            Ok(len) if len <= max => Ok(len),
+            _ => Err(FrameError::Oversize { declared, max }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: MessageLimits = MessageLimits {
+        max_total_encoded_size: 1024,
+    };
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn pipelined_frames_come_out_of_one_buffer() {
+        let mut reader = FrameReader::new(LIMITS);
+        reader.extend(&[frame(b"first"), frame(b"second")].concat());
+        assert_eq!(reader.next_frame().unwrap().unwrap(), &b"first"[..]);
+        assert_eq!(reader.next_frame().unwrap().unwrap(), &b"second"[..]);
+        assert!(reader.next_frame().unwrap().is_none());
+        assert_eq!(reader.buffered(), 0);
+    }
+
+    #[test]
+    fn frames_may_arrive_a_byte_at_a_time() {
+        let mut reader = FrameReader::new(LIMITS);
+        let bytes = frame(b"body");
+        for byte in &bytes[..bytes.len() - 1] {
+            reader.extend(&[*byte]);
+            assert!(reader.next_frame().unwrap().is_none());
+        }
+        reader.extend(&bytes[bytes.len() - 1..]);
+        assert_eq!(reader.next_frame().unwrap().unwrap(), &b"body"[..]);
+    }
+
+    #[test]
+    fn bad_length_prefixes_are_protocol_violations() {
+        let mut reader = FrameReader::new(LIMITS);
+        reader.extend(&u32::MAX.to_be_bytes());
+        let error = reader.next_frame().unwrap_err();
+        assert!(matches!(error, FrameError::Oversize { max: 1024, .. }));
+        assert!(error.is_protocol_violation());
+
+        let mut reader = FrameReader::new(LIMITS);
+        reader.extend(&0u32.to_be_bytes());
+        assert!(matches!(reader.next_frame(), Err(FrameError::EmptyFrame)));
+    }
+
+    #[tokio::test]
+    async fn an_oversize_claim_is_refused_before_reading_the_body() {
+        let mut reader = FrameReader::new(LIMITS);
+        let mut bytes = 4096u32.to_be_bytes().to_vec();
+        bytes.resize(4 + 4096, 0);
+        let mut input = &bytes[..];
+        let result = reader.read_frame(&mut input).await;
+        assert!(matches!(
+            result,
+            Err(FrameError::Oversize { declared: 4096, .. })
+        ));
+        assert!(reader.buf.capacity() <= READ_CHUNK);
+    }
+
+    #[tokio::test]
+    async fn connections_ending_mid_frame_are_truncated() {
+        let mut reader = FrameReader::new(LIMITS);
+        let bytes = frame(b"body");
+        let mut input = &bytes[..bytes.len() - 1];
+        let result = reader.read_frame(&mut input).await;
+        assert!(matches!(result, Err(FrameError::Truncated)));
+    }
+
+    #[test]
+    fn random_input_never_panics_or_buffers_past_the_limit() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..1000 {
+            let mut reader = FrameReader::new(LIMITS);
+            let len = (random() % 64) as usize;
+            let bytes: Vec<u8> = (0..len).map(|_| random() as u8).collect();
+            reader.extend(&bytes);
+            while let Ok(Some(frame)) = reader.next_frame() {
+                assert!(frame.len() <= LIMITS.max_total_encoded_size);
+            }
+            if let Some(expected) = reader.expected {
+                assert!(expected <= LIMITS.max_total_encoded_size);
+            }
+        }
+    }
+}
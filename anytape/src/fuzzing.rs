@@ -0,0 +1,297 @@
+//! [`Arbitrary`] values of the core types, for fuzzing; see the `fuzz/`
+//! directory of the repository for the targets.
+//!
+//! Sizes are bounded so that no input grows huge: names and identities take at
+//! most [`MAX_NAME`] bytes, payloads and other byte strings at most
+//! [`MAX_BYTES`], and lists and maps at most [`MAX_ITEMS`] entries. Every value
+//! generated is one the [wire](crate::wire) encoding carries unchanged, so
+//! encoding and decoding it gives it back: extension fields only get tags this
+//! build does not know, throttles last whole milliseconds and their rates are
+//! never zero.
+
+use std::{num::NonZeroU32, time::Duration};
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    control::{ControlMessage, ThrottleScope},
+    wire::{UnknownField, LAST_KNOWN_EXTENSION},
+    Address, Identity, Message, MessageStatus, PathNode, Protocol, RejectReason, WithdrawReason,
+};
+
+const MAX_NAME: usize = 32;
+const MAX_BYTES: usize = 1024;
+const MAX_ITEMS: usize = 8;
+
+/// At most `max` bytes, fewer once the input runs out.
+fn bytes(u: &mut Unstructured<'_>, max: usize) -> Result<Vec<u8>> {
+    let len = u.arbitrary_len::<u8>()?.min(max);
+    Ok(u.bytes(len)?.to_vec())
+}
+
+/// A string of at most `max` bytes.
+fn string(u: &mut Unstructured<'_>, max: usize) -> Result<String> {
+    let s = <&str>::arbitrary(u)?;
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Ok(s[..end].to_owned())
+}
+
+/// At most [`MAX_ITEMS`] values of `item`.
+fn list<'a, T>(
+    u: &mut Unstructured<'a>,
+    mut item: impl FnMut(&mut Unstructured<'a>) -> Result<T>,
+) -> Result<Vec<T>> {
+    let len = u.int_in_range(0..=MAX_ITEMS)?;
+    (0..len).map(|_| item(u)).collect()
+}
+
+fn option<'a, T>(
+    u: &mut Unstructured<'a>,
+    value: impl FnOnce(&mut Unstructured<'a>) -> Result<T>,
+) -> Result<Option<T>> {
+    Ok(if u.arbitrary()? {
+        Some(value(u)?)
+    } else {
+        None
+    })
+}
+
+impl<'a> Arbitrary<'a> for Protocol {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Protocol::new(bytes(u, MAX_NAME)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Identity {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Identity::new(bytes(u, MAX_NAME)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Address {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Address::new(u.arbitrary()?, u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for PathNode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(PathNode {
+            name: option(u, |u| string(u, MAX_NAME))?,
+            address: u.arbitrary()?,
+            ts: u.arbitrary()?,
+            sig: option(u, |u| bytes(u, MAX_BYTES))?,
+            remaining_budget_ms: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnknownField {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(UnknownField {
+            tag: u.int_in_range(LAST_KNOWN_EXTENSION + 1..=u64::MAX)?,
+            value: bytes(u, MAX_BYTES)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Message {
+            destination: u.arbitrary()?,
+            path: list(u, PathNode::arbitrary)?,
+            payload: bytes(u, MAX_BYTES)?,
+            signature: bytes(u, MAX_BYTES)?,
+            unique_id: u.arbitrary()?,
+            seq: u.arbitrary()?,
+            budget_ms: u.arbitrary()?,
+            route_plan: option(u, |u| list(u, Address::arbitrary))?,
+            reply_to: u.arbitrary()?,
+            ttl: u.arbitrary()?,
+            headers: list(u, |u| Ok((string(u, MAX_NAME)?, bytes(u, MAX_BYTES)?)))?
+                .into_iter()
+                .collect(),
+            metadata: list(u, |u| Ok((string(u, MAX_NAME)?, string(u, MAX_NAME)?)))?,
+            extensions: list(u, UnknownField::arbitrary)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for RejectReason {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.choose(&[
+            RejectReason::Duplicate,
+            RejectReason::TtlExceeded,
+            RejectReason::InvalidSignature,
+            RejectReason::PathTooLong,
+            RejectReason::NoHandler,
+            RejectReason::QuotaExceeded,
+            RejectReason::Quarantined,
+            RejectReason::DuplicateContent,
+            RejectReason::SchemaViolation,
+        ])
+        .copied()
+    }
+}
+
+impl<'a> Arbitrary<'a> for MessageStatus {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=5)? {
+            0 => MessageStatus::Sended,
+            1 => MessageStatus::Received,
+            2 => MessageStatus::Unreachable,
+            3 => MessageStatus::SendError,
+            4 => MessageStatus::Rejected {
+                reason: u.arbitrary()?,
+            },
+            _ => MessageStatus::Expired,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for WithdrawReason {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.choose(&[WithdrawReason::DeliveryFailures, WithdrawReason::Manual])
+            .copied()
+    }
+}
+
+impl<'a> Arbitrary<'a> for ThrottleScope {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => ThrottleScope::Sender,
+            1 => ThrottleScope::Destination(u.arbitrary()?),
+            _ => ThrottleScope::All,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ControlMessage {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=8)? {
+            0 => ControlMessage::Receipt {
+                unique_id: u.arbitrary()?,
+                status: u.arbitrary()?,
+            },
+            1 => ControlMessage::Hello {
+                name: option(u, |u| string(u, MAX_NAME))?,
+                addresses: list(u, Address::arbitrary)?,
+                wire_version: u.arbitrary()?,
+            },
+            2 => ControlMessage::Subscribe {
+                topic: string(u, MAX_NAME)?,
+            },
+            3 => ControlMessage::Unsubscribe {
+                topic: string(u, MAX_NAME)?,
+            },
+            4 => ControlMessage::RouteAdvert {
+                destination: u.arbitrary()?,
+                cost: u.arbitrary()?,
+            },
+            5 => ControlMessage::Throttle {
+                scope: u.arbitrary()?,
+                retry_after: Duration::from_millis(u.arbitrary()?),
+                rate: Option::<NonZeroU32>::arbitrary(u)?.map(NonZeroU32::get),
+            },
+            6 => ControlMessage::Ping {
+                nonce: u.arbitrary()?,
+                pong: u.arbitrary()?,
+            },
+            7 => ControlMessage::RouteWithdraw {
+                destination: u.arbitrary()?,
+                via: u.arbitrary()?,
+                reason: u.arbitrary()?,
+                ttl: u.arbitrary()?,
+            },
+            _ => ControlMessage::IdentityRotation {
+                old: u.arbitrary()?,
+                new: u.arbitrary()?,
+                valid_until: u.arbitrary()?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{
+        collection::vec,
+        prelude::{any, prop_assert, prop_assert_eq, proptest, Strategy},
+    };
+
+    use super::*;
+    use crate::{frame::FrameReader, frame::MessageLimits, GroupControl};
+
+    /// Bytes for the generators above to draw from.
+    fn seed() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..4096)
+    }
+
+    fn generated<T: for<'a> Arbitrary<'a>>(seed: &[u8]) -> Option<T> {
+        T::arbitrary(&mut Unstructured::new(seed)).ok()
+    }
+
+    proptest! {
+        #[test]
+        fn messages_decode_to_what_was_encoded(seed in seed()) {
+            if let Some(message) = generated::<Message>(&seed) {
+                // messages are not Debug, so as not to show their payloads
+                prop_assert!(Message::decode(&message.encode()) == Ok(message));
+            }
+        }
+
+        #[test]
+        fn control_messages_decode_to_what_was_encoded(seed in seed()) {
+            if let Some(control) = generated::<ControlMessage>(&seed) {
+                prop_assert_eq!(ControlMessage::decode(&control.encode()), Ok(Some(control)));
+            }
+        }
+
+        #[test]
+        fn group_controls_decode_to_what_was_encoded(seed in seed(), add in any::<bool>()) {
+            let Some((to, group, member)) = generated::<(Address, Address, Address)>(&seed) else {
+                return Ok(());
+            };
+            let control = if add {
+                GroupControl::Add { group, member }
+            } else {
+                GroupControl::Remove { group, member }
+            };
+            let message = control.clone().into_message(to);
+            prop_assert_eq!(GroupControl::decode(&message.payload), Ok(control));
+        }
+
+        #[test]
+        fn decoding_takes_no_more_than_the_input_holds(bytes in vec(any::<u8>(), 0..2048)) {
+            if let Ok(message) = Message::decode(&bytes) {
+                let carried = message.payload.len() + message.signature.len();
+                prop_assert!(carried <= bytes.len());
+                prop_assert!(message.path.len() <= bytes.len());
+            }
+            let _ = ControlMessage::decode(&bytes);
+            let _ = GroupControl::decode(&bytes);
+        }
+
+        #[test]
+        fn frames_stay_within_the_limit(chunks in vec(vec(any::<u8>(), 0..64), 0..16)) {
+            let limits = MessageLimits { max_total_encoded_size: 256 };
+            let mut reader = FrameReader::new(limits);
+            let mut received = 0;
+            'read: for chunk in chunks {
+                received += chunk.len();
+                reader.extend(&chunk);
+                loop {
+                    match reader.next_frame() {
+                        Ok(Some(frame)) => prop_assert!(frame.len() <= 256),
+                        Ok(None) => break,
+                        Err(_) => break 'read,
+                    }
+                }
+                prop_assert!(reader.buffered() <= received);
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Multicast groups: one [`Protocol::GROUP`] address standing for a set of members.
+//!
+//! Sending or forwarding to a group address fans out one copy per member. Each
+//! copy is addressed to its member, keeps the original `unique_id` so receivers
+//! can drop duplicates, and carries the group address in [`GROUP_HEADER`]. Groups
+//! may contain other groups; they are flattened and cycles are ignored.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    wire::{DecodeError, Reader, Writer},
+    Address, AuditKind, Message, MessageStatus, NodeInstance, Protocol, RejectReason, SendError,
+};
+
+/// Names the group a fanned out copy was sent to, as a wire encoded address.
+pub const GROUP_HEADER: &str = "anytape-group";
+/// Marks a message whose payload is a [`GroupControl`] operation.
+pub const GROUP_CONTROL_HEADER: &str = "anytape-group-control";
+
+impl Protocol {
+    pub const GROUP: Protocol = Protocol::new_static(b"anytape-group");
+}
+
+/// The outcome of fanning a message out to a group where at least one member failed.
+#[derive(Debug)]
+pub struct GroupReport {
+    pub delivered: Vec<Address>,
+    pub failed: Vec<(Address, SendError)>,
+}
+
+impl fmt::Display for GroupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} group members failed",
+            self.failed.len(),
+            self.failed.len() + self.delivered.len()
+        )
+    }
+}
+
+/// A membership change sent to a remote node's group registry.
+///
+/// Nodes only apply control messages their
+/// [group authorizer](NodeInstance::with_group_authorizer) accepts, typically after
+/// checking `signature`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupControl {
+    Add { group: Address, member: Address },
+    Remove { group: Address, member: Address },
+}
+
+const CONTROL_ADD: u8 = 0;
+const CONTROL_REMOVE: u8 = 1;
+
+impl GroupControl {
+    /// Wrap this operation in a message for the node at `to`. Sign it before sending.
+    pub fn into_message(self, to: Address) -> Message {
+        let mut w = Writer::new();
+        let (op, group, member) = match &self {
+            GroupControl::Add { group, member } => (CONTROL_ADD, group, member),
+            GroupControl::Remove { group, member } => (CONTROL_REMOVE, group, member),
+        };
+        w.put_u8(op);
+        w.put_address(group);
+        w.put_address(member);
+        Message {
+            destination: to,
+            path: Vec::new(),
+            payload: w.finish(),
+            signature: Vec::new(),
+            unique_id: crate::random_u64(),
+            ttl: None,
+            headers: [(GROUP_CONTROL_HEADER.to_owned(), Vec::new())].into(),
+            metadata: Vec::new(),
+            extensions: Vec::new(),
+            seq: None,
+            budget_ms: None,
+            route_plan: None,
+            reply_to: None,
+        }
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let op = r.get_u8()?;
+        let group = r.get_address()?;
+        let member = r.get_address()?;
+        r.finish()?;
+        match op {
+            CONTROL_ADD => Ok(GroupControl::Add { group, member }),
+            CONTROL_REMOVE => Ok(GroupControl::Remove { group, member }),
+            op => Err(DecodeError::InvalidFlags(op)),
+        }
+    }
+}
+
+type GroupAuthorizer = dyn Fn(&Message) -> bool + Send + Sync;
+
+#[derive(Default)]
+pub(crate) struct GroupRegistry {
+    groups: RwLock<HashMap<Address, HashSet<Address>>>,
+    pub(crate) authorizer: Option<Arc<GroupAuthorizer>>,
+}
+
+impl GroupRegistry {
+    fn flatten(&self, group: &Address) -> Vec<Address> {
+        let groups = self.groups.read().unwrap();
+        let mut seen = HashSet::new();
+        let mut members = Vec::new();
+        let mut pending = vec![group.clone()];
+        while let Some(group) = pending.pop() {
+            if !seen.insert(group.clone()) {
+                continue;
+            }
+            for member in groups.get(&group).into_iter().flatten() {
+                if member.protocol == Protocol::GROUP {
+                    pending.push(member.clone());
+                } else if !members.contains(member) {
+                    members.push(member.clone());
+                }
+            }
+        }
+        members
+    }
+}
+
+impl NodeInstance {
+    /// Apply remote [`GroupControl`] messages for which `authorize` returns `true`.
+    /// Without an authorizer every control message is rejected.
+    pub fn with_group_authorizer(
+        mut self,
+        authorize: impl Fn(&Message) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.groups.authorizer = Some(Arc::new(authorize));
+        self
+    }
+    /// Add `member`, which may itself be a group, to `group`. Returns `false` if it
+    /// already was a member.
+    pub fn group_add(&self, group: Address, member: Address) -> bool {
+        let event = self
+            .audit_event(AuditKind::GroupChanged, "member added")
+            .address(&group)
+            .address(&member);
+        let added = self
+            .groups
+            .groups
+            .write()
+            .unwrap()
+            .entry(group)
+            .or_default()
+            .insert(member);
+        if added {
+            self.audit(event);
+        }
+        added
+    }
+    /// Remove `member` from `group`. Returns `false` if it was not a member.
+    pub fn group_remove(&self, group: &Address, member: &Address) -> bool {
+        let mut groups = self.groups.groups.write().unwrap();
+        let Some(members) = groups.get_mut(group) else {
+            return false;
+        };
+        let removed = members.remove(member);
+        if members.is_empty() {
+            groups.remove(group);
+        }
+        if removed {
+            self.audit(
+                self.audit_event(AuditKind::GroupChanged, "member removed")
+                    .address(group)
+                    .address(member),
+            );
+        }
+        removed
+    }
+    /// The members of `group` with nested groups flattened.
+    pub fn group_members(&self, group: &Address) -> Vec<Address> {
+        self.groups.flatten(group)
+    }
+    /// Send one copy of `message` to every member of `group`.
+    ///
+    /// Fails with [`SendError::Group`] if any member could not be reached; the
+    /// others still get their copy.
+    pub async fn multicast(&self, message: Message, group: &Address) -> Result<(), SendError> {
+        let mut report = GroupReport {
+            delivered: Vec::new(),
+            failed: Vec::new(),
+        };
+        let mut group_header = Writer::new();
+        group_header.put_address(group);
+        let group_header = group_header.finish();
+        for member in self.groups.flatten(group) {
+            let mut copy = message.clone();
+            copy.destination = member.clone();
+            copy.headers
+                .insert(GROUP_HEADER.to_owned(), group_header.clone());
+            match Box::pin(self.forward(copy)).await {
+                Ok(()) => report.delivered.push(member),
+                Err(e) => report.failed.push((member, e)),
+            }
+        }
+        if report.failed.is_empty() {
+            Ok(())
+        } else {
+            Err(SendError::Group(report))
+        }
+    }
+    pub(crate) fn handle_group_control(&self, message: Message) -> MessageStatus {
+        let authorized = self
+            .groups
+            .authorizer
+            .as_ref()
+            .is_some_and(|authorize| authorize(&message));
+        if !authorized {
+            let mut event = self
+                .audit_event(AuditKind::SignatureFailure, "group control not authorized")
+                .unique_id(message.unique_id);
+            if let Some(origin) = message.path.first().and_then(|node| node.address.as_ref()) {
+                event = event.identity(&origin.identity).address(origin);
+            }
+            self.audit(event);
+            return MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature,
+            };
+        }
+        match GroupControl::decode(&message.payload) {
+            Ok(GroupControl::Add { group, member }) => {
+                self.group_add(group, member);
+            }
+            Ok(GroupControl::Remove { group, member }) => {
+                self.group_remove(&group, &member);
+            }
+            Err(_) => return MessageStatus::SendError,
+        }
+        MessageStatus::Received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Loopback, Recorder, TEST};
+    use crate::Identity;
+
+    fn group(name: &str) -> Address {
+        Address::new(Protocol::GROUP, Identity::new(name))
+    }
+
+    #[tokio::test]
+    async fn unreachable_members_make_a_partial_failure() {
+        let loopback = Loopback::new();
+        let node = NodeInstance::new().with_executor(TEST, loopback.clone());
+        for member in ["b", "c"] {
+            let node = NodeInstance::new()
+                .with_address(addr(member))
+                .with_handler(|_| async {});
+            loopback.attach(member, Arc::new(node));
+        }
+        for member in ["b", "c", "gone"] {
+            node.group_add(group("g"), addr(member));
+        }
+        let Err(SendError::Group(mut report)) =
+            node.multicast(message(group("g"), b"x"), &group("g")).await
+        else {
+            panic!("expected a group report");
+        };
+        report.delivered.sort_by_key(ToString::to_string);
+        assert_eq!(report.delivered, [addr("b"), addr("c")]);
+        let failed: Vec<_> = report.failed.iter().map(|(member, _)| member).collect();
+        assert_eq!(failed, [&addr("gone")]);
+        assert_eq!(report.to_string(), "1 of 3 group members failed");
+    }
+
+    #[tokio::test]
+    async fn groups_containing_themselves_send_once_per_member() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+        node.group_add(group("g"), group("g"));
+        node.group_add(group("g"), group("h"));
+        node.group_add(group("h"), group("g"));
+        node.group_add(group("h"), addr("b"));
+        node.group_add(group("g"), addr("b"));
+        assert_eq!(node.group_members(&group("g")), [addr("b")]);
+        node.multicast(message(group("g"), b"x"), &group("g"))
+            .await
+            .unwrap();
+        let sent = recorder.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].destination, addr("b"));
+        let mut header = Reader::new(&sent[0].headers[GROUP_HEADER]);
+        assert_eq!(header.get_address().unwrap(), group("g"));
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use crate::{Address, Message, NodeInstance, SendError, SendReceipt};
+
+/// A cheap, cloneable reference to a [`NodeInstance`] for sharing across tasks.
+#[derive(Clone, Debug)]
+pub struct NodeHandle {
+    node: Arc<NodeInstance>,
+}
+
+impl NodeInstance {
+    /// Finish configuring this node and turn it into a shareable [`NodeHandle`].
+    pub fn handle(self) -> NodeHandle {
+        NodeHandle {
+            node: Arc::new(self),
+        }
+    }
+}
+
+impl From<Arc<NodeInstance>> for NodeHandle {
+    fn from(node: Arc<NodeInstance>) -> Self {
+        Self { node }
+    }
+}
+
+impl NodeHandle {
+    pub fn node(&self) -> &NodeInstance {
+        &self.node
+    }
+    pub(crate) fn arc(&self) -> &Arc<NodeInstance> {
+        &self.node
+    }
+    /// See [`NodeInstance::send`].
+    pub async fn send(&self, message: Message, to: impl Into<Address>) -> Result<(), SendError> {
+        self.node.send(message, to).await
+    }
+    /// See [`NodeInstance::send_detailed`].
+    pub async fn send_detailed(
+        &self,
+        message: Message,
+        to: impl Into<Address>,
+    ) -> Result<SendReceipt, SendError> {
+        self.node.send_detailed(message, to).await
+    }
+    /// See [`NodeInstance::forward`].
+    pub async fn forward(&self, message: Message) -> Result<(), SendError> {
+        self.node.forward(message).await
+    }
+    /// See [`NodeInstance::dispatch`].
+    pub async fn dispatch(&self, message: Message) -> Result<(), SendError> {
+        self.node.dispatch(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cloned_handles_send_concurrently() {
+        let recorder = Recorder::new();
+        let handle = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .handle();
+        let tasks: Vec<_> = (0..8u8)
+            .map(|task| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    for i in 0..10u8 {
+                        handle
+                            .send(message(addr("b"), &[task, i]), addr("b"))
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let mut payloads: Vec<_> = recorder.sent().into_iter().map(|m| m.payload).collect();
+        payloads.sort();
+        let expected: Vec<_> = (0..8u8)
+            .flat_map(|task| (0..10u8).map(move |i| vec![task, i]))
+            .collect();
+        assert_eq!(payloads, expected);
+    }
+
+    #[test]
+    fn handles_share_one_node() {
+        let handle = NodeInstance::new().handle();
+        let clone = handle.clone();
+        assert!(std::ptr::eq(handle.node(), clone.node()));
+        assert_eq!(Arc::strong_count(handle.arc()), 2);
+    }
+}
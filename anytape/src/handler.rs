@@ -0,0 +1,231 @@
+//! How a node runs its [`ReceiveHandler`].
+//!
+//! By default the handler runs inline, so a slow handler holds up the dispatch
+//! of every message after it. [`HandlerPolicy::Spawned`] runs each call on its
+//! own task instead, and [`HandlerPolicy::SerializedByKey`] keeps calls for the
+//! same key in order while letting different keys run side by side.
+//!
+//! Panics of spawned handler calls are caught and counted in
+//! [`NodeInstance::handler_panics`]; an inline handler that panics takes the
+//! dispatching task down with it, as before.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Semaphore;
+
+use crate::{panic::catch_unwind, Message, NodeInstance, ReceiveHandler};
+
+/// Keys messages for [`HandlerPolicy::SerializedByKey`].
+pub type HandlerKey = fn(&Message) -> u64;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum HandlerPolicy {
+    /// Await the handler before dispatch returns.
+    #[default]
+    Inline,
+    /// Run up to `max_concurrency` handler calls at once on their own tasks.
+    /// Dispatch waits while all of them are busy.
+    Spawned { max_concurrency: usize },
+    /// Run handler calls one at a time and in dispatch order for messages with
+    /// the same key, e.g. the same origin, and concurrently across keys.
+    SerializedByKey(HandlerKey),
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct HandlerRunner {
+    policy: HandlerPolicy,
+    slots: Option<Arc<Semaphore>>,
+    queues: Arc<Mutex<HashMap<u64, VecDeque<Message>>>>,
+    panics: Arc<AtomicU64>,
+}
+
+impl HandlerRunner {
+    fn new(policy: HandlerPolicy) -> Self {
+        let slots = match policy {
+            HandlerPolicy::Spawned { max_concurrency } => {
+                Some(Arc::new(Semaphore::new(max_concurrency.max(1))))
+            }
+            _ => None,
+        };
+        Self {
+            policy,
+            slots,
+            ..Self::default()
+        }
+    }
+    /// Hand `message` to `handler` as the policy says. Returns once the call is
+    /// done for inline handlers and once it is scheduled otherwise.
+    pub(crate) async fn run(&self, handler: &Arc<dyn ReceiveHandler>, message: Message) {
+        match self.policy {
+            HandlerPolicy::Inline => handler.handle(message).await,
+            HandlerPolicy::Spawned { .. } => {
+                let slots = self.slots.clone().expect("spawned policy has slots");
+                let Ok(slot) = slots.acquire_owned().await else {
+                    return;
+                };
+                let handler = handler.clone();
+                let panics = self.panics.clone();
+                tokio::spawn(async move {
+                    if catch_unwind(|| handler.handle(message)).await.is_err() {
+                        panics.fetch_add(1, Ordering::Relaxed);
+                    }
+                    drop(slot);
+                });
+            }
+            HandlerPolicy::SerializedByKey(key_of) => {
+                let key = key_of(&message);
+                {
+                    let mut queues = self.queues.lock().unwrap();
+                    if let Some(queue) = queues.get_mut(&key) {
+                        // a worker is already draining this key
+                        queue.push_back(message);
+                        return;
+                    }
+                    queues.insert(key, VecDeque::new());
+                }
+                let handler = handler.clone();
+                let queues = self.queues.clone();
+                let panics = self.panics.clone();
+                tokio::spawn(async move {
+                    let mut next = Some(message);
+                    while let Some(message) = next {
+                        if catch_unwind(|| handler.handle(message)).await.is_err() {
+                            panics.fetch_add(1, Ordering::Relaxed);
+                        }
+                        let mut queues = queues.lock().unwrap();
+                        next = queues.get_mut(&key).and_then(VecDeque::pop_front);
+                        if next.is_none() {
+                            queues.remove(&key);
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl HandlerRunner {
+    /// Whether `message` would wait for a busy handler: every spawned slot is
+    /// taken, or `high_water` messages are queued for its key.
+    pub(crate) fn is_saturated(&self, message: &Message, high_water: usize) -> bool {
+        match self.policy {
+            HandlerPolicy::Inline => false,
+            HandlerPolicy::Spawned { .. } => self
+                .slots
+                .as_ref()
+                .is_some_and(|slots| slots.available_permits() == 0),
+            HandlerPolicy::SerializedByKey(key_of) => self
+                .queues
+                .lock()
+                .unwrap()
+                .get(&key_of(message))
+                .is_some_and(|queue| queue.len() >= high_water),
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Run the [handler](NodeInstance::with_handler) according to `policy`; see
+    /// the [module docs](self).
+    pub fn with_handler_policy(self, policy: HandlerPolicy) -> Self {
+        Self {
+            handler_runner: HandlerRunner::new(policy),
+            ..self
+        }
+    }
+    /// Spawned handler calls that panicked.
+    pub fn handler_panics(&self) -> u64 {
+        self.handler_runner.panics.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{addr, eventually, message};
+
+    /// Counts the handler calls running at once, and records each call's
+    /// payload as it starts.
+    #[derive(Clone, Default)]
+    struct Probe {
+        active: Arc<AtomicU64>,
+        max_active: Arc<AtomicU64>,
+        started: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Probe {
+        fn node(&self, policy: HandlerPolicy) -> NodeInstance {
+            let probe = self.clone();
+            NodeInstance::new()
+                .with_address(addr("me"))
+                .with_handler_policy(policy)
+                .with_handler(move |message: Message| {
+                    let probe = probe.clone();
+                    async move {
+                        let active = probe.active.fetch_add(1, Ordering::SeqCst) + 1;
+                        probe.max_active.fetch_max(active, Ordering::SeqCst);
+                        probe.started.lock().unwrap().push(message.payload.to_vec());
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        probe.active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+        }
+        fn started(&self) -> Vec<Vec<u8>> {
+            self.started.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spawned_handlers_run_at_most_max_concurrency_at_once() {
+        let probe = Probe::default();
+        let node = probe.node(HandlerPolicy::Spawned { max_concurrency: 4 });
+        let start = tokio::time::Instant::now();
+        for n in 0..8 {
+            node.dispatch_inbound(message(addr("me"), &[n]), addr("me"))
+                .await
+                .unwrap();
+        }
+        // the last four waited for the first four to finish
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        eventually(|| probe.started().len() == 8).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(probe.max_active.load(Ordering::SeqCst), 4);
+        assert_eq!(probe.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_for_a_key_stay_in_order_while_keys_interleave() {
+        let probe = Probe::default();
+        let node = probe.node(HandlerPolicy::SerializedByKey(|message| {
+            u64::from(message.payload[0])
+        }));
+        for n in 0..3 {
+            for key in [b'a', b'b'] {
+                node.dispatch_inbound(message(addr("me"), &[key, n]), addr("me"))
+                    .await
+                    .unwrap();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        let started = probe.started();
+        for key in [b'a', b'b'] {
+            let order: Vec<u8> = started
+                .iter()
+                .filter(|payload| payload[0] == key)
+                .map(|payload| payload[1])
+                .collect();
+            assert_eq!(order, [0, 1, 2]);
+        }
+        // one call per key at a time, both keys at once
+        assert_eq!(probe.max_active.load(Ordering::SeqCst), 2);
+        assert_eq!(started[..2], [vec![b'a', 0], vec![b'b', 0]]);
+    }
+}
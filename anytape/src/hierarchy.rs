@@ -0,0 +1,231 @@
+//! Protocols named in segments, such as `mesh.v2.control`, and executors
+//! registered for a whole family of them.
+//!
+//! A protocol without an executor of its own is sent with the executor of its
+//! longest registered prefix: `mesh.v2.control` falls back to `mesh.v2`, then
+//! to `mesh`, before the send fails with
+//! [`SendError::ProtocolNotSupport`](crate::SendError::ProtocolNotSupport). An
+//! exact registration always wins over a prefix. The executor still gets the
+//! full protocol to [send with](crate::ProtocolExecutor::send_via), and the
+//! [receipt](crate::SendReceipt::registration) names the registration used.
+//!
+//! The same fallback applies to [named executors](NodeInstance::register_named_executor)
+//! and their [overrides](NodeInstance::with_executor_override), to
+//! [send timeouts](NodeInstance::with_send_timeout_for), and to panic counts,
+//! which are [reported](crate::NodeMetrics::executor_panics) under the
+//! registration that panicked.
+//!
+//! Segments are split on [`DEFAULT_PROTOCOL_DELIMITER`] unless the node
+//! [chooses another](NodeInstance::with_protocol_delimiter), or none to turn the
+//! fallback off. An executor registered for the empty protocol catches every
+//! protocol without another match, but only once
+//! [enabled](NodeInstance::with_catch_all_executor).
+
+use std::{borrow::Borrow, collections::HashMap};
+
+use crate::{NodeInstance, Protocol};
+
+/// What protocols are split into segments on by default.
+pub const DEFAULT_PROTOCOL_DELIMITER: u8 = b'.';
+
+impl Protocol {
+    /// The segments of this protocol, split on [`DEFAULT_PROTOCOL_DELIMITER`].
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments_by(DEFAULT_PROTOCOL_DELIMITER)
+    }
+    /// The segments of this protocol, split on `delimiter`.
+    pub fn segments_by(&self, delimiter: u8) -> impl Iterator<Item = &[u8]> {
+        self.as_bytes().split(move |byte| *byte == delimiter)
+    }
+}
+
+/// Lets maps keyed by protocol be searched by prefix without allocating.
+impl Borrow<[u8]> for Protocol {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProtocolHierarchy {
+    delimiter: Option<u8>,
+    catch_all: bool,
+}
+
+impl Default for ProtocolHierarchy {
+    fn default() -> Self {
+        Self {
+            delimiter: Some(DEFAULT_PROTOCOL_DELIMITER),
+            catch_all: false,
+        }
+    }
+}
+
+impl ProtocolHierarchy {
+    /// `protocol` and the registrations it falls back to, most specific first.
+    pub(crate) fn candidates<'a>(&self, protocol: &'a Protocol) -> impl Iterator<Item = &'a [u8]> {
+        let bytes = protocol.as_bytes();
+        let prefixes = self.delimiter.into_iter().flat_map(move |delimiter| {
+            (0..bytes.len())
+                .rev()
+                .filter(move |&end| end > 0 && bytes[end] == delimiter)
+                .map(move |end| &bytes[..end])
+        });
+        let catch_all = (self.catch_all && !bytes.is_empty()).then_some(&bytes[..0]);
+        std::iter::once(bytes).chain(prefixes).chain(catch_all)
+    }
+    /// The most specific registration for `protocol` in `map`.
+    pub(crate) fn find<'m, V>(
+        &self,
+        map: &'m HashMap<Protocol, V>,
+        protocol: &Protocol,
+    ) -> Option<(&'m Protocol, &'m V)> {
+        if map.is_empty() {
+            return None;
+        }
+        self.candidates(protocol)
+            .find_map(|candidate| map.get_key_value(candidate))
+    }
+}
+
+impl NodeInstance {
+    /// Split protocols into segments on `delimiter` to find the executor to fall
+    /// back to, or with `None` only use exact registrations; see the
+    /// [module docs](self).
+    pub fn with_protocol_delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.protocol_hierarchy.delimiter = delimiter;
+        self
+    }
+    /// Let an executor registered for the empty protocol send every protocol
+    /// that has no other match.
+    pub fn with_catch_all_executor(mut self, enable: bool) -> Self {
+        self.protocol_hierarchy.catch_all = enable;
+        self
+    }
+    /// The protocol the default executor for `protocol` is registered under,
+    /// lazily registered ones included.
+    pub(crate) fn registration(&self, protocol: &Protocol) -> Option<&Protocol> {
+        self.protocol_hierarchy
+            .candidates(protocol)
+            .find_map(|candidate| {
+                self.protocol_executor
+                    .get_key_value(candidate)
+                    .map(|(registered, _)| registered)
+                    .or_else(|| {
+                        self.lazy_executors
+                            .get_key_value(candidate)
+                            .map(|(registered, _)| registered)
+                    })
+            })
+    }
+    /// The protocol the executor sending `protocol`, the one registered under
+    /// `name` or else the default, is registered under; `protocol` itself if
+    /// there is none.
+    pub(crate) fn registered_as<'a>(
+        &'a self,
+        protocol: &'a Protocol,
+        name: Option<&str>,
+    ) -> &'a Protocol {
+        match name {
+            Some(name) => self.named_registration(protocol, name),
+            None => self.registration(protocol),
+        }
+        .unwrap_or(protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{message, Recorder},
+        Address, Identity, SendError,
+    };
+
+    fn at(protocol: &str) -> Address {
+        Address::new(Protocol::new(protocol), Identity::new("b"))
+    }
+
+    /// Where a send to `protocol` went, by the registration it used, or why it
+    /// failed.
+    async fn registration(node: &NodeInstance, protocol: &str) -> Result<Protocol, SendError> {
+        let to = at(protocol);
+        let receipt = node.send_detailed(message(to.clone(), b"x"), to).await?;
+        assert_eq!(receipt.protocol, Protocol::new(protocol));
+        Ok(receipt.registration)
+    }
+
+    fn mesh_node() -> NodeInstance {
+        NodeInstance::new()
+            .with_executor(Protocol::new("mesh"), Recorder::new())
+            .with_executor(Protocol::new("mesh.v2"), Recorder::new())
+            .with_executor(Protocol::new("mesh.v2.control"), Recorder::new())
+    }
+
+    #[test]
+    fn protocols_split_into_segments() {
+        let protocol = Protocol::new("mesh.v2.control");
+        let segments: Vec<_> = protocol.segments().collect();
+        assert_eq!(segments, [&b"mesh"[..], b"v2", b"control"]);
+        let protocol = Protocol::new("a/b");
+        let segments: Vec<_> = protocol.segments_by(b'/').collect();
+        assert_eq!(segments, [&b"a"[..], b"b"]);
+    }
+
+    #[tokio::test]
+    async fn sends_fall_back_to_the_longest_registered_prefix() {
+        let node = mesh_node();
+        for (protocol, registered) in [
+            ("mesh.v2.control", "mesh.v2.control"),
+            ("mesh.v2.data", "mesh.v2"),
+            ("mesh.v2.control.urgent", "mesh.v2.control"),
+            ("mesh.v3", "mesh"),
+            ("mesh", "mesh"),
+        ] {
+            let used = registration(&node, protocol).await.unwrap();
+            assert_eq!(used, Protocol::new(registered), "{protocol}");
+        }
+        for unknown in ["meshy", "other.mesh", "v2"] {
+            assert!(matches!(
+                registration(&node, unknown).await,
+                Err(SendError::ProtocolNotSupport { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn exact_registrations_win_and_no_delimiter_turns_fallback_off() {
+        let exact = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(Protocol::new("mesh"), Recorder::new())
+            .with_executor(Protocol::new("mesh.v2"), exact.clone())
+            .with_protocol_delimiter(None);
+        assert_eq!(
+            registration(&node, "mesh.v2").await.unwrap(),
+            Protocol::new("mesh.v2")
+        );
+        assert_eq!(exact.sent().len(), 1);
+        assert!(matches!(
+            registration(&node, "mesh.v2.data").await,
+            Err(SendError::ProtocolNotSupport { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_empty_protocol_catches_all_only_once_enabled() {
+        let node = mesh_node().with_executor(Protocol::new(""), Recorder::new());
+        assert!(matches!(
+            registration(&node, "other").await,
+            Err(SendError::ProtocolNotSupport { .. })
+        ));
+        let node = node.with_catch_all_executor(true);
+        assert_eq!(
+            registration(&node, "other").await.unwrap(),
+            Protocol::new("")
+        );
+        assert_eq!(
+            registration(&node, "mesh.v9").await.unwrap(),
+            Protocol::new("mesh")
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Where the `unique_id`s of the messages a node creates come from.
+//!
+//! [`Sender`](crate::Sender)s and onion sends take their ids from the node's
+//! [`IdGenerator`], random by default. Two messages given the same id look like
+//! duplicates to a receiving node's journal, which drops one of them. A
+//! generator that repeats itself, say because two of them were seeded alike,
+//! is caught in debug builds by [`NodeInstance::with_id_collision_detector`];
+//! release builds do not check.
+
+use std::sync::Arc;
+#[cfg(debug_assertions)]
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+};
+
+use crate::NodeInstance;
+
+/// Issues `unique_id`s.
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> u64;
+}
+
+impl<F> IdGenerator for F
+where
+    F: Fn() -> u64 + Send + Sync,
+{
+    fn next_id(&self) -> u64 {
+        self()
+    }
+}
+
+/// The ids issued last, to notice one being issued again.
+#[cfg(debug_assertions)]
+struct RecentIds {
+    window: usize,
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+#[cfg(debug_assertions)]
+impl RecentIds {
+    /// Remember `id`, returning whether it was issued already.
+    fn issue(&mut self, id: u64) -> bool {
+        if !self.seen.insert(id) {
+            return true;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        false
+    }
+}
+
+pub(crate) struct Ids {
+    generator: Arc<dyn IdGenerator>,
+    #[cfg(debug_assertions)]
+    recent: Option<Mutex<RecentIds>>,
+}
+
+impl Default for Ids {
+    fn default() -> Self {
+        Self {
+            generator: Arc::new(crate::random_u64),
+            #[cfg(debug_assertions)]
+            recent: None,
+        }
+    }
+}
+
+impl Ids {
+    fn detect_collisions(&mut self, window: usize) {
+        #[cfg(debug_assertions)]
+        {
+            self.recent = Some(Mutex::new(RecentIds {
+                window,
+                order: VecDeque::new(),
+                seen: HashSet::new(),
+            }));
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = window;
+    }
+}
+
+impl NodeInstance {
+    /// Give the messages this node creates ids from `generator`.
+    pub fn with_id_generator(mut self, generator: impl IdGenerator + 'static) -> Self {
+        self.ids.generator = Arc::new(generator);
+        self
+    }
+    /// In debug builds, remember the last `window` ids this node issued and
+    /// panic if its generator issues one of them again; see the
+    /// [module docs](self). Release builds ignore this.
+    pub fn with_id_collision_detector(mut self, window: usize) -> Self {
+        self.ids.detect_collisions(window);
+        self
+    }
+    /// A fresh `unique_id` from the node's [generator](NodeInstance::with_id_generator).
+    ///
+    /// # Panics
+    ///
+    /// In debug builds with the [collision detector](NodeInstance::with_id_collision_detector)
+    /// on, if the generator issued the same id recently.
+    pub fn next_id(&self) -> u64 {
+        let id = self.ids.generator.next_id();
+        #[cfg(debug_assertions)]
+        if let Some(recent) = &self.ids.recent {
+            let collided = recent.lock().unwrap().issue(id);
+            assert!(
+                !collided,
+                "unique_id {id} was issued twice; is the IdGenerator seeded like another one?"
+            );
+        }
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    /// Issues 1, 2, 3, then starts over.
+    fn cycling() -> impl IdGenerator {
+        let next = AtomicU64::new(0);
+        move || next.fetch_add(1, Ordering::Relaxed) % 3 + 1
+    }
+
+    #[test]
+    fn ids_come_from_the_generator() {
+        let node = NodeInstance::new().with_id_generator(cycling());
+        let ids: Vec<_> = (0..4).map(|_| node.next_id()).collect();
+        assert_eq!(ids, [1, 2, 3, 1]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "unique_id 1 was issued twice")]
+    fn colliding_generators_trip_the_detector() {
+        let node = NodeInstance::new()
+            .with_id_generator(cycling())
+            .with_id_collision_detector(8);
+        for _ in 0..4 {
+            node.next_id();
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn ids_older_than_the_window_may_repeat() {
+        let node = NodeInstance::new()
+            .with_id_generator(cycling())
+            .with_id_collision_detector(2);
+        let ids: Vec<_> = (0..6).map(|_| node.next_id()).collect();
+        assert_eq!(ids, [1, 2, 3, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Shared storage for addresses kept in large tables.
+//!
+//! Every [`Address`] owns its bytes, so a route table with millions of entries
+//! holds millions of copies of the same few protocols and of every identity that
+//! appears as both a destination and a next hop. An [`AddressInterner`] keeps
+//! one copy of each distinct byte string and hands out [`InternedAddress`]es
+//! pointing into it.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::{Address, Identity, NodeInstance, Protocol};
+
+/// An address whose bytes may be shared with other addresses.
+///
+/// Cloning is two reference count increments. Equality and hashing go by the
+/// bytes, so interned and uninterned handles of the same address compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InternedAddress {
+    protocol: Arc<[u8]>,
+    identity: Arc<[u8]>,
+}
+
+impl InternedAddress {
+    pub fn protocol_bytes(&self) -> &[u8] {
+        &self.protocol
+    }
+    pub fn identity_bytes(&self) -> &[u8] {
+        &self.identity
+    }
+    pub fn to_address(&self) -> Address {
+        Address::new(
+            Protocol::new(self.protocol.to_vec()),
+            Identity::new(self.identity.to_vec()),
+        )
+    }
+    /// Whether both handles point at the same storage, not just equal bytes.
+    pub fn shares_storage_with(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.protocol, &other.protocol) && Arc::ptr_eq(&self.identity, &other.identity)
+    }
+}
+
+/// Copies the bytes without sharing them; see [`AddressInterner::intern`].
+impl From<&Address> for InternedAddress {
+    fn from(address: &Address) -> Self {
+        Self {
+            protocol: address.protocol.as_bytes().into(),
+            identity: address.identity.as_bytes().into(),
+        }
+    }
+}
+
+impl From<&InternedAddress> for Address {
+    fn from(address: &InternedAddress) -> Self {
+        address.to_address()
+    }
+}
+
+/// Deduplicates protocol and identity bytes across addresses.
+///
+/// Entries stay until [`AddressInterner::purge`] drops the ones nothing refers
+/// to anymore.
+#[derive(Debug, Default)]
+pub struct AddressInterner {
+    protocols: Mutex<HashSet<Arc<[u8]>>>,
+    identities: Mutex<HashSet<Arc<[u8]>>>,
+}
+
+fn intern_bytes(set: &Mutex<HashSet<Arc<[u8]>>>, bytes: &[u8]) -> Arc<[u8]> {
+    let mut set = set.lock().unwrap();
+    match set.get(bytes) {
+        Some(shared) => shared.clone(),
+        None => {
+            let shared: Arc<[u8]> = bytes.into();
+            set.insert(shared.clone());
+            shared
+        }
+    }
+}
+
+impl AddressInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn intern(&self, address: &Address) -> InternedAddress {
+        InternedAddress {
+            protocol: intern_bytes(&self.protocols, address.protocol.as_bytes()),
+            identity: intern_bytes(&self.identities, address.identity.as_bytes()),
+        }
+    }
+    /// The interned handle for `address` if its bytes are already stored,
+    /// without storing anything.
+    pub fn get(&self, address: &Address) -> Option<InternedAddress> {
+        Some(InternedAddress {
+            protocol: self
+                .protocols
+                .lock()
+                .unwrap()
+                .get(address.protocol.as_bytes())?
+                .clone(),
+            identity: self
+                .identities
+                .lock()
+                .unwrap()
+                .get(address.identity.as_bytes())?
+                .clone(),
+        })
+    }
+    /// Number of distinct protocol and identity byte strings stored.
+    pub fn len(&self) -> usize {
+        self.protocols.lock().unwrap().len() + self.identities.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Drop the byte strings no handle refers to anymore.
+    pub fn purge(&self) {
+        self.protocols
+            .lock()
+            .unwrap()
+            .retain(|bytes| Arc::strong_count(bytes) > 1);
+        self.identities
+            .lock()
+            .unwrap()
+            .retain(|bytes| Arc::strong_count(bytes) > 1);
+    }
+}
+
+impl NodeInstance {
+    /// Store route cache entries through `interner`, which may be shared with
+    /// other nodes or tables.
+    pub fn with_address_interner(mut self, interner: Arc<AddressInterner>) -> Self {
+        self.interner = Some(interner);
+        self
+    }
+    pub(crate) fn intern(&self, address: &Address) -> InternedAddress {
+        let address = &*self.canonical(address);
+        match &self.interner {
+            Some(interner) => interner.intern(address),
+            None => address.into(),
+        }
+    }
+    /// The route cache key for `address`, or `None` if it cannot be in the cache.
+    pub(crate) fn cache_key(&self, address: &Address) -> Option<InternedAddress> {
+        let address = &*self.canonical(address);
+        match &self.interner {
+            Some(interner) => interner.get(address),
+            None => Some(address.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    #[test]
+    fn equal_addresses_share_one_allocation() {
+        let interner = AddressInterner::new();
+        let (first, second) = (interner.intern(&addr("a")), interner.intern(&addr("a")));
+        assert!(first.shares_storage_with(&second));
+        assert_eq!(interner.len(), 2);
+        // equal bytes alone do not share anything
+        let copied = InternedAddress::from(&addr("a"));
+        assert_eq!(copied, first);
+        assert!(!copied.shares_storage_with(&first));
+        assert_eq!(first.to_address(), addr("a"));
+    }
+
+    #[test]
+    fn addresses_share_the_parts_they_have_in_common() {
+        let interner = AddressInterner::new();
+        let (a, b) = (interner.intern(&addr("a")), interner.intern(&addr("b")));
+        assert!(std::ptr::eq(a.protocol_bytes(), b.protocol_bytes()));
+        assert!(!a.shares_storage_with(&b));
+        assert_eq!(interner.len(), 3);
+    }
+
+    #[test]
+    fn purging_drops_only_unreferenced_bytes() {
+        let interner = AddressInterner::new();
+        let kept = interner.intern(&addr("a"));
+        drop(interner.intern(&addr("b")));
+        interner.purge();
+        assert_eq!(interner.len(), 2);
+        assert!(interner.get(&addr("b")).is_none());
+        assert!(interner.get(&addr("a")).unwrap().shares_storage_with(&kept));
+    }
+}
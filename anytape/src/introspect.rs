@@ -0,0 +1,323 @@
+//! A point-in-time view of what a node is busy with, for admin tools.
+//!
+//! [`NodeInstance::introspect`] lists the sends in flight and those still
+//! queued for their turn, the requests [waiting for a
+//! reply](crate::Sender::send_and_wait_reply), the streams under way and the
+//! [quarantined](NodeInstance::with_peer_scoring) peers. Each list is copied
+//! under its own short lock, so a snapshot never holds up traffic but is not
+//! one atomic view of the node either. Entries carry stable ids to diff
+//! successive snapshots by.
+//!
+//! [`NodeInstance::introspect_redacted`] replaces every identity by a hash of
+//! it, so snapshots can be shared without the addresses they mention while
+//! still telling the same peer apart from others.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+
+use crate::{Address, Identity, NodeInstance};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NodeIntrospection {
+    /// Unix milliseconds, by the [node's clock](NodeInstance::with_clock).
+    pub taken_at_ms: u64,
+    /// Sends handed to their executor.
+    pub in_flight: Vec<SendSummary>,
+    /// Sends waiting for a [permit](NodeInstance::with_max_in_flight) or
+    /// [throttle](NodeInstance::with_throttle_feedback).
+    pub queued: Vec<SendSummary>,
+    pub pending_replies: Vec<PendingReply>,
+    pub streams: Vec<StreamTransfer>,
+    pub quarantined: Vec<QuarantinedPeer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SendSummary {
+    /// Unique among the node's sends, unlike `unique_id`, which retries share.
+    pub id: u64,
+    pub unique_id: u64,
+    pub next_hop: String,
+    pub protocol: String,
+    /// Since the send started, queueing included.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PendingReply {
+    /// The request's `unique_id`.
+    pub unique_id: u64,
+    pub waiting: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum StreamDirection {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StreamTransfer {
+    pub stream_id: u64,
+    pub direction: StreamDirection,
+    /// The receiver of an outgoing stream, the sender of an incoming one.
+    pub peer: String,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QuarantinedPeer {
+    pub identity: String,
+    pub score: f64,
+    /// Until the quarantine ends.
+    pub remaining: Duration,
+}
+
+struct TrackedSend {
+    unique_id: u64,
+    to: Address,
+    started: Instant,
+    sending: bool,
+}
+
+/// The sends under way, for [introspection](NodeInstance::introspect).
+#[derive(Default)]
+pub(crate) struct SendTracker {
+    next: AtomicU64,
+    sends: Mutex<HashMap<u64, TrackedSend>>,
+}
+
+impl SendTracker {
+    /// Track a send of `unique_id` to `to` until the returned guard drops.
+    pub(crate) fn track(&self, unique_id: u64, to: &Address) -> Tracked<'_> {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let send = TrackedSend {
+            unique_id,
+            to: to.clone(),
+            started: Instant::now(),
+            sending: false,
+        };
+        self.sends.lock().unwrap().insert(id, send);
+        Tracked { tracker: self, id }
+    }
+}
+
+pub(crate) struct Tracked<'a> {
+    tracker: &'a SendTracker,
+    id: u64,
+}
+
+impl Tracked<'_> {
+    /// The send is done queueing and goes to its executor.
+    pub(crate) fn sending(&self) {
+        if let Some(send) = self.tracker.sends.lock().unwrap().get_mut(&self.id) {
+            send.sending = true;
+        }
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.tracker.sends.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Shows or hides identities in a snapshot.
+struct Names {
+    redact: bool,
+}
+
+impl Names {
+    fn identity(&self, identity: &Identity) -> String {
+        if !self.redact {
+            return identity.to_string();
+        }
+        let digest = Sha256::digest(identity.as_bytes());
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+    fn address(&self, address: &Address) -> String {
+        if !self.redact {
+            return address.to_string();
+        }
+        format!("{}:{}", address.protocol, self.identity(&address.identity))
+    }
+}
+
+impl NodeInstance {
+    /// What the node is doing right now; see the [module docs](self).
+    pub fn introspect(&self) -> NodeIntrospection {
+        self.snapshot(Names { redact: false })
+    }
+    /// Like [`NodeInstance::introspect`], with every identity replaced by the
+    /// first 8 bytes of its SHA-256 hash, in hex.
+    pub fn introspect_redacted(&self) -> NodeIntrospection {
+        self.snapshot(Names { redact: true })
+    }
+    fn snapshot(&self, names: Names) -> NodeIntrospection {
+        let now = Instant::now();
+        let (mut in_flight, mut queued) = (Vec::new(), Vec::new());
+        {
+            let sends = self.tracked_sends.sends.lock().unwrap();
+            for (id, send) in sends.iter() {
+                let summary = SendSummary {
+                    id: *id,
+                    unique_id: send.unique_id,
+                    next_hop: names.address(&send.to),
+                    protocol: send.to.protocol.to_string(),
+                    elapsed: now - send.started,
+                };
+                if send.sending {
+                    in_flight.push(summary);
+                } else {
+                    queued.push(summary);
+                }
+            }
+        }
+        in_flight.sort_unstable_by_key(|send| send.id);
+        queued.sort_unstable_by_key(|send| send.id);
+        let mut pending_replies: Vec<_> = self
+            .replies
+            .pending()
+            .into_iter()
+            .map(|(unique_id, since)| PendingReply {
+                unique_id,
+                waiting: now - since,
+            })
+            .collect();
+        pending_replies.sort_unstable_by_key(|reply| reply.unique_id);
+        let mut streams: Vec<_> = self
+            .streams
+            .transfers()
+            .into_iter()
+            .map(|(stream_id, direction, peer, started)| StreamTransfer {
+                stream_id,
+                direction,
+                peer: names.address(&peer),
+                elapsed: now - started,
+            })
+            .collect();
+        streams.sort_unstable_by_key(|stream| stream.stream_id);
+        let monotonic = self.clock.monotonic();
+        let mut quarantined: Vec<_> = self
+            .peer_scores
+            .iter()
+            .flat_map(|scores| scores.quarantined(monotonic))
+            .map(|(identity, score, until)| QuarantinedPeer {
+                identity: names.identity(&identity),
+                score,
+                remaining: until - monotonic,
+            })
+            .collect();
+        quarantined.sort_unstable_by(|a, b| a.identity.cmp(&b.identity));
+        NodeIntrospection {
+            taken_at_ms: self.clock.now_millis(),
+            in_flight,
+            queued,
+            pending_replies,
+            streams,
+            quarantined,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::{addr, eventually, message, Recorder, TEST};
+
+    /// A node with two slow sends to `b` in flight and one queued behind them,
+    /// half a second in.
+    async fn busy() -> Arc<NodeInstance> {
+        let node = Arc::new(
+            NodeInstance::new()
+                .with_executor(TEST, Recorder::new().with_delay(Duration::from_secs(10)))
+                .with_max_in_flight(2),
+        );
+        for unique_id in 1..=3 {
+            let node = node.clone();
+            let mut message = message(addr("b"), b"x");
+            message.unique_id = unique_id;
+            tokio::spawn(async move { node.send(message, addr("b")).await });
+        }
+        eventually(|| node.introspect().queued.len() == 1).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        node
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshots_list_sends_in_flight_and_queued() {
+        let node = busy().await;
+        let snapshot = node.introspect();
+        assert_eq!(snapshot.in_flight.len(), 2);
+        assert_eq!(snapshot.queued.len(), 1);
+        for send in snapshot.in_flight.iter().chain(&snapshot.queued) {
+            assert_eq!(send.next_hop, addr("b").to_string());
+            assert_eq!(send.protocol, TEST.to_string());
+            assert_eq!(send.elapsed, Duration::from_millis(500));
+        }
+        let mut unique_ids: Vec<_> = snapshot
+            .in_flight
+            .iter()
+            .chain(&snapshot.queued)
+            .map(|send| send.unique_id)
+            .collect();
+        unique_ids.sort_unstable();
+        assert_eq!(unique_ids, [1, 2, 3]);
+        assert!(snapshot.pending_replies.is_empty());
+        assert!(snapshot.streams.is_empty());
+
+        let later = node.introspect();
+        let ids = |snapshot: &NodeIntrospection| -> Vec<u64> {
+            snapshot.in_flight.iter().map(|send| send.id).collect()
+        };
+        assert_eq!(ids(&later), ids(&snapshot));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn redacted_snapshots_hash_identities() {
+        let node = busy().await;
+        let (plain, redacted) = (node.introspect(), node.introspect_redacted());
+        let hops: Vec<_> = redacted
+            .in_flight
+            .iter()
+            .chain(&redacted.queued)
+            .map(|send| send.next_hop.clone())
+            .collect();
+        assert_eq!(hops.len(), 3);
+        assert!(hops.iter().all(|hop| *hop == hops[0]));
+        assert_ne!(hops[0], plain.in_flight[0].next_hop);
+        let hash = hops[0].strip_prefix(&format!("{TEST}:")).unwrap();
+        assert_eq!(hash.len(), 16);
+        assert!(hash.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(redacted.in_flight[0].elapsed, plain.in_flight[0].elapsed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test(start_paused = true)]
+    async fn snapshots_serialize() {
+        let node = busy().await;
+        let value = serde_json::to_value(node.introspect_redacted()).unwrap();
+        assert_eq!(value["in_flight"].as_array().unwrap().len(), 2);
+        assert_eq!(value["queued"][0]["protocol"], TEST.to_string());
+        assert!(!value.to_string().contains(&addr("b").to_string()));
+    }
+}
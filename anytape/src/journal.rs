@@ -0,0 +1,993 @@
+//! Crash-safe delivery bookkeeping in one write-ahead log.
+//!
+//! With a journal, [`NodeInstance::dispatch_inbound`] rejects a `unique_id` it
+//! already accepted with [`RejectReason::Duplicate`] (fragments excepted, as
+//! they share their message's id), remembers the outcome of
+//! every message it accepts, and [`NodeInstance::send_detailed`] keeps each send
+//! in an outbox until it completes. All three are records appended to a single
+//! log file:
+//!
+//! - `Accepted { unique_id }`, before an inbound message is processed;
+//! - `Status { unique_id, status }`, once its outcome is known;
+//! - `Enqueue { unique_id, to, message }` and `Dequeue { unique_id }`, around
+//!   a send.
+//!
+//! Each record is a little-endian `u32` length, the CRC-32 of the body and the
+//! body. Appends are written right away but synced in groups: once
+//! [`JournalConfig::group_commit_records`] records are pending or
+//! [`JournalConfig::group_commit_interval`] has passed since the last sync.
+//! Every [`JournalConfig::snapshot_every`] records the current state is written
+//! to a snapshot file next to the log, after which the log is truncated.
+//!
+//! [`NodeInstance::recover_from_journal`] loads the snapshot and replays the log
+//! on top of it. A torn or corrupt record ends the replay; it and everything
+//! after it are cut off the log. Sends still in the outbox are the ones a crash
+//! interrupted; [`NodeInstance::resend_outbox`] sends them again.
+//!
+//! # Compaction
+//!
+//! After a long outage the outbox may hold sends that are no longer worth
+//! making. [`NodeInstance::compact_outbox`] drops them as a [`CompactionPolicy`]
+//! says: sends whose propagated [deadline](crate::Deadline::propagated) passed,
+//! sends superseded by a newer one with the same coalescing header, and the
+//! oldest sends to a destination beyond its count or byte budget. Each dropped
+//! send is dequeued in the log and handed to the
+//! [dead-letter hook](NodeInstance::with_outbox_dead_letter). With
+//! [`JournalConfig::compaction`] set, the node compacts on its own every
+//! [`JournalConfig::compaction_interval`] and before each
+//! [resend](NodeInstance::resend_outbox).
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    control::{get_status, put_status},
+    wire::{DecodeError, Reader, Writer},
+    Address, Deadline, Message, MessageStatus, NodeInstance, RejectReason, SendError,
+};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"ATJS";
+const SNAPSHOT_VERSION: u8 = 1;
+/// Largest record a recovery accepts; anything declaring more is corrupt.
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalConfig {
+    /// Sync once this many records are pending.
+    pub group_commit_records: usize,
+    /// Sync on the next append once this long has passed since the last sync.
+    pub group_commit_interval: Duration,
+    /// Write a snapshot and truncate the log after this many records.
+    pub snapshot_every: usize,
+    /// Accepted `unique_id`s remembered for deduplication.
+    pub dedup_window: usize,
+    /// Outcomes remembered for [`NodeInstance::journaled_status`].
+    pub status_capacity: usize,
+    /// Compact the outbox on its own with this policy; see the
+    /// [module docs](self#compaction).
+    pub compaction: Option<CompactionPolicy>,
+    /// How often to compact on its own, checked on each append.
+    pub compaction_interval: Duration,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            group_commit_records: 64,
+            group_commit_interval: Duration::from_millis(10),
+            snapshot_every: 100_000,
+            dedup_window: 100_000,
+            status_capacity: 100_000,
+            compaction: None,
+            compaction_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JournalStats {
+    /// Records appended since the journal was opened.
+    pub records: u64,
+    pub syncs: u64,
+    pub snapshots: u64,
+    /// Bytes cut off the log as torn or corrupt during recovery.
+    pub discarded_bytes: u64,
+    pub compactions: u64,
+    /// Sends dropped from the outbox by compactions.
+    pub compacted: u64,
+}
+
+/// Which sends [`NodeInstance::compact_outbox`] drops; see the
+/// [module docs](self#compaction). The default drops none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionPolicy {
+    /// Drop sends whose propagated deadline passed.
+    pub drop_expired: bool,
+    /// Of the sends to one destination with the same value of this header, keep
+    /// only the newest. Sends without the header are never superseded.
+    pub coalescing_header: Option<&'static str>,
+    /// Keep at most this many sends per destination, dropping the oldest.
+    pub max_per_destination: Option<usize>,
+    /// Keep at most this many payload bytes per destination, dropping the oldest
+    /// sends.
+    pub max_bytes_per_destination: Option<usize>,
+}
+
+/// Why compaction dropped a send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionReason {
+    Expired,
+    /// A newer send had the same coalescing header.
+    Superseded,
+    /// The destination's count or byte budget was spent.
+    OverBudget,
+}
+
+/// What a compaction dropped.
+#[derive(Clone, Default)]
+pub struct CompactionReport {
+    pub expired: usize,
+    pub superseded: usize,
+    pub evicted: usize,
+    /// Sends left in the outbox.
+    pub kept: usize,
+    /// The dropped sends as `(message, to, reason)`, oldest first.
+    pub dropped: Vec<(Message, Address, CompactionReason)>,
+}
+
+/// Receives each send compaction drops.
+pub(crate) type DeadLetterHook = dyn Fn(&Message, &Address, CompactionReason) + Send + Sync;
+
+enum Record {
+    Accepted {
+        unique_id: u64,
+    },
+    Status {
+        unique_id: u64,
+        status: MessageStatus,
+    },
+    Enqueue {
+        unique_id: u64,
+        to: Address,
+        message: Box<Message>,
+    },
+    Dequeue {
+        unique_id: u64,
+    },
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Record::Accepted { unique_id } => {
+                w.put_u8(0);
+                w.put_u64(*unique_id);
+            }
+            Record::Status { unique_id, status } => {
+                w.put_u8(1);
+                w.put_u64(*unique_id);
+                put_status(&mut w, *status);
+            }
+            Record::Enqueue {
+                unique_id,
+                to,
+                message,
+            } => {
+                w.put_u8(2);
+                w.put_u64(*unique_id);
+                w.put_address(to);
+                w.put_bytes(&message.encode());
+            }
+            Record::Dequeue { unique_id } => {
+                w.put_u8(3);
+                w.put_u64(*unique_id);
+            }
+        }
+        w.finish()
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let record = match r.get_u8()? {
+            0 => Record::Accepted {
+                unique_id: r.get_u64()?,
+            },
+            1 => Record::Status {
+                unique_id: r.get_u64()?,
+                status: get_status(&mut r)?,
+            },
+            2 => Record::Enqueue {
+                unique_id: r.get_u64()?,
+                to: r.get_address()?,
+                message: Box::new(Message::decode(r.get_bytes()?)?),
+            },
+            3 => Record::Dequeue {
+                unique_id: r.get_u64()?,
+            },
+            tag => return Err(DecodeError::InvalidFlags(tag)),
+        };
+        r.finish()?;
+        Ok(record)
+    }
+    fn frame(&self) -> Vec<u8> {
+        let body = self.encode();
+        let mut frame = Vec::with_capacity(body.len() + 8);
+        frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32(&body).to_le_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE), as used by zip and ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Read framed records from `bytes` until the end or the first bad one.
+/// Returns the records and how many bytes they took.
+fn read_records(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + 8) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len > MAX_RECORD_LEN {
+            break;
+        }
+        let Some(body) = bytes.get(offset + 8..offset + 8 + len as usize) else {
+            break;
+        };
+        if crc32(body) != crc {
+            break;
+        }
+        let Ok(record) = Record::decode(body) else {
+            break;
+        };
+        records.push(record);
+        offset += 8 + len as usize;
+    }
+    (records, offset)
+}
+
+#[derive(Default)]
+struct State {
+    accepted: HashSet<u64>,
+    accepted_order: VecDeque<u64>,
+    statuses: HashMap<u64, MessageStatus>,
+    status_order: VecDeque<u64>,
+    /// Sends not yet completed, in the order they started.
+    outbox: Vec<(u64, Address, Message)>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record, config: &JournalConfig) {
+        match record {
+            Record::Accepted { unique_id } => {
+                if self.accepted.insert(unique_id) {
+                    self.accepted_order.push_back(unique_id);
+                    while self.accepted_order.len() > config.dedup_window {
+                        if let Some(old) = self.accepted_order.pop_front() {
+                            self.accepted.remove(&old);
+                        }
+                    }
+                }
+            }
+            Record::Status { unique_id, status } => {
+                if self.statuses.insert(unique_id, status).is_none() {
+                    self.status_order.push_back(unique_id);
+                    while self.status_order.len() > config.status_capacity {
+                        if let Some(old) = self.status_order.pop_front() {
+                            self.statuses.remove(&old);
+                        }
+                    }
+                }
+            }
+            Record::Enqueue {
+                unique_id,
+                to,
+                message,
+            } => self.outbox.push((unique_id, to, *message)),
+            Record::Dequeue { unique_id } => {
+                if let Some(at) = self.outbox.iter().position(|(id, ..)| *id == unique_id) {
+                    self.outbox.remove(at);
+                }
+            }
+        }
+    }
+    /// The outbox entries `policy` drops, by index, and why.
+    fn compaction(
+        &self,
+        policy: &CompactionPolicy,
+        now_millis: u64,
+    ) -> Vec<(usize, CompactionReason)> {
+        let mut reasons: Vec<Option<CompactionReason>> = self
+            .outbox
+            .iter()
+            .map(|(_, _, message)| {
+                let expired = policy.drop_expired
+                    && Deadline::header_expired(message, now_millis, Duration::ZERO);
+                expired.then_some(CompactionReason::Expired)
+            })
+            .collect();
+        // newest first, so each send is only measured against newer ones it kept
+        if let Some(header) = policy.coalescing_header {
+            let mut newest = HashSet::new();
+            for (reason, (_, to, message)) in reasons.iter_mut().zip(&self.outbox).rev() {
+                let Some(key) = message.headers.get(header) else {
+                    continue;
+                };
+                if reason.is_none() && !newest.insert((to, key)) {
+                    *reason = Some(CompactionReason::Superseded);
+                }
+            }
+        }
+        let mut spent: HashMap<&Address, (usize, usize, bool)> = HashMap::new();
+        for (reason, (_, to, message)) in reasons.iter_mut().zip(&self.outbox).rev() {
+            if reason.is_some() {
+                continue;
+            }
+            let (count, bytes, full) = spent.entry(to).or_default();
+            *count += 1;
+            *bytes += message.payload.len();
+            *full |= policy.max_per_destination.is_some_and(|max| *count > max)
+                || policy
+                    .max_bytes_per_destination
+                    .is_some_and(|max| *bytes > max);
+            if *full {
+                *reason = Some(CompactionReason::OverBudget);
+            }
+        }
+        reasons
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, reason)| Some((index, reason?)))
+            .collect()
+    }
+    /// Records that rebuild this state, oldest first.
+    fn records(&self) -> Vec<Record> {
+        let accepted = self
+            .accepted_order
+            .iter()
+            .map(|&unique_id| Record::Accepted { unique_id });
+        let statuses = self.status_order.iter().map(|&unique_id| Record::Status {
+            unique_id,
+            status: self.statuses[&unique_id],
+        });
+        let outbox = self
+            .outbox
+            .iter()
+            .map(|(unique_id, to, message)| Record::Enqueue {
+                unique_id: *unique_id,
+                to: to.clone(),
+                message: Box::new(message.clone()),
+            });
+        accepted.chain(statuses).chain(outbox).collect()
+    }
+}
+
+struct Log {
+    file: BufWriter<File>,
+    pending: usize,
+    last_sync: Instant,
+    since_snapshot: usize,
+    last_compaction: Instant,
+    state: State,
+    stats: JournalStats,
+}
+
+pub(crate) struct Journal {
+    path: PathBuf,
+    config: JournalConfig,
+    log: Mutex<Log>,
+}
+
+fn snapshot_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".snapshot");
+    name.into()
+}
+
+impl Journal {
+    fn open(path: &Path, config: JournalConfig) -> io::Result<Self> {
+        let mut state = State::default();
+        let mut stats = JournalStats::default();
+        match fs::read(snapshot_path(path)) {
+            Ok(bytes) => {
+                let header = SNAPSHOT_MAGIC.len() + 1;
+                if bytes.len() < header
+                    || bytes[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC
+                    || bytes[SNAPSHOT_MAGIC.len()] != SNAPSHOT_VERSION
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "not a journal snapshot",
+                    ));
+                }
+                // snapshots are renamed into place whole, so they are never torn
+                for record in read_records(&bytes[header..]).0 {
+                    state.apply(record, &config);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (records, valid) = read_records(&bytes);
+        let replayed = records.len();
+        for record in records {
+            state.apply(record, &config);
+        }
+        if valid < bytes.len() {
+            // a torn or corrupt tail
+            stats.discarded_bytes = (bytes.len() - valid) as u64;
+            file.set_len(valid as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            path: path.to_owned(),
+            config,
+            log: Mutex::new(Log {
+                file: BufWriter::new(file),
+                pending: 0,
+                last_sync: Instant::now(),
+                since_snapshot: replayed,
+                last_compaction: Instant::now(),
+                state,
+                stats,
+            }),
+        })
+    }
+    fn append(&self, record: Record) -> io::Result<()> {
+        self.write(&mut self.log.lock().unwrap(), record)
+    }
+    fn write(&self, log: &mut Log, record: Record) -> io::Result<()> {
+        log.file.write_all(&record.frame())?;
+        log.state.apply(record, &self.config);
+        log.stats.records += 1;
+        log.pending += 1;
+        log.since_snapshot += 1;
+        if log.since_snapshot >= self.config.snapshot_every {
+            return self.snapshot(log);
+        }
+        if log.pending >= self.config.group_commit_records
+            || log.last_sync.elapsed() >= self.config.group_commit_interval
+        {
+            Self::sync(log)?;
+        }
+        Ok(())
+    }
+    /// Drop the outbox entries `policy` picks, dequeueing them in the log. The
+    /// report lists them even if the log could not be written.
+    fn compact(
+        &self,
+        policy: &CompactionPolicy,
+        now_millis: u64,
+    ) -> (CompactionReport, io::Result<()>) {
+        let mut log = self.log.lock().unwrap();
+        let mut report = CompactionReport::default();
+        for (index, reason) in log.state.compaction(policy, now_millis) {
+            let (_, to, message) = &log.state.outbox[index];
+            match reason {
+                CompactionReason::Expired => report.expired += 1,
+                CompactionReason::Superseded => report.superseded += 1,
+                CompactionReason::OverBudget => report.evicted += 1,
+            }
+            report.dropped.push((message.clone(), to.clone(), reason));
+        }
+        let mut written = Ok(());
+        for (message, ..) in &report.dropped {
+            let unique_id = message.unique_id;
+            if let Err(e) = self.write(&mut log, Record::Dequeue { unique_id }) {
+                // keep the state in step with what was decided
+                log.state.apply(Record::Dequeue { unique_id }, &self.config);
+                written = Err(e);
+            }
+        }
+        report.kept = log.state.outbox.len();
+        log.last_compaction = Instant::now();
+        log.stats.compactions += 1;
+        log.stats.compacted += report.dropped.len() as u64;
+        (report, written)
+    }
+    fn compaction_due(&self) -> bool {
+        self.config.compaction.is_some()
+            && self.log.lock().unwrap().last_compaction.elapsed() >= self.config.compaction_interval
+    }
+    fn sync(log: &mut Log) -> io::Result<()> {
+        log.file.flush()?;
+        log.file.get_ref().sync_data()?;
+        log.pending = 0;
+        log.last_sync = Instant::now();
+        log.stats.syncs += 1;
+        Ok(())
+    }
+    fn snapshot(&self, log: &mut Log) -> io::Result<()> {
+        let final_path = snapshot_path(&self.path);
+        let mut temporary = final_path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut snapshot = BufWriter::new(File::create(&temporary)?);
+        snapshot.write_all(&SNAPSHOT_MAGIC)?;
+        snapshot.write_all(&[SNAPSHOT_VERSION])?;
+        for record in log.state.records() {
+            snapshot.write_all(&record.frame())?;
+        }
+        snapshot.flush()?;
+        snapshot.get_ref().sync_all()?;
+        fs::rename(&temporary, &final_path)?;
+        // the snapshot holds everything the log did
+        log.file.flush()?;
+        log.file.get_ref().set_len(0)?;
+        Self::sync(log)?;
+        log.since_snapshot = 0;
+        log.stats.snapshots += 1;
+        Ok(())
+    }
+}
+
+impl NodeInstance {
+    /// Keep delivery bookkeeping in the journal at `path`, first rebuilding it
+    /// from what an earlier run left there; see the [module docs](self).
+    pub fn recover_from_journal(
+        mut self,
+        path: impl AsRef<Path>,
+        config: JournalConfig,
+    ) -> io::Result<Self> {
+        self.journal = Some(Journal::open(path.as_ref(), config)?);
+        Ok(self)
+    }
+    pub fn journal_stats(&self) -> Option<JournalStats> {
+        let journal = self.journal.as_ref()?;
+        Some(journal.log.lock().unwrap().stats)
+    }
+    /// Sync everything appended so far, without waiting for the group commit.
+    pub fn flush_journal(&self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => Journal::sync(&mut journal.log.lock().unwrap()),
+            None => Ok(()),
+        }
+    }
+    /// The outcome of the accepted message `unique_id`, if the journal still
+    /// remembers it.
+    pub fn journaled_status(&self, unique_id: u64) -> Option<MessageStatus> {
+        let journal = self.journal.as_ref()?;
+        let log = journal.log.lock().unwrap();
+        log.state.statuses.get(&unique_id).copied()
+    }
+    /// Sends that started but did not complete, as `(message, to)`.
+    pub fn pending_outbox(&self) -> Vec<(Message, Address)> {
+        let Some(journal) = &self.journal else {
+            return Vec::new();
+        };
+        let log = journal.log.lock().unwrap();
+        log.state
+            .outbox
+            .iter()
+            .map(|(_, to, message)| (message.clone(), to.clone()))
+            .collect()
+    }
+    /// Hand each send [compaction](self#compaction) drops from the outbox to
+    /// `hook`, e.g. to keep it in a dead-letter queue.
+    pub fn with_outbox_dead_letter(
+        self,
+        hook: impl Fn(&Message, &Address, CompactionReason) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            outbox_dead_letter: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+    /// Drop the sends `policy` picks from the outbox; see the
+    /// [module docs](self#compaction). Without a journal nothing is dropped.
+    pub fn compact_outbox(&self, policy: CompactionPolicy) -> CompactionReport {
+        let Some(journal) = &self.journal else {
+            return CompactionReport::default();
+        };
+        let (report, written) = journal.compact(&policy, self.clock.now_millis());
+        if let Err(e) = written {
+            self.audit_journal_failure(e);
+        }
+        if let Some(hook) = &self.outbox_dead_letter {
+            for (message, to, reason) in &report.dropped {
+                hook(message, to, *reason);
+            }
+        }
+        report
+    }
+    /// Send every message of the [outbox](NodeInstance::pending_outbox) again,
+    /// in the order they were first sent, after compacting it if the journal is
+    /// [configured](JournalConfig::compaction) to.
+    pub async fn resend_outbox(&self) -> Vec<Result<(), SendError>> {
+        if let Some(policy) = self.journal.as_ref().and_then(|j| j.config.compaction) {
+            self.compact_outbox(policy);
+        }
+        let mut results = Vec::new();
+        for (message, to) in self.pending_outbox() {
+            // the new attempt gets its own outbox entry
+            self.journal_dequeue(message.unique_id);
+            results.push(self.send(message, to).await);
+        }
+        results
+    }
+    /// The rejection for an already accepted `unique_id`; otherwise records it
+    /// as accepted.
+    pub(crate) fn journal_accept(&self, message: &Message) -> Option<MessageStatus> {
+        let journal = self.journal.as_ref()?;
+        if message.is_fragment() {
+            // fragments share their message's id; reassembly sorts them out
+            return None;
+        }
+        let unique_id = message.unique_id;
+        if journal
+            .log
+            .lock()
+            .unwrap()
+            .state
+            .accepted
+            .contains(&unique_id)
+        {
+            return Some(MessageStatus::Rejected {
+                reason: RejectReason::Duplicate,
+            });
+        }
+        self.journal_append(journal, Record::Accepted { unique_id });
+        None
+    }
+    pub(crate) fn journal_status(&self, unique_id: u64, result: &Result<MessageStatus, SendError>) {
+        if let Some(journal) = &self.journal {
+            let status = match result {
+                Ok(status) => *status,
+                Err(_) => MessageStatus::SendError,
+            };
+            self.journal_append(journal, Record::Status { unique_id, status });
+        }
+    }
+    pub(crate) fn journal_enqueue(&self, message: &Message, to: &Address) {
+        if let Some(journal) = &self.journal {
+            let record = Record::Enqueue {
+                unique_id: message.unique_id,
+                to: to.clone(),
+                message: Box::new(message.clone()),
+            };
+            self.journal_append(journal, record);
+        }
+    }
+    pub(crate) fn journal_dequeue(&self, unique_id: u64) {
+        if let Some(journal) = &self.journal {
+            self.journal_append(journal, Record::Dequeue { unique_id });
+        }
+    }
+    fn journal_append(&self, journal: &Journal, record: Record) {
+        // bookkeeping must not fail the traffic; the audit log tells the operator
+        if let Err(e) = journal.append(record) {
+            self.audit_journal_failure(e);
+        }
+        if journal.compaction_due() {
+            if let Some(policy) = journal.config.compaction {
+                self.compact_outbox(policy);
+            }
+        }
+    }
+    fn audit_journal_failure(&self, error: io::Error) {
+        self.audit(self.audit_event(
+            crate::AuditKind::PolicyChanged,
+            format!("journal write failed: {error}"),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deadline::DEADLINE_HEADER,
+        random_u64,
+        testing::{addr, message, Recorder, TEST},
+    };
+
+    /// A journal path of its own for each test, removed when dropped.
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new() -> Self {
+            let name = format!("anytape-journal-{:x}", random_u64());
+            Self(std::env::temp_dir().join(name))
+        }
+        fn node(&self, config: JournalConfig) -> NodeInstance {
+            NodeInstance::new()
+                .with_executor(TEST, Recorder::new())
+                .recover_from_journal(&self.0, config)
+                .unwrap()
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(snapshot_path(&self.0));
+        }
+    }
+
+    fn with_header(mut message: Message, name: &str, value: &[u8]) -> Message {
+        message.headers.insert(name.to_owned(), value.to_vec());
+        message
+    }
+
+    fn payloads(node: &NodeInstance) -> Vec<Vec<u8>> {
+        let outbox = node.pending_outbox();
+        outbox
+            .into_iter()
+            .map(|(message, _)| message.payload)
+            .collect()
+    }
+
+    fn enqueue(node: &NodeInstance, messages: impl IntoIterator<Item = Message>) {
+        for message in messages {
+            let to = message.destination.clone();
+            node.journal_enqueue(&message, &to);
+        }
+    }
+
+    #[test]
+    fn expired_sends_are_dropped() {
+        let journal = TempJournal::new();
+        let node = journal.node(JournalConfig::default());
+        let expired = with_header(
+            message(addr("b"), b"old"),
+            DEADLINE_HEADER,
+            &1u64.to_le_bytes(),
+        );
+        enqueue(&node, [expired, message(addr("b"), b"new")]);
+        let report = node.compact_outbox(CompactionPolicy {
+            drop_expired: true,
+            ..CompactionPolicy::default()
+        });
+        assert_eq!((report.expired, report.kept), (1, 1));
+        assert_eq!(payloads(&node), [b"new"]);
+    }
+
+    #[test]
+    fn newer_sends_supersede_older_ones_with_the_same_key() {
+        let journal = TempJournal::new();
+        let node = journal.node(JournalConfig::default());
+        let keyed = |to, payload, key: &[u8]| with_header(message(addr(to), payload), "key", key);
+        enqueue(
+            &node,
+            [
+                keyed("b", b"1", b"x"),
+                keyed("b", b"2", b"y"),
+                message(addr("b"), b"3"),
+                keyed("c", b"4", b"x"),
+                keyed("b", b"5", b"x"),
+            ],
+        );
+        let report = node.compact_outbox(CompactionPolicy {
+            coalescing_header: Some("key"),
+            ..CompactionPolicy::default()
+        });
+        assert_eq!(report.superseded, 1);
+        let dropped = &report.dropped[0];
+        assert_eq!(
+            (&dropped.0.payload[..], dropped.2),
+            (&b"1"[..], CompactionReason::Superseded)
+        );
+        assert_eq!(payloads(&node), [&b"2"[..], b"3", b"4", b"5"]);
+    }
+
+    #[test]
+    fn the_oldest_sends_beyond_a_budget_are_evicted() {
+        let journal = TempJournal::new();
+        let node = journal.node(JournalConfig::default());
+        enqueue(
+            &node,
+            [
+                message(addr("b"), b"1"),
+                message(addr("c"), b"2"),
+                message(addr("b"), b"3"),
+                message(addr("b"), b"4"),
+            ],
+        );
+        let report = node.compact_outbox(CompactionPolicy {
+            max_per_destination: Some(2),
+            ..CompactionPolicy::default()
+        });
+        assert_eq!((report.evicted, report.kept), (1, 3));
+        assert_eq!(payloads(&node), [b"2", b"3", b"4"]);
+
+        let report = node.compact_outbox(CompactionPolicy {
+            max_bytes_per_destination: Some(1),
+            ..CompactionPolicy::default()
+        });
+        assert_eq!(report.evicted, 1);
+        assert_eq!(payloads(&node), [b"2", b"4"]);
+    }
+
+    #[test]
+    fn dropped_sends_are_dead_lettered_and_stay_dropped() {
+        let journal = TempJournal::new();
+        let letters = Arc::new(Mutex::new(Vec::new()));
+        let node = journal
+            .node(JournalConfig::default())
+            .with_outbox_dead_letter({
+                let letters = letters.clone();
+                move |message: &Message, _: &Address, reason| {
+                    letters
+                        .lock()
+                        .unwrap()
+                        .push((message.payload.clone(), reason))
+                }
+            });
+        enqueue(&node, [message(addr("b"), b"1"), message(addr("b"), b"2")]);
+        node.compact_outbox(CompactionPolicy {
+            max_per_destination: Some(1),
+            ..CompactionPolicy::default()
+        });
+        assert_eq!(
+            *letters.lock().unwrap(),
+            [(b"1".to_vec(), CompactionReason::OverBudget)]
+        );
+        let stats = node.journal_stats().unwrap();
+        assert_eq!((stats.compactions, stats.compacted), (1, 1));
+        node.flush_journal().unwrap();
+        drop(node);
+
+        let recovered = journal.node(JournalConfig::default());
+        assert_eq!(payloads(&recovered), [b"2"]);
+    }
+
+    #[tokio::test]
+    async fn resends_compact_first() {
+        let journal = TempJournal::new();
+        let config = JournalConfig {
+            compaction: Some(CompactionPolicy {
+                max_per_destination: Some(1),
+                ..CompactionPolicy::default()
+            }),
+            compaction_interval: Duration::from_secs(3600),
+            ..JournalConfig::default()
+        };
+        let node = journal.node(config);
+        enqueue(&node, [message(addr("b"), b"1"), message(addr("b"), b"2")]);
+        let results = node.resend_outbox().await;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+        assert!(node.pending_outbox().is_empty());
+    }
+
+    #[test]
+    fn compaction_runs_on_its_own_once_due() {
+        let journal = TempJournal::new();
+        let config = JournalConfig {
+            compaction: Some(CompactionPolicy {
+                max_per_destination: Some(1),
+                ..CompactionPolicy::default()
+            }),
+            compaction_interval: Duration::ZERO,
+            ..JournalConfig::default()
+        };
+        let node = journal.node(config);
+        enqueue(&node, [message(addr("b"), b"1"), message(addr("b"), b"2")]);
+        assert_eq!(payloads(&node), [b"2"]);
+    }
+
+    #[tokio::test]
+    async fn a_crashed_node_recovers_its_accepted_messages_and_outbox() {
+        let journal = TempJournal::new();
+        let node = || {
+            journal
+                .node(JournalConfig::default())
+                .with_address(addr("me"))
+                .with_handler(|_| async {})
+        };
+        let inbound: Vec<_> = (0..3u8).map(|n| message(addr("me"), &[n])).collect();
+        let first = node();
+        for message in &inbound {
+            let status = first.dispatch_inbound(message.clone(), addr("me")).await;
+            assert!(matches!(status, Ok(MessageStatus::Received)));
+        }
+        // a send under way when the node goes down, and one that completed
+        enqueue(&first, [message(addr("b"), b"unacked")]);
+        first
+            .send_detailed(message(addr("b"), b"acked"), addr("b"))
+            .await
+            .unwrap();
+        // dropped without a flush, mid group commit
+        drop(first);
+
+        let recovered = node();
+        for message in &inbound {
+            assert_eq!(
+                recovered.journaled_status(message.unique_id),
+                Some(MessageStatus::Received)
+            );
+            let status = recovered
+                .dispatch_inbound(message.clone(), addr("me"))
+                .await;
+            assert!(matches!(
+                status,
+                Ok(MessageStatus::Rejected {
+                    reason: RejectReason::Duplicate
+                })
+            ));
+        }
+        assert_eq!(payloads(&recovered), [b"unacked"]);
+        let results = recovered.resend_outbox().await;
+        assert!(results.iter().all(Result::is_ok));
+        assert!(recovered.pending_outbox().is_empty());
+    }
+
+    #[test]
+    fn torn_records_are_cut_off_on_recovery() {
+        let journal = TempJournal::new();
+        let node = journal.node(JournalConfig::default());
+        enqueue(&node, [message(addr("b"), b"kept")]);
+        node.flush_journal().unwrap();
+        drop(node);
+        // half a record, as a crash in the middle of an append leaves it
+        let mut log = OpenOptions::new().append(true).open(&journal.0).unwrap();
+        log.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        drop(log);
+
+        let recovered = journal.node(JournalConfig::default());
+        assert_eq!(payloads(&recovered), [b"kept"]);
+        assert_eq!(recovered.journal_stats().unwrap().discarded_bytes, 6);
+    }
+
+    #[test]
+    fn group_commits_sync_far_less_than_per_record_flushing() {
+        let syncs = |group_commit_records| {
+            let journal = TempJournal::new();
+            let node = journal.node(JournalConfig {
+                group_commit_records,
+                group_commit_interval: Duration::from_secs(3600),
+                ..JournalConfig::default()
+            });
+            for n in 0..256u32 {
+                let message = message(addr("b"), &n.to_be_bytes());
+                node.journal_enqueue(&message, &addr("b"));
+                node.journal_dequeue(message.unique_id);
+            }
+            let stats = node.journal_stats().unwrap();
+            assert_eq!(stats.records, 512);
+            stats.syncs
+        };
+        let (grouped, single) = (syncs(64), syncs(1));
+        assert_eq!(single, 512);
+        assert!(grouped <= 512 / 64, "{grouped} syncs with group commit");
+    }
+}
@@ -0,0 +1,327 @@
+//! Checking JSON payloads against a JSON Schema.
+//!
+//! [`JsonSchema`] understands the keywords most contracts need: `type`,
+//! `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`,
+//! `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum` and `maximum`.
+//! Other keywords are ignored, so a schema written for a full validator still
+//! accepts everything that one would, and possibly more.
+
+use serde_json::{Map, Value};
+
+use crate::{PayloadSchema, SchemaError};
+
+const TYPES: [&str; 7] = [
+    "null", "boolean", "object", "array", "number", "string", "integer",
+];
+
+/// A JSON Schema document; see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct JsonSchema(Value);
+
+impl JsonSchema {
+    /// Fails if `schema` is not an object or a boolean, or uses one of the
+    /// keywords understood with a value of the wrong kind.
+    pub fn new(schema: Value) -> Result<Self, SchemaError> {
+        check_schema(&schema, "")?;
+        Ok(Self(schema))
+    }
+    pub fn as_value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl TryFrom<Value> for JsonSchema {
+    type Error = SchemaError;
+    fn try_from(schema: Value) -> Result<Self, SchemaError> {
+        Self::new(schema)
+    }
+}
+
+impl From<JsonSchema> for Value {
+    fn from(schema: JsonSchema) -> Value {
+        schema.0
+    }
+}
+
+impl PayloadSchema for JsonSchema {
+    fn validate(&self, payload: &[u8]) -> Result<(), SchemaError> {
+        let value: Value = serde_json::from_slice(payload)
+            .map_err(|e| SchemaError::invalid(format!("not JSON: {e}")))?;
+        validate(&self.0, &value, "")
+    }
+}
+
+/// `detail`, said of the value at the JSON pointer `at`.
+fn located(at: &str, detail: impl std::fmt::Display) -> String {
+    let at = if at.is_empty() { "/" } else { at };
+    format!("{at}: {detail}")
+}
+
+fn invalid(at: &str, detail: impl std::fmt::Display) -> SchemaError {
+    SchemaError::Invalid(located(at, detail))
+}
+
+fn malformed(at: &str, detail: impl std::fmt::Display) -> SchemaError {
+    SchemaError::MalformedSchema(located(at, detail))
+}
+
+fn check_schema(schema: &Value, at: &str) -> Result<(), SchemaError> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => return Err(malformed(at, "a schema must be an object or a boolean")),
+    };
+    if let Some(types) = schema.get("type") {
+        let known = |name: &Value| name.as_str().is_some_and(|name| TYPES.contains(&name));
+        let valid = match types {
+            Value::Array(names) => names.iter().all(known),
+            name => known(name),
+        };
+        if !valid {
+            return Err(malformed(at, format!("unknown type {types}")));
+        }
+    }
+    if let Some(required) = schema.get("required") {
+        if !required
+            .as_array()
+            .is_some_and(|names| names.iter().all(Value::is_string))
+        {
+            return Err(malformed(at, "required must list property names"));
+        }
+    }
+    if schema.get("enum").is_some_and(|values| !values.is_array()) {
+        return Err(malformed(at, "enum must be an array"));
+    }
+    for keyword in ["minItems", "maxItems", "minLength", "maxLength"] {
+        if schema.get(keyword).is_some_and(|limit| !limit.is_u64()) {
+            return Err(malformed(at, format!("{keyword} must be a whole number")));
+        }
+    }
+    for keyword in ["minimum", "maximum"] {
+        if schema.get(keyword).is_some_and(|limit| !limit.is_number()) {
+            return Err(malformed(at, format!("{keyword} must be a number")));
+        }
+    }
+    if let Some(properties) = schema.get("properties") {
+        let Some(properties) = properties.as_object() else {
+            return Err(malformed(at, "properties must be an object"));
+        };
+        for (name, property) in properties {
+            check_schema(property, &format!("{at}/{name}"))?;
+        }
+    }
+    for keyword in ["additionalProperties", "items"] {
+        if let Some(schema) = schema.get(keyword) {
+            check_schema(schema, &format!("{at}/{keyword}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => false,
+    }
+}
+
+fn limit(schema: &Map<String, Value>, keyword: &str) -> Option<u64> {
+    schema.get(keyword).and_then(Value::as_u64)
+}
+
+fn validate(schema: &Value, value: &Value, at: &str) -> Result<(), SchemaError> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(invalid(at, "no value is allowed here")),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::Array(names) => names
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|name| type_matches(name, value)),
+            name => name.as_str().is_some_and(|name| type_matches(name, value)),
+        };
+        if !matches {
+            return Err(invalid(at, format!("expected type {types}, got {value}")));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(invalid(at, format!("expected {expected}, got {value}")));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(invalid(at, format!("{value} is not one of {allowed:?}")));
+        }
+    }
+    match value {
+        Value::Object(object) => validate_object(schema, object, at),
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = limit(schema, "minItems").filter(|min| len < *min) {
+                return Err(invalid(at, format!("{len} items, expected at least {min}")));
+            }
+            if let Some(max) = limit(schema, "maxItems").filter(|max| len > *max) {
+                return Err(invalid(at, format!("{len} items, expected at most {max}")));
+            }
+            if let Some(item) = schema.get("items") {
+                for (index, value) in items.iter().enumerate() {
+                    validate(item, value, &format!("{at}/{index}"))?;
+                }
+            }
+            Ok(())
+        }
+        Value::String(string) => {
+            let len = string.chars().count() as u64;
+            if let Some(min) = limit(schema, "minLength").filter(|min| len < *min) {
+                return Err(invalid(
+                    at,
+                    format!("{len} characters, expected at least {min}"),
+                ));
+            }
+            if let Some(max) = limit(schema, "maxLength").filter(|max| len > *max) {
+                return Err(invalid(
+                    at,
+                    format!("{len} characters, expected at most {max}"),
+                ));
+            }
+            Ok(())
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or(f64::NAN);
+            let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+            if let Some(min) = bound("minimum").filter(|min| number < *min) {
+                return Err(invalid(at, format!("{number} is less than {min}")));
+            }
+            if let Some(max) = bound("maximum").filter(|max| number > *max) {
+                return Err(invalid(at, format!("{number} is more than {max}")));
+            }
+            Ok(())
+        }
+        Value::Null | Value::Bool(_) => Ok(()),
+    }
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    at: &str,
+) -> Result<(), SchemaError> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(invalid(at, format!("missing required property {name:?}")));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let additional = schema.get("additionalProperties");
+    for (name, value) in object {
+        let at = format!("{at}/{name}");
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property) => validate(property, value, &at)?,
+            None => {
+                if let Some(additional) = additional {
+                    validate(additional, value, &at)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        testing::{addr, Recorder, TEST},
+        MessageBuilder, MessageStatus, NodeInstance, RejectReason, SendError, CONTENT_TYPE_HEADER,
+    };
+
+    fn order_schema() -> JsonSchema {
+        JsonSchema::new(json!({
+            "type": "object",
+            "required": ["id", "items"],
+            "properties": {
+                "id": { "type": "integer", "minimum": 1 },
+                "items": { "type": "array", "minItems": 1, "items": { "type": "string" } }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn documents_are_checked_against_the_keywords() {
+        let schema = order_schema();
+        assert_eq!(schema.validate(br#"{"id": 1, "items": ["a"]}"#), Ok(()));
+        for (payload, detail) in [
+            (
+                &br#"{"id": 1}"#[..],
+                "/: missing required property \"items\"",
+            ),
+            (br#"{"id": 0, "items": ["a"]}"#, "/id"),
+            (br#"{"id": 1, "items": [2]}"#, "/items/0"),
+            (b"not json", "not JSON"),
+        ] {
+            let Err(SchemaError::Invalid(error)) = schema.validate(payload) else {
+                panic!("{detail} should not validate");
+            };
+            assert!(error.contains(detail), "{error}");
+        }
+        assert!(matches!(
+            JsonSchema::new(json!({ "type": "nonsense" })),
+            Err(SchemaError::MalformedSchema(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_missing_field_is_rejected_on_send_and_receive() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_address(addr("b"))
+            .with_executor(TEST, recorder.clone())
+            .with_handler(|_| async {})
+            .with_schema("application/order+json", order_schema());
+        let order = |payload: &'static str| {
+            MessageBuilder::new(addr("b"))
+                .header(CONTENT_TYPE_HEADER, "application/order+json")
+                .payload(payload)
+                .build()
+        };
+
+        let result = node.send(order(r#"{"id": 7}"#), addr("c")).await;
+        let Err(SendError::SchemaViolation(SchemaError::Invalid(detail))) = result else {
+            panic!("expected a schema violation");
+        };
+        assert!(detail.contains("items"), "{detail}");
+        let status = node
+            .dispatch_inbound(order(r#"{"id": 7}"#), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            status,
+            MessageStatus::Rejected {
+                reason: RejectReason::SchemaViolation
+            }
+        );
+
+        let valid = r#"{"id": 7, "items": ["tea"]}"#;
+        node.send(order(valid), addr("c")).await.unwrap();
+        let status = node.dispatch_inbound(order(valid), addr("b")).await;
+        assert_eq!(status.unwrap(), MessageStatus::Received);
+        assert_eq!(recorder.sent().len(), 1);
+    }
+}
@@ -0,0 +1,271 @@
+//! Executors built on first use.
+
+use std::{
+    fmt,
+    sync::Arc,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{sync::OnceCell, time::Instant};
+
+use crate::{
+    replace::TrackedExecutor, BoxError, BoxFuture, BoxResult, DynProtocolExecutor, NodeInstance,
+    Protocol, SendError, SendFailure,
+};
+
+pub(crate) const DEFAULT_LAZY_COOLDOWN: Duration = Duration::from_secs(5);
+
+type ExecutorFactory = dyn Fn() -> BoxFuture<BoxResult<Arc<dyn DynProtocolExecutor>>> + Send + Sync;
+
+/// A lazily registered executor's factory failed recently and is not retried yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutorCoolingDown {
+    pub protocol: Protocol,
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for ExecutorCoolingDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "executor initialization failed recently, retrying in {:?}",
+            self.retry_in
+        )
+    }
+}
+
+impl std::error::Error for ExecutorCoolingDown {}
+
+pub(crate) struct LazyExecutor {
+    factory: Arc<ExecutorFactory>,
+    executor: OnceCell<Arc<TrackedExecutor>>,
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl LazyExecutor {
+    pub(crate) fn ready(&self) -> Option<&Arc<TrackedExecutor>> {
+        self.executor.get()
+    }
+    /// The executor, initializing it if this is the first use. Concurrent callers
+    /// share one initialization; after a failure, callers fail fast for `cooldown`.
+    async fn get(
+        &self,
+        protocol: &Protocol,
+        cooldown: Duration,
+    ) -> BoxResult<Arc<TrackedExecutor>> {
+        if let Some(executor) = self.ready() {
+            return Ok(executor.clone());
+        }
+        let executor = self
+            .executor
+            .get_or_try_init(|| async {
+                // waiters on a failed initialization land here too; they must not
+                // call the factory again
+                if let Some(failed_at) = *self.failed_at.lock().unwrap() {
+                    let retry_at = failed_at + cooldown;
+                    let now = Instant::now();
+                    if now < retry_at {
+                        return Err(Box::new(ExecutorCoolingDown {
+                            protocol: protocol.clone(),
+                            retry_in: retry_at - now,
+                        }) as BoxError);
+                    }
+                }
+                let result = (self.factory)().await;
+                if result.is_err() {
+                    *self.failed_at.lock().unwrap() = Some(Instant::now());
+                }
+                result.map(|executor| Arc::new(TrackedExecutor::new(executor)))
+            })
+            .await?;
+        Ok(executor.clone())
+    }
+}
+
+impl NodeInstance {
+    /// Register a factory building the executor for `protocol` on the first send.
+    ///
+    /// The factory runs at most once unless it fails; after a failure, sends fail
+    /// with [`ExecutorCoolingDown`] until the
+    /// [cool-down](NodeInstance::with_lazy_executor_cooldown) has passed.
+    /// Replaces any executor registered for `protocol`.
+    pub fn register_lazy_executor(
+        &mut self,
+        protocol: Protocol,
+        factory: impl Fn() -> BoxFuture<BoxResult<Arc<dyn DynProtocolExecutor>>> + Send + Sync + 'static,
+    ) {
+        self.protocol_executor.remove(&protocol);
+        self.replacements.remove(&protocol);
+        self.lazy_executors.insert(
+            protocol,
+            LazyExecutor {
+                factory: Arc::new(factory),
+                executor: OnceCell::new(),
+                failed_at: Mutex::new(None),
+            },
+        );
+        self.refresh_single_executor();
+    }
+    /// How long sends fail fast after a lazy executor's factory failed.
+    pub fn with_lazy_executor_cooldown(self, cooldown: Duration) -> Self {
+        Self {
+            lazy_cooldown: cooldown,
+            ..self
+        }
+    }
+    /// Initialize the lazily registered executor for `protocol` ahead of traffic.
+    pub async fn warmup(&self, protocol: &Protocol) -> Result<(), SendError> {
+        self.executor(protocol).await.map(|_| ())
+    }
+    /// Every protocol with an executor, lazily registered ones included.
+    pub fn supported_protocols(&self) -> Vec<Protocol> {
+        self.protocol_executor
+            .keys()
+            .chain(self.lazy_executors.keys())
+            .cloned()
+            .collect()
+    }
+    /// The executor for `protocol` as its concrete type `T`.
+    ///
+    /// `None` if there is none, it has another type, it is registered lazily
+    /// and not initialized yet, or it was [replaced](NodeInstance::replace_executor).
+    pub fn executor_as<T: 'static>(&self, protocol: &Protocol) -> Option<&T> {
+        let registration = self.registration(protocol)?;
+        if self.replacements.contains(registration) {
+            return None;
+        }
+        let executor = match self.protocol_executor.get(registration) {
+            Some(executor) => executor,
+            None => self.lazy_executors.get(registration)?.ready()?,
+        };
+        executor.as_any().downcast_ref()
+    }
+    pub(crate) fn has_executor(&self, protocol: &Protocol) -> bool {
+        self.registration(protocol).is_some()
+    }
+    /// The executor for `protocol` if it can be used without initializing it.
+    pub(crate) fn ready_executor(
+        &self,
+        protocol: &Protocol,
+    ) -> Option<Arc<dyn DynProtocolExecutor>> {
+        let registration = self.registration(protocol)?;
+        if let Some(executor) = self.replacements.get(registration) {
+            return Some(executor);
+        }
+        let executor = match self.protocol_executor.get(registration) {
+            Some(executor) => executor.clone(),
+            None => self.lazy_executors.get(registration)?.ready()?.clone(),
+        };
+        Some(executor)
+    }
+    /// Call after changing the registered executors.
+    pub(crate) fn refresh_single_executor(&mut self) {
+        self.single_executor = match (self.protocol_executor.len(), self.lazy_executors.len()) {
+            (1, 0) => self
+                .protocol_executor
+                .iter()
+                .next()
+                .map(|(protocol, executor)| (protocol.clone(), executor.clone())),
+            _ => None,
+        };
+    }
+    pub(crate) async fn executor(
+        &self,
+        protocol: &Protocol,
+    ) -> Result<Arc<TrackedExecutor>, SendError> {
+        if let Some((only, executor)) = &self.single_executor {
+            if only == protocol {
+                return Ok(self
+                    .replacements
+                    .get(only)
+                    .unwrap_or_else(|| executor.clone()));
+            }
+        }
+        let registration = self.registration(protocol).unwrap_or(protocol);
+        if let Some(executor) = self.replacements.get(registration) {
+            return Ok(executor);
+        }
+        if let Some(executor) = self.protocol_executor.get(registration) {
+            return Ok(executor.clone());
+        }
+        match self.lazy_executors.get(registration) {
+            Some(lazy) => lazy
+                .get(registration, self.lazy_cooldown)
+                .await
+                .map_err(|error| {
+                    SendError::ExecutorError(SendFailure {
+                        protocol: protocol.clone(),
+                        identity: String::new(),
+                        attempt: 1,
+                        at: SystemTime::now(),
+                        source: error,
+                        budget_exhausted: false,
+                    })
+                }),
+            None => Err(SendError::ProtocolNotSupport {
+                supported: self.supported_protocols(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TestError, TEST};
+
+    /// Register a factory for [`TEST`] that takes `delay` and fails its first
+    /// `failures` calls; returns the count of its calls.
+    fn register(node: &mut NodeInstance, delay: Duration, failures: usize) -> Arc<AtomicUsize> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        node.register_lazy_executor(TEST, move || {
+            let call = counted.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                if call < failures {
+                    return Err(Box::new(TestError("factory failed")) as BoxError);
+                }
+                Ok(Arc::new(Recorder::new()) as Arc<dyn DynProtocolExecutor>)
+            })
+        });
+        calls
+    }
+
+    fn cooling_down(error: &SendError) -> bool {
+        matches!(error, SendError::ExecutorError(failure)
+            if failure.source.downcast_ref::<ExecutorCoolingDown>().is_some())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_first_sends_build_the_executor_once() {
+        let mut node = NodeInstance::new();
+        let calls = register(&mut node, Duration::from_millis(100), 0);
+        let send = || node.send(message(addr("b"), b"x"), addr("b"));
+        let (first, second, third) = tokio::join!(send(), send(), send());
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(node.executor_as::<Recorder>(&TEST).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_factories_fail_fast_until_the_cooldown_has_passed() {
+        let mut node = NodeInstance::new().with_lazy_executor_cooldown(Duration::from_secs(5));
+        let calls = register(&mut node, Duration::ZERO, 1);
+        let send = || node.send(message(addr("b"), b"x"), addr("b"));
+
+        let first = send().await.unwrap_err();
+        assert!(!cooling_down(&first));
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(cooling_down(&send().await.unwrap_err()));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        send().await.unwrap();
+        node.warmup(&TEST).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,11 +1,194 @@
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
     error::Error,
     future::Future,
+    hash::BuildHasher,
     pin::Pin,
-    sync::Arc,
+    sync::{atomic::AtomicBool, Arc, RwLock},
+    time::{Duration, SystemTime},
 };
+
+mod audit;
+mod backend;
+mod batching;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod book;
+mod budget;
+mod cache;
+mod canonical;
+pub mod capture;
+mod clock;
+mod coalesce;
+mod config;
+pub mod control;
+mod cost;
+mod deadline;
+mod dedup;
+mod delivery;
+mod dry_run;
+pub mod encoding;
+mod envelope;
+mod events;
+mod fallback;
+mod fragment;
+pub mod frame;
+// the property tests build on the same generators
+#[cfg(any(test, feature = "arbitrary"))]
+mod fuzzing;
+mod group;
+mod handle;
+mod handler;
+mod hierarchy;
+mod ids;
+mod intern;
+mod introspect;
+mod lazy;
+mod metrics;
+mod mux;
+mod named;
+mod onion;
+mod panic;
+mod pathcanon;
+mod pathsig;
+mod permit;
+mod plan;
+mod quota;
+mod ratelimit;
+mod receipt;
+mod redact;
+mod remote_limit;
+mod reorder;
+mod replace;
+mod retry;
+mod retry_budget;
+mod rewrite;
+mod rotation;
+mod schema;
+mod score;
+mod sender;
+mod shed;
+mod state;
+mod stream;
+mod streaming;
+mod subscribe;
+mod throttle;
+mod transform;
+mod typed;
+pub mod typed_address;
+mod validate;
+mod warm;
+pub mod wire;
+mod withdraw;
+
+#[cfg(feature = "serde")]
+pub use audit::JsonLinesAuditSink;
+pub use audit::{AuditEvent, AuditKind, AuditSink, MemoryAuditSink};
+pub use backend::{MemoryBackend, RetryingBackend, RouteChange, StoredRoute};
+pub use batching::{BatchError, BatchingExecutor};
+pub use book::{AddressBook, AddressBookError};
+pub use cache::{CacheEviction, ROUTE_TOUCH_INTERVAL};
+pub use canonical::{AsciiCaseFold, Canonicalizer, Canonicalizers};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use coalesce::{CoalescingBackend, CoalescingConfig};
+pub use config::NodeConfig;
+pub use cost::{CostConfig, RouteCandidate, RouteSelection};
+pub use deadline::{Deadline, DEADLINE_HEADER};
+pub use dedup::{
+    ContentDedupConfig, ContentDedupPolicy, CONTENT_DIGEST_HEADER, DUPLICATE_CONTENT_HEADER,
+};
+pub use delivery::DeliveryReport;
+pub use dry_run::RelayPlan;
+pub use envelope::{RETURN_BLOCK_HEADER, RETURN_ENVELOPE_HEADER};
+pub use events::{
+    BackendEvents, EventMask, EventStream, NodeEvent, StampedEvent, DEFAULT_EVENT_CAPACITY,
+};
+pub use fallback::{FallbackError, FallbackExecutor};
+pub use fragment::{FRAGMENT_HEADER, MAX_REASSEMBLED_LEN};
+pub use group::{GroupControl, GroupReport, GROUP_CONTROL_HEADER, GROUP_HEADER};
+pub use handle::NodeHandle;
+pub use handler::{HandlerKey, HandlerPolicy};
+pub use hierarchy::DEFAULT_PROTOCOL_DELIMITER;
+pub use ids::IdGenerator;
+pub use intern::{AddressInterner, InternedAddress};
+pub use introspect::{
+    NodeIntrospection, PendingReply, QuarantinedPeer, SendSummary, StreamDirection, StreamTransfer,
+};
+pub use lazy::ExecutorCoolingDown;
+pub use metrics::NodeMetrics;
+pub use mux::{MuxError, MuxExecutor};
+pub use named::EXECUTOR_HEADER;
+pub use onion::{OnionError, OnionOpener, OnionSealer, ONION_HEADER, SEALED_DESTINATION_HEADER};
+pub use panic::ExecutorPanic;
+pub use pathcanon::{PathCanonicalization, PATH_CLAMPED_HEADER};
+pub use pathsig::{PathChainError, Signer, Verifier};
+pub use permit::SendPermit;
+pub use quota::{QuotaLimits, QuotaManager, QuotaUsage};
+pub use ratelimit::{RateLimitMode, RateLimiter};
+pub use receipt::{SendContext, SendOutcome, SendReceipt};
+pub use redact::RedactedMessage;
+pub use remote_limit::{PerRemoteLimit, RemoteLimitError, RemoteLimitMode};
+pub use replace::{DrainPolicy, DrainReport};
+pub use retry::{RetryPolicy, RetryingExecutor};
+pub use retry_budget::{RetryBudget, RetryBudgetConfig};
+pub use rewrite::{RewriteRule, RewriteRules};
+pub use rotation::{IdentityAlias, RotationConfig};
+pub use schema::{
+    ByteSchema, PayloadSchema, SchemaConfig, SchemaError, SchemaMode, SchemaPolicy, SchemaRegistry,
+    SchemaSpec, CONTENT_TYPE_HEADER,
+};
+pub use score::{Offense, PeerScoreConfig};
+pub use sender::{Sender, ToPayload, REPLY_HEADER};
+pub use shed::{LoadShedConfig, Priority, PRIORITY_HEADER};
+pub use state::NodeState;
+pub use stream::{StreamAssembler, StreamError, StreamOptions, STREAM_HEADER};
+pub use streaming::{StreamSendError, StreamingMessage};
+pub use subscribe::AddressPattern;
+pub use throttle::{ThrottleConfig, MAX_THROTTLE};
+pub use transform::{ForwardTransform, TransformError, TransformFuture, TransformScope};
+pub use typed::{TypedDynExecutor, TypedExecutor};
+pub use validate::{
+    IdentityValidator, IdentityValidators, InvalidAddress, KeyLengthValidator, SocketValidator,
+    ValidationError,
+};
+pub use warm::{WarmOptions, WarmPriority, WarmReport};
+pub use withdraw::{RouteWithdrawConfig, RouteWithdrawStats, WithdrawReason};
+
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(feature = "json-schema")]
+mod json_schema;
+#[cfg(feature = "json-schema")]
+pub use json_schema::JsonSchema;
+#[cfg(feature = "serde")]
+mod payload;
+#[cfg(feature = "serde")]
+pub use payload::{PayloadError, PayloadFormat};
+#[cfg(feature = "serde")]
+pub use transform::Transcode;
+#[cfg(any(test, feature = "test-util"))]
+pub mod conformance;
+#[cfg(feature = "tcp")]
+pub mod proxy;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(any(feature = "quic", feature = "tcp"))]
+pub mod resolve;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(feature = "tower")]
+pub mod tower;
+#[cfg(any(feature = "quic", feature = "tcp"))]
+mod transport;
+#[cfg(any(test, feature = "test-util"))]
+pub mod virtual_net;
+
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Protocol {
     expr: Cow<'static, [u8]>,
@@ -16,29 +199,299 @@ pub struct Identity {
     expr: Vec<u8>,
 }
 
+impl Protocol {
+    pub const fn new_static(expr: &'static [u8]) -> Self {
+        Self {
+            expr: Cow::Borrowed(expr),
+        }
+    }
+    pub fn new(expr: impl Into<Vec<u8>>) -> Self {
+        Self {
+            expr: Cow::Owned(expr.into()),
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.expr
+    }
+}
+
+impl Identity {
+    pub fn new(expr: impl Into<Vec<u8>>) -> Self {
+        Self { expr: expr.into() }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.expr
+    }
+}
+
+impl AsRef<[u8]> for Protocol {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for Identity {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Identities derived from key material are wiped when dropped. Clones are
+/// separate buffers, each wiped on its own drop.
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for Identity {
+    fn zeroize(&mut self) {
+        self.expr.zeroize()
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Identity {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self)
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for Identity {}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Address {
     pub protocol: Protocol,
     pub identity: Identity,
 }
 
+impl Address {
+    pub fn new(protocol: Protocol, identity: Identity) -> Self {
+        Self { protocol, identity }
+    }
+}
+
+impl From<(Protocol, Identity)> for Address {
+    fn from((protocol, identity): (Protocol, Identity)) -> Self {
+        Self::new(protocol, identity)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Node {
     pub address_set: Vec<Address>,
+    /// Free-form traits of the peer, such as `relay`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capabilities: Vec<String>,
 }
 
-pub struct Sender {}
+impl Node {
+    pub fn new(address_set: Vec<Address>) -> Self {
+        Self {
+            address_set,
+            capabilities: Vec::new(),
+        }
+    }
+    pub fn with_capability(mut self, capability: impl Into<String>) -> Self {
+        self.capabilities.push(capability.into());
+        self
+    }
+    pub fn group_by_protocol(&self) -> HashMap<&Protocol, Vec<&Address>> {
+        group_by_protocol(&self.address_set)
+    }
+    pub fn protocols(&self) -> HashSet<&Protocol> {
+        protocols(&self.address_set)
+    }
+}
+
+/// Group addresses by their protocol, keeping the input order within each bucket.
+pub fn group_by_protocol(addrs: &[Address]) -> HashMap<&Protocol, Vec<&Address>> {
+    let mut groups: HashMap<&Protocol, Vec<&Address>> = HashMap::new();
+    for addr in addrs {
+        groups.entry(&addr.protocol).or_default().push(addr);
+    }
+    groups
+}
+
+/// The distinct protocols appearing in `addrs`.
+pub fn protocols(addrs: &[Address]) -> HashSet<&Protocol> {
+    addrs.iter().map(|addr| &addr.protocol).collect()
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct Message {
     pub destination: Address,
     pub path: Vec<PathNode>,
     pub payload: Vec<u8>,
     pub signature: Vec<u8>,
     pub unique_id: u64,
+    /// Position in the sender's ordered sequence, for the
+    /// [reorder buffer](NodeInstance::with_reorder_buffer). Unlike `unique_id` it
+    /// says nothing about duplicates.
+    pub seq: Option<u64>,
+    /// Milliseconds left of the end-to-end [latency budget](NodeInstance::forward_with_budget).
+    pub budget_ms: Option<u64>,
+    /// Relays the message must pass through, in order, ending at the destination;
+    /// see [`NodeInstance::forward`].
+    pub route_plan: Option<Vec<Address>>,
+    /// Where answers should go, for senders whose path does not lead back to
+    /// them, e.g. behind anonymous hops; see [`Message::reply`].
+    pub reply_to: Option<Address>,
+    /// Remaining hops this message may be relayed over; `None` means unlimited.
+    pub ttl: Option<u32>,
+    /// Protocol-level headers, keyed by name. Names starting with `anytape-` are
+    /// reserved for this crate.
+    pub headers: BTreeMap<String, Vec<u8>>,
+    /// Application tags such as `region=eu`, readable by routing without touching
+    /// the payload.
+    pub metadata: Vec<(String, String)>,
+    /// Wire fields this build does not understand, kept so relays pass them on;
+    /// see [`Message::unknown_fields`].
+    pub extensions: Vec<wire::UnknownField>,
+}
+
+impl Message {
+    /// The path on one line, hops as `name@address+ts` joined by ` -> `. Hops
+    /// without a name leave out `name@`, hops without an address show `?` for it
+    /// and anonymous hops render as a bare `?`. Hops that recorded the
+    /// [budget left](PathNode::remaining_budget_ms) end in `~` and its
+    /// milliseconds, e.g. `relay@mesh/b+1700000000000~120ms`.
+    pub fn path_summary(&self) -> String {
+        let hops: Vec<String> = self
+            .path
+            .iter()
+            .map(|node| {
+                let hop = match (&node.name, &node.address) {
+                    (None, None) => "?".to_owned(),
+                    (Some(name), None) => format!("{name}@?+{}", node.ts),
+                    (None, Some(address)) => format!("{address}+{}", node.ts),
+                    (Some(name), Some(address)) => format!("{name}@{address}+{}", node.ts),
+                };
+                match node.remaining_budget_ms {
+                    Some(remaining) => format!("{hop}~{remaining}ms"),
+                    None => hop,
+                }
+            })
+            .collect();
+        hops.join(" -> ")
+    }
+    /// The value of the first metadata tag named `key`.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Set the metadata tag `key` to `value`, in place of any tags named `key`
+    /// already there. The tag keeps the position of the first of them.
+    pub fn set_tag(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let key = key.into();
+        let value = value.into();
+        match self.metadata.iter().position(|(k, _)| *k == key) {
+            Some(first) => {
+                self.metadata[first].1 = value;
+                let mut i = 0;
+                self.metadata.retain(|(k, _)| {
+                    i += 1;
+                    i - 1 <= first || *k != key
+                });
+            }
+            None => self.metadata.push((key, value)),
+        }
+    }
+    /// [Set](Message::set_tag) every tag of `other` in turn, so that for a key
+    /// set twice the last value wins, `other`'s over this message's.
+    pub fn merge_metadata(&mut self, other: impl IntoIterator<Item = (String, String)>) {
+        for (key, value) in other {
+            self.set_tag(key, value);
+        }
+    }
+}
+
+/// Builds a [`Message`] with a random `unique_id` unless one is set.
+pub struct MessageBuilder {
+    message: Message,
 }
 
+impl MessageBuilder {
+    pub fn new(destination: Address) -> Self {
+        Self {
+            message: Message {
+                destination,
+                path: Vec::new(),
+                payload: Vec::new(),
+                signature: Vec::new(),
+                unique_id: random_u64(),
+                ttl: None,
+                headers: BTreeMap::new(),
+                metadata: Vec::new(),
+                extensions: Vec::new(),
+                seq: None,
+                budget_ms: None,
+                route_plan: None,
+                reply_to: None,
+            },
+        }
+    }
+    pub fn payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.message.payload = payload.into();
+        self
+    }
+    pub fn signature(mut self, signature: impl Into<Vec<u8>>) -> Self {
+        self.message.signature = signature.into();
+        self
+    }
+    pub fn unique_id(mut self, unique_id: u64) -> Self {
+        self.message.unique_id = unique_id;
+        self
+    }
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.message.ttl = Some(ttl);
+        self
+    }
+    pub fn seq(mut self, seq: u64) -> Self {
+        self.message.seq = Some(seq);
+        self
+    }
+    pub fn budget_ms(mut self, budget_ms: u64) -> Self {
+        self.message.budget_ms = Some(budget_ms);
+        self
+    }
+    /// Source-route the message through `hops`, the last of which must be the
+    /// destination.
+    pub fn route_plan(mut self, hops: Vec<Address>) -> Self {
+        self.message.route_plan = Some(hops);
+        self
+    }
+    /// Ask for answers at `address` instead of the message's origin.
+    pub fn reply_to(mut self, address: Address) -> Self {
+        self.message.reply_to = Some(address);
+        self
+    }
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.message.headers.insert(name.into(), value.into());
+        self
+    }
+    /// Add a metadata tag `key=value`, replacing an earlier one named `key`.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.message.set_tag(key, value);
+        self
+    }
+    pub fn build(self) -> Message {
+        self.message
+    }
+}
+
+/// `==` compares every field, `ts` included; [`PathNode::same_node`] only asks
+/// whether two entries are the same node.
+#[derive(Clone, PartialEq, Eq)]
 pub struct PathNode {
     pub name: Option<String>,
     pub address: Option<Address>,
     pub ts: u64,
+    /// The node's [signature](NodeInstance::with_path_signer) of its place in the path.
+    pub sig: Option<Vec<u8>>,
+    /// Milliseconds left of the message's [budget](NodeInstance::forward_with_budget),
+    /// or else until its propagated [deadline](Deadline::propagated), when the
+    /// node took the message on. Not covered by the path signature.
+    pub remaining_budget_ms: Option<u64>,
 }
 
 impl Default for PathNode {
@@ -53,6 +506,8 @@ impl PathNode {
             name: None,
             address: None,
             ts: 0,
+            sig: None,
+            remaining_budget_ms: None,
         }
     }
     pub fn with_name(self, name: impl Into<String>) -> Self {
@@ -67,101 +522,1602 @@ impl PathNode {
             ..self
         }
     }
+    /// Unix time in milliseconds at which the node saw the message.
+    pub fn with_ts(self, ts: u64) -> Self {
+        Self { ts, ..self }
+    }
+    pub fn with_remaining_budget_ms(self, remaining_budget_ms: u64) -> Self {
+        Self {
+            remaining_budget_ms: Some(remaining_budget_ms),
+            ..self
+        }
+    }
+    /// Whether both entries have the same name and address, whenever and
+    /// however else the node filled them in. Anonymous entries are never the
+    /// same node, as nothing tells them apart.
+    pub fn same_node(&self, other: &PathNode) -> bool {
+        self.address.is_some() && self.address == other.address && self.name == other.name
+    }
+}
+
+/// What a transport can carry, for the node to adapt its sends to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutorCapabilities {
+    /// Largest [encoded](Message::encode) message the transport takes; bigger
+    /// ones are [split](Message::split) before sending.
+    pub max_message_size: usize,
+    /// Messages to one remote arrive in the order they were sent.
+    pub ordered: bool,
+    /// Sent messages are not silently lost.
+    pub reliable: bool,
+    /// `get_status` reports more than a guess.
+    pub supports_status: bool,
+}
+
+impl Default for ExecutorCapabilities {
+    /// Promises nothing but leaves the message size unlimited, so executors that
+    /// don't say otherwise never see their messages split.
+    fn default() -> Self {
+        Self {
+            max_message_size: usize::MAX,
+            ordered: false,
+            reliable: false,
+            supports_status: false,
+        }
+    }
+}
+
+impl ExecutorCapabilities {
+    /// What both `self` and `other` can do.
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            max_message_size: self.max_message_size.min(other.max_message_size),
+            ordered: self.ordered && other.ordered,
+            reliable: self.reliable && other.reliable,
+            supports_status: self.supports_status && other.supports_status,
+        }
+    }
 }
+
 pub trait ProtocolExecutor {
     type Error: std::error::Error + Send + 'static + Sized;
+    /// Send `message` to `remote`, telling how far it got: an executor that
+    /// only hands messages to the transport returns [`SendOutcome::sent`], one
+    /// that waits for acknowledgements [`SendOutcome::received`].
     fn send(
         &self,
         remote: &Identity,
         message: Message,
-    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static;
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static;
     fn get_status(
         &self,
         remote: &Identity,
         message: Message,
     ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static;
+    /// Send `message` to `remote` over `protocol`, the protocol the node picked
+    /// this executor for. Executors registered for several protocols override this
+    /// to tell them apart; the default ignores `protocol` and calls `send`.
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let _ = protocol;
+        self.send(remote, message)
+    }
+    /// Send several messages to one remote. The outer error means the whole batch
+    /// failed; otherwise there is one result per message, in order.
+    ///
+    /// The default sends them one after another; transports with a cheaper bulk
+    /// path should override it.
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> impl Future<Output = Result<Vec<Result<SendOutcome, Self::Error>>, Self::Error>> + Send + 'static
+    {
+        let sends: Vec<_> = messages
+            .into_iter()
+            .map(|message| self.send(remote, message))
+            .collect();
+        async move {
+            let mut results = Vec::with_capacity(sends.len());
+            for send in sends {
+                results.push(SendContext::scope_message(send).await);
+            }
+            Ok(results)
+        }
+    }
+    /// Send a message whose payload is still being produced. Transports that can
+    /// write a payload piecewise should override this; the default collects the
+    /// whole payload and calls `send`.
+    fn send_stream(
+        &self,
+        remote: &Identity,
+        message: StreamingMessage,
+    ) -> impl Future<Output = Result<(), StreamSendError<Self::Error>>> + Send + '_
+    where
+        Self: Sync,
+    {
+        let remote = remote.clone();
+        async move {
+            let message = message.collect().await.map_err(StreamSendError::Payload)?;
+            self.send(&remote, message)
+                .await
+                .map(|_| ())
+                .map_err(StreamSendError::Send)
+        }
+    }
+    fn capabilities(&self) -> ExecutorCapabilities {
+        ExecutorCapabilities::default()
+    }
+    /// Tear down what the executor holds, such as pooled connections, once the
+    /// node no longer sends with it; see [`NodeInstance::replace_executor`]. The
+    /// default does nothing.
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        async {}
+    }
 }
 
-type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
-type BoxError = Box<dyn Error + Send + 'static>;
-type BoxResult<T> = Result<T, BoxError>;
-pub trait DynProtocolExecutor {
-    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<BoxResult<()>>;
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+pub(crate) type BoxStream<T> = Pin<Box<dyn futures_core::Stream<Item = T> + Send + 'static>>;
+pub(crate) type BoxError = Box<dyn Error + Send + 'static>;
+pub(crate) type BoxResult<T> = Result<T, BoxError>;
+
+fn box_error(error: impl Error + Send + 'static) -> BoxError {
+    Box::new(error)
+}
+pub trait DynProtocolExecutor: Send + Sync {
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<BoxResult<SendOutcome>>;
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<BoxResult<SendOutcome>>;
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<BoxResult<MessageStatus>>;
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> BoxFuture<BoxResult<Vec<BoxResult<SendOutcome>>>>;
+    fn send_stream<'a>(
+        &'a self,
+        remote: &Identity,
+        message: StreamingMessage,
+    ) -> Pin<Box<dyn Future<Output = BoxResult<()>> + Send + 'a>>;
+    fn capabilities(&self) -> ExecutorCapabilities;
+    /// See [`ProtocolExecutor::close`]; the default does nothing.
+    fn close(&self) -> BoxFuture<()> {
+        Box::pin(async {})
+    }
+    /// The concrete executor, for reaching transport-specific methods through
+    /// [`Any::downcast_ref`](std::any::Any::downcast_ref).
+    fn as_any(&self) -> &dyn std::any::Any;
 }
 
 impl<T> DynProtocolExecutor for T
 where
-    T: ProtocolExecutor,
+    T: ProtocolExecutor + Send + Sync + 'static,
 {
-    fn send(
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<BoxResult<SendOutcome>> {
+        let fut = panic::catch_unwind(|| ProtocolExecutor::send(self, remote, message));
+        Box::pin(async move { fut.await.map_err(box_error)?.map_err(box_error) })
+    }
+    fn send_via(
         &self,
+        protocol: &Protocol,
         remote: &Identity,
         message: Message,
-    ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + 'static>>> + Send + 'static>>
-    {
-        let fut = ProtocolExecutor::send(self, remote, message);
+    ) -> BoxFuture<BoxResult<SendOutcome>> {
+        let fut =
+            panic::catch_unwind(|| ProtocolExecutor::send_via(self, protocol, remote, message));
+        Box::pin(async move { fut.await.map_err(box_error)?.map_err(box_error) })
+    }
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<BoxResult<MessageStatus>> {
+        let fut = panic::catch_unwind(|| ProtocolExecutor::get_status(self, remote, message));
+        Box::pin(async move { fut.await.map_err(box_error)?.map_err(box_error) })
+    }
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> BoxFuture<BoxResult<Vec<BoxResult<SendOutcome>>>> {
+        let fut = panic::catch_unwind(|| ProtocolExecutor::send_batch(self, remote, messages));
         Box::pin(async move {
-            let result = fut.await;
-            match result {
-                Ok(()) => Ok(()),
-                Err(e) => Err(Box::new(e) as Box<dyn Error + Send + 'static>),
-            }
+            let results = fut.await.map_err(box_error)?.map_err(box_error)?;
+            Ok(results
+                .into_iter()
+                .map(|result| result.map_err(box_error))
+                .collect())
+        })
+    }
+    fn send_stream<'a>(
+        &'a self,
+        remote: &Identity,
+        message: StreamingMessage,
+    ) -> Pin<Box<dyn Future<Output = BoxResult<()>> + Send + 'a>> {
+        let fut = panic::catch_unwind(|| ProtocolExecutor::send_stream(self, remote, message));
+        Box::pin(async move { fut.await.map_err(box_error)?.map_err(box_error) })
+    }
+    fn capabilities(&self) -> ExecutorCapabilities {
+        ProtocolExecutor::capabilities(self)
+    }
+    fn close(&self) -> BoxFuture<()> {
+        let close = panic::catch_unwind(|| ProtocolExecutor::close(self));
+        // a teardown that panics must not take the drain down with it
+        Box::pin(async move {
+            let _ = close.await;
         })
     }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Application side of a node: receives every message addressed to it.
+pub trait ReceiveHandler: Send + Sync {
+    fn handle(&self, message: Message) -> BoxFuture<()>;
+}
+
+impl<F, Fut> ReceiveHandler for F
+where
+    F: Fn(Message) -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn handle(&self, message: Message) -> BoxFuture<()> {
+        Box::pin(self(message))
+    }
+}
+
+/// A fresh random value, e.g. for `unique_id`s.
+pub(crate) fn random_u64() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
 }
 
+/// A node: its addresses, routes, executors and everything it applies to the
+/// messages it sends, relays and receives.
+///
+/// A node is `Send + Sync`, so one `Arc<NodeInstance>` can serve every task and
+/// thread. Its methods take `&self` from the moment it runs and only ever hold
+/// locks for short, non-async sections; everything it stores, executors,
+/// backends, handlers, clocks and hooks alike, must be `Send + Sync` too, which
+/// the bounds of the methods registering them require.
 pub struct NodeInstance {
     anon: bool,
     name: Option<String>,
     address_set: HashSet<Address>,
-    next_cache: HashMap<Address, Address>,
-    protocol_executor: HashMap<Protocol, Arc<dyn DynProtocolExecutor>>,
+    subscriptions: RwLock<Vec<AddressPattern>>,
+    next_cache: Arc<RwLock<cache::RouteCache>>,
+    /// The backend's change feed, if it has one, until the first lookup starts
+    /// watching it.
+    backend_watch: std::sync::Mutex<Option<Option<BoxStream<RouteChange>>>>,
+    interner: Option<Arc<AddressInterner>>,
+    canonicalizers: Canonicalizers,
+    identity_validators: IdentityValidators,
+    ids: ids::Ids,
+    costs: cost::CostTable,
+    protocol_executor: HashMap<Protocol, Arc<replace::TrackedExecutor>>,
+    /// The only executor, while exactly one is registered and none lazily, so
+    /// sends skip the map.
+    single_executor: Option<(Protocol, Arc<replace::TrackedExecutor>)>,
+    named_executors: named::NamedExecutors,
+    replacements: replace::Replacements,
+    protocol_hierarchy: hierarchy::ProtocolHierarchy,
+    lazy_executors: HashMap<Protocol, lazy::LazyExecutor>,
+    lazy_cooldown: Duration,
+    backend: Option<Arc<dyn DataBackend>>,
+    handler: Option<Arc<dyn ReceiveHandler>>,
+    handler_runner: handler::HandlerRunner,
+    onion_opener: Option<Arc<dyn OnionOpener>>,
+    path_signer: Option<Arc<dyn Signer>>,
+    streams: stream::StreamState,
+    fragments: fragment::Reassembly,
+    rewrites: rewrite::RewriteState,
+    groups: group::GroupRegistry,
+    control: control::ControlState,
+    replies: sender::ReplyTable,
+    shut_down: AtomicBool,
+    clock_skew_tolerance: Duration,
+    max_path_len: Option<usize>,
+    path_canonicalization: Option<PathCanonicalization>,
+    tag_routes: Vec<(String, String, Address)>,
+    send_timeout: Option<Duration>,
+    send_timeout_per_protocol: HashMap<Protocol, Duration>,
+    on_send_result: Option<Arc<SendResultHook>>,
+    on_send_err: Option<Arc<SendErrHook>>,
+    rate_limiter: Option<RateLimiter>,
+    quotas: Option<QuotaManager>,
+    metrics: metrics::Metrics,
+    clock: Arc<dyn Clock>,
+    reorder: reorder::ReorderState,
+    audit: Arc<audit::AuditLog>,
+    transforms: Vec<(TransformScope, Box<dyn ForwardTransform>)>,
+    peer_scores: Option<score::PeerScores>,
+    content_dedup: Option<dedup::ContentDedup>,
+    plan_fallback: bool,
+    address_book: Option<AddressBook>,
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+    panics: panic::PanicTracker,
+    withdraws: withdraw::WithdrawState,
+    events: events::EventBus,
+    rotations: rotation::RotationState,
+    inbound_capture: Option<Arc<capture::CaptureWriter>>,
+    shedding: shed::LoadShedder,
+    throttles: throttle::Throttles,
+    tracked_sends: introspect::SendTracker,
+    return_blocks: envelope::ConsumedBlocks,
+    retry_budget: Option<Arc<RetryBudget>>,
+    schemas: schema::SchemaRegistry,
+    #[cfg(feature = "journal")]
+    journal: Option<journal::Journal>,
+    #[cfg(feature = "journal")]
+    outbox_dead_letter: Option<Arc<journal::DeadLetterHook>>,
 }
 
-pub trait DataBackend {
+// fails to compile if a field ever makes nodes unshareable across threads
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<NodeInstance>();
+};
+
+/// Debug output for a trait object, which has none of its own.
+struct Placeholder(&'static str);
+
+impl std::fmt::Debug for Placeholder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Shows how the node is set up, with executors, the backend and handlers as
+/// placeholders.
+impl std::fmt::Debug for NodeInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let executors: BTreeMap<String, Placeholder> = self
+            .lazy_executors
+            .keys()
+            .map(|protocol| (protocol.to_string(), Placeholder("<lazy executor>")))
+            .chain(
+                self.protocol_executor
+                    .keys()
+                    .map(|protocol| (protocol.to_string(), Placeholder("<executor>"))),
+            )
+            .collect();
+        let placeholder = |set: bool, name| set.then_some(Placeholder(name));
+        f.debug_struct("NodeInstance")
+            .field("name", &self.name)
+            .field("anon", &self.anon)
+            .field("addresses", &self.address_set.len())
+            .field("protocols", &executors.len())
+            .field("executors", &executors)
+            .field("route_cache", &self.next_cache.read().unwrap().len())
+            .field("backend", &placeholder(self.backend.is_some(), "<backend>"))
+            .field("handler", &placeholder(self.handler.is_some(), "<handler>"))
+            .field("shut_down", &self.is_shut_down())
+            .finish_non_exhaustive()
+    }
+}
+
+type SendResultHook = dyn Fn(&Address, u64, &Result<SendReceipt, SendError>) + Send + Sync;
+type SendErrHook = dyn Fn(&Address, &SendError, &str) + Send + Sync;
+
+const DEFAULT_CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(1);
+
+pub trait DataBackend: Send + Sync {
     fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>>;
+    /// Store `next` as the route to `addr`, or remove the route if `None`,
+    /// returning the route it replaced. With the `test-util` feature,
+    /// [`conformance::verify_backend`] checks an implementation keeps to this.
     fn set_next(
         &self,
         addr: &Address,
         next: Option<&Address>,
     ) -> BoxFuture<BoxResult<Option<Address>>>;
+    /// Store or remove several routes at once, in order. Backends that can
+    /// write in bulk override this; by default each route is
+    /// [set](DataBackend::set_next) in turn, stopping at the first error.
+    fn set_next_batch(&self, routes: Vec<(Address, Option<Address>)>) -> BoxFuture<BoxResult<()>> {
+        let writes: Vec<_> = routes
+            .iter()
+            .map(|(addr, next)| self.set_next(addr, next.as_ref()))
+            .collect();
+        Box::pin(async move {
+            for write in writes {
+                write.await?;
+            }
+            Ok(())
+        })
+    }
+    /// Changes to the stored routes, including those made by other nodes sharing
+    /// the backend. Nodes drop cached routes as they change. Backends that cannot
+    /// tell return `None`.
+    fn watch(&self) -> Option<BoxStream<RouteChange>> {
+        None
+    }
+    /// Every stored route, to [warm](NodeInstance::warm_cache) a route cache
+    /// from. Backends that cannot list their routes return `None`.
+    fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+        None
+    }
+    /// Note that the route to `addr` was used at `at_ms`, Unix milliseconds, as
+    /// [`StoredRoute::last_used_ms`]. Nodes report this lazily, at most once per
+    /// [`ROUTE_TOUCH_INTERVAL`] and route. Backends that keep no recency ignore it.
+    fn touch(&self, addr: &Address, at_ms: u64) -> BoxFuture<BoxResult<()>> {
+        let _ = (addr, at_ms);
+        Box::pin(std::future::ready(Ok(())))
+    }
+    /// The [alias](NodeInstance::with_identity_rotation) stored for `old`.
+    /// Backends that cannot store aliases always return `None`.
+    fn get_alias(&self, old: &Identity) -> BoxFuture<BoxResult<Option<IdentityAlias>>> {
+        let _ = old;
+        Box::pin(std::future::ready(Ok(None)))
+    }
+    /// Store or, with `None`, remove the alias for `old`. Backends that cannot
+    /// store aliases ignore this.
+    fn set_alias(&self, old: &Identity, alias: Option<&IdentityAlias>) -> BoxFuture<BoxResult<()>> {
+        let _ = (old, alias);
+        Box::pin(std::future::ready(Ok(())))
+    }
+    /// Hand the backend the events of the node it serves, for backends that
+    /// report on their own work. Called once, on the node's first lookup.
+    fn attach_events(&self, events: BackendEvents) {
+        let _ = events;
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageStatus {
     Sended,
     Received,
     Unreachable,
     SendError,
+    /// The receiving node dropped the message on purpose.
+    Rejected {
+        reason: RejectReason,
+    },
+    /// The message's latency budget ran out on the way.
+    Expired,
+}
+
+/// Why a node dropped a message it received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The message was already seen.
+    Duplicate,
+    /// The message has no hops left.
+    TtlExceeded,
+    InvalidSignature,
+    /// Relaying would grow the path beyond the node's limit.
+    PathTooLong,
+    /// The message is for this node but nothing is there to take it.
+    NoHandler,
+    /// The origin used up its traffic quota.
+    QuotaExceeded,
+    /// The origin is [quarantined](NodeInstance::with_peer_scoring).
+    Quarantined,
+    /// The payload was already seen for the same destination, under another
+    /// `unique_id`; see [`NodeInstance::with_content_dedup`].
+    DuplicateContent,
+    /// The payload does not match the [schema](SchemaRegistry) of its content
+    /// type.
+    SchemaViolation,
+    /// A reason this build does not know, with its wire code, from a receipt
+    /// sent by a newer peer.
+    Unknown(u8),
+}
+
+/// An executor error together with where and when it happened.
+#[derive(Debug)]
+pub struct SendFailure {
+    pub protocol: Protocol,
+    /// The remote identity, as [displayed](Identity#impl-Display-for-Identity);
+    /// empty if no remote was involved, e.g. while [warming up](NodeInstance::warmup).
+    pub identity: String,
+    /// How many attempts had been made, counting the failed one.
+    pub attempt: u32,
+    pub at: SystemTime,
+    pub source: BoxError,
+    /// Retries were skipped because the destination's
+    /// [retry budget](RetryBudget) ran out.
+    pub budget_exhausted: bool,
+}
+
+impl SendFailure {
+    pub fn new(to: &Address, attempt: u32, source: BoxError) -> Self {
+        Self {
+            protocol: to.protocol.clone(),
+            identity: to.identity.to_string(),
+            attempt,
+            at: SystemTime::now(),
+            source,
+            budget_exhausted: false,
+        }
+    }
+}
+
+impl std::fmt::Display for SendFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = self
+            .at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "send to {}:{} failed on attempt {} at {}.{:03}: {}",
+            self.protocol,
+            self.identity,
+            self.attempt,
+            at.as_secs(),
+            at.subsec_millis(),
+            self.source
+        )?;
+        if self.budget_exhausted {
+            write!(f, " (retry budget exhausted)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SendFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
 }
 
+#[derive(Debug)]
 pub enum SendError {
-    ExecutorError(BoxError),
-    ProtocolNotSupport { supported: Vec<Protocol> },
+    ExecutorError(SendFailure),
+    ProtocolNotSupport {
+        supported: Vec<Protocol>,
+    },
+    /// The send or the message's propagated deadline ran out.
+    DeadlineExceeded,
+    /// This node already appears in the message's path, or another node appears
+    /// in it twice with other hops in between.
+    Loop,
+    /// The message has no hops left.
+    TtlExceeded,
+    /// The path would grow beyond the node's limit.
+    PathTooLong {
+        max: usize,
+    },
+    /// No route is known and the destination cannot be reached directly.
+    NoRoute,
+    /// An onion layer could not be built or peeled.
+    Onion(BoxError),
+    /// A streaming transfer failed.
+    Stream(StreamError),
+    /// Some members of a multicast group could not be reached.
+    Group(GroupReport),
+    /// The protocol's rate limit is used up and the limiter does not wait.
+    RateLimited,
+    /// The next hop's identity used up its traffic quota.
+    QuotaExceeded,
+    /// The node was [shut down](NodeInstance::shutdown).
+    Shutdown,
+    /// A [forward transform](NodeInstance::with_forward_transform) failed.
+    Transform(TransformError),
+    /// The message's [latency budget](NodeInstance::forward_with_budget) is used up.
+    BudgetExhausted,
+    /// A [fragment](Message::split) header could not be parsed or contradicts the
+    /// fragments seen before, or the fragments add up to more than
+    /// [`MAX_REASSEMBLED_LEN`].
+    MalformedFragment,
+    /// The [route plan](Message::route_plan) does not end at the destination.
+    InvalidRoutePlan,
+    /// The next hop of the route plan could not be reached.
+    PlannedHopUnreachable {
+        hop: Address,
+        error: Box<SendError>,
+    },
+    /// The [address book](NodeInstance::with_address_book) has no peer by this name.
+    UnknownName(String),
+    /// The executor panicked while sending.
+    ExecutorPanicked {
+        message: String,
+    },
+    /// The protocol's executor was taken out of service after
+    /// [panicking too often](NodeInstance::with_executor_panic_limit).
+    ProtocolUnavailable(Protocol),
+    /// Sends to the next hop queue up faster than they complete; see
+    /// [`NodeInstance::with_load_shedding`].
+    Overloaded {
+        destination: Address,
+        /// How long the oldest queued send has been waiting.
+        queue_age: Duration,
+    },
+    /// The send stayed queued beyond the
+    /// [hard age limit](LoadShedConfig::hard_age_limit).
+    ShedByAge {
+        destination: Address,
+        queue_age: Duration,
+    },
+    /// The protocol's executor cannot report delivery status; see
+    /// [`ExecutorCapabilities::supports_status`].
+    StatusUnsupported(Protocol),
+    /// No executor is [registered](NodeInstance::register_named_executor) for
+    /// the protocol under the name the send asked for.
+    ExecutorNotFound {
+        protocol: Protocol,
+        name: String,
+    },
+    /// The payload does not match the [schema](SchemaRegistry) of its content
+    /// type.
+    SchemaViolation(SchemaError),
+}
+
+impl SendError {
+    /// Whether trying the same send again later may succeed.
+    ///
+    /// Executor errors count as transient when their source chain holds an
+    /// [`io::Error`](std::io::Error) of a kind that usually clears up, such as a
+    /// refused or reset connection or a timeout, or when the executor is
+    /// [cooling down](ExecutorCoolingDown).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SendError::ExecutorError(failure) => is_transient_error(&*failure.source),
+            SendError::DeadlineExceeded
+            | SendError::RateLimited
+            | SendError::QuotaExceeded
+            | SendError::Overloaded { .. }
+            | SendError::ShedByAge { .. } => true,
+            SendError::Stream(StreamError::Stalled) => true,
+            SendError::Stream(StreamError::Io(error)) => is_transient_error(error),
+            SendError::PlannedHopUnreachable { error, .. } => error.is_transient(),
+            SendError::ProtocolNotSupport { .. }
+            | SendError::Loop
+            | SendError::TtlExceeded
+            | SendError::PathTooLong { .. }
+            | SendError::NoRoute
+            | SendError::Onion(_)
+            | SendError::Stream(_)
+            | SendError::Group(_)
+            | SendError::Shutdown
+            | SendError::Transform(_)
+            | SendError::BudgetExhausted
+            | SendError::MalformedFragment
+            | SendError::InvalidRoutePlan
+            | SendError::UnknownName(_)
+            | SendError::ExecutorPanicked { .. }
+            | SendError::ProtocolUnavailable(_)
+            | SendError::StatusUnsupported(_)
+            | SendError::ExecutorNotFound { .. }
+            | SendError::SchemaViolation(_) => false,
+        }
+    }
+}
+
+fn is_transient_error(error: &(dyn Error + 'static)) -> bool {
+    use std::io::ErrorKind;
+    let mut error = Some(error);
+    while let Some(current) = error {
+        if current.is::<ExecutorCoolingDown>() {
+            return true;
+        }
+        if let Some(io) = current.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::AddrNotAvailable
+            );
+        }
+        error = current.source();
+    }
+    false
+}
+
+impl Default for NodeInstance {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NodeInstance {
+    pub fn new() -> Self {
+        Self {
+            anon: false,
+            name: None,
+            address_set: HashSet::new(),
+            subscriptions: RwLock::default(),
+            next_cache: Default::default(),
+            backend_watch: Default::default(),
+            interner: None,
+            canonicalizers: Canonicalizers::default(),
+            identity_validators: IdentityValidators::default(),
+            ids: Default::default(),
+            costs: Default::default(),
+            protocol_executor: HashMap::new(),
+            single_executor: None,
+            named_executors: Default::default(),
+            replacements: Default::default(),
+            protocol_hierarchy: Default::default(),
+            lazy_executors: HashMap::new(),
+            lazy_cooldown: lazy::DEFAULT_LAZY_COOLDOWN,
+            backend: None,
+            handler: None,
+            handler_runner: Default::default(),
+            onion_opener: None,
+            path_signer: None,
+            streams: stream::StreamState::new(),
+            fragments: Default::default(),
+            rewrites: Default::default(),
+            groups: Default::default(),
+            control: Default::default(),
+            replies: Default::default(),
+            shut_down: AtomicBool::new(false),
+            clock_skew_tolerance: DEFAULT_CLOCK_SKEW_TOLERANCE,
+            max_path_len: None,
+            path_canonicalization: None,
+            tag_routes: Vec::new(),
+            send_timeout: None,
+            send_timeout_per_protocol: HashMap::new(),
+            on_send_result: None,
+            on_send_err: None,
+            rate_limiter: None,
+            quotas: None,
+            metrics: Default::default(),
+            clock: Arc::new(SystemClock),
+            reorder: Default::default(),
+            audit: Default::default(),
+            transforms: Vec::new(),
+            peer_scores: None,
+            content_dedup: None,
+            plan_fallback: false,
+            address_book: None,
+            in_flight: None,
+            panics: Default::default(),
+            withdraws: Default::default(),
+            events: Default::default(),
+            rotations: Default::default(),
+            inbound_capture: None,
+            shedding: Default::default(),
+            throttles: Default::default(),
+            tracked_sends: Default::default(),
+            return_blocks: Default::default(),
+            retry_budget: None,
+            schemas: Default::default(),
+            #[cfg(feature = "journal")]
+            journal: None,
+            #[cfg(feature = "journal")]
+            outbox_dead_letter: None,
+        }
+    }
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self
+        }
+    }
+    pub fn with_anon(self, anon: bool) -> Self {
+        Self { anon, ..self }
+    }
+    pub fn with_address(mut self, address: impl Into<Address>) -> Self {
+        self.address_set.insert(address.into());
+        self
+    }
+    /// Whether `address` is one of this node's own, i.e. messages to it are
+    /// delivered here rather than relayed.
+    pub fn is_local(&self, address: &Address) -> bool {
+        self.address_set.contains(address)
+    }
+    pub fn local_addresses(&self) -> impl Iterator<Item = &Address> {
+        self.address_set.iter()
+    }
+    /// How far past a propagated deadline a message is still accepted, to absorb
+    /// clock differences between nodes.
+    pub fn with_clock_skew_tolerance(self, clock_skew_tolerance: Duration) -> Self {
+        Self {
+            clock_skew_tolerance,
+            ..self
+        }
+    }
+    /// Refuse to relay messages whose path would grow beyond `max_path_len` nodes.
+    pub fn with_max_path_len(self, max_path_len: usize) -> Self {
+        Self {
+            max_path_len: Some(max_path_len),
+            ..self
+        }
+    }
+    /// Look up routes in `backend`, dropping cached routes whenever it
+    /// [reports](DataBackend::watch) a change.
+    pub fn with_backend(self, backend: impl DataBackend + 'static) -> Self {
+        Self {
+            backend_watch: std::sync::Mutex::new(Some(backend.watch())),
+            backend: Some(Arc::new(backend)),
+            ..self
+        }
+    }
+    /// Deliver messages addressed to this node to `handler`.
+    pub fn with_handler(self, handler: impl ReceiveHandler + 'static) -> Self {
+        Self {
+            handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+    /// Give up on sends the executor has not finished after `timeout`, failing
+    /// them with [`SendError::DeadlineExceeded`]. The timeout covers the whole
+    /// send, every fragment of a [split](Message::split) message included.
+    pub fn with_send_timeout(self, timeout: Duration) -> Self {
+        Self {
+            send_timeout: Some(timeout),
+            ..self
+        }
+    }
+    /// Override the [send timeout](NodeInstance::with_send_timeout) for `protocol`.
+    pub fn with_send_timeout_for(mut self, protocol: Protocol, timeout: Duration) -> Self {
+        self.send_timeout_per_protocol.insert(protocol, timeout);
+        self
+    }
+    /// The send timeout applying to `protocol`, if any.
+    pub fn send_timeout_for(&self, protocol: &Protocol) -> Option<Duration> {
+        self.protocol_hierarchy
+            .find(&self.send_timeout_per_protocol, protocol)
+            .map(|(_, timeout)| *timeout)
+            .or(self.send_timeout)
+    }
+    /// Forward messages tagged `key=value` to `next`, ahead of any other route.
+    /// Tag routes are tried in the order they were added.
+    pub fn with_tag_route(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        next: Address,
+    ) -> Self {
+        self.tag_routes.push((key.into(), value.into(), next));
+        self
+    }
+    /// Limit sends per protocol with `rate_limiter`, which then runs on this
+    /// node's [`Clock`]; see [`RateLimiter`].
+    pub fn with_rate_limiter(mut self, mut rate_limiter: RateLimiter) -> Self {
+        rate_limiter.set_clock(self.clock.clone());
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+    /// Take all time from `clock`, including that of an already configured rate
+    /// limiter or quota manager.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        if let Some(limiter) = &mut self.rate_limiter {
+            limiter.set_clock(clock.clone());
+        }
+        if let Some(quotas) = &mut self.quotas {
+            quotas.set_clock(clock.clone());
+        }
+        self.clock = clock;
+        self
+    }
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+    /// Call `hook` with the next hop, the `unique_id` and the outcome of every send,
+    /// successful or not.
+    pub fn with_on_send_result(
+        self,
+        hook: impl Fn(&Address, u64, &Result<SendReceipt, SendError>) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_send_result: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+    /// Call `hook` with the next hop, the error and the message's
+    /// [redacted view](Message::redacted), which never shows the payload but
+    /// ends in the [path summary](Message::path_summary), whenever a send or
+    /// relay fails.
+    pub fn with_on_send_err(
+        self,
+        hook: impl Fn(&Address, &SendError, &str) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_send_err: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+    /// Let this node act as an onion relay, peeling layers sealed to it with `opener`.
+    pub fn with_onion_opener(self, opener: impl OnionOpener + 'static) -> Self {
+        Self {
+            onion_opener: Some(Arc::new(opener)),
+            ..self
+        }
+    }
+    /// Fail incoming streams that receive nothing for `idle_timeout`.
+    pub fn with_stream_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.streams.idle_timeout = idle_timeout;
+        self
+    }
+    pub fn with_executor(
+        mut self,
+        protocol: Protocol,
+        executor: impl DynProtocolExecutor + 'static,
+    ) -> Self {
+        self.register_executor(protocol, Arc::new(executor));
+        self
+    }
+    /// Register `executor` for `protocol`, returning the executor it replaced.
+    /// Known addresses are not [validated](NodeInstance::try_register_executor).
+    pub fn register_executor(
+        &mut self,
+        protocol: Protocol,
+        executor: Arc<dyn DynProtocolExecutor>,
+    ) -> Option<Arc<dyn DynProtocolExecutor>> {
+        self.lazy_executors.remove(&protocol);
+        self.replacements.remove(&protocol);
+        self.panics.reset(&protocol, None);
+        let replaced = self.protocol_executor.insert(
+            protocol.clone(),
+            Arc::new(replace::TrackedExecutor::new(executor)),
+        );
+        self.refresh_single_executor();
+        self.publish(|| NodeEvent::ExecutorRegistered {
+            protocol,
+            replaced: replaced.is_some(),
+        });
+        replaced.map(|executor| executor as Arc<dyn DynProtocolExecutor>)
+    }
+    /// Stop sending over `protocol`, returning the executor that did.
+    pub fn deregister_executor(
+        &mut self,
+        protocol: &Protocol,
+    ) -> Option<Arc<dyn DynProtocolExecutor>> {
+        self.lazy_executors.remove(protocol);
+        self.replacements.remove(protocol);
+        self.panics.reset(protocol, None);
+        let removed = self.protocol_executor.remove(protocol);
+        self.refresh_single_executor();
+        if removed.is_some() {
+            self.publish(|| NodeEvent::ExecutorDeregistered {
+                protocol: protocol.clone(),
+            });
+        }
+        removed.map(|executor| executor as Arc<dyn DynProtocolExecutor>)
+    }
+    /// Append this node to the message's path. Anonymous nodes add an empty entry;
+    /// others their address, name and the time from [their clock](NodeInstance::with_clock),
+    /// never earlier than the previous hop's so a clock step cannot make a hop
+    /// appear to take negative time. Nodes without a name of their own use the
+    /// [address book](NodeInstance::with_address_book)'s name for `accept_at`.
+    /// Messages with a budget or propagated deadline also get what is
+    /// [left of it](PathNode::remaining_budget_ms). The path is
+    /// [canonicalized](NodeInstance::with_path_canonicalization) first.
     pub fn mark(&self, accept_at: Address, message: &mut Message) {
+        if let Some(policy) = self.path_canonicalization {
+            message.canonicalize_path(policy);
+        }
         let this_node = if self.anon {
             PathNode::new()
         } else {
-            let mut pn = PathNode::new().with_address(accept_at);
-            if let Some(name) = &self.name {
+            let previous = message.path.last().map_or(0, |node| node.ts);
+            let mut pn = PathNode::new()
+                .with_address(accept_at)
+                .with_ts(self.clock.now_millis().max(previous));
+            if let Some(name) = self
+                .name
+                .as_deref()
+                .or_else(|| self.name_of(pn.address.as_ref()?))
+            {
                 pn = pn.with_name(name)
             }
+            if let Some(remaining) = self.remaining_budget(message) {
+                pn = pn.with_remaining_budget_ms(remaining)
+            }
+            self.sign_path_node(message, &mut pn);
             pn
         };
         message.path.push(this_node)
     }
-    pub async fn send(&self, message: Message, to: Address) -> Result<(), SendError> {
-        if let Some(executor) = self.protocol_executor.get(&to.protocol) {
-            executor
-                .send(&to.identity, message)
-                .await
-                .map_err(SendError::ExecutorError)
+    /// A snapshot of the route cache as `(destination, next hop)` pairs.
+    pub fn iter_routes(&self) -> Vec<(Address, Address)> {
+        self.next_cache
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(destination, next)| (destination.to_address(), next.to_address()))
+            .collect()
+    }
+    /// Find the next hop towards `destination`: the cheapest route candidate under
+    /// [`RouteSelection::LowestCost`], then the route cache, then the backend, and
+    /// finally the destination itself if one of our executors speaks its protocol.
+    /// Backend errors are treated as a miss. The destination is
+    /// [canonicalized](NodeInstance::with_canonicalizer) first.
+    pub async fn resolve_next(&self, destination: &Address) -> Result<Address, SendError> {
+        let canonical = self.canonical(destination);
+        let mut destination = self.aliased(&canonical);
+        if let Some(next) = self
+            .costs
+            .select(&destination, |next| !self.is_quarantined(&next.identity))
+        {
+            return Ok(next);
+        }
+        self.start_backend_watch();
+        let key = self.cache_key(&destination);
+        let cached = key.as_ref().and_then(|key| {
+            let cache = self.next_cache.read().unwrap();
+            let next = cache.get(key)?;
+            Some((next, cache.touch_due(key, self.clock.now_millis())))
+        });
+        if let Some((next, touch)) = cached {
+            self.metrics.record_route_cache(true);
+            if let Some(backend) = self.backend.as_ref().filter(|_| touch) {
+                // only a hint for warming, not worth waiting for, nor worth a
+                // runtime when the lookup runs without one
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn(backend.touch(&destination, self.clock.now_millis()));
+                }
+            }
+            return Ok(next.to_address());
+        }
+        self.metrics.record_route_cache(false);
+        if let Some(backend) = &self.backend {
+            let mut found = backend.get_next(&destination).await.ok().flatten();
+            if found.is_none() {
+                if let Some(identity) = self.load_alias(&destination.identity).await {
+                    let moved = Address {
+                        protocol: destination.protocol.clone(),
+                        identity,
+                    };
+                    found = backend.get_next(&moved).await.ok().flatten();
+                    destination = Cow::Owned(moved);
+                }
+            }
+            let destination = &*destination;
+            if let Some(next) = found {
+                let evicted = self
+                    .next_cache
+                    .write()
+                    .unwrap()
+                    .insert(self.intern(destination), self.intern(&next));
+                if let Some(evicted) = evicted {
+                    self.publish(|| NodeEvent::RouteEvicted {
+                        destination: evicted.to_address(),
+                    });
+                }
+                self.publish(|| NodeEvent::RouteCached {
+                    destination: destination.clone(),
+                    next: next.clone(),
+                });
+                return Ok(next);
+            }
+        }
+        if self.has_executor(&destination.protocol) {
+            Ok(destination.into_owned())
         } else {
-            Err(SendError::ProtocolNotSupport {
-                supported: self.protocol_executor.keys().cloned().collect(),
+            Err(SendError::NoRoute)
+        }
+    }
+    /// Send `message` one hop closer to `message.destination`, as resolved by
+    /// a matching [tag route](NodeInstance::with_tag_route) or else
+    /// [`NodeInstance::resolve_next`].
+    ///
+    /// A message with a [`route_plan`](Message::route_plan) goes to the next
+    /// planned hop instead; if that hop is unreachable the send fails with
+    /// [`SendError::PlannedHopUnreachable`] unless
+    /// [fallback](NodeInstance::with_route_plan_fallback) is allowed. A plan that
+    /// does not end at the destination fails with [`SendError::InvalidRoutePlan`].
+    ///
+    /// Path addresses are rewritten by the [outbound rules](NodeInstance::with_outbound_rewrites) first,
+    /// the message by the [transforms](NodeInstance::with_forward_transform) for the next hop last.
+    /// Group destinations are [multicast](NodeInstance::multicast).
+    pub async fn forward(&self, mut message: Message) -> Result<(), SendError> {
+        if message.destination.protocol == Protocol::GROUP {
+            let group = message.destination.clone();
+            return self.multicast(message, &group).await;
+        }
+        self.rewrite_outbound(&mut message);
+        if let Some(hop) = self.planned_next(&message)? {
+            return self.forward_planned(message, hop).await;
+        }
+        self.forward_dynamic(message).await
+    }
+    async fn forward_dynamic(&self, mut message: Message) -> Result<(), SendError> {
+        let next = match self.tagged_next(&message) {
+            Some(next) => next,
+            None => self.resolve_next(&message.destination).await?,
+        };
+        self.transform_for(&mut message, &next)
+            .await
+            .map_err(SendError::Transform)?;
+        let destination = message.destination.clone();
+        let start = tokio::time::Instant::now();
+        let result = self.send(message, next.clone()).await;
+        let latency = result.is_ok().then(|| start.elapsed());
+        self.costs.observe(&destination, &next, latency);
+        self.observe_delivery(&destination, result.is_ok()).await;
+        result
+    }
+    /// The next hop of the first [tag route](NodeInstance::with_tag_route)
+    /// `message` matches.
+    pub(crate) fn tagged_next(&self, message: &Message) -> Option<Address> {
+        self.tag_routes
+            .iter()
+            .find(|(key, value, _)| message.tag(key) == Some(value))
+            .map(|(_, _, next)| next.clone())
+    }
+    /// Forward a message this node accepted at `accept_at` on behalf of someone else.
+    ///
+    /// Marks the message, rejects loops, exhausted ttls and overlong paths, then
+    /// [forwards](NodeInstance::forward) it. Intermediary nodes should use this
+    /// rather than calling [`NodeInstance::mark`] and [`NodeInstance::send`] by hand.
+    pub async fn relay(&self, message: Message, accept_at: Address) -> Result<(), SendError> {
+        let destination = message.destination.clone();
+        let path = self.path_for_report(&message);
+        let result = self.relay_inner(message, accept_at).await;
+        // failures of the send itself were reported by `send` already
+        if let (Some(hook), Some(path), Err(error)) = (&self.on_send_err, path, &result) {
+            if matches!(
+                error,
+                SendError::Loop | SendError::TtlExceeded | SendError::PathTooLong { .. }
+            ) {
+                hook(&destination, error, &path)
+            }
+        }
+        result
+    }
+    async fn relay_inner(&self, mut message: Message, accept_at: Address) -> Result<(), SendError> {
+        if self.is_loop(&message) {
+            return Err(SendError::Loop);
+        }
+        match &mut message.ttl {
+            Some(0) => return Err(SendError::TtlExceeded),
+            Some(ttl) => *ttl -= 1,
+            None => {}
+        }
+        self.note_upstream(&message, &accept_at);
+        self.mark(accept_at, &mut message);
+        if let Some(max) = self.max_path_len {
+            if message.path.len() > max {
+                return Err(SendError::PathTooLong { max });
+            }
+        }
+        self.forward(message).await
+    }
+    /// Whether `message` went through this node before, or through another
+    /// node [twice](PathNode::same_node) with other hops in between.
+    pub(crate) fn is_loop(&self, message: &Message) -> bool {
+        let path = &message.path;
+        path.iter().enumerate().any(|(i, node)| {
+            node.address
+                .as_ref()
+                .is_some_and(|address| self.is_local(address))
+                || path[i + 1..]
+                    .iter()
+                    // a node recorded twice in a row is no loop
+                    .skip_while(|later| later.same_node(node))
+                    .any(|later| later.same_node(node))
+        })
+    }
+    /// Entry point for a message a transport received at `accept_at`.
+    ///
+    /// The destination is first rewritten by the
+    /// [inbound rules](NodeInstance::with_inbound_rewrites). Onion messages have
+    /// one layer peeled; [control messages](control) for this node go to their
+    /// [`ControlHandler`](control::ControlHandler), messages with a
+    /// [sealed destination](NodeInstance::send_sealed) are unsealed and relayed,
+    /// other messages for one of this node's addresses or
+    /// [subscriptions](NodeInstance::subscribe) go to the
+    /// [`ReceiveHandler`]; everything else is
+    /// [relayed](NodeInstance::relay).
+    /// Returns [`MessageStatus::Received`] for local delivery and
+    /// [`MessageStatus::Sended`] once the message was passed on.
+    pub async fn dispatch_inbound(
+        &self,
+        message: Message,
+        accept_at: Address,
+    ) -> Result<MessageStatus, SendError> {
+        self.capture_inbound(&message, &accept_at);
+        if let Some(rejected) = self.screen_origin(&message) {
+            let result = Ok(rejected);
+            self.metrics.record_inbound(&result);
+            return result;
+        }
+        #[cfg(feature = "journal")]
+        if let Some(duplicate) = self.journal_accept(&message) {
+            let result = Ok(duplicate);
+            self.metrics.record_inbound(&result);
+            return result;
+        }
+        #[cfg(feature = "journal")]
+        let unique_id = message.unique_id;
+        let origin = self.origin_identity(&message);
+        let result = self
+            .holding(self.dispatch_inbound_inner(message, accept_at))
+            .await;
+        #[cfg(feature = "journal")]
+        self.journal_status(unique_id, &result);
+        self.metrics.record_inbound(&result);
+        self.score_inbound(origin.as_ref(), &result);
+        result
+    }
+    async fn dispatch_inbound_inner(
+        &self,
+        mut message: Message,
+        accept_at: Address,
+    ) -> Result<MessageStatus, SendError> {
+        self.rewrite_inbound(&mut message);
+        let origin = message.path.first().and_then(|node| node.address.as_ref());
+        if let Some(rejected) =
+            self.quotas.as_ref().zip(origin).and_then(|(q, origin)| {
+                q.admit_received(&self.principal(&origin.identity), &message)
             })
+        {
+            let mut event = self
+                .audit_event(AuditKind::QuotaRejected, "received")
+                .unique_id(message.unique_id);
+            if let Some(origin) = message.path.first().and_then(|node| node.address.as_ref()) {
+                event = event.identity(&origin.identity).address(origin);
+            }
+            self.audit(event);
+            if let Some(source) = self.throttle_target(&message) {
+                self.throttle_source(source).await;
+            }
+            return Ok(rejected);
+        }
+        // whatever the message turns out to be, it needs all of its payload
+        if message.headers.contains_key(FRAGMENT_HEADER) {
+            match self.reassemble(message)? {
+                Some(whole) => message = whole,
+                None => return Ok(MessageStatus::Received),
+            }
+        }
+        if let Some(rejected) = self.screen_content(&mut message) {
+            return Ok(rejected);
+        }
+        if message.headers.contains_key(ONION_HEADER) {
+            return self.peel_onion(message).await;
+        }
+        if self.is_local(&message.destination) || self.is_subscribed(&message.destination) {
+            if message.headers.contains_key(RETURN_BLOCK_HEADER) {
+                return self.open_return_block(message, accept_at).await;
+            }
+            if message.headers.contains_key(SEALED_DESTINATION_HEADER) {
+                return self.unseal_destination(message, accept_at).await;
+            }
+            if message.headers.contains_key(control::CONTROL_HEADER) {
+                return Ok(self.handle_control(message).await);
+            }
+            if message.headers.contains_key(GROUP_CONTROL_HEADER) {
+                return Ok(self.handle_group_control(message));
+            }
+            if message.headers.contains_key(STREAM_HEADER) {
+                return self.handle_stream_chunk(message);
+            }
+            if let Some(rejected) = self.screen_inbound_schema(&message) {
+                return Ok(rejected);
+            }
+            return Ok(self.deliver(message).await);
         }
+        let source = self.throttle_target(&message);
+        let result = self.relay(message, accept_at).await;
+        let shed = matches!(
+            result,
+            Err(SendError::Overloaded { .. } | SendError::ShedByAge { .. })
+        );
+        if let Some(source) = source.filter(|_| shed) {
+            self.throttle_source(source).await;
+        }
+        result.map(|()| MessageStatus::Sended)
+    }
+    /// Like [`NodeInstance::dispatch_inbound`], but folds every outcome into a
+    /// [`MessageStatus`]: messages this node drops on purpose come back as
+    /// [`MessageStatus::Rejected`] with the reason, other failures as
+    /// [`MessageStatus::SendError`].
+    pub async fn receive(&self, message: Message, accept_at: Address) -> MessageStatus {
+        match self.dispatch_inbound(message, accept_at).await {
+            Ok(status) => status,
+            Err(SendError::TtlExceeded) => MessageStatus::Rejected {
+                reason: RejectReason::TtlExceeded,
+            },
+            Err(SendError::PathTooLong { .. }) => MessageStatus::Rejected {
+                reason: RejectReason::PathTooLong,
+            },
+            Err(SendError::BudgetExhausted) => MessageStatus::Expired,
+            Err(_) => MessageStatus::SendError,
+        }
+    }
+    pub(crate) async fn deliver(&self, message: Message) -> MessageStatus {
+        // replies someone is waiting for skip the handler
+        let Some(message) = self.replies.complete(message) else {
+            return MessageStatus::Received;
+        };
+        match &self.handler {
+            Some(handler) => {
+                self.throttle_if_saturated(&message).await;
+                self.deliver_ordered(handler, message).await;
+                MessageStatus::Received
+            }
+            None => MessageStatus::Rejected {
+                reason: RejectReason::NoHandler,
+            },
+        }
+    }
+    /// Send `message` to its final destination, `message.destination`.
+    ///
+    /// This is what an originating node normally wants; see [`NodeInstance::send`]
+    /// for handing a message to an explicit next hop instead.
+    pub async fn dispatch(&self, message: Message) -> Result<(), SendError> {
+        let to = message.destination.clone();
+        self.send(message, to).await
+    }
+    /// Hand `message` to the next hop `to`, which may differ from `message.destination`
+    /// when the message is routed through intermediaries.
+    ///
+    /// Messages carrying a [`DEADLINE_HEADER`] that has already passed are dropped
+    /// with [`SendError::DeadlineExceeded`].
+    ///
+    /// A [`Protocol::GROUP`] address for `to` [multicasts](NodeInstance::multicast) the message.
+    ///
+    /// `Ok` means the executor succeeded, which for many transports only means
+    /// the message was handed over; [`NodeInstance::send_detailed`] tells this
+    /// apart from an acknowledgement.
+    ///
+    /// `to` is anything that converts into an [`Address`], such as a
+    /// `(Protocol, Identity)` pair.
+    pub async fn send(&self, message: Message, to: impl Into<Address>) -> Result<(), SendError> {
+        let to = to.into();
+        if to.protocol == Protocol::GROUP {
+            return self.multicast(message, &to).await;
+        }
+        self.send_detailed(message, to).await.map(|_| ())
+    }
+    /// Like [`NodeInstance::send`], but reports which protocol carried the message,
+    /// how long it took, how many attempts were made and whether the remote
+    /// [acknowledged](SendReceipt::outcome) it.
+    pub async fn send_detailed(
+        &self,
+        message: Message,
+        to: impl Into<Address>,
+    ) -> Result<SendReceipt, SendError> {
+        let to = to.into();
+        let unique_id = message.unique_id;
+        let path = self.path_for_report(&message);
+        #[cfg(feature = "journal")]
+        self.journal_enqueue(&message, &to);
+        let result = self.send_once(message, &to).await;
+        #[cfg(feature = "journal")]
+        self.journal_dequeue(unique_id);
+        self.report_send(&to, unique_id, path, &result);
+        result
+    }
+    /// Ask the executor for `to.protocol` what became of `message`, sent to `to`.
+    /// Fails with [`SendError::StatusUnsupported`] without asking if the executor
+    /// does not [support status](ExecutorCapabilities::supports_status).
+    pub async fn status(&self, message: Message, to: &Address) -> Result<MessageStatus, SendError> {
+        let executor = self.executor_for_send(to, None).await?;
+        if !executor.capabilities().supports_status {
+            return Err(SendError::StatusUnsupported(to.protocol.clone()));
+        }
+        executor
+            .get_status(&to.identity, message)
+            .await
+            .map_err(|source| SendError::ExecutorError(SendFailure::new(to, 1, source)))
+    }
+    pub(crate) async fn send_once(
+        &self,
+        message: Message,
+        to: &Address,
+    ) -> Result<SendReceipt, SendError> {
+        let tracked = self.tracked_sends.track(message.unique_id, to);
+        self.shed_or_send(shed::is_sheddable(&message), to, async {
+            self.hold_for_throttles(&message, to).await;
+            let _permit = self.ready().await?;
+            tracked.sending();
+            self.send_permitted(message, to).await
+        })
+        .await
+    }
+    async fn send_permitted(
+        &self,
+        mut message: Message,
+        to: &Address,
+    ) -> Result<SendReceipt, SendError> {
+        let name = self.choose_executor(&mut message, to);
+        let executor = self.executor_for_send(to, name.as_deref()).await?;
+        let message = self.admit_send(message, to).await?;
+        let result = self.transmit(&executor, name.as_deref(), message, to).await;
+        if let Some(name) = &name {
+            self.record_named_send(&to.protocol, name, result.is_ok());
+        }
+        result
+    }
+    /// The executor to send to `to` with, the one registered under `name` or
+    /// else the default, if the node may send at all.
+    pub(crate) async fn executor_for_send(
+        &self,
+        to: &Address,
+        name: Option<&str>,
+    ) -> Result<replace::SelectedExecutor, SendError> {
+        if self.is_shut_down() {
+            return Err(SendError::Shutdown);
+        }
+        if self
+            .panics
+            .is_unavailable(self.registered_as(&to.protocol, name), name)
+        {
+            return Err(SendError::ProtocolUnavailable(to.protocol.clone()));
+        }
+        if let Some(name) = name {
+            return self.named_executor(to, name).map(Into::into);
+        }
+        self.executor(&to.protocol)
+            .await
+            .map(|executor| executor.select())
+            .map_err(|error| match error {
+                SendError::ExecutorError(mut failure) => {
+                    failure.identity = to.identity.to_string();
+                    SendError::ExecutorError(failure)
+                }
+                error => error,
+            })
+    }
+    /// Check `message` against its deadline, its schema, the rate limit, its
+    /// budget and the quotas before it is sent to `to`.
+    pub(crate) async fn admit_send(
+        &self,
+        mut message: Message,
+        to: &Address,
+    ) -> Result<Message, SendError> {
+        if Deadline::header_expired(&message, self.clock.now_millis(), self.clock_skew_tolerance) {
+            return Err(SendError::DeadlineExceeded);
+        }
+        self.screen_outbound_schema(&message)?;
+        if let Some(limiter) = &self.rate_limiter {
+            match limiter.mode() {
+                RateLimitMode::Wait => limiter.acquire(&to.protocol).await,
+                RateLimitMode::Reject => limiter
+                    .try_acquire(&to.protocol)
+                    .map_err(|_| SendError::RateLimited)?,
+            }
+        }
+        self.charge_budget(&mut message)?;
+        if let Some(quotas) = &self.quotas {
+            if !quotas.admit_sent(&self.principal(&to.identity), &message) {
+                self.audit(
+                    self.audit_event(AuditKind::QuotaRejected, "sent")
+                        .identity(&to.identity)
+                        .address(to)
+                        .unique_id(message.unique_id),
+                );
+                return Err(SendError::QuotaExceeded);
+            }
+        }
+        Ok(message)
+    }
+    pub(crate) async fn transmit(
+        &self,
+        executor: &Arc<dyn DynProtocolExecutor>,
+        name: Option<&str>,
+        message: Message,
+        to: &Address,
+    ) -> Result<SendReceipt, SendError> {
+        let unique_id = message.unique_id;
+        let max_size = executor.capabilities().max_message_size;
+        // fragments are never split again
+        let pieces = if max_size == usize::MAX || message.headers.contains_key(FRAGMENT_HEADER) {
+            vec![message]
+        } else {
+            message.split(max_size)
+        };
+        let start = tokio::time::Instant::now();
+        let send_pieces = async {
+            let mut total_attempts = 0;
+            let mut outcome: Option<SendOutcome> = None;
+            for piece in pieces {
+                let (result, recorded) =
+                    SendContext::scope(executor.send_via(&to.protocol, &to.identity, piece)).await;
+                total_attempts += recorded.attempts.max(1);
+                let sent = result.map_err(|error| {
+                    match self.executor_failure(to, name, total_attempts, error) {
+                        SendError::ExecutorError(failure) => {
+                            SendError::ExecutorError(SendFailure {
+                                budget_exhausted: recorded.budget_exhausted,
+                                ..failure
+                            })
+                        }
+                        other => other,
+                    }
+                })?;
+                outcome = Some(match outcome {
+                    Some(so_far) => so_far.then(sent),
+                    None => sent,
+                });
+            }
+            Ok((total_attempts, outcome))
+        };
+        // one timeout for the whole send, however many fragments it takes
+        let (attempts, outcome) = match self.send_timeout_for(&to.protocol) {
+            Some(timeout) => tokio::time::timeout(timeout, send_pieces)
+                .await
+                .map_err(|_| SendError::DeadlineExceeded)??,
+            None => send_pieces.await?,
+        };
+        Ok(SendReceipt {
+            protocol: to.protocol.clone(),
+            registration: self.registered_as(&to.protocol, name).clone(),
+            unique_id,
+            elapsed: start.elapsed(),
+            attempts,
+            outcome: outcome.unwrap_or_default(),
+        })
+    }
+    /// The send error for `error` from the executor for `to`, counting panics
+    /// against the executor registered under `name` or else the default.
+    pub(crate) fn executor_failure(
+        &self,
+        to: &Address,
+        name: Option<&str>,
+        attempts: u32,
+        error: BoxError,
+    ) -> SendError {
+        if let Some(panic) = ExecutorPanic::find(&*error) {
+            self.record_executor_panic(self.registered_as(&to.protocol, name), name);
+            return SendError::ExecutorPanicked {
+                message: panic.message.clone(),
+            };
+        }
+        SendError::ExecutorError(SendFailure::new(to, attempts, error))
+    }
+    /// The redacted message for [`NodeInstance::with_on_send_err`], if anyone listens.
+    pub(crate) fn path_for_report(&self, message: &Message) -> Option<String> {
+        self.on_send_err
+            .as_ref()
+            .map(|_| message.redacted().to_string())
+    }
+    pub(crate) fn report_send(
+        &self,
+        to: &Address,
+        unique_id: u64,
+        path: Option<String>,
+        result: &Result<SendReceipt, SendError>,
+    ) {
+        self.metrics.record_send(result);
+        if let Some(hook) = &self.on_send_result {
+            hook(to, unique_id, result)
+        }
+        if let (Some(hook), Some(path), Err(error)) = (&self.on_send_err, path, result) {
+            hook(to, error, &path)
+        }
+    }
+    /// Like [`NodeInstance::send`], but gives up with [`SendError::DeadlineExceeded`]
+    /// once `deadline` passes. A [propagated](Deadline::propagated) deadline is also
+    /// stamped into the message so downstream relays stop forwarding it in time.
+    pub async fn send_with_deadline(
+        &self,
+        mut message: Message,
+        to: impl Into<Address>,
+        deadline: Deadline,
+    ) -> Result<(), SendError> {
+        let to = to.into();
+        let unique_id = message.unique_id;
+        let path = self.path_for_report(&message);
+        let result = if deadline.is_expired() {
+            Err(SendError::DeadlineExceeded)
+        } else {
+            if deadline.is_propagated() {
+                deadline.stamp(&mut message, self.clock.now_millis());
+            }
+            tokio::time::timeout_at(deadline.instant(), self.send_once(message, &to))
+                .await
+                .unwrap_or(Err(SendError::DeadlineExceeded))
+        };
+        self.report_send(&to, unique_id, path, &result);
+        result.map(|_| ())
     }
 }
@@ -0,0 +1,237 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{receipt::SendReceipt, MessageStatus, NodeInstance, SendError};
+
+/// Every counter a node keeps, as returned by [`NodeInstance::metrics_snapshot`].
+///
+/// Counters only grow; rates are up to whoever scrapes them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NodeMetrics {
+    pub sends_attempted: u64,
+    pub sends_ok: u64,
+    pub sends_failed: u64,
+    /// Sends refused by the [rate limiter](crate::RateLimiter).
+    pub rate_limited: u64,
+    /// Sends and receptions refused by the [quota manager](crate::QuotaManager).
+    pub quota_exceeded: u64,
+    pub deadline_exceeded: u64,
+    pub route_cache_hits: u64,
+    pub route_cache_misses: u64,
+    /// Routes evicted from the [full route cache](NodeInstance::with_route_cache_capacity).
+    pub route_cache_evictions: u64,
+    pub messages_received: u64,
+    /// Inbound messages answered with [`MessageStatus::Rejected`].
+    pub messages_rejected: u64,
+    pub unknown_control_messages: u64,
+    /// Audit events lost because the audit queue was full.
+    pub audit_events_dropped: u64,
+    /// Inbound messages dropped because their origin was quarantined.
+    pub quarantine_drops: u64,
+    /// Inbound messages that repeated recently seen
+    /// [content](NodeInstance::with_content_dedup).
+    pub content_duplicates: u64,
+    /// Spawned [handler](NodeInstance::with_handler_policy) calls that panicked.
+    pub handler_panics: u64,
+    pub route_withdraws: crate::RouteWithdrawStats,
+    /// [Executor panics](crate::ExecutorPanic) by protocol.
+    pub executor_panics: BTreeMap<String, u64>,
+    /// Sends refused with [`SendError::Overloaded`].
+    pub shed_overloaded: u64,
+    /// Sends given up with [`SendError::ShedByAge`].
+    pub shed_by_age: u64,
+    /// Age of the oldest queued send by destination, while
+    /// [load shedding](NodeInstance::with_load_shedding) is on.
+    pub oldest_queued: BTreeMap<String, Duration>,
+    /// Sends by [named executor](NodeInstance::register_named_executor), keyed
+    /// `protocol/name`.
+    pub named_executor_sends: BTreeMap<String, u64>,
+    /// Failed sends by named executor.
+    pub named_executor_failures: BTreeMap<String, u64>,
+    /// [Throttles](NodeInstance::with_throttle_feedback) sent to sources.
+    pub throttles_sent: u64,
+    /// Throttles received and honored.
+    pub throttles_honored: u64,
+    /// Throttles received for destinations their sender may not throttle.
+    pub throttles_ignored: u64,
+    /// Inbound connections closed because the peer broke the
+    /// [framing](crate::frame), as [recorded](NodeInstance::record_protocol_violation)
+    /// by listeners.
+    pub protocol_violations: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    sends_attempted: AtomicU64,
+    sends_ok: AtomicU64,
+    sends_failed: AtomicU64,
+    rate_limited: AtomicU64,
+    quota_exceeded: AtomicU64,
+    deadline_exceeded: AtomicU64,
+    route_cache_hits: AtomicU64,
+    route_cache_misses: AtomicU64,
+    messages_received: AtomicU64,
+    messages_rejected: AtomicU64,
+    /// Shared with listeners that count towards this node.
+    protocol_violations: Arc<AtomicU64>,
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub(crate) fn record_route_cache(&self, hit: bool) {
+        bump(if hit {
+            &self.route_cache_hits
+        } else {
+            &self.route_cache_misses
+        })
+    }
+    pub(crate) fn record_send(&self, result: &Result<SendReceipt, SendError>) {
+        bump(&self.sends_attempted);
+        match result {
+            Ok(_) => bump(&self.sends_ok),
+            Err(error) => {
+                bump(&self.sends_failed);
+                match error {
+                    SendError::RateLimited => bump(&self.rate_limited),
+                    SendError::QuotaExceeded => bump(&self.quota_exceeded),
+                    SendError::DeadlineExceeded => bump(&self.deadline_exceeded),
+                    _ => {}
+                }
+            }
+        }
+    }
+    pub(crate) fn record_inbound(&self, result: &Result<MessageStatus, SendError>) {
+        bump(&self.messages_received);
+        match result {
+            Ok(MessageStatus::Rejected { reason }) => {
+                bump(&self.messages_rejected);
+                if *reason == crate::RejectReason::QuotaExceeded {
+                    bump(&self.quota_exceeded);
+                }
+            }
+            Err(SendError::TtlExceeded | SendError::PathTooLong { .. }) => {
+                bump(&self.messages_rejected)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Count an inbound connection closed because the peer broke the
+    /// [framing](crate::frame), such as with an oversize or empty frame. For
+    /// listeners of transports this crate does not provide; its own can count
+    /// towards the node directly.
+    pub fn record_protocol_violation(&self) {
+        bump(&self.metrics.protocol_violations)
+    }
+    #[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+    pub(crate) fn protocol_violation_counter(&self) -> Arc<AtomicU64> {
+        self.metrics.protocol_violations.clone()
+    }
+    /// Read all counters at once, e.g. for a metrics endpoint.
+    ///
+    /// Each counter is read atomically, the snapshot as a whole is not.
+    pub fn metrics_snapshot(&self) -> NodeMetrics {
+        let m = &self.metrics;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (shed_overloaded, shed_by_age) = self.shed_counts();
+        let named = self.named_send_counts();
+        let (throttles_sent, throttles_honored, throttles_ignored) = self.throttle_counts();
+        NodeMetrics {
+            sends_attempted: load(&m.sends_attempted),
+            sends_ok: load(&m.sends_ok),
+            sends_failed: load(&m.sends_failed),
+            rate_limited: load(&m.rate_limited),
+            quota_exceeded: load(&m.quota_exceeded),
+            deadline_exceeded: load(&m.deadline_exceeded),
+            route_cache_hits: load(&m.route_cache_hits),
+            route_cache_misses: load(&m.route_cache_misses),
+            route_cache_evictions: self.next_cache.read().unwrap().evictions(),
+            messages_received: load(&m.messages_received),
+            messages_rejected: load(&m.messages_rejected),
+            unknown_control_messages: self.unknown_control_messages(),
+            audit_events_dropped: self.audit_events_dropped(),
+            quarantine_drops: self.quarantine_drops(),
+            content_duplicates: self.content_duplicates(),
+            handler_panics: self.handler_panics(),
+            route_withdraws: self.route_withdraw_stats(),
+            executor_panics: self.panics.snapshot(),
+            shed_overloaded,
+            shed_by_age,
+            named_executor_sends: named
+                .iter()
+                .map(|(name, (sends, _))| (name.clone(), *sends))
+                .collect(),
+            named_executor_failures: named
+                .iter()
+                .map(|(name, (_, failed))| (name.clone(), *failed))
+                .collect(),
+            throttles_sent,
+            throttles_honored,
+            throttles_ignored,
+            protocol_violations: load(&m.protocol_violations),
+            oldest_queued: self
+                .send_queue_ages()
+                .into_iter()
+                .map(|(to, age)| (to.to_string(), age))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+
+    #[tokio::test]
+    async fn sends_are_counted() {
+        let node = NodeInstance::new().with_executor(TEST, Recorder::new());
+        node.dispatch(message(addr("b"), b"x")).await.unwrap();
+        let metrics = node.metrics_snapshot();
+        assert_eq!(
+            (
+                metrics.sends_attempted,
+                metrics.sends_ok,
+                metrics.sends_failed
+            ),
+            (1, 1, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_touch_only_the_send_and_route_cache_counters() {
+        let node = NodeInstance::new().with_executor(TEST, Recorder::new().failing_first(1));
+        assert!(node.forward(message(addr("b"), b"x")).await.is_err());
+        node.forward(message(addr("b"), b"x")).await.unwrap();
+        assert_eq!(
+            node.metrics_snapshot(),
+            NodeMetrics {
+                sends_attempted: 2,
+                sends_ok: 1,
+                sends_failed: 1,
+                route_cache_misses: 2,
+                ..NodeMetrics::default()
+            }
+        );
+    }
+
+    #[test]
+    fn protocol_violations_are_counted() {
+        let node = NodeInstance::new();
+        node.record_protocol_violation();
+        node.record_protocol_violation();
+        assert_eq!(node.metrics_snapshot().protocol_violations, 2);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{collections::HashMap, fmt, future::Future, sync::Arc};
+
+use crate::{
+    BoxError, DynProtocolExecutor, ExecutorCapabilities, Identity, Message, MessageStatus,
+    NodeInstance, Protocol, ProtocolExecutor, SendOutcome,
+};
+
+#[derive(Debug)]
+pub enum MuxError {
+    /// No inner executor is registered for the protocol.
+    UnknownProtocol(Protocol),
+    Inner(BoxError),
+}
+
+impl fmt::Display for MuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MuxError::UnknownProtocol(protocol) => {
+                write!(f, "no inner executor for protocol {protocol}")
+            }
+            MuxError::Inner(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MuxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MuxError::Inner(e) => Some(e.as_ref()),
+            MuxError::UnknownProtocol(_) => None,
+        }
+    }
+}
+
+/// One executor standing in for several protocols, dispatching each send to the
+/// inner executor of the protocol the node picked.
+///
+/// Register it with [`NodeInstance::with_mux_executor`]. Plain
+/// [`ProtocolExecutor::send`] and `get_status` fall back to the protocol of
+/// `message.destination`.
+#[derive(Clone, Default)]
+pub struct MuxExecutor {
+    inner: HashMap<Protocol, Arc<dyn DynProtocolExecutor>>,
+}
+
+impl MuxExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_protocol(
+        mut self,
+        protocol: Protocol,
+        executor: impl DynProtocolExecutor + 'static,
+    ) -> Self {
+        self.inner.insert(protocol, Arc::new(executor));
+        self
+    }
+    pub fn protocols(&self) -> impl Iterator<Item = &Protocol> {
+        self.inner.keys()
+    }
+    fn route(&self, protocol: &Protocol) -> Result<&Arc<dyn DynProtocolExecutor>, MuxError> {
+        self.inner
+            .get(protocol)
+            .ok_or_else(|| MuxError::UnknownProtocol(protocol.clone()))
+    }
+}
+
+impl ProtocolExecutor for MuxExecutor {
+    type Error = MuxError;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let protocol = message.destination.protocol.clone();
+        let send = self
+            .route(&protocol)
+            .map(|executor| executor.send_via(&protocol, remote, message));
+        async move { send?.await.map_err(MuxError::Inner) }
+    }
+
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let send = self
+            .route(protocol)
+            .map(|executor| executor.send_via(protocol, remote, message));
+        async move { send?.await.map_err(MuxError::Inner) }
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let protocol = message.destination.protocol.clone();
+        let status = self
+            .route(&protocol)
+            .map(|executor| executor.get_status(remote, message));
+        async move { status?.await.map_err(MuxError::Inner) }
+    }
+
+    /// What every inner executor can do.
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner
+            .values()
+            .map(|executor| executor.capabilities())
+            .reduce(ExecutorCapabilities::intersect)
+            .unwrap_or_default()
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        let closes: Vec<_> = self
+            .inner
+            .values()
+            .map(|executor| executor.close())
+            .collect();
+        async move {
+            for close in closes {
+                close.await;
+            }
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Register `mux` once for every protocol it carries.
+    pub fn with_mux_executor(mut self, mux: MuxExecutor) -> Self {
+        let protocols: Vec<_> = mux.protocols().cloned().collect();
+        let mux: Arc<dyn DynProtocolExecutor> = Arc::new(mux);
+        for protocol in protocols {
+            self.register_executor(protocol, mux.clone());
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::Address;
+
+    const OTHER: Protocol = Protocol::new_static(b"other");
+
+    #[tokio::test]
+    async fn sends_reach_the_executor_of_their_protocol() {
+        let (test, other) = (Recorder::new(), Recorder::new());
+        let mux = MuxExecutor::new()
+            .with_protocol(TEST, test.clone())
+            .with_protocol(OTHER, other.clone());
+        let node = NodeInstance::new().with_mux_executor(mux);
+        let elsewhere = Address::new(OTHER, Identity::new("c"));
+        node.send(message(addr("b"), b"test"), addr("b"))
+            .await
+            .unwrap();
+        node.send(message(elsewhere.clone(), b"other"), elsewhere)
+            .await
+            .unwrap();
+        assert_eq!(test.remotes(), [Identity::new("b")]);
+        assert_eq!(other.remotes(), [Identity::new("c")]);
+        assert_eq!(other.sent()[0].payload, b"other");
+    }
+
+    #[tokio::test]
+    async fn unknown_protocols_are_refused() {
+        let mux = MuxExecutor::new().with_protocol(TEST, Recorder::new());
+        let elsewhere = Address::new(OTHER, Identity::new("c"));
+        let result =
+            ProtocolExecutor::send(&mux, &Identity::new("c"), message(elsewhere, b"x")).await;
+        assert!(matches!(result, Err(MuxError::UnknownProtocol(p)) if p == OTHER));
+    }
+
+    #[tokio::test]
+    async fn closing_the_mux_closes_every_inner_executor() {
+        let (test, other) = (Recorder::new(), Recorder::new());
+        let mux = MuxExecutor::new()
+            .with_protocol(TEST, test.clone())
+            .with_protocol(OTHER, other.clone());
+        ProtocolExecutor::close(&mux).await;
+        assert_eq!((test.closes(), other.closes()), (1, 1));
+    }
+}
@@ -0,0 +1,260 @@
+//! Several executors for one protocol, picked per message.
+//!
+//! Besides its default executor, a protocol can have executors
+//! [registered under a name](NodeInstance::register_named_executor), e.g. a
+//! direct and a proxied transport for the same addresses. A send uses:
+//!
+//! 1. the executor named in the message's [`EXECUTOR_HEADER`], as set by
+//!    [`NodeInstance::send_via`];
+//! 2. otherwise the executor of the longest matching
+//!    [override](NodeInstance::with_executor_override) for the next hop's
+//!    identity;
+//! 3. otherwise the protocol's default executor.
+//!
+//! A name without an executor fails the send with
+//! [`SendError::ExecutorNotFound`]. The header is removed before the message
+//! leaves the node. Named executors have their own
+//! [panic count](NodeInstance::named_executor_panics) and their own sends in
+//! [`NodeMetrics`](crate::NodeMetrics). Batched and streaming sends always use
+//! the default executor.
+//!
+//! Names and overrides registered for a protocol also apply to the protocols
+//! below it in its [hierarchy](crate::DEFAULT_PROTOCOL_DELIMITER), the most
+//! specific registration first.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    hierarchy::ProtocolHierarchy, Address, DynProtocolExecutor, Message, NodeInstance, Protocol,
+    SendError,
+};
+
+/// Names the executor, as UTF-8, a message is to be sent with.
+pub const EXECUTOR_HEADER: &str = "anytape-executor";
+
+#[derive(Default)]
+pub(crate) struct NamedExecutors {
+    executors: HashMap<Protocol, HashMap<String, Arc<dyn DynProtocolExecutor>>>,
+    /// Identity prefixes and the executor they use, by protocol.
+    overrides: HashMap<Protocol, Vec<(Vec<u8>, String)>>,
+    /// Sends and failed sends by executor.
+    sends: Mutex<HashMap<(Protocol, String), (u64, u64)>>,
+}
+
+impl NamedExecutors {
+    fn override_for(&self, hierarchy: &ProtocolHierarchy, to: &Address) -> Option<&str> {
+        if self.overrides.is_empty() {
+            return None;
+        }
+        hierarchy.candidates(&to.protocol).find_map(|candidate| {
+            self.overrides
+                .get(candidate)?
+                .iter()
+                .filter(|(prefix, _)| to.identity.as_bytes().starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, name)| name.as_str())
+        })
+    }
+    /// The protocol and executor registered under `name` for `protocol`.
+    fn find(
+        &self,
+        hierarchy: &ProtocolHierarchy,
+        protocol: &Protocol,
+        name: &str,
+    ) -> Option<(&Protocol, &Arc<dyn DynProtocolExecutor>)> {
+        hierarchy.candidates(protocol).find_map(|candidate| {
+            let (registered, executors) = self.executors.get_key_value(candidate)?;
+            Some((registered, executors.get(name)?))
+        })
+    }
+}
+
+impl NodeInstance {
+    /// Also send over `protocol` with `executor` when a message asks for `name`;
+    /// see the [module docs](self).
+    pub fn with_named_executor(
+        mut self,
+        protocol: Protocol,
+        name: impl Into<String>,
+        executor: impl DynProtocolExecutor + 'static,
+    ) -> Self {
+        self.register_named_executor(protocol, name, Arc::new(executor));
+        self
+    }
+    /// Register `executor` for `protocol` under `name`, returning the executor it
+    /// replaced.
+    pub fn register_named_executor(
+        &mut self,
+        protocol: Protocol,
+        name: impl Into<String>,
+        executor: Arc<dyn DynProtocolExecutor>,
+    ) -> Option<Arc<dyn DynProtocolExecutor>> {
+        let name = name.into();
+        self.panics.reset(&protocol, Some(&name));
+        self.named_executors
+            .executors
+            .entry(protocol)
+            .or_default()
+            .insert(name, executor)
+    }
+    pub fn deregister_named_executor(
+        &mut self,
+        protocol: &Protocol,
+        name: &str,
+    ) -> Option<Arc<dyn DynProtocolExecutor>> {
+        self.panics.reset(protocol, Some(name));
+        self.named_executors
+            .executors
+            .get_mut(protocol)?
+            .remove(name)
+    }
+    /// Send messages over `protocol` to identities starting with
+    /// `identity_prefix` with the executor named `name`, unless they name one
+    /// themselves. The longest matching prefix wins.
+    pub fn with_executor_override(
+        mut self,
+        protocol: Protocol,
+        identity_prefix: impl Into<Vec<u8>>,
+        name: impl Into<String>,
+    ) -> Self {
+        self.named_executors
+            .overrides
+            .entry(protocol)
+            .or_default()
+            .push((identity_prefix.into(), name.into()));
+        self
+    }
+    /// Like [`NodeInstance::send`], but with the executor registered for
+    /// `to.protocol` under `name`.
+    pub async fn send_via(
+        &self,
+        mut message: Message,
+        to: impl Into<Address>,
+        name: &str,
+    ) -> Result<(), SendError> {
+        let to = to.into();
+        message
+            .headers
+            .insert(EXECUTOR_HEADER.to_owned(), name.as_bytes().to_vec());
+        self.send(message, to).await
+    }
+    /// The name of the executor to send `message` to `to` with, taking the
+    /// [`EXECUTOR_HEADER`] off the message. `None` for the default executor.
+    pub(crate) fn choose_executor(&self, message: &mut Message, to: &Address) -> Option<String> {
+        match message.headers.remove(EXECUTOR_HEADER) {
+            Some(name) => Some(String::from_utf8_lossy(&name).into_owned()),
+            None => self
+                .named_executors
+                .override_for(&self.protocol_hierarchy, to)
+                .map(str::to_owned),
+        }
+    }
+    pub(crate) fn named_executor(
+        &self,
+        to: &Address,
+        name: &str,
+    ) -> Result<Arc<dyn DynProtocolExecutor>, SendError> {
+        self.named_executors
+            .find(&self.protocol_hierarchy, &to.protocol, name)
+            .map(|(_, executor)| executor.clone())
+            .ok_or_else(|| SendError::ExecutorNotFound {
+                protocol: to.protocol.clone(),
+                name: name.to_owned(),
+            })
+    }
+    /// The protocol the executor for `protocol` named `name` is registered
+    /// under.
+    pub(crate) fn named_registration(&self, protocol: &Protocol, name: &str) -> Option<&Protocol> {
+        self.named_executors
+            .find(&self.protocol_hierarchy, protocol, name)
+            .map(|(registered, _)| registered)
+    }
+    pub(crate) fn record_named_send(&self, protocol: &Protocol, name: &str, ok: bool) {
+        let protocol = self.registered_as(protocol, Some(name));
+        let mut sends = self.named_executors.sends.lock().unwrap();
+        let (total, failed) = sends
+            .entry((protocol.clone(), name.to_owned()))
+            .or_default();
+        *total += 1;
+        if !ok {
+            *failed += 1;
+        }
+    }
+    /// Sends and failed sends by named executor, keyed `protocol/name`.
+    pub(crate) fn named_send_counts(&self) -> BTreeMap<String, (u64, u64)> {
+        let sends = self.named_executors.sends.lock().unwrap();
+        sends
+            .iter()
+            .map(|((protocol, name), counts)| (format!("{protocol}/{name}"), *counts))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{addr, message, Recorder, TEST},
+        MessageBuilder,
+    };
+
+    #[tokio::test]
+    async fn sends_reach_the_executor_they_name_or_override() {
+        let (default, direct, proxied) = (Recorder::new(), Recorder::new(), Recorder::new());
+        let node = NodeInstance::new()
+            .with_executor(TEST, default.clone())
+            .with_named_executor(TEST, "direct", direct.clone())
+            .with_named_executor(TEST, "proxied", proxied.clone())
+            .with_executor_override(TEST, "far", "proxied")
+            .with_executor_override(TEST, "far-but-near", "direct");
+
+        let by_header = MessageBuilder::new(addr("b"))
+            .header(EXECUTOR_HEADER, "proxied")
+            .build();
+        node.send(by_header, addr("b")).await.unwrap();
+        node.send_via(message(addr("b"), b"x"), addr("b"), "direct")
+            .await
+            .unwrap();
+        node.send(message(addr("far-away"), b"x"), addr("far-away"))
+            .await
+            .unwrap();
+        node.send(message(addr("far-but-near"), b"x"), addr("far-but-near"))
+            .await
+            .unwrap();
+        node.send(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+
+        assert_eq!(default.remotes(), [addr("b").identity]);
+        assert_eq!(
+            direct.remotes(),
+            [addr("b").identity, addr("far-but-near").identity]
+        );
+        assert_eq!(
+            proxied.remotes(),
+            [addr("b").identity, addr("far-away").identity]
+        );
+        let sent = [default.sent(), direct.sent(), proxied.sent()].concat();
+        assert!(sent
+            .iter()
+            .all(|message| !message.headers.contains_key(EXECUTOR_HEADER)));
+        assert_eq!(node.named_send_counts()["test/proxied"], (2, 0));
+    }
+
+    #[tokio::test]
+    async fn naming_a_missing_executor_fails_the_send() {
+        let default = Recorder::new();
+        let node = NodeInstance::new().with_executor(TEST, default.clone());
+        let result = node
+            .send_via(message(addr("b"), b"x"), addr("b"), "proxied")
+            .await;
+        assert!(matches!(
+            result,
+            Err(SendError::ExecutorNotFound { name, .. }) if name == "proxied"
+        ));
+        assert!(default.sent().is_empty());
+    }
+}
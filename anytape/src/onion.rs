@@ -0,0 +1,401 @@
+//! Sender-built layered routes.
+//!
+//! The sender wraps the payload once per hop, innermost layer first, each layer
+//! sealed to its hop's key and naming only the next hop. A relay peels exactly
+//! one layer and forwards what is left, so it learns its neighbours but neither
+//! its position in the route nor the final destination. Onion messages carry
+//! [`ONION_HEADER`] and are never marked with path nodes.
+//!
+//! A lighter alternative hides only the destination: [`NodeInstance::send_sealed`]
+//! addresses an ordinary message to a relay and seals the real destination to it
+//! in [`SEALED_DESTINATION_HEADER`]. Relays before it see only the sealing relay;
+//! that relay opens the header and forwards the message to the real destination.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    random_u64,
+    wire::{DecodeError, Reader, Writer},
+    Address, BoxResult, Message, MessageStatus, NodeInstance, SendError,
+};
+
+/// Marks a message whose payload is a sealed onion layer.
+pub const ONION_HEADER: &str = "anytape-onion";
+
+/// Carries the real destination of a message, sealed to the relay it is
+/// addressed to.
+pub const SEALED_DESTINATION_HEADER: &str = "anytape-sealed-destination";
+
+/// Seals a layer to a relay's key, typically with the relay's public key.
+pub trait OnionSealer: Send + Sync {
+    fn seal(&self, relay: &Address, layer: &[u8]) -> BoxResult<Vec<u8>>;
+}
+
+/// Opens layers sealed to this node.
+pub trait OnionOpener: Send + Sync {
+    fn open(&self, sealed: &[u8]) -> BoxResult<Vec<u8>>;
+}
+
+#[derive(Debug)]
+pub enum OnionError {
+    /// An onion layer was addressed to a node without an [`OnionOpener`].
+    NotARelay,
+    /// An opened layer was malformed.
+    MalformedLayer(DecodeError),
+    /// An opened [sealed destination](SEALED_DESTINATION_HEADER) was not an address.
+    MalformedSeal(DecodeError),
+}
+
+impl fmt::Display for OnionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnionError::NotARelay => write!(f, "this node is not an onion relay"),
+            OnionError::MalformedLayer(e) => write!(f, "malformed onion layer: {e}"),
+            OnionError::MalformedSeal(e) => write!(f, "malformed sealed destination: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for OnionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OnionError::MalformedLayer(e) | OnionError::MalformedSeal(e) => Some(e),
+            OnionError::NotARelay => None,
+        }
+    }
+}
+
+/// What a hop finds after opening its layer.
+struct Layer {
+    /// `None` for the final hop.
+    next: Option<Address>,
+    /// `unique_id` of the message carrying `inner` onwards, chosen by the sender
+    /// so ids cannot be used to link hops of one circuit.
+    unique_id: u64,
+    inner: Vec<u8>,
+}
+
+impl Layer {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match &self.next {
+            Some(next) => {
+                w.put_u8(1);
+                w.put_address(next);
+            }
+            None => w.put_u8(0),
+        }
+        w.put_u64(self.unique_id);
+        w.put_bytes(&self.inner);
+        w.finish()
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let next = match r.get_u8()? {
+            0 => None,
+            1 => Some(r.get_address()?),
+            flags => return Err(DecodeError::InvalidFlags(flags)),
+        };
+        let unique_id = r.get_u64()?;
+        let inner = r.get_bytes()?.to_vec();
+        r.finish()?;
+        Ok(Self {
+            next,
+            unique_id,
+            inner,
+        })
+    }
+}
+
+fn onion_message(destination: Address, payload: Vec<u8>, unique_id: u64) -> Message {
+    Message {
+        destination,
+        path: Vec::new(),
+        payload,
+        signature: Vec::new(),
+        unique_id,
+        ttl: None,
+        headers: BTreeMap::from([(ONION_HEADER.to_owned(), Vec::new())]),
+        metadata: Vec::new(),
+        extensions: Vec::new(),
+        seq: None,
+        budget_ms: None,
+        route_plan: None,
+        reply_to: None,
+    }
+}
+
+impl NodeInstance {
+    /// Send `payload` along `route`, whose last address is the recipient and
+    /// every earlier one a relay. `key_resolver` seals each layer to its hop.
+    pub async fn send_onion(
+        &self,
+        payload: Vec<u8>,
+        route: &[Address],
+        key_resolver: &dyn OnionSealer,
+    ) -> Result<(), SendError> {
+        let (first, _) = route.split_first().ok_or(SendError::NoRoute)?;
+        let mut sealed = payload;
+        let mut next = None;
+        for hop in route.iter().rev() {
+            let layer = Layer {
+                next: next.take(),
+                unique_id: random_u64(),
+                inner: sealed,
+            };
+            sealed = key_resolver
+                .seal(hop, &layer.encode())
+                .map_err(SendError::Onion)?;
+            next = Some(hop.clone());
+        }
+        self.forward(onion_message(first.clone(), sealed, self.next_id()))
+            .await
+    }
+
+    /// Forward `message` through `relay` without revealing `message.destination`
+    /// to anyone before it: the destination is sealed to `relay` with `sealer` and
+    /// `relay` becomes the visible destination.
+    pub async fn send_sealed(
+        &self,
+        mut message: Message,
+        relay: Address,
+        sealer: &dyn OnionSealer,
+    ) -> Result<(), SendError> {
+        let mut destination = Writer::new();
+        destination.put_address(&message.destination);
+        let sealed = sealer
+            .seal(&relay, &destination.finish())
+            .map_err(SendError::Onion)?;
+        message
+            .headers
+            .insert(SEALED_DESTINATION_HEADER.to_owned(), sealed);
+        message.destination = relay;
+        self.forward(message).await
+    }
+
+    /// Swap the sealed destination of a message addressed to this node back in and
+    /// relay it there.
+    pub(crate) async fn unseal_destination(
+        &self,
+        mut message: Message,
+        accept_at: Address,
+    ) -> Result<MessageStatus, SendError> {
+        let opener = self
+            .onion_opener
+            .as_ref()
+            .ok_or_else(|| SendError::Onion(Box::new(OnionError::NotARelay)))?;
+        let sealed = message
+            .headers
+            .remove(SEALED_DESTINATION_HEADER)
+            .unwrap_or_default();
+        let opened = opener.open(&sealed).map_err(SendError::Onion)?;
+        let mut r = Reader::new(&opened);
+        message.destination = r
+            .get_address()
+            .and_then(|destination| r.finish().map(|()| destination))
+            .map_err(|e| SendError::Onion(Box::new(OnionError::MalformedSeal(e))))?;
+        self.relay(message, accept_at)
+            .await
+            .map(|()| MessageStatus::Sended)
+    }
+
+    pub(crate) async fn peel_onion(&self, message: Message) -> Result<MessageStatus, SendError> {
+        if !self.is_local(&message.destination) {
+            // in transit between two onion hops; pass it on untouched
+            return self.forward(message).await.map(|()| MessageStatus::Sended);
+        }
+        let opener = self
+            .onion_opener
+            .as_ref()
+            .ok_or_else(|| SendError::Onion(Box::new(OnionError::NotARelay)))?;
+        let opened = opener.open(&message.payload).map_err(SendError::Onion)?;
+        let layer = Layer::decode(&opened)
+            .map_err(|e| SendError::Onion(Box::new(OnionError::MalformedLayer(e))))?;
+        match layer.next {
+            Some(next) => self
+                .forward(onion_message(next, layer.inner, layer.unique_id))
+                .await
+                .map(|()| MessageStatus::Sended),
+            None => {
+                let delivered = Message {
+                    destination: message.destination,
+                    path: Vec::new(),
+                    payload: layer.inner,
+                    signature: Vec::new(),
+                    unique_id: layer.unique_id,
+                    ttl: None,
+                    headers: BTreeMap::new(),
+                    metadata: Vec::new(),
+                    extensions: Vec::new(),
+                    seq: None,
+                    budget_ms: None,
+                    route_plan: None,
+                    reply_to: None,
+                };
+                Ok(self.deliver(delivered).await)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{virtual_net::VirtualNetwork, Identity};
+
+    /// Seals by xoring with the hop's name, which is enough to keep a layer
+    /// unreadable to anyone who opens it with another name.
+    struct XorSealer;
+
+    fn xor(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let key = key.iter().map(|k| k.wrapping_mul(31) | 0x80);
+        bytes.iter().zip(key.cycle()).map(|(b, k)| b ^ k).collect()
+    }
+
+    impl OnionSealer for XorSealer {
+        fn seal(&self, relay: &Address, layer: &[u8]) -> BoxResult<Vec<u8>> {
+            Ok(xor(relay.identity.as_bytes(), layer))
+        }
+    }
+
+    /// Opens layers sealed to `name` and keeps what it found in them.
+    struct Opener {
+        name: &'static str,
+        opened: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl OnionOpener for Opener {
+        fn open(&self, sealed: &[u8]) -> BoxResult<Vec<u8>> {
+            let layer = xor(self.name.as_bytes(), sealed);
+            self.opened.lock().unwrap().push(layer.clone());
+            Ok(layer)
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn a_three_relay_circuit_peels_one_layer_per_relay() {
+        const PAYLOAD: &[u8] = b"the secret payload";
+        let mut net = VirtualNetwork::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut opened = Vec::new();
+        for name in ["r1", "r2", "r3", "dest"] {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            opened.push(seen.clone());
+            let received = received.clone();
+            net.add_node(name, |node| {
+                node.with_onion_opener(Opener { name, opened: seen })
+                    .with_handler(move |message: Message| {
+                        received.lock().unwrap().push(message.payload);
+                        async {}
+                    })
+            });
+        }
+        let sender = net.add_node("sender", |node| node);
+        let route: Vec<_> = ["r1", "r2", "r3", "dest"]
+            .into_iter()
+            .map(VirtualNetwork::address)
+            .collect();
+        sender
+            .send_onion(PAYLOAD.to_vec(), &route, &XorSealer)
+            .await
+            .unwrap();
+        let hops = net.run_until_idle(10).await;
+
+        let visited: Vec<_> = hops.iter().map(|hop| hop.to.clone()).collect();
+        let names: Vec<_> = route.iter().map(|hop| hop.identity.clone()).collect();
+        assert_eq!(visited, names);
+        assert!(matches!(hops[3].result, Ok(MessageStatus::Received)));
+        for (i, seen) in opened.iter().enumerate() {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 1, "{}", route[i]);
+            let layer = Layer::decode(&seen[0]).unwrap();
+            assert_eq!(layer.next, route.get(i + 1).cloned());
+            if i < 3 {
+                assert!(!contains(&layer.inner, PAYLOAD));
+                assert!(route[i + 2..]
+                    .iter()
+                    .all(|later| !contains(&layer.inner, later.identity.as_bytes())));
+            } else {
+                assert_eq!(layer.inner, PAYLOAD);
+            }
+        }
+        assert_eq!(*received.lock().unwrap(), [PAYLOAD.to_vec()]);
+    }
+
+    /// A sink whose bytes the test can still read after handing it over.
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedSink {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_before_the_sealing_one_never_see_the_destination() {
+        use crate::{capture::CaptureWriter, DataBackend, MemoryBackend, MessageBuilder};
+
+        const HIDDEN: &str = "hidden-destination";
+        let at = VirtualNetwork::address;
+        let routes = MemoryBackend::new();
+        routes
+            .set_next(&at("gate"), Some(&at("mid")))
+            .await
+            .unwrap();
+        let sink = SharedSink::default();
+        let capture = Arc::new(CaptureWriter::new(sink.clone()).unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let mut net = VirtualNetwork::new();
+        let sender = net.add_node("sender", |node| node.with_backend(routes));
+        net.add_node("mid", |node| node.with_inbound_capture(capture.clone()));
+        net.add_node("gate", |node| {
+            node.with_onion_opener(Opener {
+                name: "gate",
+                opened: Arc::default(),
+            })
+        });
+        net.add_node(HIDDEN, |node| {
+            let received = received.clone();
+            node.with_handler(move |message: Message| {
+                received.lock().unwrap().push(message);
+                async {}
+            })
+        });
+
+        let mut message = MessageBuilder::new(at(HIDDEN)).payload("hi").build();
+        sender.mark(at("sender"), &mut message);
+        sender
+            .send_sealed(message, at("gate"), &XorSealer)
+            .await
+            .unwrap();
+        let hops = net.run_until_idle(10).await;
+        let visited: Vec<_> = hops.iter().map(|hop| hop.to.clone()).collect();
+        assert_eq!(visited, ["mid", "gate", HIDDEN].map(Identity::new));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].destination, at(HIDDEN));
+        assert_eq!(received[0].payload, b"hi");
+        assert!(!received[0].headers.contains_key(SEALED_DESTINATION_HEADER));
+        // everything the middle relay took in, every field of it
+        capture.flush().unwrap();
+        assert_eq!(capture.entries(), 1);
+        let seen = sink.0.lock().unwrap();
+        assert!(contains(&seen, b"gate"));
+        assert!(!contains(&seen, HIDDEN.as_bytes()));
+    }
+}
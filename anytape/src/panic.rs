@@ -0,0 +1,281 @@
+//! Containing executors that panic.
+//!
+//! The [`DynProtocolExecutor`](crate::DynProtocolExecutor) wrapper catches panics
+//! raised while an executor builds or runs its future and reports them as an
+//! [`ExecutorPanic`] error, which the node turns into
+//! [`SendError::ExecutorPanicked`]. With a
+//! [panic limit](NodeInstance::with_executor_panic_limit), a protocol whose
+//! executor keeps panicking is taken out of service and fails fast with
+//! [`SendError::ProtocolUnavailable`] until an executor is registered for it
+//! again. [Named executors](NodeInstance::register_named_executor) are counted
+//! and taken out of service on their own.
+//!
+//! Catching relies on unwinding; with `panic = "abort"` the process still ends.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use crate::{NodeEvent, NodeInstance, Protocol};
+
+/// An executor panicked; `message` is the panic payload if it was a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutorPanic {
+    pub message: String,
+}
+
+impl ExecutorPanic {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "non-string panic payload".to_owned(),
+            },
+        };
+        Self { message }
+    }
+    /// The panic behind `error`, looking through its sources as well since
+    /// wrapping executors pass inner errors on.
+    pub(crate) fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a ExecutorPanic> {
+        let mut error = Some(error);
+        while let Some(current) = error {
+            if let Some(panic) = current.downcast_ref::<ExecutorPanic>() {
+                return Some(panic);
+            }
+            error = current.source();
+        }
+        None
+    }
+}
+
+impl fmt::Display for ExecutorPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "executor panicked: {}", self.message)
+    }
+}
+
+impl Error for ExecutorPanic {}
+
+/// Resolves to the output of the future built by `make`, or to the panic raised
+/// while building or polling it.
+pub(crate) fn catch_unwind<F, Fut>(make: F) -> CatchUnwind<Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    CatchUnwind {
+        state: std::panic::catch_unwind(AssertUnwindSafe(make))
+            .map(Box::pin)
+            .map_err(|payload| Some(ExecutorPanic::from_payload(payload))),
+    }
+}
+
+pub(crate) struct CatchUnwind<Fut> {
+    state: Result<Pin<Box<Fut>>, Option<ExecutorPanic>>,
+}
+
+impl<Fut: Future> Future for CatchUnwind<Fut> {
+    type Output = Result<Fut::Output, ExecutorPanic>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = match &mut self.state {
+            Ok(future) => future,
+            Err(panic) => return Poll::Ready(Err(panic.take().expect("polled after completion"))),
+        };
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => {
+                self.state = Err(None);
+                Poll::Ready(Err(ExecutorPanic::from_payload(payload)))
+            }
+        }
+    }
+}
+
+/// The default executor of a protocol, or the one registered under a name.
+type ExecutorKey = (Protocol, Option<String>);
+
+fn key(protocol: &Protocol, name: Option<&str>) -> ExecutorKey {
+    (protocol.clone(), name.map(str::to_owned))
+}
+
+#[derive(Default)]
+pub(crate) struct PanicTracker {
+    counts: Mutex<HashMap<ExecutorKey, u64>>,
+    limit: Option<u64>,
+}
+
+impl PanicTracker {
+    /// Count a panic of the executor, returning the new count.
+    fn record(&self, protocol: &Protocol, name: Option<&str>) -> u64 {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key(protocol, name)).or_default();
+        *count += 1;
+        *count
+    }
+    fn count(&self, protocol: &Protocol, name: Option<&str>) -> u64 {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&key(protocol, name))
+            .copied()
+            .unwrap_or(0)
+    }
+    pub(crate) fn is_unavailable(&self, protocol: &Protocol, name: Option<&str>) -> bool {
+        self.limit
+            .is_some_and(|limit| self.count(protocol, name) >= limit)
+    }
+    pub(crate) fn reset(&self, protocol: &Protocol, name: Option<&str>) {
+        self.counts.lock().unwrap().remove(&key(protocol, name));
+    }
+    /// Counts by protocol, named executors as `protocol/name`.
+    pub(crate) fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((protocol, name), count)| match name {
+                Some(name) => (format!("{protocol}/{name}"), *count),
+                None => (protocol.to_string(), *count),
+            })
+            .collect()
+    }
+}
+
+impl NodeInstance {
+    /// Take a protocol out of service once its executor has panicked `limit`
+    /// times; see the [module docs](self).
+    pub fn with_executor_panic_limit(mut self, limit: u64) -> Self {
+        self.panics.limit = Some(limit);
+        self
+    }
+    pub(crate) fn record_executor_panic(&self, protocol: &Protocol, name: Option<&str>) {
+        let panics = self.panics.record(protocol, name);
+        if name.is_none() && self.panics.limit == Some(panics) {
+            self.publish(|| NodeEvent::ExecutorQuarantined {
+                protocol: protocol.clone(),
+                panics,
+            });
+        }
+    }
+    /// How often the executor for `protocol` panicked since it was registered.
+    pub fn executor_panics(&self, protocol: &Protocol) -> u64 {
+        self.panics.count(protocol, None)
+    }
+    /// How often the executor registered for `protocol` under `name` panicked
+    /// since it was registered.
+    pub fn named_executor_panics(&self, protocol: &Protocol, name: &str) -> u64 {
+        self.panics.count(protocol, Some(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TestError, TEST};
+    use crate::{
+        Address, Identity, Message, MessageStatus, ProtocolExecutor, SendError, SendOutcome,
+    };
+
+    const STABLE: Protocol = Protocol::new_static(b"stable");
+
+    /// Panics while building the send for payload `early`, and while running it
+    /// otherwise.
+    #[derive(Clone, Default)]
+    struct Panicker {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl ProtocolExecutor for Panicker {
+        type Error = TestError;
+        fn send(
+            &self,
+            _: &Identity,
+            message: Message,
+        ) -> impl Future<Output = Result<SendOutcome, TestError>> + Send + 'static {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if message.payload == b"early" {
+                panic!("early");
+            }
+            async move { panic!("late {}", message.payload.len()) }
+        }
+        fn get_status(
+            &self,
+            _: &Identity,
+            _: Message,
+        ) -> impl Future<Output = Result<MessageStatus, TestError>> + Send + 'static {
+            std::future::ready(Ok(MessageStatus::Sended))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn panics_become_errors_until_the_protocol_is_taken_out_of_service() {
+        let panicker = Panicker::default();
+        let recorder = Recorder::new();
+        let node = Arc::new(
+            NodeInstance::new()
+                .with_executor(TEST, panicker.clone())
+                .with_executor(STABLE, recorder.clone())
+                .with_executor_panic_limit(3),
+        );
+        let send = |payload: &'static [u8]| {
+            let node = node.clone();
+            // on a worker, so a panic escaping the node would fail the join
+            tokio::spawn(async move { node.send(message(addr("b"), payload), addr("b")).await })
+        };
+        let early = send(b"early").await.unwrap();
+        assert!(
+            matches!(early, Err(SendError::ExecutorPanicked { message }) if message == "early")
+        );
+        let late = send(b"x").await.unwrap();
+        assert!(
+            matches!(late, Err(SendError::ExecutorPanicked { message }) if message == "late 1")
+        );
+        assert_eq!(node.executor_panics(&TEST), 2);
+
+        // other protocols are unaffected
+        let stable = Address::new(STABLE, Identity::new("b"));
+        node.send(message(stable.clone(), b"x"), stable)
+            .await
+            .unwrap();
+        assert_eq!(recorder.sent().len(), 1);
+
+        let third = send(b"x").await.unwrap();
+        assert!(matches!(third, Err(SendError::ExecutorPanicked { .. })));
+        let unavailable = send(b"x").await.unwrap();
+        assert!(matches!(unavailable, Err(SendError::ProtocolUnavailable(p)) if p == TEST));
+        assert_eq!(panicker.calls.load(Ordering::Relaxed), 3);
+        assert_eq!(node.metrics_snapshot().executor_panics["test"], 3);
+    }
+
+    #[tokio::test]
+    async fn registering_the_protocol_again_puts_it_back_in_service() {
+        let mut node = NodeInstance::new()
+            .with_executor(TEST, Panicker::default())
+            .with_executor_panic_limit(1);
+        let _ = node.send(message(addr("b"), b"x"), addr("b")).await;
+        assert!(matches!(
+            node.send(message(addr("b"), b"x"), addr("b")).await,
+            Err(SendError::ProtocolUnavailable(_))
+        ));
+        node.register_executor(TEST, Arc::new(Recorder::new()));
+        assert_eq!(node.executor_panics(&TEST), 0);
+        node.send(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! Bringing message paths into one form before they are hashed or signed.
+//!
+//! Relays fill in path nodes differently and long routes grow long paths.
+//! [`Message::canonicalize_path`] tidies a path up according to a
+//! [`PathCanonicalization`]; a node [configured](NodeInstance::with_path_canonicalization)
+//! with one applies it to every message it [marks](NodeInstance::mark), before
+//! adding and signing its own path node. Note that dropping or changing hops
+//! breaks the [path signatures](NodeInstance::with_path_signer) of the hops
+//! after them. [`Message::path_digest`] hashes a path independently of how its
+//! nodes were filled in. Messages are encoded the same whether or not their path
+//! was canonicalized.
+
+use sha2::{Digest, Sha256};
+
+use crate::{wire::Writer, Message, NodeInstance, PathNode};
+
+/// Set, empty, on messages whose path had timestamps
+/// [clamped](PathCanonicalization::monotonic_ts).
+pub const PATH_CLAMPED_HEADER: &str = "anytape-path-clamped";
+
+/// What [`Message::canonicalize_path`] does; by default nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PathCanonicalization {
+    /// Keep only the first of consecutive path nodes with the same address.
+    pub dedup_consecutive: bool,
+    /// Keep only this many of the most recent anonymous path nodes that carry
+    /// nothing at all.
+    pub retain_empty_anonymous: Option<usize>,
+    /// Raise timestamps earlier than one before them to it, and mark the
+    /// message with [`PATH_CLAMPED_HEADER`] if any was. Anonymous path nodes,
+    /// which record no time, are left alone.
+    pub monotonic_ts: bool,
+}
+
+impl PathCanonicalization {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_dedup_consecutive(self) -> Self {
+        Self {
+            dedup_consecutive: true,
+            ..self
+        }
+    }
+    pub fn with_retain_empty_anonymous(self, retain: usize) -> Self {
+        Self {
+            retain_empty_anonymous: Some(retain),
+            ..self
+        }
+    }
+    pub fn with_monotonic_ts(self) -> Self {
+        Self {
+            monotonic_ts: true,
+            ..self
+        }
+    }
+}
+
+fn is_empty(node: &PathNode) -> bool {
+    *node == PathNode::new()
+}
+
+impl Message {
+    /// Tidy up the path according to `policy`; see the [module docs](self).
+    pub fn canonicalize_path(&mut self, policy: PathCanonicalization) {
+        if policy.monotonic_ts {
+            let mut latest = 0;
+            let mut clamped = false;
+            for node in self.path.iter_mut().filter(|node| node.address.is_some()) {
+                if node.ts < latest {
+                    node.ts = latest;
+                    clamped = true;
+                }
+                latest = node.ts;
+            }
+            if clamped {
+                self.headers
+                    .insert(PATH_CLAMPED_HEADER.to_owned(), Vec::new());
+            }
+        }
+        if policy.dedup_consecutive {
+            self.path.dedup_by(|node, previous| {
+                node.address.is_some() && node.address == previous.address
+            });
+        }
+        if let Some(retain) = policy.retain_empty_anonymous {
+            let mut excess = self
+                .path
+                .iter()
+                .filter(|node| is_empty(node))
+                .count()
+                .saturating_sub(retain);
+            self.path.retain(|node| {
+                if excess > 0 && is_empty(node) {
+                    excess -= 1;
+                    return false;
+                }
+                true
+            });
+        }
+    }
+    /// SHA-256 of the hops of the path: their names, addresses and timestamps.
+    ///
+    /// Signatures and [remaining budgets](PathNode::remaining_budget_ms) are left
+    /// out and an empty name counts as none, so paths that went through the same
+    /// hops at the same times have the same digest however the relays filled
+    /// them in.
+    pub fn path_digest(&self) -> [u8; 32] {
+        let mut w = Writer::new();
+        w.put_varint(self.path.len() as u64);
+        for node in &self.path {
+            w.put_bytes(node.name.as_deref().unwrap_or_default().as_bytes());
+            match &node.address {
+                Some(address) => {
+                    w.put_u8(1);
+                    w.put_address(address);
+                }
+                None => w.put_u8(0),
+            }
+            w.put_u64(node.ts);
+        }
+        Sha256::digest(w.finish()).into()
+    }
+}
+
+impl NodeInstance {
+    /// [Canonicalize](Message::canonicalize_path) the path of every message this
+    /// node marks, before adding its own path node.
+    pub fn with_path_canonicalization(mut self, policy: PathCanonicalization) -> Self {
+        self.path_canonicalization = Some(policy);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message};
+
+    fn hop(name: &str, ts: u64) -> PathNode {
+        PathNode::new().with_address(addr(name)).with_ts(ts)
+    }
+
+    fn with_path(path: Vec<PathNode>) -> Message {
+        let mut message = message(addr("d"), b"x");
+        message.path = path;
+        message
+    }
+
+    /// The address and timestamp of every hop, `?` for anonymous ones.
+    fn hops(message: &Message) -> Vec<(String, u64)> {
+        message
+            .path
+            .iter()
+            .map(|node| match &node.address {
+                Some(address) => (
+                    String::from_utf8_lossy(address.identity.as_bytes()).into(),
+                    node.ts,
+                ),
+                None => ("?".to_owned(), node.ts),
+            })
+            .collect()
+    }
+
+    fn expect(pairs: &[(&str, u64)]) -> Vec<(String, u64)> {
+        pairs
+            .iter()
+            .map(|(name, ts)| (name.to_string(), *ts))
+            .collect()
+    }
+
+    #[test]
+    fn the_default_policy_changes_nothing() {
+        let path = vec![hop("a", 5), hop("a", 1), PathNode::new()];
+        let mut message = with_path(path.clone());
+        message.canonicalize_path(PathCanonicalization::new());
+        assert!(message.path == path);
+        assert!(!message.headers.contains_key(PATH_CLAMPED_HEADER));
+    }
+
+    #[test]
+    fn consecutive_hops_at_one_address_are_deduplicated() {
+        let mut message = with_path(vec![
+            hop("a", 1),
+            hop("a", 2),
+            hop("b", 3),
+            PathNode::new(),
+            PathNode::new(),
+            hop("a", 4),
+        ]);
+        message.canonicalize_path(PathCanonicalization::new().with_dedup_consecutive());
+        assert_eq!(
+            hops(&message),
+            expect(&[("a", 1), ("b", 3), ("?", 0), ("?", 0), ("a", 4)])
+        );
+    }
+
+    #[test]
+    fn only_the_latest_empty_anonymous_hops_are_kept() {
+        let named_anonymous = PathNode::new().with_name("relay");
+        let mut message = with_path(vec![
+            PathNode::new(),
+            hop("a", 1),
+            PathNode::new(),
+            named_anonymous.clone(),
+            PathNode::new(),
+        ]);
+        message.canonicalize_path(PathCanonicalization::new().with_retain_empty_anonymous(1));
+        assert_eq!(hops(&message), expect(&[("a", 1), ("?", 0), ("?", 0)]));
+        assert!(message.path[1] == named_anonymous);
+    }
+
+    #[test]
+    fn earlier_timestamps_are_clamped_and_flagged() {
+        let mut message = with_path(vec![
+            hop("a", 10),
+            PathNode::new(),
+            hop("b", 7),
+            hop("c", 12),
+        ]);
+        message.canonicalize_path(PathCanonicalization::new().with_monotonic_ts());
+        assert_eq!(
+            hops(&message),
+            expect(&[("a", 10), ("?", 0), ("b", 10), ("c", 12)])
+        );
+        assert!(message.headers.contains_key(PATH_CLAMPED_HEADER));
+
+        let mut ordered = with_path(vec![hop("a", 1), hop("b", 2)]);
+        ordered.canonicalize_path(PathCanonicalization::new().with_monotonic_ts());
+        assert!(!ordered.headers.contains_key(PATH_CLAMPED_HEADER));
+    }
+
+    #[test]
+    fn digests_ignore_how_hops_were_filled_in() {
+        let plain = with_path(vec![hop("a", 1), hop("b", 2)]);
+        let mut filled = with_path(vec![hop("a", 1).with_remaining_budget_ms(90), hop("b", 2)]);
+        filled.path[1].sig = Some(vec![1, 2, 3]);
+        filled.path[0].name = Some(String::new());
+        filled.payload = b"other".to_vec();
+        assert_eq!(plain.path_digest(), filled.path_digest());
+        assert_eq!(plain.path_digest(), plain.clone().path_digest());
+    }
+
+    #[test]
+    fn digests_change_with_any_hop() {
+        let base = with_path(vec![hop("a", 1), hop("b", 2)]);
+        for changed in [
+            vec![hop("a", 1), hop("c", 2)],
+            vec![hop("a", 1), hop("b", 3)],
+            vec![hop("a", 1).with_name("edge"), hop("b", 2)],
+            vec![hop("b", 2), hop("a", 1)],
+            vec![hop("a", 1)],
+            vec![hop("a", 1), hop("b", 2), PathNode::new()],
+        ] {
+            assert_ne!(with_path(changed).path_digest(), base.path_digest());
+        }
+    }
+
+    #[test]
+    fn canonicalizing_nodes_tidy_the_path_before_marking() {
+        let node = NodeInstance::new()
+            .with_path_canonicalization(PathCanonicalization::new().with_dedup_consecutive());
+        let mut message = with_path(vec![hop("a", 1), hop("a", 2)]);
+        node.mark(addr("me"), &mut message);
+        assert_eq!(hops(&message).len(), 2);
+        assert_eq!(message.path[0].address, Some(addr("a")));
+        assert_eq!(message.path[1].address, Some(addr("me")));
+    }
+}
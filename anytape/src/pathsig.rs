@@ -0,0 +1,227 @@
+//! Signed paths, so a recipient can check which hops a message went through.
+//!
+//! A node with a [path signer](NodeInstance::with_path_signer) signs the path
+//! node it [marks](NodeInstance::mark) a message with. What it signs is a digest
+//! of its address, its timestamp and a hash of everything before it in the path:
+//! the destination and `unique_id` of the message, then every earlier path node
+//! including its signature. Changing, inserting or dropping any hop therefore
+//! breaks the signature of every hop after it, which
+//! [`Message::verify_path_chain`] detects.
+
+use std::{error::Error, fmt, sync::Arc};
+
+use sha2::{Digest, Sha256};
+
+use crate::{wire::Writer, Address, Message, NodeInstance, PathNode};
+
+/// Signs the path nodes of the node it is given to.
+pub trait Signer: Send + Sync {
+    fn sign(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Checks path node signatures of other nodes.
+pub trait Verifier: Send + Sync {
+    /// Whether `signature` is `signer`'s signature of `data`.
+    fn verify(&self, signer: &Address, data: &[u8], signature: &[u8]) -> bool;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathChainError {
+    /// The path node at `index` has no address to check its signature against.
+    Anonymous { index: usize },
+    /// The path node at `index` is not signed.
+    Unsigned { index: usize },
+    /// The signature of the path node at `index` does not match.
+    InvalidSignature { index: usize },
+}
+
+impl fmt::Display for PathChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathChainError::Anonymous { index } => write!(f, "path node {index} is anonymous"),
+            PathChainError::Unsigned { index } => write!(f, "path node {index} is not signed"),
+            PathChainError::InvalidSignature { index } => {
+                write!(f, "path node {index} has an invalid signature")
+            }
+        }
+    }
+}
+
+impl Error for PathChainError {}
+
+type ChainHash = [u8; 32];
+
+fn chain_start(message: &Message) -> ChainHash {
+    let mut w = Writer::new();
+    w.put_address(&message.destination);
+    w.put_u64(message.unique_id);
+    Sha256::digest(w.finish()).into()
+}
+
+/// The hash of the path up to and including `node`.
+fn chain_next(previous: &ChainHash, node: &PathNode) -> ChainHash {
+    let mut w = Writer::new();
+    w.put_bytes(previous);
+    w.put_bytes(node.name.as_deref().unwrap_or_default().as_bytes());
+    match &node.address {
+        Some(address) => {
+            w.put_u8(1);
+            w.put_address(address);
+        }
+        None => w.put_u8(0),
+    }
+    w.put_u64(node.ts);
+    w.put_bytes(node.sig.as_deref().unwrap_or_default());
+    Sha256::digest(w.finish()).into()
+}
+
+/// What the node at `address` signs when it joins a path hashing to `previous`.
+fn signing_input(previous: &ChainHash, address: &Address, ts: u64) -> ChainHash {
+    let mut w = Writer::new();
+    w.put_bytes(previous);
+    w.put_address(address);
+    w.put_u64(ts);
+    Sha256::digest(w.finish()).into()
+}
+
+impl Message {
+    /// Check that every path node is signed by the node whose address it names,
+    /// and that no hop was changed since; see the [module docs](crate::pathsig).
+    pub fn verify_path_chain(&self, verifier: &dyn Verifier) -> Result<(), PathChainError> {
+        let mut hash = chain_start(self);
+        for (index, node) in self.path.iter().enumerate() {
+            let Some(address) = &node.address else {
+                return Err(PathChainError::Anonymous { index });
+            };
+            let Some(sig) = &node.sig else {
+                return Err(PathChainError::Unsigned { index });
+            };
+            let input = signing_input(&hash, address, node.ts);
+            if !verifier.verify(address, &input, sig) {
+                return Err(PathChainError::InvalidSignature { index });
+            }
+            hash = chain_next(&hash, node);
+        }
+        Ok(())
+    }
+}
+
+impl NodeInstance {
+    /// Sign every path node this node adds with `signer`. Anonymous nodes add
+    /// unsigned path nodes regardless.
+    pub fn with_path_signer(self, signer: impl Signer + 'static) -> Self {
+        Self {
+            path_signer: Some(Arc::new(signer)),
+            ..self
+        }
+    }
+    /// Sign `node`, which is about to be appended to the path of `message`.
+    pub(crate) fn sign_path_node(&self, message: &Message, node: &mut PathNode) {
+        let (Some(signer), Some(address)) = (&self.path_signer, &node.address) else {
+            return;
+        };
+        let hash = message
+            .path
+            .iter()
+            .fold(chain_start(message), |hash, node| chain_next(&hash, node));
+        node.sig = Some(signer.sign(&signing_input(&hash, address, node.ts)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message};
+    use crate::wire::{UnknownField, EXTENSION_PATH_SIGNATURES};
+
+    /// Stands in for a key pair: a node's signature is a hash of its address
+    /// and the data.
+    struct Keyed(Address);
+
+    fn keyed_signature(signer: &Address, data: &[u8]) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_address(signer);
+        w.put_bytes(data);
+        Sha256::digest(w.finish()).to_vec()
+    }
+
+    impl Signer for Keyed {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            keyed_signature(&self.0, data)
+        }
+    }
+
+    struct KeyedVerifier;
+
+    impl Verifier for KeyedVerifier {
+        fn verify(&self, signer: &Address, data: &[u8], signature: &[u8]) -> bool {
+            keyed_signature(signer, data) == signature
+        }
+    }
+
+    fn signed_through(hops: &[&str]) -> Message {
+        let mut message = message(addr("dest"), b"x");
+        for hop in hops {
+            let node = NodeInstance::new().with_path_signer(Keyed(addr(hop)));
+            node.mark(addr(hop), &mut message);
+        }
+        message
+    }
+
+    #[test]
+    fn a_signed_chain_verifies_across_the_wire() {
+        let message = signed_through(&["a", "b"]);
+        assert_eq!(message.verify_path_chain(&KeyedVerifier), Ok(()));
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.verify_path_chain(&KeyedVerifier), Ok(()));
+    }
+
+    #[test]
+    fn a_tampered_middle_hop_is_detected() {
+        let mut message = signed_through(&["a", "b", "c"]);
+        message.path[1].ts += 1;
+        assert_eq!(
+            message.verify_path_chain(&KeyedVerifier),
+            Err(PathChainError::InvalidSignature { index: 1 })
+        );
+
+        let mut message = signed_through(&["a", "b", "c"]);
+        message.path[1].address = Some(addr("mallory"));
+        assert_eq!(
+            message.verify_path_chain(&KeyedVerifier),
+            Err(PathChainError::InvalidSignature { index: 1 })
+        );
+
+        let mut message = signed_through(&["a", "b", "c"]);
+        message.path.remove(1);
+        assert_eq!(
+            message.verify_path_chain(&KeyedVerifier),
+            Err(PathChainError::InvalidSignature { index: 1 })
+        );
+    }
+
+    #[test]
+    fn hops_added_without_the_signature_tag_decode_unsigned() {
+        let signed = signed_through(&["a"]);
+        // a relay that does not know the tag keeps it as is and appends its hop
+        let mut value = Writer::new();
+        value.put_varint(1);
+        value.put_u8(1);
+        value.put_bytes(signed.path[0].sig.as_deref().unwrap());
+        let mut relayed = signed.clone();
+        relayed.path[0].sig = None;
+        relayed.path.push(PathNode::new().with_address(addr("old")));
+        relayed.extensions.push(UnknownField {
+            tag: EXTENSION_PATH_SIGNATURES,
+            value: value.finish(),
+        });
+
+        let decoded = Message::decode(&relayed.encode()).unwrap();
+        assert_eq!(decoded.path[0].sig, signed.path[0].sig);
+        assert_eq!(decoded.path[1].sig, None);
+        assert_eq!(
+            decoded.verify_path_chain(&KeyedVerifier),
+            Err(PathChainError::Unsigned { index: 1 })
+        );
+    }
+}
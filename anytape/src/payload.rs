@@ -0,0 +1,195 @@
+//! Typed payloads, serialized with serde.
+//!
+//! [`MessageBuilder::payload_of`] records the format it used in
+//! [`CONTENT_TYPE_HEADER`], so [`Message::payload_as`] picks the right one on
+//! the way out; messages without the header are read as JSON.
+
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Message, MessageBuilder, CONTENT_TYPE_HEADER};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl PayloadFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "json",
+            #[cfg(feature = "cbor")]
+            PayloadFormat::Cbor => "cbor",
+        }
+    }
+    pub fn from_content_type(content_type: &[u8]) -> Option<Self> {
+        match content_type {
+            b"json" => Some(PayloadFormat::Json),
+            #[cfg(feature = "cbor")]
+            b"cbor" => Some(PayloadFormat::Cbor),
+            _ => None,
+        }
+    }
+    pub fn serialize<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, PayloadError> {
+        match self {
+            PayloadFormat::Json => serde_json::to_vec(value).map_err(PayloadError::Json),
+            #[cfg(feature = "cbor")]
+            PayloadFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)
+                    .map_err(|e| PayloadError::Cbor(e.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, PayloadError> {
+        match self {
+            PayloadFormat::Json => serde_json::from_slice(bytes).map_err(PayloadError::Json),
+            #[cfg(feature = "cbor")]
+            PayloadFormat::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| PayloadError::Cbor(e.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    /// The content type header names a format this build does not support.
+    UnknownFormat(Vec<u8>),
+    Json(serde_json::Error),
+    #[cfg(feature = "cbor")]
+    Cbor(String),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::UnknownFormat(content_type) => write!(
+                f,
+                "unknown payload format {:?}",
+                String::from_utf8_lossy(content_type)
+            ),
+            PayloadError::Json(e) => write!(f, "json payload: {e}"),
+            #[cfg(feature = "cbor")]
+            PayloadError::Cbor(e) => write!(f, "cbor payload: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PayloadError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    /// The format of the payload according to [`CONTENT_TYPE_HEADER`], JSON if unset.
+    pub fn payload_format(&self) -> Result<PayloadFormat, PayloadError> {
+        match self.headers.get(CONTENT_TYPE_HEADER) {
+            Some(content_type) => PayloadFormat::from_content_type(content_type)
+                .ok_or_else(|| PayloadError::UnknownFormat(content_type.clone())),
+            None => Ok(PayloadFormat::Json),
+        }
+    }
+    /// Deserialize the payload in the format it was built with.
+    pub fn payload_as<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        self.payload_format()?.deserialize(&self.payload)
+    }
+}
+
+impl MessageBuilder {
+    /// Serialize `value` as the JSON payload.
+    pub fn payload_of<T: Serialize + ?Sized>(self, value: &T) -> Result<Self, PayloadError> {
+        self.payload_of_with(value, PayloadFormat::Json)
+    }
+    /// Serialize `value` as the payload in `format`.
+    pub fn payload_of_with<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+        format: PayloadFormat,
+    ) -> Result<Self, PayloadError> {
+        let payload = format.serialize(value)?;
+        Ok(self
+            .payload(payload)
+            .header(CONTENT_TYPE_HEADER, format.content_type()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testing::addr;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        celsius: f64,
+        tags: Vec<String>,
+        previous: Option<Box<Reading>>,
+    }
+
+    fn reading() -> Reading {
+        Reading {
+            sensor: "roof".to_owned(),
+            celsius: -3.5,
+            tags: vec!["outdoor".to_owned()],
+            previous: Some(Box::new(Reading {
+                sensor: "roof".to_owned(),
+                celsius: -2.0,
+                tags: Vec::new(),
+                previous: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn custom_structs_round_trip_through_the_payload() {
+        let message = MessageBuilder::new(addr("b"))
+            .payload_of(&reading())
+            .unwrap()
+            .build();
+        assert_eq!(message.payload_format().unwrap(), PayloadFormat::Json);
+        assert_eq!(message.payload_as::<Reading>().unwrap(), reading());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn the_content_type_picks_the_format_on_the_way_out() {
+        let message = MessageBuilder::new(addr("b"))
+            .payload_of_with(&reading(), PayloadFormat::Cbor)
+            .unwrap()
+            .build();
+        assert!(serde_json::from_slice::<Reading>(&message.payload).is_err());
+        assert_eq!(message.payload_as::<Reading>().unwrap(), reading());
+    }
+
+    #[test]
+    fn unknown_formats_and_mismatched_types_are_errors() {
+        let untyped = MessageBuilder::new(addr("b"))
+            .payload(r#"{"sensor":"roof"}"#)
+            .build();
+        assert!(matches!(
+            untyped.payload_as::<Reading>(),
+            Err(PayloadError::Json(_))
+        ));
+        let unknown = MessageBuilder::new(addr("b"))
+            .payload_of(&reading())
+            .unwrap()
+            .header(CONTENT_TYPE_HEADER, "yaml")
+            .build();
+        assert!(matches!(
+            unknown.payload_as::<Reading>(),
+            Err(PayloadError::UnknownFormat(content_type)) if content_type == b"yaml"
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! Reserving send capacity ahead of time.
+//!
+//! With a [limit on sends in flight](NodeInstance::with_max_in_flight), every
+//! send holds a permit while it runs. [`NodeInstance::ready`] hands out such a
+//! permit up front so callers can shed load by timing out on it, then commit the
+//! message with [`NodeInstance::send_reserved`].
+
+use std::{fmt, sync::Arc};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{Address, Message, NodeInstance, SendError};
+
+/// Capacity for one send, released when dropped.
+pub struct SendPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl fmt::Debug for SendPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendPermit").finish_non_exhaustive()
+    }
+}
+
+impl NodeInstance {
+    /// Let at most `max` sends run at once; further sends wait for a permit.
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+    /// Wait until a send may start and reserve its capacity. Fails with
+    /// [`SendError::Shutdown`] once the node is shut down.
+    pub async fn ready(&self) -> Result<SendPermit, SendError> {
+        if self.is_shut_down() {
+            return Err(SendError::Shutdown);
+        }
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(
+                in_flight
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| SendError::Shutdown)?,
+            ),
+            None => None,
+        };
+        Ok(SendPermit { _permit: permit })
+    }
+    /// Like [`NodeInstance::send`], but runs on a permit from
+    /// [`NodeInstance::ready`] instead of waiting for one. Group addresses are not
+    /// multicast.
+    pub async fn send_reserved(
+        &self,
+        permit: SendPermit,
+        message: Message,
+        to: impl Into<Address>,
+    ) -> Result<(), SendError> {
+        let to = to.into();
+        let unique_id = message.unique_id;
+        let path = self.path_for_report(&message);
+        let sheddable = crate::shed::is_sheddable(&message);
+        let result = self
+            .shed_or_send(sheddable, &to, self.send_permitted(message, &to))
+            .await;
+        drop(permit);
+        self.report_send(&to, unique_id, path, &result);
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+
+    #[tokio::test(start_paused = true)]
+    async fn ready_pends_while_every_permit_is_held() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_max_in_flight(2);
+        let permits = [node.ready().await.unwrap(), node.ready().await.unwrap()];
+        let third = tokio::time::timeout(Duration::from_secs(1), node.ready()).await;
+        assert!(third.is_err());
+
+        // committing a send gives its permit back
+        let [first, _second] = permits;
+        node.send_reserved(first, message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        let third = tokio::time::timeout(Duration::from_secs(1), node.ready()).await;
+        assert!(third.unwrap().is_ok());
+        assert_eq!(recorder.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unlimited_nodes_are_always_ready() {
+        let node = NodeInstance::new();
+        let permits: Vec<_> = (0..100).map(|_| node.ready()).collect();
+        for permit in permits {
+            permit.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Source routing: messages that name every relay they must pass through.
+//!
+//! A message with a [`route_plan`](Message::route_plan) is forwarded to the
+//! planned hop after the last one it already visited, whatever the relay's own
+//! routes say. The plan travels unchanged so the receiver can check it against
+//! the path.
+
+use crate::{Address, Message, NodeInstance, SendError};
+
+impl NodeInstance {
+    /// When the next hop of a route plan cannot be reached, route the message
+    /// dynamically instead of failing with [`SendError::PlannedHopUnreachable`].
+    pub fn with_route_plan_fallback(mut self, allow: bool) -> Self {
+        self.plan_fallback = allow;
+        self
+    }
+    /// The planned hop after the last planned hop the message visited, counting
+    /// this node as visited. `None` for messages without a plan.
+    pub(crate) fn planned_next(&self, message: &Message) -> Result<Option<Address>, SendError> {
+        let Some(plan) = &message.route_plan else {
+            return Ok(None);
+        };
+        if plan.last() != Some(&message.destination) {
+            return Err(SendError::InvalidRoutePlan);
+        }
+        let visited = plan.iter().rposition(|hop| {
+            self.is_local(hop)
+                || message
+                    .path
+                    .iter()
+                    .any(|node| node.address.as_ref() == Some(hop))
+        });
+        let next = visited.map_or(0, |index| index + 1);
+        plan.get(next)
+            .cloned()
+            .map(Some)
+            .ok_or(SendError::InvalidRoutePlan)
+    }
+    pub(crate) async fn forward_planned(
+        &self,
+        mut message: Message,
+        hop: Address,
+    ) -> Result<(), SendError> {
+        let fallback = self.plan_fallback.then(|| message.clone());
+        self.transform_for(&mut message, &hop)
+            .await
+            .map_err(SendError::Transform)?;
+        match self.send(message, hop.clone()).await {
+            Err(error) if is_unreachable(&error) => match fallback {
+                Some(message) => self.forward_dynamic(message).await,
+                None => Err(SendError::PlannedHopUnreachable {
+                    hop,
+                    error: Box::new(error),
+                }),
+            },
+            result => result,
+        }
+    }
+}
+
+pub(crate) fn is_unreachable(error: &SendError) -> bool {
+    matches!(
+        error,
+        SendError::ExecutorError(_) | SendError::ProtocolNotSupport { .. } | SendError::NoRoute
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::virtual_net::VirtualNetwork;
+    use crate::{DataBackend, Identity, MemoryBackend, MessageBuilder, MessageStatus};
+
+    fn at(name: &str) -> Address {
+        VirtualNetwork::address(name)
+    }
+
+    /// A backend routing `destination` via `next`.
+    async fn route(destination: &str, next: &str) -> MemoryBackend {
+        let backend = MemoryBackend::new();
+        backend
+            .set_next(&at(destination), Some(&at(next)))
+            .await
+            .unwrap();
+        backend
+    }
+
+    fn planned(plan: &[&str]) -> Message {
+        MessageBuilder::new(at(plan.last().unwrap()))
+            .payload("x")
+            .route_plan(plan.iter().copied().map(at).collect())
+            .build()
+    }
+
+    fn visited(hops: &[crate::virtual_net::Delivery]) -> Vec<Identity> {
+        hops.iter().map(|hop| hop.to.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn plans_are_followed_whatever_the_relays_routes_say() {
+        let mut net = VirtualNetwork::new();
+        let sender = net.add_node("sender", |node| node);
+        for relay in ["r1", "r2", "r3"] {
+            let backend = route("dest", "decoy").await;
+            net.add_node(relay, |node| node.with_backend(backend));
+        }
+        net.add_node("decoy", |node| node);
+        net.add_node("dest", |node| node.with_handler(|_| async {}));
+
+        let mut message = planned(&["r2", "r1", "r3", "dest"]);
+        sender.mark(at("sender"), &mut message);
+        sender.forward(message).await.unwrap();
+        let hops = net.run_until_idle(10).await;
+        assert_eq!(
+            visited(&hops),
+            ["r2", "r1", "r3", "dest"].map(Identity::new)
+        );
+        assert!(matches!(hops[3].result, Ok(MessageStatus::Received)));
+    }
+
+    #[tokio::test]
+    async fn a_broken_middle_hop_fails_the_message_unless_fallback_is_on() {
+        for fallback in [false, true] {
+            let mut net = VirtualNetwork::new();
+            let reports = Arc::new(Mutex::new(Vec::new()));
+            let backend = route("dest", "detour").await;
+            let sender = net.add_node("sender", |node| node);
+            net.add_node("r1", |node| {
+                let reports = reports.clone();
+                node.with_backend(backend)
+                    .with_route_plan_fallback(fallback)
+                    .with_on_send_err(move |to, _, _| reports.lock().unwrap().push(to.clone()))
+            });
+            // "r2" is not in the network
+            net.add_node("r3", |node| node);
+            // the plan still names "r2" after the detour, so it falls back too
+            net.add_node("detour", |node| node.with_route_plan_fallback(fallback));
+            net.add_node("dest", |node| node.with_handler(|_| async {}));
+
+            sender
+                .forward(planned(&["r1", "r2", "r3", "dest"]))
+                .await
+                .unwrap();
+            let hops = net.run_until_idle(10).await;
+            if fallback {
+                assert_eq!(visited(&hops), ["r1", "detour", "dest"].map(Identity::new));
+                assert!(matches!(hops[2].result, Ok(MessageStatus::Received)));
+            } else {
+                assert_eq!(visited(&hops), [Identity::new("r1")]);
+                let Err(SendError::PlannedHopUnreachable { hop, .. }) = &hops[0].result else {
+                    panic!("expected the planned hop to be unreachable");
+                };
+                assert_eq!(*hop, at("r2"));
+            }
+            // the failed send to the planned hop is reported either way
+            assert_eq!(*reports.lock().unwrap(), [at("r2")]);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Tunnelling outbound TCP connections through a SOCKS5 or HTTP CONNECT proxy.
+
+use std::{fmt, io, net::IpAddr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const MAX_HTTP_RESPONSE_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyConfig {
+    /// A SOCKS5 proxy at `addr` (`host:port`), optionally with username/password auth.
+    Socks5 {
+        addr: String,
+        auth: Option<ProxyCredentials>,
+    },
+    /// An HTTP proxy at `addr` (`host:port`) tunnelling with `CONNECT`, optionally
+    /// with basic auth.
+    HttpConnect {
+        addr: String,
+        auth: Option<ProxyCredentials>,
+    },
+}
+
+/// A failure talking to the proxy, as opposed to the destination behind it.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The proxy itself could not be reached.
+    Unreachable(io::Error),
+    /// The connection to the proxy failed during the handshake.
+    Io(io::Error),
+    /// The SOCKS5 proxy accepted none of the offered authentication methods.
+    NoAcceptableAuth,
+    /// The proxy rejected the credentials.
+    AuthFailed,
+    /// The SOCKS5 proxy refused the CONNECT with this reply code.
+    Socks5Refused(u8),
+    /// The HTTP proxy answered CONNECT with a non-2xx status.
+    HttpStatus(u16),
+    /// The proxy sent something that is not valid for its protocol.
+    Malformed(&'static str),
+    /// The destination host cannot be expressed in the proxy protocol.
+    InvalidTarget,
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Unreachable(e) => write!(f, "proxy unreachable: {e}"),
+            ProxyError::Io(e) => write!(f, "proxy connection failed: {e}"),
+            ProxyError::NoAcceptableAuth => write!(f, "proxy accepted no offered auth method"),
+            ProxyError::AuthFailed => write!(f, "proxy rejected credentials"),
+            ProxyError::Socks5Refused(code) => write!(f, "socks5 connect refused ({code})"),
+            ProxyError::HttpStatus(status) => write!(f, "proxy answered CONNECT with {status}"),
+            ProxyError::Malformed(what) => write!(f, "malformed proxy response: {what}"),
+            ProxyError::InvalidTarget => write!(f, "destination cannot be sent to the proxy"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::Unreachable(e) | ProxyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        ProxyError::Io(e)
+    }
+}
+
+impl ProxyConfig {
+    pub fn addr(&self) -> &str {
+        match self {
+            ProxyConfig::Socks5 { addr, .. } | ProxyConfig::HttpConnect { addr, .. } => addr,
+        }
+    }
+
+    /// Open a tunnel to `host:port` through this proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, ProxyError> {
+        let mut stream = TcpStream::connect(self.addr())
+            .await
+            .map_err(ProxyError::Unreachable)?;
+        match self {
+            ProxyConfig::Socks5 { auth, .. } => {
+                socks5_handshake(&mut stream, auth.as_ref(), host, port).await?
+            }
+            ProxyConfig::HttpConnect { auth, .. } => {
+                http_connect(&mut stream, auth.as_ref(), host, port).await?
+            }
+        }
+        Ok(stream)
+    }
+}
+
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    auth: Option<&ProxyCredentials>,
+    host: &str,
+    port: u16,
+) -> Result<(), ProxyError> {
+    const VERSION: u8 = 5;
+    const NO_AUTH: u8 = 0x00;
+    const USERNAME_PASSWORD: u8 = 0x02;
+    const NO_ACCEPTABLE: u8 = 0xff;
+
+    let greeting: &[u8] = match auth {
+        Some(_) => &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD],
+        None => &[VERSION, 1, NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(ProxyError::Malformed("socks version"));
+    }
+    match (choice[1], auth) {
+        (NO_AUTH, _) => {}
+        (USERNAME_PASSWORD, Some(credentials)) => {
+            let (user, pass) = (
+                credentials.username.as_bytes(),
+                credentials.password.as_bytes(),
+            );
+            let (Ok(user_len), Ok(pass_len)) = (u8::try_from(user.len()), u8::try_from(pass.len()))
+            else {
+                return Err(ProxyError::AuthFailed);
+            };
+            let mut request = vec![1, user_len];
+            request.extend_from_slice(user);
+            request.push(pass_len);
+            request.extend_from_slice(pass);
+            stream.write_all(&request).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(ProxyError::AuthFailed);
+            }
+        }
+        (NO_ACCEPTABLE, _) | (USERNAME_PASSWORD, None) => return Err(ProxyError::NoAcceptableAuth),
+        _ => return Err(ProxyError::Malformed("socks auth method")),
+    }
+
+    let mut request = vec![VERSION, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| ProxyError::InvalidTarget)?;
+            request.push(0x03);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(ProxyError::Malformed("socks version"));
+    }
+    if reply[1] != 0 {
+        return Err(ProxyError::Socks5Refused(reply[1]));
+    }
+    // skip the bound address and port the proxy reports
+    let bound_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => usize::from(stream.read_u8().await?),
+        _ => return Err(ProxyError::Malformed("socks address type")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    auth: Option<&ProxyCredentials>,
+    host: &str,
+    port: u16,
+) -> Result<(), ProxyError> {
+    let authority = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = auth {
+        let token = base64(format!("{}:{}", credentials.username, credentials.password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing past the response head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+            return Err(ProxyError::Malformed("response head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status = std::str::from_utf8(&head)
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(ProxyError::Malformed("status line"))?;
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(ProxyError::AuthFailed),
+        status => Err(ProxyError::HttpStatus(status)),
+    }
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,566 @@
+//! QUIC transport built on [quinn].
+//!
+//! Identities are `host:port` strings. One connection is kept per remote and
+//! every message travels on its own unidirectional stream, so a slow or lossy
+//! message never blocks the ones sent after it.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Endpoint,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    resolve::{DnsResolver, ResolveError, Resolver, DEFAULT_CONNECT_TIMEOUT},
+    transport::{StatusTable, DEFAULT_STATUS_CAPACITY},
+    typed_address::{self, SocketIdentity},
+    wire::{CompactFormat, WireFormat},
+    ExecutorCapabilities, Identity, Message, MessageStatus, Protocol, ProtocolExecutor,
+    SendOutcome,
+};
+
+const ALPN: &[u8] = b"anytape";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+impl Protocol {
+    pub const QUIC: Protocol = typed_address::QUIC;
+}
+
+/// How the client side checks the certificate presented by a remote.
+pub enum CertVerification {
+    /// Verify the chain against the given trust anchors.
+    Roots(RootCertStore),
+    /// Accept only these exact end-entity certificates.
+    Pinned(Vec<CertificateDer<'static>>),
+    /// Accept any certificate. Only meant for tests and local development.
+    InsecureSkipVerify,
+}
+
+#[derive(Debug)]
+pub enum QuicError {
+    InvalidIdentity(Identity),
+    /// The identity could not be resolved to any address.
+    Unresolvable(ResolveError),
+    Io(std::io::Error),
+    Tls(rustls::Error),
+    Crypto(String),
+    Connect(quinn::ConnectError),
+    Connection(quinn::ConnectionError),
+    Write(quinn::WriteError),
+    ClosedStream(quinn::ClosedStream),
+    Stopped(quinn::StoppedError),
+    /// The remote stopped the stream with an application error code before acknowledging it.
+    RejectedByRemote(u64),
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::InvalidIdentity(identity) => {
+                write!(f, "identity {identity:?} is not a host:port pair")
+            }
+            QuicError::Unresolvable(e) => write!(f, "{e}"),
+            QuicError::Io(e) => write!(f, "io error: {e}"),
+            QuicError::Tls(e) => write!(f, "tls configuration error: {e}"),
+            QuicError::Crypto(e) => write!(f, "quic crypto configuration error: {e}"),
+            QuicError::Connect(e) => write!(f, "failed to connect: {e}"),
+            QuicError::Connection(e) => write!(f, "connection lost: {e}"),
+            QuicError::Write(e) => write!(f, "failed to write stream: {e}"),
+            QuicError::ClosedStream(e) => write!(f, "stream closed: {e}"),
+            QuicError::Stopped(e) => write!(f, "stream was not acknowledged: {e}"),
+            QuicError::RejectedByRemote(code) => {
+                write!(f, "remote stopped the stream with code {code}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QuicError::Unresolvable(e) => Some(e),
+            QuicError::Io(e) => Some(e),
+            QuicError::Tls(e) => Some(e),
+            QuicError::Connect(e) => Some(e),
+            QuicError::Connection(e) => Some(e),
+            QuicError::Write(e) => Some(e),
+            QuicError::ClosedStream(e) => Some(e),
+            QuicError::Stopped(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type ConnectionSlot = Arc<tokio::sync::Mutex<Option<Connection>>>;
+
+pub struct QuicExecutor {
+    endpoint: Endpoint,
+    connections: Arc<Mutex<HashMap<Identity, ConnectionSlot>>>,
+    statuses: Arc<Mutex<StatusTable>>,
+    format: Arc<dyn WireFormat>,
+    resolver: Arc<dyn Resolver>,
+    connect_timeout: Duration,
+}
+
+impl QuicExecutor {
+    /// Create a client endpoint bound to `bind` (use port 0 for an ephemeral port).
+    pub fn new(bind: SocketAddr, verification: CertVerification) -> Result<Self, QuicError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(QuicError::Tls)?;
+        let mut crypto = match verification {
+            CertVerification::Roots(roots) => builder.with_root_certificates(roots),
+            CertVerification::Pinned(certs) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pinned: Some(certs),
+                    provider,
+                })),
+            CertVerification::InsecureSkipVerify => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pinned: None,
+                    provider,
+                })),
+        }
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        // resumption tickets are kept by the default in-memory store, which is what
+        // makes 0-RTT possible when reconnecting to a known remote
+        crypto.enable_early_data = true;
+        let crypto =
+            QuicClientConfig::try_from(crypto).map_err(|e| QuicError::Crypto(e.to_string()))?;
+        let mut endpoint = Endpoint::client(bind).map_err(QuicError::Io)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        Ok(Self {
+            endpoint,
+            connections: Default::default(),
+            statuses: Arc::new(Mutex::new(StatusTable::new(DEFAULT_STATUS_CAPACITY))),
+            format: Arc::new(CompactFormat),
+            resolver: Arc::new(DnsResolver),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        })
+    }
+
+    /// Encode outgoing messages with `format`; the listener must use the same one.
+    pub fn with_wire_format(self, format: impl WireFormat + 'static) -> Self {
+        Self {
+            format: Arc::new(format),
+            ..self
+        }
+    }
+
+    /// Look up remotes with `resolver` instead of the system's DNS. The host
+    /// part of the identity is still the name the certificate is checked for.
+    pub fn with_resolver(self, resolver: impl Resolver + 'static) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            ..self
+        }
+    }
+
+    /// Give up on a resolved address and try the next one when its handshake
+    /// takes longer than `timeout`.
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        Self {
+            connect_timeout: timeout,
+            ..self
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, QuicError> {
+        self.endpoint.local_addr().map_err(QuicError::Io)
+    }
+
+    fn slot(&self, remote: &Identity) -> ConnectionSlot {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(remote.clone())
+            .or_default()
+            .clone()
+    }
+}
+
+struct Connector {
+    endpoint: Endpoint,
+    resolver: Arc<dyn Resolver>,
+    connect_timeout: Duration,
+}
+
+async fn connection(
+    connector: &Connector,
+    slot: &ConnectionSlot,
+    remote: &Identity,
+) -> Result<Connection, QuicError> {
+    let mut slot = slot.lock().await;
+    if let Some(conn) = slot.as_ref() {
+        if conn.close_reason().is_none() {
+            return Ok(conn.clone());
+        }
+    }
+    let target =
+        SocketIdentity::try_from(remote).map_err(|_| QuicError::InvalidIdentity(remote.clone()))?;
+    let addrs = connector
+        .resolver
+        .resolve(remote)
+        .await
+        .map_err(QuicError::Unresolvable)?;
+    let mut last = QuicError::Unresolvable(ResolveError::NotFound(remote.clone()));
+    for addr in addrs {
+        let connecting = match connector.endpoint.connect(addr, target.host()) {
+            Ok(connecting) => connecting,
+            Err(e) => {
+                last = QuicError::Connect(e);
+                continue;
+            }
+        };
+        let conn = match connecting.into_0rtt() {
+            Ok((conn, _accepted)) => conn,
+            Err(connecting) => {
+                match tokio::time::timeout(connector.connect_timeout, connecting).await {
+                    Ok(Ok(conn)) => conn,
+                    Ok(Err(e)) => {
+                        last = QuicError::Connection(e);
+                        continue;
+                    }
+                    Err(_) => {
+                        last = QuicError::Connection(quinn::ConnectionError::TimedOut);
+                        continue;
+                    }
+                }
+            }
+        };
+        *slot = Some(conn.clone());
+        return Ok(conn);
+    }
+    Err(last)
+}
+
+async fn send_on(conn: &Connection, encoded: &[u8]) -> Result<(), QuicError> {
+    let mut stream = conn.open_uni().await.map_err(QuicError::Connection)?;
+    stream.write_all(encoded).await.map_err(QuicError::Write)?;
+    stream.finish().map_err(QuicError::ClosedStream)?;
+    match stream.stopped().await.map_err(QuicError::Stopped)? {
+        None => Ok(()),
+        Some(code) => Err(QuicError::RejectedByRemote(code.into_inner())),
+    }
+}
+
+impl ProtocolExecutor for QuicExecutor {
+    type Error = QuicError;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let connector = Connector {
+            endpoint: self.endpoint.clone(),
+            resolver: self.resolver.clone(),
+            connect_timeout: self.connect_timeout,
+        };
+        let slot = self.slot(remote);
+        let statuses = self.statuses.clone();
+        let encoded = self.format.encode(&message);
+        let remote = remote.clone();
+        async move {
+            let unique_id = message.unique_id;
+            let result = async {
+                let conn = connection(&connector, &slot, &remote).await?;
+                match send_on(&conn, &encoded).await {
+                    // the remote refused our early data; the handshake has completed by now,
+                    // so the same connection can carry the message in 1-RTT
+                    Err(QuicError::Write(quinn::WriteError::ZeroRttRejected))
+                    | Err(QuicError::Stopped(quinn::StoppedError::ZeroRttRejected)) => {
+                        send_on(&conn, &encoded).await
+                    }
+                    result => result,
+                }
+            }
+            .await;
+            let status = match result {
+                Ok(()) => MessageStatus::Sended,
+                Err(_) => MessageStatus::SendError,
+            };
+            statuses.lock().unwrap().set(unique_id, status);
+            result.map(|()| SendOutcome::sent())
+        }
+    }
+
+    fn get_status(
+        &self,
+        _remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let status = self
+            .statuses
+            .lock()
+            .unwrap()
+            .get(message.unique_id)
+            .unwrap_or(MessageStatus::Unreachable);
+        async move { Ok(status) }
+    }
+
+    /// Every message travels on its own stream, so messages are not ordered
+    /// relative to each other.
+    fn capabilities(&self) -> ExecutorCapabilities {
+        ExecutorCapabilities {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            ordered: false,
+            reliable: true,
+            supports_status: true,
+        }
+    }
+
+    /// Closes every pooled connection and waits until the remotes acknowledged.
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        let slots: Vec<_> = self.connections.lock().unwrap().drain().collect();
+        let endpoint = self.endpoint.clone();
+        async move {
+            for (_, slot) in slots {
+                if let Some(conn) = slot.lock().await.take() {
+                    conn.close(0u32.into(), b"executor closed");
+                }
+            }
+            endpoint.wait_idle().await;
+        }
+    }
+}
+
+/// Server side of the QUIC transport: accepts connections and streams and hands
+/// every decoded message to `sink`.
+pub struct QuicListenerTask {
+    endpoint: Endpoint,
+    sink: mpsc::Sender<Message>,
+    max_message_size: usize,
+    format: Arc<dyn WireFormat>,
+}
+
+impl QuicListenerTask {
+    pub fn bind(
+        addr: SocketAddr,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        sink: mpsc::Sender<Message>,
+    ) -> Result<Self, QuicError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(QuicError::Tls)?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .map_err(QuicError::Tls)?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        crypto.max_early_data_size = u32::MAX;
+        let crypto =
+            QuicServerConfig::try_from(crypto).map_err(|e| QuicError::Crypto(e.to_string()))?;
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = Endpoint::server(config, addr).map_err(QuicError::Io)?;
+        Ok(Self {
+            endpoint,
+            sink,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            format: Arc::new(CompactFormat),
+        })
+    }
+
+    pub fn with_wire_format(self, format: impl WireFormat + 'static) -> Self {
+        Self {
+            format: Arc::new(format),
+            ..self
+        }
+    }
+
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, QuicError> {
+        self.endpoint.local_addr().map_err(QuicError::Io)
+    }
+
+    /// Accept connections until the endpoint is closed or the sink is dropped.
+    pub async fn run(self) {
+        while let Some(incoming) = self.endpoint.accept().await {
+            if self.sink.is_closed() {
+                break;
+            }
+            let sink = self.sink.clone();
+            let max_message_size = self.max_message_size;
+            let format = self.format.clone();
+            tokio::spawn(async move {
+                let Ok(conn) = incoming.await else {
+                    return;
+                };
+                while let Ok(mut stream) = conn.accept_uni().await {
+                    let sink = sink.clone();
+                    let format = format.clone();
+                    tokio::spawn(async move {
+                        let Ok(bytes) = stream.read_to_end(max_message_size).await else {
+                            return;
+                        };
+                        // malformed streams are dropped; they never reach the sink
+                        if let Ok(message) = format.decode(&bytes) {
+                            let _ = sink.send(message).await;
+                        }
+                    });
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    /// `None` accepts every certificate.
+    pinned: Option<Vec<CertificateDer<'static>>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.pinned {
+            Some(pinned) if !pinned.iter().any(|cert| cert == end_entity) => {
+                Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use super::*;
+    use crate::{resolve::StaticResolver, Address, MessageBuilder};
+
+    fn certificate() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        (certified.cert.der().clone(), key.into())
+    }
+
+    /// A listener on localhost, its identity and what it receives.
+    fn listen(
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    ) -> (Identity, StaticResolver, mpsc::Receiver<Message>) {
+        let (sink, inbound) = mpsc::channel(8);
+        let any_port = "127.0.0.1:0".parse().unwrap();
+        let listener = QuicListenerTask::bind(any_port, vec![cert], key, sink).unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+        let identity = Identity::new(format!("localhost:{}", local.port()));
+        let resolver = StaticResolver::new().with_entry(identity.clone(), [local]);
+        (identity, resolver, inbound)
+    }
+
+    #[tokio::test]
+    async fn messages_reach_a_listener_with_a_pinned_certificate() {
+        let (cert, key) = certificate();
+        let (identity, resolver, mut inbound) = listen(cert.clone(), key);
+        let executor = QuicExecutor::new(
+            "127.0.0.1:0".parse().unwrap(),
+            CertVerification::Pinned(vec![cert]),
+        )
+        .unwrap()
+        .with_resolver(resolver);
+        let to = Address::new(Protocol::QUIC, identity.clone());
+        for payload in [&b"first"[..], b"second"] {
+            let message = MessageBuilder::new(to.clone()).payload(payload).build();
+            executor.send(&identity, message).await.unwrap();
+        }
+        let mut payloads = Vec::new();
+        for _ in 0..2 {
+            let received = tokio::time::timeout(Duration::from_secs(5), inbound.recv());
+            payloads.push(received.await.unwrap().unwrap().payload);
+        }
+        // each message has a stream of its own, so they may arrive in any order
+        payloads.sort();
+        assert_eq!(payloads, [b"first".to_vec(), b"second".to_vec()]);
+        // both went over the one pooled connection
+        assert_eq!(executor.connections.lock().unwrap().len(), 1);
+        executor.close().await;
+    }
+
+    #[tokio::test]
+    async fn certificates_other_than_the_pinned_one_are_refused() {
+        let (cert, key) = certificate();
+        let (identity, resolver, _inbound) = listen(cert, key);
+        let (other, _) = certificate();
+        let executor = QuicExecutor::new(
+            "127.0.0.1:0".parse().unwrap(),
+            CertVerification::Pinned(vec![other]),
+        )
+        .unwrap()
+        .with_resolver(resolver);
+        let to = Address::new(Protocol::QUIC, identity.clone());
+        let message = MessageBuilder::new(to).payload(b"x").build();
+        let result = executor.send(&identity, message).await;
+        assert!(result.is_err());
+    }
+}
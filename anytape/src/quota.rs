@@ -0,0 +1,395 @@
+//! Per identity traffic accounting with enforceable quotas.
+//!
+//! Traffic is charged to the peer it is exchanged with: sends to the identity of
+//! the next hop, received messages to the identity of their origin (the first
+//! address in the path). Usage is counted over a rolling window split into slots
+//! of a configurable granularity, so old traffic expires one slot at a time.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    audit::AuditLog, AuditEvent, AuditKind, Clock, Identity, Message, MessageStatus, NodeInstance,
+    RejectReason, SystemClock,
+};
+
+const SHARDS: usize = 16;
+
+/// Traffic exchanged with one identity within the current window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+impl QuotaUsage {
+    pub fn messages(&self) -> u64 {
+        self.messages_sent + self.messages_received
+    }
+    /// Payload bytes in both directions.
+    pub fn bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+}
+
+/// Caps on the traffic, in both directions, exchanged with one identity per window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl QuotaLimits {
+    fn admits(&self, usage: &QuotaUsage, bytes: u64) -> bool {
+        self.max_messages.is_none_or(|max| usage.messages() < max)
+            && self
+                .max_bytes
+                .is_none_or(|max| usage.bytes() + bytes <= max)
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    epoch: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+struct Account {
+    slots: Box<[Slot]>,
+    limits: RwLock<Option<QuotaLimits>>,
+}
+
+impl Account {
+    fn new(slots: usize) -> Self {
+        Self {
+            slots: (0..slots).map(|_| Slot::default()).collect(),
+            limits: RwLock::new(None),
+        }
+    }
+    fn usage(&self, epoch: u64) -> QuotaUsage {
+        let oldest = (epoch + 1).saturating_sub(self.slots.len() as u64);
+        let mut usage = QuotaUsage::default();
+        for slot in self.slots.iter() {
+            let slot_epoch = slot.epoch.load(Ordering::Acquire);
+            // slots start at epoch 0 with zero counts, so they can be summed as is
+            if slot_epoch >= oldest && slot_epoch <= epoch {
+                usage.messages_sent += slot.messages_sent.load(Ordering::Relaxed);
+                usage.bytes_sent += slot.bytes_sent.load(Ordering::Relaxed);
+                usage.messages_received += slot.messages_received.load(Ordering::Relaxed);
+                usage.bytes_received += slot.bytes_received.load(Ordering::Relaxed);
+            }
+        }
+        usage
+    }
+    fn record(&self, epoch: u64, direction: Direction, bytes: u64) {
+        let slot = &self.slots[(epoch % self.slots.len() as u64) as usize];
+        let current = slot.epoch.load(Ordering::Acquire);
+        if current != epoch
+            && slot
+                .epoch
+                .compare_exchange(current, epoch, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            slot.messages_sent.store(0, Ordering::Relaxed);
+            slot.bytes_sent.store(0, Ordering::Relaxed);
+            slot.messages_received.store(0, Ordering::Relaxed);
+            slot.bytes_received.store(0, Ordering::Relaxed);
+        }
+        let (messages, total) = match direction {
+            Direction::Sent => (&slot.messages_sent, &slot.bytes_sent),
+            Direction::Received => (&slot.messages_received, &slot.bytes_received),
+        };
+        messages.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Add the usage recorded in `other` to this account's.
+    fn absorb(&self, other: &Account) {
+        for (slot, theirs) in self.slots.iter().zip(other.slots.iter()) {
+            let their_epoch = theirs.epoch.load(Ordering::Acquire);
+            let counts = [
+                (&slot.messages_sent, &theirs.messages_sent),
+                (&slot.bytes_sent, &theirs.bytes_sent),
+                (&slot.messages_received, &theirs.messages_received),
+                (&slot.bytes_received, &theirs.bytes_received),
+            ];
+            let epoch = slot.epoch.load(Ordering::Acquire);
+            if their_epoch == epoch {
+                for (ours, theirs) in counts {
+                    ours.fetch_add(theirs.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+            } else if their_epoch > epoch {
+                slot.epoch.store(their_epoch, Ordering::Release);
+                for (ours, theirs) in counts {
+                    ours.store(theirs.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+type Shard = RwLock<HashMap<Identity, Arc<Account>>>;
+type ExhaustionCallback = dyn Fn(&Identity, &QuotaUsage) + Send + Sync;
+
+/// Tracks per identity usage and enforces [`QuotaLimits`] on a [`NodeInstance`].
+pub struct QuotaManager {
+    shards: Box<[Shard]>,
+    hasher: RandomState,
+    granularity: Duration,
+    slots: usize,
+    clock: Arc<dyn Clock>,
+    started: Duration,
+    audit: Option<Arc<AuditLog>>,
+    on_exhausted: Option<Arc<ExhaustionCallback>>,
+}
+
+impl QuotaManager {
+    /// Count usage over the last `window`, expiring it in steps of `granularity`.
+    pub fn new(window: Duration, granularity: Duration) -> Self {
+        let granularity = granularity.max(Duration::from_millis(1));
+        let slots = window.as_nanos().div_ceil(granularity.as_nanos()).max(1) as usize;
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+            granularity,
+            slots,
+            clock: Arc::new(SystemClock),
+            started: SystemClock.monotonic(),
+            audit: None,
+            on_exhausted: None,
+        }
+    }
+    /// Call `callback` with the identity and its usage whenever traffic is rejected
+    /// for exceeding its quota.
+    pub fn with_exhaustion_callback(
+        self,
+        callback: impl Fn(&Identity, &QuotaUsage) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_exhausted: Some(Arc::new(callback)),
+            ..self
+        }
+    }
+    /// Measure windows on `clock` instead of the [`SystemClock`]. The node's clock
+    /// replaces it in [`NodeInstance::with_quota_manager`].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.set_clock(clock);
+        self
+    }
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.started = clock.monotonic();
+        self.clock = clock;
+    }
+    pub fn set_quota(&self, identity: Identity, limits: QuotaLimits) {
+        *self.account(&identity).limits.write().unwrap() = Some(limits);
+        self.audit_change(&identity, format!("quota set to {limits:?}"));
+    }
+    pub fn clear_quota(&self, identity: &Identity) {
+        if let Some(account) = self.existing(identity) {
+            *account.limits.write().unwrap() = None;
+            self.audit_change(identity, "quota cleared".to_owned());
+        }
+    }
+    fn audit_change(&self, identity: &Identity, detail: String) {
+        if let Some(audit) = &self.audit {
+            audit.record(
+                AuditEvent::new(AuditKind::PolicyChanged, self.clock.now_millis(), detail)
+                    .identity(identity),
+            );
+        }
+    }
+    /// Fold the usage of `old` into `new` and forget `old`. `new` keeps its
+    /// limits, or takes over those of `old` if it has none.
+    pub fn merge(&self, old: &Identity, new: &Identity) {
+        let Some(previous) = self.shard(old).write().unwrap().remove(old) else {
+            return;
+        };
+        let account = self.account(new);
+        account.absorb(&previous);
+        let limits = *previous.limits.read().unwrap();
+        let mut current = account.limits.write().unwrap();
+        if current.is_none() {
+            *current = limits;
+        }
+    }
+    pub fn usage(&self, identity: &Identity) -> QuotaUsage {
+        self.existing(identity)
+            .map(|account| account.usage(self.epoch()))
+            .unwrap_or_default()
+    }
+
+    fn epoch(&self) -> u64 {
+        let elapsed = self.clock.monotonic().saturating_sub(self.started);
+        (elapsed.as_nanos() / self.granularity.as_nanos()) as u64
+    }
+    fn shard(&self, identity: &Identity) -> &Shard {
+        &self.shards[self.hasher.hash_one(identity) as usize % SHARDS]
+    }
+    fn existing(&self, identity: &Identity) -> Option<Arc<Account>> {
+        self.shard(identity).read().unwrap().get(identity).cloned()
+    }
+    fn account(&self, identity: &Identity) -> Arc<Account> {
+        if let Some(account) = self.existing(identity) {
+            return account;
+        }
+        self.shard(identity)
+            .write()
+            .unwrap()
+            .entry(identity.clone())
+            .or_insert_with(|| Arc::new(Account::new(self.slots)))
+            .clone()
+    }
+    /// Record the traffic if the identity's quota allows it.
+    fn admit(&self, identity: &Identity, direction: Direction, bytes: u64) -> bool {
+        let account = self.account(identity);
+        let epoch = self.epoch();
+        let limits = *account.limits.read().unwrap();
+        if let Some(limits) = limits {
+            let usage = account.usage(epoch);
+            if !limits.admits(&usage, bytes) {
+                if let Some(callback) = &self.on_exhausted {
+                    callback(identity, &usage);
+                }
+                return false;
+            }
+        }
+        account.record(epoch, direction, bytes);
+        true
+    }
+    pub(crate) fn admit_sent(&self, to: &Identity, message: &Message) -> bool {
+        self.admit(to, Direction::Sent, message.payload.len() as u64)
+    }
+    /// Returns the rejection for a message received from `origin` over its quota.
+    pub(crate) fn admit_received(
+        &self,
+        origin: &Identity,
+        message: &Message,
+    ) -> Option<MessageStatus> {
+        let bytes = message.payload.len() as u64;
+        (!self.admit(origin, Direction::Received, bytes)).then_some(MessageStatus::Rejected {
+            reason: RejectReason::QuotaExceeded,
+        })
+    }
+}
+
+impl NodeInstance {
+    /// Account traffic per identity and enforce quotas; see [`QuotaManager`].
+    pub fn with_quota_manager(mut self, mut quotas: QuotaManager) -> Self {
+        quotas.set_clock(self.clock.clone());
+        quotas.audit = Some(self.audit.clone());
+        self.quotas = Some(quotas);
+        self
+    }
+    pub fn quotas(&self) -> Option<&QuotaManager> {
+        self.quotas.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{ManualClock, PathNode, SendError};
+
+    fn node(clock: &ManualClock, quotas: QuotaManager) -> NodeInstance {
+        NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, Recorder::new())
+            .with_handler(|_| async {})
+            .with_clock(clock.clone())
+            .with_quota_manager(quotas)
+    }
+
+    #[tokio::test]
+    async fn sends_past_the_quota_are_rejected_until_the_window_rolls_over() {
+        let clock = ManualClock::new(0);
+        let exhausted = Arc::new(Mutex::new(Vec::new()));
+        let quotas = QuotaManager::new(Duration::from_secs(10), Duration::from_secs(1))
+            .with_exhaustion_callback({
+                let exhausted = exhausted.clone();
+                move |identity, usage| exhausted.lock().unwrap().push((identity.clone(), *usage))
+            });
+        let node = node(&clock, quotas);
+        let b = Identity::new("b");
+        node.quotas().unwrap().set_quota(
+            b.clone(),
+            QuotaLimits {
+                max_messages: Some(3),
+                max_bytes: None,
+            },
+        );
+        let send = || node.send(message(addr("b"), b"four"), addr("b"));
+        for _ in 0..3 {
+            send().await.unwrap();
+            clock.advance(Duration::from_secs(2));
+        }
+        assert!(matches!(send().await, Err(SendError::QuotaExceeded)));
+        let usage = QuotaUsage {
+            messages_sent: 3,
+            bytes_sent: 12,
+            ..QuotaUsage::default()
+        };
+        assert_eq!(node.quotas().unwrap().usage(&b), usage);
+        assert_eq!(*exhausted.lock().unwrap(), [(b.clone(), usage)]);
+
+        // the first send leaves the window, the other two are still in it
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(node.quotas().unwrap().usage(&b).messages_sent, 2);
+        send().await.unwrap();
+        assert!(matches!(send().await, Err(SendError::QuotaExceeded)));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(node.quotas().unwrap().usage(&b), QuotaUsage::default());
+        send().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn received_bytes_count_towards_the_origin() {
+        let clock = ManualClock::new(0);
+        let quotas = QuotaManager::new(Duration::from_secs(10), Duration::from_secs(1));
+        let node = node(&clock, quotas);
+        let origin = Identity::new("origin");
+        node.quotas().unwrap().set_quota(
+            origin.clone(),
+            QuotaLimits {
+                max_messages: None,
+                max_bytes: Some(10),
+            },
+        );
+        let receive = |payload: &[u8]| {
+            let mut message = message(addr("me"), payload);
+            message
+                .path
+                .push(PathNode::new().with_address(addr("origin")));
+            node.receive(message, addr("me"))
+        };
+        assert_eq!(receive(b"123456").await, MessageStatus::Received);
+        assert_eq!(
+            receive(b"12345").await,
+            MessageStatus::Rejected {
+                reason: RejectReason::QuotaExceeded
+            }
+        );
+        assert_eq!(receive(b"1234").await, MessageStatus::Received);
+        let usage = node.quotas().unwrap().usage(&origin);
+        assert_eq!((usage.messages_received, usage.bytes_received), (2, 10));
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{Clock, Protocol, SystemClock};
+
+/// What [`NodeInstance::send`](crate::NodeInstance::send) does when a protocol is
+/// out of tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitMode {
+    /// Wait until a token is available.
+    #[default]
+    Wait,
+    /// Fail with [`SendError::RateLimited`](crate::SendError::RateLimited).
+    Reject,
+}
+
+/// Token bucket rate limits on sends, one bucket per [`Protocol`].
+///
+/// Buckets refill on the monotonic time of a [`Clock`]: the [`SystemClock`]
+/// unless one is set, and the node's clock once the limiter is handed to
+/// [`NodeInstance::with_rate_limiter`](crate::NodeInstance::with_rate_limiter).
+pub struct RateLimiter {
+    mode: RateLimitMode,
+    buckets: Mutex<HashMap<Protocol, Bucket>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitMode::default())
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("mode", &self.mode)
+            .field("buckets", &self.buckets)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    /// Tokens added per second.
+    rate: f64,
+    tokens: f64,
+    refilled_at: Duration,
+}
+
+impl Bucket {
+    /// Take a token, or return how long until one is available.
+    fn take(&mut self, now: Duration) -> Result<(), Duration> {
+        let elapsed = now.saturating_sub(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = self.refilled_at.max(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(mode: RateLimitMode) -> Self {
+        Self {
+            mode,
+            buckets: Mutex::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.set_clock(clock);
+        self
+    }
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let now = clock.monotonic();
+        for bucket in self.buckets.get_mut().unwrap().values_mut() {
+            bucket.refilled_at = now;
+        }
+        self.clock = clock;
+    }
+    /// Allow `count` sends over `protocol` per `per`, with bursts of up to `count`.
+    pub fn with_limit(self, protocol: Protocol, count: u32, per: Duration) -> Self {
+        let capacity = f64::from(count.max(1));
+        self.buckets.lock().unwrap().insert(
+            protocol,
+            Bucket {
+                capacity,
+                rate: capacity / per.as_secs_f64().max(f64::MIN_POSITIVE),
+                tokens: capacity,
+                refilled_at: self.clock.monotonic(),
+            },
+        );
+        self
+    }
+    pub fn mode(&self) -> RateLimitMode {
+        self.mode
+    }
+    /// Take a token for `protocol` without waiting. Protocols without a limit always
+    /// succeed; otherwise `Err` holds the time until the next token.
+    pub fn try_acquire(&self, protocol: &Protocol) -> Result<(), Duration> {
+        match self.buckets.lock().unwrap().get_mut(protocol) {
+            Some(bucket) => bucket.take(self.clock.monotonic()),
+            None => Ok(()),
+        }
+    }
+    /// Take a token for `protocol`, waiting for one to become available.
+    pub async fn acquire(&self, protocol: &Protocol) {
+        while let Err(wait) = self.try_acquire(protocol) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{ManualClock, NodeInstance, SendError};
+
+    const OTHER: Protocol = Protocol::new_static(b"other");
+
+    fn limiter(mode: RateLimitMode, clock: &ManualClock) -> RateLimiter {
+        RateLimiter::new(mode)
+            .with_clock(Arc::new(clock.clone()))
+            .with_limit(TEST, 3, Duration::from_secs(3))
+    }
+
+    #[test]
+    fn the_fourth_send_of_three_per_window_is_limited() {
+        let clock = ManualClock::new(0);
+        let limiter = limiter(RateLimitMode::Reject, &clock);
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(&TEST), Ok(()));
+        }
+        assert_eq!(limiter.try_acquire(&TEST), Err(Duration::from_secs(1)));
+        // other protocols have no limit of their own
+        assert_eq!(limiter.try_acquire(&OTHER), Ok(()));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.try_acquire(&TEST), Ok(()));
+        assert!(limiter.try_acquire(&TEST).is_err());
+    }
+
+    #[test]
+    fn idle_buckets_refill_no_further_than_the_burst() {
+        let clock = ManualClock::new(0);
+        let limiter = limiter(RateLimitMode::Reject, &clock);
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(&TEST), Ok(()));
+        }
+        assert!(limiter.try_acquire(&TEST).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejecting_nodes_fail_the_fourth_send() {
+        let clock = ManualClock::new(0);
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_rate_limiter(limiter(RateLimitMode::Reject, &clock))
+            .with_clock(clock.clone());
+        for _ in 0..3 {
+            node.send(message(addr("b"), b"x"), addr("b"))
+                .await
+                .unwrap();
+        }
+        let fourth = node.send(message(addr("b"), b"x"), addr("b")).await;
+        assert!(matches!(fourth, Err(SendError::RateLimited)));
+        assert_eq!(recorder.sent().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_nodes_hold_the_fourth_send_until_a_token_is_back() {
+        let clock = ManualClock::new(0);
+        let node = NodeInstance::new()
+            .with_executor(TEST, Recorder::new())
+            .with_rate_limiter(limiter(RateLimitMode::Wait, &clock))
+            .with_clock(clock.clone());
+        for _ in 0..3 {
+            node.send(message(addr("b"), b"x"), addr("b"))
+                .await
+                .unwrap();
+        }
+        let send = || node.send(message(addr("b"), b"x"), addr("b"));
+        let held = tokio::time::timeout(Duration::from_secs(5), send()).await;
+        assert!(held.is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(send().await.is_ok());
+    }
+}
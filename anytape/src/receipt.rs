@@ -0,0 +1,143 @@
+use std::{cell::RefCell, future::Future, time::Duration};
+
+use crate::{MessageStatus, Protocol};
+
+tokio::task_local! {
+    static RECORDED: RefCell<Recorded>;
+}
+
+/// What executors reported through [`SendContext`] while a send ran.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Recorded {
+    pub(crate) attempts: u32,
+    pub(crate) budget_exhausted: bool,
+    /// What was recorded for each message of a batch sent message by message
+    /// meanwhile, in order.
+    pub(crate) messages: Vec<Recorded>,
+}
+
+impl Recorded {
+    /// What counts towards message `index` of the `count` sent in one batch
+    /// while this was recorded: its own record if the batch was sent message
+    /// by message, else everything the batch as a whole reported.
+    pub(crate) fn for_message(&self, index: usize, count: usize) -> Recorded {
+        match self.messages.get(index) {
+            Some(recorded) if self.messages.len() == count => recorded.clone(),
+            _ => Recorded {
+                messages: Vec::new(),
+                ..*self
+            },
+        }
+    }
+}
+
+/// How far a send that succeeded got, as its
+/// [executor](crate::ProtocolExecutor::send) reported it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendOutcome {
+    /// [`MessageStatus::Sended`] if the transport only took the message on,
+    /// [`MessageStatus::Received`] if the remote acknowledged it.
+    pub status: MessageStatus,
+    /// The remote's sequence number for the message, if it sent one back.
+    pub remote_seq: Option<u64>,
+}
+
+impl Default for SendOutcome {
+    /// Handed to the transport, which is all executors that report nothing
+    /// can promise.
+    fn default() -> Self {
+        Self::sent()
+    }
+}
+
+impl SendOutcome {
+    pub fn sent() -> Self {
+        Self {
+            status: MessageStatus::Sended,
+            remote_seq: None,
+        }
+    }
+    pub fn received(remote_seq: Option<u64>) -> Self {
+        Self {
+            status: MessageStatus::Received,
+            remote_seq,
+        }
+    }
+    /// The outcome of a message sent in pieces, this one first: only
+    /// acknowledged if every piece was.
+    pub(crate) fn then(self, next: Self) -> Self {
+        match self.status {
+            MessageStatus::Received => next,
+            status => Self { status, ..next },
+        }
+    }
+}
+
+/// What a successful [`NodeInstance::send_detailed`](crate::NodeInstance::send_detailed)
+/// did to get the message out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendReceipt {
+    /// Protocol of the executor that carried the message.
+    pub protocol: Protocol,
+    /// The protocol that executor is registered under: `protocol` or, for a
+    /// [hierarchical](crate::DEFAULT_PROTOCOL_DELIMITER) fallback, a prefix of it.
+    pub registration: Protocol,
+    pub unique_id: u64,
+    pub elapsed: Duration,
+    /// Attempts made, as reported through [`SendContext::record_attempt`]; `1` if
+    /// nothing reported any.
+    pub attempts: u32,
+    /// Whether the remote acknowledged the message, as its executor reported.
+    /// Messages [split](crate::Message::split) into pieces only count as
+    /// acknowledged if every piece was.
+    pub outcome: SendOutcome,
+}
+
+/// Lets executors and decorators contribute to the [`SendReceipt`] of the send
+/// they are running under.
+pub struct SendContext;
+
+impl SendContext {
+    /// Count one attempt towards the current send. Does nothing outside a send.
+    pub fn record_attempt() {
+        Self::record(|recorded| recorded.attempts += 1);
+    }
+
+    /// Report that a retry of the current send was skipped because the
+    /// [retry budget](crate::RetryBudget) ran out, which the node notes in the
+    /// [`SendFailure`](crate::SendFailure). Does nothing outside a send.
+    pub fn record_budget_exhausted() {
+        Self::record(|recorded| recorded.budget_exhausted = true);
+    }
+
+    fn record(update: impl FnOnce(&mut Recorded)) {
+        let _ = RECORDED.try_with(|cell| update(&mut cell.borrow_mut()));
+    }
+
+    /// Count what was recorded for a send run elsewhere, such as in a batch
+    /// flushed by another task, towards the current send.
+    pub(crate) fn replay(recorded: &Recorded) {
+        Self::record(|current| {
+            current.attempts += recorded.attempts;
+            current.budget_exhausted |= recorded.budget_exhausted;
+        });
+    }
+
+    /// Run `send`, returning its output and what was recorded while it ran.
+    pub(crate) async fn scope<F: Future>(send: F) -> (F::Output, Recorded) {
+        RECORDED
+            .scope(RefCell::default(), async move {
+                let output = send.await;
+                (output, RECORDED.with(RefCell::take))
+            })
+            .await
+    }
+
+    /// Run `send`, one message of a batch sent message by message, keeping
+    /// what it records apart from the rest of the batch.
+    pub(crate) async fn scope_message<F: Future>(send: F) -> F::Output {
+        let (output, recorded) = Self::scope(send).await;
+        Self::record(|batch| batch.messages.push(recorded));
+        output
+    }
+}
@@ -0,0 +1,110 @@
+//! Showing messages in logs without their payload.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::Message;
+
+/// A [`Message`] as it may be logged: its destination, `unique_id` and
+/// [path](Message::path_summary), and of the payload only its length and the
+/// start of its SHA-256, enough to tell payloads apart but not to read them.
+/// Headers and metadata are left out as well.
+#[derive(Clone, Copy)]
+pub struct RedactedMessage<'a> {
+    message: &'a Message,
+}
+
+impl RedactedMessage<'_> {
+    /// The first 8 bytes of the payload's SHA-256, in hex.
+    fn fingerprint(&self) -> String {
+        Sha256::digest(&self.message.payload)[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl fmt::Debug for RedactedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("destination", &format_args!("{}", self.message.destination))
+            .field("unique_id", &self.message.unique_id)
+            .field("path", &self.message.path_summary())
+            .field("payload_len", &self.message.payload.len())
+            .field("payload_sha256", &self.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+/// `destination #unique_id (len bytes, sha256 fingerprint) via path`.
+impl fmt::Display for RedactedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} #{} ({} bytes, sha256 {}) via {}",
+            self.message.destination,
+            self.message.unique_id,
+            self.message.payload.len(),
+            self.fingerprint(),
+            self.message.path_summary()
+        )
+    }
+}
+
+impl Message {
+    /// A view of this message for logs, without the payload; see
+    /// [`RedactedMessage`].
+    pub fn redacted(&self) -> RedactedMessage<'_> {
+        RedactedMessage { message: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        testing::{addr, message, Recorder, TEST},
+        NodeInstance,
+    };
+
+    const SECRET: &[u8] = b"hunter2-password";
+
+    #[test]
+    fn redacted_messages_show_the_length_but_not_the_payload() {
+        let mut secret = message(addr("b"), SECRET);
+        secret.unique_id = 42;
+        let shown = secret.redacted().to_string();
+        assert!(!shown.contains("hunter2"), "{shown}");
+        assert!(
+            shown.contains(&format!("{} bytes", SECRET.len())),
+            "{shown}"
+        );
+        assert!(shown.contains("#42") && shown.contains(&addr("b").to_string()));
+
+        let debug = format!("{:?}", secret.redacted());
+        assert!(!debug.contains("hunter2"), "{debug}");
+        assert!(debug.contains("payload_len: 16"), "{debug}");
+        // the fingerprint tells payloads apart
+        let other = message(addr("b"), b"hunter3-password")
+            .redacted()
+            .to_string();
+        assert_ne!(shown.split("sha256 ").nth(1), other.split("sha256 ").nth(1));
+    }
+
+    #[tokio::test]
+    async fn send_error_reports_show_the_redacted_message() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let node = NodeInstance::new()
+            .with_executor(TEST, Recorder::new().failing("down"))
+            .with_on_send_err({
+                let reports = reports.clone();
+                move |_, _, shown| reports.lock().unwrap().push(shown.to_owned())
+            });
+        let secret = message(addr("b"), SECRET);
+        let expected = secret.redacted().to_string();
+        assert!(node.send(secret, addr("b")).await.is_err());
+        assert_eq!(*reports.lock().unwrap(), [expected]);
+    }
+}
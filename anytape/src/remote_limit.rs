@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Semaphore;
+
+use crate::{
+    ExecutorCapabilities, Identity, Message, MessageStatus, Protocol, ProtocolExecutor, SendOutcome,
+};
+
+/// What a [`PerRemoteLimit`] does with a send to a remote at its cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemoteLimitMode {
+    /// Wait until one of the remote's sends is done.
+    #[default]
+    Queue,
+    /// Fail with [`RemoteLimitError::AtCapacity`].
+    Reject,
+}
+
+#[derive(Debug)]
+pub enum RemoteLimitError<E> {
+    /// The remote already has as many sends in flight as allowed.
+    AtCapacity(Identity),
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for RemoteLimitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteLimitError::AtCapacity(remote) => {
+                write!(f, "too many sends in flight to {remote}")
+            }
+            RemoteLimitError::Inner(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for RemoteLimitError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RemoteLimitError::Inner(e) => Some(e),
+            RemoteLimitError::AtCapacity(_) => None,
+        }
+    }
+}
+
+/// Caps the sends in flight on `inner` to each remote, so that one slow or
+/// misbehaving remote cannot take up every connection.
+///
+/// Sends beyond the cap are queued or rejected according to the
+/// [`RemoteLimitMode`]. A queued send is handed to `inner` right away, and the
+/// future `inner` returns is awaited once the remote has a free slot. A batch
+/// counts as one send. Status queries are not limited.
+pub struct PerRemoteLimit<E> {
+    inner: E,
+    max_in_flight: usize,
+    mode: RemoteLimitMode,
+    slots: Arc<Mutex<HashMap<Identity, Arc<Semaphore>>>>,
+}
+
+impl<E: ProtocolExecutor> PerRemoteLimit<E> {
+    pub fn new(inner: E, max_in_flight: usize, mode: RemoteLimitMode) -> Self {
+        Self {
+            inner,
+            max_in_flight: max_in_flight.max(1),
+            mode,
+            slots: Default::default(),
+        }
+    }
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+    /// Sends in flight to `remote`.
+    pub fn in_flight(&self, remote: &Identity) -> usize {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(remote)
+            .map_or(0, |slots| self.max_in_flight - slots.available_permits())
+    }
+    /// Run `send` once `remote` has a free slot.
+    fn limited<T>(
+        &self,
+        remote: &Identity,
+        send: impl Future<Output = Result<T, E::Error>> + Send + 'static,
+    ) -> impl Future<Output = Result<T, RemoteLimitError<E::Error>>> + Send + 'static {
+        let mode = self.mode;
+        let remote = remote.clone();
+        let slots = self.slots.clone();
+        let remote_slots = slots
+            .lock()
+            .unwrap()
+            .entry(remote.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_in_flight)))
+            .clone();
+        async move {
+            let permit = match mode {
+                RemoteLimitMode::Queue => remote_slots.clone().acquire_owned().await.ok(),
+                RemoteLimitMode::Reject => remote_slots.clone().try_acquire_owned().ok(),
+            };
+            let Some(permit) = permit else {
+                release_idle(&slots, &remote, remote_slots);
+                return Err(RemoteLimitError::AtCapacity(remote));
+            };
+            let result = send.await.map_err(RemoteLimitError::Inner);
+            drop(permit);
+            release_idle(&slots, &remote, remote_slots);
+            result
+        }
+    }
+}
+
+/// Forget the semaphore of `remote` once nothing uses it, so that remotes
+/// sent to once do not pile up.
+fn release_idle(
+    slots: &Mutex<HashMap<Identity, Arc<Semaphore>>>,
+    remote: &Identity,
+    remote_slots: Arc<Semaphore>,
+) {
+    let mut slots = slots.lock().unwrap();
+    // the map holds one reference and we hold the other
+    if Arc::strong_count(&remote_slots) == 2 {
+        slots.remove(remote);
+    }
+}
+
+impl<E> ProtocolExecutor for PerRemoteLimit<E>
+where
+    E: ProtocolExecutor + Send + Sync + 'static,
+{
+    type Error = RemoteLimitError<E::Error>;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        self.limited(remote, self.inner.send(remote, message))
+    }
+
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        self.limited(remote, self.inner.send_via(protocol, remote, message))
+    }
+
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> impl Future<Output = Result<Vec<Result<SendOutcome, Self::Error>>, Self::Error>> + Send + 'static
+    {
+        let batch = self.limited(remote, self.inner.send_batch(remote, messages));
+        async move {
+            let results = batch.await?;
+            Ok(results
+                .into_iter()
+                .map(|result| result.map_err(RemoteLimitError::Inner))
+                .collect())
+        }
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let status = self.inner.get_status(remote, message);
+        async move { status.await.map_err(RemoteLimitError::Inner) }
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder};
+
+    fn recorder() -> Recorder {
+        Recorder::new().with_delay(Duration::from_millis(100))
+    }
+
+    /// How long after `start` `send` succeeded.
+    async fn done_after<T, E: fmt::Debug>(
+        start: Instant,
+        send: impl Future<Output = Result<T, E>>,
+    ) -> Duration {
+        send.await.unwrap();
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_to_one_remote_serialize_and_others_proceed() {
+        let limit = PerRemoteLimit::new(recorder(), 1, RemoteLimitMode::Queue);
+        let (b, c) = (addr("b").identity, addr("c").identity);
+        let start = Instant::now();
+        let (first, second, other) = tokio::join!(
+            done_after(start, limit.send(&b, message(addr("b"), b"1"))),
+            done_after(start, limit.send(&b, message(addr("b"), b"2"))),
+            done_after(start, limit.send(&c, message(addr("c"), b"3"))),
+        );
+        assert_eq!(first, Duration::from_millis(100));
+        assert_eq!(second, Duration::from_millis(200));
+        assert_eq!(other, Duration::from_millis(100));
+        assert_eq!(limit.in_flight(&b), 0);
+        assert!(limit.slots.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_beyond_the_cap_are_rejected_in_reject_mode() {
+        let limit = PerRemoteLimit::new(recorder(), 1, RemoteLimitMode::Reject);
+        let b = addr("b").identity;
+        let (first, second) = tokio::join!(limit.send(&b, message(addr("b"), b"1")), async {
+            tokio::task::yield_now().await;
+            assert_eq!(limit.in_flight(&b), 1);
+            limit.send(&b, message(addr("b"), b"2")).await
+        });
+        first.unwrap();
+        assert!(matches!(second, Err(RemoteLimitError::AtCapacity(remote)) if remote == b));
+        limit.send(&b, message(addr("b"), b"3")).await.unwrap();
+        assert_eq!(limit.inner().sent().len(), 2);
+    }
+}
@@ -0,0 +1,210 @@
+//! In-order delivery of sequenced messages.
+//!
+//! Messages carrying a [`seq`](crate::Message::seq) are numbered per origin, from
+//! zero. With a reorder buffer enabled, a message that arrives ahead of its turn
+//! is held until the messages before it have been delivered, or until the gap has
+//! stalled for the configured timeout, at which point everything held is released
+//! in order and the missing messages are given up on.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{Identity, Message, NodeInstance, ReceiveHandler};
+
+/// Held messages per origin beyond which the buffer is flushed regardless.
+const MAX_PENDING: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct ReorderState {
+    timeout: Option<Duration>,
+    sources: Arc<Mutex<HashMap<Identity, Source>>>,
+}
+
+#[derive(Default)]
+struct Source {
+    next: u64,
+    pending: BTreeMap<u64, Message>,
+    /// Bumped whenever a new wait for a gap starts, so stale timers do nothing.
+    generation: u64,
+}
+
+impl Source {
+    /// Release the messages that are next in line.
+    fn release(&mut self, ready: &mut Vec<Message>) {
+        while let Some(message) = self.pending.remove(&self.next) {
+            self.next += 1;
+            ready.push(message);
+        }
+    }
+    fn flush(&mut self, ready: &mut Vec<Message>) {
+        if let Some((&last, _)) = self.pending.last_key_value() {
+            self.next = last + 1;
+        }
+        ready.extend(std::mem::take(&mut self.pending).into_values());
+    }
+}
+
+impl ReorderState {
+    pub(crate) fn watermarks(&self) -> Vec<(Identity, u64)> {
+        self.sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(origin, source)| (origin.clone(), source.next))
+            .collect()
+    }
+    /// Expect at least `next` from `origin`; held messages before it are given
+    /// up on.
+    pub(crate) fn raise_watermark(&self, origin: Identity, next: u64) {
+        let mut sources = self.sources.lock().unwrap();
+        let source = sources.entry(origin).or_default();
+        if next > source.next {
+            source.next = next;
+            source.pending = source.pending.split_off(&next);
+        }
+    }
+    /// The messages to hand to the handler now, in order, and the generation to
+    /// flush after the timeout if a gap remains.
+    fn accept(&self, origin: Identity, seq: u64, message: Message) -> (Vec<Message>, Option<u64>) {
+        let mut sources = self.sources.lock().unwrap();
+        let source = sources.entry(origin).or_default();
+        let mut ready = Vec::new();
+        if seq < source.next {
+            // late after a flush, or a duplicate; do not hold it back
+            ready.push(message);
+            return (ready, None);
+        }
+        let was_waiting = !source.pending.is_empty();
+        source.pending.insert(seq, message);
+        source.release(&mut ready);
+        if source.pending.len() > MAX_PENDING {
+            source.flush(&mut ready);
+        }
+        if source.pending.is_empty() || (was_waiting && ready.is_empty()) {
+            return (ready, None);
+        }
+        source.generation += 1;
+        (ready, Some(source.generation))
+    }
+}
+
+impl NodeInstance {
+    /// Deliver sequenced messages from each origin in order, waiting at most
+    /// `timeout` for a missing one before giving up on it.
+    pub fn with_reorder_buffer(mut self, timeout: Duration) -> Self {
+        self.reorder.timeout = Some(timeout);
+        self
+    }
+    /// Hand `message` to `handler`, after the messages before it if it is sequenced
+    /// and the reorder buffer is enabled.
+    pub(crate) async fn deliver_ordered(
+        &self,
+        handler: &Arc<dyn ReceiveHandler>,
+        message: Message,
+    ) {
+        let (Some(timeout), Some(seq), Some(origin)) = (
+            self.reorder.timeout,
+            message.seq,
+            message
+                .path
+                .first()
+                .and_then(|node| node.address.as_ref())
+                .map(|address| address.identity.clone()),
+        ) else {
+            return self.handler_runner.run(handler, message).await;
+        };
+        let (ready, stalled) = self.reorder.accept(origin.clone(), seq, message);
+        if let Some(generation) = stalled {
+            let sources = self.reorder.sources.clone();
+            let handler = handler.clone();
+            let runner = self.handler_runner.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                let mut ready = Vec::new();
+                if let Some(source) = sources.lock().unwrap().get_mut(&origin) {
+                    if source.generation == generation {
+                        source.flush(&mut ready);
+                    }
+                }
+                for message in ready {
+                    runner.run(&handler, message).await;
+                }
+            });
+        }
+        for message in ready {
+            self.handler_runner.run(handler, message).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, eventually};
+    use crate::MessageBuilder;
+
+    /// A node delivering in order, and the sequence numbers it delivered.
+    fn receiver() -> (Arc<NodeInstance>, Arc<Mutex<Vec<u64>>>) {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_reorder_buffer(Duration::from_secs(1))
+            .with_handler({
+                let delivered = delivered.clone();
+                move |message: Message| {
+                    delivered.lock().unwrap().push(message.seq.unwrap());
+                    async {}
+                }
+            });
+        (Arc::new(node), delivered)
+    }
+
+    /// Have `node` receive message `seq` from `origin`.
+    async fn receive(node: &NodeInstance, origin: &str, seq: u64) {
+        let mut message = MessageBuilder::new(addr("me")).seq(seq).build();
+        NodeInstance::new().mark(addr(origin), &mut message);
+        node.dispatch_inbound(message, addr("me")).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_order_messages_are_delivered_at_once() {
+        let (node, delivered) = receiver();
+        for seq in 0..3 {
+            receive(&node, "src", seq).await;
+            assert_eq!(delivered.lock().unwrap().len() as u64, seq + 1);
+        }
+        assert_eq!(*delivered.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn early_messages_wait_for_the_ones_before_them() {
+        let (node, delivered) = receiver();
+        receive(&node, "src", 2).await;
+        receive(&node, "src", 1).await;
+        // origins are ordered separately
+        receive(&node, "other", 0).await;
+        assert_eq!(*delivered.lock().unwrap(), [0]);
+        receive(&node, "src", 0).await;
+        assert_eq!(*delivered.lock().unwrap(), [0, 0, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stalled_gap_is_given_up_after_the_timeout() {
+        let (node, delivered) = receiver();
+        receive(&node, "src", 0).await;
+        receive(&node, "src", 2).await;
+        receive(&node, "src", 3).await;
+        tokio::time::sleep(Duration::from_millis(999)).await;
+        assert_eq!(*delivered.lock().unwrap(), [0]);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        eventually(|| delivered.lock().unwrap().len() == 3).await;
+        assert_eq!(*delivered.lock().unwrap(), [0, 2, 3]);
+        // the missing message is delivered as it comes, later ones in order again
+        receive(&node, "src", 1).await;
+        receive(&node, "src", 4).await;
+        assert_eq!(*delivered.lock().unwrap(), [0, 2, 3, 1, 4]);
+    }
+}
@@ -0,0 +1,392 @@
+//! Swapping the executor of a protocol while the node runs.
+//!
+//! [`NodeInstance::replace_executor`] sends everything from then on with the new
+//! executor, waits for the sends still under way on the old one, and then
+//! [closes](ProtocolExecutor::close) it so it can shut its connections down
+//! cleanly. Every registered executor counts the sends, batches and status
+//! queries it has under way for this, from the moment a send picks the executor
+//! until it is done with it, so that a send still waiting on the rate limit or
+//! a throttle when the executor is replaced finishes on it before it is closed
+//! rather than after. A [`DrainPolicy`] deadline bounds the
+//! wait: once it passes, the old executor is closed anyway, and what is still
+//! under way finishes on it as well as it can.
+//!
+//! The start and end of a replacement are published as
+//! [`NodeEvent::ExecutorReplacing`] and [`NodeEvent::ExecutorReplaced`].
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    BoxFuture, BoxResult, DynProtocolExecutor, ExecutorCapabilities, Identity, Message,
+    MessageStatus, NodeEvent, NodeInstance, Protocol, SendError, SendOutcome, StreamingMessage,
+};
+
+/// How long [`NodeInstance::replace_executor`] waits for the old executor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainPolicy {
+    /// After this, the old executor is closed with sends still under way;
+    /// `None` waits for all of them.
+    pub deadline: Option<Duration>,
+}
+
+impl DrainPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_deadline(self, deadline: Duration) -> Self {
+        Self {
+            deadline: Some(deadline),
+        }
+    }
+}
+
+/// How the old executor of a [replacement](NodeInstance::replace_executor)
+/// was drained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Sends under way on the old executor when it was replaced.
+    pub in_flight: usize,
+    /// Sends still under way when it was closed, only ever more than zero if
+    /// the deadline passed.
+    pub remaining: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.clone())
+    }
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+    async fn until_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            // registered before checking, so that a send finishing in between
+            // is not missed
+            idle.as_mut().enable();
+            if self.count() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// A registered executor, counting what it has under way so that it can be
+/// drained once replaced.
+pub(crate) struct TrackedExecutor {
+    inner: Arc<dyn DynProtocolExecutor>,
+    in_flight: Arc<InFlight>,
+}
+
+impl TrackedExecutor {
+    pub(crate) fn new(inner: Arc<dyn DynProtocolExecutor>) -> Self {
+        Self {
+            inner,
+            in_flight: Default::default(),
+        }
+    }
+    /// This executor, counted as in use once until the selection is dropped,
+    /// however much is sent with it meanwhile.
+    pub(crate) fn select(&self) -> SelectedExecutor {
+        SelectedExecutor {
+            executor: self.inner.clone(),
+            _in_flight: Some(self.in_flight.enter()),
+        }
+    }
+    fn track<T: 'static>(&self, fut: BoxFuture<T>) -> BoxFuture<T> {
+        let guard = self.in_flight.enter();
+        Box::pin(async move {
+            let result = fut.await;
+            drop(guard);
+            result
+        })
+    }
+}
+
+impl DynProtocolExecutor for TrackedExecutor {
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<BoxResult<SendOutcome>> {
+        self.track(self.inner.send(remote, message))
+    }
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<BoxResult<SendOutcome>> {
+        self.track(self.inner.send_via(protocol, remote, message))
+    }
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<BoxResult<MessageStatus>> {
+        self.track(self.inner.get_status(remote, message))
+    }
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> BoxFuture<BoxResult<Vec<BoxResult<SendOutcome>>>> {
+        self.track(self.inner.send_batch(remote, messages))
+    }
+    fn send_stream<'a>(
+        &'a self,
+        remote: &Identity,
+        message: StreamingMessage,
+    ) -> Pin<Box<dyn Future<Output = BoxResult<()>> + Send + 'a>> {
+        let guard = self.in_flight.enter();
+        let send = self.inner.send_stream(remote, message);
+        Box::pin(async move {
+            let result = send.await;
+            drop(guard);
+            result
+        })
+    }
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner.capabilities()
+    }
+    fn close(&self) -> BoxFuture<()> {
+        self.inner.close()
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self.inner.as_any()
+    }
+}
+
+/// The executor a send is to go out with, kept from being closed by a
+/// [replacement](NodeInstance::replace_executor) while the send holds it.
+pub(crate) struct SelectedExecutor {
+    executor: Arc<dyn DynProtocolExecutor>,
+    _in_flight: Option<InFlightGuard>,
+}
+
+impl From<Arc<dyn DynProtocolExecutor>> for SelectedExecutor {
+    /// An executor that is never replaced, so it needs no counting.
+    fn from(executor: Arc<dyn DynProtocolExecutor>) -> Self {
+        Self {
+            executor,
+            _in_flight: None,
+        }
+    }
+}
+
+impl Deref for SelectedExecutor {
+    type Target = Arc<dyn DynProtocolExecutor>;
+    fn deref(&self) -> &Self::Target {
+        &self.executor
+    }
+}
+
+/// Executors installed by [`NodeInstance::replace_executor`], in place of the
+/// registered ones.
+#[derive(Default)]
+pub(crate) struct Replacements {
+    /// Whether there are any, so that sends skip the lock until then.
+    any: AtomicBool,
+    by_protocol: RwLock<HashMap<Protocol, Arc<TrackedExecutor>>>,
+}
+
+impl Replacements {
+    /// The executor replacing the one registered under `registration`.
+    pub(crate) fn get(&self, registration: &Protocol) -> Option<Arc<TrackedExecutor>> {
+        if !self.any.load(Ordering::Acquire) {
+            return None;
+        }
+        self.by_protocol.read().unwrap().get(registration).cloned()
+    }
+    pub(crate) fn contains(&self, registration: &Protocol) -> bool {
+        self.any.load(Ordering::Acquire)
+            && self.by_protocol.read().unwrap().contains_key(registration)
+    }
+    /// Forget the replacement for `protocol`, when it is registered anew.
+    pub(crate) fn remove(&mut self, protocol: &Protocol) {
+        self.by_protocol.get_mut().unwrap().remove(protocol);
+    }
+    fn insert(
+        &self,
+        protocol: Protocol,
+        executor: Arc<TrackedExecutor>,
+    ) -> Option<Arc<TrackedExecutor>> {
+        let replaced = self.by_protocol.write().unwrap().insert(protocol, executor);
+        self.any.store(true, Ordering::Release);
+        replaced
+    }
+}
+
+impl NodeInstance {
+    /// Send over `protocol` with `executor` from now on, and drain and close
+    /// the executor it replaces; see the [module docs](self).
+    ///
+    /// `protocol` must be registered itself, not through a
+    /// [prefix](crate::DEFAULT_PROTOCOL_DELIMITER), or this fails with
+    /// [`SendError::ProtocolNotSupport`]. Panics counted against the old
+    /// executor are forgotten. The new executor takes over right away; the
+    /// future returned drains the old one, reporting how once it closed it,
+    /// and can be spawned to carry on meanwhile.
+    pub fn replace_executor(
+        &self,
+        protocol: Protocol,
+        executor: Arc<dyn DynProtocolExecutor>,
+        drain: DrainPolicy,
+    ) -> Result<impl Future<Output = DrainReport> + Send + 'static, SendError> {
+        let registered = match self.protocol_executor.get(&protocol) {
+            Some(executor) => Some(executor.clone()),
+            None => match self.lazy_executors.get(&protocol) {
+                Some(lazy) => lazy.ready().cloned(),
+                None => {
+                    return Err(SendError::ProtocolNotSupport {
+                        supported: self.supported_protocols(),
+                    })
+                }
+            },
+        };
+        let replaced = self
+            .replacements
+            .insert(protocol.clone(), Arc::new(TrackedExecutor::new(executor)));
+        self.panics.reset(&protocol, None);
+        // a lazy executor never initialized has nothing to drain
+        let old = replaced.or(registered);
+        let in_flight = old.as_ref().map_or(0, |old| old.in_flight.count());
+        self.publish(|| NodeEvent::ExecutorReplacing {
+            protocol: protocol.clone(),
+            in_flight,
+        });
+        let events = self.events.clone();
+        let clock = self.clock.clone();
+        Ok(async move {
+            let start = Instant::now();
+            let mut remaining = 0;
+            if let Some(old) = old {
+                let idle = old.in_flight.until_idle();
+                match drain.deadline {
+                    Some(deadline) => {
+                        let _ = tokio::time::timeout(deadline, idle).await;
+                    }
+                    None => idle.await,
+                }
+                remaining = old.in_flight.count();
+                old.close().await;
+            }
+            events.publish(&*clock, || NodeEvent::ExecutorReplaced {
+                protocol,
+                remaining,
+            });
+            DrainReport {
+                in_flight,
+                remaining,
+                elapsed: start.elapsed(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{RateLimitMode, RateLimiter};
+
+    fn payloads(recorder: &Recorder) -> Vec<Vec<u8>> {
+        recorder.sent().into_iter().map(|m| m.payload).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_finish_on_the_executor_they_picked() {
+        let old = Recorder::new().with_delay(Duration::from_millis(100));
+        let new = Recorder::new();
+        let node = Arc::new(
+            NodeInstance::new()
+                .with_executor(TEST, old.clone())
+                .with_rate_limiter(RateLimiter::new(RateLimitMode::Wait).with_limit(
+                    TEST,
+                    1,
+                    Duration::from_secs(1),
+                )),
+        );
+        node.send(message(addr("b"), b"first"), addr("b"))
+            .await
+            .unwrap();
+        // picks the old executor, then waits for the rate limit
+        let waiting = tokio::spawn({
+            let node = node.clone();
+            async move { node.send(message(addr("b"), b"before"), addr("b")).await }
+        });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let drain = node
+            .replace_executor(TEST, Arc::new(new.clone()), DrainPolicy::new())
+            .unwrap();
+        let drained = tokio::spawn(drain);
+        node.send(message(addr("b"), b"after"), addr("b"))
+            .await
+            .unwrap();
+
+        let report = drained.await.unwrap();
+        assert_eq!(report.in_flight, 1);
+        assert_eq!(report.remaining, 0);
+        assert!(waiting.is_finished());
+        waiting.await.unwrap().unwrap();
+        assert_eq!(payloads(&old), [b"first".to_vec(), b"before".to_vec()]);
+        assert_eq!(payloads(&new), [b"after".to_vec()]);
+        assert_eq!((old.closes(), new.closes()), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_replaced_executor_is_closed_once() {
+        let slow = || Recorder::new().with_delay(Duration::from_millis(50));
+        let executors = [slow(), slow(), slow()];
+        let node = Arc::new(NodeInstance::new().with_executor(TEST, executors[0].clone()));
+        for next in &executors[1..] {
+            let sending = tokio::spawn({
+                let node = node.clone();
+                async move { node.send(message(addr("b"), b"x"), addr("b")).await }
+            });
+            tokio::task::yield_now().await;
+            let drain = node
+                .replace_executor(TEST, Arc::new(next.clone()), DrainPolicy::new())
+                .unwrap();
+            assert_eq!(drain.await.in_flight, 1);
+            sending.await.unwrap().unwrap();
+        }
+        node.send(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        let closes: Vec<_> = executors.iter().map(Recorder::closes).collect();
+        assert_eq!(closes, [1, 1, 0]);
+        let sent: Vec<_> = executors.iter().map(|e| e.sent().len()).collect();
+        assert_eq!(sent, [1, 1, 1]);
+    }
+}
@@ -0,0 +1,267 @@
+//! Turning `host:port` identities into socket addresses for the network
+//! transports.
+//!
+//! Executors use a [`DnsResolver`] unless given another [`Resolver`], e.g. one
+//! asking a service registry. They try the resolved addresses in order, moving
+//! on to the next one when connecting fails or takes longer than the connect
+//! timeout. Wrap a resolver in a [`CachingResolver`] to avoid a lookup per
+//! connection.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{typed_address::SocketIdentity, BoxFuture, Clock, Identity, SystemClock};
+
+/// How long an executor waits on one resolved address before trying the next.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
+pub enum ResolveError {
+    InvalidIdentity(Identity),
+    /// The identity resolved to no addresses.
+    NotFound(Identity),
+    /// The lookup itself failed, e.g. the name server was unreachable.
+    Io(io::Error),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::InvalidIdentity(identity) => {
+                write!(f, "identity {identity:?} is not a host:port pair")
+            }
+            ResolveError::NotFound(identity) => {
+                write!(f, "identity {identity:?} resolved to no addresses")
+            }
+            ResolveError::Io(e) => write!(f, "failed to resolve remote: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResolveError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub trait Resolver: Send + Sync {
+    /// The addresses to try for `identity`, best first. An empty list is
+    /// reported as [`ResolveError::NotFound`].
+    fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>> {
+        R::resolve(self, identity)
+    }
+}
+
+/// Resolves through the system, with [`tokio::net::lookup_host`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>> {
+        let identity = identity.clone();
+        Box::pin(async move {
+            let target = SocketIdentity::try_from(&identity)
+                .map_err(|_| ResolveError::InvalidIdentity(identity.clone()))?;
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((target.host(), target.port()))
+                .await
+                .map_err(ResolveError::Io)?
+                .collect();
+            if addrs.is_empty() {
+                return Err(ResolveError::NotFound(identity));
+            }
+            Ok(addrs)
+        })
+    }
+}
+
+/// Fixed addresses per identity, for tests and small static deployments.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    entries: HashMap<Identity, Vec<SocketAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_entry(
+        mut self,
+        identity: Identity,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+    ) -> Self {
+        self.entries.insert(identity, addrs.into_iter().collect());
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>> {
+        let result = match self.entries.get(identity) {
+            Some(addrs) if !addrs.is_empty() => Ok(addrs.clone()),
+            _ => Err(ResolveError::NotFound(identity.clone())),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+enum Cached {
+    Found(Vec<SocketAddr>),
+    NotFound,
+}
+
+/// Remembers what another resolver answered: addresses for
+/// [`ttl`](CachingResolver::new), and identities that resolved to nothing for
+/// the shorter `negative_ttl`. Failed lookups are not cached.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    negative_ttl: Duration,
+    clock: Arc<dyn Clock>,
+    cache: Arc<Mutex<HashMap<Identity, (Cached, Duration)>>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            negative_ttl,
+            clock: Arc::new(SystemClock),
+            cache: Arc::default(),
+        }
+    }
+    /// Measure the time to live on `clock` instead of the system clock.
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+    /// Forget every cached answer.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>> {
+        let now = self.clock.monotonic();
+        if let Some((cached, expires)) = self.cache.lock().unwrap().get(identity) {
+            if now < *expires {
+                let result = match cached {
+                    Cached::Found(addrs) => Ok(addrs.clone()),
+                    Cached::NotFound => Err(ResolveError::NotFound(identity.clone())),
+                };
+                return Box::pin(std::future::ready(result));
+            }
+        }
+        let lookup = self.inner.resolve(identity);
+        let cache = self.cache.clone();
+        let identity = identity.clone();
+        let (ttl, negative_ttl) = (self.ttl, self.negative_ttl);
+        Box::pin(async move {
+            let result = lookup.await;
+            let entry = match &result {
+                Ok(addrs) if !addrs.is_empty() => Some((Cached::Found(addrs.clone()), now + ttl)),
+                Ok(_) | Err(ResolveError::NotFound(_)) => {
+                    Some((Cached::NotFound, now + negative_ttl))
+                }
+                Err(_) => None,
+            };
+            if let Some(entry) = entry {
+                cache.lock().unwrap().insert(identity.clone(), entry);
+            }
+            match result {
+                Ok(addrs) if addrs.is_empty() => Err(ResolveError::NotFound(identity)),
+                result => result,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{testing::block_on, ManualClock};
+
+    /// Counts the lookups that reach the resolver it wraps.
+    struct Counting(StaticResolver, Arc<AtomicUsize>);
+
+    impl Resolver for Counting {
+        fn resolve(&self, identity: &Identity) -> BoxFuture<Result<Vec<SocketAddr>, ResolveError>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.resolve(identity)
+        }
+    }
+
+    #[test]
+    fn static_entries_keep_their_addresses_in_order() {
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        let resolver = StaticResolver::new()
+            .with_entry(Identity::new("both"), addrs.clone())
+            .with_entry(Identity::new("empty"), []);
+        assert_eq!(
+            block_on(resolver.resolve(&Identity::new("both"))).unwrap(),
+            addrs
+        );
+        for unknown in ["empty", "missing"] {
+            assert!(matches!(
+                block_on(resolver.resolve(&Identity::new(unknown))),
+                Err(ResolveError::NotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn missing_identities_are_cached_for_the_negative_ttl() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let known = Identity::new("known");
+        let inner =
+            StaticResolver::new().with_entry(known.clone(), ["127.0.0.1:1".parse().unwrap()]);
+        let clock = ManualClock::new(0);
+        let resolver = CachingResolver::new(
+            Counting(inner, lookups.clone()),
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+        )
+        .with_clock(clock.clone());
+        let missing = Identity::new("missing");
+
+        for _ in 0..2 {
+            assert!(block_on(resolver.resolve(&known)).is_ok());
+            assert!(block_on(resolver.resolve(&missing)).is_err());
+        }
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+
+        // the negative answer expires first
+        clock.advance(Duration::from_secs(5));
+        assert!(block_on(resolver.resolve(&known)).is_ok());
+        assert!(block_on(resolver.resolve(&missing)).is_err());
+        assert_eq!(lookups.load(Ordering::Relaxed), 3);
+
+        clock.advance(Duration::from_secs(55));
+        assert!(block_on(resolver.resolve(&known)).is_ok());
+        assert_eq!(lookups.load(Ordering::Relaxed), 4);
+
+        resolver.clear();
+        assert!(block_on(resolver.resolve(&missing)).is_err());
+        assert_eq!(lookups.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,269 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use crate::{
+    ExecutorCapabilities, Identity, Message, MessageStatus, Protocol, ProtocolExecutor,
+    RetryBudget, SendContext, SendOutcome,
+};
+
+/// How many times to try an operation and how long to wait in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Factor the backoff grows by after each failed attempt.
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Self::default()
+        }
+    }
+    pub fn with_backoff(self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            initial_backoff,
+            max_backoff,
+            ..self
+        }
+    }
+    pub fn with_multiplier(self, multiplier: f64) -> Self {
+        Self { multiplier, ..self }
+    }
+    /// Wait before attempt number `attempt + 1`, where the first attempt is `0`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(attempt as i32);
+        self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+    }
+    /// Run `op` until it succeeds or the attempts are used up, returning the last error.
+    pub async fn retry<T, E, F, Fut>(&self, op: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry_if(op, || true).await
+    }
+    /// Like [`RetryPolicy::retry`], but gives up early once `may_retry` says no.
+    pub(crate) async fn retry_if<T, E, F, Fut>(
+        &self,
+        mut op: F,
+        mut may_retry: impl FnMut() -> bool,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt + 1 >= self.max_attempts || !may_retry() => return Err(e),
+                Err(_) => {
+                    tokio::time::sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Retries failed sends on `inner` according to a [`RetryPolicy`].
+///
+/// Every attempt is reported through [`SendContext::record_attempt`], so it shows
+/// up in the [`SendReceipt`](crate::SendReceipt) of the send.
+pub struct RetryingExecutor<E> {
+    inner: Arc<E>,
+    policy: RetryPolicy,
+    budget: Option<Arc<RetryBudget>>,
+}
+
+impl<E: ProtocolExecutor> RetryingExecutor<E> {
+    pub fn new(inner: E, policy: RetryPolicy) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            policy,
+            budget: None,
+        }
+    }
+    /// Only retry as far as `budget` allows, crediting it with the sends that
+    /// succeed; see [`RetryBudget`].
+    pub fn with_budget(self, budget: Arc<RetryBudget>) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+}
+
+/// Whether `budget`, if any, has a retry to `remote` left.
+pub(crate) fn may_retry(budget: Option<&RetryBudget>, remote: &Identity) -> bool {
+    let Some(budget) = budget else {
+        return true;
+    };
+    let granted = budget.try_retry(remote);
+    if !granted {
+        SendContext::record_budget_exhausted();
+    }
+    granted
+}
+
+/// Credit `budget`, if any, with `result` if it is a success.
+pub(crate) fn credit<T, E>(budget: Option<&RetryBudget>, remote: &Identity, result: &Result<T, E>) {
+    if let (Some(budget), Ok(_)) = (budget, result) {
+        budget.record_success(remote);
+    }
+}
+
+impl<E> ProtocolExecutor for RetryingExecutor<E>
+where
+    E: ProtocolExecutor + Send + Sync + 'static,
+{
+    type Error = E::Error;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let budget = self.budget.clone();
+        let remote = remote.clone();
+        async move {
+            let result = policy
+                .retry_if(
+                    || {
+                        SendContext::record_attempt();
+                        inner.send(&remote, message.clone())
+                    },
+                    || may_retry(budget.as_deref(), &remote),
+                )
+                .await;
+            credit(budget.as_deref(), &remote, &result);
+            result
+        }
+    }
+
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let budget = self.budget.clone();
+        let protocol = protocol.clone();
+        let remote = remote.clone();
+        async move {
+            let result = policy
+                .retry_if(
+                    || {
+                        SendContext::record_attempt();
+                        inner.send_via(&protocol, &remote, message.clone())
+                    },
+                    || may_retry(budget.as_deref(), &remote),
+                )
+                .await;
+            credit(budget.as_deref(), &remote, &result);
+            result
+        }
+    }
+
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        self.inner.get_status(remote, message)
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{NodeInstance, SendError};
+
+    fn retrying(recorder: Recorder) -> RetryingExecutor<Recorder> {
+        RetryingExecutor::new(
+            recorder,
+            RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO),
+        )
+    }
+
+    #[tokio::test]
+    async fn failures_keep_their_context_through_the_retries() {
+        let recorder = Recorder::new().failing("down");
+        let node = NodeInstance::new().with_executor(TEST, retrying(recorder));
+        let error = node.send(message(addr("b"), b"x"), addr("b")).await;
+        let Err(SendError::ExecutorError(failure)) = error else {
+            panic!("expected an executor error");
+        };
+        assert_eq!(failure.protocol, TEST);
+        assert_eq!(failure.identity, addr("b").identity.to_string());
+        assert_eq!(failure.attempt, 3);
+        assert_eq!(failure.source.to_string(), "down");
+        assert!(!failure.budget_exhausted);
+    }
+
+    #[tokio::test]
+    async fn failed_send_reports_carry_the_context() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let node = NodeInstance::new()
+            .with_executor(TEST, retrying(Recorder::new().failing("down")))
+            .with_on_send_err({
+                let reports = reports.clone();
+                move |to, error, _| {
+                    if let SendError::ExecutorError(failure) = error {
+                        let context = (failure.identity.clone(), failure.attempt);
+                        reports.lock().unwrap().push((to.clone(), context));
+                    }
+                }
+            });
+        assert!(node.forward(message(addr("b"), b"x")).await.is_err());
+        assert_eq!(
+            *reports.lock().unwrap(),
+            [(addr("b"), (addr("b").identity.to_string(), 3))]
+        );
+    }
+
+    #[tokio::test]
+    async fn later_successes_end_the_retries() {
+        let recorder = Recorder::new().failing_first(2);
+        let node = NodeInstance::new().with_executor(TEST, retrying(recorder.clone()));
+        let receipt = node
+            .send_detailed(message(addr("b"), b"x"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.attempts, 3);
+        assert_eq!(recorder.sent().len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+//! Capping the retries all senders make to a destination together.
+//!
+//! Retry decorators that each retry on their own multiply the load on a peer
+//! that is down: ten callers retrying five times each hit it fifty times. A
+//! [`RetryBudget`] shared by every [`RetryingExecutor`](crate::RetryingExecutor)
+//! [given it](crate::RetryingExecutor::with_budget) grants each destination
+//! identity retries from a token bucket: it refills by
+//! [`min_retries_per_second`](RetryBudgetConfig::min_retries_per_second) on its
+//! own and by [`retry_ratio`](RetryBudgetConfig::retry_ratio) for every
+//! successful send, and each retry takes one token. Once the bucket is empty,
+//! failed sends are not retried but fail right away, with
+//! [`SendFailure::budget_exhausted`](crate::SendFailure::budget_exhausted) set.
+//!
+//! A node [made to keep a budget](NodeInstance::with_retry_budget) hands it out
+//! through [`NodeInstance::retry_budget`], for its executors to share.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::time::Instant;
+
+use crate::{Identity, NodeInstance};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryBudgetConfig {
+    /// Retries each destination gets a second however its sends fare.
+    pub min_retries_per_second: u32,
+    /// Retries each successful send to a destination earns it.
+    pub retry_ratio: f64,
+    /// Most retries a destination can save up.
+    pub max_retries: u32,
+    /// Destinations tracked before the one idle longest is forgotten, and
+    /// starts over with a fresh budget when it is back.
+    pub max_destinations: usize,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            min_retries_per_second: 10,
+            retry_ratio: 0.2,
+            max_retries: 100,
+            max_destinations: 1024,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Retries left to each destination; see the [module docs](self).
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    buckets: Mutex<HashMap<Identity, Bucket>>,
+    skipped: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+            skipped: AtomicU64::new(0),
+        }
+    }
+    pub fn config(&self) -> &RetryBudgetConfig {
+        &self.config
+    }
+    /// Take a retry to `remote` from the budget, if one is left.
+    pub fn try_retry(&self, remote: &Identity) -> bool {
+        let granted = self.update(remote, |bucket| {
+            let granted = bucket.tokens >= 1.0;
+            if granted {
+                bucket.tokens -= 1.0;
+            }
+            granted
+        });
+        if !granted {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        granted
+    }
+    /// Credit a successful send to `remote`.
+    pub fn record_success(&self, remote: &Identity) {
+        let max = self.max_tokens();
+        let ratio = self.config.retry_ratio.max(0.0);
+        self.update(remote, |bucket| {
+            bucket.tokens = (bucket.tokens + ratio).min(max)
+        });
+    }
+    /// Whole retries left to `remote`.
+    pub fn available(&self, remote: &Identity) -> u32 {
+        self.update(remote, |bucket| bucket.tokens as u32)
+    }
+    /// Retries refused so far, across destinations.
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+    fn max_tokens(&self) -> f64 {
+        f64::from(self.config.max_retries.max(1))
+    }
+    /// Run `f` on the bucket of `remote`, refilled up to now.
+    fn update<T>(&self, remote: &Identity, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let now = Instant::now();
+        let max = self.max_tokens();
+        let rate = f64::from(self.config.min_retries_per_second);
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(remote) && buckets.len() >= self.config.max_destinations.max(1) {
+            let idlest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.refilled)
+                .map(|(remote, _)| remote.clone());
+            if let Some(idlest) = idlest {
+                buckets.remove(&idlest);
+            }
+        }
+        let bucket = buckets.entry(remote.clone()).or_insert_with(|| Bucket {
+            // a second's worth to start with
+            tokens: rate.min(max),
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(max);
+        bucket.refilled = now;
+        f(bucket)
+    }
+}
+
+impl NodeInstance {
+    /// Keep a [`RetryBudget`] for the executors of this node to share; see the
+    /// [module docs](self).
+    pub fn with_retry_budget(mut self, config: RetryBudgetConfig) -> Self {
+        self.retry_budget = Some(Arc::new(RetryBudget::new(config)));
+        self
+    }
+    /// The retry budget to [give](crate::RetryingExecutor::with_budget) the
+    /// retrying executors of this node, if it keeps one.
+    pub fn retry_budget(&self) -> Option<Arc<RetryBudget>> {
+        self.retry_budget.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+    use crate::{
+        testing::{addr, message, Recorder, TEST},
+        RetryPolicy, RetryingExecutor, SendError,
+    };
+
+    fn node(recorder: Recorder, budget: &Arc<RetryBudget>) -> Arc<NodeInstance> {
+        let policy = RetryPolicy::new(5).with_backoff(Duration::ZERO, Duration::ZERO);
+        let executor = RetryingExecutor::new(recorder, policy).with_budget(budget.clone());
+        Arc::new(NodeInstance::new().with_executor(TEST, executor))
+    }
+
+    /// Send from `callers` tasks at once, returning the retries the failed
+    /// sends made and how many of them stopped short of the policy's attempts.
+    async fn failing_sends(node: &Arc<NodeInstance>, callers: usize) -> (u32, usize) {
+        let mut sends = JoinSet::new();
+        for _ in 0..callers {
+            let node = node.clone();
+            sends.spawn(async move { node.send(message(addr("b"), b"x"), addr("b")).await });
+        }
+        let (mut retries, mut exhausted) = (0, 0);
+        while let Some(result) = sends.join_next().await {
+            let Err(SendError::ExecutorError(failure)) = result.unwrap() else {
+                panic!("expected an executor error");
+            };
+            retries += failure.attempt - 1;
+            // only the budget stops a send short
+            assert_eq!(failure.budget_exhausted, failure.attempt < 5);
+            exhausted += usize::from(failure.budget_exhausted);
+        }
+        (retries, exhausted)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_the_retries_of_a_destination() {
+        let budget = Arc::new(RetryBudget::new(RetryBudgetConfig {
+            min_retries_per_second: 5,
+            retry_ratio: 0.5,
+            max_retries: 5,
+            ..RetryBudgetConfig::default()
+        }));
+        let down = node(Recorder::new().failing("down"), &budget);
+
+        let (retries, exhausted) = failing_sends(&down, 10).await;
+        assert_eq!(retries, 5);
+        assert!(exhausted >= 9);
+        assert_eq!(budget.available(&addr("b").identity), 0);
+        assert_eq!(budget.available(&addr("c").identity), 5);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(failing_sends(&down, 10).await.0, 5);
+
+        let healthy = node(Recorder::new(), &budget);
+        for _ in 0..4 {
+            healthy
+                .send(message(addr("b"), b"x"), addr("b"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(budget.available(&addr("b").identity), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_destinations_are_forgotten_past_the_limit() {
+        let budget = RetryBudget::new(RetryBudgetConfig {
+            min_retries_per_second: 2,
+            max_destinations: 2,
+            ..RetryBudgetConfig::default()
+        });
+        let (b, c, d) = (addr("b").identity, addr("c").identity, addr("d").identity);
+        assert!(budget.try_retry(&b) && budget.try_retry(&b));
+        assert!(!budget.try_retry(&b));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        budget.available(&c);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        budget.available(&d);
+        // b was idle longest and starts over
+        assert_eq!(budget.buckets.lock().unwrap().len(), 2);
+        assert_eq!(budget.available(&b), 2);
+    }
+}
@@ -0,0 +1,197 @@
+//! NAT-style address rewriting at a cluster boundary.
+//!
+//! Outbound rules hide internal addresses: they rewrite the addresses recorded in
+//! the path of every message this node [forwards](NodeInstance::forward). Inbound
+//! rules map public identities to internal nodes: they rewrite the destination of
+//! messages arriving through [`NodeInstance::dispatch_inbound`]. Each rewrite is
+//! remembered, so a reply to a rewritten address is translated back even without a
+//! matching rule in the other direction.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use crate::{Address, Identity, Message, NodeInstance};
+
+/// Replace the `from` identity prefix under `from`'s protocol with `to`, keeping the
+/// rest of the identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteRule {
+    from: Address,
+    to: Address,
+}
+
+impl RewriteRule {
+    pub fn new(from: Address, to: Address) -> Self {
+        Self { from, to }
+    }
+    pub fn apply(&self, address: &Address) -> Option<Address> {
+        if address.protocol != self.from.protocol {
+            return None;
+        }
+        let suffix = address
+            .identity
+            .as_bytes()
+            .strip_prefix(self.from.identity.as_bytes())?;
+        let mut identity = self.to.identity.as_bytes().to_vec();
+        identity.extend_from_slice(suffix);
+        Some(Address::new(
+            self.to.protocol.clone(),
+            Identity::new(identity),
+        ))
+    }
+}
+
+/// An ordered list of [`RewriteRule`]s; the first matching rule wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RewriteRules {
+    rules: Vec<RewriteRule>,
+}
+
+impl RewriteRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_rule(mut self, from: Address, to: Address) -> Self {
+        self.push(RewriteRule::new(from, to));
+        self
+    }
+    pub fn push(&mut self, rule: RewriteRule) {
+        self.rules.push(rule)
+    }
+    /// Rewrite `address` with the first matching rule, or `None` if no rule matches.
+    pub fn apply(&self, address: &Address) -> Option<Address> {
+        self.rules.iter().find_map(|rule| rule.apply(address))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RewriteState {
+    pub(crate) outbound: RewriteRules,
+    pub(crate) inbound: RewriteRules,
+    /// Public address handed out on the way out → the internal address it replaced.
+    hidden: RwLock<HashMap<Address, Address>>,
+    /// Internal address a message was delivered to → the public address it was sent to.
+    exposed: RwLock<HashMap<Address, Address>>,
+}
+
+impl NodeInstance {
+    pub fn with_outbound_rewrites(mut self, rules: RewriteRules) -> Self {
+        self.rewrites.outbound = rules;
+        self
+    }
+    pub fn with_inbound_rewrites(mut self, rules: RewriteRules) -> Self {
+        self.rewrites.inbound = rules;
+        self
+    }
+    pub(crate) fn rewrite_outbound(&self, message: &mut Message) {
+        let state = &self.rewrites;
+        for address in message
+            .path
+            .iter_mut()
+            .filter_map(|node| node.address.as_mut())
+        {
+            if let Some(public) = state.exposed.read().unwrap().get(address) {
+                *address = public.clone();
+            } else if let Some(public) = state.outbound.apply(address) {
+                let internal = std::mem::replace(address, public.clone());
+                state.hidden.write().unwrap().insert(public, internal);
+            }
+        }
+    }
+    pub(crate) fn rewrite_inbound(&self, message: &mut Message) {
+        let state = &self.rewrites;
+        let destination = &mut message.destination;
+        if let Some(internal) = state.hidden.read().unwrap().get(destination) {
+            *destination = internal.clone();
+        } else if let Some(internal) = state.inbound.apply(destination) {
+            let public = std::mem::replace(destination, internal.clone());
+            state.exposed.write().unwrap().insert(internal, public);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::testing::addr;
+    use crate::virtual_net::VirtualNetwork;
+    use crate::{DataBackend, MemoryBackend, MessageBuilder};
+
+    #[test]
+    fn rules_keep_the_rest_of_the_identity() {
+        let rule = RewriteRule::new(addr("10.0."), addr("public-"));
+        assert_eq!(rule.apply(&addr("10.0.3.7")), Some(addr("public-3.7")));
+        assert_eq!(rule.apply(&addr("10.1.3.7")), None);
+        let other = Address::new(VirtualNetwork::PROTOCOL, Identity::new("10.0.3.7"));
+        assert_eq!(rule.apply(&other), None);
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let rules = RewriteRules::new()
+            .with_rule(addr("10.0.1."), addr("special-"))
+            .with_rule(addr("10.0."), addr("public-"))
+            .with_rule(addr("10.0.2."), addr("never-"));
+        assert_eq!(rules.apply(&addr("10.0.1.5")), Some(addr("special-5")));
+        assert_eq!(rules.apply(&addr("10.0.2.5")), Some(addr("public-2.5")));
+        assert_eq!(rules.apply(&addr("192.168.0.1")), None);
+    }
+
+    fn received(node: crate::NodeInstance, into: &Arc<Mutex<Vec<Message>>>) -> crate::NodeInstance {
+        let into = into.clone();
+        node.with_handler(move |message: Message| {
+            into.lock().unwrap().push(message);
+            async {}
+        })
+    }
+
+    #[tokio::test]
+    async fn external_peers_reach_internal_nodes_by_their_public_identity() {
+        let at = VirtualNetwork::address;
+        let to_gateway = |destination: &str| {
+            let routes = MemoryBackend::new();
+            let destination = at(destination);
+            async move {
+                routes
+                    .set_next(&destination, Some(&at("gw")))
+                    .await
+                    .unwrap();
+                routes
+            }
+        };
+        let (outside_routes, inside_routes) =
+            (to_gateway("pub-int").await, to_gateway("ext").await);
+        let (outside, inside) = Default::default();
+        let mut net = VirtualNetwork::new();
+        let ext = net.add_node("ext", |node| {
+            received(node, &outside).with_backend(outside_routes)
+        });
+        net.add_node("gw", |node| {
+            node.with_inbound_rewrites(RewriteRules::new().with_rule(at("pub-"), at("")))
+                .with_outbound_rewrites(RewriteRules::new().with_rule(at("int"), at("pub-int")))
+        });
+        let int = net.add_node("int", |node| {
+            received(node, &inside).with_backend(inside_routes)
+        });
+
+        let mut request = MessageBuilder::new(at("pub-int")).payload("ping").build();
+        ext.mark(at("ext"), &mut request);
+        ext.forward(request).await.unwrap();
+        net.run_until_idle(10).await;
+        let request = inside.lock().unwrap().pop().expect("delivered inside");
+        assert_eq!(request.destination, at("int"));
+        assert_eq!(request.path[0].address, Some(at("ext")));
+
+        let mut reply = MessageBuilder::new(request.path[0].address.clone().unwrap())
+            .payload("pong")
+            .build();
+        int.mark(at("int"), &mut reply);
+        int.forward(reply).await.unwrap();
+        net.run_until_idle(10).await;
+        let reply = outside.lock().unwrap().pop().expect("delivered outside");
+        assert_eq!(reply.payload, b"pong");
+        let path: Vec<_> = reply.path.iter().map(|node| node.address.clone()).collect();
+        assert_eq!(path, [Some(at("pub-int")), Some(at("gw"))]);
+    }
+}
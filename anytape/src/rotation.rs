@@ -0,0 +1,265 @@
+//! Keeping a peer recognizable while it moves to a new identity.
+//!
+//! A peer rotating its identity announces it with a
+//! [`ControlMessage::IdentityRotation`] whose payload it signs with the key of the
+//! old identity, putting the signature in the envelope's `signature`. With
+//! [identity rotation](NodeInstance::with_identity_rotation) on, a node checks
+//! that signature and then treats the old identity as an alias of the new one
+//! until `valid_until`:
+//!
+//! - messages for the old identity take the route to the new one;
+//! - quota usage of the old identity is merged into the new one's, and traffic
+//!   of either counts against the new one;
+//! - peer scores and quarantines, content deduplication and address book names
+//!   apply to both as one.
+//!
+//! Once the grace period ends the old identity stands for itself again. Aliases
+//! are stored in the [`DataBackend`](crate::DataBackend) if it
+//! [supports them](crate::DataBackend::set_alias), so nodes sharing a backend
+//! or restarting pick them up. A principal has at most
+//! [`RotationConfig::max_aliases`] aliases; further rotations drop the ones
+//! closest to expiring.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{Address, AuditKind, Identity, Message, MessageStatus, NodeInstance, RejectReason};
+
+/// An identity standing in for `new` until `valid_until`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityAlias {
+    pub new: Identity,
+    /// Unix time in milliseconds.
+    pub valid_until: u64,
+}
+
+impl IdentityAlias {
+    fn is_live(&self, now_millis: u64) -> bool {
+        now_millis < self.valid_until
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RotationConfig {
+    /// Old identities one principal may have at a time.
+    pub max_aliases: usize,
+    /// The longest grace period accepted; later `valid_until`s are cut short.
+    pub max_grace: Duration,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_aliases: 4,
+            max_grace: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Checks `signature` of `payload` against the key of `old`.
+type RotationVerifier = dyn Fn(&Identity, &[u8], &[u8]) -> bool + Send + Sync;
+
+#[derive(Default)]
+pub(crate) struct RotationState {
+    settings: Option<(RotationConfig, Arc<RotationVerifier>)>,
+    aliases: RwLock<HashMap<Identity, IdentityAlias>>,
+}
+
+impl NodeInstance {
+    /// Accept identity rotations whose signature `verify` confirms, given the old
+    /// identity, the signed payload and the signature; see the
+    /// [module docs](self).
+    pub fn with_identity_rotation(
+        mut self,
+        config: RotationConfig,
+        verify: impl Fn(&Identity, &[u8], &[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.rotations.settings = Some((config, Arc::new(verify)));
+        self
+    }
+    /// The identity `identity` currently stands in for, if it is a rotated one.
+    pub fn alias_of(&self, identity: &Identity) -> Option<Identity> {
+        let now = self.clock.now_millis();
+        let aliases = self.rotations.aliases.read().unwrap();
+        let alias = aliases.get(identity)?;
+        alias.is_live(now).then(|| alias.new.clone())
+    }
+    pub(crate) fn rotation_enabled(&self) -> bool {
+        self.rotations.settings.is_some()
+    }
+    /// The identity traffic of `identity` is accounted to.
+    pub(crate) fn principal<'a>(&self, identity: &'a Identity) -> Cow<'a, Identity> {
+        if !self.rotation_enabled() {
+            return Cow::Borrowed(identity);
+        }
+        match self.alias_of(identity) {
+            Some(new) => Cow::Owned(new),
+            None => Cow::Borrowed(identity),
+        }
+    }
+    /// `address` with its identity replaced by the one it stands in for.
+    pub(crate) fn aliased<'a>(&self, address: &'a Address) -> Cow<'a, Address> {
+        match self.principal(&address.identity) {
+            Cow::Owned(identity) => Cow::Owned(Address {
+                protocol: address.protocol.clone(),
+                identity,
+            }),
+            Cow::Borrowed(_) => Cow::Borrowed(address),
+        }
+    }
+    /// Look for an alias of `identity` the backend knows but this node does not.
+    pub(crate) async fn load_alias(&self, identity: &Identity) -> Option<Identity> {
+        self.rotations.settings.as_ref()?;
+        let backend = self.backend.as_ref()?;
+        let alias = backend.get_alias(identity).await.ok()??;
+        if !alias.is_live(self.clock.now_millis()) {
+            return None;
+        }
+        let new = alias.new.clone();
+        self.install_alias(identity.clone(), alias);
+        Some(new)
+    }
+    /// Handle a rotation from `old` to `new`, carried by `envelope`.
+    pub(crate) async fn apply_rotation(
+        &self,
+        old: &Identity,
+        new: &Identity,
+        valid_until: u64,
+        envelope: &Message,
+    ) -> MessageStatus {
+        let Some((config, verify)) = &self.rotations.settings else {
+            return MessageStatus::Rejected {
+                reason: RejectReason::NoHandler,
+            };
+        };
+        if !verify(old, &envelope.payload, &envelope.signature) {
+            self.audit(
+                self.audit_event(
+                    AuditKind::SignatureFailure,
+                    "identity rotation not verified",
+                )
+                .unique_id(envelope.unique_id)
+                .identity(old),
+            );
+            return MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature,
+            };
+        }
+        let now = self.clock.now_millis();
+        let max_until = now.saturating_add(config.max_grace.as_millis() as u64);
+        let alias = IdentityAlias {
+            new: new.clone(),
+            valid_until: valid_until.min(max_until),
+        };
+        if old == new || !alias.is_live(now) {
+            return MessageStatus::Received;
+        }
+        if let Some(quotas) = &self.quotas {
+            quotas.merge(old, new);
+        }
+        self.install_alias(old.clone(), alias.clone());
+        if let Some(backend) = &self.backend {
+            // the alias still holds on this node if it cannot be stored
+            let _ = backend.set_alias(old, Some(&alias)).await;
+        }
+        MessageStatus::Received
+    }
+    fn install_alias(&self, old: Identity, alias: IdentityAlias) {
+        let Some((config, _)) = &self.rotations.settings else {
+            return;
+        };
+        let now = self.clock.now_millis();
+        let mut aliases = self.rotations.aliases.write().unwrap();
+        aliases.retain(|_, alias| alias.is_live(now));
+        // rotating back to an old identity makes it a principal again
+        aliases.remove(&alias.new);
+        // identities that stood in for `old` now stand in for its successor
+        for earlier in aliases.values_mut() {
+            if earlier.new == old {
+                earlier.new = alias.new.clone();
+            }
+        }
+        aliases.insert(old, alias.clone());
+        let mut held: Vec<_> = aliases
+            .iter()
+            .filter(|(_, held)| held.new == alias.new)
+            .map(|(identity, held)| (held.valid_until, identity.clone()))
+            .collect();
+        if held.len() > config.max_aliases {
+            held.sort_by_key(|(valid_until, _)| *valid_until);
+            for (_, identity) in held.drain(..held.len() - config.max_aliases) {
+                aliases.remove(&identity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        control::ControlMessage,
+        testing::{addr, message, Recorder, TEST},
+        ManualClock, QuotaManager,
+    };
+
+    fn rotation(signature: &[u8]) -> Message {
+        let mut message = ControlMessage::IdentityRotation {
+            old: Identity::new("old"),
+            new: Identity::new("new"),
+            valid_until: 60_000,
+        }
+        .into_message(addr("me"));
+        message.signature = signature.to_vec();
+        message
+    }
+
+    #[tokio::test]
+    async fn old_identities_stand_in_for_new_ones_until_the_grace_period_ends() {
+        let clock = ManualClock::new(0);
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, recorder.clone())
+            .with_clock(clock.clone())
+            .with_quota_manager(QuotaManager::new(
+                Duration::from_secs(600),
+                Duration::from_secs(1),
+            ))
+            .with_identity_rotation(RotationConfig::default(), |old, _, signature| {
+                old == &Identity::new("old") && signature == b"signed by old"
+            });
+        let (old, new) = (Identity::new("old"), Identity::new("new"));
+        node.forward(message(addr("old"), b"before")).await.unwrap();
+
+        let rejected = node.dispatch_inbound(rotation(b"forged"), addr("me")).await;
+        assert!(matches!(
+            rejected,
+            Ok(MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature
+            })
+        ));
+        assert_eq!(node.alias_of(&old), None);
+        let accepted = node
+            .dispatch_inbound(rotation(b"signed by old"), addr("me"))
+            .await;
+        assert!(matches!(accepted, Ok(MessageStatus::Received)));
+        assert_eq!(node.alias_of(&old), Some(new.clone()));
+
+        // the old identity's usage moved over to the new one
+        let quotas = node.quotas().unwrap();
+        assert_eq!(quotas.usage(&new).messages_sent, 1);
+        node.forward(message(addr("old"), b"during")).await.unwrap();
+        assert_eq!(quotas.usage(&new).messages_sent, 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(node.alias_of(&old), None);
+        node.forward(message(addr("old"), b"after")).await.unwrap();
+        assert_eq!(recorder.remotes(), [old.clone(), new, old]);
+    }
+}
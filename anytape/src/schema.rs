@@ -0,0 +1,525 @@
+//! Checking payloads against the schema their content type promises.
+//!
+//! The [`SchemaRegistry`] of a node maps values of [`CONTENT_TYPE_HEADER`] to
+//! [`PayloadSchema`]s. Messages the node sends are checked before they are
+//! handed to an executor, relayed ones included, and messages for this node are
+//! checked once reassembled, before the [`ReceiveHandler`](crate::ReceiveHandler)
+//! sees them. What happens to a payload that does not match is up to the
+//! [`SchemaMode`] of each direction: a rejected send fails with
+//! [`SendError::SchemaViolation`], a rejected message is dropped with
+//! [`RejectReason::SchemaViolation`]. Every violation found is published as a
+//! [`NodeEvent::SchemaViolation`] with the validator's error, whether or not the
+//! message went on.
+//!
+//! Messages without a content type, fragments and control messages are never
+//! checked. A content type without a schema is let through or counts as a
+//! violation, as [`SchemaPolicy::allow_unknown`] says.
+//!
+//! [`ByteSchema`] checks lengths and magic bytes; with the `json-schema`
+//! feature, `JsonSchema` checks JSON documents. Both can be declared in a
+//! [`SchemaConfig`], which a running node [reloads](NodeInstance::reload_schemas)
+//! without a restart.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::{
+    control::CONTROL_HEADER, Message, MessageStatus, NodeEvent, NodeInstance, RejectReason,
+    SendError, FRAGMENT_HEADER,
+};
+
+/// Names the content type of a payload, e.g. a
+/// [`PayloadFormat`](crate::PayloadFormat) or a key of the [`SchemaRegistry`].
+pub const CONTENT_TYPE_HEADER: &str = "anytape-content-type";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// The content type has no schema and the policy does not
+    /// [allow](SchemaPolicy::allow_unknown) that.
+    UnknownContentType(Vec<u8>),
+    /// The payload does not match its schema, for the reason given.
+    Invalid(String),
+    /// A schema could not be built from its declaration, for the reason given.
+    MalformedSchema(String),
+}
+
+impl SchemaError {
+    pub fn invalid(detail: impl Into<String>) -> Self {
+        SchemaError::Invalid(detail.into())
+    }
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::UnknownContentType(content_type) => write!(
+                f,
+                "no schema for content type {:?}",
+                String::from_utf8_lossy(content_type)
+            ),
+            SchemaError::Invalid(detail) => {
+                write!(f, "payload does not match its schema: {detail}")
+            }
+            SchemaError::MalformedSchema(detail) => write!(f, "malformed schema: {detail}"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// What the payloads of a content type must look like.
+pub trait PayloadSchema: Send + Sync {
+    fn validate(&self, payload: &[u8]) -> Result<(), SchemaError>;
+}
+
+/// Payloads of bounded length that start with the given magic bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ByteSchema {
+    pub min_len: usize,
+    pub max_len: Option<usize>,
+    /// What every payload starts with; empty for anything.
+    pub magic: Vec<u8>,
+}
+
+impl ByteSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_len(self, min_len: usize, max_len: Option<usize>) -> Self {
+        Self {
+            min_len,
+            max_len,
+            ..self
+        }
+    }
+    pub fn with_magic(self, magic: impl Into<Vec<u8>>) -> Self {
+        Self {
+            magic: magic.into(),
+            ..self
+        }
+    }
+}
+
+impl PayloadSchema for ByteSchema {
+    fn validate(&self, payload: &[u8]) -> Result<(), SchemaError> {
+        if payload.len() < self.min_len {
+            return Err(SchemaError::invalid(format!(
+                "{} bytes, expected at least {}",
+                payload.len(),
+                self.min_len
+            )));
+        }
+        if let Some(max_len) = self.max_len.filter(|max_len| payload.len() > *max_len) {
+            return Err(SchemaError::invalid(format!(
+                "{} bytes, expected at most {max_len}",
+                payload.len()
+            )));
+        }
+        if !payload.starts_with(&self.magic) {
+            return Err(SchemaError::invalid("magic bytes do not match"));
+        }
+        Ok(())
+    }
+}
+
+/// What to do with a payload that does not match its schema.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SchemaMode {
+    /// Fail the send, or drop the received message.
+    #[default]
+    Reject,
+    /// Publish the violation, but send or deliver the message anyway.
+    Warn,
+    /// Do not check at all.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SchemaPolicy {
+    /// For messages this node sends or relays.
+    pub outbound: SchemaMode,
+    /// For messages delivered to this node.
+    pub inbound: SchemaMode,
+    /// Whether content types without a schema pass unchecked, rather than
+    /// count as a [violation](SchemaError::UnknownContentType).
+    pub allow_unknown: bool,
+}
+
+impl Default for SchemaPolicy {
+    fn default() -> Self {
+        Self {
+            outbound: SchemaMode::Reject,
+            inbound: SchemaMode::Reject,
+            allow_unknown: true,
+        }
+    }
+}
+
+/// A schema that can be written down in a [`SchemaConfig`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SchemaSpec {
+    Bytes(ByteSchema),
+    #[cfg(feature = "json-schema")]
+    Json(crate::JsonSchema),
+}
+
+impl SchemaSpec {
+    fn build(&self) -> Arc<dyn PayloadSchema> {
+        match self {
+            SchemaSpec::Bytes(schema) => Arc::new(schema.clone()),
+            #[cfg(feature = "json-schema")]
+            SchemaSpec::Json(schema) => Arc::new(schema.clone()),
+        }
+    }
+}
+
+/// The policy and the declared schemas of a [`SchemaRegistry`], keyed by
+/// content type.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SchemaConfig {
+    pub policy: SchemaPolicy,
+    pub schemas: BTreeMap<String, SchemaSpec>,
+}
+
+struct Registered {
+    schema: Arc<dyn PayloadSchema>,
+    /// Whether it came from a [`SchemaConfig`], and goes with the next one.
+    configured: bool,
+}
+
+#[derive(Default)]
+struct Schemas {
+    policy: SchemaPolicy,
+    by_content_type: HashMap<Vec<u8>, Registered>,
+}
+
+/// The schemas of a node, by content type; see the [module docs](self).
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: RwLock<Schemas>,
+    violations: AtomicU64,
+}
+
+impl SchemaRegistry {
+    /// Check payloads of `content_type` against `schema` from now on, in place
+    /// of the schema it had. Reloading a [`SchemaConfig`] keeps it unless the
+    /// config declares the same content type.
+    pub fn register(&self, content_type: impl Into<Vec<u8>>, schema: impl PayloadSchema + 'static) {
+        self.schemas.write().unwrap().by_content_type.insert(
+            content_type.into(),
+            Registered {
+                schema: Arc::new(schema),
+                configured: false,
+            },
+        );
+    }
+    /// Stop checking payloads of `content_type`, returning whether it had a schema.
+    pub fn remove(&self, content_type: &[u8]) -> bool {
+        self.schemas
+            .write()
+            .unwrap()
+            .by_content_type
+            .remove(content_type)
+            .is_some()
+    }
+    pub fn contains(&self, content_type: &[u8]) -> bool {
+        self.schemas
+            .read()
+            .unwrap()
+            .by_content_type
+            .contains_key(content_type)
+    }
+    pub fn policy(&self) -> SchemaPolicy {
+        self.schemas.read().unwrap().policy
+    }
+    pub fn set_policy(&self, policy: SchemaPolicy) {
+        self.schemas.write().unwrap().policy = policy;
+    }
+    /// Take the policy and schemas of `config`, dropping those the previous
+    /// config declared.
+    pub fn apply(&self, config: &SchemaConfig) {
+        let mut schemas = self.schemas.write().unwrap();
+        schemas.policy = config.policy;
+        schemas
+            .by_content_type
+            .retain(|_, registered| !registered.configured);
+        for (content_type, spec) in &config.schemas {
+            schemas.by_content_type.insert(
+                content_type.as_bytes().to_vec(),
+                Registered {
+                    schema: spec.build(),
+                    configured: true,
+                },
+            );
+        }
+    }
+    /// Check `payload` against the schema of `content_type`, under the
+    /// [unknown content type](SchemaPolicy::allow_unknown) policy.
+    pub fn validate(&self, content_type: &[u8], payload: &[u8]) -> Result<(), SchemaError> {
+        let (schema, allow_unknown) = {
+            let schemas = self.schemas.read().unwrap();
+            let schema = schemas
+                .by_content_type
+                .get(content_type)
+                .map(|registered| registered.schema.clone());
+            (schema, schemas.policy.allow_unknown)
+        };
+        match schema {
+            Some(schema) => schema.validate(payload),
+            None if allow_unknown => Ok(()),
+            None => Err(SchemaError::UnknownContentType(content_type.to_vec())),
+        }
+    }
+    /// Violations found so far, in either direction and whatever the mode.
+    pub fn violations(&self) -> u64 {
+        self.violations.load(Ordering::Relaxed)
+    }
+    /// The violation of `message`, if it is checked under `mode` and does not
+    /// match.
+    fn check(&self, message: &Message, mode: SchemaMode) -> Option<SchemaError> {
+        if mode == SchemaMode::Skip
+            || message.headers.contains_key(FRAGMENT_HEADER)
+            || message.headers.contains_key(CONTROL_HEADER)
+        {
+            return None;
+        }
+        let content_type = message.headers.get(CONTENT_TYPE_HEADER)?;
+        let error = self.validate(content_type, &message.payload).err()?;
+        self.violations.fetch_add(1, Ordering::Relaxed);
+        Some(error)
+    }
+}
+
+impl NodeInstance {
+    /// Check payloads of `content_type` against `schema`; see the
+    /// [module docs](self).
+    pub fn with_schema(
+        self,
+        content_type: impl Into<Vec<u8>>,
+        schema: impl PayloadSchema + 'static,
+    ) -> Self {
+        self.schemas.register(content_type, schema);
+        self
+    }
+    pub fn with_schema_policy(self, policy: SchemaPolicy) -> Self {
+        self.schemas.set_policy(policy);
+        self
+    }
+    /// The schemas and policy of this node, to change while it runs.
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+    /// Take the policy and schemas of `config` while running, as
+    /// [`NodeConfig::schemas`](crate::NodeConfig::schemas) reloaded; schemas
+    /// [registered](SchemaRegistry::register) in code stay unless `config`
+    /// declares their content type.
+    pub fn reload_schemas(&self, config: &SchemaConfig) {
+        self.schemas.apply(config);
+    }
+    /// Check `message` against its schema before it is sent.
+    pub(crate) fn screen_outbound_schema(&self, message: &Message) -> Result<(), SendError> {
+        let mode = self.schemas.policy().outbound;
+        let Some(error) = self.schemas.check(message, mode) else {
+            return Ok(());
+        };
+        let dropped = mode == SchemaMode::Reject;
+        self.publish(|| NodeEvent::SchemaViolation {
+            unique_id: message.unique_id,
+            error: error.clone(),
+            inbound: false,
+            dropped,
+        });
+        if dropped {
+            return Err(SendError::SchemaViolation(error));
+        }
+        Ok(())
+    }
+    /// Check `message`, delivered to this node, against its schema, returning
+    /// the status to drop it with if it may not be delivered.
+    pub(crate) fn screen_inbound_schema(&self, message: &Message) -> Option<MessageStatus> {
+        let mode = self.schemas.policy().inbound;
+        let error = self.schemas.check(message, mode)?;
+        let dropped = mode == SchemaMode::Reject;
+        self.publish(|| NodeEvent::SchemaViolation {
+            unique_id: message.unique_id,
+            error,
+            inbound: true,
+            dropped,
+        });
+        dropped.then_some(MessageStatus::Rejected {
+            reason: RejectReason::SchemaViolation,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        testing::{addr, Recorder, TEST},
+        MessageBuilder,
+    };
+
+    const PNG: &[u8] = b"image/png";
+
+    fn typed(content_type: &[u8], payload: &[u8]) -> Message {
+        MessageBuilder::new(addr("b"))
+            .header(CONTENT_TYPE_HEADER, content_type)
+            .payload(payload)
+            .build()
+    }
+
+    /// A node at `b` that keeps what it is handed, checking PNG magic bytes.
+    fn png_node(recorder: &Recorder) -> (NodeInstance, Arc<Mutex<Vec<Message>>>) {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let node = NodeInstance::new()
+            .with_address(addr("b"))
+            .with_executor(TEST, recorder.clone())
+            .with_schema(PNG, ByteSchema::new().with_magic(b"\x89PNG"))
+            .with_handler({
+                let delivered = delivered.clone();
+                move |message: Message| {
+                    delivered.lock().unwrap().push(message);
+                    async {}
+                }
+            });
+        (node, delivered)
+    }
+
+    #[test]
+    fn byte_schemas_check_length_and_magic() {
+        let schema = ByteSchema::new().with_len(4, Some(8)).with_magic(b"AT");
+        assert_eq!(schema.validate(b"ATxx"), Ok(()));
+        for payload in [&b"AT"[..], b"ATxxxxxxx", b"xxAT"] {
+            assert!(matches!(
+                schema.validate(payload),
+                Err(SchemaError::Invalid(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatched_payloads_are_rejected_both_ways() {
+        let recorder = Recorder::new();
+        let (node, delivered) = png_node(&recorder);
+        let mut events = node.events();
+
+        let result = node.send(typed(PNG, b"GIF89a"), addr("c")).await;
+        assert!(matches!(
+            result,
+            Err(SendError::SchemaViolation(SchemaError::Invalid(_)))
+        ));
+        assert!(recorder.sent().is_empty());
+        let status = node
+            .dispatch_inbound(typed(PNG, b"GIF89a"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            status,
+            MessageStatus::Rejected {
+                reason: RejectReason::SchemaViolation
+            }
+        );
+        assert!(delivered.lock().unwrap().is_empty());
+        for inbound in [false, true] {
+            let event = events.next_event().await.unwrap().event;
+            assert!(matches!(
+                event,
+                NodeEvent::SchemaViolation { inbound: seen, dropped: true, .. } if seen == inbound
+            ));
+        }
+
+        node.send(typed(PNG, b"\x89PNG..."), addr("c"))
+            .await
+            .unwrap();
+        node.send(
+            MessageBuilder::new(addr("c")).payload("untyped").build(),
+            addr("c"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(recorder.sent().len(), 2);
+        assert_eq!(node.schemas().violations(), 2);
+    }
+
+    #[tokio::test]
+    async fn warn_mode_lets_violations_through() {
+        let recorder = Recorder::new();
+        let (node, delivered) = png_node(&recorder);
+        let node = node.with_schema_policy(SchemaPolicy {
+            outbound: SchemaMode::Warn,
+            inbound: SchemaMode::Warn,
+            ..SchemaPolicy::default()
+        });
+        let mut events = node.events();
+        node.send(typed(PNG, b"GIF89a"), addr("c")).await.unwrap();
+        let status = node
+            .dispatch_inbound(typed(PNG, b"GIF89a"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(status, MessageStatus::Received);
+        assert_eq!(recorder.sent().len(), 1);
+        assert_eq!(delivered.lock().unwrap().len(), 1);
+        assert!(matches!(
+            events.next_event().await.unwrap().event,
+            NodeEvent::SchemaViolation { dropped: false, .. }
+        ));
+        assert_eq!(node.schemas().violations(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_content_types_pass_only_if_allowed() {
+        let recorder = Recorder::new();
+        let (node, _) = png_node(&recorder);
+        node.send(typed(b"text/plain", b"hi"), addr("c"))
+            .await
+            .unwrap();
+        node.schemas().set_policy(SchemaPolicy {
+            allow_unknown: false,
+            ..SchemaPolicy::default()
+        });
+        let result = node.send(typed(b"text/plain", b"hi"), addr("c")).await;
+        assert!(matches!(
+            result,
+            Err(SendError::SchemaViolation(SchemaError::UnknownContentType(content_type)))
+                if content_type == b"text/plain"
+        ));
+        assert_eq!(recorder.sent().len(), 1);
+    }
+
+    #[test]
+    fn reloading_a_config_keeps_schemas_registered_in_code() {
+        let registry = SchemaRegistry::default();
+        registry.register(PNG, ByteSchema::new().with_magic(b"\x89PNG"));
+        let config = |content_type: &str| SchemaConfig {
+            schemas: [(
+                content_type.to_owned(),
+                SchemaSpec::Bytes(ByteSchema::new().with_len(1, None)),
+            )]
+            .into(),
+            ..SchemaConfig::default()
+        };
+        registry.apply(&config("text/plain"));
+        assert!(registry.contains(PNG) && registry.contains(b"text/plain"));
+        registry.apply(&config("text/csv"));
+        assert!(registry.contains(PNG) && registry.contains(b"text/csv"));
+        assert!(!registry.contains(b"text/plain"));
+    }
+}
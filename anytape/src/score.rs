@@ -0,0 +1,313 @@
+//! Automatic deprioritization of misbehaving peers.
+//!
+//! Every [`Offense`] a peer commits adds its weight to the peer's score, which
+//! halves every [`PeerScoreConfig::half_life`]. A peer whose score reaches the
+//! threshold is quarantined: its inbound messages are dropped with
+//! [`RejectReason::Quarantined`] and it is not chosen as a
+//! [route candidate](NodeInstance::add_route_candidate) until the quarantine ends.
+//!
+//! Peers are identified by the identity of a message's origin, the first address
+//! in its path.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{Identity, Message, MessageStatus, NodeEvent, NodeInstance, RejectReason, SendError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offense {
+    /// A message that could not be decoded or processed.
+    Malformed,
+    /// A message that failed authentication.
+    InvalidSignature,
+    /// A message seen before.
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerScoreConfig {
+    pub malformed_weight: f64,
+    pub invalid_signature_weight: f64,
+    pub replay_weight: f64,
+    /// Score at which a peer is quarantined.
+    pub threshold: f64,
+    pub half_life: Duration,
+    pub quarantine: Duration,
+}
+
+impl Default for PeerScoreConfig {
+    fn default() -> Self {
+        Self {
+            malformed_weight: 1.0,
+            invalid_signature_weight: 5.0,
+            replay_weight: 2.0,
+            threshold: 10.0,
+            half_life: Duration::from_secs(60),
+            quarantine: Duration::from_secs(300),
+        }
+    }
+}
+
+impl PeerScoreConfig {
+    fn weight(&self, offense: Offense) -> f64 {
+        match offense {
+            Offense::Malformed => self.malformed_weight,
+            Offense::InvalidSignature => self.invalid_signature_weight,
+            Offense::Replay => self.replay_weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    score: f64,
+    scored_at: Duration,
+    quarantined_until: Option<Duration>,
+}
+
+impl Peer {
+    fn decayed(&self, now: Duration, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_sub(self.scored_at).as_secs_f64();
+        self.score * 0.5f64.powf(elapsed / half_life.as_secs_f64().max(f64::MIN_POSITIVE))
+    }
+}
+
+pub(crate) struct PeerScores {
+    config: PeerScoreConfig,
+    peers: Mutex<HashMap<Identity, Peer>>,
+    dropped: AtomicU64,
+}
+
+impl PeerScores {
+    /// The quarantined peers at `now`: identity, score and when it ends.
+    pub(crate) fn quarantined(&self, now: Duration) -> Vec<(Identity, f64, Duration)> {
+        let half_life = self.config.half_life;
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .filter_map(|(identity, peer)| {
+                let until = peer.quarantined_until.filter(|until| now < *until)?;
+                Some((identity.clone(), peer.decayed(now, half_life), until))
+            })
+            .collect()
+    }
+    fn is_quarantined(&self, identity: &Identity, now: Duration) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(identity)
+            .and_then(|peer| peer.quarantined_until)
+            .is_some_and(|until| now < until)
+    }
+}
+
+fn origin(message: &Message) -> Option<Identity> {
+    Some(message.path.first()?.address.as_ref()?.identity.clone())
+}
+
+impl NodeInstance {
+    /// Score peers by the offenses they commit and quarantine the worst; see the
+    /// [module docs](self).
+    pub fn with_peer_scoring(mut self, config: PeerScoreConfig) -> Self {
+        self.peer_scores = Some(PeerScores {
+            config,
+            peers: Mutex::default(),
+            dropped: AtomicU64::new(0),
+        });
+        self
+    }
+    /// Count `offense` against `identity`, e.g. from application level validation.
+    /// Does nothing unless [peer scoring](NodeInstance::with_peer_scoring) is on.
+    pub fn record_offense(&self, identity: &Identity, offense: Offense) {
+        let Some(scores) = &self.peer_scores else {
+            return;
+        };
+        let identity = &*self.principal(identity);
+        let now = self.clock.monotonic();
+        let config = &scores.config;
+        let mut peers = scores.peers.lock().unwrap();
+        let peer = peers.entry(identity.clone()).or_insert(Peer {
+            score: 0.0,
+            scored_at: now,
+            quarantined_until: None,
+        });
+        peer.score = peer.decayed(now, config.half_life) + config.weight(offense);
+        peer.scored_at = now;
+        let quarantined = peer.quarantined_until.is_some_and(|until| now < until);
+        if !quarantined && peer.score >= config.threshold {
+            peer.quarantined_until = Some(now + config.quarantine);
+            let score = peer.score;
+            self.publish(|| NodeEvent::PeerQuarantined {
+                identity: identity.clone(),
+                score,
+            });
+        }
+    }
+    /// Current, decayed score of every peer with one.
+    pub fn peer_scores(&self) -> Vec<(Identity, f64)> {
+        let Some(scores) = &self.peer_scores else {
+            return Vec::new();
+        };
+        let now = self.clock.monotonic();
+        scores
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(identity, peer)| (identity.clone(), peer.decayed(now, scores.config.half_life)))
+            .collect()
+    }
+    /// Peers currently in quarantine.
+    pub fn quarantined(&self) -> Vec<Identity> {
+        let Some(scores) = &self.peer_scores else {
+            return Vec::new();
+        };
+        let now = self.clock.monotonic();
+        scores
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, peer)| peer.quarantined_until.is_some_and(|until| now < until))
+            .map(|(identity, _)| identity.clone())
+            .collect()
+    }
+    pub fn is_quarantined(&self, identity: &Identity) -> bool {
+        self.peer_scores.as_ref().is_some_and(|scores| {
+            scores.is_quarantined(&self.principal(identity), self.clock.monotonic())
+        })
+    }
+    /// Forget the score of `identity` and lift its quarantine.
+    pub fn pardon(&self, identity: &Identity) {
+        if let Some(scores) = &self.peer_scores {
+            scores
+                .peers
+                .lock()
+                .unwrap()
+                .remove(&*self.principal(identity));
+        }
+    }
+    /// Inbound messages dropped because their origin was quarantined.
+    pub fn quarantine_drops(&self) -> u64 {
+        self.peer_scores
+            .as_ref()
+            .map_or(0, |scores| scores.dropped.load(Ordering::Relaxed))
+    }
+    /// The rejection for a message from a quarantined origin.
+    pub(crate) fn screen_origin(&self, message: &Message) -> Option<MessageStatus> {
+        let scores = self.peer_scores.as_ref()?;
+        let origin = origin(message)?;
+        if !scores.is_quarantined(&self.principal(&origin), self.clock.monotonic()) {
+            return None;
+        }
+        scores.dropped.fetch_add(1, Ordering::Relaxed);
+        Some(MessageStatus::Rejected {
+            reason: RejectReason::Quarantined,
+        })
+    }
+    /// Count the offense, if any, that the outcome of dispatching a message from
+    /// `origin` reveals.
+    pub(crate) fn score_inbound(
+        &self,
+        origin: Option<&Identity>,
+        result: &Result<MessageStatus, SendError>,
+    ) {
+        let Some(origin) = origin.filter(|_| self.peer_scores.is_some()) else {
+            return;
+        };
+        let offense = match result {
+            Ok(MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature,
+            }) => Offense::InvalidSignature,
+            Err(SendError::Onion(_) | SendError::Stream(_) | SendError::MalformedFragment) => {
+                Offense::Malformed
+            }
+            _ => return,
+        };
+        self.record_offense(origin, offense)
+    }
+    pub(crate) fn origin_identity(&self, message: &Message) -> Option<Identity> {
+        self.peer_scores.as_ref().and_then(|_| origin(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{Address, GroupControl, ManualClock, Protocol, RouteSelection};
+
+    fn node(clock: &ManualClock, recorder: &Recorder) -> NodeInstance {
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, recorder.clone())
+            .with_handler(|_| async {})
+            .with_clock(clock.clone())
+            .with_peer_scoring(PeerScoreConfig::default())
+            .with_route_selection(RouteSelection::LowestCost { exploration: 0.0 });
+        node.add_route_candidate(addr("dest"), addr("bad"));
+        node.add_route_candidate(addr("dest"), addr("good"));
+        node
+    }
+
+    /// Have `node` receive a group control message from `origin`, which no one
+    /// is authorized to send.
+    async fn forged_control(node: &NodeInstance, origin: &str) -> MessageStatus {
+        let group = Address::new(Protocol::GROUP, Identity::new("g"));
+        let mut control = GroupControl::Add {
+            group,
+            member: addr(origin),
+        }
+        .into_message(addr("me"));
+        node.mark(addr(origin), &mut control);
+        node.dispatch_inbound(control, addr("me")).await.unwrap()
+    }
+
+    async fn ordinary(node: &NodeInstance, origin: &str) -> MessageStatus {
+        let mut message = message(addr("me"), b"x");
+        node.mark(addr(origin), &mut message);
+        node.dispatch_inbound(message, addr("me")).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn offending_peers_are_quarantined_and_rehabilitated_by_decay() {
+        let (clock, recorder) = (ManualClock::new(0), Recorder::new());
+        let node = node(&clock, &recorder);
+        let bad = Identity::new("bad");
+        let forward = || node.forward(message(addr("dest"), b"x"));
+
+        forward().await.unwrap();
+        forged_control(&node, "bad").await;
+        assert!(!node.is_quarantined(&bad));
+        forged_control(&node, "bad").await;
+        assert_eq!(node.quarantined(), std::slice::from_ref(&bad));
+        assert_eq!(node.peer_scores(), [(bad.clone(), 10.0)]);
+
+        let quarantined = MessageStatus::Rejected {
+            reason: RejectReason::Quarantined,
+        };
+        assert_eq!(ordinary(&node, "bad").await, quarantined);
+        assert_eq!(ordinary(&node, "good").await, MessageStatus::Received);
+        assert_eq!(node.quarantine_drops(), 1);
+        forward().await.unwrap();
+        assert_eq!(recorder.remotes(), [bad.clone(), Identity::new("good")]);
+
+        // five half lives later the quarantine is over and the score mostly gone
+        clock.advance(Duration::from_secs(300));
+        assert!(!node.is_quarantined(&bad));
+        assert_eq!(node.peer_scores(), [(bad.clone(), 10.0 / 32.0)]);
+        assert_eq!(ordinary(&node, "bad").await, MessageStatus::Received);
+        forward().await.unwrap();
+        assert_eq!(recorder.remotes()[2], bad);
+        // a single offense is no longer enough to go back
+        forged_control(&node, "bad").await;
+        assert!(!node.is_quarantined(&bad));
+    }
+}
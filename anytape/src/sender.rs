@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{Address, Message, MessageBuilder, NodeHandle, NodeInstance, SendError};
+
+/// Names the `unique_id` (u64 little-endian) of the message this one replies to.
+pub const REPLY_HEADER: &str = "anytape-reply";
+
+/// Payload encoding used by [`Sender::send_typed`].
+pub trait ToPayload {
+    fn to_payload(&self) -> Vec<u8>;
+}
+
+impl ToPayload for [u8] {
+    fn to_payload(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl ToPayload for Vec<u8> {
+    fn to_payload(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+impl ToPayload for str {
+    fn to_payload(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl ToPayload for String {
+    fn to_payload(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+/// A cheap, cloneable handle for sending payloads through a node, optionally
+/// bound to one destination.
+///
+/// Messages get a fresh `unique_id` from the node's
+/// [generator](NodeInstance::with_id_generator), the configured ttl and a path entry for this
+/// node, and are [forwarded](NodeInstance::forward). Once the node is
+/// [shut down](NodeInstance::shutdown), sends fail with [`SendError::Shutdown`].
+#[derive(Clone, Debug)]
+pub struct Sender {
+    node: Arc<NodeInstance>,
+    destination: Option<Address>,
+    ttl: Option<u32>,
+    reply_to: Option<Address>,
+}
+
+impl NodeHandle {
+    /// A [`Sender`] for this node without a destination; use [`Sender::send_to`].
+    pub fn sender(&self) -> Sender {
+        Sender {
+            node: self.arc().clone(),
+            destination: None,
+            ttl: None,
+            reply_to: None,
+        }
+    }
+    /// A [`Sender`] bound to `destination`.
+    pub fn sender_to(&self, destination: impl Into<Address>) -> Sender {
+        Sender {
+            destination: Some(destination.into()),
+            ..self.sender()
+        }
+    }
+}
+
+impl Sender {
+    /// Give every message this many hops.
+    pub fn with_ttl(self, ttl: u32) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+    /// Ask for answers at `address`, e.g. for
+    /// [`Sender::send_and_wait_reply`] from an anonymous node.
+    pub fn with_reply_to(self, address: Address) -> Self {
+        Self {
+            reply_to: Some(address),
+            ..self
+        }
+    }
+    pub fn destination(&self) -> Option<&Address> {
+        self.destination.as_ref()
+    }
+    /// Send `payload` to the bound destination; fails with [`SendError::NoRoute`]
+    /// if there is none.
+    pub async fn send(&self, payload: impl Into<Vec<u8>>) -> Result<(), SendError> {
+        let destination = self.destination.clone().ok_or(SendError::NoRoute)?;
+        self.send_to(destination, payload).await
+    }
+    pub async fn send_typed<T: ToPayload + ?Sized>(&self, value: &T) -> Result<(), SendError> {
+        self.send(value.to_payload()).await
+    }
+    pub async fn send_to(
+        &self,
+        destination: impl Into<Address>,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), SendError> {
+        let message = self.message(destination.into(), payload.into());
+        self.forward(message).await
+    }
+    /// Send `payload` to the bound destination and wait up to `timeout` for the
+    /// answer built with [`Message::reply`].
+    pub async fn send_and_wait_reply(
+        &self,
+        payload: impl Into<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<Message, SendError> {
+        let destination = self.destination.clone().ok_or(SendError::NoRoute)?;
+        let message = self.message(destination, payload.into());
+        let unique_id = message.unique_id;
+        let (tx, rx) = oneshot::channel();
+        self.node.replies.insert(unique_id, tx);
+        let result = async {
+            self.forward(message).await?;
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(reply)) => Ok(reply),
+                Ok(Err(_)) => Err(SendError::Shutdown),
+                Err(_) => Err(SendError::DeadlineExceeded),
+            }
+        }
+        .await;
+        self.node.replies.remove(unique_id);
+        result
+    }
+
+    fn message(&self, destination: Address, payload: Vec<u8>) -> Message {
+        let source = self.node.source_address(&destination);
+        let mut builder = MessageBuilder::new(destination)
+            .unique_id(self.node.next_id())
+            .payload(payload);
+        if let Some(ttl) = self.ttl {
+            builder = builder.ttl(ttl);
+        }
+        if let Some(reply_to) = &self.reply_to {
+            builder = builder.reply_to(reply_to.clone());
+        }
+        let mut message = builder.build();
+        if let Some(source) = source {
+            self.node.mark(source, &mut message);
+        }
+        message
+    }
+    async fn forward(&self, message: Message) -> Result<(), SendError> {
+        if self.node.is_shut_down() {
+            return Err(SendError::Shutdown);
+        }
+        self.node.forward(message).await
+    }
+}
+
+impl Message {
+    /// A reply to this message, addressed to its [`reply_to`](Message::reply_to)
+    /// address or else its origin (the first address in its path). Returns
+    /// `None` if neither is known.
+    pub fn reply(&self, payload: impl Into<Vec<u8>>) -> Option<Message> {
+        let origin = match &self.reply_to {
+            Some(reply_to) => reply_to.clone(),
+            None => self.path.first()?.address.clone()?,
+        };
+        Some(
+            MessageBuilder::new(origin)
+                .payload(payload)
+                .header(REPLY_HEADER, self.unique_id.to_le_bytes().to_vec())
+                .build(),
+        )
+    }
+}
+
+/// Waiters of [`Sender::send_and_wait_reply`], keyed by the request's `unique_id`.
+#[derive(Default)]
+pub(crate) struct ReplyTable {
+    waiters: Mutex<HashMap<u64, (oneshot::Sender<Message>, Instant)>>,
+}
+
+impl ReplyTable {
+    fn insert(&self, unique_id: u64, waiter: oneshot::Sender<Message>) {
+        let waiter = (waiter, Instant::now());
+        self.waiters.lock().unwrap().insert(unique_id, waiter);
+    }
+    /// The `unique_id`s of the requests waited on, and since when.
+    pub(crate) fn pending(&self) -> Vec<(u64, Instant)> {
+        let waiters = self.waiters.lock().unwrap();
+        waiters
+            .iter()
+            .map(|(unique_id, (_, since))| (*unique_id, *since))
+            .collect()
+    }
+    fn remove(&self, unique_id: u64) {
+        self.waiters.lock().unwrap().remove(&unique_id);
+    }
+    /// Hand `message` to the waiter it replies to, or give it back if there is none.
+    pub(crate) fn complete(&self, message: Message) -> Option<Message> {
+        let Some(unique_id) = message
+            .headers
+            .get(REPLY_HEADER)
+            .and_then(|id| <[u8; 8]>::try_from(id.as_slice()).ok())
+            .map(u64::from_le_bytes)
+        else {
+            return Some(message);
+        };
+        match self.waiters.lock().unwrap().remove(&unique_id) {
+            Some((waiter, _)) => {
+                let _ = waiter.send(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+    pub(crate) fn clear(&self) {
+        self.waiters.lock().unwrap().clear();
+    }
+}
+
+impl NodeInstance {
+    /// Stop accepting new sends: [`NodeInstance::send`] and every [`Sender`] fail
+    /// with [`SendError::Shutdown`] from now on, and pending replies are abandoned.
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::Release);
+        self.replies.clear();
+    }
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
+    }
+    /// The address to record as the source of messages to `destination`:
+    /// preferably one speaking the destination's protocol.
+    pub(crate) fn source_address(&self, destination: &Address) -> Option<Address> {
+        let same_protocol = self
+            .address_set
+            .iter()
+            .filter(|address| address.protocol == destination.protocol)
+            .min_by(|a, b| a.identity.as_bytes().cmp(b.identity.as_bytes()));
+        same_protocol
+            .or_else(|| {
+                self.address_set
+                    .iter()
+                    .min_by(|a, b| a.identity.as_bytes().cmp(b.identity.as_bytes()))
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::testing::{addr, eventually, Loopback, TEST};
+    use crate::ProtocolExecutor;
+
+    /// A sender node `a` and a receiver `b` that keeps what it receives.
+    fn pair(loopback: &Loopback) -> (NodeHandle, Arc<Mutex<Vec<Message>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let receiver = NodeInstance::new().with_address(addr("b")).with_handler({
+            let received = received.clone();
+            move |message: Message| {
+                received.lock().unwrap().push(message);
+                async {}
+            }
+        });
+        loopback.attach("b", Arc::new(receiver));
+        let sender = NodeInstance::new()
+            .with_address(addr("a"))
+            .with_executor(TEST, loopback.clone())
+            .handle();
+        loopback.attach("a", sender.arc().clone());
+        (sender, received)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cloned_senders_send_concurrently() {
+        let loopback = Loopback::new();
+        let (node, received) = pair(&loopback);
+        let sender = node.sender_to(addr("b")).with_ttl(4);
+        let tasks: Vec<_> = (0..10u8)
+            .map(|task| {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    for i in 0..5u8 {
+                        sender.send(vec![task, i]).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 50);
+        let ids: HashSet<_> = received.iter().map(|m| m.unique_id).collect();
+        assert_eq!(ids.len(), 50);
+        for message in received.iter() {
+            assert_eq!(message.destination, addr("b"));
+            assert_eq!(message.ttl, Some(4));
+            assert_eq!(message.path[0].address, Some(addr("a")));
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_waiting_sender() {
+        let loopback = Loopback::new();
+        let responder = NodeInstance::new().with_address(addr("b")).with_handler({
+            let loopback = loopback.clone();
+            move |message: Message| {
+                let reply = message.reply(b"pong".to_vec()).unwrap();
+                let loopback = loopback.clone();
+                async move {
+                    let origin = reply.destination.identity.clone();
+                    loopback.send(&origin, reply).await.unwrap();
+                }
+            }
+        });
+        loopback.attach("b", Arc::new(responder));
+        let node = NodeInstance::new()
+            .with_address(addr("a"))
+            .with_executor(TEST, loopback.clone())
+            .handle();
+        loopback.attach("a", node.arc().clone());
+
+        let reply = node
+            .sender_to(addr("b"))
+            .send_and_wait_reply(b"ping".to_vec(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(reply.payload, b"pong");
+        assert!(node.node().replies.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handlers_answer_at_the_reply_to_address() {
+        let loopback = Loopback::new();
+        let responder = NodeInstance::new().with_address(addr("b")).with_handler({
+            let loopback = loopback.clone();
+            move |message: Message| {
+                let reply = message.reply(b"answer".to_vec()).unwrap();
+                let loopback = loopback.clone();
+                async move {
+                    let to = reply.destination.identity.clone();
+                    loopback.send(&to, reply).await.unwrap();
+                }
+            }
+        });
+        loopback.attach("b", Arc::new(responder));
+        let inbox = Arc::new(Mutex::new(Vec::new()));
+        let collector = NodeInstance::new()
+            .with_address(addr("inbox"))
+            .with_handler({
+                let inbox = inbox.clone();
+                move |message: Message| {
+                    inbox.lock().unwrap().push(message);
+                    async {}
+                }
+            });
+        loopback.attach("inbox", Arc::new(collector));
+        let node = NodeInstance::new()
+            .with_address(addr("a"))
+            .with_executor(TEST, loopback.clone())
+            .handle();
+
+        let sender = node.sender_to(addr("b")).with_reply_to(addr("inbox"));
+        let request = sender.message(addr("b"), b"question".to_vec());
+        assert_eq!(request.reply_to, Some(addr("inbox")));
+        let unique_id = request.unique_id;
+        sender.forward(request).await.unwrap();
+
+        eventually(|| inbox.lock().unwrap().len() == 1).await;
+        let reply = inbox.lock().unwrap().remove(0);
+        assert_eq!(reply.payload, b"answer");
+        assert_eq!(
+            reply.headers.get(REPLY_HEADER),
+            Some(&unique_id.to_le_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn senders_fail_once_the_node_shut_down() {
+        let loopback = Loopback::new();
+        let (node, received) = pair(&loopback);
+        let sender = node.sender_to(addr("b"));
+        sender.send(b"before".to_vec()).await.unwrap();
+        node.node().shutdown();
+        assert!(matches!(
+            sender.send(b"after".to_vec()).await,
+            Err(SendError::Shutdown)
+        ));
+        assert!(matches!(
+            node.sender().send(b"unbound".to_vec()).await,
+            Err(SendError::NoRoute)
+        ));
+        eventually(|| received.lock().unwrap().len() == 1).await;
+    }
+}
@@ -0,0 +1,324 @@
+//! Refusing sends to destinations that fall behind.
+//!
+//! With [load shedding](NodeInstance::with_load_shedding), every send counts as
+//! queued for its next hop from the moment it is handed to the node until it
+//! completes. Once the oldest queued send to a destination is older than
+//! [`LoadShedConfig::max_queue_age`], or [`LoadShedConfig::high_water`] sends
+//! are queued for it, further sends to that destination fail right away with
+//! [`SendError::Overloaded`] instead of joining a queue that delivers them too
+//! late. Queued sends still running after [`LoadShedConfig::hard_age_limit`]
+//! are given up with [`SendError::ShedByAge`].
+//!
+//! [High-priority](Priority::High) and [control](crate::control) messages are
+//! queued like all others but never shed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{control, Address, Message, NodeInstance, SendError};
+
+/// Header carrying a message's [`Priority`] as one byte.
+pub const PRIORITY_HEADER: &str = "anytape-priority";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    /// Never [shed](NodeInstance::with_load_shedding).
+    High,
+}
+
+impl Priority {
+    /// The priority `message` carries; [`Priority::Normal`] without a valid
+    /// [`PRIORITY_HEADER`].
+    pub fn of(message: &Message) -> Self {
+        match message.headers.get(PRIORITY_HEADER).map(Vec::as_slice) {
+            Some([0]) => Priority::Low,
+            Some([2]) => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+    pub fn stamp(self, message: &mut Message) {
+        let byte = match self {
+            Priority::Low => 0,
+            Priority::Normal => 1,
+            Priority::High => 2,
+        };
+        message
+            .headers
+            .insert(PRIORITY_HEADER.to_owned(), vec![byte]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoadShedConfig {
+    /// Shed new sends once the oldest queued one is older than this.
+    pub max_queue_age: Duration,
+    /// Shed new sends once this many are queued for the destination.
+    pub high_water: usize,
+    /// Give up queued sends older than this.
+    pub hard_age_limit: Option<Duration>,
+}
+
+impl Default for LoadShedConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_age: Duration::from_secs(1),
+            high_water: 1024,
+            hard_age_limit: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct LoadShedder {
+    config: RwLock<Option<LoadShedConfig>>,
+    /// Start times of the queued sends, by destination and arrival order.
+    queues: Mutex<HashMap<Address, BTreeMap<u64, Instant>>>,
+    next: AtomicU64,
+    overloaded: AtomicU64,
+    shed_by_age: AtomicU64,
+}
+
+impl LoadShedder {
+    /// Queue a send to `to`, unless the destination is overloaded and the send
+    /// may be shed.
+    fn enqueue(
+        &self,
+        to: &Address,
+        sheddable: bool,
+        config: &LoadShedConfig,
+    ) -> Result<u64, SendError> {
+        let now = Instant::now();
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(to.clone()).or_default();
+        let queue_age = queue
+            .values()
+            .next()
+            .map_or(Duration::ZERO, |started| now - *started);
+        if sheddable && (queue_age > config.max_queue_age || queue.len() >= config.high_water) {
+            self.overloaded.fetch_add(1, Ordering::Relaxed);
+            return Err(SendError::Overloaded {
+                destination: to.clone(),
+                queue_age,
+            });
+        }
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        queue.insert(ticket, now);
+        Ok(ticket)
+    }
+    fn dequeue(&self, to: &Address, ticket: u64) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(to) {
+            queue.remove(&ticket);
+            if queue.is_empty() {
+                queues.remove(to);
+            }
+        }
+    }
+}
+
+/// Whether sends of `message` may be shed.
+pub(crate) fn is_sheddable(message: &Message) -> bool {
+    Priority::of(message) != Priority::High
+        && !message.headers.contains_key(control::CONTROL_HEADER)
+}
+
+/// Takes a send off its queue when dropped, however the send ends.
+struct Queued<'a> {
+    shedder: &'a LoadShedder,
+    to: &'a Address,
+    ticket: u64,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.shedder.dequeue(self.to, self.ticket);
+    }
+}
+
+impl NodeInstance {
+    /// Shed sends to destinations that fall behind; see the
+    /// [module docs](self).
+    pub fn with_load_shedding(self, config: LoadShedConfig) -> Self {
+        self.set_load_shedding(Some(config));
+        self
+    }
+    /// Change the shedding thresholds of a running node, or with `None` stop
+    /// shedding. Sends already queued keep their limits.
+    pub fn set_load_shedding(&self, config: Option<LoadShedConfig>) {
+        *self.shedding.config.write().unwrap() = config;
+    }
+    pub fn load_shedding(&self) -> Option<LoadShedConfig> {
+        *self.shedding.config.read().unwrap()
+    }
+    /// How long the oldest queued send to each destination has been waiting.
+    /// Only tracked while load shedding is on.
+    pub fn send_queue_ages(&self) -> Vec<(Address, Duration)> {
+        let now = Instant::now();
+        let queues = self.shedding.queues.lock().unwrap();
+        queues
+            .iter()
+            .filter_map(|(to, queue)| Some((to.clone(), now - *queue.values().next()?)))
+            .collect()
+    }
+    pub(crate) fn shed_counts(&self) -> (u64, u64) {
+        let shedding = &self.shedding;
+        (
+            shedding.overloaded.load(Ordering::Relaxed),
+            shedding.shed_by_age.load(Ordering::Relaxed),
+        )
+    }
+    /// Run `send` as a queued send to `to`, which may be shed unless it carries
+    /// a message [exempt](is_sheddable) from shedding.
+    pub(crate) async fn shed_or_send<T>(
+        &self,
+        sheddable: bool,
+        to: &Address,
+        send: impl Future<Output = Result<T, SendError>>,
+    ) -> Result<T, SendError> {
+        let Some(config) = self.load_shedding() else {
+            return send.await;
+        };
+        let ticket = self.shedding.enqueue(to, sheddable, &config)?;
+        let _queued = Queued {
+            shedder: &self.shedding,
+            to,
+            ticket,
+        };
+        match config.hard_age_limit.filter(|_| sheddable) {
+            Some(limit) => match tokio::time::timeout(limit, send).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.shedding.shed_by_age.fetch_add(1, Ordering::Relaxed);
+                    Err(SendError::ShedByAge {
+                        destination: to.clone(),
+                        queue_age: limit,
+                    })
+                }
+            },
+            None => send.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::{
+        testing::{addr, message, TestError, TEST},
+        Identity, MessageStatus, ProtocolExecutor, SendOutcome,
+    };
+
+    /// Holds every send until the test opens it.
+    #[derive(Clone)]
+    struct Stalled(Arc<Semaphore>);
+
+    impl Stalled {
+        fn new() -> Self {
+            Self(Arc::new(Semaphore::new(0)))
+        }
+        fn unblock(&self) {
+            self.0.add_permits(Semaphore::MAX_PERMITS / 2);
+        }
+    }
+
+    impl ProtocolExecutor for Stalled {
+        type Error = TestError;
+        fn send(
+            &self,
+            _: &Identity,
+            _: Message,
+        ) -> impl Future<Output = Result<SendOutcome, TestError>> + Send + 'static {
+            let gate = self.0.clone();
+            async move {
+                let _open = gate.acquire().await.map_err(|_| TestError("closed"))?;
+                Ok(SendOutcome::sent())
+            }
+        }
+        fn get_status(
+            &self,
+            _: &Identity,
+            _: Message,
+        ) -> impl Future<Output = Result<MessageStatus, TestError>> + Send + 'static {
+            std::future::ready(Ok(MessageStatus::Sended))
+        }
+    }
+
+    fn node(executor: &Stalled, hard_age_limit: Option<Duration>) -> Arc<NodeInstance> {
+        Arc::new(
+            NodeInstance::new()
+                .with_executor(TEST, executor.clone())
+                .with_load_shedding(LoadShedConfig {
+                    max_queue_age: Duration::from_secs(1),
+                    high_water: 100,
+                    hard_age_limit,
+                }),
+        )
+    }
+
+    fn spawn_send(
+        node: &Arc<NodeInstance>,
+        message: Message,
+    ) -> tokio::task::JoinHandle<Result<(), SendError>> {
+        let node = node.clone();
+        tokio::spawn(async move { node.send(message, addr("b")).await })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_destinations_shed_all_but_high_priority_until_they_recover() {
+        let executor = Stalled::new();
+        let node = node(&executor, None);
+        let stuck = spawn_send(&node, message(addr("b"), b"first"));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(node.send_queue_ages()[0].1, Duration::from_millis(1500));
+
+        let shed = node.send(message(addr("b"), b"normal"), addr("b")).await;
+        assert!(matches!(
+            shed,
+            Err(SendError::Overloaded { queue_age, .. }) if queue_age == Duration::from_millis(1500)
+        ));
+        let mut urgent = message(addr("b"), b"urgent");
+        Priority::High.stamp(&mut urgent);
+        let urgent = spawn_send(&node, urgent);
+        tokio::task::yield_now().await;
+        assert!(!urgent.is_finished());
+
+        executor.unblock();
+        assert!(stuck.await.unwrap().is_ok());
+        assert!(urgent.await.unwrap().is_ok());
+        assert!(node.send_queue_ages().is_empty());
+        node.send(message(addr("b"), b"recovered"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(node.shed_counts(), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_past_the_hard_age_limit_are_given_up() {
+        let executor = Stalled::new();
+        let node = node(&executor, Some(Duration::from_secs(3)));
+        let result = node.send(message(addr("b"), b"x"), addr("b")).await;
+        assert!(matches!(
+            result,
+            Err(SendError::ShedByAge { queue_age, .. }) if queue_age == Duration::from_secs(3)
+        ));
+        assert_eq!(node.shed_counts(), (0, 1));
+        assert!(node.send_queue_ages().is_empty());
+    }
+}
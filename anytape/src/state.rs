@@ -0,0 +1,176 @@
+//! Carrying learned state across restarts.
+//!
+//! [`NodeInstance::export_state`] snapshots what a node has learned in memory
+//! only: its route cache and, per origin, the next [`seq`](crate::Message::seq)
+//! the [reorder buffer](NodeInstance::with_reorder_buffer) expects. A restarted
+//! node [imports](NodeInstance::import_state) it to route without asking the
+//! backend again and to keep treating already delivered sequence numbers as
+//! such. Messages held in the reorder buffer are not part of the snapshot.
+//! Deployments whose backend is durable can instead
+//! [drain](NodeInstance::drain_to_backend) the route cache into it.
+
+use crate::{Address, BoxResult, Identity, NodeEvent, NodeInstance};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeState {
+    /// Route cache entries as `(destination, next)`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub routes: Vec<(Address, Address)>,
+    /// The next expected `seq` of each origin.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub watermarks: Vec<(Identity, u64)>,
+}
+
+impl NodeInstance {
+    pub fn export_state(&self) -> NodeState {
+        NodeState {
+            routes: self.iter_routes(),
+            watermarks: self.reorder.watermarks(),
+        }
+    }
+    /// Store every route in the cache in the backend, e.g. before shutting
+    /// down, returning how many there were. Nodes without a backend store
+    /// nothing.
+    pub async fn drain_to_backend(&self) -> BoxResult<usize> {
+        let Some(backend) = &self.backend else {
+            return Ok(0);
+        };
+        let routes: Vec<_> = self
+            .iter_routes()
+            .into_iter()
+            .map(|(destination, next)| (destination, Some(next)))
+            .collect();
+        let count = routes.len();
+        backend.set_next_batch(routes).await?;
+        Ok(count)
+    }
+    /// Merge `state` into this node's. Routes the cache already has are kept,
+    /// and of two watermarks for one origin the higher one wins.
+    pub fn import_state(&mut self, state: NodeState) {
+        for (destination, next) in state.routes {
+            let key = self.intern(&destination);
+            let mut cache = self.next_cache.write().unwrap();
+            if cache.contains_key(&key) {
+                continue;
+            }
+            let evicted = cache.insert(key, self.intern(&next));
+            drop(cache);
+            if let Some(evicted) = evicted {
+                self.publish(|| NodeEvent::RouteEvicted {
+                    destination: evicted.to_address(),
+                });
+            }
+            self.publish(|| NodeEvent::RouteCached { destination, next });
+        }
+        for (origin, next) in state.watermarks {
+            self.reorder.raise_watermark(origin, next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        testing::{addr, Shared},
+        DataBackend, MemoryBackend, Message, MessageBuilder,
+    };
+
+    /// A node delivering in order, and the sequence numbers it delivered.
+    fn receiver() -> (NodeInstance, Arc<Mutex<Vec<u64>>>) {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_reorder_buffer(Duration::from_secs(1))
+            .with_handler({
+                let delivered = delivered.clone();
+                move |message: Message| {
+                    delivered.lock().unwrap().push(message.seq.unwrap());
+                    async {}
+                }
+            });
+        (node, delivered)
+    }
+
+    async fn receive(node: &NodeInstance, seq: u64) {
+        let mut message = MessageBuilder::new(addr("me")).seq(seq).build();
+        NodeInstance::new().mark(addr("src"), &mut message);
+        node.dispatch_inbound(message, addr("me")).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn imported_state_restores_routes_and_watermarks() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend
+            .set_next(&addr("dest"), Some(&addr("relay")))
+            .await
+            .unwrap();
+        let (node, _) = receiver();
+        let node = node.with_backend(Shared(backend));
+        node.resolve_next(&addr("dest")).await.unwrap();
+        for seq in 0..2 {
+            receive(&node, seq).await;
+        }
+        let state = node.export_state();
+        assert_eq!(state.routes, [(addr("dest"), addr("relay"))]);
+        assert_eq!(state.watermarks, [(Identity::new("src"), 2)]);
+        #[cfg(feature = "serde")]
+        let state: NodeState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+
+        // without a backend or executor, only the imported route leads anywhere
+        let (mut restarted, delivered) = receiver();
+        restarted.import_state(state.clone());
+        assert_eq!(
+            restarted.resolve_next(&addr("dest")).await.unwrap(),
+            addr("relay")
+        );
+        // the next message is due at once, where a fresh node would wait for 0
+        receive(&restarted, 2).await;
+        assert_eq!(*delivered.lock().unwrap(), [2]);
+        let (fresh, held) = receiver();
+        receive(&fresh, 2).await;
+        assert!(held.lock().unwrap().is_empty());
+        assert_eq!(
+            restarted.export_state(),
+            NodeState {
+                watermarks: vec![(Identity::new("src"), 3)],
+                ..state
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn draining_stores_every_cached_route_in_the_backend() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut node = NodeInstance::new().with_backend(Shared(backend.clone()));
+        let routes: Vec<_> = (0..5)
+            .map(|i| (addr(&format!("d{i}")), addr("relay")))
+            .collect();
+        node.import_state(NodeState {
+            routes: routes.clone(),
+            watermarks: Vec::new(),
+        });
+        assert_eq!(backend.get_next(&routes[0].0).await.unwrap(), None);
+
+        assert_eq!(node.drain_to_backend().await.unwrap(), 5);
+        for (destination, next) in &routes {
+            assert_eq!(
+                backend.get_next(destination).await.unwrap().as_ref(),
+                Some(next)
+            );
+        }
+        let mut without_backend = NodeInstance::new();
+        without_backend.import_state(NodeState {
+            routes,
+            watermarks: Vec::new(),
+        });
+        assert_eq!(without_backend.drain_to_backend().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,667 @@
+//! Streaming transfers of payloads too large to hold in memory at once.
+//!
+//! [`NodeInstance::send_stream`] cuts a reader into sequenced chunk messages and
+//! the receiving node reassembles them into a [`StreamAssembler`]. The receiver
+//! grants the sender a window of chunks and hands out more credit as the
+//! application consumes them, so neither side buffers more than the window.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::{mpsc, Semaphore},
+    time::{Instant, Sleep},
+};
+
+use crate::{
+    random_u64,
+    wire::{Reader, Writer},
+    Address, DynProtocolExecutor, Message, MessageStatus, NodeInstance, RejectReason, SendError,
+    StreamDirection,
+};
+
+/// Carries stream id, chunk sequence number and chunk kind.
+pub const STREAM_HEADER: &str = "anytape-stream";
+/// Carries the window and the address credit should be sent back to.
+const STREAM_OPEN_HEADER: &str = "anytape-stream-open";
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_WINDOW: u32 = 16;
+pub(crate) const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_QUEUE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkKind {
+    Data = 0,
+    End = 1,
+    Abort = 2,
+    Window = 3,
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    /// Payload bytes per chunk message.
+    pub chunk_size: usize,
+    /// Chunks the receiver may hold before it has to grant more credit.
+    pub window: u32,
+    /// Where the receiver sends credit; defaults to one of this node's addresses,
+    /// preferring one of the destination's protocol.
+    pub reply_to: Option<Address>,
+    /// How long the sender waits for credit before giving up.
+    pub stall_timeout: Duration,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            window: DEFAULT_WINDOW,
+            reply_to: None,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    /// The other side aborted the stream.
+    Aborted,
+    /// Nothing arrived, or no credit was granted, within the timeout.
+    Stalled,
+    /// The sender ran past the window it was granted.
+    WindowExceeded,
+    /// A chunk's stream headers were malformed.
+    Malformed,
+    /// Reading the source failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Aborted => write!(f, "stream aborted by peer"),
+            StreamError::Stalled => write!(f, "stream stalled"),
+            StreamError::WindowExceeded => write!(f, "peer exceeded the stream window"),
+            StreamError::Malformed => write!(f, "malformed stream chunk"),
+            StreamError::Io(e) => write!(f, "stream source failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+struct ChunkHeader {
+    stream_id: u64,
+    seq: u64,
+    kind: ChunkKind,
+}
+
+impl ChunkHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_u64(self.stream_id);
+        w.put_u64(self.seq);
+        w.put_u8(self.kind as u8);
+        w.finish()
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader::new(bytes);
+        let stream_id = r.get_u64().ok()?;
+        let seq = r.get_u64().ok()?;
+        let kind = match r.get_u8().ok()? {
+            0 => ChunkKind::Data,
+            1 => ChunkKind::End,
+            2 => ChunkKind::Abort,
+            3 => ChunkKind::Window,
+            _ => return None,
+        };
+        r.finish().ok()?;
+        Some(Self {
+            stream_id,
+            seq,
+            kind,
+        })
+    }
+}
+
+fn chunk_message(destination: Address, header: ChunkHeader, payload: Vec<u8>) -> Message {
+    Message {
+        destination,
+        path: Vec::new(),
+        payload,
+        signature: Vec::new(),
+        unique_id: random_u64(),
+        ttl: None,
+        headers: BTreeMap::from([(STREAM_HEADER.to_owned(), header.encode())]),
+        metadata: Vec::new(),
+        extensions: Vec::new(),
+        seq: None,
+        budget_ms: None,
+        route_plan: None,
+        reply_to: None,
+    }
+}
+
+struct IncomingStream {
+    /// Where credit goes back to.
+    peer: Address,
+    started: Instant,
+    next_seq: u64,
+    window: u32,
+    /// Chunks that arrived ahead of `next_seq`; `None` marks the end.
+    pending: BTreeMap<u64, Option<Vec<u8>>>,
+    tx: mpsc::Sender<Result<Bytes, StreamError>>,
+}
+
+type IncomingTable = Arc<Mutex<HashMap<u64, IncomingStream>>>;
+
+struct OutgoingStream {
+    credit: Arc<Semaphore>,
+    to: Address,
+    started: Instant,
+}
+
+/// Per-node bookkeeping for streams in both directions.
+pub(crate) struct StreamState {
+    outgoing: Mutex<HashMap<u64, OutgoingStream>>,
+    incoming: IncomingTable,
+    accept_tx: mpsc::Sender<StreamAssembler>,
+    accept_rx: tokio::sync::Mutex<mpsc::Receiver<StreamAssembler>>,
+    pub(crate) idle_timeout: Duration,
+}
+
+impl StreamState {
+    pub(crate) fn new() -> Self {
+        let (accept_tx, accept_rx) = mpsc::channel(ACCEPT_QUEUE);
+        Self {
+            outgoing: Default::default(),
+            incoming: Default::default(),
+            accept_tx,
+            accept_rx: tokio::sync::Mutex::new(accept_rx),
+            idle_timeout: DEFAULT_STALL_TIMEOUT,
+        }
+    }
+    /// The streams under way: their id, direction, peer and start.
+    pub(crate) fn transfers(&self) -> Vec<(u64, StreamDirection, Address, Instant)> {
+        let outgoing: Vec<_> = self
+            .outgoing
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, stream)| {
+                (
+                    *id,
+                    StreamDirection::Outgoing,
+                    stream.to.clone(),
+                    stream.started,
+                )
+            })
+            .collect();
+        let incoming = self.incoming.lock().unwrap();
+        let incoming = incoming.iter().map(|(id, stream)| {
+            (
+                *id,
+                StreamDirection::Incoming,
+                stream.peer.clone(),
+                stream.started,
+            )
+        });
+        outgoing.into_iter().chain(incoming).collect()
+    }
+}
+
+/// Sends credit for consumed chunks back to the stream's sender.
+struct CreditReturn {
+    stream_id: u64,
+    reply_to: Address,
+    executor: Option<Arc<dyn DynProtocolExecutor>>,
+    owed: u32,
+    threshold: u32,
+}
+
+impl CreditReturn {
+    fn send(&self, kind: ChunkKind, payload: Vec<u8>) {
+        let (Some(executor), Ok(runtime)) = (&self.executor, tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let header = ChunkHeader {
+            stream_id: self.stream_id,
+            seq: 0,
+            kind,
+        };
+        let send = executor.send(
+            &self.reply_to.identity,
+            chunk_message(self.reply_to.clone(), header, payload),
+        );
+        runtime.spawn(send);
+    }
+    fn consumed(&mut self) {
+        self.owed += 1;
+        if self.owed >= self.threshold {
+            self.send(ChunkKind::Window, self.owed.to_le_bytes().to_vec());
+            self.owed = 0;
+        }
+    }
+}
+
+/// The receiving end of a stream, yielding its chunks in order.
+///
+/// Usable both as a [`Stream`] of chunks and as an [`AsyncRead`]. A transfer
+/// that is aborted or stalls ends with an error rather than a short read.
+/// Dropping the assembler before the end aborts the transfer.
+pub struct StreamAssembler {
+    stream_id: u64,
+    rx: mpsc::Receiver<Result<Bytes, StreamError>>,
+    credit: CreditReturn,
+    incoming: IncomingTable,
+    idle: Pin<Box<Sleep>>,
+    idle_timeout: Duration,
+    current: Bytes,
+    finished: bool,
+}
+
+impl StreamAssembler {
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+}
+
+impl Stream for StreamAssembler {
+    type Item = Result<Bytes, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.credit.consumed();
+                let deadline = Instant::now() + self.idle_timeout;
+                self.idle.as_mut().reset(deadline);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                self.finished = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                self.finished = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => match self.idle.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    self.finished = true;
+                    self.credit.send(ChunkKind::Abort, Vec::new());
+                    Poll::Ready(Some(Err(StreamError::Stalled)))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl AsyncRead for StreamAssembler {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.current.is_empty() {
+            match self.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.current = chunk,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = self.current.len().min(buf.remaining());
+        buf.put_slice(&self.current.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for StreamAssembler {
+    fn drop(&mut self) {
+        let removed = self.incoming.lock().unwrap().remove(&self.stream_id);
+        if !self.finished && removed.is_some() {
+            self.credit.send(ChunkKind::Abort, Vec::new());
+        }
+    }
+}
+
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        match reader.read(&mut chunk[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+impl NodeInstance {
+    /// Stream everything `reader` yields to `to`, returning the stream id once
+    /// the last chunk has been sent.
+    ///
+    /// At most `opts.window` chunks are in flight ahead of what the receiving
+    /// application has consumed.
+    pub async fn send_stream<R: AsyncRead + Unpin>(
+        &self,
+        to: impl Into<Address>,
+        mut reader: R,
+        opts: StreamOptions,
+    ) -> Result<u64, SendError> {
+        let to = to.into();
+        let reply_to = opts
+            .reply_to
+            .clone()
+            .or_else(|| {
+                let mut local = self.address_set.iter();
+                self.address_set
+                    .iter()
+                    .find(|address| address.protocol == to.protocol)
+                    .or_else(|| local.next())
+                    .cloned()
+            })
+            .ok_or(SendError::NoRoute)?;
+        let window = opts.window.max(1);
+        let mut open = Writer::new();
+        open.put_u64(u64::from(window));
+        open.put_address(&reply_to);
+        let open = open.finish();
+
+        let stream_id = random_u64();
+        let credit = Arc::new(Semaphore::new(window as usize));
+        self.streams.outgoing.lock().unwrap().insert(
+            stream_id,
+            OutgoingStream {
+                credit: credit.clone(),
+                to: to.clone(),
+                started: Instant::now(),
+            },
+        );
+        let result = async {
+            let mut seq = 0;
+            loop {
+                let chunk = read_chunk(&mut reader, opts.chunk_size.max(1))
+                    .await
+                    .map_err(|e| SendError::Stream(StreamError::Io(e)))?;
+                let kind = if chunk.is_empty() {
+                    ChunkKind::End
+                } else {
+                    match tokio::time::timeout(opts.stall_timeout, credit.acquire()).await {
+                        Ok(Ok(permit)) => permit.forget(),
+                        Ok(Err(_closed)) => return Err(SendError::Stream(StreamError::Aborted)),
+                        Err(_elapsed) => return Err(SendError::Stream(StreamError::Stalled)),
+                    }
+                    ChunkKind::Data
+                };
+                let header = ChunkHeader {
+                    stream_id,
+                    seq,
+                    kind,
+                };
+                let mut message = chunk_message(to.clone(), header, chunk);
+                message
+                    .headers
+                    .insert(STREAM_OPEN_HEADER.to_owned(), open.clone());
+                self.forward(message).await?;
+                if kind == ChunkKind::End {
+                    return Ok(stream_id);
+                }
+                seq += 1;
+            }
+        }
+        .await;
+        self.streams.outgoing.lock().unwrap().remove(&stream_id);
+        if result.is_err() {
+            let header = ChunkHeader {
+                stream_id,
+                seq: 0,
+                kind: ChunkKind::Abort,
+            };
+            let _ = self.forward(chunk_message(to, header, Vec::new())).await;
+        }
+        result
+    }
+
+    /// Wait for the next stream another node opens towards this one.
+    pub async fn accept_stream(&self) -> Option<StreamAssembler> {
+        self.streams.accept_rx.lock().await.recv().await
+    }
+
+    pub(crate) fn handle_stream_chunk(&self, message: Message) -> Result<MessageStatus, SendError> {
+        let header = message
+            .headers
+            .get(STREAM_HEADER)
+            .and_then(|header| ChunkHeader::decode(header))
+            .ok_or(SendError::Stream(StreamError::Malformed))?;
+        match header.kind {
+            ChunkKind::Window => {
+                let credits = <[u8; 4]>::try_from(message.payload.as_slice())
+                    .map(u32::from_le_bytes)
+                    .map_err(|_| SendError::Stream(StreamError::Malformed))?;
+                if let Some(stream) = self.streams.outgoing.lock().unwrap().get(&header.stream_id) {
+                    stream.credit.add_permits(credits as usize);
+                }
+                return Ok(MessageStatus::Received);
+            }
+            ChunkKind::Abort => {
+                if let Some(stream) = self.streams.outgoing.lock().unwrap().get(&header.stream_id) {
+                    stream.credit.close();
+                }
+                if let Some(incoming) = self
+                    .streams
+                    .incoming
+                    .lock()
+                    .unwrap()
+                    .remove(&header.stream_id)
+                {
+                    let _ = incoming.tx.try_send(Err(StreamError::Aborted));
+                }
+                return Ok(MessageStatus::Received);
+            }
+            ChunkKind::Data | ChunkKind::End => {}
+        }
+        let mut incoming = self.streams.incoming.lock().unwrap();
+        let assembler = match incoming.entry(header.stream_id) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(slot) => {
+                let (window, reply_to) = message
+                    .headers
+                    .get(STREAM_OPEN_HEADER)
+                    .and_then(|open| {
+                        let mut r = Reader::new(open);
+                        let window = u32::try_from(r.get_u64().ok()?).ok()?;
+                        Some((window.max(1), r.get_address().ok()?))
+                    })
+                    .ok_or(SendError::Stream(StreamError::Malformed))?;
+                // data never takes more than the window, which leaves room
+                // for the error ending an aborted transfer
+                let (tx, rx) = mpsc::channel(window as usize + 1);
+                slot.insert(IncomingStream {
+                    peer: reply_to.clone(),
+                    started: Instant::now(),
+                    next_seq: 0,
+                    window,
+                    pending: BTreeMap::new(),
+                    tx,
+                });
+                Some(StreamAssembler {
+                    stream_id: header.stream_id,
+                    rx,
+                    credit: CreditReturn {
+                        stream_id: header.stream_id,
+                        executor: self.ready_executor(&reply_to.protocol),
+                        reply_to,
+                        owed: 0,
+                        threshold: window.div_ceil(2),
+                    },
+                    incoming: self.streams.incoming.clone(),
+                    idle: Box::pin(tokio::time::sleep(self.streams.idle_timeout)),
+                    idle_timeout: self.streams.idle_timeout,
+                    current: Bytes::new(),
+                    finished: false,
+                })
+            }
+        };
+        if let Some(assembler) = assembler {
+            if let Err(rejected) = self.streams.accept_tx.try_send(assembler) {
+                // nobody is accepting streams; the assembler aborts the transfer
+                // when dropped, which needs the table lock
+                drop(incoming);
+                drop(rejected);
+                return Ok(MessageStatus::Rejected {
+                    reason: RejectReason::NoHandler,
+                });
+            }
+        }
+        let stream = incoming.get_mut(&header.stream_id).expect("inserted above");
+        if header.seq < stream.next_seq {
+            // duplicate of a chunk already delivered
+            return Ok(MessageStatus::Received);
+        }
+        let buffered = stream.pending.len() + stream.tx.max_capacity() - stream.tx.capacity();
+        // the end marker takes no credit
+        if header.kind == ChunkKind::Data && buffered >= stream.window as usize {
+            let stream = incoming.remove(&header.stream_id).expect("present");
+            let _ = stream.tx.try_send(Err(StreamError::WindowExceeded));
+            return Err(SendError::Stream(StreamError::WindowExceeded));
+        }
+        let chunk = (header.kind == ChunkKind::Data).then_some(message.payload);
+        stream.pending.insert(header.seq, chunk);
+        while let Some(chunk) = stream.pending.remove(&stream.next_seq) {
+            stream.next_seq += 1;
+            match chunk {
+                Some(chunk) => {
+                    if stream.tx.try_send(Ok(Bytes::from(chunk))).is_err() {
+                        let stream = incoming.remove(&header.stream_id).expect("present");
+                        let _ = stream.tx.try_send(Err(StreamError::WindowExceeded));
+                        return Err(SendError::Stream(StreamError::WindowExceeded));
+                    }
+                }
+                None => {
+                    // dropping the sender ends the assembler's stream cleanly
+                    incoming.remove(&header.stream_id);
+                    break;
+                }
+            }
+        }
+        Ok(MessageStatus::Received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, next, Loopback, TEST};
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// A sender and a receiver joined by one loopback, which carries the
+    /// chunks one way and the credit the other.
+    fn pair() -> (Arc<NodeInstance>, Arc<NodeInstance>, Loopback) {
+        let loopback = Loopback::new();
+        let sender = Arc::new(
+            NodeInstance::new()
+                .with_address(addr("a"))
+                .with_executor(TEST, loopback.clone()),
+        );
+        let receiver = Arc::new(
+            NodeInstance::new()
+                .with_address(addr("b"))
+                .with_executor(TEST, loopback.clone()),
+        );
+        loopback.attach("a", sender.clone());
+        loopback.attach("b", receiver.clone());
+        (sender, receiver, loopback)
+    }
+
+    #[tokio::test]
+    async fn large_payloads_arrive_byte_exact_within_the_window() {
+        const WINDOW: u32 = 4;
+        let payload = pseudo_random(50 * 1024 * 1024);
+        let (sender, receiver, _) = pair();
+        let opts = StreamOptions {
+            chunk_size: 256 * 1024,
+            window: WINDOW,
+            ..StreamOptions::default()
+        };
+        let receive = async {
+            let mut assembler = receiver.accept_stream().await.unwrap();
+            let mut received = Vec::with_capacity(payload.len());
+            let mut peak = 0;
+            while let Some(chunk) = next(&mut assembler).await {
+                received.extend_from_slice(&chunk.unwrap());
+                // what the sender got ahead of us by, this chunk included
+                peak = peak.max(assembler.rx.len() + 1);
+            }
+            (received, peak)
+        };
+        let (sent, (received, peak)) =
+            tokio::join!(sender.send_stream(addr("b"), &payload[..], opts), receive);
+        sent.unwrap();
+        assert!(received == payload);
+        assert!(peak <= WINDOW as usize, "{peak} chunks buffered");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn senders_stall_once_the_window_is_used_up() {
+        let (sender, receiver, loopback) = pair();
+        let opts = StreamOptions {
+            chunk_size: 8,
+            window: 3,
+            stall_timeout: Duration::from_secs(1),
+            ..StreamOptions::default()
+        };
+        let result = sender
+            .send_stream(addr("b"), &pseudo_random(100)[..], opts)
+            .await;
+        assert!(matches!(
+            result,
+            Err(SendError::Stream(StreamError::Stalled))
+        ));
+        // three chunks, then the abort once the sender gave up
+        assert_eq!(loopback.delivered(), 4);
+        let mut assembler = receiver.accept_stream().await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(next(&mut assembler).await.unwrap().unwrap().len(), 8);
+        }
+        assert!(matches!(
+            next(&mut assembler).await,
+            Some(Err(StreamError::Aborted))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! Messages whose payload is produced while they are sent.
+//!
+//! A [`StreamingMessage`] pairs the small part of a message, destination,
+//! headers and `unique_id`, with a stream of payload chunks.
+//! [`ProtocolExecutor::send_stream`](crate::ProtocolExecutor::send_stream) hands
+//! it to a transport, which may write the chunks as they come; the default
+//! collects them into an ordinary [`Message`] first.
+
+use std::{error::Error, fmt, future::poll_fn, io, pin::Pin};
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::{Address, BoxStream, Message, NodeInstance, SendError};
+
+pub struct StreamingMessage {
+    /// Everything but the payload, which is ignored.
+    pub message: Message,
+    payload: BoxStream<io::Result<Bytes>>,
+}
+
+impl StreamingMessage {
+    pub fn new(
+        message: Message,
+        payload: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+    ) -> Self {
+        Self {
+            message,
+            payload: Box::pin(payload),
+        }
+    }
+    /// The next payload chunk, `None` once the payload is complete.
+    pub async fn next_chunk(&mut self) -> Option<io::Result<Bytes>> {
+        poll_fn(|cx| Pin::new(&mut self.payload).poll_next(cx)).await
+    }
+    /// Read the whole payload into the message.
+    pub async fn collect(mut self) -> io::Result<Message> {
+        let mut payload = Vec::new();
+        while let Some(chunk) = self.next_chunk().await {
+            payload.extend_from_slice(&chunk?);
+        }
+        Ok(Message {
+            payload,
+            ..self.message
+        })
+    }
+}
+
+impl fmt::Debug for StreamingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamingMessage")
+            .field("destination", &self.message.destination)
+            .field("unique_id", &self.message.unique_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamSendError<E> {
+    /// Producing the payload failed.
+    Payload(io::Error),
+    /// The transport failed.
+    Send(E),
+}
+
+impl<E: fmt::Display> fmt::Display for StreamSendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamSendError::Payload(e) => write!(f, "failed to read payload: {e}"),
+            StreamSendError::Send(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for StreamSendError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StreamSendError::Payload(e) => Some(e),
+            StreamSendError::Send(e) => Some(e),
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Send `message` to the next hop `to`, reading its payload as the executor
+    /// needs it. Unlike [`NodeInstance::send`], the message is neither split nor
+    /// subject to the send timeout, since a transfer may take arbitrarily long.
+    pub async fn send_streaming(
+        &self,
+        message: StreamingMessage,
+        to: impl Into<Address>,
+    ) -> Result<(), SendError> {
+        let to = to.into();
+        let _permit = self.ready().await?;
+        if self
+            .panics
+            .is_unavailable(self.registered_as(&to.protocol, None), None)
+        {
+            return Err(SendError::ProtocolUnavailable(to.protocol.clone()));
+        }
+        let executor = self.executor(&to.protocol).await?.select();
+        let result = executor.send_stream(&to.identity, message).await;
+        result.map_err(|error| self.executor_failure(&to, None, 1, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    use super::*;
+    use crate::testing::{addr, eventually, message, Loopback, TEST};
+
+    /// Yields its chunks one per poll.
+    struct Chunks(VecDeque<io::Result<Bytes>>);
+
+    impl Stream for Chunks {
+        type Item = io::Result<Bytes>;
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.pop_front())
+        }
+    }
+
+    fn streaming(chunks: Vec<io::Result<&'static [u8]>>) -> StreamingMessage {
+        let chunks = chunks
+            .into_iter()
+            .map(|chunk| chunk.map(Bytes::from_static))
+            .collect();
+        StreamingMessage::new(message(addr("dest"), b"ignored"), Chunks(chunks))
+    }
+
+    fn pair() -> (NodeInstance, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let loopback = Loopback::new();
+        let receiver = NodeInstance::new()
+            .with_address(addr("dest"))
+            .with_handler({
+                let received = received.clone();
+                move |message: Message| {
+                    received.lock().unwrap().push(message.payload);
+                    async {}
+                }
+            });
+        loopback.attach("dest", Arc::new(receiver));
+        (NodeInstance::new().with_executor(TEST, loopback), received)
+    }
+
+    #[tokio::test]
+    async fn chunks_arrive_as_one_payload() {
+        let (sender, received) = pair();
+        let message = streaming(vec![Ok(b"stre"), Ok(b""), Ok(b"amed "), Ok(b"payload")]);
+        sender.send_streaming(message, addr("dest")).await.unwrap();
+        eventually(|| received.lock().unwrap().len() == 1).await;
+        assert_eq!(*received.lock().unwrap(), [b"streamed payload".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_chunk_fails_the_send() {
+        let (sender, received) = pair();
+        let message = streaming(vec![Ok(b"half"), Err(io::ErrorKind::BrokenPipe.into())]);
+        assert!(sender.send_streaming(message, addr("dest")).await.is_err());
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
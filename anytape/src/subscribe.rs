@@ -0,0 +1,174 @@
+//! Delivering messages for addresses a node subscribed to.
+//!
+//! Besides messages for its own addresses, a node delivers those whose
+//! destination matches one of its [subscriptions](NodeInstance::subscribe):
+//! a whole protocol or the identities of a protocol starting with some prefix.
+//! A publisher sending one message per subscriber, or relays routing a topic
+//! address to them, gets pub/sub on top of plain addressing. Subscribed
+//! messages are handled like local ones and never relayed further.
+
+use std::fmt;
+
+use crate::{Address, NodeInstance, Protocol};
+
+/// Which destinations a subscription covers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddressPattern {
+    Exact(Address),
+    /// Every identity of the protocol.
+    Protocol(Protocol),
+    /// The identities of the protocol starting with the bytes.
+    Prefix(Protocol, Vec<u8>),
+}
+
+impl AddressPattern {
+    pub fn matches(&self, address: &Address) -> bool {
+        match self {
+            AddressPattern::Exact(exact) => exact == address,
+            AddressPattern::Protocol(protocol) => *protocol == address.protocol,
+            AddressPattern::Prefix(protocol, prefix) => {
+                *protocol == address.protocol && address.identity.as_bytes().starts_with(prefix)
+            }
+        }
+    }
+}
+
+/// `proto:identity`, `proto:*` and `proto:prefix*`, identities in hex.
+impl fmt::Display for AddressPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressPattern::Exact(address) => write!(f, "{address}"),
+            AddressPattern::Protocol(protocol) => write!(f, "{protocol}:*"),
+            AddressPattern::Prefix(protocol, prefix) => {
+                write!(f, "{protocol}:")?;
+                prefix.iter().try_for_each(|byte| write!(f, "{byte:02x}"))?;
+                f.write_str("*")
+            }
+        }
+    }
+}
+
+impl From<Address> for AddressPattern {
+    fn from(address: Address) -> Self {
+        AddressPattern::Exact(address)
+    }
+}
+
+impl NodeInstance {
+    /// Deliver messages for destinations matching `pattern` here; see the
+    /// [module docs](self).
+    pub fn with_subscription(self, pattern: impl Into<AddressPattern>) -> Self {
+        self.subscribe(pattern);
+        self
+    }
+    /// Start delivering messages matching `pattern`. Returns `false` if the
+    /// node was subscribed to it already.
+    pub fn subscribe(&self, pattern: impl Into<AddressPattern>) -> bool {
+        let pattern = pattern.into();
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        if subscriptions.contains(&pattern) {
+            return false;
+        }
+        subscriptions.push(pattern);
+        true
+    }
+    /// Returns `false` if the node was not subscribed to `pattern`.
+    pub fn unsubscribe(&self, pattern: &AddressPattern) -> bool {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|subscribed| subscribed != pattern);
+        subscriptions.len() != before
+    }
+    /// The patterns subscribed to, in the order they were added.
+    pub fn subscriptions(&self) -> Vec<AddressPattern> {
+        self.subscriptions.read().unwrap().clone()
+    }
+    /// Whether `address` matches one of the [subscriptions](NodeInstance::subscribe).
+    pub fn is_subscribed(&self, address: &Address) -> bool {
+        self.subscriptions
+            .read()
+            .unwrap()
+            .iter()
+            .any(|pattern| pattern.matches(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::*, Identity, MessageStatus};
+
+    const OTHER: Protocol = Protocol::new_static(b"other");
+
+    #[test]
+    fn exact_patterns_match_only_their_address() {
+        let pattern = AddressPattern::from(addr("topic/a"));
+        assert!(pattern.matches(&addr("topic/a")));
+        assert!(!pattern.matches(&addr("topic/b")));
+        assert!(!pattern.matches(&Address::new(OTHER, Identity::new("topic/a"))));
+    }
+
+    #[test]
+    fn protocol_patterns_match_every_identity_of_the_protocol() {
+        let pattern = AddressPattern::Protocol(TEST);
+        assert!(pattern.matches(&addr("topic/a")));
+        assert!(pattern.matches(&addr("")));
+        assert!(!pattern.matches(&Address::new(OTHER, Identity::new("topic/a"))));
+    }
+
+    #[test]
+    fn prefix_patterns_match_identities_starting_with_the_prefix() {
+        let pattern = AddressPattern::Prefix(TEST, b"topic/".to_vec());
+        assert!(pattern.matches(&addr("topic/")));
+        assert!(pattern.matches(&addr("topic/a")));
+        assert!(!pattern.matches(&addr("topi")));
+        assert!(!pattern.matches(&addr("other/topic/a")));
+        assert!(!pattern.matches(&Address::new(OTHER, Identity::new("topic/a"))));
+    }
+
+    #[test]
+    fn patterns_display_with_hex_prefixes() {
+        assert_eq!(AddressPattern::Protocol(TEST).to_string(), "test:*");
+        let prefix = AddressPattern::Prefix(TEST, vec![0xab, 0x01]);
+        assert_eq!(prefix.to_string(), "test:ab01*");
+    }
+
+    #[test]
+    fn nodes_deliver_only_what_they_subscribed_to() {
+        let node =
+            NodeInstance::new().with_subscription(AddressPattern::Prefix(TEST, b"news/".to_vec()));
+        assert!(node.is_subscribed(&addr("news/today")));
+        assert!(!node.is_subscribed(&addr("sports/today")));
+
+        assert!(node.subscribe(addr("sports/today")));
+        assert!(!node.subscribe(addr("sports/today")));
+        assert!(node.is_subscribed(&addr("sports/today")));
+        assert_eq!(node.subscriptions().len(), 2);
+
+        assert!(node.unsubscribe(&addr("sports/today").into()));
+        assert!(!node.unsubscribe(&addr("sports/today").into()));
+        assert!(!node.is_subscribed(&addr("sports/today")));
+    }
+
+    #[tokio::test]
+    async fn subscribed_messages_are_delivered_instead_of_relayed() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_address(addr("me"))
+            .with_executor(TEST, recorder.clone())
+            .with_handler(|_| async {})
+            .with_subscription(AddressPattern::Prefix(TEST, b"news/".to_vec()));
+
+        let status = node
+            .dispatch_inbound(message(addr("news/today"), b"x"), addr("me"))
+            .await
+            .unwrap();
+        assert_eq!(status, MessageStatus::Received);
+        assert!(recorder.sent().is_empty());
+
+        node.dispatch_inbound(message(addr("sports/today"), b"x"), addr("me"))
+            .await
+            .unwrap();
+        assert_eq!(recorder.remotes(), [Identity::new("sports/today")]);
+    }
+}
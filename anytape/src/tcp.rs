@@ -0,0 +1,574 @@
+//! TCP transport: messages travel as length-prefixed frames over one pooled
+//! connection per remote, optionally through a proxy.
+//!
+//! Identities are `host:port` strings. Each frame is a big-endian `u32` length
+//! followed by the message in the configured [`WireFormat`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{
+    frame::{FrameReader, MessageLimits},
+    proxy::{ProxyConfig, ProxyError},
+    resolve::{DnsResolver, ResolveError, Resolver, DEFAULT_CONNECT_TIMEOUT},
+    transport::{StatusTable, DEFAULT_STATUS_CAPACITY},
+    typed_address::{self, SocketIdentity},
+    wire::{CompactFormat, WireFormat},
+    ExecutorCapabilities, Identity, Message, MessageStatus, NodeInstance, Protocol,
+    ProtocolExecutor, SendOutcome,
+};
+
+impl Protocol {
+    pub const TCP: Protocol = typed_address::TCP;
+}
+
+#[derive(Debug)]
+pub enum TcpError {
+    InvalidIdentity(Identity),
+    /// The identity could not be resolved to any address.
+    Unresolvable(ResolveError),
+    /// Connecting to the destination failed, at its last address if it has
+    /// several.
+    Connect(io::Error),
+    /// Going through the proxy failed; the destination was never reached.
+    Proxy(ProxyError),
+    /// The connection broke while writing the frame.
+    Io(io::Error),
+    FrameTooLarge(usize),
+}
+
+impl fmt::Display for TcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpError::InvalidIdentity(identity) => {
+                write!(f, "identity {identity:?} is not a host:port pair")
+            }
+            TcpError::Unresolvable(e) => write!(f, "{e}"),
+            TcpError::Connect(e) => write!(f, "failed to connect: {e}"),
+            TcpError::Proxy(e) => write!(f, "{e}"),
+            TcpError::Io(e) => write!(f, "connection failed: {e}"),
+            TcpError::FrameTooLarge(len) => write!(f, "frame of {len} bytes is too large"),
+        }
+    }
+}
+
+impl std::error::Error for TcpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TcpError::Connect(e) | TcpError::Io(e) => Some(e),
+            TcpError::Unresolvable(e) => Some(e),
+            TcpError::Proxy(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type ConnectionSlot = Arc<tokio::sync::Mutex<Option<TcpStream>>>;
+
+pub struct TcpExecutor {
+    connections: Arc<Mutex<HashMap<Identity, ConnectionSlot>>>,
+    statuses: Arc<Mutex<StatusTable>>,
+    proxy: Option<Arc<ProxyConfig>>,
+    proxy_bypass: Arc<Vec<Vec<u8>>>,
+    format: Arc<dyn WireFormat>,
+    resolver: Arc<dyn Resolver>,
+    connect_timeout: Duration,
+}
+
+impl TcpExecutor {
+    pub fn new() -> Self {
+        Self {
+            connections: Arc::default(),
+            statuses: Arc::new(Mutex::new(StatusTable::new(DEFAULT_STATUS_CAPACITY))),
+            proxy: None,
+            proxy_bypass: Arc::default(),
+            format: Arc::new(CompactFormat),
+            resolver: Arc::new(DnsResolver),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+    /// Encode outgoing messages with `format`; the listener must use the same one.
+    pub fn with_wire_format(self, format: impl WireFormat + 'static) -> Self {
+        Self {
+            format: Arc::new(format),
+            ..self
+        }
+    }
+    /// Look up remotes with `resolver` instead of the system's DNS. Proxied
+    /// connections leave resolution to the proxy.
+    pub fn with_resolver(self, resolver: impl Resolver + 'static) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            ..self
+        }
+    }
+    /// Give up on a resolved address and try the next one after `timeout`.
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        Self {
+            connect_timeout: timeout,
+            ..self
+        }
+    }
+    /// Connect through `proxy` unless the remote matches a bypass rule.
+    pub fn with_proxy(self, proxy: ProxyConfig) -> Self {
+        Self {
+            proxy: Some(Arc::new(proxy)),
+            ..self
+        }
+    }
+    /// Connect directly to identities starting with `prefix`, even with a proxy configured.
+    pub fn with_proxy_bypass(self, prefix: impl Into<Vec<u8>>) -> Self {
+        let mut proxy_bypass = Vec::clone(&self.proxy_bypass);
+        proxy_bypass.push(prefix.into());
+        Self {
+            proxy_bypass: Arc::new(proxy_bypass),
+            ..self
+        }
+    }
+
+    fn slot(&self, remote: &Identity) -> ConnectionSlot {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(remote.clone())
+            .or_default()
+            .clone()
+    }
+}
+
+struct Connector {
+    proxy: Option<Arc<ProxyConfig>>,
+    proxy_bypass: Arc<Vec<Vec<u8>>>,
+    resolver: Arc<dyn Resolver>,
+    connect_timeout: Duration,
+}
+
+impl Connector {
+    async fn connect(&self, remote: &Identity) -> Result<TcpStream, TcpError> {
+        let bypass = self
+            .proxy_bypass
+            .iter()
+            .any(|prefix| remote.as_bytes().starts_with(prefix));
+        let stream = match &self.proxy {
+            Some(proxy) if !bypass => {
+                let target = SocketIdentity::try_from(remote)
+                    .map_err(|_| TcpError::InvalidIdentity(remote.clone()))?;
+                proxy
+                    .connect(target.host(), target.port())
+                    .await
+                    .map_err(TcpError::Proxy)?
+            }
+            _ => self.connect_direct(remote).await?,
+        };
+        let _ = stream.set_nodelay(true);
+        Ok(stream)
+    }
+    async fn connect_direct(&self, remote: &Identity) -> Result<TcpStream, TcpError> {
+        let addrs = self.resolver.resolve(remote).await.map_err(|e| match e {
+            ResolveError::InvalidIdentity(identity) => TcpError::InvalidIdentity(identity),
+            e => TcpError::Unresolvable(e),
+        })?;
+        let mut last = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        for addr in addrs {
+            match tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last = e,
+                Err(_) => last = io::Error::from(io::ErrorKind::TimedOut),
+            }
+        }
+        Err(TcpError::Connect(last))
+    }
+}
+
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(frame).await?;
+    stream.flush().await
+}
+
+impl Default for TcpExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolExecutor for TcpExecutor {
+    type Error = TcpError;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let slot = self.slot(remote);
+        let statuses = self.statuses.clone();
+        let connector = Connector {
+            proxy: self.proxy.clone(),
+            proxy_bypass: self.proxy_bypass.clone(),
+            resolver: self.resolver.clone(),
+            connect_timeout: self.connect_timeout,
+        };
+        let encoded = self.format.encode(&message);
+        let remote = remote.clone();
+        async move {
+            let unique_id = message.unique_id;
+            let result = async {
+                let len = u32::try_from(encoded.len())
+                    .map_err(|_| TcpError::FrameTooLarge(encoded.len()))?;
+                let mut frame = Vec::with_capacity(4 + encoded.len());
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(&encoded);
+
+                let mut slot = slot.lock().await;
+                if let Some(stream) = slot.as_mut() {
+                    if write_frame(stream, &frame).await.is_ok() {
+                        return Ok(());
+                    }
+                    // the pooled connection went stale; try once more on a fresh one
+                    *slot = None;
+                }
+                let mut stream = connector.connect(&remote).await?;
+                write_frame(&mut stream, &frame)
+                    .await
+                    .map_err(TcpError::Io)?;
+                *slot = Some(stream);
+                Ok(())
+            }
+            .await;
+            let status = match result {
+                Ok(()) => MessageStatus::Sended,
+                Err(_) => MessageStatus::SendError,
+            };
+            statuses.lock().unwrap().set(unique_id, status);
+            result.map(|()| SendOutcome::sent())
+        }
+    }
+
+    fn get_status(
+        &self,
+        _remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let status = self
+            .statuses
+            .lock()
+            .unwrap()
+            .get(message.unique_id)
+            .unwrap_or(MessageStatus::Unreachable);
+        async move { Ok(status) }
+    }
+
+    fn capabilities(&self) -> ExecutorCapabilities {
+        ExecutorCapabilities {
+            max_message_size: MessageLimits::default().max_total_encoded_size,
+            ordered: true,
+            reliable: true,
+            supports_status: true,
+        }
+    }
+
+    /// Shuts down every pooled connection, once the sends using it are done.
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        let slots: Vec<_> = self.connections.lock().unwrap().drain().collect();
+        async move {
+            for (_, slot) in slots {
+                if let Some(mut stream) = slot.lock().await.take() {
+                    let _ = stream.shutdown().await;
+                }
+            }
+        }
+    }
+}
+
+/// Server side of the TCP transport: reads frames from every accepted connection
+/// and hands the decoded messages to `sink`.
+pub struct TcpListenerTask {
+    listener: TcpListener,
+    sink: mpsc::Sender<Message>,
+    limits: MessageLimits,
+    format: Arc<dyn WireFormat>,
+    protocol_violations: Arc<AtomicU64>,
+}
+
+/// Counters of a running [`TcpListenerTask`].
+#[derive(Clone)]
+pub struct ListenerStats {
+    protocol_violations: Arc<AtomicU64>,
+}
+
+impl ListenerStats {
+    /// Connections closed because the peer sent an oversize, empty, truncated or
+    /// undecodable frame.
+    pub fn protocol_violations(&self) -> u64 {
+        self.protocol_violations.load(Ordering::Relaxed)
+    }
+}
+
+impl TcpListenerTask {
+    pub async fn bind(addr: SocketAddr, sink: mpsc::Sender<Message>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            sink,
+            limits: MessageLimits::default(),
+            format: Arc::new(CompactFormat),
+            protocol_violations: Arc::default(),
+        })
+    }
+
+    pub fn with_wire_format(self, format: impl WireFormat + 'static) -> Self {
+        Self {
+            format: Arc::new(format),
+            ..self
+        }
+    }
+
+    pub fn with_message_limits(self, limits: MessageLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Count protocol violations towards `node`'s
+    /// [metrics](NodeInstance::metrics_snapshot). The [stats](Self::stats) then
+    /// read the node's count, which other listeners may add to.
+    pub fn with_node_metrics(self, node: &NodeInstance) -> Self {
+        Self {
+            protocol_violations: node.protocol_violation_counter(),
+            ..self
+        }
+    }
+
+    pub fn stats(&self) -> ListenerStats {
+        ListenerStats {
+            protocol_violations: self.protocol_violations.clone(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the sink is dropped.
+    pub async fn run(self) {
+        while let Ok((mut stream, _peer)) = self.listener.accept().await {
+            if self.sink.is_closed() {
+                break;
+            }
+            let sink = self.sink.clone();
+            let limits = self.limits;
+            let format = self.format.clone();
+            let violations = self.protocol_violations.clone();
+            tokio::spawn(async move {
+                let mut frames = FrameReader::new(limits);
+                loop {
+                    let frame = match frames.read_frame(&mut stream).await {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => return,
+                        Err(e) => {
+                            if e.is_protocol_violation() {
+                                violations.fetch_add(1, Ordering::Relaxed);
+                            }
+                            return;
+                        }
+                    };
+                    let Ok(message) = format.decode(&frame) else {
+                        violations.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    };
+                    if sink.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// A SOCKS5 proxy without auth that tunnels every CONNECT and keeps the
+    /// targets it was asked for.
+    async fn socks5_proxy() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let seen = targets.clone();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0; 2];
+                    client.read_exact(&mut greeting).await?;
+                    let mut methods = vec![0; usize::from(greeting[1])];
+                    client.read_exact(&mut methods).await?;
+                    client.write_all(&[5, 0]).await?;
+                    let mut request = [0; 4];
+                    client.read_exact(&mut request).await?;
+                    let host = match request[3] {
+                        1 => {
+                            let mut ip = [0; 4];
+                            client.read_exact(&mut ip).await?;
+                            std::net::Ipv4Addr::from(ip).to_string()
+                        }
+                        _ => {
+                            let mut name = vec![0; usize::from(client.read_u8().await?)];
+                            client.read_exact(&mut name).await?;
+                            String::from_utf8_lossy(&name).into_owned()
+                        }
+                    };
+                    let port = client.read_u16().await?;
+                    let target = format!("{host}:{port}");
+                    seen.lock().unwrap().push(target.clone());
+                    let mut upstream = TcpStream::connect(target).await?;
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    io::Result::Ok(())
+                });
+            }
+        });
+        (local, targets)
+    }
+
+    async fn listener() -> (Identity, mpsc::Receiver<Message>) {
+        let (sink, inbound) = mpsc::channel(8);
+        let listener = TcpListenerTask::bind("127.0.0.1:0".parse().unwrap(), sink)
+            .await
+            .unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+        (Identity::new(local.to_string()), inbound)
+    }
+
+    fn message(payload: &[u8]) -> Message {
+        let to = crate::Address::new(Protocol::TCP, Identity::new("unused"));
+        crate::MessageBuilder::new(to).payload(payload).build()
+    }
+
+    #[tokio::test]
+    async fn messages_tunnel_through_a_socks5_proxy() {
+        let (proxy, targets) = socks5_proxy().await;
+        let (remote, mut inbound) = listener().await;
+        let executor = TcpExecutor::new().with_proxy(ProxyConfig::Socks5 {
+            addr: proxy.to_string(),
+            auth: None,
+        });
+        for payload in [b"first", b"again"] {
+            executor.send(&remote, message(payload)).await.unwrap();
+            let received = tokio::time::timeout(Duration::from_secs(5), inbound.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received.payload, payload);
+        }
+        // the second message reused the tunnel
+        let remote = String::from_utf8(remote.as_bytes().to_vec()).unwrap();
+        assert_eq!(*targets.lock().unwrap(), [remote]);
+    }
+
+    #[tokio::test]
+    async fn bypassed_remotes_are_connected_directly() {
+        let (proxy, targets) = socks5_proxy().await;
+        let (remote, mut inbound) = listener().await;
+        let executor = TcpExecutor::new()
+            .with_proxy(ProxyConfig::Socks5 {
+                addr: proxy.to_string(),
+                auth: None,
+            })
+            .with_proxy_bypass("127.0.0.1:");
+        executor.send(&remote, message(b"direct")).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), inbound.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload, b"direct");
+        assert!(targets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_proxies_are_told_apart_from_the_destination() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = closed.local_addr().unwrap();
+        drop(closed);
+        let (remote, _inbound) = listener().await;
+        let executor = TcpExecutor::new().with_proxy(ProxyConfig::Socks5 {
+            addr: proxy.to_string(),
+            auth: None,
+        });
+        let result = executor.send(&remote, message(b"x")).await;
+        assert!(matches!(
+            result,
+            Err(TcpError::Proxy(ProxyError::Unreachable(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resolved_addresses_are_tried_until_one_connects() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = closed.local_addr().unwrap();
+        drop(closed);
+        let (live, mut inbound) = listener().await;
+        let live: SocketAddr = std::str::from_utf8(live.as_bytes())
+            .unwrap()
+            .parse()
+            .unwrap();
+        let remote = Identity::new("service:1");
+        let executor = TcpExecutor::new().with_resolver(
+            crate::resolve::StaticResolver::new().with_entry(remote.clone(), [dead, live]),
+        );
+        executor.send(&remote, message(b"second")).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), inbound.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload, b"second");
+
+        let executor = TcpExecutor::new().with_resolver(
+            crate::resolve::StaticResolver::new().with_entry(remote.clone(), [dead]),
+        );
+        let result = executor.send(&remote, message(b"x")).await;
+        assert!(matches!(result, Err(TcpError::Connect(_))));
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_count_towards_the_node() {
+        let node = NodeInstance::new();
+        let (sink, _inbound) = mpsc::channel(1);
+        let listener = TcpListenerTask::bind("127.0.0.1:0".parse().unwrap(), sink)
+            .await
+            .unwrap()
+            .with_message_limits(MessageLimits {
+                max_total_encoded_size: 1024,
+            })
+            .with_node_metrics(&node);
+        let (local, stats) = (listener.local_addr().unwrap(), listener.stats());
+        tokio::spawn(listener.run());
+
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.metrics_snapshot().protocol_violations == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.protocol_violations(), 1);
+    }
+}
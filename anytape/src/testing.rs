@@ -0,0 +1,355 @@
+//! Executors and helpers shared by the unit tests.
+
+// not every feature combination uses every helper
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures_core::Stream;
+
+use crate::{
+    Address, BackendEvents, BoxFuture, BoxResult, BoxStream, DataBackend, ExecutorCapabilities,
+    Identity, IdentityAlias, Message, MessageBuilder, MessageStatus, NodeInstance, Protocol,
+    ProtocolExecutor, RouteChange, SendContext, SendOutcome, StoredRoute,
+};
+
+/// The protocol the test executors are registered for.
+pub(crate) const TEST: Protocol = Protocol::new_static(b"test");
+
+pub(crate) fn addr(identity: &str) -> Address {
+    Address::new(TEST, Identity::new(identity))
+}
+
+pub(crate) fn message(to: Address, payload: &[u8]) -> Message {
+    MessageBuilder::new(to).payload(payload).build()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TestError(pub(crate) &'static str);
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for TestError {}
+
+/// Keeps every message it is asked to send, after an optional delay, and fails
+/// them if told to.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    sent: Arc<Mutex<Vec<(Identity, Message)>>>,
+    batches: Arc<Mutex<Vec<usize>>>,
+    closes: Arc<AtomicUsize>,
+    delay: Option<Duration>,
+    fail: Option<&'static str>,
+    fail_first: Arc<AtomicUsize>,
+    capabilities: ExecutorCapabilities,
+    status: MessageStatus,
+    outcome: SendOutcome,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            sent: Default::default(),
+            batches: Default::default(),
+            closes: Default::default(),
+            delay: None,
+            fail: None,
+            fail_first: Default::default(),
+            capabilities: ExecutorCapabilities::default(),
+            status: MessageStatus::Sended,
+            outcome: SendOutcome::sent(),
+        }
+    }
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+    pub(crate) fn with_delay(self, delay: Duration) -> Self {
+        Self {
+            delay: Some(delay),
+            ..self
+        }
+    }
+    pub(crate) fn failing(self, reason: &'static str) -> Self {
+        Self {
+            fail: Some(reason),
+            ..self
+        }
+    }
+    /// Fail the first `sends` sends, whatever they are, and no others.
+    pub(crate) fn failing_first(self, sends: usize) -> Self {
+        self.fail_first.store(sends, Ordering::Relaxed);
+        self
+    }
+    pub(crate) fn with_capabilities(self, capabilities: ExecutorCapabilities) -> Self {
+        Self {
+            capabilities,
+            ..self
+        }
+    }
+    /// Answer status queries with `status`, and claim to support them.
+    pub(crate) fn with_status(mut self, status: MessageStatus) -> Self {
+        self.capabilities.supports_status = true;
+        Self { status, ..self }
+    }
+    /// Report every send that succeeds as `outcome`.
+    pub(crate) fn with_outcome(self, outcome: SendOutcome) -> Self {
+        Self { outcome, ..self }
+    }
+    /// The messages sent so far, oldest first.
+    pub(crate) fn sent(&self) -> Vec<Message> {
+        let sent = self.sent.lock().unwrap();
+        sent.iter().map(|(_, message)| message.clone()).collect()
+    }
+    /// The remotes sent to so far, oldest first.
+    pub(crate) fn remotes(&self) -> Vec<Identity> {
+        let sent = self.sent.lock().unwrap();
+        sent.iter().map(|(remote, _)| remote.clone()).collect()
+    }
+    /// The size of every batch it was handed, oldest first.
+    pub(crate) fn batches(&self) -> Vec<usize> {
+        self.batches.lock().unwrap().clone()
+    }
+    /// How often the executor was closed.
+    pub(crate) fn closes(&self) -> usize {
+        self.closes.load(Ordering::Relaxed)
+    }
+}
+
+impl ProtocolExecutor for Recorder {
+    type Error = TestError;
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, TestError>> + Send + 'static {
+        let (sent, delay, fail) = (self.sent.clone(), self.delay, self.fail);
+        let outcome = self.outcome;
+        let flaky = self
+            .fail_first
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        let remote = remote.clone();
+        async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(reason) = fail {
+                return Err(TestError(reason));
+            }
+            if flaky {
+                return Err(TestError("flaky"));
+            }
+            sent.lock().unwrap().push((remote, message));
+            Ok(outcome)
+        }
+    }
+    fn send_batch(
+        &self,
+        remote: &Identity,
+        messages: Vec<Message>,
+    ) -> impl Future<Output = Result<Vec<Result<SendOutcome, TestError>>, TestError>> + Send + 'static
+    {
+        self.batches.lock().unwrap().push(messages.len());
+        let sends: Vec<_> = messages
+            .into_iter()
+            .map(|message| self.send(remote, message))
+            .collect();
+        async move {
+            let mut results = Vec::with_capacity(sends.len());
+            for send in sends {
+                results.push(SendContext::scope_message(send).await);
+            }
+            Ok(results)
+        }
+    }
+    fn get_status(
+        &self,
+        _: &Identity,
+        _: Message,
+    ) -> impl Future<Output = Result<MessageStatus, TestError>> + Send + 'static {
+        std::future::ready(Ok(self.status))
+    }
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.capabilities
+    }
+    fn close(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.closes.fetch_add(1, Ordering::Relaxed);
+        std::future::ready(())
+    }
+}
+
+/// Hands messages straight to the inbound side of other nodes, looked up by
+/// identity when sending, so nodes can be attached after they were built.
+#[derive(Clone)]
+pub(crate) struct Loopback {
+    nodes: Arc<Mutex<HashMap<Identity, Arc<NodeInstance>>>>,
+    delivered: Arc<AtomicUsize>,
+    capabilities: ExecutorCapabilities,
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self {
+            nodes: Default::default(),
+            delivered: Default::default(),
+            capabilities: ExecutorCapabilities {
+                supports_status: true,
+                ..ExecutorCapabilities::default()
+            },
+        }
+    }
+}
+
+impl Loopback {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+    /// Claim to carry messages of at most `max_message_size` bytes.
+    pub(crate) fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.capabilities.max_message_size = max_message_size;
+        self
+    }
+    /// Let `node` receive what is sent to `addr(identity)`.
+    pub(crate) fn attach(&self, identity: &str, node: Arc<NodeInstance>) {
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(Identity::new(identity), node);
+    }
+    /// How many messages were handed to a node so far.
+    pub(crate) fn delivered(&self) -> usize {
+        self.delivered.load(Ordering::Relaxed)
+    }
+}
+
+impl ProtocolExecutor for Loopback {
+    type Error = TestError;
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, TestError>> + Send + 'static {
+        let node = self.nodes.lock().unwrap().get(remote).cloned();
+        let accept_at = Address::new(TEST, remote.clone());
+        let max_message_size = self.capabilities.max_message_size;
+        let delivered = self.delivered.clone();
+        async move {
+            let node = node.ok_or(TestError("unreachable"))?;
+            // through the wire format, as a real transport would
+            let bytes = message.encode();
+            if bytes.len() > max_message_size {
+                return Err(TestError("too large"));
+            }
+            let message = Message::decode(&bytes).map_err(|_| TestError("decode"))?;
+            delivered.fetch_add(1, Ordering::Relaxed);
+            let dispatch: BoxFuture<_> =
+                Box::pin(async move { node.dispatch_inbound(message, accept_at).await });
+            match dispatch.await {
+                Ok(MessageStatus::Received) => Ok(SendOutcome::received(None)),
+                Ok(_) => Ok(SendOutcome::sent()),
+                Err(_) => Err(TestError("rejected")),
+            }
+        }
+    }
+    fn get_status(
+        &self,
+        remote: &Identity,
+        _: Message,
+    ) -> impl Future<Output = Result<MessageStatus, TestError>> + Send + 'static {
+        let known = self.nodes.lock().unwrap().contains_key(remote);
+        std::future::ready(Ok(if known {
+            MessageStatus::Received
+        } else {
+            MessageStatus::Unreachable
+        }))
+    }
+    fn capabilities(&self) -> ExecutorCapabilities {
+        self.capabilities
+    }
+}
+
+/// A backend the test keeps a handle on after giving it to a node.
+pub(crate) struct Shared<B>(pub(crate) Arc<B>);
+
+impl<B: DataBackend> DataBackend for Shared<B> {
+    fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+        self.0.get_next(addr)
+    }
+    fn set_next(
+        &self,
+        addr: &Address,
+        next: Option<&Address>,
+    ) -> BoxFuture<BoxResult<Option<Address>>> {
+        self.0.set_next(addr, next)
+    }
+    fn set_next_batch(&self, routes: Vec<(Address, Option<Address>)>) -> BoxFuture<BoxResult<()>> {
+        self.0.set_next_batch(routes)
+    }
+    fn watch(&self) -> Option<BoxStream<RouteChange>> {
+        self.0.watch()
+    }
+    fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+        self.0.scan()
+    }
+    fn touch(&self, addr: &Address, at_ms: u64) -> BoxFuture<BoxResult<()>> {
+        self.0.touch(addr, at_ms)
+    }
+    fn get_alias(&self, old: &Identity) -> BoxFuture<BoxResult<Option<IdentityAlias>>> {
+        self.0.get_alias(old)
+    }
+    fn set_alias(&self, old: &Identity, alias: Option<&IdentityAlias>) -> BoxFuture<BoxResult<()>> {
+        self.0.set_alias(old, alias)
+    }
+    fn attach_events(&self, events: BackendEvents) {
+        self.0.attach_events(events)
+    }
+}
+
+/// The next item of `stream`.
+pub(crate) async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+    std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+}
+
+/// Yield to other tasks until `condition` holds, panicking if it never does.
+pub(crate) async fn eventually(mut condition: impl FnMut() -> bool) {
+    for _ in 0..1000 {
+        if condition() {
+            return;
+        }
+        tokio::task::yield_now().await;
+    }
+    panic!("condition never held");
+}
+
+/// Run `future` to completion on the current thread without any runtime, for
+/// futures that never wait on one.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    for _ in 0..1000 {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+    panic!("future never completed");
+}
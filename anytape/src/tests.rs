@@ -0,0 +1,610 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::testing::{addr, block_on, message, Recorder, Shared, TEST};
+use crate::{
+    DataBackend, ExecutorCapabilities, MemoryBackend, MessageStatus, NodeInstance, PathNode,
+    Protocol, RetryPolicy, RetryingExecutor, SendError, SendOutcome,
+};
+
+const SLOW: Protocol = Protocol::new_static(b"slow");
+
+#[tokio::test(start_paused = true)]
+async fn send_timeouts_apply_per_protocol() {
+    let node = NodeInstance::new()
+        .with_executor(TEST, Recorder::new().with_delay(Duration::from_secs(10)))
+        .with_executor(SLOW, Recorder::new().with_delay(Duration::from_secs(10)))
+        .with_send_timeout(Duration::from_secs(2))
+        .with_send_timeout_for(TEST, Duration::from_millis(50));
+    let slow = crate::Address::new(SLOW, crate::Identity::new("b"));
+    for (to, expected) in [
+        (addr("b"), Duration::from_millis(50)),
+        (slow, Duration::from_secs(2)),
+    ] {
+        let start = tokio::time::Instant::now();
+        let result = node.send(message(to.clone(), b"x"), to).await;
+        assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+        assert_eq!(start.elapsed(), expected);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn send_timeout_covers_every_fragment() {
+    let recorder = Recorder::new()
+        .with_delay(Duration::from_millis(40))
+        .with_capabilities(ExecutorCapabilities {
+            max_message_size: 300,
+            ..ExecutorCapabilities::default()
+        });
+    let node = NodeInstance::new()
+        .with_executor(TEST, recorder.clone())
+        .with_send_timeout(Duration::from_millis(100));
+    let start = tokio::time::Instant::now();
+    let result = node.send(message(addr("b"), &[7; 1000]), addr("b")).await;
+    assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+    assert_eq!(start.elapsed(), Duration::from_millis(100));
+    assert!(recorder.sent().len() < 4);
+}
+
+#[tokio::test]
+async fn iter_routes_snapshots_the_route_cache() {
+    let backend = Arc::new(MemoryBackend::new());
+    let node = NodeInstance::new().with_backend(Shared(backend.clone()));
+    assert!(node.iter_routes().is_empty());
+    for (destination, next) in [("d1", "b"), ("d2", "c")] {
+        backend
+            .set_next(&addr(destination), Some(&addr(next)))
+            .await
+            .unwrap();
+        node.resolve_next(&addr(destination)).await.unwrap();
+    }
+    let mut routes = node.iter_routes();
+    routes.sort_by_key(|(destination, _)| destination.to_string());
+    assert_eq!(
+        routes,
+        vec![(addr("d1"), addr("b")), (addr("d2"), addr("c"))]
+    );
+}
+
+#[test]
+fn cached_lookups_work_without_a_runtime() {
+    let backend = Arc::new(MemoryBackend::new());
+    block_on(backend.set_next(&addr("dest"), Some(&addr("b")))).unwrap();
+    let node = NodeInstance::new().with_backend(Shared(backend));
+    // the first lookup fills the cache, the second hits it and would touch the backend
+    for _ in 0..2 {
+        assert_eq!(
+            block_on(node.resolve_next(&addr("dest"))).unwrap(),
+            addr("b")
+        );
+    }
+}
+
+#[tokio::test]
+async fn acknowledged_sends_report_the_remote_sequence_number() {
+    let recorder = Recorder::new().with_outcome(SendOutcome::received(Some(7)));
+    let node = NodeInstance::new().with_executor(TEST, recorder);
+    let receipt = node
+        .send_detailed(message(addr("b"), b"x"), addr("b"))
+        .await
+        .unwrap();
+    assert_eq!(receipt.outcome.status, MessageStatus::Received);
+    assert_eq!(receipt.outcome.remote_seq, Some(7));
+
+    let plain = NodeInstance::new().with_executor(TEST, Recorder::new());
+    let receipt = plain
+        .send_detailed(message(addr("b"), b"x"), addr("b"))
+        .await
+        .unwrap();
+    assert_eq!(receipt.outcome, SendOutcome::sent());
+}
+
+#[tokio::test]
+async fn split_messages_are_acknowledged_only_if_every_piece_is() {
+    let recorder = Recorder::new()
+        .with_outcome(SendOutcome::received(Some(7)))
+        .with_capabilities(ExecutorCapabilities {
+            max_message_size: 300,
+            ..ExecutorCapabilities::default()
+        });
+    let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+    let receipt = node
+        .send_detailed(message(addr("b"), &[7; 1000]), addr("b"))
+        .await
+        .unwrap();
+    assert!(recorder.sent().len() > 1);
+    assert_eq!(receipt.outcome, SendOutcome::received(Some(7)));
+}
+
+#[tokio::test]
+async fn receipts_describe_the_send() {
+    let node = NodeInstance::new().with_executor(TEST, Recorder::new());
+    let message = message(addr("b"), b"x");
+    let unique_id = message.unique_id;
+    let receipt = node.send_detailed(message, addr("b")).await.unwrap();
+    assert_eq!(receipt.protocol, TEST);
+    assert_eq!(receipt.registration, TEST);
+    assert_eq!(receipt.unique_id, unique_id);
+    assert_eq!(receipt.attempts, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn receipts_count_the_attempts_of_a_retried_send() {
+    let backoff = Duration::from_millis(1);
+    let retrying = RetryingExecutor::new(
+        Recorder::new().failing_first(2),
+        RetryPolicy::new(3).with_backoff(backoff, backoff),
+    );
+    let node = NodeInstance::new().with_executor(TEST, retrying);
+    let receipt = node
+        .send_detailed(message(addr("b"), b"x"), addr("b"))
+        .await
+        .unwrap();
+    assert_eq!(receipt.attempts, 3);
+}
+
+#[tokio::test]
+async fn the_send_result_hook_fires_once_per_send() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let hook = {
+        let calls = calls.clone();
+        move |_: &crate::Address, unique_id, result: &Result<_, SendError>| {
+            calls.lock().unwrap().push((unique_id, result.is_ok()))
+        }
+    };
+    let node = NodeInstance::new()
+        .with_executor(TEST, Recorder::new())
+        .with_executor(SLOW, Recorder::new().failing("down"))
+        .with_on_send_result(hook);
+    let ok = message(addr("b"), b"x");
+    let failing = message(crate::Address::new(SLOW, crate::Identity::new("b")), b"x");
+    let expected = vec![(ok.unique_id, true), (failing.unique_id, false)];
+    node.dispatch(ok).await.unwrap();
+    assert!(node.dispatch(failing).await.is_err());
+    assert_eq!(*calls.lock().unwrap(), expected);
+}
+
+#[test]
+fn addresses_group_by_protocol_in_order() {
+    let other = |identity: &str| crate::Address::new(SLOW, crate::Identity::new(identity));
+    let addrs = vec![addr("a"), other("x"), addr("b"), other("y"), addr("c")];
+    let groups = crate::group_by_protocol(&addrs);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[&TEST], [&addr("a"), &addr("b"), &addr("c")]);
+    assert_eq!(groups[&SLOW], [&other("x"), &other("y")]);
+    assert_eq!(crate::protocols(&addrs), [&TEST, &SLOW].into());
+    let node = crate::Node::new(addrs.clone());
+    assert_eq!(node.group_by_protocol(), groups);
+}
+
+#[tokio::test]
+async fn dispatch_routes_by_the_message_destination() {
+    let recorder = Recorder::new();
+    let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+    node.dispatch(message(addr("b"), b"x")).await.unwrap();
+    // send takes its next hop from the caller instead
+    node.send(message(addr("b"), b"y"), addr("c"))
+        .await
+        .unwrap();
+    let remotes = recorder.remotes();
+    assert_eq!(
+        remotes,
+        [crate::Identity::new("b"), crate::Identity::new("c")]
+    );
+}
+
+#[tokio::test]
+async fn relays_add_exactly_one_path_node_and_reach_the_next_hop() {
+    let recorder = Recorder::new();
+    let backend = MemoryBackend::new();
+    backend
+        .set_next(&addr("far"), Some(&addr("next")))
+        .await
+        .unwrap();
+    let node = NodeInstance::new()
+        .with_executor(TEST, recorder.clone())
+        .with_backend(backend);
+    let mut relayed = message(addr("far"), b"x");
+    relayed
+        .path
+        .push(PathNode::new().with_address(addr("upstream")));
+    node.relay(relayed, addr("me")).await.unwrap();
+
+    assert_eq!(recorder.remotes(), [crate::Identity::new("next")]);
+    let path = &recorder.sent()[0].path;
+    assert_eq!(path.len(), 2);
+    assert_eq!(path[0].address, Some(addr("upstream")));
+    assert_eq!(path[1].address, Some(addr("me")));
+}
+
+#[tokio::test]
+async fn relays_refuse_messages_that_went_through_them() {
+    let recorder = Recorder::new();
+    let node = NodeInstance::new()
+        .with_address(addr("me"))
+        .with_executor(TEST, recorder.clone());
+    let mut looped = message(addr("far"), b"x");
+    looped.path.push(PathNode::new().with_address(addr("me")));
+    let result = node.relay(looped, addr("me")).await;
+    assert!(matches!(result, Err(SendError::Loop)));
+    assert!(recorder.sent().is_empty());
+}
+
+#[tokio::test]
+async fn receive_maps_each_drop_to_its_reason() {
+    use crate::RejectReason;
+
+    let rejected = |reason| MessageStatus::Rejected { reason };
+    let relay = NodeInstance::new()
+        .with_address(addr("me"))
+        .with_executor(TEST, Recorder::new())
+        .with_max_path_len(2);
+
+    let mut exhausted = message(addr("far"), b"x");
+    exhausted.ttl = Some(0);
+    assert_eq!(
+        relay.receive(exhausted, addr("me")).await,
+        rejected(RejectReason::TtlExceeded)
+    );
+
+    let mut long = message(addr("far"), b"x");
+    long.path = vec![
+        PathNode::new().with_address(addr("a")),
+        PathNode::new().with_address(addr("b")),
+    ];
+    assert_eq!(
+        relay.receive(long, addr("me")).await,
+        rejected(RejectReason::PathTooLong)
+    );
+
+    assert_eq!(
+        relay.receive(message(addr("me"), b"x"), addr("me")).await,
+        rejected(RejectReason::NoHandler)
+    );
+
+    let mut live = message(addr("far"), b"x");
+    live.ttl = Some(1);
+    assert_eq!(relay.receive(live, addr("me")).await, MessageStatus::Sended);
+}
+
+#[test]
+fn protocols_and_identities_are_byte_slices() {
+    fn byte_len(bytes: impl AsRef<[u8]>) -> usize {
+        bytes.as_ref().len()
+    }
+    assert_eq!(byte_len(TEST), 4);
+    assert_eq!(byte_len(Protocol::new("quic")), 4);
+    assert_eq!(byte_len(crate::Identity::new([0xffu8; 7])), 7);
+    assert_eq!(TEST.as_ref(), b"test");
+}
+
+#[tokio::test]
+async fn tag_routes_steer_messages_by_their_tags() {
+    use crate::MessageBuilder;
+
+    let recorder = Recorder::new();
+    let backend = MemoryBackend::new();
+    backend
+        .set_next(&addr("dest"), Some(&addr("usual")))
+        .await
+        .unwrap();
+    let node = NodeInstance::new()
+        .with_executor(TEST, recorder.clone())
+        .with_backend(backend)
+        .with_tag_route("lane", "bulk", addr("bulk-relay"))
+        .with_tag_route("lane", "urgent", addr("fast-relay"))
+        .with_tag_route("priority", "high", addr("priority-relay"));
+    for lane in ["urgent", "bulk", "other"] {
+        let message = MessageBuilder::new(addr("dest"))
+            .tag("lane", lane)
+            .tag("priority", "high")
+            .build();
+        node.forward(message).await.unwrap();
+    }
+    node.forward(message(addr("dest"), b"untagged"))
+        .await
+        .unwrap();
+    // the first matching route wins; untagged messages take the usual route
+    let expected =
+        ["fast-relay", "bulk-relay", "priority-relay", "usual"].map(crate::Identity::new);
+    assert_eq!(recorder.remotes(), expected);
+    assert_eq!(recorder.sent()[0].tag("lane"), Some("urgent"));
+}
+
+#[tokio::test]
+async fn registered_executors_downcast_to_their_concrete_type() {
+    use crate::{testing::Loopback, DynProtocolExecutor};
+
+    let recorder = Recorder::new();
+    let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+    node.send(message(addr("b"), b"x"), addr("b"))
+        .await
+        .unwrap();
+    let registered = node.executor_as::<Recorder>(&TEST).unwrap();
+    assert_eq!(registered.sent().len(), 1);
+    assert!(node.executor_as::<Loopback>(&TEST).is_none());
+    assert!(node.executor_as::<Recorder>(&SLOW).is_none());
+
+    let boxed: Arc<dyn DynProtocolExecutor> = Arc::new(recorder);
+    assert!(boxed.as_any().downcast_ref::<Recorder>().is_some());
+}
+
+#[test]
+fn independently_built_messages_compare_equal() {
+    use crate::MessageBuilder;
+
+    let build = |first_tag: (&str, &str), second_tag: (&str, &str)| {
+        let mut message = MessageBuilder::new(addr("dest"))
+            .payload("x")
+            .unique_id(7)
+            .ttl(3)
+            .header("h", "v")
+            .tag(first_tag.0, first_tag.1)
+            .tag(second_tag.0, second_tag.1)
+            .build();
+        message
+            .path
+            .push(PathNode::new().with_address(addr("a")).with_ts(1));
+        message
+    };
+    let (a, b) = (("region", "eu"), ("lane", "bulk"));
+    assert!(build(a, b) == build(a, b));
+    // tags keep their order, so the same tags in another order are another message
+    assert!(build(a, b) != build(b, a));
+    let mut later = build(a, b);
+    later.path[0] = later.path[0].clone().with_ts(2);
+    assert!(later != build(a, b));
+    assert!(later.path[0] != build(a, b).path[0]);
+}
+
+#[test]
+fn path_summaries_show_named_and_anonymous_hops() {
+    let mut message = message(addr("dest"), b"x");
+    message.path = vec![
+        PathNode::new()
+            .with_name("origin")
+            .with_address(addr("a"))
+            .with_ts(1),
+        PathNode::new(),
+        PathNode::new().with_address(addr("b")).with_ts(2),
+        PathNode::new()
+            .with_name("edge")
+            .with_ts(3)
+            .with_remaining_budget_ms(40),
+    ];
+    assert_eq!(
+        message.path_summary(),
+        format!(
+            "origin@{}+1 -> ? -> {}+2 -> edge@?+3~40ms",
+            addr("a"),
+            addr("b")
+        )
+    );
+    assert_eq!(addr("a").to_string(), "test:61");
+}
+
+#[test]
+fn only_own_addresses_are_local() {
+    let node = NodeInstance::new()
+        .with_address(addr("me"))
+        .with_address(addr("alias"));
+    assert!(node.is_local(&addr("me")));
+    assert!(node.is_local(&addr("alias")));
+    assert!(!node.is_local(&addr("other")));
+    // the protocol is part of the address
+    assert!(!node.is_local(&crate::Address::new(SLOW, addr("me").identity)));
+    let mut local: Vec<_> = node.local_addresses().cloned().collect();
+    local.sort_by(|a, b| a.identity.as_bytes().cmp(b.identity.as_bytes()));
+    assert_eq!(local, [addr("alias"), addr("me")]);
+}
+
+#[test]
+fn send_errors_tell_whether_a_retry_may_help() {
+    use std::io::{Error as IoError, ErrorKind};
+
+    use crate::{
+        ExecutorCoolingDown, GroupReport, SchemaError, SendFailure, StreamError, TransformError,
+    };
+
+    let failure =
+        |source: crate::BoxError| SendError::ExecutorError(SendFailure::new(&addr("b"), 1, source));
+    let overloaded = || SendError::Overloaded {
+        destination: addr("b"),
+        queue_age: Duration::from_secs(1),
+    };
+    let transient = [
+        failure(Box::new(IoError::from(ErrorKind::ConnectionRefused))),
+        failure(Box::new(SendFailure::new(
+            &addr("b"),
+            1,
+            Box::new(IoError::from(ErrorKind::TimedOut)),
+        ))),
+        failure(Box::new(ExecutorCoolingDown {
+            protocol: TEST,
+            retry_in: Duration::from_secs(1),
+        })),
+        SendError::DeadlineExceeded,
+        SendError::RateLimited,
+        SendError::QuotaExceeded,
+        overloaded(),
+        SendError::ShedByAge {
+            destination: addr("b"),
+            queue_age: Duration::from_secs(1),
+        },
+        SendError::Stream(StreamError::Stalled),
+        SendError::Stream(StreamError::Io(ErrorKind::ConnectionReset.into())),
+        SendError::PlannedHopUnreachable {
+            hop: addr("b"),
+            error: Box::new(overloaded()),
+        },
+    ];
+    for error in &transient {
+        assert!(error.is_transient(), "{error:?} should be transient");
+    }
+
+    let permanent = [
+        failure(Box::new(crate::testing::TestError("refused"))),
+        failure(Box::new(IoError::from(ErrorKind::InvalidData))),
+        SendError::ProtocolNotSupport { supported: vec![] },
+        SendError::Loop,
+        SendError::TtlExceeded,
+        SendError::PathTooLong { max: 4 },
+        SendError::NoRoute,
+        SendError::Onion(Box::new(crate::testing::TestError("bad layer"))),
+        SendError::Stream(StreamError::Aborted),
+        SendError::Stream(StreamError::Io(ErrorKind::InvalidData.into())),
+        SendError::Group(GroupReport {
+            delivered: vec![],
+            failed: vec![(addr("b"), SendError::RateLimited)],
+        }),
+        SendError::Shutdown,
+        SendError::Transform(TransformError::Unsupported("gzip".into())),
+        SendError::BudgetExhausted,
+        SendError::MalformedFragment,
+        SendError::InvalidRoutePlan,
+        SendError::PlannedHopUnreachable {
+            hop: addr("b"),
+            error: Box::new(SendError::NoRoute),
+        },
+        SendError::UnknownName("edge".into()),
+        SendError::ExecutorPanicked {
+            message: "boom".into(),
+        },
+        SendError::ProtocolUnavailable(TEST),
+        SendError::StatusUnsupported(TEST),
+        SendError::ExecutorNotFound {
+            protocol: TEST,
+            name: "primary".into(),
+        },
+        SendError::SchemaViolation(SchemaError::Invalid("missing field".into())),
+    ];
+    for error in &permanent {
+        assert!(!error.is_transient(), "{error:?} should not be transient");
+    }
+}
+
+#[tokio::test]
+async fn status_queries_fail_on_executors_without_status() {
+    let node = NodeInstance::new().with_executor(TEST, Recorder::new());
+    let result = node.status(message(addr("b"), b"x"), &addr("b")).await;
+    assert!(matches!(result, Err(SendError::StatusUnsupported(protocol)) if protocol == TEST));
+
+    let node = NodeInstance::new()
+        .with_executor(TEST, Recorder::new().with_status(MessageStatus::Received));
+    let result = node.status(message(addr("b"), b"x"), &addr("b")).await;
+    assert_eq!(result.unwrap(), MessageStatus::Received);
+}
+
+#[test]
+fn setting_a_tag_replaces_every_tag_of_that_name() {
+    let mut message = message(addr("b"), b"x");
+    message.metadata = [("a", "1"), ("b", "2"), ("a", "3")]
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .into();
+    message.set_tag("a", "4");
+    message.set_tag("c", "5");
+    let tags: Vec<_> = message
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(tags, [("a", "4"), ("b", "2"), ("c", "5")]);
+}
+
+#[test]
+fn merged_tags_resolve_conflicts_by_the_last_value() {
+    let tags = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let mut message = message(addr("b"), b"x");
+    message.metadata = tags(&[("region", "eu"), ("tier", "gold")]);
+    message.merge_metadata(tags(&[
+        ("tier", "silver"),
+        ("trace", "t1"),
+        ("tier", "bronze"),
+    ]));
+    assert_eq!(
+        message.metadata,
+        tags(&[("region", "eu"), ("tier", "bronze"), ("trace", "t1")])
+    );
+    assert_eq!(message.tag("tier"), Some("bronze"));
+}
+
+#[test]
+fn debug_output_shows_the_setup_but_not_the_trait_objects() {
+    let node = NodeInstance::new()
+        .with_name("edge-7")
+        .with_address(addr("me"))
+        .with_executor(TEST, Recorder::new())
+        .with_executor(SLOW, Recorder::new())
+        .with_backend(MemoryBackend::new());
+    let shown = format!("{node:?}");
+    assert!(shown.contains(r#"name: Some("edge-7")"#), "{shown}");
+    assert!(shown.contains("protocols: 2"), "{shown}");
+    assert!(
+        shown.contains("<executor>") && shown.contains("<backend>"),
+        "{shown}"
+    );
+    assert!(shown.contains("handler: None"), "{shown}");
+
+    let sender = node.handle().sender_to(addr("b"));
+    let shown = format!("{sender:?}");
+    assert!(shown.contains("edge-7"), "{shown}");
+}
+
+#[test]
+fn path_nodes_at_other_times_are_the_same_node() {
+    let earlier = PathNode::new().with_address(addr("me")).with_ts(1);
+    let later = PathNode::new().with_address(addr("me")).with_ts(2);
+    assert!(earlier.same_node(&later));
+    assert!(earlier != later);
+    assert!(!earlier.same_node(&later.clone().with_name("edge")));
+    assert!(!earlier.same_node(&PathNode::new().with_address(addr("other"))));
+    // anonymous nodes cannot be told apart
+    assert!(!PathNode::new().same_node(&PathNode::new()));
+}
+
+#[tokio::test]
+async fn loops_are_found_whatever_the_timestamps() {
+    let recorder = Recorder::new();
+    let node = NodeInstance::new()
+        .with_address(addr("me"))
+        .with_executor(TEST, recorder.clone());
+    let mut looped = message(addr("far"), b"x");
+    looped.path = vec![
+        PathNode::new().with_address(addr("me")).with_ts(5),
+        PathNode::new().with_address(addr("b")).with_ts(9),
+    ];
+    let result = node.relay(looped, addr("me")).await;
+    assert!(matches!(result, Err(SendError::Loop)));
+    assert!(recorder.sent().is_empty());
+}
+
+#[tokio::test]
+async fn send_accepts_a_protocol_identity_pair() {
+    let recorder = Recorder::new();
+    let node = NodeInstance::new().with_executor(TEST, recorder.clone());
+    let to = (TEST, crate::Identity::new("b"));
+    node.send(message(addr("b"), b"x"), to.clone())
+        .await
+        .unwrap();
+    let deadline = crate::Deadline::after(Duration::from_secs(1));
+    node.send_with_deadline(message(addr("b"), b"x"), to.clone(), deadline)
+        .await
+        .unwrap();
+    node.send_batch(vec![message(addr("b"), b"x")], to.clone())
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()
+        .unwrap();
+
+    let handle = node.handle();
+    handle.sender().send_to(to.clone(), "x").await.unwrap();
+    handle.sender_to(to).send("x").await.unwrap();
+    assert_eq!(recorder.remotes(), vec![crate::Identity::new("b"); 5]);
+}
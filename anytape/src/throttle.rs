@@ -0,0 +1,359 @@
+//! Telling senders to back off, and backing off when told.
+//!
+//! With [throttle feedback](NodeInstance::with_throttle_feedback), a node under
+//! inbound load answers the origin of an offending message with a
+//! [`ControlMessage::Throttle`] for [`ThrottleConfig::retry_after`]. It does so
+//! when
+//!
+//! - the message exceeds its origin's [quota](crate::QuotaManager);
+//! - the [handler](crate::HandlerPolicy) is saturated: every slot of a spawned
+//!   handler is busy, or [`ThrottleConfig::handler_high_water`] messages are
+//!   queued for the message's key;
+//! - relaying the message is [shed](NodeInstance::with_load_shedding).
+//!
+//! Each source gets at most one throttle per [`ThrottleConfig::min_interval`].
+//!
+//! Every node honors the throttles it receives. Sends whose next hop or
+//! destination is throttled wait until the throttle ends, or with a rate are
+//! spaced to that rate. They wait in the [send queue](crate::LoadShedConfig), so
+//! shedding counts them. [High-priority](crate::Priority::High) and
+//! [control](crate::control) messages are never held back. Unless the
+//! [authorizer](NodeInstance::with_throttle_authorizer) accepts a throttle, it
+//! only ever holds back traffic to its sender: one naming another destination
+//! is ignored. No throttle lasts longer than [`MAX_THROTTLE`].
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    control::{ControlMessage, ThrottleScope, CONTROL_HEADER},
+    shed, Address, Message, NodeInstance,
+};
+
+/// The longest a received throttle holds back traffic, whatever it asks for.
+pub const MAX_THROTTLE: Duration = Duration::from_secs(60);
+
+/// Sources remembered before those throttled long ago are forgotten.
+const MAX_SOURCES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThrottleConfig {
+    /// How long sources are asked to hold back.
+    pub retry_after: Duration,
+    /// The rate sources are asked to slow to instead of pausing, in messages a
+    /// second.
+    pub rate: Option<u32>,
+    /// Throttle each source at most once this often.
+    pub min_interval: Duration,
+    /// Messages queued for one key of a
+    /// [serialized handler](crate::HandlerPolicy::SerializedByKey) before it
+    /// counts as saturated.
+    pub handler_high_water: usize,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            retry_after: Duration::from_secs(1),
+            rate: None,
+            min_interval: Duration::from_secs(1),
+            handler_high_water: 64,
+        }
+    }
+}
+
+type ThrottleAuthorizer = dyn Fn(&Message) -> bool + Send + Sync;
+
+/// A throttle this node honors.
+struct Hold {
+    until: Instant,
+    rate: Option<u32>,
+    /// When the next send may go at `rate`.
+    next_slot: Instant,
+}
+
+impl Hold {
+    /// When a send starting at `now` may go.
+    fn reserve(&mut self, now: Instant) -> Instant {
+        match self.rate {
+            Some(rate) => {
+                let slot = self.next_slot.max(now);
+                self.next_slot = slot + Duration::from_secs(1) / rate;
+                slot.min(self.until)
+            }
+            None => self.until,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Throttles {
+    config: Option<ThrottleConfig>,
+    authorizer: Option<Box<ThrottleAuthorizer>>,
+    /// When each source was last throttled.
+    throttled: Mutex<HashMap<Address, Instant>>,
+    /// Throttles honored, by destination; `None` for all traffic.
+    holds: Mutex<HashMap<Option<Address>, Hold>>,
+    sent: AtomicU64,
+    honored: AtomicU64,
+    ignored: AtomicU64,
+}
+
+impl NodeInstance {
+    /// Throttle the sources of inbound traffic this node cannot keep up with;
+    /// see the [module docs](self).
+    pub fn with_throttle_feedback(mut self, config: ThrottleConfig) -> Self {
+        self.throttles.config = Some(config);
+        self
+    }
+    /// Fully honor the throttles `authorize` accepts, typically after checking
+    /// their signature: those for [all traffic](ThrottleScope::All) and for
+    /// destinations other than their sender.
+    pub fn with_throttle_authorizer(
+        mut self,
+        authorize: impl Fn(&Message) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.throttles.authorizer = Some(Box::new(authorize));
+        self
+    }
+    /// The destinations sends to are held back for, and for how much longer.
+    pub fn throttled_destinations(&self) -> Vec<(Address, Duration)> {
+        let now = Instant::now();
+        let holds = self.throttles.holds.lock().unwrap();
+        holds
+            .iter()
+            .filter(|(_, hold)| hold.until > now)
+            .filter_map(|(to, hold)| Some((to.clone()?, hold.until - now)))
+            .collect()
+    }
+    /// How much longer all sends are held back for, if they are.
+    pub fn throttled_globally(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let holds = self.throttles.holds.lock().unwrap();
+        holds
+            .get(&None)
+            .filter(|hold| hold.until > now)
+            .map(|hold| hold.until - now)
+    }
+    /// Throttles sent, honored and ignored.
+    pub(crate) fn throttle_counts(&self) -> (u64, u64, u64) {
+        let throttles = &self.throttles;
+        (
+            throttles.sent.load(Ordering::Relaxed),
+            throttles.honored.load(Ordering::Relaxed),
+            throttles.ignored.load(Ordering::Relaxed),
+        )
+    }
+    /// The source to throttle for `message`, if throttle feedback is on. Control
+    /// messages are never answered with a throttle.
+    pub(crate) fn throttle_target(&self, message: &Message) -> Option<Address> {
+        self.throttles.config?;
+        if message.headers.contains_key(CONTROL_HEADER) {
+            return None;
+        }
+        message
+            .path
+            .first()
+            .and_then(|node| node.address.clone())
+            .filter(|source| !self.is_local(source))
+    }
+    /// Ask `source` to hold back, unless it was asked recently.
+    pub(crate) async fn throttle_source(&self, source: Address) {
+        let Some(config) = self.throttles.config else {
+            return;
+        };
+        {
+            let now = Instant::now();
+            let mut throttled = self.throttles.throttled.lock().unwrap();
+            if throttled
+                .get(&source)
+                .is_some_and(|at| now - *at < config.min_interval)
+            {
+                return;
+            }
+            if throttled.len() >= MAX_SOURCES {
+                throttled.retain(|_, at| now - *at < config.min_interval);
+            }
+            throttled.insert(source.clone(), now);
+        }
+        let mut throttle = ControlMessage::Throttle {
+            scope: ThrottleScope::Sender,
+            retry_after: config.retry_after,
+            rate: config.rate,
+        }
+        .into_message(source);
+        // the source goes by our address in the path
+        if let Some(at) = self.source_address(&throttle.destination) {
+            self.mark(at, &mut throttle);
+        }
+        if self.forward(throttle).await.is_ok() {
+            self.throttles.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// Throttle the source of `message` if the handler is too busy to take it.
+    pub(crate) async fn throttle_if_saturated(&self, message: &Message) {
+        let Some(config) = self.throttles.config else {
+            return;
+        };
+        if !self
+            .handler_runner
+            .is_saturated(message, config.handler_high_water)
+        {
+            return;
+        }
+        if let Some(source) = self.throttle_target(message) {
+            self.throttle_source(source).await;
+        }
+    }
+    /// Honor a throttle received in `envelope`, as far as its sender may ask for.
+    pub(crate) fn apply_throttle(
+        &self,
+        scope: &ThrottleScope,
+        retry_after: Duration,
+        rate: Option<u32>,
+        envelope: &Message,
+    ) {
+        let source = envelope.path.first().and_then(|node| node.address.as_ref());
+        let authorized = self
+            .throttles
+            .authorizer
+            .as_ref()
+            .is_some_and(|authorize| authorize(envelope));
+        let target = match scope {
+            ThrottleScope::Destination(destination)
+                if authorized || Some(destination) == source =>
+            {
+                Some(Some(destination.clone()))
+            }
+            ThrottleScope::Destination(_) => None,
+            ThrottleScope::All if authorized => Some(None),
+            ThrottleScope::Sender | ThrottleScope::All => source.cloned().map(Some),
+        };
+        let Some(target) = target else {
+            self.throttles.ignored.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let now = Instant::now();
+        let hold = Hold {
+            until: now + retry_after.min(MAX_THROTTLE),
+            rate: rate.filter(|rate| *rate > 0),
+            next_slot: now,
+        };
+        self.throttles.holds.lock().unwrap().insert(target, hold);
+        self.throttles.honored.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Wait for the throttles on sending `message` to `to` to let it go.
+    pub(crate) async fn hold_for_throttles(&self, message: &Message, to: &Address) {
+        if !shed::is_sheddable(message) {
+            return;
+        }
+        let go_at = {
+            let mut holds = self.throttles.holds.lock().unwrap();
+            if holds.is_empty() {
+                return;
+            }
+            let now = Instant::now();
+            holds.retain(|_, hold| hold.until > now);
+            let mut keys = vec![Some(to.clone()), None];
+            if message.destination != *to {
+                keys.push(Some(message.destination.clone()));
+            }
+            keys.iter()
+                .filter_map(|key| Some(holds.get_mut(key)?.reserve(now)))
+                .max()
+        };
+        if let Some(go_at) = go_at {
+            tokio::time::sleep_until(go_at).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::{
+        testing::{addr, Loopback, TEST},
+        HandlerPolicy, ProtocolExecutor,
+    };
+
+    /// A receiver at `b` whose handler is stuck until `unblock` gets permits,
+    /// throttling at the first queued message, and a sender at `a`.
+    fn saturated(loopback: &Loopback, unblock: &Arc<Semaphore>) -> crate::NodeHandle {
+        let receiver = NodeInstance::new()
+            .with_address(addr("b"))
+            .with_executor(TEST, loopback.clone())
+            .with_throttle_feedback(ThrottleConfig {
+                retry_after: Duration::from_secs(1),
+                handler_high_water: 1,
+                ..ThrottleConfig::default()
+            })
+            .with_handler({
+                let unblock = unblock.clone();
+                move |_| {
+                    let unblock = unblock.clone();
+                    async move { unblock.acquire().await.unwrap().forget() }
+                }
+            })
+            .with_handler_policy(HandlerPolicy::SerializedByKey(|_| 0));
+        loopback.attach("b", Arc::new(receiver));
+        let sender = NodeInstance::new()
+            .with_address(addr("a"))
+            .with_executor(TEST, loopback.clone())
+            .handle();
+        loopback.attach("a", sender.arc().clone());
+        sender
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn saturated_receivers_pause_their_senders() {
+        let (loopback, unblock) = (Loopback::new(), Arc::new(Semaphore::new(0)));
+        let node = saturated(&loopback, &unblock);
+        let sender = node.sender_to(addr("b"));
+        for payload in [b"running", b"queued1", b"queued2"] {
+            sender.send(payload.to_vec()).await.unwrap();
+        }
+        let throttled = node.node().throttled_destinations();
+        assert_eq!(throttled, [(addr("b"), Duration::from_secs(1))]);
+        assert_eq!(node.node().throttle_counts(), (0, 1, 0));
+
+        unblock.add_permits(3);
+        let start = Instant::now();
+        sender.send(b"held".to_vec()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        let start = Instant::now();
+        sender.send(b"resumed".to_vec()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(node.node().throttled_destinations().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_for_other_destinations_are_ignored() {
+        let loopback = Loopback::new();
+        let node = saturated(&loopback, &Arc::new(Semaphore::new(0)));
+        let mut forged = ControlMessage::Throttle {
+            scope: ThrottleScope::Destination(addr("c")),
+            retry_after: Duration::from_secs(30),
+            rate: None,
+        }
+        .into_message(addr("a"));
+        NodeInstance::new().mark(addr("b"), &mut forged);
+        loopback.send(&addr("a").identity, forged).await.unwrap();
+
+        assert_eq!(node.node().throttle_counts(), (0, 0, 1));
+        assert!(node.node().throttled_destinations().is_empty());
+        assert_eq!(node.node().throttled_globally(), None);
+    }
+}
@@ -0,0 +1,281 @@
+//! Adapters between anytape and [tower](https://docs.rs/tower) services.
+//!
+//! [`AnytapeService`] exposes a node's sends as a `Service<(Address, Message)>`,
+//! or `Service<(Message, Address)>` in the order of
+//! [`NodeInstance::send`](crate::NodeInstance::send), so tower layers such as retries or concurrency limits can wrap them, and
+//! [`ServiceExecutor`] turns a `Service<Message>`, say a hyper or tonic client,
+//! into a [`ProtocolExecutor`].
+
+use std::{
+    error::Error,
+    fmt,
+    future::{poll_fn, Future},
+    task::{Context, Poll},
+};
+
+use tower_service::Service;
+
+use crate::{
+    Address, BoxFuture, Identity, Message, MessageStatus, NodeHandle, ProtocolExecutor, SendError,
+    SendOutcome, SendPermit,
+};
+
+/// A [`Service`] sending each `(next hop, message)` request with
+/// [`NodeInstance::send_reserved`](crate::NodeInstance::send_reserved).
+///
+/// `poll_ready` waits for a [`SendPermit`] from
+/// [`NodeInstance::ready`](crate::NodeInstance::ready), so the node's
+/// [in-flight limit](crate::NodeInstance::with_max_in_flight) shows up as
+/// backpressure. As usual for tower services, clones start out not ready.
+pub struct AnytapeService {
+    node: NodeHandle,
+    pending: Option<BoxFuture<Result<SendPermit, SendError>>>,
+    permit: Option<SendPermit>,
+}
+
+impl Clone for AnytapeService {
+    fn clone(&self) -> Self {
+        Self::new(self.node.clone())
+    }
+}
+
+impl AnytapeService {
+    pub fn new(node: NodeHandle) -> Self {
+        Self {
+            node,
+            pending: None,
+            permit: None,
+        }
+    }
+    pub fn node(&self) -> &NodeHandle {
+        &self.node
+    }
+}
+
+impl Service<(Address, Message)> for AnytapeService {
+    type Response = ();
+    type Error = SendError;
+    type Future = BoxFuture<Result<(), SendError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        let pending = self.pending.get_or_insert_with(|| {
+            let node = self.node.clone();
+            Box::pin(async move { node.node().ready().await })
+        });
+        let result = std::task::ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        Poll::Ready(result.map(|permit| self.permit = Some(permit)))
+    }
+
+    /// # Panics
+    ///
+    /// If called without a successful `poll_ready` first.
+    fn call(&mut self, (to, message): (Address, Message)) -> Self::Future {
+        let permit = self
+            .permit
+            .take()
+            .expect("AnytapeService::call without poll_ready");
+        let node = self.node.clone();
+        Box::pin(async move { node.node().send_reserved(permit, message, to).await })
+    }
+}
+
+/// The same service, taking `(message, next hop)` like
+/// [`NodeInstance::send`](crate::NodeInstance::send).
+impl Service<(Message, Address)> for AnytapeService {
+    type Response = ();
+    type Error = SendError;
+    type Future = BoxFuture<Result<(), SendError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<(Address, Message)>::poll_ready(self, cx)
+    }
+
+    /// # Panics
+    ///
+    /// If called without a successful `poll_ready` first.
+    fn call(&mut self, (message, to): (Message, Address)) -> Self::Future {
+        self.call((to, message))
+    }
+}
+
+type ServiceBoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    /// The service failed while getting ready.
+    NotReady(ServiceBoxError),
+    /// The call itself failed.
+    Call(ServiceBoxError),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::NotReady(e) => write!(f, "service not ready: {e}"),
+            ServiceError::Call(e) => write!(f, "service call failed: {e}"),
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServiceError::NotReady(e) | ServiceError::Call(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// A [`ProtocolExecutor`] handing each message to a clone of a tower service.
+///
+/// The service only sees the message, so one executor usually stands for one
+/// remote endpoint. It keeps no record of past sends: `get_status` always says
+/// [`MessageStatus::Unreachable`].
+#[derive(Clone)]
+pub struct ServiceExecutor<S> {
+    service: S,
+}
+
+impl<S> ServiceExecutor<S> {
+    pub fn new(service: S) -> Self {
+        Self { service }
+    }
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+}
+
+impl<S> ProtocolExecutor for ServiceExecutor<S>
+where
+    S: Service<Message, Response = ()> + Clone + Send + 'static,
+    S::Error: Into<ServiceBoxError>,
+    S::Future: Send,
+{
+    type Error = ServiceError;
+
+    fn send(
+        &self,
+        _remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let mut service = self.service.clone();
+        async move {
+            poll_fn(|cx| service.poll_ready(cx))
+                .await
+                .map_err(|e| ServiceError::NotReady(e.into()))?;
+            service
+                .call(message)
+                .await
+                .map(|()| SendOutcome::sent())
+                .map_err(|e| ServiceError::Call(e.into()))
+        }
+    }
+
+    fn get_status(
+        &self,
+        _remote: &Identity,
+        _message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        std::future::ready(Ok(MessageStatus::Unreachable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use ::tower::{limit::ConcurrencyLimit, service_fn, ServiceExt};
+
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TestError, TEST};
+    use crate::NodeInstance;
+
+    type Request = (Address, Message);
+
+    #[tokio::test]
+    async fn oneshot_sends_a_message_to_its_next_hop() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .handle();
+        let service = AnytapeService::new(node.clone());
+        service
+            .oneshot((message(addr("d"), b"x"), addr("b")))
+            .await
+            .unwrap();
+        assert_eq!(recorder.remotes(), [addr("b").identity]);
+        assert_eq!(recorder.sent()[0].destination, addr("d"));
+
+        let unsupported = Address::new(crate::Protocol::new("other"), addr("b").identity);
+        let result = AnytapeService::new(node)
+            .oneshot((message(unsupported.clone(), b"x"), unsupported))
+            .await;
+        assert!(matches!(result, Err(SendError::ProtocolNotSupport { .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrency_limits_hold_back_sends_beyond_the_limit() {
+        let recorder = Recorder::new().with_delay(Duration::from_secs(1));
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .handle();
+        let mut service = ConcurrencyLimit::new(AnytapeService::new(node), 2);
+        let mut calls = Vec::new();
+        for _ in 0..2 {
+            let ready = ServiceExt::<Request>::ready(&mut service).await.unwrap();
+            calls.push(ready.call((addr("b"), message(addr("b"), b"x"))));
+        }
+        let third = tokio::time::timeout(
+            Duration::from_secs(5),
+            ServiceExt::<Request>::ready(&mut service),
+        )
+        .await;
+        assert!(
+            third.is_err(),
+            "a third send got ready within the limit of two"
+        );
+
+        calls.remove(0).await.unwrap();
+        let ready = ServiceExt::<Request>::ready(&mut service).await.unwrap();
+        calls.push(ready.call((addr("b"), message(addr("b"), b"x"))));
+        for call in calls {
+            call.await.unwrap();
+        }
+        assert_eq!(recorder.sent().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn nodes_send_through_a_service_executor() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let service = service_fn({
+            let seen = seen.clone();
+            move |message: Message| {
+                let seen = seen.clone();
+                async move {
+                    if message.payload == b"refuse" {
+                        return Err(TestError("refused"));
+                    }
+                    seen.lock().unwrap().push(message.payload);
+                    Ok(())
+                }
+            }
+        });
+        let node = NodeInstance::new().with_executor(TEST, ServiceExecutor::new(service));
+        node.send(message(addr("b"), b"hello"), addr("b"))
+            .await
+            .unwrap();
+        assert_eq!(*seen.lock().unwrap(), [b"hello".to_vec()]);
+
+        let refused = node.send(message(addr("b"), b"refuse"), addr("b")).await;
+        let Err(SendError::ExecutorError(failure)) = refused else {
+            panic!("expected the call to fail");
+        };
+        assert_eq!(failure.source.to_string(), "service call failed: refused");
+    }
+}
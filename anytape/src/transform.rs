@@ -0,0 +1,263 @@
+//! Per next hop rewriting of forwarded messages.
+//!
+//! A [`ForwardTransform`] runs in [`NodeInstance::forward`] once the next hop is
+//! known and before the message is handed to the executor, so a relay can adapt
+//! payloads to what a particular downstream peer accepts. Transforms are
+//! [scoped](TransformScope) to next hops and never see control, group control,
+//! stream or onion messages.
+
+use std::{fmt, future::Future, pin::Pin};
+
+use crate::{
+    control::CONTROL_HEADER, Address, BoxError, Message, NodeInstance, Protocol,
+    GROUP_CONTROL_HEADER, ONION_HEADER, SEALED_DESTINATION_HEADER, STREAM_HEADER,
+};
+
+#[derive(Debug)]
+pub enum TransformError {
+    /// The payload is not in a form the transform can handle.
+    Unsupported(String),
+    Failed(BoxError),
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformError::Unsupported(reason) => write!(f, "unsupported payload: {reason}"),
+            TransformError::Failed(e) => write!(f, "transform failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransformError::Failed(e) => Some(&**e),
+            TransformError::Unsupported(_) => None,
+        }
+    }
+}
+
+pub type TransformFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), TransformError>> + Send + 'a>>;
+
+/// Rewrites a message for the hop it is about to be sent to. A transform that
+/// changes the payload encoding is responsible for updating its content type
+/// header.
+pub trait ForwardTransform: Send + Sync {
+    fn transform<'a>(
+        &'a self,
+        message: &'a mut Message,
+        next_hop: &'a Address,
+    ) -> TransformFuture<'a>;
+}
+
+/// Which next hops a transform applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransformScope {
+    Protocol(Protocol),
+    /// Next hops of `protocol` whose identity starts with `prefix`.
+    IdentityPrefix {
+        protocol: Protocol,
+        prefix: Vec<u8>,
+    },
+}
+
+impl TransformScope {
+    pub fn matches(&self, next_hop: &Address) -> bool {
+        match self {
+            TransformScope::Protocol(protocol) => &next_hop.protocol == protocol,
+            TransformScope::IdentityPrefix { protocol, prefix } => {
+                &next_hop.protocol == protocol && next_hop.identity.as_bytes().starts_with(prefix)
+            }
+        }
+    }
+}
+
+fn is_transformable(message: &Message) -> bool {
+    ![
+        CONTROL_HEADER,
+        GROUP_CONTROL_HEADER,
+        ONION_HEADER,
+        SEALED_DESTINATION_HEADER,
+        STREAM_HEADER,
+    ]
+    .iter()
+    .any(|header| message.headers.contains_key(*header))
+}
+
+impl NodeInstance {
+    /// Run `transform` on messages forwarded to next hops in `scope`. Transforms
+    /// run in the order they were added; a failing one fails the forward with
+    /// [`SendError::Transform`](crate::SendError::Transform).
+    pub fn with_forward_transform(
+        mut self,
+        scope: TransformScope,
+        transform: impl ForwardTransform + 'static,
+    ) -> Self {
+        self.transforms.push((scope, Box::new(transform)));
+        self
+    }
+    pub(crate) async fn transform_for(
+        &self,
+        message: &mut Message,
+        next_hop: &Address,
+    ) -> Result<(), TransformError> {
+        if !is_transformable(message) {
+            return Ok(());
+        }
+        for (scope, transform) in &self.transforms {
+            if scope.matches(next_hop) {
+                transform.transform(message, next_hop).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+pub use transcode::Transcode;
+
+#[cfg(feature = "serde")]
+mod transcode {
+    use super::{ForwardTransform, TransformError, TransformFuture};
+    use crate::{Address, Message, PayloadFormat, CONTENT_TYPE_HEADER};
+
+    /// Re-encodes typed payloads into another [`PayloadFormat`], e.g. JSON into
+    /// the more compact CBOR for constrained peers.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Transcode {
+        to: PayloadFormat,
+    }
+
+    impl Transcode {
+        pub fn new(to: PayloadFormat) -> Self {
+            Self { to }
+        }
+    }
+
+    impl ForwardTransform for Transcode {
+        fn transform<'a>(
+            &'a self,
+            message: &'a mut Message,
+            _: &'a Address,
+        ) -> TransformFuture<'a> {
+            Box::pin(async move {
+                let from = message
+                    .payload_format()
+                    .map_err(|e| TransformError::Unsupported(e.to_string()))?;
+                if from == self.to {
+                    return Ok(());
+                }
+                let value: serde_json::Value = message
+                    .payload_as()
+                    .map_err(|e| TransformError::Failed(Box::new(e)))?;
+                message.payload = self
+                    .to
+                    .serialize(&value)
+                    .map_err(|e| TransformError::Failed(Box::new(e)))?;
+                message.headers.insert(
+                    CONTENT_TYPE_HEADER.to_owned(),
+                    self.to.content_type().as_bytes().to_vec(),
+                );
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TEST};
+    use crate::{MessageBuilder, SendError};
+
+    /// Appends its bytes to the payload, or fails if it has none.
+    struct Append(&'static [u8]);
+
+    impl ForwardTransform for Append {
+        fn transform<'a>(
+            &'a self,
+            message: &'a mut Message,
+            _: &'a Address,
+        ) -> TransformFuture<'a> {
+            Box::pin(async move {
+                if self.0.is_empty() {
+                    return Err(TransformError::Unsupported("nothing to append".to_owned()));
+                }
+                message.payload.extend_from_slice(self.0);
+                Ok(())
+            })
+        }
+    }
+
+    fn edges() -> TransformScope {
+        TransformScope::IdentityPrefix {
+            protocol: TEST,
+            prefix: b"edge-".to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn transforms_touch_only_next_hops_in_their_scope() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_forward_transform(edges(), Append(b"!"))
+            .with_forward_transform(TransformScope::Protocol(TEST), Append(b"?"));
+        for to in ["edge-1", "core"] {
+            node.forward(message(addr(to), b"x")).await.unwrap();
+        }
+        let control = MessageBuilder::new(addr("edge-2"))
+            .payload("x")
+            .header(CONTROL_HEADER, "")
+            .build();
+        node.forward(control).await.unwrap();
+        let payloads: Vec<_> = recorder.sent().into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, [&b"x!?"[..], b"x?", b"x"]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_transform_fails_the_forward() {
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_forward_transform(edges(), Append(b""));
+        let forwarded = node.forward(message(addr("edge-1"), b"x")).await;
+        assert!(matches!(
+            forwarded,
+            Err(SendError::Transform(TransformError::Unsupported(_)))
+        ));
+        assert!(recorder.sent().is_empty());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn json_leaves_constrained_hops_as_cbor() {
+        use crate::{PayloadFormat, CONTENT_TYPE_HEADER};
+
+        let recorder = Recorder::new();
+        let node = NodeInstance::new()
+            .with_executor(TEST, recorder.clone())
+            .with_forward_transform(edges(), Transcode::new(PayloadFormat::Cbor));
+        let value = serde_json::json!({ "reading": 21.5, "unit": "C" });
+        for to in ["edge-1", "core"] {
+            let message = MessageBuilder::new(addr(to))
+                .payload_of(&value)
+                .unwrap()
+                .build();
+            node.forward(message).await.unwrap();
+        }
+        let sent = recorder.sent();
+        let content_type = |m: &Message| m.headers.get(CONTENT_TYPE_HEADER).cloned();
+        assert_eq!(content_type(&sent[0]), Some(b"cbor".to_vec()));
+        assert_eq!(
+            PayloadFormat::Cbor
+                .deserialize::<serde_json::Value>(&sent[0].payload)
+                .unwrap(),
+            value
+        );
+        assert_eq!(content_type(&sent[1]), Some(b"json".to_vec()));
+        assert_eq!(sent[1].payload, serde_json::to_vec(&value).unwrap());
+    }
+}
@@ -0,0 +1,37 @@
+//! Pieces shared by the network transports.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::MessageStatus;
+
+pub(crate) const DEFAULT_STATUS_CAPACITY: usize = 4096;
+
+/// Bounded record of the final status of recently sent messages, keyed by `unique_id`.
+pub(crate) struct StatusTable {
+    statuses: HashMap<u64, MessageStatus>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl StatusTable {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            statuses: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+    pub(crate) fn set(&mut self, unique_id: u64, status: MessageStatus) {
+        if self.statuses.insert(unique_id, status).is_none() {
+            self.order.push_back(unique_id);
+            while self.order.len() > self.capacity {
+                if let Some(evicted) = self.order.pop_front() {
+                    self.statuses.remove(&evicted);
+                }
+            }
+        }
+    }
+    pub(crate) fn get(&self, unique_id: u64) -> Option<MessageStatus> {
+        self.statuses.get(&unique_id).copied()
+    }
+}
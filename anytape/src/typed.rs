@@ -0,0 +1,169 @@
+//! Type-erased executors that fail with a caller-chosen error type.
+//!
+//! [`DynProtocolExecutor`] boxes every error into a `Box<dyn Error>`. Code with
+//! its own error enum can use [`TypedDynExecutor<E>`] instead: implemented for
+//! every [`ProtocolExecutor`] whose error converts into `E`, so concrete errors
+//! reach `E` without boxing, and for [`TypedExecutor`], which adapts an existing
+//! `DynProtocolExecutor` through `E: From<BoxError>`.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{
+    BoxError, BoxFuture, DynProtocolExecutor, Identity, Message, MessageStatus, Protocol,
+    ProtocolExecutor, SendOutcome,
+};
+
+pub trait TypedDynExecutor<E>: Send + Sync {
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<Result<SendOutcome, E>>;
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<SendOutcome, E>>;
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<MessageStatus, E>>;
+}
+
+impl<T, E> TypedDynExecutor<E> for T
+where
+    T: ProtocolExecutor + Send + Sync,
+    E: From<T::Error> + 'static,
+{
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<Result<SendOutcome, E>> {
+        let fut = ProtocolExecutor::send(self, remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<SendOutcome, E>> {
+        let fut = ProtocolExecutor::send_via(self, protocol, remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<MessageStatus, E>> {
+        let fut = ProtocolExecutor::get_status(self, remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+}
+
+/// A [`DynProtocolExecutor`] whose boxed errors are converted into `E`.
+pub struct TypedExecutor<E> {
+    inner: Arc<dyn DynProtocolExecutor>,
+    error: PhantomData<fn() -> E>,
+}
+
+impl<E> TypedExecutor<E> {
+    pub fn new(inner: Arc<dyn DynProtocolExecutor>) -> Self {
+        Self {
+            inner,
+            error: PhantomData,
+        }
+    }
+    pub fn inner(&self) -> &Arc<dyn DynProtocolExecutor> {
+        &self.inner
+    }
+}
+
+impl<E> Clone for TypedExecutor<E> {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+impl<E> From<Arc<dyn DynProtocolExecutor>> for TypedExecutor<E> {
+    fn from(inner: Arc<dyn DynProtocolExecutor>) -> Self {
+        Self::new(inner)
+    }
+}
+
+impl<E> TypedDynExecutor<E> for TypedExecutor<E>
+where
+    E: From<BoxError> + 'static,
+{
+    fn send(&self, remote: &Identity, message: Message) -> BoxFuture<Result<SendOutcome, E>> {
+        let fut = self.inner.send(remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+    fn send_via(
+        &self,
+        protocol: &Protocol,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<SendOutcome, E>> {
+        let fut = self.inner.send_via(protocol, remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+    fn get_status(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> BoxFuture<Result<MessageStatus, E>> {
+        let fut = self.inner.get_status(remote, message);
+        Box::pin(async move { fut.await.map_err(E::from) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message, Recorder, TestError};
+
+    /// The error enum of an application using the crate.
+    #[derive(Debug, PartialEq)]
+    enum AppError {
+        Transport(&'static str),
+        Other(String),
+    }
+
+    impl From<TestError> for AppError {
+        fn from(error: TestError) -> Self {
+            AppError::Transport(error.0)
+        }
+    }
+
+    impl From<BoxError> for AppError {
+        fn from(error: BoxError) -> Self {
+            AppError::Other(error.to_string())
+        }
+    }
+
+    fn remote() -> Identity {
+        addr("b").identity
+    }
+
+    #[tokio::test]
+    async fn concrete_errors_reach_the_app_error_unboxed() {
+        let executors: Vec<Arc<dyn TypedDynExecutor<AppError>>> = vec![
+            Arc::new(Recorder::new()),
+            Arc::new(Recorder::new().failing("down")),
+        ];
+        assert!(executors[0]
+            .send(&remote(), message(addr("b"), b"x"))
+            .await
+            .is_ok());
+        let failed = executors[1].send(&remote(), message(addr("b"), b"x")).await;
+        assert_eq!(failed.err(), Some(AppError::Transport("down")));
+        let status = executors[1]
+            .get_status(&remote(), message(addr("b"), b"x"))
+            .await;
+        assert_eq!(status, Ok(MessageStatus::Sended));
+    }
+
+    #[tokio::test]
+    async fn erased_executors_convert_their_boxed_errors() {
+        let erased: Arc<dyn DynProtocolExecutor> = Arc::new(Recorder::new().failing("down"));
+        let typed = TypedExecutor::<AppError>::new(erased);
+        let failed = typed.send(&remote(), message(addr("b"), b"x")).await;
+        assert_eq!(failed.err(), Some(AppError::Other("down".to_owned())));
+    }
+}
@@ -0,0 +1,527 @@
+//! Parsing identities as socket addresses, URLs and filesystem paths.
+//!
+//! Each newtype here has the one canonical parse of its kind of identity and
+//! turns back into an [`Identity`] in canonical form, so executors and
+//! applications agree on what an identity means:
+//!
+//! - [`SocketIdentity`] is `host:port`, with IPv6 hosts in brackets;
+//! - [`UrlIdentity`] is `[scheme://]host[:port][/path][?query]`, with the path
+//!   percent-decoded;
+//! - [`PathIdentity`] is a non-empty UTF-8 path.
+//!
+//! [`Address::as_socket`], [`Address::as_url`] and [`Address::as_path`] also check
+//! that the protocol is one whose identities are of that kind (see
+//! [`IdentityKind::of`]), and fill in the [default port](default_port) of the
+//! protocol when the identity has none.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use crate::{Address, Identity, Protocol};
+
+pub const TCP: Protocol = Protocol::new_static(b"tcp");
+pub const UDP: Protocol = Protocol::new_static(b"udp");
+pub const QUIC: Protocol = Protocol::new_static(b"quic");
+pub const HTTP: Protocol = Protocol::new_static(b"http");
+pub const HTTPS: Protocol = Protocol::new_static(b"https");
+pub const WS: Protocol = Protocol::new_static(b"ws");
+pub const WSS: Protocol = Protocol::new_static(b"wss");
+pub const UNIX: Protocol = Protocol::new_static(b"unix");
+
+/// The port an identity of `protocol` without one refers to.
+pub fn default_port(protocol: &Protocol) -> Option<u16> {
+    match protocol.as_bytes() {
+        b"http" | b"ws" => Some(80),
+        b"https" | b"wss" | b"quic" => Some(443),
+        _ => None,
+    }
+}
+
+/// What the identities of a protocol look like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityKind {
+    Socket,
+    Url,
+    Path,
+}
+
+impl IdentityKind {
+    /// The kind of identity `protocol` uses, `None` for protocols this crate
+    /// does not know.
+    pub fn of(protocol: &Protocol) -> Option<Self> {
+        match protocol.as_bytes() {
+            b"tcp" | b"udp" | b"quic" => Some(IdentityKind::Socket),
+            b"http" | b"https" | b"ws" | b"wss" => Some(IdentityKind::Url),
+            b"unix" => Some(IdentityKind::Path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IdentityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IdentityKind::Socket => "socket",
+            IdentityKind::Url => "url",
+            IdentityKind::Path => "path",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityParseError {
+    /// The identity is not valid UTF-8.
+    NotUtf8,
+    Empty,
+    /// The host is empty or a bracketed host is not an IPv6 address.
+    InvalidHost,
+    /// There is no port and the protocol has no default.
+    MissingPort,
+    InvalidPort,
+    /// A percent escape is malformed or decodes to invalid UTF-8.
+    InvalidEscape,
+    /// The protocol uses identities of another kind.
+    KindMismatch {
+        protocol: Protocol,
+        expected: IdentityKind,
+    },
+}
+
+impl fmt::Display for IdentityParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityParseError::NotUtf8 => write!(f, "identity is not valid utf-8"),
+            IdentityParseError::Empty => write!(f, "identity is empty"),
+            IdentityParseError::InvalidHost => write!(f, "invalid host"),
+            IdentityParseError::MissingPort => write!(f, "missing port"),
+            IdentityParseError::InvalidPort => write!(f, "invalid port"),
+            IdentityParseError::InvalidEscape => write!(f, "invalid percent escape"),
+            IdentityParseError::KindMismatch { protocol, expected } => write!(
+                f,
+                "protocol {} does not use {expected} identities",
+                String::from_utf8_lossy(protocol.as_bytes())
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdentityParseError {}
+
+fn text(identity: &Identity) -> Result<&str, IdentityParseError> {
+    match std::str::from_utf8(identity.as_bytes()) {
+        Ok("") => Err(IdentityParseError::Empty),
+        Ok(text) => Ok(text),
+        Err(_) => Err(IdentityParseError::NotUtf8),
+    }
+}
+
+/// Split `host[:port]`, unbracketing IPv6 hosts. A bare IPv6 address is a host
+/// without a port.
+fn split_authority(authority: &str) -> Result<(&str, Option<u16>), IdentityParseError> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or(IdentityParseError::InvalidHost)?;
+        host.parse::<Ipv6Addr>()
+            .map_err(|_| IdentityParseError::InvalidHost)?;
+        match rest {
+            "" => (host, None),
+            _ => (
+                host,
+                Some(
+                    rest.strip_prefix(':')
+                        .ok_or(IdentityParseError::InvalidPort)?,
+                ),
+            ),
+        }
+    } else if authority.parse::<Ipv6Addr>().is_ok() {
+        (authority, None)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(IdentityParseError::InvalidHost);
+    }
+    let port = port
+        .map(|port| port.parse().map_err(|_| IdentityParseError::InvalidPort))
+        .transpose()?;
+    Ok((host, port))
+}
+
+fn write_host(f: &mut fmt::Formatter<'_>, host: &str) -> fmt::Result {
+    if host.contains(':') {
+        write!(f, "[{host}]")
+    } else {
+        f.write_str(host)
+    }
+}
+
+/// A `host:port` identity, where the host is an IP address or a name to resolve.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SocketIdentity {
+    host: String,
+    port: u16,
+}
+
+impl SocketIdentity {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+    /// Parse `identity`, using `default_port` if it has no port.
+    pub fn parse(
+        identity: &Identity,
+        default_port: Option<u16>,
+    ) -> Result<Self, IdentityParseError> {
+        let (host, port) = split_authority(text(identity)?)?;
+        let port = port
+            .or(default_port)
+            .ok_or(IdentityParseError::MissingPort)?;
+        Ok(Self::new(host, port))
+    }
+    /// The host, without brackets.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    /// The socket address, if the host is an IP address.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let ip: IpAddr = self.host.parse().ok()?;
+        Some(SocketAddr::new(ip, self.port))
+    }
+}
+
+impl TryFrom<&Identity> for SocketIdentity {
+    type Error = IdentityParseError;
+    fn try_from(identity: &Identity) -> Result<Self, Self::Error> {
+        Self::parse(identity, None)
+    }
+}
+
+impl From<SocketAddr> for SocketIdentity {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl fmt::Display for SocketIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_host(f, &self.host)?;
+        write!(f, ":{}", self.port)
+    }
+}
+
+impl From<SocketIdentity> for Identity {
+    fn from(socket: SocketIdentity) -> Self {
+        Identity::new(socket.to_string())
+    }
+}
+
+/// A URL identity. The scheme is optional, since the protocol of the address
+/// usually implies it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UrlIdentity {
+    scheme: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: String,
+    query: Option<String>,
+}
+
+impl UrlIdentity {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            scheme: None,
+            host: host.into(),
+            port: None,
+            path: String::new(),
+            query: None,
+        }
+    }
+    pub fn with_scheme(self, scheme: impl Into<String>) -> Self {
+        Self {
+            scheme: Some(scheme.into()),
+            ..self
+        }
+    }
+    pub fn with_port(self, port: u16) -> Self {
+        Self {
+            port: Some(port),
+            ..self
+        }
+    }
+    /// Set the decoded path; a leading `/` is added if missing.
+    pub fn with_path(self, path: impl Into<String>) -> Self {
+        let mut path = path.into();
+        if !path.is_empty() && !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        Self { path, ..self }
+    }
+    /// Set the query, kept as written.
+    pub fn with_query(self, query: impl Into<String>) -> Self {
+        Self {
+            query: Some(query.into()),
+            ..self
+        }
+    }
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+    /// The host, without brackets.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+    /// The percent-decoded path, empty or starting with `/`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+}
+
+impl TryFrom<&Identity> for UrlIdentity {
+    type Error = IdentityParseError;
+    fn try_from(identity: &Identity) -> Result<Self, Self::Error> {
+        let text = text(identity)?;
+        let (scheme, rest) = match text.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_owned()), rest),
+            None => (None, text),
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query.to_owned())),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let (host, port) = split_authority(authority)?;
+        Ok(Self {
+            scheme,
+            host: host.to_owned(),
+            port,
+            path: percent_decode(path)?,
+            query,
+        })
+    }
+}
+
+impl fmt::Display for UrlIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scheme) = &self.scheme {
+            write!(f, "{scheme}://")?;
+        }
+        write_host(f, &self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        f.write_str(&percent_encode(&self.path))?;
+        if let Some(query) = &self.query {
+            write!(f, "?{query}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<UrlIdentity> for Identity {
+    fn from(url: UrlIdentity) -> Self {
+        Identity::new(url.to_string())
+    }
+}
+
+fn percent_decode(text: &str) -> Result<String, IdentityParseError> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or(IdentityParseError::InvalidEscape)?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| IdentityParseError::InvalidEscape)
+}
+
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// A filesystem path identity, such as a Unix socket path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PathIdentity(PathBuf);
+
+impl PathIdentity {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl TryFrom<&Identity> for PathIdentity {
+    type Error = IdentityParseError;
+    fn try_from(identity: &Identity) -> Result<Self, Self::Error> {
+        Ok(Self::new(text(identity)?))
+    }
+}
+
+impl From<PathIdentity> for Identity {
+    /// Non-UTF-8 parts of the path are replaced, as identities are UTF-8.
+    fn from(path: PathIdentity) -> Self {
+        Identity::new(path.0.to_string_lossy().into_owned())
+    }
+}
+
+impl Address {
+    fn expect_kind(&self, expected: IdentityKind) -> Result<(), IdentityParseError> {
+        match IdentityKind::of(&self.protocol) {
+            Some(kind) if kind != expected => Err(IdentityParseError::KindMismatch {
+                protocol: self.protocol.clone(),
+                expected,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// The identity as a socket address, defaulting the port by protocol.
+    /// Fails if the protocol is known to use another kind of identity.
+    pub fn as_socket(&self) -> Result<SocketIdentity, IdentityParseError> {
+        self.expect_kind(IdentityKind::Socket)?;
+        SocketIdentity::parse(&self.identity, default_port(&self.protocol))
+    }
+    /// The identity as a URL, defaulting the port by protocol. Fails if the
+    /// protocol is known to use another kind of identity.
+    pub fn as_url(&self) -> Result<UrlIdentity, IdentityParseError> {
+        self.expect_kind(IdentityKind::Url)?;
+        let mut url = UrlIdentity::try_from(&self.identity)?;
+        if url.port.is_none() {
+            let protocol = url.scheme.as_ref().map_or(self.protocol.clone(), |scheme| {
+                Protocol::new(scheme.as_bytes())
+            });
+            url.port = default_port(&protocol);
+        }
+        Ok(url)
+    }
+    /// The identity as a filesystem path. Fails if the protocol is known to use
+    /// another kind of identity.
+    pub fn as_path(&self) -> Result<PathIdentity, IdentityParseError> {
+        self.expect_kind(IdentityKind::Path)?;
+        PathIdentity::try_from(&self.identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(protocol: Protocol, identity: &str) -> Address {
+        Address::new(protocol, Identity::new(identity))
+    }
+
+    #[test]
+    fn ipv6_hosts_keep_their_brackets_and_ports() {
+        let socket = address(TCP, "[::1]:8080").as_socket().unwrap();
+        assert_eq!((socket.host(), socket.port()), ("::1", 8080));
+        assert_eq!(socket.socket_addr(), Some("[::1]:8080".parse().unwrap()));
+        assert_eq!(Identity::from(socket), Identity::new("[::1]:8080"));
+
+        let url = address(HTTPS, "[2001:db8::1]:8443/a%20b?x=1")
+            .as_url()
+            .unwrap();
+        assert_eq!((url.host(), url.port()), ("2001:db8::1", Some(8443)));
+        assert_eq!((url.path(), url.query()), ("/a b", Some("x=1")));
+        assert_eq!(url.to_string(), "[2001:db8::1]:8443/a%20b?x=1");
+    }
+
+    #[test]
+    fn missing_ports_default_by_protocol() {
+        assert_eq!(
+            address(QUIC, "example.org").as_socket().unwrap().port(),
+            443
+        );
+        // a bare IPv6 address is a host without a port
+        assert_eq!(
+            address(QUIC, "::1").as_socket().unwrap().to_string(),
+            "[::1]:443"
+        );
+        assert_eq!(
+            address(HTTP, "example.org/x").as_url().unwrap().port(),
+            Some(80)
+        );
+        // the scheme decides over the protocol
+        let url = address(HTTP, "wss://example.org").as_url().unwrap();
+        assert_eq!((url.scheme(), url.port()), (Some("wss"), Some(443)));
+        assert_eq!(
+            address(TCP, "example.org").as_socket(),
+            Err(IdentityParseError::MissingPort)
+        );
+        assert_eq!(
+            address(TCP, "example.org:http").as_socket(),
+            Err(IdentityParseError::InvalidPort)
+        );
+        assert_eq!(
+            address(TCP, "[example]:1").as_socket(),
+            Err(IdentityParseError::InvalidHost)
+        );
+    }
+
+    #[test]
+    fn protocols_only_parse_their_own_kind() {
+        assert_eq!(
+            address(UNIX, "/run/anytape.sock").as_socket(),
+            Err(IdentityParseError::KindMismatch {
+                protocol: UNIX,
+                expected: IdentityKind::Socket,
+            })
+        );
+        assert_eq!(
+            address(TCP, "127.0.0.1:1").as_url(),
+            Err(IdentityParseError::KindMismatch {
+                protocol: TCP,
+                expected: IdentityKind::Url,
+            })
+        );
+        assert!(matches!(
+            address(HTTPS, "example.org").as_path(),
+            Err(IdentityParseError::KindMismatch { .. })
+        ));
+        let path = address(UNIX, "/run/anytape.sock").as_path().unwrap();
+        assert_eq!(path.path(), Path::new("/run/anytape.sock"));
+        // protocols of unknown kind parse as anything
+        let custom = Protocol::new_static(b"custom");
+        assert!(address(custom.clone(), "host:1").as_socket().is_ok());
+        assert!(address(custom, "host").as_url().is_ok());
+    }
+}
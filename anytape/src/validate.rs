@@ -0,0 +1,296 @@
+//! Checking that identities fit the protocol they are used with.
+//!
+//! An identity a protocol's executor cannot make sense of, such as a key for a
+//! socket protocol, otherwise only fails once something is sent to it. An
+//! [`IdentityValidator`] registered for the protocol with
+//! [`NodeInstance::with_identity_validator`] catches it earlier: when the
+//! address is built with [`Address::validated`], and when the executor is
+//! registered with [`NodeInstance::try_register_executor`], for the node's own
+//! addresses and those in its [address book](NodeInstance::with_address_book).
+//! Protocols without a validator accept any identity.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{
+    typed_address::{default_port, IdentityParseError, SocketIdentity},
+    Address, DynProtocolExecutor, Identity, NodeInstance, Protocol,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The identity does not parse as the protocol's kind of identity.
+    Malformed(IdentityParseError),
+    /// A key identity of the wrong length, in bytes.
+    KeyLength {
+        expected: usize,
+        actual: usize,
+    },
+    Other(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Malformed(e) => write!(f, "malformed identity: {e}"),
+            ValidationError::KeyLength { expected, actual } => {
+                write!(f, "key is {actual} bytes long, expected {expected}")
+            }
+            ValidationError::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ValidationError::Malformed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<IdentityParseError> for ValidationError {
+    fn from(e: IdentityParseError) -> Self {
+        ValidationError::Malformed(e)
+    }
+}
+
+/// An address whose identity its protocol's validator rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidAddress {
+    pub address: Address,
+    pub error: ValidationError,
+}
+
+impl fmt::Display for InvalidAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid address {}: {}", self.address, self.error)
+    }
+}
+
+impl std::error::Error for InvalidAddress {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Decides whether an identity is one of a protocol.
+pub trait IdentityValidator: Send + Sync {
+    fn validate(&self, identity: &Identity) -> Result<(), ValidationError>;
+}
+
+impl<F> IdentityValidator for F
+where
+    F: Fn(&Identity) -> Result<(), ValidationError> + Send + Sync,
+{
+    fn validate(&self, identity: &Identity) -> Result<(), ValidationError> {
+        self(identity)
+    }
+}
+
+/// Accepts `host:port` identities, as the TCP and QUIC executors send to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketValidator {
+    default_port: Option<u16>,
+}
+
+impl SocketValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Also accept a bare host for protocols with a [default port](default_port).
+    pub fn for_protocol(protocol: &Protocol) -> Self {
+        Self {
+            default_port: default_port(protocol),
+        }
+    }
+}
+
+impl IdentityValidator for SocketValidator {
+    fn validate(&self, identity: &Identity) -> Result<(), ValidationError> {
+        SocketIdentity::parse(identity, self.default_port)?;
+        Ok(())
+    }
+}
+
+/// Accepts identities that are raw keys of a fixed length, such as the 32
+/// bytes of an Ed25519 public key.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyLengthValidator {
+    len: usize,
+}
+
+impl KeyLengthValidator {
+    pub fn new(len: usize) -> Self {
+        Self { len }
+    }
+}
+
+impl IdentityValidator for KeyLengthValidator {
+    fn validate(&self, identity: &Identity) -> Result<(), ValidationError> {
+        match identity.as_bytes().len() {
+            len if len == self.len => Ok(()),
+            actual => Err(ValidationError::KeyLength {
+                expected: self.len,
+                actual,
+            }),
+        }
+    }
+}
+
+/// The identity validator of each protocol that has one.
+#[derive(Clone, Default)]
+pub struct IdentityValidators {
+    by_protocol: HashMap<Protocol, Arc<dyn IdentityValidator>>,
+}
+
+impl IdentityValidators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with(mut self, protocol: Protocol, validator: impl IdentityValidator + 'static) -> Self {
+        self.by_protocol.insert(protocol, Arc::new(validator));
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.by_protocol.is_empty()
+    }
+    /// Check `address` against the validator of its protocol, if any.
+    pub fn validate(&self, address: &Address) -> Result<(), InvalidAddress> {
+        match self.by_protocol.get(&address.protocol) {
+            Some(validator) => {
+                validator
+                    .validate(&address.identity)
+                    .map_err(|error| InvalidAddress {
+                        address: address.clone(),
+                        error,
+                    })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Address {
+    /// An address of `identity` over `protocol`, if the validator registered for
+    /// `protocol` in `registry` accepts it.
+    pub fn validated(
+        protocol: Protocol,
+        identity: Identity,
+        registry: &IdentityValidators,
+    ) -> Result<Address, InvalidAddress> {
+        let address = Address::new(protocol, identity);
+        registry.validate(&address)?;
+        Ok(address)
+    }
+}
+
+impl NodeInstance {
+    /// Reject identities of `protocol` that `validator` does not accept; see the
+    /// [module docs](self).
+    pub fn with_identity_validator(
+        mut self,
+        protocol: Protocol,
+        validator: impl IdentityValidator + 'static,
+    ) -> Self {
+        self.identity_validators = self.identity_validators.with(protocol, validator);
+        self
+    }
+    pub fn identity_validators(&self) -> &IdentityValidators {
+        &self.identity_validators
+    }
+    /// Check the addresses over `protocol` known without a send: the node's own
+    /// and those in its address book.
+    pub fn validate_addresses(&self, protocol: &Protocol) -> Result<(), InvalidAddress> {
+        let book = self
+            .address_book()
+            .into_iter()
+            .flat_map(|book| book.iter())
+            .flat_map(|(_, node)| &node.address_set);
+        self.local_addresses()
+            .chain(book)
+            .filter(|address| address.protocol == *protocol)
+            .try_for_each(|address| self.identity_validators.validate(address))
+    }
+    /// Like [`NodeInstance::register_executor`], but fails without registering
+    /// anything if one of the [addresses known](NodeInstance::validate_addresses)
+    /// over `protocol` is invalid.
+    pub fn try_register_executor(
+        &mut self,
+        protocol: Protocol,
+        executor: Arc<dyn DynProtocolExecutor>,
+    ) -> Result<Option<Arc<dyn DynProtocolExecutor>>, InvalidAddress> {
+        self.validate_addresses(&protocol)?;
+        Ok(self.register_executor(protocol, executor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::Recorder, typed_address::TCP};
+
+    const ED25519: Protocol = Protocol::new_static(b"ed25519");
+    const HTTPS: Protocol = Protocol::new_static(b"https");
+
+    fn registry() -> IdentityValidators {
+        IdentityValidators::new()
+            .with(TCP, SocketValidator::for_protocol(&TCP))
+            .with(HTTPS, SocketValidator::for_protocol(&HTTPS))
+            .with(ED25519, KeyLengthValidator::new(32))
+    }
+
+    #[test]
+    fn each_protocol_accepts_its_own_identities() {
+        let registry = registry();
+        for (protocol, identity) in [
+            (TCP, Identity::new("127.0.0.1:7000")),
+            (TCP, Identity::new("[::1]:7000")),
+            (HTTPS, Identity::new("example.com")),
+            (ED25519, Identity::new([7; 32])),
+            (Protocol::new("other"), Identity::new("anything")),
+        ] {
+            let address = Address::validated(protocol, identity.clone(), &registry).unwrap();
+            assert_eq!(address.identity, identity);
+        }
+    }
+
+    #[test]
+    fn each_protocol_rejects_identities_of_another_kind() {
+        let registry = registry();
+        let key = Identity::new([0xff; 32]);
+        for protocol in [TCP, HTTPS] {
+            let error = Address::validated(protocol, key.clone(), &registry).unwrap_err();
+            assert!(matches!(error.error, ValidationError::Malformed(_)));
+        }
+        // tcp has no default port to fall back to
+        assert!(Address::validated(TCP, Identity::new("example.com"), &registry).is_err());
+        let error =
+            Address::validated(ED25519, Identity::new("127.0.0.1:7000"), &registry).unwrap_err();
+        assert_eq!(
+            error.error,
+            ValidationError::KeyLength {
+                expected: 32,
+                actual: 14
+            }
+        );
+        assert_eq!(error.address.protocol, ED25519);
+    }
+
+    #[test]
+    fn executors_are_not_registered_for_invalid_own_addresses() {
+        let mut node = NodeInstance::new()
+            .with_address(Address::new(TCP, Identity::new("not a socket")))
+            .with_address(Address::new(ED25519, Identity::new([1; 32])))
+            .with_identity_validator(TCP, SocketValidator::new())
+            .with_identity_validator(ED25519, KeyLengthValidator::new(32));
+        let Err(error) = node.try_register_executor(TCP, Arc::new(Recorder::new())) else {
+            panic!("the own tcp address is invalid");
+        };
+        assert_eq!(error.address.identity, Identity::new("not a socket"));
+        assert!(!node.has_executor(&TCP));
+        let registered = node.try_register_executor(ED25519, Arc::new(Recorder::new()));
+        assert!(matches!(registered, Ok(None)));
+        assert!(node.has_executor(&ED25519));
+    }
+}
@@ -0,0 +1,429 @@
+//! An in-process network of nodes for reproducible multi-hop tests.
+//!
+//! A [`VirtualNetwork`] owns several [`NodeInstance`]s that reach each other over
+//! [`VirtualNetwork::PROTOCOL`] and share one [`ManualClock`]. Sending never
+//! touches a socket: the message is queued with the latency of its
+//! [link](VirtualNetwork::set_link), or dropped with the link's loss rate, and
+//! nothing arrives until the test calls [`VirtualNetwork::deliver_next`]. That
+//! delivers the queued message due first, moving the clock forward to its
+//! arrival, so a run depends only on the links, the seed and the order of calls.
+//!
+//! Nodes are addressed by name: the node added as `"a"` has the address
+//! [`VirtualNetwork::address`]`("a")`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    Address, Clock, Identity, ManualClock, Message, MessageStatus, NodeInstance, Protocol,
+    ProtocolExecutor, SendError, SendOutcome,
+};
+
+/// How messages travel from one node to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    /// Share of messages lost, between 0 and 1.
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(1),
+            loss: 0.0,
+        }
+    }
+}
+
+/// A message that reached its next hop, and how that node took it.
+#[derive(Debug)]
+pub struct Delivery {
+    pub from: Identity,
+    pub to: Identity,
+    pub unique_id: u64,
+    pub result: Result<MessageStatus, SendError>,
+}
+
+#[derive(Debug)]
+pub enum VirtualNetError {
+    /// No node of that name is in the network.
+    UnknownNode(Identity),
+}
+
+impl fmt::Display for VirtualNetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtualNetError::UnknownNode(identity) => write!(f, "unknown node {identity:?}"),
+        }
+    }
+}
+
+impl std::error::Error for VirtualNetError {}
+
+struct InFlight {
+    from: Identity,
+    to: Identity,
+    message: Message,
+}
+
+struct Shared {
+    clock: ManualClock,
+    members: Mutex<HashSet<Identity>>,
+    default_link: Mutex<LinkConfig>,
+    links: Mutex<HashMap<(Identity, Identity), LinkConfig>>,
+    /// Keyed by arrival time, then by send order.
+    in_flight: Mutex<BTreeMap<(Duration, u64), InFlight>>,
+    statuses: Mutex<HashMap<u64, MessageStatus>>,
+    sent: Mutex<u64>,
+    lost: Mutex<u64>,
+    rng: Mutex<u64>,
+}
+
+impl Shared {
+    fn link(&self, from: &Identity, to: &Identity) -> LinkConfig {
+        self.links
+            .lock()
+            .unwrap()
+            .get(&(from.clone(), to.clone()))
+            .copied()
+            .unwrap_or(*self.default_link.lock().unwrap())
+    }
+    /// A deterministic roll in `[0, 1)`.
+    fn roll(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        // xorshift64
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn enqueue(&self, from: &Identity, to: &Identity, message: Message) {
+        let link = self.link(from, to);
+        let seq = {
+            let mut sent = self.sent.lock().unwrap();
+            *sent += 1;
+            *sent
+        };
+        if link.loss > 0.0 && self.roll() < link.loss {
+            *self.lost.lock().unwrap() += 1;
+            self.statuses
+                .lock()
+                .unwrap()
+                .insert(message.unique_id, MessageStatus::Unreachable);
+            return;
+        }
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(message.unique_id, MessageStatus::Sended);
+        let arrival = self.clock.monotonic() + link.latency;
+        self.in_flight.lock().unwrap().insert(
+            (arrival, seq),
+            InFlight {
+                from: from.clone(),
+                to: to.clone(),
+                message,
+            },
+        );
+    }
+}
+
+/// The executor each node of a [`VirtualNetwork`] sends with.
+pub struct VirtualExecutor {
+    local: Identity,
+    shared: Arc<Shared>,
+}
+
+impl ProtocolExecutor for VirtualExecutor {
+    type Error = VirtualNetError;
+
+    fn send(
+        &self,
+        remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<SendOutcome, Self::Error>> + Send + 'static {
+        let result = if self.shared.members.lock().unwrap().contains(remote) {
+            self.shared.enqueue(&self.local, remote, message);
+            Ok(SendOutcome::sent())
+        } else {
+            Err(VirtualNetError::UnknownNode(remote.clone()))
+        };
+        std::future::ready(result)
+    }
+    fn get_status(
+        &self,
+        _remote: &Identity,
+        message: Message,
+    ) -> impl Future<Output = Result<MessageStatus, Self::Error>> + Send + 'static {
+        let status = self
+            .shared
+            .statuses
+            .lock()
+            .unwrap()
+            .get(&message.unique_id)
+            .copied()
+            .unwrap_or(MessageStatus::Unreachable);
+        std::future::ready(Ok(status))
+    }
+}
+
+/// Nodes connected by simulated links; see the [module docs](self).
+pub struct VirtualNetwork {
+    shared: Arc<Shared>,
+    nodes: HashMap<Identity, Arc<NodeInstance>>,
+}
+
+impl Default for VirtualNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualNetwork {
+    pub const PROTOCOL: Protocol = Protocol::new_static(b"virtual");
+
+    /// An empty network whose clock starts at wall time zero.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                clock: ManualClock::new(0),
+                members: Default::default(),
+                default_link: Default::default(),
+                links: Default::default(),
+                in_flight: Default::default(),
+                statuses: Default::default(),
+                sent: Default::default(),
+                lost: Default::default(),
+                rng: Mutex::new(0x9e37_79b9_7f4a_7c15),
+            }),
+            nodes: HashMap::new(),
+        }
+    }
+    /// Seed the generator deciding which messages lossy links drop.
+    pub fn with_seed(self, seed: u64) -> Self {
+        // xorshift never leaves zero
+        *self.shared.rng.lock().unwrap() = seed.max(1);
+        self
+    }
+    /// Use `link` between nodes without a link of their own.
+    pub fn with_default_link(self, link: LinkConfig) -> Self {
+        *self.shared.default_link.lock().unwrap() = link;
+        self
+    }
+    pub fn address(name: &str) -> Address {
+        Address::new(Self::PROTOCOL, Identity::new(name))
+    }
+    pub fn clock(&self) -> &ManualClock {
+        &self.shared.clock
+    }
+    /// Add a node called `name`, after `configure` has set it up. The node
+    /// already has the network's clock, its [address](Self::address) and the
+    /// executor for [`Self::PROTOCOL`].
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        configure: impl FnOnce(NodeInstance) -> NodeInstance,
+    ) -> Arc<NodeInstance> {
+        let identity = Identity::new(name);
+        let node = NodeInstance::new()
+            .with_name(name)
+            .with_clock(self.shared.clock.clone())
+            .with_address(Self::address(name))
+            .with_executor(
+                Self::PROTOCOL,
+                VirtualExecutor {
+                    local: identity.clone(),
+                    shared: self.shared.clone(),
+                },
+            );
+        let node = Arc::new(configure(node));
+        self.shared.members.lock().unwrap().insert(identity.clone());
+        self.nodes.insert(identity, node.clone());
+        node
+    }
+    pub fn node(&self, name: &str) -> Option<&Arc<NodeInstance>> {
+        self.nodes.get(&Identity::new(name))
+    }
+    /// Set how messages travel from `from` to `to`. Links are one way.
+    pub fn set_link(&self, from: &str, to: &str, link: LinkConfig) {
+        self.shared
+            .links
+            .lock()
+            .unwrap()
+            .insert((Identity::new(from), Identity::new(to)), link);
+    }
+    /// Set the link both ways between `a` and `b`.
+    pub fn connect(&self, a: &str, b: &str, link: LinkConfig) {
+        self.set_link(a, b, link);
+        self.set_link(b, a, link);
+    }
+    /// Messages sent and not yet delivered.
+    pub fn in_flight(&self) -> usize {
+        self.shared.in_flight.lock().unwrap().len()
+    }
+    /// Messages dropped by lossy links so far.
+    pub fn lost(&self) -> u64 {
+        *self.shared.lost.lock().unwrap()
+    }
+    /// Deliver the message due first, moving the clock to its arrival time.
+    /// `None` once nothing is in flight.
+    pub async fn deliver_next(&self) -> Option<Delivery> {
+        let ((arrival, _), in_flight) = self.shared.in_flight.lock().unwrap().pop_first()?;
+        let now = self.shared.clock.monotonic();
+        if arrival > now {
+            self.shared.clock.advance(arrival - now);
+        }
+        let InFlight { from, to, message } = in_flight;
+        let unique_id = message.unique_id;
+        let result = match self.nodes.get(&to) {
+            Some(node) => {
+                let at = Address::new(Self::PROTOCOL, to.clone());
+                node.dispatch_inbound(message, at).await
+            }
+            None => Ok(MessageStatus::Unreachable),
+        };
+        let status = match &result {
+            Ok(status) => *status,
+            Err(_) => MessageStatus::SendError,
+        };
+        self.shared
+            .statuses
+            .lock()
+            .unwrap()
+            .insert(unique_id, status);
+        Some(Delivery {
+            from,
+            to,
+            unique_id,
+            result,
+        })
+    }
+    /// Deliver until nothing is in flight or `max_steps` messages were
+    /// delivered, which stops routing loops. Returns the deliveries in order.
+    pub async fn run_until_idle(&self, max_steps: usize) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        while deliveries.len() < max_steps {
+            match self.deliver_next().await {
+                Some(delivery) => deliveries.push(delivery),
+                None => break,
+            }
+        }
+        deliveries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::Shared, DataBackend, MemoryBackend, MessageBuilder};
+
+    fn link(latency_ms: u64, loss: f64) -> LinkConfig {
+        LinkConfig {
+            latency: Duration::from_millis(latency_ms),
+            loss,
+        }
+    }
+
+    fn hops(deliveries: &[Delivery]) -> Vec<String> {
+        deliveries
+            .iter()
+            .map(|delivery| String::from_utf8_lossy(delivery.to.as_bytes()).into_owned())
+            .collect()
+    }
+
+    /// `a -> b -> c`, with `a` routing to `c` through `b`, and the payloads `c`
+    /// received.
+    fn chain(ab: LinkConfig, bc: LinkConfig) -> (VirtualNetwork, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let c = VirtualNetwork::address("c");
+        let routes = Arc::new(MemoryBackend::new());
+        crate::testing::block_on(routes.set_next(&c, Some(&VirtualNetwork::address("b")))).unwrap();
+        let mut net = VirtualNetwork::new();
+        net.connect("a", "b", ab);
+        net.connect("b", "c", bc);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        net.add_node("a", |node| node.with_backend(Shared(routes)));
+        net.add_node("b", |node| node);
+        net.add_node("c", |node| {
+            let received = received.clone();
+            node.with_handler(move |message: Message| {
+                received.lock().unwrap().push(message.payload);
+                async {}
+            })
+        });
+        (net, received)
+    }
+
+    async fn send_from_a(net: &VirtualNetwork, message: Message) {
+        let a = net.node("a").unwrap();
+        let mut message = message;
+        a.mark(VirtualNetwork::address("a"), &mut message);
+        a.forward(message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_cross_three_nodes_on_the_link_latencies() {
+        let (net, received) = chain(link(20, 0.0), link(30, 0.0));
+        let c = VirtualNetwork::address("c");
+        send_from_a(&net, MessageBuilder::new(c).payload("hello").build()).await;
+        assert_eq!(net.in_flight(), 1);
+
+        let first = net.deliver_next().await.unwrap();
+        assert_eq!(net.clock().monotonic(), Duration::from_millis(20));
+        let rest = net.run_until_idle(10).await;
+        assert_eq!(hops(&[first]), ["b"]);
+        assert_eq!(hops(&rest), ["c"]);
+        assert!(matches!(rest[0].result, Ok(MessageStatus::Received)));
+        assert_eq!(net.clock().monotonic(), Duration::from_millis(50));
+        assert_eq!(*received.lock().unwrap(), [b"hello".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn exhausted_ttls_stop_at_the_relay() {
+        let (net, received) = chain(link(1, 0.0), link(1, 0.0));
+        let c = VirtualNetwork::address("c");
+        send_from_a(&net, MessageBuilder::new(c).ttl(0).payload("x").build()).await;
+        let deliveries = net.run_until_idle(10).await;
+        assert_eq!(hops(&deliveries), ["b"]);
+        assert!(matches!(deliveries[0].result, Err(SendError::TtlExceeded)));
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lossy_links_drop_the_same_messages_for_the_same_seed() {
+        let mut lost = Vec::new();
+        for _ in 0..2 {
+            let (net, received) = chain(link(1, 0.5), link(1, 0.0));
+            let net = net.with_seed(7);
+            let c = VirtualNetwork::address("c");
+            for n in 0..20u8 {
+                send_from_a(&net, MessageBuilder::new(c.clone()).payload([n]).build()).await;
+            }
+            net.run_until_idle(100).await;
+            let received = received.lock().unwrap().len() as u64;
+            assert_eq!(received + net.lost(), 20);
+            assert!(net.lost() > 0 && received > 0);
+            lost.push(net.lost());
+        }
+        assert_eq!(lost[0], lost[1]);
+    }
+
+    #[tokio::test]
+    async fn unknown_nodes_cannot_be_sent_to() {
+        let (net, _) = chain(link(1, 0.0), link(1, 0.0));
+        let a = net.node("a").unwrap();
+        let result = a
+            .send(
+                MessageBuilder::new(VirtualNetwork::address("z")).build(),
+                VirtualNetwork::address("z"),
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(net.in_flight(), 0);
+    }
+}
@@ -0,0 +1,322 @@
+//! Filling a restarted node's route cache before its traffic does.
+//!
+//! A node starts with an empty route cache, and under load its first lookups
+//! all go to the backend at once. [`NodeInstance::warm_cache`] preloads up to
+//! [`WarmOptions::max_routes`] routes beforehand: the most recently used ones,
+//! by the recency hint found when [scanning](crate::DataBackend::scan) the
+//! backend, or those to an operator's list of [hot](WarmPriority::Hot)
+//! destinations. Hot destinations are looked up in waves that double in size,
+//! so the backend sees a ramp rather than a burst.
+//!
+//! Warming gives up at its [time budget](WarmOptions::time_budget) and caches
+//! what it found until then. Progress is published as
+//! [`NodeEvent::RouteCacheWarming`]. Warmed routes never replace or evict those
+//! live traffic cached in the meantime, and go in in batches so that lookups
+//! are never locked out for long.
+
+use std::{
+    cmp::Reverse,
+    future::{poll_fn, Future},
+    time::Duration,
+};
+
+use tokio::{task::JoinSet, time::Instant};
+
+use crate::{Address, NodeEvent, NodeInstance, StoredRoute};
+
+/// Routes cached, or scanned, between progress events.
+const WARM_BATCH: usize = 1024;
+/// Most concurrent lookups of hot destinations.
+const MAX_WAVE: usize = 64;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum WarmPriority {
+    /// The most recently used routes in the backend.
+    #[default]
+    Recency,
+    /// The routes to these destinations, in order.
+    Hot(Vec<Address>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarmOptions {
+    /// Cache at most this many routes, and never more than the cache holds.
+    pub max_routes: usize,
+    pub priority: WarmPriority,
+    /// Stop reading from the backend after this long.
+    pub time_budget: Option<Duration>,
+}
+
+impl Default for WarmOptions {
+    fn default() -> Self {
+        Self {
+            max_routes: 10_000,
+            priority: WarmPriority::default(),
+            time_budget: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// What [`NodeInstance::warm_cache`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmReport {
+    /// Routes read from the backend.
+    pub scanned: u64,
+    /// Routes cached.
+    pub loaded: u64,
+    /// Whether the time budget ran out before the backend was done.
+    pub timed_out: bool,
+}
+
+impl NodeInstance {
+    /// Preload the route cache from the backend; see the [module docs](self).
+    /// Does nothing without a backend, and with [`WarmPriority::Recency`]
+    /// nothing if the backend cannot be scanned.
+    pub async fn warm_cache(&self, options: WarmOptions) -> WarmReport {
+        let mut report = WarmReport::default();
+        let deadline = options.time_budget.map(|budget| Instant::now() + budget);
+        let routes = match options.priority {
+            WarmPriority::Recency => {
+                self.scan_recent(options.max_routes, deadline, &mut report)
+                    .await
+            }
+            WarmPriority::Hot(hot) => {
+                self.look_up_hot(hot, options.max_routes, deadline, &mut report)
+                    .await
+            }
+        };
+        for batch in routes.chunks(WARM_BATCH) {
+            {
+                let mut cache = self.next_cache.write().unwrap();
+                for route in batch {
+                    let touched = route.last_used_ms.unwrap_or(0);
+                    if cache.warm(self.intern(&route.addr), self.intern(&route.next), touched) {
+                        report.loaded += 1;
+                    }
+                }
+            }
+            self.publish_warming(&report, false);
+            tokio::task::yield_now().await;
+        }
+        self.publish_warming(&report, true);
+        report
+    }
+    /// The `max` most recently used routes in the backend, most recent first.
+    async fn scan_recent(
+        &self,
+        max: usize,
+        deadline: Option<Instant>,
+        report: &mut WarmReport,
+    ) -> Vec<StoredRoute> {
+        let Some(mut scan) = self.backend.as_ref().and_then(|backend| backend.scan()) else {
+            return Vec::new();
+        };
+        let mut kept = Vec::new();
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                report.timed_out = true;
+                break;
+            }
+            let next = poll_fn(|cx| scan.as_mut().poll_next(cx));
+            let Some(route) = before(deadline, next).await else {
+                report.timed_out = true;
+                break;
+            };
+            let Some(route) = route else {
+                break;
+            };
+            kept.push(route);
+            report.scanned += 1;
+            if report.scanned.is_multiple_of(WARM_BATCH as u64) {
+                self.publish_warming(report, false);
+                // scans that are always ready would hog the runtime
+                tokio::task::yield_now().await;
+            }
+            if kept.len() >= max.saturating_mul(2).max(WARM_BATCH) {
+                keep_most_recent(&mut kept, max);
+            }
+        }
+        keep_most_recent(&mut kept, max);
+        kept.sort_unstable_by_key(|route| Reverse(route.last_used_ms));
+        kept
+    }
+    /// The routes to the first `max` destinations of `hot` the backend knows.
+    async fn look_up_hot(
+        &self,
+        hot: Vec<Address>,
+        max: usize,
+        deadline: Option<Instant>,
+        report: &mut WarmReport,
+    ) -> Vec<StoredRoute> {
+        let Some(backend) = self.backend.clone() else {
+            return Vec::new();
+        };
+        let mut hot = hot
+            .iter()
+            .take(max)
+            .map(|addr| self.canonical(addr).into_owned())
+            .peekable();
+        let mut found = Vec::new();
+        let mut wave = 1;
+        while hot.peek().is_some() {
+            let mut lookups = JoinSet::new();
+            for (i, addr) in hot.by_ref().take(wave).enumerate() {
+                let backend = backend.clone();
+                lookups
+                    .spawn(async move { (i, backend.get_next(&addr).await.ok().flatten(), addr) });
+            }
+            let mut answers = Vec::new();
+            loop {
+                let Some(answer) = before(deadline, lookups.join_next()).await else {
+                    report.timed_out = true;
+                    break;
+                };
+                match answer {
+                    Some(Ok(answer)) => answers.push(answer),
+                    // a panicking backend only loses its route
+                    Some(Err(_)) => {}
+                    None => break,
+                }
+            }
+            answers.sort_unstable_by_key(|(i, ..)| *i);
+            report.scanned += answers.len() as u64;
+            found.extend(answers.into_iter().filter_map(|(_, next, addr)| {
+                Some(StoredRoute {
+                    addr,
+                    next: next?,
+                    last_used_ms: None,
+                })
+            }));
+            if report.timed_out {
+                break;
+            }
+            self.publish_warming(report, false);
+            wave = (wave * 2).min(MAX_WAVE);
+        }
+        found
+    }
+    fn publish_warming(&self, report: &WarmReport, finished: bool) {
+        self.publish(|| NodeEvent::RouteCacheWarming {
+            scanned: report.scanned,
+            loaded: report.loaded,
+            finished,
+        });
+    }
+}
+
+/// The output of `future`, or `None` if `deadline` passes first.
+async fn before<T>(deadline: Option<Instant>, future: impl Future<Output = T>) -> Option<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Drop all but the `max` most recently used of `routes`.
+fn keep_most_recent(routes: &mut Vec<StoredRoute>, max: usize) {
+    if routes.len() > max {
+        if max > 0 {
+            routes.select_nth_unstable_by_key(max - 1, |route| Reverse(route.last_used_ms));
+        }
+        routes.truncate(max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::{testing::addr, BoxFuture, BoxResult, BoxStream, DataBackend, MemoryBackend};
+
+    /// Counts the lookups that reach the backend it wraps.
+    struct Counting(Arc<MemoryBackend>, Arc<AtomicUsize>);
+
+    impl DataBackend for Counting {
+        fn get_next(&self, addr: &Address) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.get_next(addr)
+        }
+        fn set_next(
+            &self,
+            addr: &Address,
+            next: Option<&Address>,
+        ) -> BoxFuture<BoxResult<Option<Address>>> {
+            self.0.set_next(addr, next)
+        }
+        fn scan(&self) -> Option<BoxStream<StoredRoute>> {
+            self.0.scan()
+        }
+    }
+
+    fn destination(i: usize) -> Address {
+        addr(&format!("d{i}"))
+    }
+
+    #[tokio::test]
+    async fn warming_caches_the_most_recently_used_routes() {
+        let backend = Arc::new(MemoryBackend::new());
+        let routes = (0..50_000).map(|i| (destination(i), Some(addr("b"))));
+        backend.set_next_batch(routes.collect()).await.unwrap();
+        for i in 0..50_000 {
+            backend.touch(&destination(i), 1 + i as u64).await.unwrap();
+        }
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let node = NodeInstance::new()
+            .with_backend(Counting(backend, lookups.clone()))
+            .with_route_cache_capacity(10_000);
+
+        let report = node
+            .warm_cache(WarmOptions {
+                max_routes: 50_000,
+                time_budget: Some(Duration::from_secs(60)),
+                ..WarmOptions::default()
+            })
+            .await;
+        assert_eq!(report.scanned, 50_000);
+        assert_eq!(report.loaded, 10_000);
+        assert!(!report.timed_out);
+        let cached = node.iter_routes();
+        assert_eq!(cached.len(), 10_000);
+        let cached: HashSet<_> = cached
+            .into_iter()
+            .map(|(destination, _)| destination)
+            .collect();
+        assert!((40_000..50_000).all(|i| cached.contains(&destination(i))));
+
+        assert_eq!(
+            node.resolve_next(&destination(45_000)).await.unwrap(),
+            addr("b")
+        );
+        assert_eq!(lookups.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn warming_never_replaces_live_routes() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend
+            .set_next(&destination(0), Some(&addr("stale")))
+            .await
+            .unwrap();
+        backend.touch(&destination(0), 1).await.unwrap();
+        let node = NodeInstance::new().with_backend(Counting(backend.clone(), Default::default()));
+        assert_eq!(
+            node.resolve_next(&destination(0)).await.unwrap(),
+            addr("stale")
+        );
+        backend
+            .set_next(&destination(0), Some(&addr("b")))
+            .await
+            .unwrap();
+
+        let report = node.warm_cache(WarmOptions::default()).await;
+        assert_eq!((report.scanned, report.loaded), (1, 0));
+        assert_eq!(node.iter_routes(), [(destination(0), addr("stale"))]);
+    }
+}
@@ -0,0 +1,677 @@
+//! Compact binary encoding of [`Message`] for transports that carry raw bytes.
+//!
+//! Every variable-length field is prefixed by its length as an unsigned LEB128
+//! varint; fixed-width integers are little-endian.
+//!
+//! # Versions
+//!
+//! Each version only appends sections to the previous one, and a decoder treats
+//! a section missing at the end of the input as empty:
+//!
+//! 1. destination, path, payload, signature, unique id, ttl and headers;
+//! 2. adds the metadata tags;
+//! 3. adds a list of extension fields, each a varint tag and a length-prefixed
+//!    value. Tags this build does not know end up in [`Message::unknown_fields`]
+//!    and are written out again when the message is relayed. Known tags:
+//!    1. [`Message::seq`] as a varint;
+//!    2. [`Message::budget_ms`] as a varint;
+//!    3. [`Message::route_plan`] as a count followed by the addresses;
+//!    4. the [path node signatures](PathNode::sig) as a count followed by, for
+//!       each path node, a flag byte and, if the flag is set, the signature;
+//!    5. [`Message::reply_to`] as an address;
+//!    6. the [remaining budgets](PathNode::remaining_budget_ms) as a count
+//!       followed by, for each path node, a flag byte and, if the flag is set,
+//!       the budget as a varint. Hops added by relays that do not know the tag
+//!       come after the count, and record none.
+//!
+//! A newer peer may therefore send fields an older build skips, and
+//! [`Message::encode_for_version`] produces output an older peer can parse. Peers
+//! announce the version they decode in their
+//! [`Hello`](crate::control::ControlMessage::Hello), and
+//! [`NodeInstance::wire_format_for`](crate::NodeInstance::wire_format_for) picks
+//! the format to encode for them in.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{Address, Identity, Message, PathNode, Protocol};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the message was complete.
+    UnexpectedEof,
+    /// A varint did not fit the integer field it encodes.
+    VarintOverflow,
+    /// A declared length exceeds the remaining input.
+    LengthOutOfRange { declared: u64, remaining: usize },
+    /// A path node name, header name or metadata tag was not valid UTF-8.
+    InvalidUtf8,
+    /// A flag byte contained unknown bits.
+    InvalidFlags(u8),
+    /// Bytes were left over after the message was decoded.
+    TrailingBytes(usize),
+    /// The input was produced by a different [`WireFormat`] or format version.
+    FormatMismatch { magic: u8, version: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::VarintOverflow => write!(f, "varint overflows its field"),
+            DecodeError::LengthOutOfRange {
+                declared,
+                remaining,
+            } => write!(
+                f,
+                "declared length {declared} exceeds remaining {remaining} bytes"
+            ),
+            DecodeError::InvalidUtf8 => write!(f, "string field is not valid utf-8"),
+            DecodeError::InvalidFlags(flags) => write!(f, "invalid flag byte {flags:#04x}"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} trailing bytes after message"),
+            DecodeError::FormatMismatch { magic, version } => {
+                write!(f, "unexpected wire format {magic:#04x} version {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// How a transport turns a [`Message`] into bytes and back.
+///
+/// Both ends of a transport must agree on the format. Every format should start its
+/// output with a magic byte and a version byte (see [`strip_tag`]) so that a peer
+/// using another format fails with [`DecodeError::FormatMismatch`] instead of
+/// decoding garbage.
+pub trait WireFormat: Send + Sync {
+    fn encode(&self, message: &Message) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Result<Message, DecodeError>;
+}
+
+/// The default format: a two byte tag followed by [`Message::encode`].
+///
+/// Decoding accepts every version from 1 on, including ones newer than
+/// [`CompactFormat::VERSION`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactFormat;
+
+impl CompactFormat {
+    pub const MAGIC: u8 = 0xa7;
+    pub const VERSION: u8 = 3;
+    /// Encode for peers that only understand `version`; see [`PinnedCompactFormat`].
+    pub fn pinned(version: u8) -> PinnedCompactFormat {
+        PinnedCompactFormat { version }
+    }
+}
+
+fn encode_tagged(message: &Message, version: u8) -> Vec<u8> {
+    let body = message.encode_for_version(version);
+    let mut bytes = Vec::with_capacity(2 + body.len());
+    bytes.extend_from_slice(&[CompactFormat::MAGIC, version]);
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+fn decode_tagged(bytes: &[u8]) -> Result<Message, DecodeError> {
+    match bytes {
+        [CompactFormat::MAGIC, version, rest @ ..] if *version >= 1 => Message::decode(rest),
+        [magic, version, ..] => Err(DecodeError::FormatMismatch {
+            magic: *magic,
+            version: *version,
+        }),
+        _ => Err(DecodeError::UnexpectedEof),
+    }
+}
+
+impl WireFormat for CompactFormat {
+    fn encode(&self, message: &Message) -> Vec<u8> {
+        encode_tagged(message, Self::VERSION)
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<Message, DecodeError> {
+        decode_tagged(bytes)
+    }
+}
+
+/// [`CompactFormat`] encoding an older version, for links to peers that have not
+/// been upgraded yet. Fields the version cannot carry are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinnedCompactFormat {
+    version: u8,
+}
+
+impl PinnedCompactFormat {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+}
+
+impl WireFormat for PinnedCompactFormat {
+    fn encode(&self, message: &Message) -> Vec<u8> {
+        encode_tagged(message, self.version.min(CompactFormat::VERSION))
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<Message, DecodeError> {
+        decode_tagged(bytes)
+    }
+}
+
+const EXTENSION_SEQ: u64 = 1;
+const EXTENSION_BUDGET: u64 = 2;
+const EXTENSION_ROUTE_PLAN: u64 = 3;
+pub(crate) const EXTENSION_PATH_SIGNATURES: u64 = 4;
+const EXTENSION_REPLY_TO: u64 = 5;
+const EXTENSION_PATH_BUDGETS: u64 = 6;
+/// The highest extension tag this build decodes into a field of its own.
+#[cfg(any(test, feature = "arbitrary"))]
+pub(crate) const LAST_KNOWN_EXTENSION: u64 = EXTENSION_PATH_BUDGETS;
+
+/// An extension field of a newer format version, kept verbatim.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownField {
+    pub tag: u64,
+    pub value: Vec<u8>,
+}
+
+/// Check the leading magic and version bytes of `bytes` and return what follows.
+pub fn strip_tag(bytes: &[u8], magic: u8, version: u8) -> Result<&[u8], DecodeError> {
+    match bytes {
+        [m, v, rest @ ..] if *m == magic && *v == version => Ok(rest),
+        [m, v, ..] => Err(DecodeError::FormatMismatch {
+            magic: *m,
+            version: *v,
+        }),
+        _ => Err(DecodeError::UnexpectedEof),
+    }
+}
+
+const PATH_NODE_HAS_NAME: u8 = 0b01;
+const PATH_NODE_HAS_ADDRESS: u8 = 0b10;
+
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+    pub(crate) fn put_u8(&mut self, value: u8) {
+        self.buf.push(value)
+    }
+    pub(crate) fn put_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes())
+    }
+    pub(crate) fn put_varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+    pub(crate) fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+    pub(crate) fn put_address(&mut self, address: &Address) {
+        self.put_bytes(address.protocol.as_bytes());
+        self.put_bytes(address.identity.as_bytes());
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if n > self.buf.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+    pub(crate) fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+    pub(crate) fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("took 8 bytes")))
+    }
+    pub(crate) fn get_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.get_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(DecodeError::VarintOverflow);
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(DecodeError::VarintOverflow);
+            }
+        }
+    }
+    pub(crate) fn get_len(&mut self) -> Result<usize, DecodeError> {
+        let declared = self.get_varint()?;
+        if declared > self.buf.len() as u64 {
+            return Err(DecodeError::LengthOutOfRange {
+                declared,
+                remaining: self.buf.len(),
+            });
+        }
+        Ok(declared as usize)
+    }
+    pub(crate) fn get_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.get_len()?;
+        self.take(len)
+    }
+    pub(crate) fn get_address(&mut self) -> Result<Address, DecodeError> {
+        let protocol = Protocol::new(self.get_bytes()?);
+        let identity = Identity::new(self.get_bytes()?);
+        Ok(Address { protocol, identity })
+    }
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        match self.buf.len() {
+            0 => Ok(()),
+            n => Err(DecodeError::TrailingBytes(n)),
+        }
+    }
+}
+
+impl Message {
+    /// Encode in the current [`CompactFormat::VERSION`].
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_for_version(CompactFormat::VERSION)
+    }
+    /// Encode only the sections a peer speaking `version` can parse.
+    pub fn encode_for_version(&self, version: u8) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_address(&self.destination);
+        w.put_varint(self.path.len() as u64);
+        for node in &self.path {
+            let mut flags = 0;
+            if node.name.is_some() {
+                flags |= PATH_NODE_HAS_NAME;
+            }
+            if node.address.is_some() {
+                flags |= PATH_NODE_HAS_ADDRESS;
+            }
+            w.put_u8(flags);
+            if let Some(name) = &node.name {
+                w.put_bytes(name.as_bytes());
+            }
+            if let Some(address) = &node.address {
+                w.put_address(address);
+            }
+            w.put_u64(node.ts);
+        }
+        w.put_bytes(&self.payload);
+        w.put_bytes(&self.signature);
+        w.put_u64(self.unique_id);
+        match self.ttl {
+            Some(ttl) => {
+                w.put_u8(1);
+                w.put_varint(u64::from(ttl));
+            }
+            None => w.put_u8(0),
+        }
+        w.put_varint(self.headers.len() as u64);
+        for (name, value) in &self.headers {
+            w.put_bytes(name.as_bytes());
+            w.put_bytes(value);
+        }
+        if version < 2 {
+            return w.finish();
+        }
+        w.put_varint(self.metadata.len() as u64);
+        for (key, value) in &self.metadata {
+            w.put_bytes(key.as_bytes());
+            w.put_bytes(value.as_bytes());
+        }
+        if version < 3 {
+            return w.finish();
+        }
+        let known = [
+            (EXTENSION_SEQ, self.seq),
+            (EXTENSION_BUDGET, self.budget_ms),
+        ];
+        let known = known
+            .iter()
+            .filter_map(|(tag, value)| Some((*tag, (*value)?)));
+        let signed = self.path.iter().any(|node| node.sig.is_some());
+        let budgeted = self
+            .path
+            .iter()
+            .any(|node| node.remaining_budget_ms.is_some());
+        let count = self.extensions.len()
+            + known.clone().count()
+            + self.route_plan.iter().count()
+            + self.reply_to.iter().count()
+            + usize::from(signed)
+            + usize::from(budgeted);
+        w.put_varint(count as u64);
+        for (tag, value) in known {
+            let mut bytes = Writer::new();
+            bytes.put_varint(value);
+            w.put_varint(tag);
+            w.put_bytes(&bytes.finish());
+        }
+        if let Some(plan) = &self.route_plan {
+            let mut bytes = Writer::new();
+            bytes.put_varint(plan.len() as u64);
+            for hop in plan {
+                bytes.put_address(hop);
+            }
+            w.put_varint(EXTENSION_ROUTE_PLAN);
+            w.put_bytes(&bytes.finish());
+        }
+        if let Some(reply_to) = &self.reply_to {
+            let mut bytes = Writer::new();
+            bytes.put_address(reply_to);
+            w.put_varint(EXTENSION_REPLY_TO);
+            w.put_bytes(&bytes.finish());
+        }
+        if signed {
+            let mut bytes = Writer::new();
+            bytes.put_varint(self.path.len() as u64);
+            for node in &self.path {
+                match &node.sig {
+                    Some(sig) => {
+                        bytes.put_u8(1);
+                        bytes.put_bytes(sig);
+                    }
+                    None => bytes.put_u8(0),
+                }
+            }
+            w.put_varint(EXTENSION_PATH_SIGNATURES);
+            w.put_bytes(&bytes.finish());
+        }
+        if budgeted {
+            let mut bytes = Writer::new();
+            bytes.put_varint(self.path.len() as u64);
+            for node in &self.path {
+                match node.remaining_budget_ms {
+                    Some(remaining) => {
+                        bytes.put_u8(1);
+                        bytes.put_varint(remaining);
+                    }
+                    None => bytes.put_u8(0),
+                }
+            }
+            w.put_varint(EXTENSION_PATH_BUDGETS);
+            w.put_bytes(&bytes.finish());
+        }
+        for field in &self.extensions {
+            w.put_varint(field.tag);
+            w.put_bytes(&field.value);
+        }
+        w.finish()
+    }
+    /// Extension fields of a newer format version that this build skipped.
+    pub fn unknown_fields(&self) -> &[UnknownField] {
+        &self.extensions
+    }
+    /// Decode a message body of any format version.
+    pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let destination = r.get_address()?;
+        let path_len = r.get_varint()?;
+        // every path node takes at least a flag byte and a timestamp
+        if path_len > (r.remaining() / 9) as u64 {
+            return Err(DecodeError::LengthOutOfRange {
+                declared: path_len,
+                remaining: r.remaining(),
+            });
+        }
+        let mut path = Vec::with_capacity(path_len as usize);
+        for _ in 0..path_len {
+            let flags = r.get_u8()?;
+            if flags & !(PATH_NODE_HAS_NAME | PATH_NODE_HAS_ADDRESS) != 0 {
+                return Err(DecodeError::InvalidFlags(flags));
+            }
+            let name = if flags & PATH_NODE_HAS_NAME != 0 {
+                let bytes = r.get_bytes()?;
+                Some(
+                    std::str::from_utf8(bytes)
+                        .map_err(|_| DecodeError::InvalidUtf8)?
+                        .to_owned(),
+                )
+            } else {
+                None
+            };
+            let address = if flags & PATH_NODE_HAS_ADDRESS != 0 {
+                Some(r.get_address()?)
+            } else {
+                None
+            };
+            let ts = r.get_u64()?;
+            path.push(PathNode {
+                name,
+                address,
+                ts,
+                sig: None,
+                remaining_budget_ms: None,
+            });
+        }
+        let payload = r.get_bytes()?.to_vec();
+        let signature = r.get_bytes()?.to_vec();
+        let unique_id = r.get_u64()?;
+        let ttl = match r.get_u8()? {
+            0 => None,
+            1 => Some(u32::try_from(r.get_varint()?).map_err(|_| DecodeError::VarintOverflow)?),
+            flags => return Err(DecodeError::InvalidFlags(flags)),
+        };
+        let header_count = r.get_varint()?;
+        let mut headers = BTreeMap::new();
+        for _ in 0..header_count {
+            let name = std::str::from_utf8(r.get_bytes()?)
+                .map_err(|_| DecodeError::InvalidUtf8)?
+                .to_owned();
+            let value = r.get_bytes()?.to_vec();
+            headers.insert(name, value);
+        }
+        let tag_count = if r.remaining() == 0 {
+            0
+        } else {
+            r.get_varint()?
+        };
+        let mut metadata = Vec::new();
+        for _ in 0..tag_count {
+            let key = std::str::from_utf8(r.get_bytes()?)
+                .map_err(|_| DecodeError::InvalidUtf8)?
+                .to_owned();
+            let value = std::str::from_utf8(r.get_bytes()?)
+                .map_err(|_| DecodeError::InvalidUtf8)?
+                .to_owned();
+            metadata.push((key, value));
+        }
+        let extension_count = if r.remaining() == 0 {
+            0
+        } else {
+            r.get_varint()?
+        };
+        let mut extensions = Vec::new();
+        let (mut seq, mut budget_ms, mut route_plan, mut reply_to) = (None, None, None, None);
+        for _ in 0..extension_count {
+            let tag = r.get_varint()?;
+            let value = r.get_bytes()?;
+            match tag {
+                EXTENSION_SEQ | EXTENSION_BUDGET => {
+                    let mut value = Reader::new(value);
+                    let field = if tag == EXTENSION_SEQ {
+                        &mut seq
+                    } else {
+                        &mut budget_ms
+                    };
+                    *field = Some(value.get_varint()?);
+                    value.finish()?;
+                }
+                EXTENSION_ROUTE_PLAN => {
+                    let mut value = Reader::new(value);
+                    let count = value.get_varint()?;
+                    // every address takes at least its two length prefixes
+                    if count > (value.remaining() / 2) as u64 {
+                        return Err(DecodeError::LengthOutOfRange {
+                            declared: count,
+                            remaining: value.remaining(),
+                        });
+                    }
+                    let mut plan = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        plan.push(value.get_address()?);
+                    }
+                    value.finish()?;
+                    route_plan = Some(plan);
+                }
+                EXTENSION_REPLY_TO => {
+                    let mut value = Reader::new(value);
+                    reply_to = Some(value.get_address()?);
+                    value.finish()?;
+                }
+                EXTENSION_PATH_SIGNATURES => {
+                    let mut value = Reader::new(value);
+                    let count = value.get_varint()?;
+                    // hops appended by relays that skip the tag stay unsigned
+                    if count > path.len() as u64 {
+                        return Err(DecodeError::LengthOutOfRange {
+                            declared: count,
+                            remaining: path.len(),
+                        });
+                    }
+                    for node in &mut path[..count as usize] {
+                        node.sig = match value.get_u8()? {
+                            0 => None,
+                            1 => Some(value.get_bytes()?.to_vec()),
+                            flags => return Err(DecodeError::InvalidFlags(flags)),
+                        };
+                    }
+                    value.finish()?;
+                }
+                EXTENSION_PATH_BUDGETS => {
+                    let mut value = Reader::new(value);
+                    let count = value.get_varint()?;
+                    // relays that skip the tag append their hops without one
+                    if count > path.len() as u64 {
+                        return Err(DecodeError::LengthOutOfRange {
+                            declared: count,
+                            remaining: path.len(),
+                        });
+                    }
+                    for node in &mut path[..count as usize] {
+                        node.remaining_budget_ms = match value.get_u8()? {
+                            0 => None,
+                            1 => Some(value.get_varint()?),
+                            flags => return Err(DecodeError::InvalidFlags(flags)),
+                        };
+                    }
+                    value.finish()?;
+                }
+                _ => extensions.push(UnknownField {
+                    tag,
+                    value: value.to_vec(),
+                }),
+            }
+        }
+        r.finish()?;
+        Ok(Message {
+            destination,
+            path,
+            payload,
+            signature,
+            unique_id,
+            ttl,
+            headers,
+            metadata,
+            extensions,
+            seq,
+            budget_ms,
+            route_plan,
+            reply_to,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{addr, message};
+
+    /// A second format, tagged as the trait asks, whose body is the compact
+    /// encoding back to front.
+    struct ReversedFormat;
+
+    impl ReversedFormat {
+        const MAGIC: u8 = 0x52;
+        const VERSION: u8 = 1;
+    }
+
+    impl WireFormat for ReversedFormat {
+        fn encode(&self, message: &Message) -> Vec<u8> {
+            let mut bytes = vec![Self::MAGIC, Self::VERSION];
+            bytes.extend(message.encode().into_iter().rev());
+            bytes
+        }
+        fn decode(&self, bytes: &[u8]) -> Result<Message, DecodeError> {
+            let body = strip_tag(bytes, Self::MAGIC, Self::VERSION)?;
+            Message::decode(&body.iter().rev().copied().collect::<Vec<_>>())
+        }
+    }
+
+    #[test]
+    fn each_format_round_trips() {
+        let sent = message(addr("b"), b"payload");
+        let formats: [&dyn WireFormat; 2] = [&CompactFormat, &ReversedFormat];
+        for format in formats {
+            let decoded = format.decode(&format.encode(&sent)).unwrap();
+            assert!(decoded == sent);
+        }
+    }
+
+    #[test]
+    fn formats_refuse_each_others_bytes() {
+        let sent = message(addr("b"), b"payload");
+        assert_eq!(
+            ReversedFormat.decode(&CompactFormat.encode(&sent)).err(),
+            Some(DecodeError::FormatMismatch {
+                magic: CompactFormat::MAGIC,
+                version: CompactFormat::VERSION,
+            })
+        );
+        assert_eq!(
+            CompactFormat.decode(&ReversedFormat.encode(&sent)).err(),
+            Some(DecodeError::FormatMismatch {
+                magic: ReversedFormat::MAGIC,
+                version: ReversedFormat::VERSION,
+            })
+        );
+    }
+
+    #[test]
+    fn pinned_formats_decode_with_the_current_one() {
+        let sent = message(addr("b"), b"payload");
+        let pinned = CompactFormat::pinned(1).encode(&sent);
+        assert_eq!(pinned[..2], [CompactFormat::MAGIC, 1]);
+        assert!(CompactFormat.decode(&pinned).unwrap().payload == sent.payload);
+        assert_eq!(
+            CompactFormat.decode(&[]).err(),
+            Some(DecodeError::UnexpectedEof)
+        );
+    }
+}
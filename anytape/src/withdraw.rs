@@ -0,0 +1,368 @@
+//! Telling upstream nodes that a route broke.
+//!
+//! With [route withdraws](NodeInstance::with_route_withdraw) on, a relay
+//! remembers which nodes recently sent it traffic for each destination, learned
+//! from the last entry of a relayed message's path. Once sends towards a
+//! destination have failed [`RouteWithdrawConfig::failure_threshold`] times in
+//! a row, the relay sends a [`ControlMessage::RouteWithdraw`] to each of them.
+//!
+//! A node receiving a withdraw checks it with its authorizer, then drops its
+//! cached route, route candidate and backend entry for the destination, but
+//! only those pointing at the withdrawing node. Withdraws for routes that do not
+//! go through the sender are ignored. A withdraw with hops left travels on to
+//! the receiver's own upstream nodes for that destination.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    control::ControlMessage, Address, AuditKind, Message, MessageStatus, NodeEvent, NodeInstance,
+    RejectReason,
+};
+
+/// Destinations whose upstream nodes and failure counts a node keeps track of.
+const MAX_TRACKED_DESTINATIONS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawReason {
+    /// Sends towards the destination kept failing.
+    DeliveryFailures,
+    /// The application asked for the withdraw.
+    Manual,
+}
+
+impl WithdrawReason {
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            WithdrawReason::DeliveryFailures => 0,
+            WithdrawReason::Manual => 1,
+        }
+    }
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(WithdrawReason::DeliveryFailures),
+            1 => Some(WithdrawReason::Manual),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteWithdrawConfig {
+    /// Consecutive failed sends towards a destination that trigger a withdraw.
+    pub failure_threshold: u32,
+    /// Hops a withdraw travels beyond the nodes this node notifies itself.
+    pub ttl: u8,
+    /// Upstream nodes remembered per destination; the least recent are
+    /// forgotten first.
+    pub max_upstreams: usize,
+}
+
+impl Default for RouteWithdrawConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            ttl: 1,
+            max_upstreams: 8,
+        }
+    }
+}
+
+/// Counters of the route withdraws a node sent and received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RouteWithdrawStats {
+    pub sent: u64,
+    /// Received withdraws that removed a route.
+    pub applied: u64,
+    /// Received withdraws for routes not going through their sender.
+    pub ignored: u64,
+}
+
+type WithdrawAuthorizer = dyn Fn(&Message) -> bool + Send + Sync;
+type WithdrawSigner = dyn Fn(&mut Message) + Send + Sync;
+
+/// A node that sent traffic for a destination, and the address it sent it to.
+#[derive(Clone, PartialEq, Eq)]
+struct Upstream {
+    address: Address,
+    via: Address,
+}
+
+#[derive(Default)]
+pub(crate) struct WithdrawState {
+    settings: Option<(RouteWithdrawConfig, Arc<WithdrawAuthorizer>)>,
+    signer: Option<Arc<WithdrawSigner>>,
+    upstreams: Mutex<HashMap<Address, VecDeque<Upstream>>>,
+    failures: Mutex<HashMap<Address, u32>>,
+    sent: AtomicU64,
+    applied: AtomicU64,
+    ignored: AtomicU64,
+}
+
+fn make_room<V>(map: &mut HashMap<Address, V>, key: &Address) {
+    if map.len() >= MAX_TRACKED_DESTINATIONS && !map.contains_key(key) {
+        if let Some(evicted) = map.keys().next().cloned() {
+            map.remove(&evicted);
+        }
+    }
+}
+
+impl NodeInstance {
+    /// Withdraw routes upstream when deliveries keep failing, and drop routes
+    /// withdrawn by downstream nodes if `authorize` accepts the withdraw; see the
+    /// [module docs](self).
+    pub fn with_route_withdraw(
+        mut self,
+        config: RouteWithdrawConfig,
+        authorize: impl Fn(&Message) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.withdraws.settings = Some((config, Arc::new(authorize)));
+        self
+    }
+    /// Let `sign` fill in the signature of every withdraw this node sends.
+    pub fn with_route_withdraw_signer(
+        mut self,
+        sign: impl Fn(&mut Message) + Send + Sync + 'static,
+    ) -> Self {
+        self.withdraws.signer = Some(Arc::new(sign));
+        self
+    }
+    pub fn route_withdraw_stats(&self) -> RouteWithdrawStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        RouteWithdrawStats {
+            sent: load(&self.withdraws.sent),
+            applied: load(&self.withdraws.applied),
+            ignored: load(&self.withdraws.ignored),
+        }
+    }
+    /// Tell the nodes that recently sent traffic for `destination` through this
+    /// node to stop doing so. Does nothing unless route withdraws are on.
+    pub async fn withdraw_route(&self, destination: &Address, reason: WithdrawReason) {
+        let Some((config, _)) = &self.withdraws.settings else {
+            return;
+        };
+        self.send_withdraws(destination, reason, config.ttl).await
+    }
+    pub(crate) fn route_withdraw_enabled(&self) -> bool {
+        self.withdraws.settings.is_some()
+    }
+    /// Remember who sent a message this node is about to relay, before marking it.
+    pub(crate) fn note_upstream(&self, message: &Message, accept_at: &Address) {
+        let Some((config, _)) = &self.withdraws.settings else {
+            return;
+        };
+        let Some(address) = message.path.last().and_then(|node| node.address.clone()) else {
+            return;
+        };
+        if self.is_local(&address) {
+            return;
+        }
+        let upstream = Upstream {
+            address,
+            via: accept_at.clone(),
+        };
+        let mut upstreams = self.withdraws.upstreams.lock().unwrap();
+        make_room(&mut upstreams, &message.destination);
+        let known = upstreams.entry(message.destination.clone()).or_default();
+        known.retain(|known| known != &upstream);
+        known.push_back(upstream);
+        while known.len() > config.max_upstreams {
+            known.pop_front();
+        }
+    }
+    /// Count the outcome of a send towards `destination`, withdrawing the route
+    /// once too many failed in a row.
+    pub(crate) async fn observe_delivery(&self, destination: &Address, delivered: bool) {
+        let Some((config, _)) = &self.withdraws.settings else {
+            return;
+        };
+        let withdraw = {
+            let mut failures = self.withdraws.failures.lock().unwrap();
+            if delivered {
+                failures.remove(destination);
+                false
+            } else {
+                make_room(&mut failures, destination);
+                let count = failures.entry(destination.clone()).or_default();
+                *count += 1;
+                if *count >= config.failure_threshold.max(1) {
+                    failures.remove(destination);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if withdraw {
+            self.send_withdraws(destination, WithdrawReason::DeliveryFailures, config.ttl)
+                .await
+        }
+    }
+    async fn send_withdraws(&self, destination: &Address, reason: WithdrawReason, ttl: u8) {
+        let upstreams: Vec<Upstream> = self
+            .withdraws
+            .upstreams
+            .lock()
+            .unwrap()
+            .get(destination)
+            .map(|known| known.iter().cloned().collect())
+            .unwrap_or_default();
+        for upstream in upstreams {
+            let mut message = ControlMessage::RouteWithdraw {
+                destination: destination.clone(),
+                via: upstream.via,
+                reason,
+                ttl,
+            }
+            .into_message(upstream.address.clone());
+            if let Some(sign) = &self.withdraws.signer {
+                sign(&mut message);
+            }
+            if self.send(message, upstream.address).await.is_ok() {
+                self.withdraws.sent.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Handle a withdraw of the route to `destination` through `via`.
+    pub(crate) async fn apply_withdraw(
+        &self,
+        destination: &Address,
+        via: &Address,
+        reason: WithdrawReason,
+        ttl: u8,
+        envelope: &Message,
+    ) -> MessageStatus {
+        let Some((_, authorize)) = &self.withdraws.settings else {
+            return MessageStatus::Rejected {
+                reason: RejectReason::NoHandler,
+            };
+        };
+        if !authorize(envelope) {
+            let mut event = self
+                .audit_event(AuditKind::SignatureFailure, "route withdraw not authorized")
+                .unique_id(envelope.unique_id)
+                .address(destination);
+            if let Some(origin) = envelope.path.first().and_then(|node| node.address.as_ref()) {
+                event = event.identity(&origin.identity).address(origin);
+            }
+            self.audit(event);
+            return MessageStatus::Rejected {
+                reason: RejectReason::InvalidSignature,
+            };
+        }
+        let mut removed = false;
+        if let Some(key) = self.cache_key(destination) {
+            let mut cache = self.next_cache.write().unwrap();
+            if cache
+                .peek(&key)
+                .is_some_and(|next| &next.to_address() == via)
+            {
+                cache.remove(&key);
+                removed = true;
+                self.publish(|| NodeEvent::RouteEvicted {
+                    destination: destination.clone(),
+                });
+            }
+        }
+        if self
+            .route_costs(destination)
+            .iter()
+            .any(|candidate| &candidate.next == via)
+        {
+            self.remove_route_candidate(destination, via);
+            removed = true;
+        }
+        if let Some(backend) = &self.backend {
+            if let Ok(Some(next)) = backend.get_next(destination).await {
+                if &next == via && backend.set_next(destination, None).await.is_ok() {
+                    removed = true;
+                }
+            }
+        }
+        if !removed {
+            self.withdraws.ignored.fetch_add(1, Ordering::Relaxed);
+            return MessageStatus::Received;
+        }
+        self.withdraws.applied.fetch_add(1, Ordering::Relaxed);
+        if ttl > 0 {
+            self.send_withdraws(destination, reason, ttl - 1).await;
+        }
+        MessageStatus::Received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::Shared,
+        virtual_net::{LinkConfig, VirtualNetwork},
+        DataBackend, MemoryBackend, MessageBuilder,
+    };
+
+    #[tokio::test]
+    async fn a_broken_last_link_purges_the_routes_upstream() {
+        let (b, c, d) = (
+            VirtualNetwork::address("b"),
+            VirtualNetwork::address("c"),
+            VirtualNetwork::address("d"),
+        );
+        let routes = |next: &Address| {
+            let backend = Arc::new(MemoryBackend::new());
+            crate::testing::block_on(backend.set_next(&d, Some(next))).unwrap();
+            backend
+        };
+        let (a_routes, b_routes) = (routes(&b), routes(&c));
+        let config = RouteWithdrawConfig {
+            failure_threshold: 1,
+            ..RouteWithdrawConfig::default()
+        };
+
+        // d is not part of the network, so every send from c towards it fails
+        let mut net = VirtualNetwork::new();
+        net.connect("a", "b", LinkConfig::default());
+        net.connect("b", "c", LinkConfig::default());
+        let a = net.add_node("a", |node| {
+            node.with_backend(Shared(a_routes.clone()))
+                .with_route_withdraw(config, |_| true)
+        });
+        let b_node = net.add_node("b", |node| {
+            node.with_backend(Shared(b_routes.clone()))
+                .with_route_withdraw(config, |_| true)
+        });
+        let c_node = net.add_node("c", |node| node.with_route_withdraw(config, |_| true));
+
+        let mut message = MessageBuilder::new(d.clone()).payload("hi").build();
+        a.mark(VirtualNetwork::address("a"), &mut message);
+        a.forward(message).await.unwrap();
+        assert!(a.iter_routes().contains(&(d.clone(), b.clone())));
+
+        let deliveries = net.run_until_idle(10).await;
+        let hops: Vec<_> = deliveries
+            .iter()
+            .map(|delivery| String::from_utf8_lossy(delivery.to.as_bytes()).into_owned())
+            .collect();
+        // the message to b and c, then the withdraw back to b and on to a
+        assert_eq!(hops, ["b", "c", "b", "a"]);
+        assert!(deliveries[1].result.is_err());
+
+        assert_eq!(c_node.route_withdraw_stats().sent, 1);
+        let stats = |node: &NodeInstance| {
+            let stats = node.route_withdraw_stats();
+            (stats.applied, stats.ignored)
+        };
+        assert_eq!(stats(&b_node), (1, 0));
+        assert_eq!(stats(&a), (1, 0));
+        for (node, backend) in [(&a, &a_routes), (&b_node, &b_routes)] {
+            assert!(!node.iter_routes().iter().any(|(to, _)| to == &d));
+            assert_eq!(backend.get_next(&d).await.unwrap(), None);
+        }
+    }
+}
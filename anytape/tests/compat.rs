@@ -0,0 +1,174 @@
+//! Frozen encodings of every released wire format version.
+//!
+//! The fixtures in `tests/compat/` were written by the release that introduced
+//! their version and must never be regenerated: current code has to decode all
+//! of them, and encoding for an old version has to reproduce its fixture byte for
+//! byte. A change that breaks one of these tests breaks mixed-version fleets.
+
+use anytape::{
+    control::ControlMessage,
+    wire::{CompactFormat, UnknownField, WireFormat},
+    Address, Identity, Message, NodeInstance, PathNode, Protocol,
+};
+
+const V1: &[u8] = include_bytes!("compat/v1.bin");
+const V2: &[u8] = include_bytes!("compat/v2.bin");
+const V3: &[u8] = include_bytes!("compat/v3.bin");
+/// A v3 message relayed by a build that predates path signatures: the relay
+/// appended its hop but kept the signature extension as it was.
+const V3_RELAYED: &[u8] = include_bytes!("compat/v3-relayed.bin");
+/// A version 4 message carrying an extension field no released build knows.
+const V4_FUTURE: &[u8] = include_bytes!("compat/v4-future.bin");
+
+const TEST: Protocol = Protocol::new_static(b"test");
+
+fn addr(identity: &str) -> Address {
+    Address::new(TEST, Identity::new(identity))
+}
+
+fn hop(name: &str, ts: u64) -> PathNode {
+    PathNode {
+        name: Some(name.to_owned()),
+        address: Some(addr(name)),
+        ts,
+        sig: None,
+        remaining_budget_ms: None,
+    }
+}
+
+/// The message every fixture encodes, with every field the current version has.
+fn fixture() -> Message {
+    Message {
+        destination: addr("destination"),
+        path: vec![
+            PathNode {
+                sig: Some(b"sig-a".to_vec()),
+                remaining_budget_ms: Some(900),
+                ..hop("a", 1_700_000_000_000)
+            },
+            hop("b", 1_700_000_000_250),
+        ],
+        payload: b"payload".to_vec(),
+        signature: b"signature".to_vec(),
+        unique_id: 0x0102_0304_0506_0708,
+        seq: Some(42),
+        budget_ms: Some(1000),
+        route_plan: Some(vec![addr("b"), addr("destination")]),
+        reply_to: Some(addr("origin")),
+        ttl: Some(8),
+        headers: [("content-type".to_owned(), b"text/plain".to_vec())].into(),
+        metadata: vec![("tenant".to_owned(), "blue".to_owned())],
+        extensions: Vec::new(),
+    }
+}
+
+/// What a build that only speaks version 2 knows of [`fixture`].
+fn fixture_v2() -> Message {
+    let mut message = fixture();
+    for node in &mut message.path {
+        node.sig = None;
+        node.remaining_budget_ms = None;
+    }
+    Message {
+        seq: None,
+        budget_ms: None,
+        route_plan: None,
+        reply_to: None,
+        ..message
+    }
+}
+
+/// What a build that only speaks version 1 knows of [`fixture`].
+fn fixture_v1() -> Message {
+    Message {
+        metadata: Vec::new(),
+        ..fixture_v2()
+    }
+}
+
+fn decode(bytes: &[u8]) -> Message {
+    CompactFormat.decode(bytes).expect("fixture decodes")
+}
+
+// messages are not Debug, so as not to show their payloads; compare encodings
+fn assert_same(actual: &Message, expected: &Message) {
+    assert_eq!(actual.encode(), expected.encode());
+}
+
+#[test]
+fn every_version_decodes() {
+    assert_same(&decode(V1), &fixture_v1());
+    assert_same(&decode(V2), &fixture_v2());
+    assert_same(&decode(V3), &fixture());
+}
+
+#[test]
+fn old_versions_encode_to_their_fixtures() {
+    assert_eq!(CompactFormat::pinned(1).encode(&fixture()), V1);
+    assert_eq!(CompactFormat::pinned(2).encode(&fixture()), V2);
+    assert_eq!(CompactFormat::pinned(3).encode(&fixture()), V3);
+    assert_eq!(CompactFormat.encode(&fixture()), V3);
+}
+
+#[test]
+fn hops_relayed_by_older_builds_are_unsigned() {
+    let message = decode(V3_RELAYED);
+    let sigs: Vec<_> = message.path.iter().map(|node| node.sig.clone()).collect();
+    assert_eq!(sigs, [Some(b"sig-a".to_vec()), None, None]);
+    assert_eq!(message.path[2].name.as_deref(), Some("relay"));
+}
+
+#[test]
+fn unknown_fields_of_newer_versions_are_kept() {
+    let message = decode(V4_FUTURE);
+    assert_eq!(
+        message.unknown_fields(),
+        [UnknownField {
+            tag: 200,
+            value: b"future".to_vec(),
+        }]
+    );
+    assert_eq!(message.payload, b"payload");
+    // and passed on to the next hop unchanged
+    let relayed = decode(&CompactFormat.encode(&message));
+    assert_eq!(relayed.unknown_fields(), message.unknown_fields());
+}
+
+#[tokio::test]
+async fn nodes_encode_for_the_version_a_peer_announced() {
+    let node = NodeInstance::new().with_address(addr("node"));
+    let (old, new) = (addr("old"), addr("new"));
+    for (peer, version) in [(&old, 1), (&new, 7)] {
+        let hello = ControlMessage::Hello {
+            name: None,
+            addresses: vec![peer.clone()],
+            wire_version: Some(version),
+        };
+        node.dispatch_inbound(hello.into_message(addr("node")), addr("node"))
+            .await
+            .unwrap();
+    }
+    assert_eq!(node.peer_wire_version(&old), Some(1));
+    assert_eq!(node.wire_format_for(&old).encode(&fixture()), V1);
+    assert_eq!(node.wire_format_for(&new).encode(&fixture()), V3);
+    let unknown = addr("unknown");
+    assert_eq!(node.wire_format_for(&unknown).encode(&fixture()), V3);
+}
+
+#[test]
+fn hellos_without_a_version_keep_the_old_encoding() {
+    let hello = ControlMessage::Hello {
+        name: Some("a".to_owned()),
+        addresses: vec![addr("a")],
+        wire_version: None,
+    };
+    let bytes = hello.encode();
+    // variant 1, version 1: what builds before version announcements read
+    assert_eq!(bytes[..2], [1, 1]);
+    assert_eq!(ControlMessage::decode(&bytes), Ok(Some(hello)));
+    let announcing = ControlMessage::hello(None, vec![addr("a")]);
+    assert_eq!(
+        ControlMessage::decode(&announcing.encode()),
+        Ok(Some(announcing))
+    );
+}
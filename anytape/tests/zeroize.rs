@@ -0,0 +1,75 @@
+//! Dropped identities leave no copy of their bytes on the heap.
+//!
+//! The global allocator of this test binary looks at one watched block as it
+//! is freed, after every destructor has run and before the memory can be
+//! reused, and notes whether it was wiped.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use anytape::Identity;
+
+/// The address of the block to look at when it is freed, or 0.
+static WATCHED: AtomicUsize = AtomicUsize::new(0);
+/// What the watched block held when freed: 0 not freed yet, 1 only zeros, 2
+/// anything else.
+static FREED: AtomicU8 = AtomicU8::new(0);
+
+struct Watching;
+
+// SAFETY: every call is passed on to the system allocator unchanged; dealloc
+// only reads the block it is about to free.
+unsafe impl GlobalAlloc for Watching {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if WATCHED
+            .compare_exchange(ptr as usize, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            // SAFETY: the block is still allocated and `layout.size()` bytes long
+            let bytes = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+            let wiped = bytes.iter().all(|byte| *byte == 0);
+            FREED.store(if wiped { 1 } else { 2 }, Ordering::SeqCst);
+        }
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Watching = Watching;
+
+/// Drop `value` while watching the heap block at `ptr`, and report whether the
+/// block was wiped.
+fn wiped_on_drop<T>(value: T, ptr: *const u8) -> bool {
+    FREED.store(0, Ordering::SeqCst);
+    WATCHED.store(ptr as usize, Ordering::SeqCst);
+    drop(value);
+    match FREED.load(Ordering::SeqCst) {
+        0 => panic!("the watched block was not freed"),
+        freed => freed == 1,
+    }
+}
+
+#[test]
+fn identity_buffers_are_zeroed_before_they_are_freed() {
+    // the same bytes in a plain vector survive, so the allocator would notice
+    let plain = b"secret key material".to_vec();
+    let ptr = plain.as_ptr();
+    assert!(!wiped_on_drop(plain, ptr));
+
+    let identity = Identity::new(b"secret key material".to_vec());
+    let ptr = identity.as_bytes().as_ptr();
+    assert!(wiped_on_drop(identity, ptr));
+
+    // clones have buffers of their own, each wiped
+    let identity = Identity::new(b"secret key material".to_vec());
+    let clone = identity.clone();
+    let (ptr, clone_ptr) = (identity.as_bytes().as_ptr(), clone.as_bytes().as_ptr());
+    assert_ne!(ptr, clone_ptr);
+    assert!(wiped_on_drop(identity, ptr));
+    assert!(wiped_on_drop(clone, clone_ptr));
+}